@@ -0,0 +1,71 @@
+/*
+ * C interface to ssh_ip_tunnel, from the library built with
+ *   cargo rustc --release --lib --features ffi --crate-type cdylib
+ * which produces target/release/libssh_ip_tunnel.so (.dylib on macOS).
+ *
+ * Every call blocks until its step is done and returns an ssh_ip_tunnel_error;
+ * ssh_ip_tunnel_last_error() describes the last failure on the calling thread.
+ * A handle must not be used from two threads at once.
+ */
+
+#ifndef SSH_IP_TUNNEL_H
+#define SSH_IP_TUNNEL_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum ssh_ip_tunnel_error {
+    SSH_IP_TUNNEL_OK = 0,
+    /* A null handle or argument, text that isn't UTF-8, or an invalid host or user */
+    SSH_IP_TUNNEL_INVALID_ARGUMENT = 1,
+    /* Loading the configuration file failed */
+    SSH_IP_TUNNEL_CONFIG = 2,
+    /* The tunnel could not be opened */
+    SSH_IP_TUNNEL_TUNNEL = 3,
+    /* The device did not answer through the tunnel */
+    SSH_IP_TUNNEL_CONNECTION = 4,
+    /* The device's host key is unknown or changed */
+    SSH_IP_TUNNEL_HOST_KEY = 5,
+    /* The device is not an ARM board, or its architecture could not be told */
+    SSH_IP_TUNNEL_ARCHITECTURE = 6,
+    /* The key could not be read or installed, or there is none to log in with */
+    SSH_IP_TUNNEL_KEY = 7,
+    SSH_IP_TUNNEL_OTHER = 99
+} ssh_ip_tunnel_error;
+
+typedef struct SshIpTunnel SshIpTunnel;
+
+/* Creates a handle for user@host with the defaults from the configuration file
+ * at config_path, or the usual one when it is NULL. */
+int ssh_ip_tunnel_new(const char *host, const char *user, const char *config_path,
+                      SshIpTunnel **out);
+
+/* Sets the public key ssh_ip_tunnel_transfer_key() installs */
+int ssh_ip_tunnel_set_key(SshIpTunnel *tunnel, const char *key_path);
+
+/* Sets the local port of the tunnel */
+int ssh_ip_tunnel_set_port(SshIpTunnel *tunnel, uint16_t port);
+
+/* Opens the tunnel and checks that the device answers through it */
+int ssh_ip_tunnel_create(SshIpTunnel *tunnel);
+
+/* Installs the key in the device's authorized_keys through an open tunnel */
+int ssh_ip_tunnel_transfer_key(SshIpTunnel *tunnel);
+
+/* Closes the tunnel; closing one that isn't open is not an error */
+int ssh_ip_tunnel_close(SshIpTunnel *tunnel);
+
+/* Releases a handle; the tunnel stays open unless closed first. NULL is ignored. */
+void ssh_ip_tunnel_free(SshIpTunnel *tunnel);
+
+/* The last failure on this thread, or NULL; valid until the next failing call */
+const char *ssh_ip_tunnel_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SSH_IP_TUNNEL_H */
@@ -0,0 +1,252 @@
+//! Installing the agent on a provisioned device.
+//!
+//! `agent install` runs the normal provisioning workflow, copies the agent
+//! build (given explicitly or picked for the device's architecture) through the
+//! tunnel and installs it as a systemd service, so one command takes a fresh
+//! board to a running agent.
+//!
+//! Nothing registers the agent anywhere: this tool has no daemon or hub for
+//! it to join. Whatever the agent reports to is for its own configuration.
+
+use crate::artifact;
+use crate::checksum;
+use crate::config::Config;
+use crate::executor::Command;
+use crate::fleet;
+use crate::output::Renderable;
+use crate::shell::{self, RemoteCommand};
+use crate::ssh;
+use crate::{RunReport, SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Name of the installed binary and its systemd unit
+const AGENT_NAME: &str = "ssh-ip-tunnel-agent";
+
+/// Where the binary is installed on the device
+const INSTALL_PATH: &str = "/usr/local/bin/ssh-ip-tunnel-agent";
+
+/// Where the unit file is installed on the device
+const UNIT_PATH: &str = "/etc/systemd/system/ssh-ip-tunnel-agent.service";
+
+/// Upload location, relative to the login user's home directory
+const STAGING_PATH: &str = ".ssh-ip-tunnel-agent.new";
+
+/// Upper bound for the upload and for the install script
+const STEP_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Which agent build to install
+#[derive(Debug, Clone)]
+pub enum AgentBuild {
+    /// A specific file
+    Path(PathBuf),
+    /// The build matching the device's architecture, see [`artifact::select`]
+    Matching(String),
+}
+
+impl AgentBuild {
+    /// Fails if an explicitly given build is not a file, before any device is touched
+    pub fn check(&self) -> Result<(), TunnelError> {
+        match self {
+            AgentBuild::Path(binary) if !binary.is_file() => Err(TunnelError::AgentInstall(
+                format!("{} is not a file", binary.display()),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Result of `agent install`
+#[derive(Debug, Serialize)]
+pub struct AgentInstallReport {
+    pub run: RunReport,
+    pub binary: PathBuf,
+    pub sha256: String,
+    /// SHA-256 of the uploaded copy, computed on the device with `--verify`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_sha256: Option<String>,
+    pub installed_path: String,
+    pub unit: String,
+}
+
+impl Renderable for AgentInstallReport {
+    fn to_human(&self) -> String {
+        format!(
+            "{}\nInstalled {} to {}:{} and started {}.service\nSHA-256 {}{}",
+            self.run.to_human(),
+            self.binary.display(),
+            self.run.host,
+            self.installed_path,
+            self.unit,
+            self.sha256,
+            if self.remote_sha256.is_some() {
+                " (verified on the device)"
+            } else {
+                ""
+            }
+        )
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Provisions `target`, then installs the agent build as a service.
+///
+/// With `verify`, the uploaded binary is hashed on the device before it is installed.
+pub async fn install(
+    config: &Config,
+    target: &Target,
+    build: &AgentBuild,
+    verify: bool,
+) -> Result<AgentInstallReport> {
+    build.check()?;
+    let run = fleet::run_target(config, target).await?;
+    deploy(config, target, run, build, verify).await
+}
+
+/// Installs the agent build as a service on `target`, which `run` provisioned
+pub async fn deploy(
+    config: &Config,
+    target: &Target,
+    run: RunReport,
+    build: &AgentBuild,
+    verify: bool,
+) -> Result<AgentInstallReport> {
+    let binary = match build {
+        AgentBuild::Path(binary) => binary.clone(),
+        AgentBuild::Matching(source) => {
+            // Architecture validation may have been skipped, but the build still has to match
+            let machine = match &run.architecture {
+                Some(arch) => arch.clone(),
+                None => {
+                    SSHTunnelManager::new(config.clone())
+                        .detect_architecture(target)
+                        .await?
+                }
+            };
+            artifact::select(source, &machine).await?
+        }
+    };
+    let binary = binary.as_path();
+    let sha256 = checksum::sha256_file(binary).map_err(|e| {
+        TunnelError::AgentInstall(format!("Cannot read {}: {}", binary.display(), e))
+    })?;
+
+    info!("Uploading agent {}...", binary.display());
+    let upload = ssh::copy_through_tunnel(target, binary, STAGING_PATH)?;
+    run_step(upload, "upload").await?;
+
+    let remote_sha256 = if verify {
+        match checksum::verify_remote(target, STAGING_PATH, &sha256).await {
+            Ok(digest) => Some(digest),
+            Err(e) => {
+                // Never leave a corrupt binary where a retry might pick it up
+                let discard = RemoteCommand::new("rm").arg("-f").arg(STAGING_PATH);
+                if let Err(rm) = run_step(ssh::through_tunnel(target, &discard)?, "cleanup").await {
+                    warn!("Could not remove {}: {}", STAGING_PATH, rm);
+                }
+                return Err(e.into());
+            }
+        }
+    } else {
+        None
+    };
+
+    info!("Installing {} service...", AGENT_NAME);
+    let script = RemoteCommand::new("sh").arg("-c").arg(install_script());
+    let install = ssh::through_tunnel(target, &script)?;
+    run_step(install, "install").await?;
+
+    info!("Agent installed and started");
+    Ok(AgentInstallReport {
+        run,
+        binary: binary.to_path_buf(),
+        sha256,
+        remote_sha256,
+        installed_path: INSTALL_PATH.to_string(),
+        unit: AGENT_NAME.to_string(),
+    })
+}
+
+/// Runs one remote step, turning a failure into [`TunnelError::AgentInstall`]
+async fn run_step(cmd: Command, step: &str) -> Result<(), TunnelError> {
+    ssh::run(cmd, STEP_TIMEOUT).await.map(drop).map_err(|e| {
+        e.into_error(|message| TunnelError::AgentInstall(format!("{} failed: {}", step, message)))
+    })
+}
+
+/// The systemd unit that keeps the agent running
+fn unit_file() -> String {
+    format!(
+        "[Unit]
+Description=ssh_ip_tunnel agent
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+ExecStart={}
+Restart=on-failure
+RestartSec=5
+
+[Install]
+WantedBy=multi-user.target
+",
+        INSTALL_PATH
+    )
+}
+
+/// Shell script that moves the staged binary into place and (re)starts the unit
+fn install_script() -> String {
+    format!(
+        "set -e
+{as_root}
+as_root install -m 0755 {staged} {binary}
+rm -f {staged}
+printf '%s' {unit_text} | as_root tee {unit_path} >/dev/null
+as_root systemctl daemon-reload
+as_root systemctl enable {unit}.service
+as_root systemctl restart {unit}.service
+",
+        as_root = shell::AS_ROOT,
+        staged = shell::quote(STAGING_PATH),
+        binary = shell::quote(INSTALL_PATH),
+        unit_text = shell::quote(&unit_file()),
+        unit_path = shell::quote(UNIT_PATH),
+        unit = AGENT_NAME,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_install_script_writes_the_unit_verbatim() {
+        let dir = std::env::temp_dir().join(format!("agent-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Replace privileged commands with stubs and check the unit text survives quoting
+        let script = install_script()
+            .replace(UNIT_PATH, &dir.join("unit").display().to_string())
+            .replace("as_root install", "true")
+            .replace("as_root systemctl", "true");
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(script.replace("sudo -n ", ""))
+            .current_dir(&dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("unit")).unwrap(),
+            unit_file()
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,569 @@
+//! `up --watch --api-listen`: the control socket's operations over HTTP, for
+//! lab servers managing their tunnels from elsewhere.
+//!
+//! Every request needs `Authorization: Bearer <token>`, with the token from
+//! `SSH_IP_TUNNEL_API_TOKEN`; without one set, `up` refuses to start the API.
+//! It listens on `127.0.0.1:9200` unless given an address. Answers are JSON:
+//!
+//! - `GET /tunnels`: every watched tunnel on this machine, as `status` shows them
+//! - `GET /tunnels/<port>`: the statistics of the watch on local port `port`
+//! - `POST /tunnels/<port>/down`: close that tunnel and end its watch
+//! - `POST /tunnels/<port>/reconnect`: close that tunnel and open it again
+//! - `GET /device`: what `up` found about this watch's device
+//! - `GET /events`: this watch's events as they happen, one JSON object per line
+//! - `GET /exec?command=<command>`: runs `command` on this watch's device and
+//!   streams its output, one JSON object per line, ending with its exit code
+//! - `GET /logs?file=<path>` or `GET /logs?unit=<unit>`: the last `lines` (10
+//!   unless given) lines of a file or a systemd unit's journal on the device,
+//!   then whatever is added to it, until the client hangs up
+//!
+//! Tunnels of other watches are reached through their control sockets.
+//!
+//! At most [`MAX_SESSIONS`] commands and logs stream at once; more get
+//! `429 Too Many Requests`. Each buffers [`LINE_BACKLOG`] lines of at most
+//! [`MAX_LINE`] bytes for its client: one that reads slower than the device
+//! writes fills the buffer, after which reading stops and SSH flow control
+//! holds the device back.
+
+use crate::control::{self, Request, Response};
+use crate::env;
+use crate::executor::Stdio;
+use crate::health::{read_head, response};
+use crate::output::Renderable;
+use crate::shell::RemoteCommand;
+use crate::ssh;
+use crate::watch::{self, Live};
+use crate::{Target, TunnelError};
+use serde::Serialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
+
+/// Where the API listens when `--api-listen` has no address
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:9200";
+
+/// Commands and logs that can stream at once
+pub const MAX_SESSIONS: usize = 4;
+
+/// Lines a stream buffers for its client before holding the device back
+pub const LINE_BACKLOG: usize = 256;
+
+/// Longest line a stream sends; longer ones are split
+pub const MAX_LINE: usize = 16 * 1024;
+
+/// Lines of a log sent before following it, unless `lines` is given
+const DEFAULT_LOG_LINES: usize = 10;
+
+/// The token clients must send, from `SSH_IP_TUNNEL_API_TOKEN`
+pub fn token() -> Result<String, TunnelError> {
+    env::process_lookup("API_TOKEN").ok_or_else(|| {
+        TunnelError::Watch(format!(
+            "--api-listen needs a token in {}API_TOKEN",
+            env::PREFIX
+        ))
+    })
+}
+
+/// What requests reach
+struct Api {
+    token: String,
+    live: Live,
+    /// The watched tunnel
+    target: Target,
+    /// One per command or log that may stream
+    sessions: Arc<Semaphore>,
+}
+
+/// A running API; dropping it stops accepting connections
+pub struct ApiServer {
+    task: JoinHandle<()>,
+}
+
+impl ApiServer {
+    /// Starts serving the watch of `live`, of `target`'s tunnel, at `address`
+    pub async fn start(
+        address: SocketAddr,
+        token: String,
+        live: Live,
+        target: Target,
+    ) -> Result<Self, TunnelError> {
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(|e| TunnelError::Watch(format!("API on {}: {}", address, e)))?;
+        let address = listener.local_addr().unwrap_or(address);
+        info!("Serving the API on http://{}", address);
+        let api = Arc::new(Api {
+            token,
+            live,
+            target,
+            sessions: Arc::new(Semaphore::new(MAX_SESSIONS)),
+        });
+        let task = tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let api = api.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = api.serve(client).await {
+                        debug!("API: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(Self { task })
+    }
+}
+
+impl Drop for ApiServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Api {
+    /// Answers one client connection
+    async fn serve(&self, client: TcpStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(client);
+        let Some(head) = read_head(&mut reader).await? else {
+            return Ok(());
+        };
+        let mut client = reader.into_inner();
+        if !self.is_authorized(&head) {
+            let body = json!({ "error": "a bearer token is needed" }).to_string();
+            let answer = response("401 Unauthorized", "application/json", &body).replacen(
+                "\r\n",
+                "\r\nWWW-Authenticate: Bearer\r\n",
+                1,
+            );
+            return client.write_all(answer.as_bytes()).await;
+        }
+        let mut words = head.split_whitespace();
+        let (method, path) = (
+            words.next().unwrap_or_default(),
+            words.next().unwrap_or_default(),
+        );
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        let segments: Vec<&str> = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let (status, body) = match (method, segments.as_slice()) {
+            ("GET", ["events"]) => return self.stream_events(client).await,
+            ("GET", ["exec"]) => match parameter(query, "command") {
+                Some(command) => {
+                    let remote = RemoteCommand::new("sh").arg("-c").arg(command);
+                    return self.stream_output(client, &remote).await;
+                }
+                None => bad_request("`command` is needed"),
+            },
+            ("GET", ["logs"]) => match log_command(query) {
+                Ok(remote) => return self.stream_output(client, &remote).await,
+                Err(error) => bad_request(error),
+            },
+            ("GET", ["tunnels"]) => ("200 OK", watch::status(true).await.to_json()),
+            ("GET", ["device"]) => match self.live.report() {
+                Some(report) => ("200 OK", report.to_json()),
+                None => not_found("`up` hasn't finished"),
+            },
+            ("GET", ["tunnels", port]) => self.tunnel(port, Request::Status).await,
+            ("POST", ["tunnels", port, "down"]) => self.tunnel(port, Request::Down).await,
+            ("POST", ["tunnels", port, "reconnect"]) => self.tunnel(port, Request::Reconnect).await,
+            _ => not_found("no such resource"),
+        };
+        client
+            .write_all(response(status, "application/json", &body.to_string()).as_bytes())
+            .await
+    }
+
+    /// Whether `head` carries the token
+    fn is_authorized(&self, head: &str) -> bool {
+        head.lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+            .filter_map(|(_, value)| value.trim().strip_prefix("Bearer "))
+            .any(|token| same(token.trim().as_bytes(), self.token.as_bytes()))
+    }
+
+    /// Carries out `request` for the watch on local port `port`: this one
+    /// directly, others through their control socket
+    async fn tunnel(&self, port: &str, request: Request) -> (&'static str, serde_json::Value) {
+        let Ok(port) = port.parse::<u16>() else {
+            return not_found("no such port");
+        };
+        let answer = if port == self.target.port {
+            control::handle(request, &self.live).await
+        } else {
+            match control::request(port, request).await {
+                Some(answer) => answer.into(),
+                None => return not_found(&format!("no watch on port {}", port)),
+            }
+        };
+        match answer {
+            Response::Stats(stats) => ("200 OK", serde_json::to_value(stats).unwrap_or_default()),
+            Response::Error(e) => ("500 Internal Server Error", json!({ "error": e })),
+        }
+    }
+
+    /// Writes the watch's events to `client` as they happen, until it hangs up
+    async fn stream_events(&self, mut client: TcpStream) -> std::io::Result<()> {
+        let mut events = self.live.subscribe();
+        client
+            .write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\n\
+                  Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+            )
+            .await?;
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let mut line = serde_json::to_string(&event)?;
+                    line.push('\n');
+                    client.write_all(line.as_bytes()).await?;
+                }
+                Err(RecvError::Lagged(missed)) => debug!("API: {} events missed", missed),
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+
+    /// Runs `remote` on the device and writes its output to `client` as it
+    /// comes, then its exit code; stops it if the client hangs up first
+    async fn stream_output(
+        &self,
+        mut client: TcpStream,
+        remote: &RemoteCommand,
+    ) -> std::io::Result<()> {
+        let Ok(_session) = Arc::clone(&self.sessions).try_acquire_owned() else {
+            let body = json!({
+                "error": format!("all {} sessions are in use; close one first", MAX_SESSIONS)
+            });
+            let answer = response(
+                "429 Too Many Requests",
+                "application/json",
+                &body.to_string(),
+            );
+            return client.write_all(answer.as_bytes()).await;
+        };
+        let mut output = match self.spawn(remote) {
+            Ok(output) => output,
+            Err(e) => {
+                let body = json!({ "error": e.to_string() }).to_string();
+                let answer = response("500 Internal Server Error", "application/json", &body);
+                return client.write_all(answer.as_bytes()).await;
+            }
+        };
+        debug!("API: streaming {}", remote);
+        let (mut reader, mut writer) = client.split();
+        writer
+            .write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\n\
+                  Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+            )
+            .await?;
+        let mut ignored = [0; 64];
+        loop {
+            tokio::select! {
+                line = output.recv() => {
+                    let Some(line) = line else { return Ok(()) };
+                    let mut line = serde_json::to_string(&line)?;
+                    line.push('\n');
+                    writer.write_all(line.as_bytes()).await?;
+                }
+                // Clients send nothing more, so this ends when they hang up
+                read = reader.read(&mut ignored) => {
+                    if matches!(read, Ok(0) | Err(_)) {
+                        debug!("API: client left {}", remote);
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Starts `remote` through the tunnel, with its output arriving on the
+    /// returned channel; dropping that ends `ssh`
+    fn spawn(&self, remote: &RemoteCommand) -> Result<mpsc::Receiver<Output>, TunnelError> {
+        let mut command = ssh::through_tunnel(&self.target, remote)?;
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| TunnelError::Watch(format!("running {}: {}", remote, e)))?;
+        let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+        let (sender, output) = mpsc::channel(LINE_BACKLOG);
+        tokio::spawn(async move {
+            let forwarded = async {
+                tokio::join!(
+                    forward(stdout, &sender, |line| Output::Stdout { line }),
+                    forward(stderr, &sender, |line| Output::Stderr { line }),
+                )
+            };
+            tokio::select! {
+                _ = forwarded => {}
+                // The client left while the device was quiet, e.g. a log without new lines
+                _ = sender.closed() => {}
+            }
+            if sender.is_closed() {
+                let _ = child.kill().await;
+                return;
+            }
+            let code = child.wait().await.ok().and_then(|status| status.code());
+            let _ = sender.send(Output::Exit { code }).await;
+        });
+        Ok(output)
+    }
+}
+
+/// A line of a streamed command's output, or its end
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Output {
+    Stdout {
+        line: String,
+    },
+    Stderr {
+        line: String,
+    },
+    /// The command ended, with no code when a signal killed it. Always last
+    Exit {
+        code: Option<i32>,
+    },
+}
+
+/// Sends each line `reader` yields, waiting while the client's buffer is full
+async fn forward<R: AsyncRead + Unpin>(
+    reader: Option<R>,
+    sender: &mpsc::Sender<Output>,
+    output: impl Fn(String) -> Output,
+) {
+    let Some(reader) = reader else { return };
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match (&mut reader)
+            .take(MAX_LINE as u64)
+            .read_until(b'\n', &mut line)
+            .await
+        {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+        let text = String::from_utf8_lossy(&line).into_owned();
+        if sender.send(output(text)).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// The command following the log that `query` names
+fn log_command(query: &str) -> Result<RemoteCommand, &'static str> {
+    let lines = match parameter(query, "lines") {
+        Some(lines) => lines
+            .parse::<usize>()
+            .map_err(|_| "`lines` must be a number")?,
+        None => DEFAULT_LOG_LINES,
+    };
+    let lines = lines.to_string();
+    match (parameter(query, "file"), parameter(query, "unit")) {
+        (Some(file), None) => Ok(RemoteCommand::new("tail")
+            .arg("-F")
+            .arg("-n")
+            .arg(&lines)
+            .arg("--")
+            .arg(file)),
+        (None, Some(unit)) => Ok(RemoteCommand::new("journalctl")
+            .arg("--no-pager")
+            .arg("-f")
+            .arg("-n")
+            .arg(&lines)
+            .arg("-u")
+            .arg(unit)),
+        _ => Err("one of `file` or `unit` is needed"),
+    }
+}
+
+/// The decoded value of `name` in a URL query
+fn parameter(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| decode(value))
+}
+
+/// Undoes URL encoding: `%XX` escapes, and `+` for a space
+fn decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        let escaped = match (byte, after) {
+            (b'%', [high, low, ..]) => std::str::from_utf8(&[*high, *low])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(escaped) => {
+                bytes.push(escaped);
+                rest = &after[2..];
+            }
+            None => {
+                bytes.push(if byte == b'+' { b' ' } else { byte });
+                rest = after;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn bad_request(error: &str) -> (&'static str, serde_json::Value) {
+    ("400 Bad Request", json!({ "error": error }))
+}
+
+fn not_found(error: &str) -> (&'static str, serde_json::Value) {
+    ("404 Not Found", json!({ "error": error }))
+}
+
+/// Compares the token in time that doesn't depend on where it differs
+fn same(given: &[u8], token: &[u8]) -> bool {
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token)
+            .fold(0, |differs, (a, b)| differs | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::watch::Stats;
+    use crate::Target;
+    use chrono::Utc;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    async fn send(address: SocketAddr, head: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(format!("{}\r\n\r\n", head).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_requests_need_the_token() {
+        let live = Live::default();
+        let target = Target::builder("pi.local", "pi", &Config::default())
+            .build()
+            .unwrap();
+        live.set(&Stats::new(&target, Duration::from_secs(30), Utc::now()));
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let _server = ApiServer::start(address, "s3cret".to_string(), live, target)
+            .await
+            .unwrap();
+
+        let unauthorized = send(address, "GET /tunnels/2222 HTTP/1.1").await;
+        assert!(unauthorized.starts_with("HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer"));
+        let wrong = send(
+            address,
+            "GET /tunnels/2222 HTTP/1.1\r\nAuthorization: Bearer s3cre",
+        )
+        .await;
+        assert!(wrong.starts_with("HTTP/1.1 401"));
+
+        let tunnel = send(
+            address,
+            "GET /tunnels/2222 HTTP/1.1\r\nauthorization: Bearer s3cret",
+        )
+        .await;
+        assert!(tunnel.starts_with("HTTP/1.1 200 OK"), "{}", tunnel);
+        assert!(tunnel.contains(r#""host":"pi.local""#));
+
+        let device = send(
+            address,
+            "GET /device HTTP/1.1\r\nAuthorization: Bearer s3cret",
+        )
+        .await;
+        assert!(device.starts_with("HTTP/1.1 404"));
+        let other = send(
+            address,
+            "DELETE /tunnels/2222 HTTP/1.1\r\nAuthorization: Bearer s3cret",
+        )
+        .await;
+        assert!(other.starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_logs_and_commands_come_from_the_query() {
+        assert_eq!(
+            parameter("command=df+-h%20%2Fboot&x=1", "command").as_deref(),
+            Some("df -h /boot")
+        );
+        assert_eq!(parameter("commands=ls", "command"), None);
+        assert_eq!(
+            log_command("file=%2Fvar%2Flog%2Fmy+app.log&lines=50")
+                .unwrap()
+                .to_shell_string(),
+            "tail -F -n 50 -- '/var/log/my app.log'"
+        );
+        assert_eq!(
+            log_command("unit=myapp").unwrap().to_shell_string(),
+            "journalctl --no-pager -f -n 10 -u myapp"
+        );
+        assert_eq!(
+            log_command("unit=a&file=b").unwrap_err(),
+            "one of `file` or `unit` is needed"
+        );
+        assert_eq!(
+            log_command("unit=a&lines=all").unwrap_err(),
+            "`lines` must be a number"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streams_past_the_session_cap_are_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let api = Api {
+            token: String::new(),
+            live: Live::default(),
+            target: Target::builder("pi.local", "pi", &Config::default())
+                .build()
+                .unwrap(),
+            sessions: Arc::new(Semaphore::new(0)),
+        };
+        api.stream_output(server, &RemoteCommand::new("true"))
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 429 Too Many Requests"),
+            "{}",
+            response
+        );
+        assert!(response.contains("all 4 sessions are in use"));
+    }
+}
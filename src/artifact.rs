@@ -0,0 +1,194 @@
+//! Picking the build of an artifact that matches a device's architecture.
+//!
+//! An artifact source is either a directory of builds whose file names name
+//! the architecture (`agent-aarch64`, `agent-armv7`, ...), or a local path or
+//! URL pattern containing `{arch}`. A device that can run an older build falls
+//! back to it: an armv7 board takes an armv6 build when there is no armv7 one.
+
+use crate::fetch;
+use crate::paths;
+use crate::TunnelError;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Placeholder replaced by the architecture in artifact patterns
+pub const ARCH_PLACEHOLDER: &str = "{arch}";
+
+/// Maps `uname -m` output to the build architecture it runs natively
+pub fn arch_family(machine: &str) -> Option<&'static str> {
+    match machine {
+        "aarch64" | "aarch64_be" | "arm64" => Some("aarch64"),
+        // armv8l is a 32-bit userland on an ARMv8 core
+        m if m.starts_with("armv7") || m.starts_with("armv8") => Some("armv7"),
+        m if m.starts_with("armv6") => Some("armv6"),
+        _ => None,
+    }
+}
+
+/// Build architectures a device of `family` can run, best first
+fn compatible(family: &str) -> &'static [&'static str] {
+    match family {
+        "aarch64" => &["aarch64"],
+        "armv7" => &["armv7", "armv6"],
+        "armv6" => &["armv6"],
+        _ => &[],
+    }
+}
+
+/// Other spellings of an architecture found in build file names
+fn aliases(arch: &str) -> &'static [&'static str] {
+    match arch {
+        "aarch64" => &["aarch64", "arm64"],
+        "armv7" => &["armv7", "armv7l", "armv7hf", "armhf"],
+        "armv6" => &["armv6", "armv6l", "armv6hf"],
+        _ => &[],
+    }
+}
+
+/// Finds the build in `source` for a device reporting `machine`, downloading it if needed
+pub async fn select(source: &str, machine: &str) -> Result<PathBuf, TunnelError> {
+    let family = arch_family(machine).ok_or_else(|| {
+        TunnelError::NoMatchingArtifact(format!("no known build architecture for '{}'", machine))
+    })?;
+    let candidates = compatible(family);
+
+    let selected = if is_url(source) {
+        select_url(source, candidates).await?
+    } else if source.contains(ARCH_PLACEHOLDER) {
+        select_pattern(source, candidates)?
+    } else {
+        select_in_dir(&paths::expand_tilde(source)?, candidates)?
+    };
+
+    match selected {
+        Some(path) => {
+            info!("Selected {} for {}", path.display(), machine);
+            Ok(path)
+        }
+        None => Err(TunnelError::NoMatchingArtifact(format!(
+            "no {} build in {}",
+            candidates.join(" or "),
+            source
+        ))),
+    }
+}
+
+fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+async fn select_url(pattern: &str, candidates: &[&str]) -> Result<Option<PathBuf>, TunnelError> {
+    if !pattern.contains(ARCH_PLACEHOLDER) {
+        return Err(TunnelError::NoMatchingArtifact(format!(
+            "URL {} has no {} placeholder",
+            pattern, ARCH_PLACEHOLDER
+        )));
+    }
+    for arch in candidates {
+        let url = pattern.replace(ARCH_PLACEHOLDER, arch);
+        let name = url
+            .rsplit('/')
+            .next()
+            .filter(|n| !n.is_empty())
+            .unwrap_or("artifact");
+        let dest = paths::cache_dir().join("artifacts").join(arch).join(name);
+        if fetch::download(&url, &dest).await? {
+            return Ok(Some(dest));
+        }
+    }
+    Ok(None)
+}
+
+fn select_pattern(pattern: &str, candidates: &[&str]) -> Result<Option<PathBuf>, TunnelError> {
+    for arch in candidates {
+        let path = paths::expand_tilde(&pattern.replace(ARCH_PLACEHOLDER, arch))?;
+        if path.is_file() {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+fn select_in_dir(dir: &Path, candidates: &[&str]) -> Result<Option<PathBuf>, TunnelError> {
+    let entries = std::fs::read_dir(dir).map_err(|e| {
+        TunnelError::NoMatchingArtifact(format!("cannot read {}: {}", dir.display(), e))
+    })?;
+    let files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file())
+        .collect();
+
+    for arch in candidates {
+        let mut matches: Vec<&PathBuf> =
+            files.iter().filter(|path| names_arch(path, arch)).collect();
+        matches.sort();
+        match matches.as_slice() {
+            [] => continue,
+            [only] => return Ok(Some(only.to_path_buf())),
+            several => {
+                let names: Vec<String> = several.iter().map(|p| p.display().to_string()).collect();
+                return Err(TunnelError::NoMatchingArtifact(format!(
+                    "several {} builds in {}: {}",
+                    arch,
+                    dir.display(),
+                    names.join(", ")
+                )));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Whether a file name contains `arch` (or an alias) as a whole word
+fn names_arch(path: &Path, arch: &str) -> bool {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    name.split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .any(|word| aliases(arch).contains(&word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arch_family() {
+        assert_eq!(arch_family("aarch64"), Some("aarch64"));
+        assert_eq!(arch_family("armv7l"), Some("armv7"));
+        assert_eq!(arch_family("armv8l"), Some("armv7"));
+        assert_eq!(arch_family("armv6l"), Some("armv6"));
+        assert_eq!(arch_family("x86_64"), None);
+    }
+
+    #[tokio::test]
+    async fn test_directory_selection_falls_back_to_compatible_builds() {
+        let dir = std::env::temp_dir().join(format!("artifact-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["agent-arm64.bin", "agent-armv6l", "notes"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let source = dir.display().to_string();
+
+        assert_eq!(
+            select(&source, "aarch64").await.unwrap(),
+            dir.join("agent-arm64.bin")
+        );
+        assert_eq!(
+            select(&source, "armv7l").await.unwrap(),
+            dir.join("agent-armv6l")
+        );
+        std::fs::remove_file(dir.join("agent-armv6l")).unwrap();
+        let err = select(&source, "armv7l").await.unwrap_err().to_string();
+        assert!(err.contains("no armv7 or armv6 build"), "{}", err);
+
+        let pattern = format!("{}/agent-{{arch}}.bin", source);
+        std::fs::write(dir.join("agent-armv6.bin"), b"").unwrap();
+        assert_eq!(
+            select(&pattern, "armv6l").await.unwrap(),
+            dir.join("agent-armv6.bin")
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,110 @@
+//! Password logins without a terminal.
+//!
+//! `ssh` reads passwords only from a terminal or from an `SSH_ASKPASS` program.
+//! For `--password`, every `ssh` is given this binary as its askpass program; run
+//! that way it prints the password it was handed in its environment and exits.
+//! This needs OpenSSH 8.4 or later, for `SSH_ASKPASS_REQUIRE`.
+
+use crate::{Target, TunnelError};
+use std::io::Write;
+use tokio::process::Command;
+
+/// Set in the environment of the askpass child to select askpass mode
+const MODE_VAR: &str = "SSH_IP_TUNNEL_ASKPASS";
+
+/// Carries the password to the askpass child
+const PASSWORD_VAR: &str = "SSH_IP_TUNNEL_ASKPASS_PASSWORD";
+
+/// A login password; never printed by `Debug`
+#[derive(Clone, PartialEq, Eq)]
+pub struct Password(String);
+
+impl Password {
+    pub fn new(password: String) -> Self {
+        Self(password)
+    }
+}
+
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Password(***)")
+    }
+}
+
+/// Asks for the password on the terminal without echoing it
+pub fn ask(prompt: &str) -> std::io::Result<String> {
+    rpassword::prompt_password(prompt)
+}
+
+/// If this process was started by `ssh` as its askpass program, answers it and returns `true`
+pub fn respond_if_invoked() -> bool {
+    if std::env::var_os(MODE_VAR).is_none() {
+        return false;
+    }
+    let password = std::env::var(PASSWORD_VAR).unwrap_or_default();
+    let mut stdout = std::io::stdout();
+    let _ = writeln!(stdout, "{}", password);
+    let _ = stdout.flush();
+    true
+}
+
+/// Makes `cmd` (an `ssh`, or a program that runs one) answer password prompts for `target`
+pub fn configure(cmd: &mut Command, target: &Target) -> Result<(), TunnelError> {
+    let Some(Password(password)) = &target.password else {
+        return Ok(());
+    };
+    let program = std::env::current_exe().map_err(|e| {
+        TunnelError::TunnelCreation(format!(
+            "Cannot locate own executable for SSH_ASKPASS: {}",
+            e
+        ))
+    })?;
+    cmd.env("SSH_ASKPASS", program)
+        .env("SSH_ASKPASS_REQUIRE", "force")
+        .env(MODE_VAR, "1")
+        .env(PASSWORD_VAR, password);
+    Ok(())
+}
+
+/// Makes `cmd` (`ssh-add`) fail instead of asking for a passphrase, by
+/// answering with an empty one
+pub fn refuse(cmd: &mut Command) -> Result<(), TunnelError> {
+    let program = std::env::current_exe().map_err(|e| {
+        TunnelError::NoIdentity(format!(
+            "Cannot locate own executable for SSH_ASKPASS: {}",
+            e
+        ))
+    })?;
+    cmd.env("SSH_ASKPASS", program)
+        .env("SSH_ASKPASS_REQUIRE", "force")
+        .env(MODE_VAR, "1")
+        .env_remove(PASSWORD_VAR);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_is_redacted_and_passed_to_askpass() {
+        let password = Password::new("hunter2".to_string());
+        assert_eq!(format!("{:?}", password), "Password(***)");
+
+        let target = Target {
+            password: Some(password),
+            ..Target::default()
+        };
+        let mut cmd = Command::new("ssh");
+        configure(&mut cmd, &target).unwrap();
+        let envs: Vec<_> = cmd.as_std().get_envs().collect();
+        assert!(envs.contains(&(
+            std::ffi::OsStr::new(PASSWORD_VAR),
+            Some(std::ffi::OsStr::new("hunter2"))
+        )));
+        assert!(envs.contains(&(
+            std::ffi::OsStr::new("SSH_ASKPASS_REQUIRE"),
+            Some(std::ffi::OsStr::new("force"))
+        )));
+    }
+}
@@ -0,0 +1,957 @@
+//! Managing the remote user's `~/.ssh/authorized_keys` through the tunnel.
+//!
+//! Keys are installed with a small POSIX shell script rather than
+//! `ssh-copy-id`, which minimal images and Windows machines don't have. It
+//! creates `~/.ssh` (mode 700) and `authorized_keys` (mode 600) when missing,
+//! and replaces the file in one `mv`, so a dropped connection can't leave it
+//! half written and lock the user out. Revoking a key rewrites the file the
+//! same way; listing keys only reads it.
+//!
+//! With `--target-user`, the file is another account's: the scripts run as
+//! that user through `sudo -u` (or `su` when logged in as root), after the
+//! account is created if the device doesn't have it.
+//!
+//! Before the file is replaced, the old one is copied to
+//! `authorized_keys.bak-<UTC time>` next to it, keeping the newest ten, so
+//! `keys restore-backup` can undo a bad deployment while the device is
+//! still reachable.
+
+use crate::config::Config;
+use crate::keys::{self, PublicKey};
+use crate::output::{self, Renderable};
+use crate::paths;
+use crate::shell::{self, RemoteCommand};
+use crate::ssh;
+use crate::ssh_agent;
+use crate::validate;
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Upper bound for reading or rewriting the file
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Upper bound for a login with one key
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Defines `back_up`, which copies the file `$1` to `$1.bak-<UTC time>`,
+/// prints `backup <path>` and deletes all but the newest ten backups; the
+/// scripts that replace authorized_keys need it (see [`with_back_up`])
+pub const BACK_UP: &str = r#"back_up() {
+  stamp="$1.bak-$(date -u +%Y%m%dT%H%M%SZ)"
+  backup="$stamp"; n=0
+  while [ -e "$backup" ]; do n=$((n + 1)); backup="$stamp.$n"; done
+  cp -p "$1" "$backup"
+  echo "backup $backup"
+  ls -1 "$1".bak-* | sort -r | tail -n +11 | while read -r old; do rm -f "$old"; done
+}"#;
+
+/// Adds the key line `$1` to authorized_keys, first dropping every entry with
+/// the key data `$2` so the key is listed exactly once
+pub const INSTALL_SCRIPT: &str = r#"set -e
+umask 077
+dir="$HOME/.ssh"
+file="$dir/authorized_keys"
+mkdir -p "$dir"
+chmod 700 "$dir"
+tmp="$file.ssh-ip-tunnel.$$"
+if [ -f "$file" ]; then
+  awk -v data="$2" '{ for (i = 1; i <= NF; i++) if ($i == data) next; print }' "$file" > "$tmp"
+else
+  : > "$tmp"
+fi
+if [ -s "$tmp" ] && [ -n "$(tail -c1 "$tmp")" ]; then echo >> "$tmp"; fi
+printf '%s\n' "$1" >> "$tmp"
+chmod 600 "$tmp"
+if [ -f "$file" ]; then back_up "$file"; fi
+mv -f "$tmp" "$file"
+if command -v restorecon >/dev/null 2>&1; then restorecon -F "$dir" "$file" 2>/dev/null || true; fi"#;
+
+/// Checks what sshd's StrictModes checks before it reads authorized_keys, and
+/// fixes what it can, as root if need be: the home directory must belong to
+/// the user or root and not be writable by others, and `~/.ssh` and the file
+/// must belong to the user with modes 700 and 600. Prints `fixed <problem>`
+/// or `unfixable <problem>` per problem found.
+pub const PERMISSIONS_SCRIPT: &str = r#"uid=$(id -u)
+gid=$(id -g)
+perms() { ls -nd "$1" | awk '{ print $1 }'; }
+owner() { ls -nd "$1" | awk '{ print $3 }'; }
+fix() {
+  problem=$1
+  shift
+  if "$@" 2>/dev/null || as_root "$@" 2>/dev/null; then echo "fixed $problem"; else echo "unfixable $problem"; fi
+}
+home_owner=$(owner "$HOME")
+if [ "$home_owner" != "$uid" ] && [ "$home_owner" != 0 ]; then
+  fix "$HOME is owned by uid $home_owner" chown "$uid:$gid" "$HOME"
+fi
+case "$(perms "$HOME")" in
+  ?????w*|????????w*) fix "$HOME is writable by others ($(perms "$HOME"))" chmod go-w "$HOME" ;;
+esac
+for path in "$HOME/.ssh" "$HOME/.ssh/authorized_keys"; do
+  [ -e "$path" ] || continue
+  path_owner=$(owner "$path")
+  if [ "$path_owner" != "$uid" ]; then
+    fix "$path is owned by uid $path_owner" chown "$uid:$gid" "$path"
+  fi
+done
+if [ -d "$HOME/.ssh" ]; then
+  case "$(perms "$HOME/.ssh")" in
+    d???------*) ;;
+    *) fix "$HOME/.ssh has mode $(perms "$HOME/.ssh")" chmod 700 "$HOME/.ssh" ;;
+  esac
+fi
+if [ -f "$HOME/.ssh/authorized_keys" ]; then
+  case "$(perms "$HOME/.ssh/authorized_keys")" in
+    -???------*) ;;
+    *) fix "$HOME/.ssh/authorized_keys has mode $(perms "$HOME/.ssh/authorized_keys")" chmod 600 "$HOME/.ssh/authorized_keys" ;;
+  esac
+fi"#;
+
+/// Runs the script `$2` as the user `$1`, with the arguments after it: through
+/// sudo, or su when already root
+pub const AS_USER_SCRIPT: &str = r#"user=$1 script=$2
+shift 2
+if [ "$(id -u)" -eq 0 ]; then
+  exec su -s /bin/sh -c "$script" -- "$user" sh "$@"
+fi
+exec sudo -n -H -u "$user" sh -c "$script" sh "$@""#;
+
+/// Creates the user `$1` with a home directory if there is none, and prints
+/// `created` when it did
+pub const CREATE_USER_SCRIPT: &str = r#"set -e
+if id "$1" >/dev/null 2>&1; then exit 0; fi
+if command -v useradd >/dev/null 2>&1; then as_root useradd -m -s /bin/sh "$1"; else as_root adduser -D -s /bin/sh "$1"; fi
+echo created"#;
+
+/// Prints authorized_keys, or nothing when there is none
+pub const READ_SCRIPT: &str = "cat ~/.ssh/authorized_keys 2>/dev/null || true";
+
+/// Removes every entry with one of the key data in `$1` (one per line) and
+/// prints how many were removed last; the file is left alone when none match
+pub const REVOKE_SCRIPT: &str = r#"set -e
+umask 077
+file="$HOME/.ssh/authorized_keys"
+if [ ! -f "$file" ]; then echo 0; exit 0; fi
+tmp="$file.ssh-ip-tunnel.$$"
+: > "$tmp"
+removed=$(awk -v list="$1" -v out="$tmp" '
+  BEGIN { n = split(list, data, "\n"); for (i = 1; i <= n; i++) drop[data[i]] = 1 }
+  { for (i = 1; i <= NF; i++) if ($i in drop) { removed++; next }; print > out }
+  END { print removed + 0 }' "$file")
+if [ "$removed" -eq 0 ]; then rm -f "$tmp"; echo 0; exit 0; fi
+chmod 600 "$tmp"
+back_up "$file"
+mv -f "$tmp" "$file"
+if command -v restorecon >/dev/null 2>&1; then restorecon -F "$file" 2>/dev/null || true; fi
+echo "$removed""#;
+
+/// Puts back the backup named `$1` in ~/.ssh, or the newest one when `$1` is
+/// empty, after backing up the current file; prints the backup restored.
+/// Other names than `authorized_keys.bak-*` are refused.
+pub const RESTORE_SCRIPT: &str = r#"set -e
+umask 077
+file="$HOME/.ssh/authorized_keys"
+restore=
+if [ -z "$1" ]; then
+  restore=$(ls -1 "$file".bak-* 2>/dev/null | sort | tail -n 1)
+else
+  case "$1" in
+    */*) ;;
+    authorized_keys.bak-*) restore="$HOME/.ssh/$1" ;;
+  esac
+  if [ -z "$restore" ]; then echo "$1 is not a backup of authorized_keys" >&2; exit 1; fi
+fi
+if [ -z "$restore" ] || [ ! -f "$restore" ]; then
+  echo "no backup ${1:-of authorized_keys} in ~/.ssh" >&2
+  exit 1
+fi
+tmp="$file.ssh-ip-tunnel.$$"
+cp "$restore" "$tmp"
+chmod 600 "$tmp"
+if [ -f "$file" ]; then back_up "$file"; fi
+mv -f "$tmp" "$file"
+if command -v restorecon >/dev/null 2>&1; then restorecon -F "$file" 2>/dev/null || true; fi
+echo "restored $restore""#;
+
+/// Which authorized_keys entries `keys revoke` removes
+#[derive(Debug, Clone)]
+pub enum KeyMatch {
+    /// The key with this fingerprint, as `ssh-keygen -l` prints it
+    Fingerprint(String),
+    /// Keys with exactly this comment
+    Comment(String),
+    /// This key
+    Key(PublicKey),
+}
+
+impl KeyMatch {
+    fn matches(&self, key: &PublicKey) -> bool {
+        match self {
+            KeyMatch::Fingerprint(fingerprint) => key.fingerprint() == *fingerprint,
+            KeyMatch::Comment(comment) => key.comment == *comment,
+            KeyMatch::Key(wanted) => key.data == wanted.data,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            KeyMatch::Fingerprint(fingerprint) => fingerprint.clone(),
+            KeyMatch::Comment(comment) => format!("comment {:?}", comment),
+            KeyMatch::Key(key) => key.fingerprint(),
+        }
+    }
+}
+
+/// A key removed from authorized_keys
+#[derive(Debug, Clone, Serialize)]
+pub struct RevokedKey {
+    pub key_type: String,
+    pub fingerprint: String,
+    pub comment: String,
+}
+
+/// Result of `keys revoke`
+#[derive(Debug, Clone, Serialize)]
+pub struct RevokeReport {
+    pub host: String,
+    /// What was looked for: a fingerprint or `comment "..."`
+    pub matching: String,
+    /// Number of authorized_keys lines deleted
+    pub removed: usize,
+    pub keys: Vec<RevokedKey>,
+    /// Where the file was backed up before the keys were removed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup: Option<String>,
+}
+
+impl Renderable for RevokeReport {
+    fn to_human(&self) -> String {
+        if self.removed == 0 {
+            return format!(
+                "No authorized_keys entry on {} matches {}",
+                self.host, self.matching
+            );
+        }
+        let mut text = format!(
+            "Removed {} entr{} from the authorized_keys of {}:\n",
+            self.removed,
+            if self.removed == 1 { "y" } else { "ies" },
+            self.host
+        );
+        let rows: Vec<Vec<String>> = self
+            .keys
+            .iter()
+            .map(|key| {
+                vec![
+                    key.key_type.clone(),
+                    key.fingerprint.clone(),
+                    key.comment.clone(),
+                ]
+            })
+            .collect();
+        text.push_str(&output::table(&["TYPE", "FINGERPRINT", "COMMENT"], &rows));
+        if let Some(backup) = &self.backup {
+            text.push_str(&format!(
+                "\nThe previous file is kept as {}; undo with keys restore-backup",
+                backup
+            ));
+        }
+        text
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// One key line of an authorized_keys file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Options in front of the key, e.g. `restrict,from="10.0.0.0/8"`
+    pub options: String,
+    pub key: PublicKey,
+}
+
+/// A key in the remote authorized_keys, as `keys list` shows it
+#[derive(Debug, Clone, Serialize)]
+pub struct ListedKey {
+    pub key_type: String,
+    pub fingerprint: String,
+    pub comment: String,
+    pub options: String,
+    /// Where this machine has the same key: a `.pub` file or `ssh-agent`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local: Option<String>,
+}
+
+/// Result of `keys list`
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyListReport {
+    pub host: String,
+    pub user: String,
+    pub keys: Vec<ListedKey>,
+}
+
+impl Renderable for KeyListReport {
+    fn to_human(&self) -> String {
+        if self.keys.is_empty() {
+            return format!("No keys are authorized for {} on {}", self.user, self.host);
+        }
+        let rows: Vec<Vec<String>> = self
+            .keys
+            .iter()
+            .map(|key| {
+                let or_dash = |value: &str| {
+                    if value.is_empty() {
+                        "-".to_string()
+                    } else {
+                        value.to_string()
+                    }
+                };
+                vec![
+                    key.key_type.clone(),
+                    key.fingerprint.clone(),
+                    or_dash(&key.comment),
+                    or_dash(&key.options),
+                    or_dash(key.local.as_deref().unwrap_or_default()),
+                ]
+            })
+            .collect();
+        format!(
+            "{} key{} authorized for {} on {}:\n{}",
+            self.keys.len(),
+            if self.keys.len() == 1 { "" } else { "s" },
+            self.user,
+            self.host,
+            output::table(
+                &["TYPE", "FINGERPRINT", "COMMENT", "OPTIONS", "LOCAL"],
+                &rows
+            )
+        )
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Fingerprints of this machine's keys, each with where it was found: the
+/// target's key, `~/.ssh/*.pub` or ssh-agent
+async fn local_keys(target: &Target) -> BTreeMap<String, String> {
+    let mut local = BTreeMap::new();
+    let mut add = |path: &Path, label: String| {
+        if let Ok(key) = keys::read_public_key(path) {
+            local.entry(key.fingerprint()).or_insert(label);
+        }
+    };
+    if let Ok(path) = paths::expand_tilde(&target.key_path) {
+        add(&path, target.key_path.clone());
+    }
+    if let Ok(home) = paths::home_dir() {
+        let mut files: Vec<_> = std::fs::read_dir(home.join(".ssh"))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "pub"))
+            .collect();
+        files.sort();
+        for path in files {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            add(&path, format!("~/.ssh/{}", name));
+        }
+    }
+    if let Ok(Some(identities)) = ssh_agent::identities().await {
+        for identity in identities {
+            local
+                .entry(identity.fingerprint)
+                .or_insert_with(|| "ssh-agent".to_string());
+        }
+    }
+    local
+}
+
+/// Opens the tunnel and lists the remote user's authorized keys, marking
+/// those this machine has too
+pub async fn list(config: &Config, target: &Target) -> Result<KeyListReport> {
+    SSHTunnelManager::new(config.clone())
+        .connect(target)
+        .await?;
+
+    let authorized = read(target).await?;
+    let local = local_keys(target).await;
+    let keys = entries(&authorized)
+        .into_iter()
+        .map(|entry| {
+            let fingerprint = entry.key.fingerprint();
+            ListedKey {
+                local: local.get(&fingerprint).cloned(),
+                key_type: entry.key.key_type,
+                fingerprint,
+                comment: entry.key.comment,
+                options: entry.options,
+            }
+        })
+        .collect();
+    Ok(KeyListReport {
+        host: target.host.clone(),
+        user: target.key_user().to_string(),
+        keys,
+    })
+}
+
+/// The keys listed in an authorized_keys file, skipping comments
+pub fn entries(authorized_keys: &str) -> Vec<Entry> {
+    authorized_keys
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            // The key starts at its type, after options that may hold quoted spaces
+            std::iter::once(0)
+                .chain(
+                    line.match_indices(|c: char| c.is_ascii_whitespace())
+                        .map(|(i, _)| i + 1),
+                )
+                .filter(|&i| {
+                    ["ssh-", "ecdsa-", "sk-"]
+                        .iter()
+                        .any(|prefix| line[i..].starts_with(prefix))
+                })
+                .find_map(|i| {
+                    Some(Entry {
+                        options: line[..i].trim().to_string(),
+                        key: PublicKey::parse(&line[i..]).ok()?,
+                    })
+                })
+        })
+        .collect()
+}
+
+/// Opens the tunnel and removes the keys `matching` from the remote user's
+/// authorized_keys
+pub async fn revoke(config: &Config, target: &Target, matching: KeyMatch) -> Result<RevokeReport> {
+    if let KeyMatch::Fingerprint(fingerprint) = &matching {
+        validate::validate_fingerprint(fingerprint).map_err(|_| {
+            anyhow::anyhow!(
+                "'{}' is not a key fingerprint as printed by ssh-keygen -l (SHA256:...)",
+                fingerprint
+            )
+        })?;
+    }
+
+    SSHTunnelManager::new(config.clone())
+        .connect(target)
+        .await?;
+
+    let found: Vec<PublicKey> = entries(&read(target).await?)
+        .into_iter()
+        .map(|entry| entry.key)
+        .filter(|key| matching.matches(key))
+        .collect();
+    let mut report = RevokeReport {
+        host: target.host.clone(),
+        matching: matching.describe(),
+        removed: 0,
+        keys: Vec::new(),
+        backup: None,
+    };
+    if found.is_empty() {
+        return Ok(report);
+    }
+
+    info!(
+        "Removing {} from the authorized_keys of {}...",
+        report.matching, target.host
+    );
+    let mut data: Vec<&str> = found.iter().map(|key| key.data.as_str()).collect();
+    data.dedup();
+    (report.removed, report.backup) = remove(target, &[], &data).await?;
+    report.keys = found
+        .into_iter()
+        .map(|key| RevokedKey {
+            fingerprint: key.fingerprint(),
+            key_type: key.key_type,
+            comment: key.comment,
+        })
+        .collect();
+
+    let login_key = paths::expand_tilde(&target.key_path)
+        .and_then(|path| keys::read_public_key(&path))
+        .ok();
+    if login_key.is_some_and(|login| {
+        report
+            .keys
+            .iter()
+            .any(|key| key.fingerprint == login.fingerprint())
+    }) {
+        warn!(
+            "The key {} was revoked; logging in to {} as {} will need another key or a password",
+            target.key_path,
+            target.host,
+            target.key_user()
+        );
+    }
+    Ok(report)
+}
+
+/// Removes every entry with one of the key `data` from the remote user's
+/// authorized_keys, logging in with `options` added, and returns how many
+/// lines went and the backup made
+pub async fn remove(
+    target: &Target,
+    options: &[String],
+    data: &[&str],
+) -> Result<(usize, Option<String>), TunnelError> {
+    let command = script(target, &with_back_up(REVOKE_SCRIPT)).arg(data.join("\n"));
+    let stdout = run_with(target, options, &command, "updating").await?;
+    let removed = stdout
+        .lines()
+        .last()
+        .unwrap_or_default()
+        .trim()
+        .parse()
+        .map_err(|_| {
+            TunnelError::KeyTransfer(format!(
+                "unexpected output from the device: {:?}",
+                stdout.trim()
+            ))
+        })?;
+    Ok((removed, backup_name(&stdout)))
+}
+
+/// Repairs the ownership and modes sshd insists on before it accepts keys
+/// from authorized_keys, returning what was fixed. Problems that can't be
+/// fixed, such as a `~/.ssh` owned by root without sudo, are an error, since
+/// key logins will be refused until they are.
+pub async fn fix_permissions(target: &Target) -> Result<Vec<String>, TunnelError> {
+    let command = script(
+        target,
+        &format!("{}\n{}", shell::AS_ROOT, PERMISSIONS_SCRIPT),
+    );
+    let stdout = run(target, &command, "checking permissions of").await?;
+    let mut fixed = Vec::new();
+    let mut unfixable = Vec::new();
+    for line in stdout.lines() {
+        if let Some(problem) = line.strip_prefix("fixed ") {
+            warn!("Fixed on {}: {}", target.host, problem);
+            fixed.push(problem.to_string());
+        } else if let Some(problem) = line.strip_prefix("unfixable ") {
+            unfixable.push(problem.to_string());
+        }
+    }
+    if !unfixable.is_empty() {
+        return Err(TunnelError::SshPermissions(format!(
+            "{}; fix this as root on {} (or give {} passwordless sudo)",
+            unfixable.join("; "),
+            target.host,
+            target.key_user()
+        )));
+    }
+    Ok(fixed)
+}
+
+/// The remote user's authorized_keys, empty when there is none yet
+pub async fn read(target: &Target) -> Result<String, TunnelError> {
+    let command = script(target, READ_SCRIPT);
+    run(target, &command, "reading").await
+}
+
+/// Adds `key` with `options` to the remote user's authorized_keys, replacing
+/// any entries it already has
+pub async fn install(
+    target: &Target,
+    key: &PublicKey,
+    options: &[String],
+) -> Result<(), TunnelError> {
+    let command = script(target, &with_back_up(INSTALL_SCRIPT))
+        .arg(keys::authorized_keys_entry(key, options))
+        .arg(&key.data);
+    let stdout = run(target, &command, "updating").await?;
+    if let Some(backup) = backup_name(&stdout) {
+        info!("The previous authorized_keys is kept as ~/.ssh/{}", backup);
+    }
+    Ok(())
+}
+
+/// Result of `keys restore-backup`
+#[derive(Debug, Clone, Serialize)]
+pub struct RestoreReport {
+    pub host: String,
+    /// The backup put back, e.g. `authorized_keys.bak-20240101T120000Z`
+    pub restored: String,
+    /// Where the replaced file was backed up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup: Option<String>,
+    /// Keys in the restored file
+    pub keys: usize,
+}
+
+impl Renderable for RestoreReport {
+    fn to_human(&self) -> String {
+        let mut text = format!(
+            "Restored the authorized_keys of {} from {} ({} key{})",
+            self.host,
+            self.restored,
+            self.keys,
+            if self.keys == 1 { "" } else { "s" }
+        );
+        if let Some(backup) = &self.backup {
+            text.push_str(&format!("; the replaced file is kept as {}", backup));
+        }
+        text
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Opens the tunnel and puts back a backup of the remote user's
+/// authorized_keys: `backup` by name, or else the newest
+pub async fn restore_backup(
+    config: &Config,
+    target: &Target,
+    backup: Option<&str>,
+) -> Result<RestoreReport> {
+    if let Some(name) = backup {
+        if !name.starts_with("authorized_keys.bak-") || name.contains('/') {
+            anyhow::bail!(
+                "'{}' is not an authorized_keys backup; names look like authorized_keys.bak-20240101T120000Z",
+                name
+            );
+        }
+    }
+
+    SSHTunnelManager::new(config.clone())
+        .connect(target)
+        .await?;
+
+    info!("Restoring the authorized_keys of {}...", target.host);
+    let command = script(target, &with_back_up(RESTORE_SCRIPT)).arg(backup.unwrap_or_default());
+    let stdout = run(target, &command, "restoring").await?;
+    let restored = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("restored "))
+        .and_then(|path| path.rsplit('/').next())
+        .ok_or_else(|| anyhow::anyhow!("unexpected output from the device: {:?}", stdout.trim()))?
+        .to_string();
+
+    let restored_keys = entries(&read(target).await?);
+    let login_key = paths::expand_tilde(&target.key_path)
+        .and_then(|path| keys::read_public_key(&path))
+        .ok();
+    if let Some(login) = login_key {
+        if !restored_keys
+            .iter()
+            .any(|entry| entry.key.data == login.data)
+        {
+            warn!(
+                "The restored authorized_keys doesn't list {}; logging in to {} as {} will need another key or a password",
+                target.key_path, target.host, target.key_user()
+            );
+        }
+    }
+    Ok(RestoreReport {
+        host: target.host.clone(),
+        restored,
+        backup: backup_name(&stdout),
+        keys: restored_keys.len(),
+    })
+}
+
+/// The target and `ssh` options for a fresh login, as the user the key is
+/// for, with nothing but the key of `public_key`: its private key file, or
+/// the agent's copy when there is no such file. Passwords, other keys and
+/// prompts are all ruled out, so the login succeeding proves the key works.
+pub fn key_login(target: &Target, public_key: &Path) -> (Target, Vec<String>) {
+    let identity = keys::private_key_path(public_key)
+        .ok()
+        .filter(|private| private.exists())
+        .unwrap_or_else(|| public_key.to_path_buf());
+    let mut login = target.clone();
+    login.identity_file = None;
+    login.password = None;
+    if let Some(user) = login.target_user.take() {
+        login.user = user;
+    }
+    let options = [
+        ssh::path_option("IdentityFile", &identity),
+        "IdentitiesOnly=yes".to_string(),
+        "PreferredAuthentications=publickey".to_string(),
+        "BatchMode=yes".to_string(),
+        "ControlPath=none".to_string(),
+    ]
+    .into_iter()
+    .flat_map(|option| ["-o".to_string(), option])
+    .collect();
+    (login, options)
+}
+
+/// Logs in as set up by [`key_login`] and runs `true`
+pub async fn verify_login(login: &Target, options: &[String]) -> Result<(), TunnelError> {
+    let probe = RemoteCommand::new("true");
+    ssh::run(
+        ssh::through_tunnel_with(login, options, &probe)?,
+        LOGIN_TIMEOUT,
+    )
+    .await
+    .map(drop)
+    .map_err(|e| e.into_error(TunnelError::KeyVerification))
+}
+
+/// Creates the target's `--target-user` through sudo if the device doesn't
+/// have it yet, returning whether it did
+pub async fn ensure_user(target: &Target) -> Result<bool, TunnelError> {
+    let Some(user) = &target.target_user else {
+        return Ok(false);
+    };
+    let command = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(format!("{}\n{}", shell::AS_ROOT, CREATE_USER_SCRIPT))
+        .arg("sh")
+        .arg(user);
+    let created = run(target, &command, "creating the user for").await?.trim() == "created";
+    if created {
+        info!("Created user {} on {}", user, target.host);
+    }
+    Ok(created)
+}
+
+/// `sh -c script` with `$0` set, to append `$1...` to, running as the user
+/// whose keys are managed: the login user, or the target's `--target-user`
+fn script(target: &Target, script: &str) -> RemoteCommand {
+    let command = RemoteCommand::new("sh").arg("-c");
+    match &target.target_user {
+        Some(user) => command.arg(AS_USER_SCRIPT).arg("sh").arg(user).arg(script),
+        None => command.arg(script).arg("sh"),
+    }
+}
+
+/// `script` after the definition of [`BACK_UP`]
+pub fn with_back_up(script: &str) -> String {
+    format!("{}\n{}", BACK_UP, script)
+}
+
+/// The file name of the backup a script reported making, if it made one
+fn backup_name(stdout: &str) -> Option<String> {
+    stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("backup "))
+        .and_then(|path| path.rsplit('/').next())
+        .map(str::to_string)
+}
+
+/// Runs `command`, `action` on authorized_keys, returning its stdout
+async fn run(
+    target: &Target,
+    command: &RemoteCommand,
+    action: &str,
+) -> Result<String, TunnelError> {
+    run_with(target, &[], command, action).await
+}
+
+async fn run_with(
+    target: &Target,
+    options: &[String],
+    command: &RemoteCommand,
+    action: &str,
+) -> Result<String, TunnelError> {
+    ssh::run(ssh::through_tunnel_with(target, options, command)?, TIMEOUT)
+        .await
+        .map_err(|e| {
+            e.into_error(|message| {
+                TunnelError::KeyTransfer(format!(
+                    "{} the device's authorized_keys failed: {}",
+                    action, message
+                ))
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_install_script_adds_key_once_with_private_modes() {
+        let home =
+            std::env::temp_dir().join(format!("authorized_keys_test_{}", std::process::id()));
+        let ssh_dir = home.join(".ssh");
+        std::fs::create_dir_all(&ssh_dir).unwrap();
+        std::fs::write(
+            ssh_dir.join("authorized_keys"),
+            "ssh-ed25519 AAAAother other@host\nrestrict ssh-ed25519 AAAAkey old@host\nssh-ed25519 AAAAkey dup@host",
+        )
+        .unwrap();
+
+        for _ in 0..2 {
+            let output = Command::new("sh")
+                .env("HOME", &home)
+                .args([
+                    "-c",
+                    &with_back_up(INSTALL_SCRIPT),
+                    "sh",
+                    "ssh-ed25519 AAAAkey new@host",
+                    "AAAAkey",
+                ])
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+        }
+
+        let contents = std::fs::read_to_string(ssh_dir.join("authorized_keys")).unwrap();
+        assert_eq!(
+            contents,
+            "ssh-ed25519 AAAAother other@host\nssh-ed25519 AAAAkey new@host\n"
+        );
+        let backups: Vec<_> = std::fs::read_dir(&ssh_dir)
+            .unwrap()
+            .flatten()
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("authorized_keys.bak-")
+            })
+            .collect();
+        assert!(!backups.is_empty());
+        let mode = |path: &std::path::Path| {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+        };
+        assert_eq!(mode(&ssh_dir), 0o700);
+        assert_eq!(mode(&ssh_dir.join("authorized_keys")), 0o600);
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_permissions_script_fixes_modes() {
+        use std::os::unix::fs::PermissionsExt;
+        let home = std::env::temp_dir().join(format!("permissions_test_{}", std::process::id()));
+        let ssh_dir = home.join(".ssh");
+        std::fs::create_dir_all(&ssh_dir).unwrap();
+        std::fs::write(ssh_dir.join("authorized_keys"), "").unwrap();
+        let set_mode = |path: &std::path::Path, mode| {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap()
+        };
+        set_mode(&home, 0o777);
+        set_mode(&ssh_dir, 0o775);
+        set_mode(&ssh_dir.join("authorized_keys"), 0o644);
+
+        let run = || {
+            let output = Command::new("sh")
+                .env("HOME", &home)
+                .args(["-c", &format!("{}\n{}", shell::AS_ROOT, PERMISSIONS_SCRIPT)])
+                .output()
+                .unwrap();
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        };
+        let report = run();
+        assert_eq!(report.lines().count(), 3, "{}", report);
+        assert!(report.lines().all(|line| line.starts_with("fixed ")));
+        let mode =
+            |path: &std::path::Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&home), 0o755);
+        assert_eq!(mode(&ssh_dir), 0o700);
+        assert_eq!(mode(&ssh_dir.join("authorized_keys")), 0o600);
+        assert_eq!(run(), "");
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_revoke_script_removes_matching_entries() {
+        let home = std::env::temp_dir().join(format!("revoke_test_{}", std::process::id()));
+        let file = home.join(".ssh").join("authorized_keys");
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(
+            &file,
+            "# laptop\nssh-ed25519 AAAAkeep keep@host\nfrom=\"10.0.0.0/8,a b\" ssh-ed25519 AAAAold old@laptop\nssh-rsa AAAAold2 old@laptop\n",
+        )
+        .unwrap();
+
+        let entries = entries(&std::fs::read_to_string(&file).unwrap());
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].options, "from=\"10.0.0.0/8,a b\"");
+        assert_eq!(entries[1].key.data, "AAAAold");
+        let matching = KeyMatch::Comment("old@laptop".to_string());
+        let data: Vec<&str> = entries
+            .iter()
+            .filter(|entry| matching.matches(&entry.key))
+            .map(|entry| entry.key.data.as_str())
+            .collect();
+
+        let revoke = |data: &str| {
+            let output = Command::new("sh")
+                .env("HOME", &home)
+                .args(["-c", &with_back_up(REVOKE_SCRIPT), "sh", data])
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        };
+        assert!(revoke(&data.join("\n")).ends_with("\n2"));
+        assert_eq!(
+            std::fs::read_to_string(&file).unwrap(),
+            "# laptop\nssh-ed25519 AAAAkeep keep@host\n"
+        );
+        assert_eq!(revoke("AAAAold"), "0");
+
+        let restore = |name: &str| {
+            Command::new("sh")
+                .env("HOME", &home)
+                .args(["-c", &with_back_up(RESTORE_SCRIPT), "sh", name])
+                .output()
+                .unwrap()
+        };
+        for name in [
+            "../.ssh/authorized_keys.bak-x",
+            "authorized_keys",
+            "id_ed25519",
+        ] {
+            let output = restore(name);
+            assert!(!output.status.success(), "{}", name);
+            assert!(String::from_utf8_lossy(&output.stderr).contains("is not a backup"));
+        }
+        let output = restore("");
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("restored "));
+        assert_eq!(
+            super::entries(&std::fs::read_to_string(&file).unwrap()).len(),
+            3
+        );
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_key_login_offers_only_the_key() {
+        let target = Target {
+            user: "pi".to_string(),
+            target_user: Some("deploy".to_string()),
+            identity_file: Some("/home/me/.ssh/login".into()),
+            password: Some(crate::askpass::Password::new("secret".to_string())),
+            ..Default::default()
+        };
+        // No private key next to it: the agent's copy is asked for by the public key
+        let (login, options) = key_login(&target, Path::new("/nonexistent/id_ed25519.pub"));
+        assert_eq!(login.user, "deploy");
+        assert!(login.identity_file.is_none() && login.password.is_none());
+        for option in [
+            "IdentityFile=\"/nonexistent/id_ed25519.pub\"",
+            "IdentitiesOnly=yes",
+            "PreferredAuthentications=publickey",
+            "BatchMode=yes",
+        ] {
+            assert!(options.iter().any(|o| o == option), "{} missing", option);
+        }
+    }
+}
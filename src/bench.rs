@@ -0,0 +1,272 @@
+//! `bench`: how fast data moves through the tunnel, each way.
+//!
+//! One session through the forwarded port swallows what is sent to it and
+//! counts it, another streams zeros back from `dd`. SSH compression is
+//! turned off for them, so the zeros cost the link as much as a rootfs image
+//! would. Each session says when it is ready, so logging in isn't timed.
+
+use crate::config::Config;
+use crate::executor::{Child, ChildStdout, Command, Stdio};
+use crate::output::{self, Renderable};
+use crate::shell::RemoteCommand;
+use crate::ssh;
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::time::timeout;
+use tracing::info;
+
+/// Size of the blocks written and read, which `dd` sends too
+const BLOCK: usize = 64 * 1024;
+
+/// Upper bound for logging in for a session
+const SESSION_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Counts the bytes it receives, once it said it's ready
+pub const UPLOAD_SCRIPT: &str = "echo ready\nwc -c";
+
+/// Sends `$1` blocks of zeros, once it said it's ready
+pub const DOWNLOAD_SCRIPT: &str = r#"echo ready
+dd if=/dev/zero bs=65536 count="$1" 2>/dev/null"#;
+
+/// Which ways `bench` measures
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Direction {
+    /// From here to the device, then back
+    #[default]
+    Both,
+    /// From here to the device
+    Upload,
+    /// From the device to here
+    Download,
+}
+
+/// Data moved one way, and how long it took
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Throughput {
+    pub bytes: u64,
+    pub secs: f64,
+    /// Mebibytes (2^20 bytes) per second
+    pub mb_per_sec: f64,
+}
+
+impl Throughput {
+    fn new(bytes: u64, elapsed: Duration) -> Self {
+        let secs = elapsed.as_secs_f64();
+        Self {
+            bytes,
+            secs,
+            mb_per_sec: bytes as f64 / (1024.0 * 1024.0) / secs.max(f64::EPSILON),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{:.1} MB/s ({} MB in {})",
+            self.mb_per_sec,
+            self.bytes / (1024 * 1024),
+            output::format_duration(Duration::from_secs_f64(self.secs))
+        )
+    }
+}
+
+/// Result of `bench`
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload: Option<Throughput>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download: Option<Throughput>,
+}
+
+impl Renderable for BenchReport {
+    fn to_human(&self) -> String {
+        let mut lines = vec![format!("Throughput through the tunnel to {}:", self.host)];
+        if let Some(upload) = &self.upload {
+            lines.push(format!("Upload:   {}", upload.describe()));
+        }
+        if let Some(download) = &self.download {
+            lines.push(format!("Download: {}", download.describe()));
+        }
+        lines.join("\n")
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Sends `size_mb` mebibytes through `target`'s tunnel in `direction`,
+/// reusing the tunnel if one is open
+pub async fn run(
+    config: &Config,
+    target: &Target,
+    size_mb: u64,
+    direction: Direction,
+) -> Result<BenchReport> {
+    let manager = SSHTunnelManager::new(config.clone());
+    manager.reuse_or_connect(target).await?;
+    let bytes = size_mb * 1024 * 1024;
+    let session = |script: &str, args: &[String]| {
+        let mut command = RemoteCommand::new("sh").arg("-c").arg(script).arg("sh");
+        for arg in args {
+            command = command.arg(arg);
+        }
+        let no_compression = ["-o".to_string(), "Compression=no".to_string()];
+        ssh::through_tunnel_with(target, &no_compression, &command)
+    };
+
+    let upload = if direction != Direction::Download {
+        info!("Sending {} MB to {}...", size_mb, target.host);
+        Some(upload(session(UPLOAD_SCRIPT, &[])?, bytes).await?)
+    } else {
+        None
+    };
+    let download = if direction != Direction::Upload {
+        info!("Receiving {} MB from {}...", size_mb, target.host);
+        let blocks = bytes / BLOCK as u64;
+        Some(download(session(DOWNLOAD_SCRIPT, &[blocks.to_string()])?, bytes).await?)
+    } else {
+        None
+    };
+    Ok(BenchReport {
+        host: target.host.clone(),
+        upload,
+        download,
+    })
+}
+
+/// Writes `bytes` zeros to `command` running [`UPLOAD_SCRIPT`]
+async fn upload(mut command: Command, bytes: u64) -> Result<Throughput, TunnelError> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| TunnelError::Bench(e.to_string()))?;
+    let (Some(mut stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        return Err(TunnelError::Bench("no pipes to the session".to_string()));
+    };
+    let mut stdout = BufReader::new(stdout);
+    wait_ready(&mut stdout, &mut child).await?;
+
+    let block = vec![0u8; BLOCK];
+    let started = Instant::now();
+    let mut sent = 0;
+    while sent < bytes {
+        let len = (bytes - sent).min(BLOCK as u64) as usize;
+        if let Err(e) = stdin.write_all(&block[..len]).await {
+            return Err(TunnelError::Bench(format!(
+                "sending stopped after {} bytes: {}",
+                sent, e
+            )));
+        }
+        sent += len as u64;
+    }
+    drop(stdin);
+    // The count comes back once everything arrived
+    let mut count = String::new();
+    let _ = stdout.read_line(&mut count).await;
+    let elapsed = started.elapsed();
+    let _ = child.wait().await;
+    match count.trim().parse::<u64>() {
+        Ok(received) if received == bytes => Ok(Throughput::new(bytes, elapsed)),
+        Ok(received) => Err(TunnelError::Bench(format!(
+            "the device received {} of {} bytes",
+            received, bytes
+        ))),
+        Err(_) => Err(TunnelError::Bench(
+            "the device didn't say how much it received".to_string(),
+        )),
+    }
+}
+
+/// Reads what `command` running [`DOWNLOAD_SCRIPT`] sends, expecting `bytes`
+async fn download(mut command: Command, bytes: u64) -> Result<Throughput, TunnelError> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| TunnelError::Bench(e.to_string()))?;
+    let Some(stdout) = child.stdout.take() else {
+        return Err(TunnelError::Bench("no pipe from the session".to_string()));
+    };
+    let mut stdout = BufReader::with_capacity(BLOCK, stdout);
+    wait_ready(&mut stdout, &mut child).await?;
+
+    let mut block = vec![0u8; BLOCK];
+    let started = Instant::now();
+    let mut received = 0;
+    loop {
+        match stdout.read(&mut block).await {
+            Ok(0) => break,
+            Ok(read) => received += read as u64,
+            Err(e) => {
+                return Err(TunnelError::Bench(format!(
+                    "receiving stopped after {} bytes: {}",
+                    received, e
+                )))
+            }
+        }
+    }
+    let elapsed = started.elapsed();
+    let _ = child.wait().await;
+    if received != bytes {
+        return Err(TunnelError::Bench(format!(
+            "received {} of {} bytes",
+            received, bytes
+        )));
+    }
+    Ok(Throughput::new(bytes, elapsed))
+}
+
+/// Waits for the session's `ready`, failing with its stderr
+async fn wait_ready(
+    stdout: &mut BufReader<ChildStdout>,
+    child: &mut Child,
+) -> Result<(), TunnelError> {
+    let mut line = String::new();
+    let read = timeout(SESSION_TIMEOUT, stdout.read_line(&mut line)).await;
+    if matches!(read, Ok(Ok(_))) && line.trim_end() == "ready" {
+        return Ok(());
+    }
+    let _ = child.start_kill();
+    let mut stderr = String::new();
+    if let Some(mut pipe) = child.stderr.take() {
+        let _ = timeout(Duration::from_secs(1), pipe.read_to_string(&mut stderr)).await;
+    }
+    Err(TunnelError::Bench(match read {
+        Err(_) => "timeout starting the session".to_string(),
+        Ok(_) if stderr.trim().is_empty() => "the session ended".to_string(),
+        Ok(_) => stderr.trim().to_string(),
+    }))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn local(script: &str, args: &[&str]) -> Command {
+        let mut command = tokio::process::Command::new("sh");
+        command.args(["-c", script, "sh"]).args(args);
+        command.into()
+    }
+
+    #[tokio::test]
+    async fn test_scripts_move_every_byte() {
+        let bytes = 3 * BLOCK as u64;
+        let sent = upload(local(UPLOAD_SCRIPT, &[]), bytes).await.unwrap();
+        assert_eq!(sent.bytes, bytes);
+        let received = download(local(DOWNLOAD_SCRIPT, &["3"]), bytes)
+            .await
+            .unwrap();
+        assert!(received.mb_per_sec > 0.0);
+        assert!(download(local(DOWNLOAD_SCRIPT, &["2"]), bytes)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,316 @@
+//! The external programs this machine has, and what works without them.
+//!
+//! The tool drives OpenSSH's programs rather than speaking SSH itself. They
+//! are looked for once ([`detect`]); a command checks the ones it can't do
+//! without before it starts ([`Capabilities::require`]), and the rest fall
+//! back to native code:
+//!
+//! - without `ssh-keygen`, fingerprints are computed here and known_hosts
+//!   entries are read and edited directly (hashed host names can't be matched)
+//! - without `ssh-add`, the agent is treated as absent
+//! - without `pkill`, tunnels are found through `/proc` (Linux only)
+//! - without `hostname`, the name is read from the kernel
+//!
+//! `ssh-copy-id` is never needed: keys are installed over `ssh` by the tool itself.
+
+use crate::output::{self, Renderable};
+use crate::process;
+use crate::TunnelError;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing::info;
+
+/// A program, or service, the tool uses on this machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    Ssh,
+    Scp,
+    Rsync,
+    SshKeygen,
+    SshAdd,
+    Pkill,
+    Hostname,
+    Mdns,
+}
+
+impl Tool {
+    pub const ALL: [Tool; 8] = [
+        Tool::Ssh,
+        Tool::Scp,
+        Tool::Rsync,
+        Tool::SshKeygen,
+        Tool::SshAdd,
+        Tool::Pkill,
+        Tool::Hostname,
+        Tool::Mdns,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Tool::Ssh => "ssh",
+            Tool::Scp => "scp",
+            Tool::Rsync => "rsync",
+            Tool::SshKeygen => "ssh-keygen",
+            Tool::SshAdd => "ssh-add",
+            Tool::Pkill => "pkill",
+            Tool::Hostname => "hostname",
+            Tool::Mdns => "avahi",
+        }
+    }
+
+    fn used_for(self) -> &'static str {
+        match self {
+            Tool::Ssh => "every connection to a device",
+            Tool::Scp => "copy, agent install",
+            Tool::Rsync => "sync",
+            Tool::SshKeygen => "keys generate, host key fingerprints",
+            Tool::SshAdd => "ssh-agent, --add-key",
+            Tool::Pkill => "closing tunnels",
+            Tool::Hostname => "comments of generated keys",
+            Tool::Mdns => "resolving .local names",
+        }
+    }
+
+    /// What happens when it is missing
+    fn without(self) -> &'static str {
+        match self {
+            Tool::Ssh => "nothing that reaches a device works; install the OpenSSH client",
+            Tool::Scp => "copy and agent install are unavailable; use push for single files",
+            Tool::Rsync => "sync is unavailable; use copy",
+            Tool::SshKeygen => {
+                "keys generate is unavailable; fingerprints are computed natively and hashed known_hosts entries can't be matched"
+            }
+            Tool::SshAdd => "the agent is ignored and --add-key is unavailable",
+            Tool::Pkill if cfg!(target_os = "linux") => "tunnels are found through /proc instead",
+            Tool::Pkill => "tunnels can't be closed",
+            Tool::Hostname => "the name is read from the kernel",
+            Tool::Mdns => "use the device's IP address instead of its .local name",
+        }
+    }
+
+    /// Whether the tool works on without it, if less well
+    fn has_fallback(self) -> bool {
+        match self {
+            Tool::Ssh | Tool::Scp | Tool::Rsync | Tool::Mdns => false,
+            Tool::Pkill => cfg!(target_os = "linux"),
+            Tool::SshKeygen | Tool::SshAdd | Tool::Hostname => true,
+        }
+    }
+
+    /// Where it was found, if it was
+    fn find(self) -> Option<String> {
+        if self == Tool::Mdns {
+            return mdns();
+        }
+        process::find_program(self.name())
+            .ok()
+            .map(|path| path.display().to_string())
+    }
+}
+
+/// How `.local` names get resolved: avahi on Linux, built into macOS and Windows
+fn mdns() -> Option<String> {
+    if !cfg!(target_os = "linux") {
+        return Some("built in".to_string());
+    }
+    ["/run/avahi-daemon/socket", "/var/run/avahi-daemon/socket"]
+        .into_iter()
+        .find(|socket| Path::new(socket).exists())
+        .map(str::to_string)
+        .or_else(|| {
+            process::find_program("avahi-daemon")
+                .ok()
+                .map(|path| path.display().to_string())
+        })
+}
+
+/// Whether one tool was found
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolStatus {
+    pub tool: &'static str,
+    /// Its path, or `built in`
+    pub found: Option<String>,
+    pub used_for: &'static str,
+    /// Whether the tool falls back to native code without it
+    pub fallback: bool,
+    pub without: &'static str,
+}
+
+/// What was found on this machine
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    pub tools: Vec<ToolStatus>,
+}
+
+impl Capabilities {
+    fn status(&self, tool: Tool) -> Option<&ToolStatus> {
+        self.tools.iter().find(|status| status.tool == tool.name())
+    }
+
+    pub fn has(&self, tool: Tool) -> bool {
+        self.status(tool)
+            .is_some_and(|status| status.found.is_some())
+    }
+
+    /// The tools that were not found
+    pub fn missing(&self) -> impl Iterator<Item = &ToolStatus> {
+        self.tools.iter().filter(|status| status.found.is_none())
+    }
+
+    /// Fails, naming every one of `tools` that is missing and what that rules out
+    pub fn require(&self, tools: &[Tool]) -> Result<(), TunnelError> {
+        let missing: Vec<String> = tools
+            .iter()
+            .filter(|tool| !self.has(**tool))
+            .filter_map(|tool| self.status(*tool))
+            .map(|status| {
+                format!(
+                    "{} was not found in PATH, so {}",
+                    status.tool, status.without
+                )
+            })
+            .collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(TunnelError::MissingTools(missing.join("; ")))
+        }
+    }
+
+    /// Logs one line naming those of `tools` that are missing, if any
+    pub fn log_missing(&self, tools: &[Tool]) {
+        let missing: Vec<&str> = tools
+            .iter()
+            .filter(|tool| !self.has(**tool))
+            .map(|tool| tool.name())
+            .collect();
+        if !missing.is_empty() {
+            info!(
+                "Not found: {}; see `ssh_ip_tunnel capabilities` for what works without them",
+                missing.join(", ")
+            );
+        }
+    }
+}
+
+impl Renderable for Capabilities {
+    fn to_human(&self) -> String {
+        let rows: Vec<Vec<String>> = self
+            .tools
+            .iter()
+            .map(|status| {
+                vec![
+                    status.tool.to_string(),
+                    status
+                        .found
+                        .clone()
+                        .unwrap_or_else(|| "missing".to_string()),
+                    status.used_for.to_string(),
+                    if status.found.is_some() {
+                        String::new()
+                    } else {
+                        status.without.to_string()
+                    },
+                ]
+            })
+            .collect();
+        let table = output::table(&["TOOL", "FOUND", "USED FOR", "WITHOUT IT"], &rows);
+        let lost: Vec<&str> = self
+            .missing()
+            .filter(|status| !status.fallback)
+            .map(|status| status.tool)
+            .collect();
+        let summary = match (self.missing().count(), lost.is_empty()) {
+            (0, _) => "Everything was found".to_string(),
+            (_, true) => "Everything works, some of it through native fallbacks".to_string(),
+            (_, false) => format!("Unavailable without {}", lost.join(", ")),
+        };
+        format!("{}\n{}", table, summary)
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Looks for every tool, once per process
+pub fn detect() -> &'static Capabilities {
+    static DETECTED: OnceLock<Capabilities> = OnceLock::new();
+    DETECTED.get_or_init(|| Capabilities {
+        tools: Tool::ALL
+            .into_iter()
+            .map(|tool| ToolStatus {
+                tool: tool.name(),
+                found: tool.find(),
+                used_for: tool.used_for(),
+                fallback: tool.has_fallback(),
+                without: tool.without(),
+            })
+            .collect(),
+    })
+}
+
+/// Whether `tool` is on this machine
+pub fn has(tool: Tool) -> bool {
+    detect().has(tool)
+}
+
+/// Logs that `host` may not resolve, when it is a `.local` name and nothing
+/// on this machine resolves those; other hosts don't need avahi
+pub fn check_mdns(host: &str) {
+    if is_mdns_name(host) && !has(Tool::Mdns) {
+        info!(
+            "Not found: avahi, which resolves {}; {}",
+            host,
+            Tool::Mdns.without()
+        );
+    }
+}
+
+fn is_mdns_name(host: &str) -> bool {
+    host.trim_end_matches('.')
+        .to_ascii_lowercase()
+        .ends_with(".local")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_require_names_what_is_missing() {
+        let capabilities = Capabilities {
+            tools: Tool::ALL
+                .into_iter()
+                .map(|tool| ToolStatus {
+                    tool: tool.name(),
+                    found: (tool != Tool::Rsync).then(|| format!("/usr/bin/{}", tool.name())),
+                    used_for: tool.used_for(),
+                    fallback: tool.has_fallback(),
+                    without: tool.without(),
+                })
+                .collect(),
+        };
+        assert!(capabilities.require(&[Tool::Ssh, Tool::Scp]).is_ok());
+        assert_eq!(
+            capabilities
+                .require(&[Tool::Ssh, Tool::Rsync])
+                .unwrap_err()
+                .to_string(),
+            "Missing tools: rsync was not found in PATH, so sync is unavailable; use copy"
+        );
+        assert!(capabilities
+            .to_human()
+            .ends_with("Unavailable without rsync"));
+    }
+
+    #[test]
+    fn test_only_local_names_need_mdns() {
+        assert!(is_mdns_name("raspberrypi.local"));
+        assert!(is_mdns_name("Pi.LOCAL."));
+        assert!(!is_mdns_name("192.168.1.50"));
+        assert!(!is_mdns_name("pi.localdomain"));
+        assert!(!is_mdns_name("build.example.com"));
+    }
+}
@@ -0,0 +1,374 @@
+//! OpenSSH certificates.
+//!
+//! With a certificate authority, devices trust one CA key (`TrustedUserCAKeys`)
+//! instead of a list of user keys, and users log in with certificates signed by
+//! that CA. `keys deploy-ca` installs the CA key on a device. A `-cert.pub` given
+//! as the key to transfer is checked (validity window, principals) and then
+//! tried against the device, since certificates never go into `authorized_keys`.
+
+use crate::config::Config;
+use crate::keys::{self, PublicKey};
+use crate::output::Renderable;
+use crate::process;
+use crate::shell::{self, RemoteCommand};
+use crate::ssh;
+use crate::sshd::{self, Login};
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::info;
+
+/// Upper bound for the remote steps of `keys deploy-ca` and the certificate login test
+const STEP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// CA key file used when sshd has no `TrustedUserCAKeys` yet
+const DEFAULT_CA_FILE: &str = "/etc/ssh/trusted_user_ca_keys.pub";
+
+/// The parts of `ssh-keygen -L` output that decide whether a certificate is usable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate {
+    /// `user` or `host`
+    pub kind: String,
+    pub key_id: String,
+    pub valid_after: Option<NaiveDateTime>,
+    pub valid_before: Option<NaiveDateTime>,
+    /// Users the certificate may log in as; empty means any
+    pub principals: Vec<String>,
+}
+
+impl Certificate {
+    /// Parses the listing printed by `ssh-keygen -L -f <cert>`
+    pub fn parse_listing(text: &str) -> Option<Self> {
+        let time = |s: &str| NaiveDateTime::parse_from_str(s.trim(), "%Y-%m-%dT%H:%M:%S").ok();
+        let mut kind = None;
+        let mut key_id = String::new();
+        let mut validity = None;
+        let mut principals = Vec::new();
+        let mut in_principals = false;
+
+        for line in text.lines() {
+            let trimmed = line.trim();
+            // Principals are listed one per line, indented under their heading
+            if in_principals {
+                if line.starts_with("\t\t") || line.starts_with("                ") {
+                    principals.push(trimmed.to_string());
+                    continue;
+                }
+                in_principals = false;
+            }
+            let Some((field, value)) = trimmed.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match field {
+                "Type" => kind = value.split_whitespace().nth(1).map(str::to_string),
+                "Key ID" => key_id = value.trim_matches('"').to_string(),
+                "Valid" => {
+                    validity = Some(if value == "forever" {
+                        (None, None)
+                    } else if let Some(range) = value.strip_prefix("from ") {
+                        let (from, to) = range.split_once(" to ")?;
+                        (Some(time(from)?), Some(time(to)?))
+                    } else if let Some(from) = value.strip_prefix("after ") {
+                        (Some(time(from)?), None)
+                    } else if let Some(to) = value.strip_prefix("before ") {
+                        (None, Some(time(to)?))
+                    } else {
+                        return None;
+                    })
+                }
+                "Principals" => in_principals = value != "(none)",
+                _ => {}
+            }
+        }
+
+        let (valid_after, valid_before) = validity?;
+        Some(Self {
+            kind: kind?,
+            key_id,
+            valid_after,
+            valid_before,
+            principals,
+        })
+    }
+
+    /// Checks that the certificate can log in as `user` at `now` (local time)
+    pub fn check(&self, now: NaiveDateTime, user: &str) -> Result<(), TunnelError> {
+        if self.kind != "user" {
+            return Err(TunnelError::InvalidCertificate(format!(
+                "{:?} is a {} certificate, not a user certificate",
+                self.key_id, self.kind
+            )));
+        }
+        if let Some(after) = self.valid_after.filter(|after| now < *after) {
+            return Err(TunnelError::InvalidCertificate(format!(
+                "{:?} is not valid until {}",
+                self.key_id, after
+            )));
+        }
+        if let Some(before) = self.valid_before.filter(|before| now >= *before) {
+            return Err(TunnelError::InvalidCertificate(format!(
+                "{:?} expired at {}",
+                self.key_id, before
+            )));
+        }
+        if !self.principals.is_empty() && !self.principals.iter().any(|p| p == user) {
+            return Err(TunnelError::InvalidCertificate(format!(
+                "{:?} is for {}, not {}",
+                self.key_id,
+                self.principals.join(", "),
+                user
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Reads a certificate with `ssh-keygen -L`
+pub async fn inspect(path: &Path) -> Result<Certificate, TunnelError> {
+    let output = process::command("ssh-keygen")?
+        .arg("-L")
+        .arg("-f")
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| TunnelError::InvalidCertificate(format!("Failed to run ssh-keygen: {}", e)))?;
+    if !output.status.success() {
+        return Err(TunnelError::InvalidCertificate(format!(
+            "{}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Certificate::parse_listing(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| TunnelError::InvalidCertificate(format!("cannot read {}", path.display())))
+}
+
+/// The private key a `<name>-cert.pub` certificate belongs to
+pub fn private_key_for(cert: &Path) -> Option<PathBuf> {
+    let name = cert.file_name()?.to_str()?.strip_suffix("-cert.pub")?;
+    Some(cert.with_file_name(name))
+}
+
+/// Checks that the device accepts `cert` for `target.user`, using nothing but the certificate
+pub async fn verify_login(target: &Target, cert: &Path) -> Result<(), TunnelError> {
+    let failed = |message: String| {
+        TunnelError::KeyTransfer(format!(
+            "the device does not accept {} ({}); deploy its CA with `keys deploy-ca`",
+            cert.display(),
+            message
+        ))
+    };
+
+    let mut options = vec![ssh::path_option("CertificateFile", cert)];
+    if let Some(private) = private_key_for(cert) {
+        options.push(ssh::path_option("IdentityFile", &private));
+    }
+    options.extend(
+        [
+            "IdentitiesOnly=yes",
+            "PreferredAuthentications=publickey",
+            // Test a fresh login, not the multiplexed one
+            "ControlPath=none",
+        ]
+        .map(str::to_string),
+    );
+    let options: Vec<String> = options
+        .into_iter()
+        .flat_map(|option| ["-o".to_string(), option])
+        .collect();
+
+    let probe = RemoteCommand::new("true");
+    ssh::run(
+        ssh::through_tunnel_with(target, &options, &probe)?,
+        STEP_TIMEOUT,
+    )
+    .await
+    .map(drop)
+    .map_err(|e| e.into_error(failed))
+}
+
+/// Result of `keys deploy-ca`
+#[derive(Debug, Serialize)]
+pub struct CaDeployReport {
+    pub host: String,
+    pub key_type: String,
+    pub comment: String,
+    /// File on the device that sshd reads trusted CA keys from
+    pub ca_file: String,
+    /// False if the device already trusted this CA
+    pub added: bool,
+}
+
+impl Renderable for CaDeployReport {
+    fn to_human(&self) -> String {
+        if self.added {
+            format!(
+                "Added CA {} {} to {}:{} and reloaded sshd",
+                self.key_type, self.comment, self.host, self.ca_file
+            )
+        } else {
+            format!(
+                "{} already trusts CA {} {} ({})",
+                self.host, self.key_type, self.comment, self.ca_file
+            )
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Opens the tunnel and makes the device's sshd trust the CA key at `ca`
+pub async fn deploy_ca(config: &Config, target: &Target, ca: &Path) -> Result<CaDeployReport> {
+    let key = keys::read_public_key(ca)?;
+    if key.is_certificate() {
+        return Err(TunnelError::InvalidPublicKey(format!(
+            "{} is a certificate; pass the CA's public key",
+            ca.display()
+        ))
+        .into());
+    }
+
+    SSHTunnelManager::new(config.clone())
+        .connect(target)
+        .await?;
+
+    info!("Installing CA key {} on {}...", key.comment, target.host);
+    let script = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(deploy_script())
+        .arg("sh")
+        .arg(key_line(&key));
+    let stdout = sshd::change(target, Login::Target, async {
+        ssh::run_through_tunnel(target, &script, STEP_TIMEOUT)
+            .await
+            .map_err(|e| {
+                e.into_error(|message| {
+                    TunnelError::KeyTransfer(format!("installing the CA key failed: {}", message))
+                })
+            })
+    })
+    .await?;
+
+    let (status, ca_file) = stdout
+        .trim()
+        .split_once(' ')
+        .ok_or_else(|| anyhow::anyhow!("unexpected output from the device: {:?}", stdout))?;
+    Ok(CaDeployReport {
+        host: target.host.clone(),
+        key_type: key.key_type,
+        comment: key.comment,
+        ca_file: ca_file.to_string(),
+        added: status == "added",
+    })
+}
+
+fn key_line(key: &PublicKey) -> String {
+    format!("{} {} {}", key.key_type, key.data, key.comment)
+        .trim_end()
+        .to_string()
+}
+
+/// Shell script that adds the key in `$1` to sshd's trusted CA keys.
+///
+/// Uses the file sshd already reads if `TrustedUserCAKeys` is set, otherwise
+/// adds the directive at the top of `sshd_config` (above any `Match` block).
+/// A configuration `sshd -t` rejects is rolled back before sshd is reloaded.
+/// Prints `added <file>` or `present <file>`.
+fn deploy_script() -> String {
+    format!(
+        r#"set -e
+{as_root}
+PATH="$PATH:/usr/sbin:/sbin"
+key=$1
+config=/etc/ssh/sshd_config
+ca_file=$(as_root sshd -T 2>/dev/null | awk '$1 == "trustedusercakeys" {{ print $2 }}')
+configured=
+if [ -z "$ca_file" ] || [ "$ca_file" = none ]; then
+  ca_file={default_ca_file}
+  as_root cp "$config" "$config.bak"
+  {{ printf 'TrustedUserCAKeys %s\n' "$ca_file"; as_root cat "$config.bak"; }} | as_root tee "$config" >/dev/null
+  configured=1
+fi
+if as_root grep -qxF "$key" "$ca_file" 2>/dev/null; then
+  status=present
+else
+  printf '%s\n' "$key" | as_root tee -a "$ca_file" >/dev/null
+  status=added
+fi
+if [ -n "$configured" ] || [ "$status" = added ]; then
+  if ! as_root sshd -t; then
+    [ -z "$configured" ] || as_root mv "$config.bak" "$config"
+    echo "sshd rejected the new configuration" >&2
+    exit 1
+  fi
+  as_root systemctl reload ssh 2>/dev/null || as_root systemctl reload sshd 2>/dev/null || as_root kill -HUP "$(cat /var/run/sshd.pid)"
+fi
+echo "$status $ca_file"
+"#,
+        as_root = shell::AS_ROOT,
+        default_ca_file = shell::quote(DEFAULT_CA_FILE),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LISTING: &str = "/home/pi/.ssh/id_ed25519-cert.pub:
+        Type: ssh-ed25519-cert-v01@openssh.com user certificate
+        Public key: ED25519-CERT SHA256:abc
+        Signing CA: ED25519 SHA256:def (using ssh-ed25519)
+        Key ID: \"pi@lab\"
+        Serial: 7
+        Valid: from 2026-01-01T00:00:00 to 2026-02-01T00:00:00
+        Principals:
+                pi
+                deploy
+        Critical Options: (none)
+        Extensions:
+                permit-pty
+";
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_parse_listing_and_check() {
+        let cert = Certificate::parse_listing(LISTING).unwrap();
+        assert_eq!(cert.kind, "user");
+        assert_eq!(cert.key_id, "pi@lab");
+        assert_eq!(cert.principals, vec!["pi", "deploy"]);
+
+        assert!(cert.check(at("2026-01-15T12:00:00"), "deploy").is_ok());
+        assert!(cert.check(at("2026-01-15T12:00:00"), "root").is_err());
+        assert!(cert.check(at("2025-12-31T23:59:59"), "pi").is_err());
+        let expired = cert.check(at("2026-02-01T00:00:00"), "pi").unwrap_err();
+        assert!(expired.to_string().contains("expired"), "{}", expired);
+
+        let forever = LISTING
+            .replace("from 2026-01-01T00:00:00 to 2026-02-01T00:00:00", "forever")
+            .replace(
+                "Principals:\n                pi\n                deploy",
+                "Principals: (none)",
+            );
+        let cert = Certificate::parse_listing(&forever).unwrap();
+        assert!(cert.principals.is_empty());
+        assert!(cert.check(at("2030-01-01T00:00:00"), "root").is_ok());
+    }
+
+    #[test]
+    fn test_private_key_for() {
+        assert_eq!(
+            private_key_for(Path::new("/k/id_ed25519-cert.pub")),
+            Some(PathBuf::from("/k/id_ed25519"))
+        );
+        assert_eq!(private_key_for(Path::new("/k/id_ed25519.pub")), None);
+    }
+}
@@ -0,0 +1,139 @@
+//! SHA-256 checksums of transferred files.
+//!
+//! The local side is hashed in-process. [`verify_remote`] hashes the copy on
+//! the device with whichever of `sha256sum`, `shasum` or `openssl` it has, so a
+//! transfer corrupted on a flaky link is caught before the file is used.
+
+use crate::shell::RemoteCommand;
+use crate::ssh;
+use crate::{Target, TunnelError};
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+use tracing::info;
+
+/// Upper bound for hashing a file on the device; large images on an SD card are slow
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Defines `sha256`, which prints `<hex> -` for its stdin with the first hashing tool available
+pub const SHA256_FN: &str = r#"sha256() {
+  if command -v sha256sum >/dev/null 2>&1; then sha256sum
+  elif command -v shasum >/dev/null 2>&1; then shasum -a 256
+  elif command -v openssl >/dev/null 2>&1; then openssl dgst -sha256 -r
+  else echo "no sha256sum, shasum or openssl on the device" >&2; return 127
+  fi
+}"#;
+
+/// Prints the checksum of the file `$1`, once [`SHA256_FN`] is defined
+pub const FILE_SCRIPT: &str = r#"sha256 < "$1""#;
+
+fn remote_script() -> String {
+    format!("{}\n{}", SHA256_FN, FILE_SCRIPT)
+}
+
+/// Computes the lowercase hex SHA-256 of a file
+pub fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Computes the lowercase hex SHA-256 of a byte string
+pub fn sha256_bytes(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(64), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    })
+}
+
+/// Extracts the checksum from a `sha256sum`-style line (`<hex>  <name>`) or a bare hex digest
+pub fn parse_sha256(text: &str) -> Option<String> {
+    let digest = text.split_whitespace().next()?.to_ascii_lowercase();
+    (digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit())).then_some(digest)
+}
+
+/// Computes the SHA-256 of `path` on the device
+pub async fn remote_sha256(target: &Target, path: &str) -> Result<String, TunnelError> {
+    let failed = |message: String| TunnelError::Transfer(format!("{}: {}", path, message));
+    let command = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(remote_script())
+        .arg("sh")
+        .arg(path);
+    let stdout = ssh::run_through_tunnel(target, &command, REMOTE_TIMEOUT)
+        .await
+        .map_err(|e| e.into_error(failed))?;
+    parse_sha256(&stdout).ok_or_else(|| failed(format!("unexpected checksum output {:?}", stdout)))
+}
+
+/// Checks that `path` on the device has SHA-256 `expected`, returning the remote digest
+pub async fn verify_remote(
+    target: &Target,
+    path: &str,
+    expected: &str,
+) -> Result<String, TunnelError> {
+    info!("Verifying {} on the device...", path);
+    let digest = remote_sha256(target, path).await?;
+    if digest != expected {
+        return Err(TunnelError::ChecksumMismatch(format!(
+            "{} on {} has SHA-256 {}, expected {}; the transfer was corrupted",
+            path, target.host, digest, expected
+        )));
+    }
+    Ok(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256_file_and_parse() {
+        let path = std::env::temp_dir().join(format!("checksum-test-{}", std::process::id()));
+        std::fs::write(&path, b"abc").unwrap();
+        let digest = sha256_file(&path).unwrap();
+        assert_eq!(
+            digest,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        std::fs::remove_file(path).unwrap();
+
+        let line = format!("{}  firmware.bin\n", digest.to_uppercase());
+        assert_eq!(parse_sha256(&line), Some(digest));
+        assert_eq!(parse_sha256("not-a-digest"), None);
+    }
+
+    #[test]
+    fn test_remote_script_output_parses() {
+        let path = std::env::temp_dir().join(format!("checksum-remote-{}", std::process::id()));
+        std::fs::write(&path, b"abc").unwrap();
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(remote_script())
+            .arg("sh")
+            .arg(&path)
+            .output()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(
+            parse_sha256(&String::from_utf8_lossy(&output.stdout)),
+            Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string())
+        );
+    }
+}
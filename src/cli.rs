@@ -0,0 +1,2848 @@
+//! The `ssh-ip-tunnel` command line: arguments and one handler per command.
+
+use crate::capabilities::Tool;
+use crate::config::{load_config, Config, HostProfile};
+use crate::info::{InfoFormat, InfoReport};
+use crate::output::{ColorChoice, EventFormat, OutputFormat, Renderable, Style};
+use crate::pure::arch::Arch;
+use crate::run::{self, RunId};
+use crate::ssh_config::SshConfig;
+use crate::*;
+use anyhow::Result;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use clap_complete::engine::ArgValueCompleter;
+use clap_complete::env::CompleteEnv;
+use serde::Serialize;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, info, info_span, warn, Instrument};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
+
+/// A CLI tool to create an IP tunnel to an ARM CPU and transfer SSH keys.
+#[derive(Parser, Debug)]
+#[command(name = "ssh-ip-tunnel")]
+#[command(about = "CLI tool for tunneling SSH and SSH key transfer", long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[command(flatten)]
+    target: TargetArgs,
+
+    /// Verbose logging
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Configuration file path
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Output mode for results and events
+    #[arg(long, value_enum, global = true, default_value_t = OutputFormat::Human)]
+    output: OutputFormat,
+
+    /// Stream events on stdout, one JSON object per line; the same as --output ndjson
+    #[arg(
+        long,
+        value_enum,
+        global = true,
+        value_name = "FORMAT",
+        conflicts_with = "output"
+    )]
+    events: Option<EventFormat>,
+
+    /// When to use colors; `auto` colors a terminal unless NO_COLOR is set
+    #[arg(long, value_enum, global = true, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Never prompt or wait for an answer, failing instead, and log only warnings and errors
+    #[arg(long, global = true)]
+    batch: bool,
+
+    /// Run against simulated devices instead of real ones; nothing goes over the network
+    #[arg(long, global = true)]
+    simulate: bool,
+
+    /// Use this namespace's own configuration, known hosts, state and tunnels, e.g. one per team
+    #[arg(long, global = true, value_name = "NAME")]
+    namespace: Option<String>,
+
+    /// Fail on purpose at these points (fail_<phase>, drop_after_<phase>), for testing
+    #[arg(long, global = true, hide = true, value_name = "POINTS")]
+    inject_fault: Option<String>,
+}
+
+impl Cli {
+    /// The output mode, which `--events ndjson` picks too
+    fn output_format(&self) -> OutputFormat {
+        match self.events {
+            Some(EventFormat::Ndjson) => OutputFormat::Ndjson,
+            None => self.output,
+        }
+    }
+
+    /// Whether stdout carries the device's own output, so logs must stay off it
+    fn stdout_is_data(&self) -> bool {
+        match &self.command {
+            Some(Commands::Exec { .. } | Commands::Completions { .. } | Commands::Docs { .. }) => {
+                true
+            }
+            Some(Commands::Service {
+                action: ServiceCommand::Install { print, .. },
+            }) => *print,
+            Some(Commands::Info { format, .. }) => *format != InfoFormat::Text,
+            _ => false,
+        }
+    }
+
+    /// Whether someone watches one device's run on a terminal, and would
+    /// rather see ✓/✗ status lines than log lines; the dashboard has its own
+    fn shows_status_lines(&self) -> bool {
+        let group = match &self.command {
+            Some(Commands::Up(args)) => args.group.is_some(),
+            Some(_) => false,
+            None => self.target.group.is_some(),
+        };
+        self.output_format() == OutputFormat::Human
+            && !self.verbose
+            && !group
+            && !matches!(self.command, Some(Commands::Tui))
+            && !self.stdout_is_data()
+            && !prompt::is_batch()
+            && std::io::stdout().is_terminal()
+    }
+
+    /// Directory for this run's per-host logs, when a group run asked for them
+    fn host_log_dir(&self, run_id: &RunId) -> Option<PathBuf> {
+        let args = match &self.command {
+            Some(Commands::Up(args)) => args,
+            Some(_) => return None,
+            None => &self.target,
+        };
+        args.group.as_ref()?;
+        Some(args.log_dir.as_ref()?.join(run_id.as_str()))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Create the tunnel and transfer the SSH key (default when no subcommand is given)
+    Up(TargetArgs),
+
+    /// Close the tunnel to the device
+    Down(TargetArgs),
+
+    /// Show the tunnels `up --watch` keeps, and kept
+    Status {
+        /// Add uptime, checks, reconnects and the last failure
+        #[arg(long)]
+        stats: bool,
+    },
+
+    /// Manage the configuration file
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+
+    /// Copy a file to the device through the tunnel, fetching it from a URL first if asked
+    Push {
+        #[command(flatten)]
+        target: TargetArgs,
+
+        /// Local file to copy
+        #[arg(long, value_name = "PATH", required_unless_present = "from_url")]
+        file: Option<PathBuf>,
+
+        /// Download the file from this HTTP(S) URL, then copy it
+        #[arg(long, value_name = "URL", conflicts_with = "file")]
+        from_url: Option<String>,
+
+        /// Path on the device (default: the file name, in the login user's home)
+        #[arg(long, value_name = "PATH")]
+        dest: Option<String>,
+
+        /// Expected SHA-256 of the file (default for URLs: <URL>.sha256, if published)
+        #[arg(long, value_name = "HEX")]
+        sha256: Option<String>,
+
+        /// Hash the uploaded copy on the device and fail if it differs
+        #[arg(long)]
+        verify: bool,
+
+        /// Split the upload over this many parallel connections (implies --verify)
+        #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=16))]
+        streams: u8,
+    },
+
+    /// Copy files or directories to or from a device with scp through its tunnel,
+    /// e.g. copy ./firmware.bin remote:/tmp/ or copy mydevboard:/var/log/syslog ./
+    Copy {
+        #[command(flatten)]
+        target: TargetArgs,
+
+        /// Sources, then the destination; device paths are remote:PATH, for the
+        /// device the options select, or <PROFILE>:PATH
+        #[arg(value_name = "PATHS")]
+        paths: Vec<String>,
+
+        /// Copy directories and everything in them
+        #[arg(short, long)]
+        recursive: bool,
+    },
+
+    /// Sync a directory tree to or from a device with rsync through its tunnel,
+    /// sending only what changed, e.g. sync ./rootfs/ remote:/srv/rootfs/
+    Sync {
+        #[command(flatten)]
+        target: TargetArgs,
+
+        /// Sources, then the destination, written as for copy; a source ending
+        /// in / stands for the directory's contents
+        #[arg(value_name = "PATHS")]
+        paths: Vec<String>,
+
+        /// Delete files at the destination that the sources don't have
+        #[arg(long)]
+        delete: bool,
+
+        /// Leave out files matching an rsync pattern (repeatable)
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+    },
+
+    /// Manage the agent running on provisioned devices
+    Agent {
+        #[command(subcommand)]
+        action: AgentCommand,
+    },
+
+    /// Write an OS image to a storage device on a board (erases the device)
+    Flash {
+        #[command(flatten)]
+        target: TargetArgs,
+
+        /// Raw image file to write
+        #[arg(long, value_name = "PATH")]
+        image: PathBuf,
+
+        /// Whole block device on the board, e.g. /dev/mmcblk1
+        #[arg(long, value_name = "DEVICE")]
+        device: String,
+
+        /// Don't ask for confirmation
+        #[arg(long)]
+        yes: bool,
+
+        /// Don't read the device back to verify the checksum
+        #[arg(long)]
+        no_verify: bool,
+    },
+
+    /// Manage the keys devices trust
+    Keys {
+        #[command(subcommand)]
+        action: KeysCommand,
+    },
+
+    /// Get a container image onto the device and run it
+    DeployContainer {
+        #[command(flatten)]
+        target: TargetArgs,
+
+        /// Image reference, e.g. ghcr.io/acme/app:1.4
+        #[arg(long, value_name = "IMAGE")]
+        image: String,
+
+        /// How the image reaches the device
+        #[arg(long, value_enum, default_value_t = container::Transfer::Pull)]
+        transfer: container::Transfer,
+
+        /// Saved image to stream to the device (`docker save` output; implies --transfer stream)
+        #[arg(long, value_name = "PATH")]
+        archive: Option<PathBuf>,
+
+        /// Container runtime on the device (default: docker if installed, else podman)
+        #[arg(long, value_enum)]
+        runtime: Option<container::Runtime>,
+
+        /// Container name (default: the image's repository name); an existing one is replaced
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Environment variable for the container
+        #[arg(short, long, value_name = "KEY=VALUE")]
+        env: Vec<String>,
+
+        /// Port mapping, as for `docker run -p`
+        #[arg(long, value_name = "HOST:CONTAINER")]
+        publish: Vec<String>,
+
+        /// Volume mount, as for `docker run -v`
+        #[arg(long, value_name = "SRC:DEST")]
+        volume: Vec<String>,
+
+        /// Further argument for `run`, e.g. --run-arg=--privileged
+        #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+        run_arg: Vec<String>,
+
+        /// Only get the image onto the device
+        #[arg(long)]
+        no_run: bool,
+    },
+
+    /// Lend the device a container registry, apt proxy, web access and/or DNS through this machine
+    Mirror {
+        #[command(flatten)]
+        target: TargetArgs,
+
+        /// Container registry to forward (default: localhost:5000)
+        #[arg(long, value_name = "HOST:PORT", num_args = 0..=1, default_missing_value = "localhost:5000")]
+        registry: Option<String>,
+
+        /// HTTP apt proxy such as apt-cacher-ng to forward (default: localhost:3142)
+        #[arg(long, value_name = "HOST:PORT", num_args = 0..=1, default_missing_value = "localhost:3142")]
+        apt_proxy: Option<String>,
+
+        /// Serve an HTTP/HTTPS forward proxy on this local port for the device's outbound traffic (default: 3128)
+        #[arg(long, value_name = "LOCAL_PORT", num_args = 0..=1, default_missing_value = "3128")]
+        reverse_proxy: Option<u16>,
+
+        /// Serve DNS for the device on this local port and make it the device's resolver; needs --user root (default: 10053)
+        #[arg(long, value_name = "LOCAL_PORT", num_args = 0..=1, default_missing_value = "10053")]
+        dns: Option<u16>,
+
+        /// Shell command to run on the device, then stop (default: stay up until Ctrl-C)
+        #[arg(long, value_name = "COMMAND")]
+        run: Option<String>,
+    },
+
+    /// Bring a device in line with a device profile from the configuration file, showing the plan first
+    ApplyProfile {
+        #[command(flatten)]
+        target: TargetArgs,
+
+        /// Device profile to apply (default: the host profile's device_profile)
+        #[arg(long, value_name = "NAME")]
+        device_profile: Option<String>,
+
+        /// Only show what would change
+        #[arg(long)]
+        plan: bool,
+
+        /// Don't ask for confirmation
+        #[arg(long, conflicts_with = "plan")]
+        yes: bool,
+
+        /// Gather the device's facts again instead of using the cached ones
+        #[arg(long)]
+        refresh_facts: bool,
+    },
+
+    /// Run a command on a device through its tunnel, reusing an open one, and exit with its exit code
+    Exec {
+        #[command(flatten)]
+        target: TargetArgs,
+
+        /// Command to run with `sh -c`, after --, e.g. -- systemctl status myapp
+        #[arg(last = true, required = true, value_name = "COMMAND")]
+        command: Vec<String>,
+    },
+
+    /// Open an interactive shell on a device through its tunnel, reusing an open one
+    Shell {
+        #[command(flatten)]
+        target: TargetArgs,
+    },
+
+    /// Report a device's architecture, board, OS, kernel, memory, disk, uptime and sshd version
+    Info {
+        #[command(flatten)]
+        target: TargetArgs,
+
+        /// Print the report alone as a JSON or TOML document, e.g. for an inventory
+        #[arg(long, value_enum, default_value_t = InfoFormat::Text)]
+        format: InfoFormat,
+    },
+
+    /// Measure round trips to a device over the network, through the tunnel and
+    /// for new commands, to tell which part is slow
+    Ping {
+        #[command(flatten)]
+        target: TargetArgs,
+
+        /// Round trips of each kind
+        #[arg(short, long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+        count: u32,
+
+        /// Milliseconds to wait between round trips
+        #[arg(long, value_name = "MS", default_value_t = 200)]
+        interval: u64,
+    },
+
+    /// Measure how fast data moves through the tunnel to a device and back
+    Bench {
+        #[command(flatten)]
+        target: TargetArgs,
+
+        /// Megabytes to send each way
+        #[arg(long, value_name = "MB", default_value_t = 32, value_parser = clap::value_parser!(u64).range(1..=1_000_000))]
+        size: u64,
+
+        /// Measure only one way
+        #[arg(long, value_enum, default_value_t = bench::Direction::Both)]
+        direction: bench::Direction,
+    },
+
+    /// Turn off password and root logins on a device, after checking that its key logs in
+    Harden {
+        #[command(flatten)]
+        target: TargetArgs,
+    },
+
+    /// Onboard a new device in one guided run: trust its host key, deploy the key,
+    /// harden sshd, set its hostname, record it as a host profile and install the agent
+    Onboard {
+        #[command(flatten)]
+        target: TargetArgs,
+
+        /// Host profile to record the device as (default: its hostname)
+        #[arg(long, value_name = "NAME")]
+        name: Option<String>,
+
+        /// Hostname to give the device
+        #[arg(long, value_name = "NAME")]
+        hostname: Option<String>,
+
+        /// Leave password logins on
+        #[arg(long)]
+        no_harden: bool,
+
+        /// Agent build to install; must match the device's architecture
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["artifacts", "no_agent"])]
+        binary: Option<PathBuf>,
+
+        /// Pick the agent build for the device's architecture from a directory, or a
+        /// path or URL containing {arch} (default: `artifacts` from the config)
+        #[arg(long, value_name = "DIR|PATTERN", conflicts_with = "no_agent")]
+        artifacts: Option<String>,
+
+        /// Don't install the agent, even if `artifacts` is configured
+        #[arg(long)]
+        no_agent: bool,
+
+        /// Don't ask; trust the host key the device presents unless --fingerprint pins one
+        #[arg(long)]
+        yes: bool,
+    },
+
+    /// Manage the host keys devices are trusted with
+    KnownHosts {
+        #[command(subcommand)]
+        action: KnownHostsCommand,
+    },
+
+    /// Record a device's packages, sshd configuration, authorized keys and enabled
+    /// services, or compare such snapshots with `snapshot diff`
+    #[command(args_conflicts_with_subcommands = true)]
+    Snapshot {
+        #[command(flatten)]
+        target: TargetArgs,
+
+        #[command(subcommand)]
+        action: Option<SnapshotCommand>,
+    },
+
+    /// Look back at past runs
+    History {
+        #[command(subcommand)]
+        action: HistoryCommand,
+    },
+
+    /// Install A/B system updates with RAUC or SWUpdate
+    Update {
+        #[command(subcommand)]
+        action: UpdateCommand,
+    },
+
+    /// Show which external tools were found and what works without the missing ones
+    Capabilities,
+
+    /// Keep tunnels up with systemd
+    Service {
+        #[command(subcommand)]
+        action: ServiceCommand,
+    },
+
+    /// Dashboard of the host profiles and recent devices: live status,
+    /// latency and uptime, with keys to bring tunnels up or down, open a
+    /// shell or deploy the key
+    Tui,
+
+    /// Print the script that completes commands, options and host profile
+    /// names in SHELL, e.g. `source <(ssh_ip_tunnel completions bash)`
+    Completions {
+        #[arg(value_enum)]
+        shell: completions::Shell,
+    },
+
+    /// Print documentation for packaging
+    Docs {
+        #[command(subcommand)]
+        action: DocsCommand,
+    },
+}
+
+impl Commands {
+    /// The target options of commands that act on devices
+    fn target_args_mut(&mut self) -> Option<&mut TargetArgs> {
+        match self {
+            Commands::Up(target)
+            | Commands::Down(target)
+            | Commands::Push { target, .. }
+            | Commands::Copy { target, .. }
+            | Commands::Sync { target, .. }
+            | Commands::Agent {
+                action: AgentCommand::Install { target, .. },
+            }
+            | Commands::Flash { target, .. }
+            | Commands::DeployContainer { target, .. }
+            | Commands::Mirror { target, .. }
+            | Commands::ApplyProfile { target, .. }
+            | Commands::Exec { target, .. }
+            | Commands::Shell { target }
+            | Commands::Info { target, .. }
+            | Commands::Ping { target, .. }
+            | Commands::Bench { target, .. }
+            | Commands::Snapshot {
+                target,
+                action: None,
+            }
+            | Commands::Harden { target }
+            | Commands::Onboard { target, .. }
+            | Commands::Update {
+                action: UpdateCommand::Install { target, .. } | UpdateCommand::Status { target },
+            } => Some(target),
+            Commands::KnownHosts {
+                action: KnownHostsCommand::Add { target, .. },
+            }
+            | Commands::Snapshot {
+                action: Some(SnapshotCommand::Diff { target, .. }),
+                ..
+            }
+            | Commands::Keys {
+                action:
+                    KeysCommand::DeployCa { target, .. }
+                    | KeysCommand::List { target }
+                    | KeysCommand::Revoke { target, .. }
+                    | KeysCommand::RestoreBackup { target, .. }
+                    | KeysCommand::Rotate { target, .. },
+            } => Some(target),
+            Commands::Config { .. }
+            | Commands::Status { .. }
+            | Commands::KnownHosts { .. }
+            | Commands::History { .. }
+            | Commands::Capabilities
+            | Commands::Tui
+            | Commands::Service { .. }
+            | Commands::Completions { .. }
+            | Commands::Docs { .. }
+            | Commands::Keys {
+                action: KeysCommand::Generate { .. },
+            } => None,
+        }
+    }
+
+    /// The tools this command can't do without
+    fn required_tools(&self) -> &'static [Tool] {
+        match self {
+            Commands::Copy { .. }
+            | Commands::Agent {
+                action: AgentCommand::Install { .. },
+            } => &[Tool::Ssh, Tool::Scp],
+            Commands::Sync { .. } => &[Tool::Ssh, Tool::Rsync],
+            Commands::Keys {
+                action: KeysCommand::Generate { .. },
+            } => &[Tool::SshKeygen],
+            Commands::KnownHosts {
+                action: KnownHostsCommand::Add { .. },
+            } => &[Tool::Ssh],
+            Commands::Config { .. }
+            | Commands::Status { .. }
+            | Commands::KnownHosts { .. }
+            | Commands::History { .. }
+            | Commands::Completions { .. }
+            | Commands::Docs { .. }
+            | Commands::Service { .. }
+            | Commands::Capabilities => &[],
+            _ => &[Tool::Ssh],
+        }
+    }
+
+    /// The tools this command uses when they are there, falling back without them
+    fn optional_tools(&self) -> &'static [Tool] {
+        match self {
+            Commands::Keys {
+                action: KeysCommand::Generate { .. },
+            } => &[Tool::Hostname],
+            Commands::KnownHosts { .. } => &[Tool::SshKeygen],
+            // avahi only matters for .local hosts; see TargetArgs::resolve
+            _ if self.required_tools().contains(&Tool::Ssh) => {
+                &[Tool::SshKeygen, Tool::SshAdd, Tool::Pkill]
+            }
+            _ => &[],
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum KeysCommand {
+    /// Make a device's sshd trust a certificate authority (TrustedUserCAKeys)
+    DeployCa {
+        #[command(flatten)]
+        target: Box<TargetArgs>,
+
+        /// Public key of the CA that signs user certificates
+        #[arg(long, value_name = "PATH")]
+        ca: PathBuf,
+    },
+
+    /// Show the keys in a device's authorized_keys, marking the ones this machine has
+    List {
+        #[command(flatten)]
+        target: Box<TargetArgs>,
+    },
+
+    /// Remove a key from a device's authorized_keys: the target's key, or
+    /// the one with --key-fingerprint or --comment
+    Revoke {
+        #[command(flatten)]
+        target: Box<TargetArgs>,
+
+        /// Fingerprint of the key to remove, as ssh-keygen -l prints it
+        #[arg(long, value_name = "SHA256:...", conflicts_with = "comment")]
+        key_fingerprint: Option<String>,
+
+        /// Remove every key with exactly this comment
+        #[arg(long, value_name = "TEXT")]
+        comment: Option<String>,
+    },
+
+    /// Put back a backup of a device's authorized_keys, made before keys were
+    /// added, revoked or restored
+    RestoreBackup {
+        #[command(flatten)]
+        target: Box<TargetArgs>,
+
+        /// Backup file in ~/.ssh to restore (default: the newest)
+        #[arg(long, value_name = "NAME")]
+        backup: Option<String>,
+    },
+
+    /// Replace the target's key on a device with a newly generated one,
+    /// checking that the new key logs in before the old one is removed
+    Rotate {
+        #[command(flatten)]
+        target: Box<TargetArgs>,
+
+        /// Public key path for the new key pair (default: the old key's name
+        /// with today's date, e.g. ~/.ssh/id_ed25519-20240101.pub)
+        #[arg(long, value_name = "PATH")]
+        new_key: Option<String>,
+
+        /// Comment for the new key (default: ssh_ip_tunnel@<hostname>)
+        #[arg(long, value_name = "TEXT")]
+        comment: Option<String>,
+
+        /// Leave the old key authorized
+        #[arg(long)]
+        keep_old: bool,
+    },
+
+    /// Create an ed25519 key pair to transfer, when you don't have one yet
+    Generate {
+        /// Public key path; the private key goes next to it without `.pub` (default: default_key_path)
+        #[arg(short, long, value_name = "PATH")]
+        key: Option<String>,
+
+        /// Key comment (default: ssh_ip_tunnel@<hostname>)
+        #[arg(short = 'C', long)]
+        comment: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SnapshotCommand {
+    /// Show what changed between two snapshots, or since a snapshot on the device as it is now
+    Diff {
+        #[command(flatten)]
+        target: Box<TargetArgs>,
+
+        /// Earlier snapshot: a file, or an ID of the device's snapshots
+        #[arg(long, value_name = "SNAPSHOT")]
+        from: String,
+
+        /// Later snapshot (default: read the device now)
+        #[arg(long, value_name = "SNAPSHOT")]
+        to: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ServiceCommand {
+    /// Install and start a systemd unit that keeps a host profile's tunnel
+    /// up, bringing it back when it fails and after reboots
+    Install {
+        /// Host profile from the configuration file
+        #[arg(add = ArgValueCompleter::new(completions::profiles))]
+        profile: String,
+
+        /// Install a system unit, running as the current user, instead of a user unit
+        #[arg(long)]
+        system: bool,
+
+        /// Replace an existing unit
+        #[arg(long)]
+        force: bool,
+
+        /// Print the unit instead of installing it
+        #[arg(long)]
+        print: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DocsCommand {
+    /// Print the manual page, with every command, configuration key,
+    /// environment variable and exit code, e.g. `ssh_ip_tunnel docs man > ssh_ip_tunnel.1`
+    Man,
+}
+
+#[derive(Subcommand, Debug)]
+enum HistoryCommand {
+    /// Show everything about a past run: its command, outcome, report and logs
+    Show {
+        /// Run ID, as in the run's log lines and JSON output, e.g. 20261016T082653Z-3f2a
+        #[arg(value_name = "RUN_ID")]
+        run_id: String,
+    },
+
+    /// List the devices `up` connected to, newest first
+    List {
+        /// Show at most this many
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+
+    /// Delete the records and logs of every past run
+    Clear,
+}
+
+#[derive(Subcommand, Debug)]
+enum KnownHostsCommand {
+    /// List the recorded host keys
+    List {
+        /// Use ~/.ssh/known_hosts instead of the tool's own file
+        #[arg(long)]
+        user_file: bool,
+    },
+
+    /// Record a device's host key, replacing any recorded before
+    Add {
+        #[command(flatten)]
+        target: Box<TargetArgs>,
+
+        /// The device's public host key (e.g. ssh_host_ed25519_key.pub from its image)
+        /// instead of fetching it; can't be combined with --fingerprint
+        #[arg(long, value_name = "PATH")]
+        host_key: Option<PathBuf>,
+
+        /// Use ~/.ssh/known_hosts instead of the tool's own file
+        #[arg(long)]
+        user_file: bool,
+    },
+
+    /// Forget a device's host keys
+    Remove {
+        /// Host as listed, e.g. 10.0.0.5 or [10.0.0.5]:2200
+        host: String,
+
+        /// Use ~/.ssh/known_hosts instead of the tool's own file
+        #[arg(long)]
+        user_file: bool,
+    },
+
+    /// Print the file, e.g. to merge it into another known_hosts
+    Export {
+        /// Use ~/.ssh/known_hosts instead of the tool's own file
+        #[arg(long)]
+        user_file: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum UpdateCommand {
+    /// Upload a bundle, install it to the inactive slot, reboot into it and confirm it booted
+    Install {
+        #[command(flatten)]
+        target: TargetArgs,
+
+        /// RAUC or SWUpdate bundle to install
+        #[arg(long, value_name = "PATH")]
+        bundle: PathBuf,
+
+        /// Path on the device for the bundle while it installs (default: /tmp/<file name>)
+        #[arg(long, value_name = "PATH")]
+        dest: Option<String>,
+
+        /// Updater to run (default: whichever is installed on the device)
+        #[arg(long, value_enum)]
+        updater: Option<update::Updater>,
+
+        /// Seconds to wait for the device to come back after rebooting
+        #[arg(long, value_name = "SECS", default_value_t = 600)]
+        reboot_timeout: u64,
+
+        /// Shell command that must succeed on the new slot before it is kept
+        #[arg(long, value_name = "COMMAND")]
+        health_command: Option<String>,
+    },
+
+    /// Show the updater and the slot the device booted from
+    Status {
+        #[command(flatten)]
+        target: TargetArgs,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AgentCommand {
+    /// Provision a device, then install the agent and its systemd unit on it
+    Install {
+        #[command(flatten)]
+        target: TargetArgs,
+
+        /// Agent build to install; must match the device's architecture
+        #[arg(long, value_name = "PATH", conflicts_with = "artifacts")]
+        binary: Option<PathBuf>,
+
+        /// Pick the build for the device's architecture from a directory, or a
+        /// path or URL containing {arch} (default: `artifacts` from the config)
+        #[arg(long, value_name = "DIR|PATTERN")]
+        artifacts: Option<String>,
+
+        /// Hash the uploaded binary on the device before installing it
+        #[arg(long)]
+        verify: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Write a commented default config.toml
+    Init {
+        /// Where to write the file (default: the per-user config directory)
+        #[arg(long)]
+        path: Option<PathBuf>,
+
+        /// Prompt for the default key path and port
+        #[arg(short, long)]
+        interactive: bool,
+
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Print the effective configuration, and the resolved target if one is given
+    Show(Box<TargetArgs>),
+
+    /// Check a configuration file for errors
+    Validate {
+        /// File to check (default: the file that would be loaded)
+        path: Option<PathBuf>,
+    },
+}
+
+/// Result of `config init`
+#[derive(Debug, Serialize)]
+struct ConfigInitReport {
+    path: PathBuf,
+    default_key_path: String,
+    default_port: u16,
+}
+
+impl Renderable for ConfigInitReport {
+    fn to_human(&self) -> String {
+        format!("Wrote default configuration to {}", self.path.display())
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Connection target, either given directly or through a named host profile
+#[derive(Args, Debug, Default, Clone)]
+struct TargetArgs {
+    /// Host profile from the configuration file
+    #[arg(add = ArgValueCompleter::new(completions::profiles))]
+    profile: Option<String>,
+
+    /// Run against every member of a host group from the configuration file
+    #[arg(
+        short,
+        long,
+        conflicts_with_all = ["profile", "host", "port"],
+        add = ArgValueCompleter::new(completions::groups)
+    )]
+    group: Option<String>,
+
+    /// Maximum number of hosts to provision concurrently with --group
+    #[arg(short, long, default_value_t = 4)]
+    jobs: usize,
+
+    /// With --group, write each host's full log to DIR/<run-id>/<host>.log and
+    /// keep the console to warnings and the summary
+    #[arg(long, value_name = "DIR", num_args = 0..=1, default_missing_value = "logs")]
+    log_dir: Option<PathBuf>,
+
+    /// Stay in the foreground once the tunnel is up, checking it and opening it
+    /// again when it stops answering; reports to systemd in a Type=notify unit
+    /// (`up` only)
+    #[arg(long, conflicts_with = "group")]
+    watch: bool,
+
+    /// With --watch, answer `GET /healthz` on ADDRESS, e.g. 127.0.0.1:9100: 200
+    /// while the tunnel passes its checks, 503 otherwise
+    #[arg(long, value_name = "ADDRESS", requires = "watch")]
+    health_listen: Option<SocketAddr>,
+
+    /// With --watch, serve the HTTP API on ADDRESS (127.0.0.1:9200 if not
+    /// given), to clients sending the token in $SSH_IP_TUNNEL_API_TOKEN
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        num_args = 0..=1,
+        default_missing_value = api::DEFAULT_ADDRESS,
+        requires = "watch"
+    )]
+    api_listen: Option<SocketAddr>,
+
+    /// With --watch, show desktop notifications when the key is deployed and
+    /// when the tunnel is lost or back
+    #[arg(long, requires = "watch")]
+    notify: bool,
+
+    /// The IP address of the ARM CPU
+    #[arg(short = 'H', long)]
+    host: Option<String>,
+
+    /// The username for SSH
+    #[arg(short, long)]
+    user: Option<String>,
+
+    /// Path to the SSH key file to transfer
+    #[arg(short, long)]
+    key: Option<String>,
+
+    /// Local port to bind for tunnel
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Connect to the host, user and port of the newest connection in the history
+    #[arg(long, conflicts_with_all = ["profile", "group", "host"])]
+    last: bool,
+
+    /// Skip SSH key transfer
+    #[arg(long)]
+    no_key_transfer: bool,
+
+    /// Transfer the key even if the device's authorized_keys already lists it
+    #[arg(long, conflicts_with = "no_key_transfer")]
+    force: bool,
+
+    /// authorized_keys option for the transferred key, e.g. from=10.0.0.0/8 or restrict (repeatable)
+    #[arg(long, value_name = "OPTION")]
+    key_option: Vec<String>,
+
+    /// Comment for the transferred key's authorized_keys line (default: the key's own)
+    #[arg(long, value_name = "TEXT")]
+    key_comment: Option<String>,
+
+    /// Install the key for this account instead of the login user, creating it through sudo if missing
+    #[arg(long, value_name = "USER")]
+    target_user: Option<String>,
+
+    /// Generate an ed25519 key pair at the key path first if there is none (`up` only)
+    #[arg(long)]
+    auto_generate: bool,
+
+    /// Skip architecture validation (use with caution)
+    #[arg(long)]
+    skip_arch_validation: bool,
+
+    /// Accept only devices with exactly this architecture, e.g. aarch64, instead of any ARM one (repeatable)
+    #[arg(long, value_name = "ARCH", conflicts_with = "skip_arch_validation")]
+    require_arch: Vec<Arch>,
+
+    /// Load the login key into ssh-agent (ssh-add) before connecting
+    #[arg(long)]
+    add_key: bool,
+
+    /// Let ssh ask for key passphrases and 2FA codes on the terminal, then reuse that login
+    #[arg(long)]
+    interactive_auth: bool,
+
+    /// Log in with this password until the key is installed (visible to other local users; prefer --ask-password)
+    #[arg(long, value_name = "PASSWORD", conflicts_with = "ask_password")]
+    password: Option<String>,
+
+    /// Ask for the login password on the terminal before connecting
+    #[arg(long)]
+    ask_password: bool,
+
+    /// Expected SHA256 fingerprint of the device's host key, checked before it is first trusted
+    #[arg(long, value_name = "SHA256:...", conflicts_with = "group")]
+    fingerprint: Option<String>,
+
+    /// Set the device's clock from this machine's right after connecting, for boards without RTC or network time
+    #[arg(long)]
+    sync_time: bool,
+
+    /// Give boards with little RAM zram or a swapfile after the key transfer (`up` only)
+    #[arg(long, value_enum, value_name = "MODE")]
+    swap: Option<swap::SwapMode>,
+
+    /// Production mode: only connect to devices whose host key is already known, with modern algorithms only
+    #[arg(long)]
+    secure: bool,
+
+    /// Upload and run this script on the device once the key is deployed, streaming its output (`up` only)
+    #[arg(long, value_name = "SCRIPT")]
+    provision: Option<PathBuf>,
+
+    /// Run the --provision script as root, through sudo -n
+    #[arg(long)]
+    provision_sudo: bool,
+
+    /// Turn off password and root logins once the key is verified to log in (`up` only)
+    #[arg(long)]
+    harden: bool,
+
+    /// Run only these phases, e.g. tunnel,key; the rest are skipped
+    #[arg(
+        long,
+        value_name = "PHASES",
+        value_delimiter = ',',
+        conflicts_with = "skip"
+    )]
+    only: Vec<Phase>,
+
+    /// Skip these phases, e.g. validate,arch
+    #[arg(long, value_name = "PHASES", value_delimiter = ',')]
+    skip: Vec<Phase>,
+
+    /// Set from the global `--simulate`
+    #[arg(skip)]
+    simulate: bool,
+}
+
+impl TargetArgs {
+    /// Whether any target option was given at all
+    fn is_given(&self) -> bool {
+        self.profile.is_some()
+            || self.group.is_some()
+            || self.log_dir.is_some()
+            || self.watch
+            || self.health_listen.is_some()
+            || self.api_listen.is_some()
+            || self.notify
+            || self.host.is_some()
+            || self.user.is_some()
+            || self.key.is_some()
+            || self.port.is_some()
+            || self.last
+            || self.no_key_transfer
+            || self.auto_generate
+            || self.force
+            || !self.key_option.is_empty()
+            || self.key_comment.is_some()
+            || self.target_user.is_some()
+            || self.skip_arch_validation
+            || !self.require_arch.is_empty()
+            || self.add_key
+            || self.interactive_auth
+            || self.password.is_some()
+            || self.ask_password
+            || self.fingerprint.is_some()
+            || self.secure
+            || self.sync_time
+            || self.swap.is_some()
+            || self.provision.is_some()
+            || self.provision_sudo
+            || self.harden
+            || !self.only.is_empty()
+            || !self.skip.is_empty()
+    }
+
+    fn resolve(&self, config: &Config, ssh_config: &SshConfig) -> Result<Target> {
+        let profile = match &self.profile {
+            Some(name) => config.profile(name)?.clone(),
+            None => HostProfile::default(),
+        };
+        let os_requirements = config.os_requirements(Some(&profile));
+
+        let alias =
+            self.host.clone().or(profile.host).ok_or_else(|| {
+                anyhow::anyhow!("No host given: pass --host or a host profile name")
+            })?;
+        // The host may be an alias from ~/.ssh/config, which fills in what the tool's own settings leave open
+        let aliased = ssh_config.lookup(&alias);
+        let host = aliased.host_name.unwrap_or(alias);
+        let user = self
+            .user
+            .clone()
+            .or(profile.user)
+            .or(aliased.user)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No user given: pass --user, set it in the host profile or in ~/.ssh/config"
+                )
+            })?;
+        validate::validate_host(&host)?;
+        validate::validate_username(&user)?;
+        if !self.simulate {
+            capabilities::check_mdns(&host);
+        }
+        let host_key_fingerprint = self.fingerprint.clone().or(profile.fingerprint);
+        if let Some(fingerprint) = &host_key_fingerprint {
+            validate::validate_fingerprint(fingerprint)?;
+        }
+        let key_options = if self.key_option.is_empty() {
+            profile.key_options.unwrap_or_default()
+        } else {
+            self.key_option.clone()
+        };
+        let key_options = key_options
+            .iter()
+            .map(|option| keys::key_option(option))
+            .collect::<Result<Vec<_>, _>>()?;
+        let key_comment = self.key_comment.clone().or(profile.key_comment);
+        if let Some(comment) = &key_comment {
+            validate::validate_key_comment(comment)?;
+        }
+        let target_user = self.target_user.clone().or(profile.target_user);
+        if let Some(target_user) = &target_user {
+            validate::validate_username(target_user)?;
+        }
+        let provision = self
+            .provision
+            .clone()
+            .or(profile.provision.map(PathBuf::from))
+            .map(|script| paths::expand_tilde(&script.to_string_lossy()))
+            .transpose()?
+            .map(|script| provision::Provision {
+                script,
+                sudo: self.provision_sudo || profile.provision_sudo.unwrap_or(false),
+            });
+        if provision.is_none() && self.provision_sudo {
+            anyhow::bail!("--provision-sudo needs a script to run: pass --provision");
+        }
+        let identity_file = aliased
+            .identity_file
+            .map(|path| paths::expand_tilde(&path))
+            .transpose()?;
+
+        let simulated = self.simulate.then(|| simulate::state_path(&host));
+        let mut target = Target {
+            host,
+            user,
+            key_path: self
+                .key
+                .clone()
+                .or(profile.key_path)
+                .unwrap_or_else(|| config.default_key()),
+            port: self.port.or(profile.port).unwrap_or(config.default_port),
+            skip_key_transfer: self.no_key_transfer || profile.no_key_transfer.unwrap_or(false),
+            force_key_transfer: self.force,
+            key_options,
+            key_comment,
+            target_user,
+            skip_arch_validation: self.skip_arch_validation
+                || profile
+                    .skip_arch_validation
+                    .unwrap_or(config.skip_arch_validation),
+            required_archs: self.require_arch.clone(),
+            os_requirements,
+            remote_port: aliased.port,
+            identity_file,
+            proxy_jump: aliased.proxy_jump,
+            add_to_agent: self.add_key,
+            interactive_auth: self.interactive_auth,
+            password: self.password.clone().map(askpass::Password::new),
+            security_key: false,
+            host_key_fingerprint,
+            secure: self.secure || profile.secure.unwrap_or(config.secure),
+            sync_time: self.sync_time || profile.sync_time.unwrap_or(false),
+            swap: self.swap.or(profile.swap).unwrap_or(config.swap),
+            hardware: profile.hardware.unwrap_or_else(|| config.hardware.clone()),
+            provision,
+            harden: self.harden || profile.harden.unwrap_or(false),
+            phases: if self.only.is_empty() {
+                Phases::skipping(&self.skip)
+            } else {
+                Phases::only(&self.only)
+            },
+            simulated,
+        };
+        target.hardware.validate()?;
+        target.os_requirements.validate()?;
+        target.phases.check()?;
+        if target.skip_arch_validation && !target.required_archs.is_empty() {
+            anyhow::bail!(
+                "--require-arch can't be combined with skipping the architecture validation"
+            );
+        }
+        // Naming a phase the target has nothing to do in is a mistake worth reporting
+        if let Some(idle) = self.only.iter().find(|phase| !target.runs(**phase)) {
+            anyhow::bail!(
+                "--only {} does nothing here: {}",
+                idle,
+                match idle {
+                    Phase::Clock => "pass --sync-time",
+                    Phase::Key => "the key transfer is turned off",
+                    Phase::Hardware => "no [hardware] settings apply",
+                    Phase::Swap => "swap is off; pass --swap",
+                    Phase::Provision => "pass --provision",
+                    Phase::Harden => "pass --harden",
+                    _ => "it has nothing to do",
+                }
+            );
+        }
+        target.security_key =
+            ssh_agent::login_key(&target).is_some_and(|key| keys::is_security_key_file(&key));
+        Ok(target)
+    }
+
+    /// Resolves the target of a command that acts on exactly one device
+    fn resolve_single(
+        &self,
+        command: &str,
+        config: &Config,
+        ssh_config: &SshConfig,
+    ) -> Result<Target> {
+        if self.group.is_some() {
+            anyhow::bail!(
+                "{} targets a single host; --group is not supported",
+                command
+            );
+        }
+        self.resolve(config, ssh_config)
+    }
+
+    /// Fills options not given on the command line from `SSH_IP_TUNNEL_*` variables.
+    ///
+    /// Host, port, profile and fingerprint describe a single device, so they are ignored for group runs.
+    fn apply_env(&mut self, lookup: env::Lookup) -> Result<()> {
+        if self.group.is_none() {
+            if self.profile.is_none() {
+                self.profile = lookup("PROFILE");
+            }
+            if self.host.is_none() {
+                self.host = lookup("HOST");
+            }
+            if self.port.is_none() {
+                self.port = env::parse(lookup, "PORT")?;
+            }
+            if self.fingerprint.is_none() {
+                self.fingerprint = lookup("FINGERPRINT");
+            }
+        }
+        if self.user.is_none() {
+            self.user = lookup("USER");
+        }
+        if self.key.is_none() {
+            self.key = lookup("KEY");
+        }
+        self.no_key_transfer |= env::flag(lookup, "NO_KEY_TRANSFER")?.unwrap_or(false);
+        self.auto_generate |= env::flag(lookup, "AUTO_GENERATE")?.unwrap_or(false);
+        self.force |= env::flag(lookup, "FORCE")?.unwrap_or(false);
+        if self.key_comment.is_none() {
+            self.key_comment = lookup("KEY_COMMENT");
+        }
+        if self.target_user.is_none() {
+            self.target_user = lookup("TARGET_USER");
+        }
+        self.skip_arch_validation |= env::flag(lookup, "SKIP_ARCH_VALIDATION")?.unwrap_or(false);
+        if self.require_arch.is_empty() {
+            self.require_arch = lookup("REQUIRE_ARCH")
+                .iter()
+                .flat_map(|list| list.split(','))
+                .map(|arch| arch.trim().parse().map_err(anyhow::Error::msg))
+                .collect::<Result<_>>()
+                .map_err(|e| anyhow::anyhow!("SSH_IP_TUNNEL_REQUIRE_ARCH: {}", e))?;
+        }
+        self.add_key |= env::flag(lookup, "ADD_KEY")?.unwrap_or(false);
+        self.interactive_auth |= env::flag(lookup, "INTERACTIVE_AUTH")?.unwrap_or(false);
+        self.secure |= env::flag(lookup, "SECURE")?.unwrap_or(false);
+        self.sync_time |= env::flag(lookup, "SYNC_TIME")?.unwrap_or(false);
+        self.harden |= env::flag(lookup, "HARDEN")?.unwrap_or(false);
+        if self.provision.is_none() {
+            self.provision = lookup("PROVISION").map(PathBuf::from);
+        }
+        self.provision_sudo |= env::flag(lookup, "PROVISION_SUDO")?.unwrap_or(false);
+        if self.swap.is_none() {
+            self.swap = env::parse(lookup, "SWAP")?;
+        }
+        if self.password.is_none() && !self.ask_password {
+            self.password = lookup("PASSWORD");
+        }
+        if self.only.is_empty() && self.skip.is_empty() {
+            let phases = |name| -> Result<Vec<Phase>> {
+                lookup(name)
+                    .iter()
+                    .flat_map(|list| list.split(','))
+                    .map(|phase| phase.trim().parse().map_err(anyhow::Error::msg))
+                    .collect::<Result<_>>()
+                    .map_err(|e| anyhow::anyhow!("SSH_IP_TUNNEL_{}: {}", name, e))
+            };
+            self.only = phases("ONLY")?;
+            self.skip = phases("SKIP")?;
+            if !self.only.is_empty() && !self.skip.is_empty() {
+                anyhow::bail!("SSH_IP_TUNNEL_ONLY and SSH_IP_TUNNEL_SKIP can't be combined");
+            }
+        }
+        Ok(())
+    }
+
+    /// The `SSH_IP_TUNNEL_*` suffixes [`TargetArgs::apply_env`] reads, in order
+    fn env_variables() -> Vec<String> {
+        let read = std::cell::RefCell::new(Vec::new());
+        let lookup = |suffix: &str| {
+            read.borrow_mut().push(suffix.to_string());
+            None
+        };
+        let _ = TargetArgs::default().apply_env(&lookup);
+        read.into_inner()
+    }
+
+    /// With --last, takes the host, and the user and port unless given, from
+    /// the newest connection in the history
+    fn apply_last(&mut self) -> Result<()> {
+        if !self.last {
+            return Ok(());
+        }
+        let last = history::last_connection().ok_or_else(|| {
+            anyhow::anyhow!("No connection in the history to repeat; run `up` with a host first")
+        })?;
+        info!("Repeating the connection to {}@{}", last.user, last.host);
+        self.host = Some(last.host);
+        self.user.get_or_insert(last.user);
+        self.port.get_or_insert(last.port);
+        Ok(())
+    }
+
+    /// Asks on the terminal for a host and user that neither the options,
+    /// the host profile nor ~/.ssh/config give: a device to pick from recent
+    /// runs and the host profiles, then any user still missing
+    fn ask_missing(&mut self, config: &Config, ssh_config: &SshConfig) -> Result<()> {
+        if self.group.is_some() || !prompt::is_interactive() {
+            return Ok(());
+        }
+        if self.host.is_none() && self.profile.is_none() {
+            match picker::pick(config)? {
+                Some(picker::Choice::Profile(name)) => self.profile = Some(name),
+                Some(picker::Choice::Login { host, user }) => {
+                    self.host = Some(host);
+                    self.user = self.user.take().or(user);
+                }
+                None => {}
+            }
+        }
+        let profile = match &self.profile {
+            Some(name) => config.profile(name)?.clone(),
+            None => HostProfile::default(),
+        };
+        let last = history::last_login();
+        if self.host.is_none() && profile.host.is_none() {
+            let default = last.as_ref().map_or("", |(host, _)| host.as_str());
+            let host = prompt::ask("Host", default)?;
+            self.host = (!host.is_empty()).then_some(host);
+        }
+        let Some(alias) = self.host.as_ref().or(profile.host.as_ref()) else {
+            return Ok(());
+        };
+        if self.user.is_none() && profile.user.is_none() && ssh_config.lookup(alias).user.is_none()
+        {
+            let default = last.as_ref().map_or("", |(_, user)| user.as_str());
+            let user = prompt::ask(&format!("User on {}", alias), default)?;
+            self.user = (!user.is_empty()).then_some(user);
+        }
+        Ok(())
+    }
+
+    /// Asks for the password once, before any device of a group is contacted
+    fn read_password(&mut self) -> Result<()> {
+        if !self.ask_password {
+            return Ok(());
+        }
+        if !prompt::is_interactive() {
+            anyhow::bail!("--ask-password needs a terminal; set SSH_IP_TUNNEL_PASSWORD instead");
+        }
+        let password = askpass::ask("SSH password: ")
+            .map_err(|e| anyhow::anyhow!("Cannot read password: {}", e))?;
+        self.password = Some(password);
+        Ok(())
+    }
+
+    /// Resolves every member of `group` as if it had been named as a profile.
+    ///
+    /// Members without a port of their own get `default_port + index`, so the
+    /// local forwards of a group never collide.
+    fn resolve_group(
+        &self,
+        group: &str,
+        config: &Config,
+        ssh_config: &SshConfig,
+    ) -> Result<Vec<(String, Target)>> {
+        let mut targets = Vec::new();
+        for (index, member) in config.group(group)?.iter().enumerate() {
+            let args = TargetArgs {
+                profile: Some(member.clone()),
+                group: None,
+                ..self.clone()
+            };
+            let mut target = args.resolve(config, ssh_config)?;
+            if config.profile(member)?.port.is_none() {
+                target.port = config
+                    .default_port
+                    .checked_add(index as u16)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Ran out of local ports for group '{}'", group)
+                    })?;
+            }
+            targets.push((member.clone(), target));
+        }
+
+        for (i, (name, target)) in targets.iter().enumerate() {
+            if let Some((other, _)) = targets[..i].iter().find(|(_, t)| t.port == target.port) {
+                anyhow::bail!(
+                    "Hosts '{}' and '{}' in group '{}' both use local port {}",
+                    other,
+                    name,
+                    group,
+                    target.port
+                );
+            }
+        }
+
+        Ok(targets)
+    }
+}
+
+/// Initialize logging based on verbosity level
+/// Sets up console logging, on stderr when `format` is machine-readable or
+/// `stdout_is_data`, in the dashboard's log pane with `tui`, and, when
+/// `host_logs` is given, per-host log files
+fn init_logging(
+    verbose: bool,
+    format: OutputFormat,
+    color: ColorChoice,
+    stdout_is_data: bool,
+    tui: bool,
+    host_logs: Option<&Path>,
+    run_log: Option<std::fs::File>,
+) -> Result<()> {
+    let log_level = match (verbose, format, host_logs) {
+        (true, _, _) => "debug",
+        (false, OutputFormat::Quiet, _) => "error",
+        // The full detail goes to the per-host files
+        (false, _, Some(_)) => "warn",
+        // Nobody watches the progress of a batch run; the history keeps it
+        (false, _, None) if prompt::is_batch() => "warn",
+        // Status lines tell the progress instead
+        (false, _, None) if output::style().status_lines => "warn",
+        (false, _, None) => "info",
+    };
+
+    // Keep stdout clean for machine-readable output
+    let (writer, ansi) = if format.is_machine_readable() || stdout_is_data {
+        (
+            BoxMakeWriter::new(std::io::stderr),
+            color.enabled(&std::io::stderr()),
+        )
+    } else if tui {
+        (BoxMakeWriter::new(|| tui::Console), false)
+    } else if output::style().status_lines {
+        (
+            BoxMakeWriter::new(|| spinner::Stdout),
+            color.enabled(&std::io::stdout()),
+        )
+    } else {
+        (
+            BoxMakeWriter::new(std::io::stdout),
+            color.enabled(&std::io::stdout()),
+        )
+    };
+
+    let console = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .with_target(false)
+        .with_thread_ids(false)
+        .with_file(false)
+        .with_line_number(false)
+        .with_filter(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| format!("ssh_ip_tunnel={}", log_level).into()),
+        );
+
+    let host_layer = host_logs
+        .map(|dir| {
+            hostlog::HostLogLayer::new(dir)
+                .map(|layer| layer.with_filter(EnvFilter::new("ssh_ip_tunnel=debug")))
+                .map_err(|e| {
+                    anyhow::anyhow!("Cannot create log directory {}: {}", dir.display(), e)
+                })
+        })
+        .transpose()?;
+
+    // The run's history keeps everything, like the per-host files
+    let run_layer = run_log.map(|file| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::sync::Mutex::new(file))
+            .with_ansi(false)
+            .with_target(false)
+            .with_filter(EnvFilter::new("ssh_ip_tunnel=debug"))
+    });
+
+    tracing_subscriber::registry()
+        .with(console)
+        .with(host_layer)
+        .with(run_layer)
+        .init();
+    Ok(())
+}
+
+/// Result of `config show`
+#[derive(Debug, Serialize)]
+struct EffectiveConfig {
+    source: Option<PathBuf>,
+    config: Config,
+    target: Option<Target>,
+}
+
+impl Renderable for EffectiveConfig {
+    fn to_human(&self) -> String {
+        let mut text = match &self.source {
+            Some(path) => format!("# Loaded from {}\n", path.display()),
+            None => "# No configuration file found; built-in defaults\n".to_string(),
+        };
+        text.push_str(&toml::to_string_pretty(&self.config).unwrap_or_default());
+        if let Some(target) = &self.target {
+            text.push_str("\n# Resolved target\n");
+            text.push_str(&toml::to_string_pretty(target).unwrap_or_default());
+        }
+        text.trim_end().to_string()
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Result of `config validate`
+#[derive(Debug, Serialize)]
+struct ValidationReport {
+    path: PathBuf,
+    problems: Vec<config::Diagnostic>,
+}
+
+impl Renderable for ValidationReport {
+    fn to_human(&self) -> String {
+        if self.problems.is_empty() {
+            return format!("{}: ok", self.path.display());
+        }
+        self.problems
+            .iter()
+            .map(|p| {
+                let location = match p.line {
+                    Some(line) => format!("{}:{}", self.path.display(), line),
+                    None => self.path.display().to_string(),
+                };
+                if p.field.is_empty() {
+                    format!("{}: {}", location, p.message)
+                } else {
+                    format!("{}: {}: {}", location, p.field, p.message)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+async fn run_keys_generate(
+    key: Option<String>,
+    comment: Option<String>,
+    config_path: Option<PathBuf>,
+) -> Result<()> {
+    let key = match key {
+        Some(key) => key,
+        None => load_config(config_path)?.default_key(),
+    };
+    let comment = match comment {
+        Some(comment) => comment,
+        None => keys::default_comment().await,
+    };
+    let report = keys::generate(&paths::expand_tilde(&key)?, &comment).await?;
+    output::renderer().result(&report);
+    Ok(())
+}
+
+async fn run_known_hosts_command(action: KnownHostsCommand) -> Result<()> {
+    match action {
+        KnownHostsCommand::List { user_file } | KnownHostsCommand::Export { user_file } => {
+            let file = host_keys::store(user_file)?;
+            let contents = match action {
+                KnownHostsCommand::Export { .. } if file.exists() => Some(
+                    std::fs::read_to_string(&file)
+                        .map_err(|e| anyhow::anyhow!("Cannot read {}: {}", file.display(), e))?,
+                ),
+                KnownHostsCommand::Export { .. } => Some(String::new()),
+                _ => None,
+            };
+            let entries = host_keys::list(&file).await?;
+            output::renderer().result(&host_keys::ListReport {
+                file,
+                entries,
+                contents,
+            });
+        }
+        KnownHostsCommand::Remove { host, user_file } => {
+            let file = host_keys::store(user_file)?;
+            let removed = host_keys::remove(&file, &host).await?;
+            output::renderer().result(&host_keys::ChangeReport {
+                file,
+                host,
+                fingerprint: None,
+                removed,
+            });
+        }
+        KnownHostsCommand::Add { .. } => unreachable!("takes target options"),
+    }
+    Ok(())
+}
+
+fn run_config_command(action: ConfigCommand, config_path: Option<PathBuf>) -> Result<()> {
+    match action {
+        ConfigCommand::Show(mut target_args) => {
+            target_args.apply_env(&env::process_lookup)?;
+            let source = config::config_source(config_path.clone());
+            let config = load_config(config_path)?;
+            let target = if target_args.is_given() {
+                Some(target_args.resolve(&config, &SshConfig::load()?)?)
+            } else {
+                None
+            };
+            output::renderer().result(&EffectiveConfig {
+                source,
+                config,
+                target,
+            });
+            Ok(())
+        }
+        ConfigCommand::Validate { path } => {
+            let path = path
+                .or_else(|| config::config_source(config_path))
+                .ok_or_else(|| anyhow::anyhow!("No configuration file found to validate"))?;
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow::anyhow!("Failed to read config file {:?}: {}", path, e))?;
+
+            let report = ValidationReport {
+                problems: config::validate_str(&contents, &interpolate::process_env),
+                path,
+            };
+            output::renderer().result(&report);
+            if !report.problems.is_empty() {
+                anyhow::bail!(
+                    "{} problem(s) found in {}",
+                    report.problems.len(),
+                    report.path.display()
+                );
+            }
+            Ok(())
+        }
+        ConfigCommand::Init {
+            path,
+            interactive,
+            force,
+        } => {
+            let path = match path {
+                Some(path) => path,
+                None => paths::user_config_dir()
+                    .map(|dir| dir.join("config.toml"))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Could not determine the config directory; pass --path")
+                    })?,
+            };
+
+            let defaults = Config::default();
+            let mut default_key_path = defaults.default_key();
+            let mut default_port = defaults.default_port;
+            if interactive {
+                if !prompt::is_interactive() {
+                    anyhow::bail!("--interactive needs a terminal");
+                }
+                default_key_path = prompt::ask("Default SSH public key path", &default_key_path)?;
+                default_port = prompt::ask("Default local tunnel port", &default_port.to_string())?
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid port: {}", e))?;
+            }
+
+            let contents = config::render_template(&default_key_path, default_port);
+            config::write_template(&path, &contents, force)?;
+            output::renderer().result(&ConfigInitReport {
+                path,
+                default_key_path,
+                default_port,
+            });
+            Ok(())
+        }
+    }
+}
+
+/// Installs the systemd unit for a host profile, or prints it
+async fn run_service_command(action: ServiceCommand, config_path: Option<PathBuf>) -> Result<()> {
+    let ServiceCommand::Install {
+        profile,
+        system,
+        force,
+        print,
+    } = action;
+    let config = load_config(config_path.clone())?;
+    config.profile(&profile)?;
+    // The unit names the file, so it finds the profile whatever its environment
+    let config_file = config::config_source(config_path)
+        .map(|path| std::path::absolute(&path))
+        .transpose()?;
+    let unit = service::Unit {
+        program: std::env::current_exe()?,
+        profile,
+        config: config_file,
+        namespace: paths::namespace().map(str::to_string),
+        run_as: service::invoking_user(),
+        scope: if system {
+            service::Scope::System
+        } else {
+            service::Scope::User
+        },
+        tunnel_timeout_secs: config.tunnel_timeout_secs,
+    };
+    if print {
+        unit.name()?;
+        print!("{}", unit.render());
+        return Ok(());
+    }
+    output::renderer().result(&service::install(&unit, force).await?);
+    Ok(())
+}
+
+/// Runs the `ssh-ip-tunnel` command line, returning the process's exit code:
+/// 0, or one by the kind of failure (see [`exit`])
+pub async fn main() -> std::process::ExitCode {
+    if askpass::respond_if_invoked() {
+        return std::process::ExitCode::SUCCESS;
+    }
+    // A shell asking for completions gets them and nothing else
+    CompleteEnv::with_factory(Cli::command)
+        .var(completions::VAR)
+        .bin(completions::bin_name())
+        .complete();
+    match run_main().await {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            std::process::ExitCode::from(exit::code(&e))
+        }
+    }
+}
+
+async fn run_main() -> Result<()> {
+    let cli = Cli::parse();
+    // Off before anything could ask
+    let batch =
+        env::flag(&env::process_lookup, "BATCH").map(|batch| cli.batch || batch == Some(true));
+    if matches!(batch, Ok(true)) {
+        prompt::disable();
+    }
+
+    output::init(
+        cli.output_format(),
+        Style {
+            status_lines: cli.shows_status_lines(),
+            color: cli.color.enabled(&std::io::stdout()),
+        },
+    );
+    // The namespace decides where this run's history goes, so it is set before logging starts
+    let namespace = cli
+        .namespace
+        .clone()
+        .or_else(|| env::process_lookup("NAMESPACE"));
+    let namespace = match namespace.map(|name| validate::validate_namespace(&name).map(|()| name)) {
+        Some(Ok(name)) => {
+            paths::set_namespace(name.clone());
+            Ok(Some(name))
+        }
+        Some(Err(e)) => Err(anyhow::Error::new(e)),
+        None => Ok(None),
+    };
+    let run_id = run::current();
+    let host_logs = cli.host_log_dir(run_id);
+    // Looking at the history doesn't add to it, nor does printing a script
+    // or the manual
+    let recorded = !matches!(
+        cli.command,
+        Some(Commands::History { .. } | Commands::Completions { .. } | Commands::Docs { .. })
+    ) && namespace.is_ok();
+    let args: Vec<String> = std::env::args_os()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    let run_log = recorded.then(|| history::begin(&args, host_logs.as_deref()));
+    let (run_log_file, run_log_error) = match run_log.transpose() {
+        Ok(file) => (file, None),
+        Err(e) => (None, Some(e)),
+    };
+    if let Err(e) = init_logging(
+        cli.verbose,
+        cli.output_format(),
+        cli.color,
+        cli.stdout_is_data(),
+        matches!(cli.command, Some(Commands::Tui)),
+        host_logs.as_deref(),
+        run_log_file,
+    ) {
+        output::renderer().error(&e);
+        return Err(e);
+    }
+    if let Some(e) = run_log_error {
+        warn!("This run won't be in the history: {}", e);
+    }
+
+    let result = async {
+        let result = start(cli, namespace, batch, host_logs).await;
+        if let Err(e) = &result {
+            output::renderer().error(e);
+        }
+        result
+    }
+    .instrument(info_span!("run", id = %run_id))
+    .await;
+    history::finish(
+        result.as_ref().err(),
+        result
+            .as_ref()
+            .map_or_else(|e| exit::code(e).into(), |()| 0),
+    );
+    result
+}
+
+/// Sets up fault injection and simulation, then runs the command
+async fn start(
+    cli: Cli,
+    namespace: Result<Option<String>>,
+    batch: Result<bool>,
+    host_logs: Option<PathBuf>,
+) -> Result<()> {
+    if let Some(namespace) = namespace? {
+        debug!("Using namespace {}", namespace);
+    }
+    if batch? {
+        debug!("Batch mode: nothing will prompt");
+    }
+    let faults = cli
+        .inject_fault
+        .clone()
+        .or_else(|| env::process_lookup("FAULT"))
+        .map(|spec| fault::parse(&spec))
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("Invalid fault injection: {}", e))?;
+    if let Some(faults) = faults {
+        let points: Vec<String> = faults.iter().map(ToString::to_string).collect();
+        warn!("Injecting faults: {}", points.join(", "));
+        fault::install(faults);
+    }
+    if cli.simulate {
+        warn!(
+            "Simulating devices; their state is kept in {}",
+            simulate::state_path("")
+                .parent()
+                .unwrap_or(Path::new(""))
+                .display()
+        );
+    }
+    run_cli(cli, host_logs).await
+}
+
+/// Records the run, then exits with a remote command's exit `code`
+fn exit_with(code: i32) -> ! {
+    history::finish(None, code);
+    std::process::exit(code);
+}
+
+async fn run_cli(cli: Cli, host_logs: Option<PathBuf>) -> Result<()> {
+    if cli.command.is_some() && cli.target.is_given() {
+        anyhow::bail!("Target options must come after the subcommand name");
+    }
+
+    let mut command = cli.command.unwrap_or(Commands::Up(cli.target));
+    // copy and sync take device names in their paths, so the first path may
+    // have been read as a profile
+    if let Commands::Copy { target, paths, .. } | Commands::Sync { target, paths, .. } =
+        &mut command
+    {
+        paths.splice(0..0, target.profile.take());
+    }
+    if !matches!(command, Commands::Capabilities) {
+        let capabilities = capabilities::detect();
+        // Simulated devices need nothing but this program
+        if !cli.simulate {
+            capabilities.require(command.required_tools())?;
+        }
+        capabilities.log_missing(command.optional_tools());
+    }
+    // Two snapshot files need no device to ask for
+    let needs_device = !matches!(
+        command,
+        Commands::Snapshot {
+            action: Some(SnapshotCommand::Diff { to: Some(_), .. }),
+            ..
+        }
+    );
+    let Some(target_args) = command.target_args_mut() else {
+        match command {
+            Commands::Capabilities => {
+                output::renderer().result(capabilities::detect());
+                return Ok(());
+            }
+            Commands::Config { action } => return run_config_command(action, cli.config),
+            Commands::Status { stats } => {
+                output::renderer().result(&watch::status(stats).await);
+                return Ok(());
+            }
+            Commands::KnownHosts { action } => return run_known_hosts_command(action).await,
+            Commands::History { action } => {
+                match action {
+                    HistoryCommand::Show { run_id } => {
+                        output::renderer().result(&history::show(&run_id)?)
+                    }
+                    HistoryCommand::List { limit } => {
+                        let mut connections = history::connections();
+                        connections.truncate(limit);
+                        output::renderer().result(&history::ConnectionList(connections));
+                    }
+                    HistoryCommand::Clear => output::renderer().result(&history::clear()?),
+                }
+                return Ok(());
+            }
+            Commands::Keys {
+                action: KeysCommand::Generate { key, comment },
+            } => return run_keys_generate(key, comment, cli.config).await,
+            Commands::Tui => return run_tui(cli.config, cli.simulate).await,
+            Commands::Service { action } => return run_service_command(action, cli.config).await,
+            Commands::Completions { shell } => {
+                completions::write_script(shell, &mut std::io::stdout())?;
+                return Ok(());
+            }
+            Commands::Docs {
+                action: DocsCommand::Man,
+            } => {
+                man::render(
+                    Cli::command(),
+                    &TargetArgs::env_variables(),
+                    &mut std::io::stdout(),
+                )?;
+                return Ok(());
+            }
+            _ => unreachable!("every other command takes target options"),
+        }
+    };
+    target_args.simulate = cli.simulate;
+    target_args.apply_env(&env::process_lookup)?;
+    target_args.apply_last()?;
+    target_args.read_password()?;
+
+    let config = load_config(cli.config.clone())?;
+    let ssh_config = SshConfig::load()?;
+    if needs_device {
+        target_args.ask_missing(&config, &ssh_config)?;
+    }
+
+    match command {
+        Commands::Up(target_args) => run_up(&target_args, &config, &ssh_config, host_logs).await,
+        Commands::Down(target) => {
+            let target = target.resolve_single("down", &config, &ssh_config)?;
+            // A watch would open a tunnel closed behind its back again
+            let closed = match control::request(target.port, control::Request::Down).await {
+                Some(answer) => answer.map(|_| true)?,
+                None => {
+                    SSHTunnelManager::new(config.clone())
+                        .close_tunnel(&target)
+                        .await?
+                }
+            };
+            output::renderer().result(&tunnel::CloseReport {
+                host: target.host.clone(),
+                port: target.port,
+                closed,
+            });
+            Ok(())
+        }
+        Commands::Push {
+            target,
+            file,
+            from_url,
+            dest,
+            sha256,
+            verify,
+            streams,
+        } => {
+            let source = match (file, from_url) {
+                (Some(path), _) => push::PushSource::File(path),
+                (None, Some(url)) => push::PushSource::Url(url),
+                (None, None) => anyhow::bail!("Nothing to push: pass --file or --from-url"),
+            };
+            let target = target.resolve_single("push", &config, &ssh_config)?;
+            let report = push::push(
+                &config,
+                &target,
+                &source,
+                dest.as_deref(),
+                sha256.as_deref(),
+                verify,
+                streams.into(),
+            )
+            .await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Copy {
+            mut target,
+            paths,
+            recursive,
+        } => {
+            let plan = copy::CopyPlan::parse(&paths)?;
+            if plan.device != copy::REMOTE {
+                target.profile = Some(plan.device.clone());
+            }
+            let target = target.resolve_single("copy", &config, &ssh_config)?;
+            let report = copy::copy(&config, &target, &plan, recursive).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Sync {
+            mut target,
+            paths,
+            delete,
+            exclude,
+        } => {
+            let plan = copy::CopyPlan::parse(&paths)?;
+            if plan.device != copy::REMOTE {
+                target.profile = Some(plan.device.clone());
+            }
+            let target = target.resolve_single("sync", &config, &ssh_config)?;
+            let options = rsync::SyncOptions {
+                delete,
+                excludes: exclude,
+            };
+            let report = rsync::sync(&config, &target, &plan, &options).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Agent {
+            action:
+                AgentCommand::Install {
+                    target,
+                    binary,
+                    artifacts,
+                    verify,
+                },
+        } => {
+            let build = match (binary, artifacts.or_else(|| config.artifacts.clone())) {
+                (Some(path), _) => agent::AgentBuild::Path(path),
+                (None, Some(source)) => agent::AgentBuild::Matching(source),
+                (None, None) => anyhow::bail!(
+                    "No agent build given: pass --binary or --artifacts, or set `artifacts` in the config"
+                ),
+            };
+            let target = target.resolve_single("agent install", &config, &ssh_config)?;
+            let report = agent::install(&config, &target, &build, verify).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Flash {
+            target,
+            image,
+            device,
+            yes,
+            no_verify,
+        } => {
+            let target = target.resolve_single("flash", &config, &ssh_config)?;
+            let report = flash::flash(&config, &target, &image, &device, yes, no_verify).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Keys {
+            action: KeysCommand::DeployCa { target, ca },
+        } => {
+            let target = target.resolve_single("keys deploy-ca", &config, &ssh_config)?;
+            let report = certs::deploy_ca(&config, &target, &ca).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Keys {
+            action: KeysCommand::List { target },
+        } => {
+            let target = target.resolve_single("keys list", &config, &ssh_config)?;
+            let report = authorized_keys::list(&config, &target).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Keys {
+            action:
+                KeysCommand::Revoke {
+                    target,
+                    key_fingerprint,
+                    comment,
+                },
+        } => {
+            let target = target.resolve_single("keys revoke", &config, &ssh_config)?;
+            let matching = match (key_fingerprint, comment) {
+                (Some(fingerprint), _) => authorized_keys::KeyMatch::Fingerprint(fingerprint),
+                (None, Some(comment)) => authorized_keys::KeyMatch::Comment(comment),
+                (None, None) => authorized_keys::KeyMatch::Key(keys::read_public_key(
+                    &paths::expand_tilde(&target.key_path)?,
+                )?),
+            };
+            let report = authorized_keys::revoke(&config, &target, matching).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Keys {
+            action:
+                KeysCommand::Rotate {
+                    target,
+                    new_key,
+                    comment,
+                    keep_old,
+                },
+        } => {
+            let target = target.resolve_single("keys rotate", &config, &ssh_config)?;
+            let new_key = match new_key {
+                Some(path) => paths::expand_tilde(&path)?,
+                None => rotate::default_new_key_path(
+                    &paths::expand_tilde(&target.key_path)?,
+                    chrono::Local::now().date_naive(),
+                ),
+            };
+            let comment = match comment {
+                Some(comment) => comment,
+                None => keys::default_comment().await,
+            };
+            let report = rotate::rotate(&config, &target, &new_key, &comment, keep_old).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Keys {
+            action: KeysCommand::RestoreBackup { target, backup },
+        } => {
+            let target = target.resolve_single("keys restore-backup", &config, &ssh_config)?;
+            let report =
+                authorized_keys::restore_backup(&config, &target, backup.as_deref()).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::DeployContainer {
+            target,
+            image,
+            transfer,
+            archive,
+            runtime,
+            name,
+            env,
+            publish,
+            volume,
+            run_arg,
+            no_run,
+        } => {
+            let target = target.resolve_single("deploy-container", &config, &ssh_config)?;
+            let deployment = container::Deployment {
+                image,
+                transfer,
+                archive,
+                runtime,
+                name,
+                env,
+                publish,
+                volumes: volume,
+                run_args: run_arg,
+                no_run,
+            };
+            let report = container::deploy(&config, &target, &deployment).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Mirror {
+            target,
+            registry,
+            apt_proxy,
+            reverse_proxy,
+            dns,
+            run,
+        } => {
+            let target = target.resolve_single("mirror", &config, &ssh_config)?;
+            let options = mirror::MirrorOptions {
+                registry,
+                apt_proxy,
+                reverse_proxy,
+                dns,
+                run,
+            };
+            let report = mirror::mirror(&config, &target, &options).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::ApplyProfile {
+            target,
+            device_profile,
+            plan,
+            yes,
+            refresh_facts,
+        } => {
+            let host_name = target.profile.clone();
+            let target = target.resolve_single("apply-profile", &config, &ssh_config)?;
+            let name = device_profile
+                .or_else(|| {
+                    let profile = config.hosts.get(host_name.as_deref()?)?;
+                    profile.device_profile.clone()
+                })
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "No device profile given: pass --device-profile or set device_profile in the host profile"
+                    )
+                })?;
+            let host_name = host_name.unwrap_or_else(|| target.host.clone());
+            let options = device_profile::ApplyOptions {
+                plan_only: plan,
+                assume_yes: yes,
+                refresh_facts,
+            };
+            let report =
+                device_profile::apply(&config, &target, &name, &host_name, options).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Exec { target, command } => {
+            let target = target.resolve_single("exec", &config, &ssh_config)?;
+            let code = exec::exec(&config, &target, &command).await?;
+            if code != 0 {
+                exit_with(code);
+            }
+            Ok(())
+        }
+        Commands::Shell { target } => {
+            let target = target.resolve_single("shell", &config, &ssh_config)?;
+            let code = exec::shell(&config, &target).await?;
+            if code != 0 {
+                exit_with(code);
+            }
+            Ok(())
+        }
+        Commands::Snapshot {
+            target,
+            action: None,
+        } => {
+            let target = target.resolve_single("snapshot", &config, &ssh_config)?;
+            let report = snapshot::take(&config, &target).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Snapshot {
+            action: Some(SnapshotCommand::Diff { target, from, to }),
+            ..
+        } => {
+            // Two snapshot files need no device
+            let target = if to.is_some() && !target.is_given() {
+                None
+            } else {
+                Some(target.resolve_single("snapshot diff", &config, &ssh_config)?)
+            };
+            let diff = snapshot::diff(&config, target.as_ref(), &from, to.as_deref()).await?;
+            output::renderer().result(&diff);
+            Ok(())
+        }
+        Commands::Info { target, format } => {
+            let target = target.resolve_single("info", &config, &ssh_config)?;
+            let info = info::gather(&config, &target).await?;
+            output::renderer().result(&InfoReport { info, format });
+            Ok(())
+        }
+        Commands::Ping {
+            target,
+            count,
+            interval,
+        } => {
+            let target = target.resolve_single("ping", &config, &ssh_config)?;
+            let report =
+                ping::run(&config, &target, count, Duration::from_millis(interval)).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Bench {
+            target,
+            size,
+            direction,
+        } => {
+            let target = target.resolve_single("bench", &config, &ssh_config)?;
+            let report = bench::run(&config, &target, size, direction).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Harden { target } => {
+            let target = target.resolve_single("harden", &config, &ssh_config)?;
+            let report = harden::run(&config, &target).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Onboard {
+            target,
+            name,
+            hostname,
+            no_harden,
+            binary,
+            artifacts,
+            no_agent,
+            yes,
+        } => {
+            let agent = match (binary, artifacts.or_else(|| config.artifacts.clone())) {
+                _ if no_agent => None,
+                (Some(path), _) => Some(agent::AgentBuild::Path(path)),
+                (None, Some(source)) => Some(agent::AgentBuild::Matching(source)),
+                (None, None) => None,
+            };
+            let config_file = match config::config_source(cli.config) {
+                Some(path) => path,
+                None => paths::user_config_dir()
+                    .map(|dir| dir.join("config.toml"))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Could not determine the config directory; pass --config")
+                    })?,
+            };
+            let target = target.resolve_single("onboard", &config, &ssh_config)?;
+            let options = onboard::OnboardOptions {
+                name,
+                hostname,
+                no_harden,
+                agent,
+                config_file,
+                assume_yes: yes,
+            };
+            let report = onboard::onboard(&config, &target, &options).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Update {
+            action:
+                UpdateCommand::Install {
+                    target,
+                    bundle,
+                    dest,
+                    updater,
+                    reboot_timeout,
+                    health_command,
+                },
+        } => {
+            let target = target.resolve_single("update install", &config, &ssh_config)?;
+            let options = update::InstallOptions {
+                dest,
+                updater,
+                reboot_timeout: Duration::from_secs(reboot_timeout),
+                health_command,
+            };
+            let report = update::install(&config, &target, &bundle, &options).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::Update {
+            action: UpdateCommand::Status { target },
+        } => {
+            let target = target.resolve_single("update status", &config, &ssh_config)?;
+            let report = update::status(&config, &target).await?;
+            output::renderer().result(&report);
+            Ok(())
+        }
+        Commands::KnownHosts {
+            action:
+                KnownHostsCommand::Add {
+                    target,
+                    host_key,
+                    user_file,
+                },
+        } => {
+            let target = target.resolve_single("known-hosts add", &config, &ssh_config)?;
+            let file = host_keys::store(user_file)?;
+            let fingerprint = match (&host_key, &target.host_key_fingerprint) {
+                (Some(_), Some(_)) => {
+                    anyhow::bail!("--host-key and --fingerprint can't be combined")
+                }
+                (Some(path), None) => host_keys::add_key_file(&file, &target, path).await?,
+                (None, fingerprint) => {
+                    host_keys::add_from_device(&file, &target, fingerprint.as_deref()).await?
+                }
+            };
+            output::renderer().result(&host_keys::ChangeReport {
+                file,
+                host: host_keys::alias(&target),
+                fingerprint: Some(fingerprint),
+                removed: 0,
+            });
+            Ok(())
+        }
+        Commands::Config { .. }
+        | Commands::Status { .. }
+        | Commands::KnownHosts { .. }
+        | Commands::History { .. }
+        | Commands::Capabilities
+        | Commands::Tui
+        | Commands::Service { .. }
+        | Commands::Completions { .. }
+        | Commands::Docs { .. }
+        | Commands::Keys {
+            action: KeysCommand::Generate { .. },
+        } => unreachable!("handled above"),
+    }
+}
+
+/// Provisions one target, or every member of a group
+async fn run_up(
+    target_args: &TargetArgs,
+    config: &Config,
+    ssh_config: &SshConfig,
+    host_logs: Option<PathBuf>,
+) -> Result<()> {
+    if let Some(group) = &target_args.group {
+        let targets = target_args.resolve_group(group, config, ssh_config)?;
+        if target_args.auto_generate {
+            generate_missing_keys(targets.iter().map(|(_, target)| target)).await?;
+        }
+        let total = targets.len();
+        let mut report = fleet::run_fleet(config, targets, target_args.jobs).await;
+        report.log_dir = host_logs;
+        output::renderer().result(&report);
+
+        let failed = report.failed();
+        if failed > 0 {
+            anyhow::bail!("{} of {} hosts in group '{}' failed", failed, total, group);
+        }
+        return Ok(());
+    }
+
+    let target = target_args.resolve(config, ssh_config)?;
+    if target_args.auto_generate {
+        generate_missing_keys([&target]).await?;
+    }
+    if target_args.watch {
+        systemd::enable();
+    }
+    if target_args.notify {
+        desktop::enable();
+    }
+    let live = watch::Live::default();
+    // Bound before the run, so a taken address fails it before the tunnel opens
+    let _health = match target_args.health_listen {
+        Some(address) => Some(health::HealthServer::start(address, live.clone()).await?),
+        None => None,
+    };
+    let _api = match target_args.api_listen {
+        Some(address) => {
+            Some(api::ApiServer::start(address, api::token()?, live.clone(), target.clone()).await?)
+        }
+        None => None,
+    };
+
+    let report = fleet::run_target(config, &target).await?;
+    // A simulated device's speed says nothing about the real one's
+    if target.simulated.is_none() {
+        let class = timing::class_of(config, target_args.profile.as_deref());
+        timing::Timings::remember(&class, &report.phase_ms);
+    }
+    output::renderer().result(&report);
+    live.set_report(&report);
+
+    if target_args.watch {
+        watch::watch(config, &target, &live).await?;
+    }
+    Ok(())
+}
+
+/// Shows the dashboard of every host profile, then the devices of recent
+/// runs on other ports
+async fn run_tui(config_path: Option<PathBuf>, simulate: bool) -> Result<()> {
+    if output::format() != OutputFormat::Human || prompt::is_batch() {
+        anyhow::bail!("tui is interactive; it needs the human output and can't run with --batch");
+    }
+    let config = load_config(config_path)?;
+    let ssh_config = SshConfig::load()?;
+    let connections = history::connections();
+    let connected = |target: &Target| {
+        connections
+            .iter()
+            .find(|past| past.connection.host == target.host && past.connection.port == target.port)
+            .map(|past| past.connection.timestamp)
+    };
+
+    let mut tunnels: Vec<tui::Tunnel> = Vec::new();
+    for name in config.hosts.keys() {
+        let args = TargetArgs {
+            profile: Some(name.clone()),
+            simulate,
+            ..TargetArgs::default()
+        };
+        match args.resolve(&config, &ssh_config) {
+            Ok(target) => tunnels.push(tui::Tunnel {
+                name: name.clone(),
+                connected: connected(&target),
+                target,
+            }),
+            Err(e) => warn!("Leaving out host profile '{}': {:#}", name, e),
+        }
+    }
+    for past in &connections {
+        let connection = &past.connection;
+        if tunnels.iter().any(|tunnel| {
+            tunnel.target.host == connection.host && tunnel.target.port == connection.port
+        }) {
+            continue;
+        }
+        let args = TargetArgs {
+            host: Some(connection.host.clone()),
+            user: Some(connection.user.clone()),
+            port: Some(connection.port),
+            simulate,
+            ..TargetArgs::default()
+        };
+        if let Ok(target) = args.resolve(&config, &ssh_config) {
+            tunnels.push(tui::Tunnel {
+                name: format!("{}@{}", connection.user, connection.host),
+                connected: Some(connection.timestamp),
+                target,
+            });
+        }
+    }
+    tui::run(&config, tunnels).await
+}
+
+/// Creates the key pairs that `targets` are to receive but that don't exist yet, once per path
+async fn generate_missing_keys<'a>(targets: impl IntoIterator<Item = &'a Target>) -> Result<()> {
+    let mut paths = std::collections::BTreeSet::new();
+    for target in targets {
+        if !target.skip_key_transfer {
+            paths.insert(paths::expand_tilde(&target.key_path)?);
+        }
+    }
+    let comment = keys::default_comment().await;
+    for path in paths.into_iter().filter(|path| !path.exists()) {
+        let report = keys::generate(&path, &comment).await?;
+        info!(
+            "Generated {} ({})",
+            report.private_key.display(),
+            report.fingerprint
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_accepts_legacy_and_up_forms() {
+        let cli = Cli::try_parse_from(["ssh-ip-tunnel", "-H", "10.0.0.5", "-u", "pi"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.target.host.as_deref(), Some("10.0.0.5"));
+
+        let cli = Cli::try_parse_from(["ssh-ip-tunnel", "up", "mydevboard", "-p", "2300"]).unwrap();
+        match cli.command {
+            Some(Commands::Up(args)) => {
+                assert_eq!(args.profile.as_deref(), Some("mydevboard"));
+                assert_eq!(args.port, Some(2300));
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_events_ndjson_is_the_ndjson_output() {
+        let cli = Cli::try_parse_from(["ssh-ip-tunnel", "--events", "ndjson", "up", "pi"]).unwrap();
+        assert_eq!(cli.output_format(), OutputFormat::Ndjson);
+        let cli = Cli::try_parse_from(["ssh-ip-tunnel", "up", "pi"]).unwrap();
+        assert_eq!(cli.output_format(), OutputFormat::Human);
+        assert!(Cli::try_parse_from([
+            "ssh-ip-tunnel",
+            "--events",
+            "ndjson",
+            "--output",
+            "json",
+            "up",
+            "pi"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_exec_takes_the_command_after_double_dash() {
+        let cli = Cli::try_parse_from([
+            "ssh-ip-tunnel",
+            "exec",
+            "mydevboard",
+            "--port",
+            "2222",
+            "--",
+            "systemctl",
+            "status",
+            "--no-pager",
+        ])
+        .unwrap();
+        assert!(cli.stdout_is_data());
+        match cli.command {
+            Some(Commands::Exec { target, command }) => {
+                assert_eq!(target.profile.as_deref(), Some("mydevboard"));
+                assert_eq!(target.port, Some(2222));
+                assert_eq!(command, ["systemctl", "status", "--no-pager"]);
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+        assert!(Cli::try_parse_from(["ssh-ip-tunnel", "exec", "mydevboard"]).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_takes_a_profile_or_diff() {
+        let cli = Cli::try_parse_from(["ssh-ip-tunnel", "snapshot", "mydevboard"]).unwrap();
+        match cli.command {
+            Some(Commands::Snapshot {
+                target,
+                action: None,
+            }) => assert_eq!(target.profile.as_deref(), Some("mydevboard")),
+            other => panic!("unexpected command: {:?}", other),
+        }
+
+        let cli = Cli::try_parse_from([
+            "ssh-ip-tunnel",
+            "snapshot",
+            "diff",
+            "mydevboard",
+            "--from",
+            "20261016T112708Z",
+        ])
+        .unwrap();
+        match cli.command {
+            Some(Commands::Snapshot {
+                action: Some(SnapshotCommand::Diff { target, from, to }),
+                ..
+            }) => {
+                assert_eq!(target.profile.as_deref(), Some("mydevboard"));
+                assert_eq!(from, "20261016T112708Z");
+                assert_eq!(to, None);
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cli_flags_override_profile() {
+        let mut config = Config::default();
+        config.hosts.insert(
+            "mydevboard".to_string(),
+            HostProfile {
+                host: Some("192.168.1.42".to_string()),
+                user: Some("pi".to_string()),
+                port: Some(2223),
+                skip_arch_validation: Some(true),
+                target_user: Some("deploy".to_string()),
+                ..Default::default()
+            },
+        );
+
+        let args = TargetArgs {
+            profile: Some("mydevboard".to_string()),
+            user: Some("root".to_string()),
+            ..Default::default()
+        };
+        let target = args.resolve(&config, &SshConfig::default()).unwrap();
+        assert_eq!(target.host, "192.168.1.42");
+        assert_eq!(target.user, "root");
+        assert_eq!(target.port, 2223);
+        assert_eq!(target.key_path, config.default_key());
+        assert!(target.skip_arch_validation);
+        assert_eq!(target.key_user(), "deploy");
+
+        let bad_target_user = TargetArgs {
+            profile: Some("mydevboard".to_string()),
+            target_user: Some("-deploy".to_string()),
+            ..Default::default()
+        };
+        assert!(bad_target_user
+            .resolve(&config, &SshConfig::default())
+            .is_err());
+
+        let missing_host = TargetArgs {
+            user: Some("pi".to_string()),
+            ..Default::default()
+        };
+        assert!(missing_host
+            .resolve(&config, &SshConfig::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_ssh_config_alias_fills_unset_options() {
+        let ssh_config = SshConfig::parse(
+            "Host mypi\n  HostName 10.0.0.5\n  User pi\n  Port 2200\n  ProxyJump bastion\n",
+            Path::new("/nonexistent"),
+        )
+        .unwrap();
+        let mut config = Config::default();
+        config.hosts.insert(
+            "lab".to_string(),
+            HostProfile {
+                host: Some("mypi".to_string()),
+                user: Some("admin".to_string()),
+                ..Default::default()
+            },
+        );
+
+        let args = TargetArgs {
+            host: Some("mypi".to_string()),
+            ..Default::default()
+        };
+        let target = args.resolve(&config, &ssh_config).unwrap();
+        assert_eq!(target.host, "10.0.0.5");
+        assert_eq!(target.user, "pi");
+        assert_eq!(target.remote_port, Some(2200));
+        assert_eq!(target.proxy_jump.as_deref(), Some("bastion"));
+        assert_eq!(target.port, config.default_port);
+
+        let profile = TargetArgs {
+            profile: Some("lab".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(profile.resolve(&config, &ssh_config).unwrap().user, "admin");
+    }
+
+    #[test]
+    fn test_group_members_get_distinct_ports() {
+        let config: Config = toml::from_str(
+            r#"
+            default_port = 3000
+            [hosts.pi1]
+            host = "10.0.0.1"
+            user = "pi"
+            [hosts.pi2]
+            host = "10.0.0.2"
+            user = "pi"
+            [hosts.jetson1]
+            host = "10.0.0.3"
+            user = "nvidia"
+            port = 4000
+            [groups]
+            lab-a = ["pi1", "pi2", "jetson1"]
+            "#,
+        )
+        .unwrap();
+
+        let targets = TargetArgs::default()
+            .resolve_group("lab-a", &config, &SshConfig::default())
+            .unwrap();
+        let ports: Vec<u16> = targets.iter().map(|(_, t)| t.port).collect();
+        assert_eq!(ports, [3000, 3001, 4000]);
+        assert_eq!(targets[2].1.user, "nvidia");
+
+        let mut clashing = config.clone();
+        clashing.hosts.get_mut("pi2").unwrap().port = Some(3000);
+        assert!(TargetArgs::default()
+            .resolve_group("lab-a", &clashing, &SshConfig::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_env_sits_between_profile_and_cli() {
+        let mut config = Config::default();
+        config.hosts.insert(
+            "mydevboard".to_string(),
+            HostProfile {
+                host: Some("192.168.1.42".to_string()),
+                user: Some("pi".to_string()),
+                port: Some(2223),
+                ..Default::default()
+            },
+        );
+        let lookup = |suffix: &str| match suffix {
+            "PROFILE" => Some("mydevboard".to_string()),
+            "USER" => Some("ubuntu".to_string()),
+            "PORT" => Some("2300".to_string()),
+            "NO_KEY_TRANSFER" => Some("1".to_string()),
+            _ => None,
+        };
+
+        let mut args = TargetArgs {
+            port: Some(2400),
+            ..Default::default()
+        };
+        args.apply_env(&lookup).unwrap();
+        let target = args.resolve(&config, &SshConfig::default()).unwrap();
+        assert_eq!(target.host, "192.168.1.42");
+        assert_eq!(target.user, "ubuntu");
+        assert_eq!(target.port, 2400);
+        assert!(target.skip_key_transfer);
+
+        let mut group_args = TargetArgs {
+            group: Some("lab-a".to_string()),
+            ..Default::default()
+        };
+        group_args.apply_env(&lookup).unwrap();
+        assert_eq!(group_args.profile, None);
+        assert_eq!(group_args.port, None);
+    }
+
+    #[test]
+    fn test_manual_and_readme_list_the_variables_target_options_read() {
+        let variables = TargetArgs::env_variables();
+        assert!(variables.contains(&"NO_KEY_TRANSFER".to_string()));
+        assert!(!variables.contains(&"JOBS".to_string()));
+
+        let mut page = Vec::new();
+        man::render(Cli::command(), &variables, &mut page).unwrap();
+        let page = String::from_utf8(page).unwrap();
+        let environment = &page[page.find(".SH ENVIRONMENT").unwrap()..];
+        assert!(environment.contains("\\fBSSH_IP_TUNNEL_HOST\\fR\nAs \\-\\-host"));
+        assert!(!environment.contains("\\-\\-jobs"));
+
+        let readme = include_str!("../README.md");
+        for variable in &variables {
+            assert!(
+                readme.contains(&format!("| `SSH_IP_TUNNEL_{}` |", variable)),
+                "README doesn't list SSH_IP_TUNNEL_{}",
+                variable
+            );
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Setting a device's clock from this machine's.
+//!
+//! Boards with a flat RTC battery and no network time boot into 1970 or
+//! their image's build date, and then fail every TLS handshake because
+//! certificates aren't valid yet. `--sync-time` steps the device clock to
+//! this machine's right after the tunnel is validated, before anything else
+//! talks to the network, and writes it to the RTC when there is one.
+
+use crate::shell::{self, RemoteCommand};
+use crate::ssh::{self, RunError};
+use crate::{Target, TunnelError};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::time::Duration;
+use tracing::info;
+
+/// Upper bound for reading and setting the clock
+const SYNC_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Offsets up to this many seconds are left alone; the transfer itself takes about that long
+const TOLERANCE_SECS: i64 = 2;
+
+/// Prints the device's time, then sets it to `$1` (seconds since the epoch)
+/// unless `$2` is empty. BusyBox and GNU `date` both take `-s @SECONDS`.
+pub const SYNC_SCRIPT: &str = r#"set -e
+date -u +%s
+[ -n "$2" ] || exit 0
+as_root date -u -s "@$1" >/dev/null
+if [ -e /dev/rtc0 ] && command -v hwclock >/dev/null 2>&1; then
+  as_root hwclock -w -u 2>/dev/null || true
+fi"#;
+
+/// How far the device's clock was off, and whether it was corrected
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct Adjustment {
+    /// The device's time before the sync
+    pub device_time: DateTime<Utc>,
+    /// Seconds the clock was moved forward (negative: back)
+    pub offset_secs: i64,
+    /// False when the clock was already within tolerance
+    pub set: bool,
+}
+
+/// Steps `target`'s clock to this machine's, if it is off by more than a couple of seconds
+pub async fn sync(target: &Target) -> Result<Adjustment, TunnelError> {
+    // Read first, so a clock that is already right isn't touched
+    let device_time = run(target, Utc::now(), false).await?;
+    let offset_secs = Utc::now().timestamp() - device_time.timestamp();
+    if offset_secs.abs() <= TOLERANCE_SECS {
+        info!("Clock on {} is in sync", target.host);
+        return Ok(Adjustment {
+            device_time,
+            offset_secs,
+            set: false,
+        });
+    }
+
+    let now = Utc::now();
+    let device_time = run(target, now, true).await?;
+    let offset_secs = now.timestamp() - device_time.timestamp();
+    info!(
+        "Set the clock on {} to {} (it read {}, {})",
+        target.host,
+        now.format("%Y-%m-%d %H:%M:%S UTC"),
+        device_time.format("%Y-%m-%d %H:%M:%S UTC"),
+        describe_offset(offset_secs)
+    );
+    Ok(Adjustment {
+        device_time,
+        offset_secs,
+        set: true,
+    })
+}
+
+/// Runs [`SYNC_SCRIPT`], returning the device's time before any change
+async fn run(target: &Target, now: DateTime<Utc>, set: bool) -> Result<DateTime<Utc>, TunnelError> {
+    let command = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(format!("{}\n{}", shell::AS_ROOT, SYNC_SCRIPT))
+        .arg("sh")
+        .arg(now.timestamp().to_string())
+        .arg(if set { "1" } else { "" });
+    let stdout = ssh::run_through_tunnel(target, &command, SYNC_TIMEOUT)
+        .await
+        .map_err(|e| match e {
+            RunError::Failed { stderr, .. } => TunnelError::Clock(format!(
+                "`date` on {} failed (setting the clock needs root or passwordless sudo): {}",
+                target.host, stderr
+            )),
+            e => e.into_error(TunnelError::Clock),
+        })?;
+    stdout
+        .trim()
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .ok_or_else(|| TunnelError::Clock(format!("unexpected `date` output {:?}", stdout.trim())))
+}
+
+/// `3 days 4h 5m ahead`-style description of how far the device's clock was off
+pub fn describe_offset(offset_secs: i64) -> String {
+    let magnitude = offset_secs.unsigned_abs();
+    let (days, rest) = (magnitude / 86_400, magnitude % 86_400);
+    let (hours, minutes, seconds) = (rest / 3600, rest % 3600 / 60, rest % 60);
+    let mut parts = Vec::new();
+    if days > 0 {
+        parts.push(format!("{} day{}", days, if days == 1 { "" } else { "s" }));
+    }
+    if hours > 0 {
+        parts.push(format!("{}h", hours));
+    }
+    if minutes > 0 {
+        parts.push(format!("{}m", minutes));
+    }
+    if seconds > 0 || parts.is_empty() {
+        parts.push(format!("{}s", seconds));
+    }
+    // A positive offset means the device was behind
+    let direction = if offset_secs >= 0 { "behind" } else { "ahead" };
+    format!("{} {}", parts.join(" "), direction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_offset() {
+        assert_eq!(describe_offset(0), "0s behind");
+        assert_eq!(describe_offset(-90), "1m 30s ahead");
+        assert_eq!(describe_offset(86_400 + 3600), "1 day 1h behind");
+        assert_eq!(
+            describe_offset(1_700_000_000),
+            "19675 days 22h 13m 20s behind"
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! `completions`: shell completion scripts.
+//!
+//! The script only registers the tool with the shell; each time Tab is
+//! pressed, the shell runs the tool again with `SSH_IP_TUNNEL_COMPLETE=<shell>` in its
+//! environment and the tool answers from its own command line definition.
+//! So completions follow upgrades, and host profile and group names come
+//! from the configuration file as it is then: the one `--config` and
+//! `--namespace` on the line being completed, or their variables, select.
+
+use crate::config::{self, Config};
+use crate::env;
+use crate::paths;
+use crate::validate;
+use clap::ValueEnum;
+use clap_complete::engine::CompletionCandidate;
+use clap_complete::env::{Bash, EnvCompleter, Fish, Powershell, Zsh};
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// Variable the shell sets to ask for completions
+pub const VAR: &str = "SSH_IP_TUNNEL_COMPLETE";
+
+/// Shells completions can be generated for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+impl Shell {
+    fn completer(self) -> &'static dyn EnvCompleter {
+        match self {
+            Shell::Bash => &Bash,
+            Shell::Zsh => &Zsh,
+            Shell::Fish => &Fish,
+            Shell::Powershell => &Powershell,
+        }
+    }
+}
+
+/// The name the tool was installed as, which the shell completes
+pub fn bin_name() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|path| Some(path.file_stem()?.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string())
+}
+
+/// Writes the script that registers completions for `shell`
+pub fn write_script(shell: Shell, out: &mut dyn Write) -> io::Result<()> {
+    let bin = bin_name();
+    shell
+        .completer()
+        .write_registration(VAR, &bin, &bin, &bin, out)
+}
+
+/// Names of the configured host profiles starting with `current`
+pub fn profiles(current: &OsStr) -> Vec<CompletionCandidate> {
+    candidates(current, |config| config.hosts.keys().cloned().collect())
+}
+
+/// Names of the configured host groups starting with `current`
+pub fn groups(current: &OsStr) -> Vec<CompletionCandidate> {
+    candidates(current, |config| config.groups.keys().cloned().collect())
+}
+
+/// `names` of the configuration starting with `current`; none when it can't be read
+fn candidates(current: &OsStr, names: impl Fn(&Config) -> Vec<String>) -> Vec<CompletionCandidate> {
+    // The words being completed follow the tool's own arguments
+    let args: Vec<String> = std::env::args_os()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    let namespace = typed_option(&args, "namespace").or_else(|| env::process_lookup("NAMESPACE"));
+    if let Some(name) = namespace.filter(|name| validate::validate_namespace(name).is_ok()) {
+        paths::set_namespace(name);
+    }
+    let Ok(config) = config::load_config(typed_option(&args, "config").map(PathBuf::from)) else {
+        return Vec::new();
+    };
+    let current = current.to_string_lossy();
+    names(&config)
+        .into_iter()
+        .filter(|name| name.starts_with(current.as_ref()))
+        .map(CompletionCandidate::new)
+        .collect()
+}
+
+/// The value given to `--<long>` in `args`, the last one if several are
+fn typed_option(args: &[String], long: &str) -> Option<String> {
+    let flag = format!("--{}", long);
+    let mut value = None;
+    for (index, arg) in args.iter().enumerate() {
+        if *arg == flag {
+            value = args.get(index + 1).cloned();
+        } else if let Some(rest) = arg.strip_prefix(&flag).and_then(|r| r.strip_prefix('=')) {
+            value = Some(rest.to_string());
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typed_option_takes_either_form() {
+        let args: Vec<String> = [
+            "ssh_ip_tunnel",
+            "--",
+            "ssh_ip_tunnel",
+            "--namespace",
+            "lab",
+            "up",
+            "--config=/tmp/a b.toml",
+            "",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        assert_eq!(typed_option(&args, "namespace").as_deref(), Some("lab"));
+        assert_eq!(
+            typed_option(&args, "config").as_deref(),
+            Some("/tmp/a b.toml")
+        );
+        assert_eq!(typed_option(&args, "host"), None);
+        // Still being typed
+        assert_eq!(typed_option(&args[..4], "namespace"), None);
+    }
+}
@@ -0,0 +1,65 @@
+//! Configuration file handling.
+
+use crate::env;
+use crate::interpolate;
+use crate::keys;
+use crate::paths;
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+pub use crate::pure::config::{
+    render_template, set_host_profile, validate_str, Config, Diagnostic, Hooks, HostProfile,
+    Webhooks, ENV_SETTINGS,
+};
+
+impl Config {
+    /// The key to transfer when neither the command line nor the host profile names one
+    pub fn default_key(&self) -> String {
+        self.default_key_path
+            .clone()
+            .unwrap_or_else(keys::default_public_key)
+    }
+}
+
+/// The file [`load_config`] reads: `--config` if given, then `SSH_IP_TUNNEL_CONFIG`,
+/// else the default location if it exists
+pub fn config_source(config_path: Option<PathBuf>) -> Option<PathBuf> {
+    config_path
+        .or_else(|| env::process_lookup("CONFIG").map(PathBuf::from))
+        .or_else(paths::default_config_path)
+}
+
+/// Load configuration from file or use defaults, expand `${NAME}` references,
+/// then apply environment overrides
+pub fn load_config(config_path: Option<PathBuf>) -> Result<Config> {
+    let mut config = match config_source(config_path) {
+        Some(path) => {
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|e| anyhow::anyhow!("Failed to read config file {:?}: {}", path, e))?;
+            let mut config: Config = toml::from_str(&contents)
+                .map_err(|e| anyhow::anyhow!("Failed to parse config file: {}", e))?;
+            config.interpolate(&interpolate::process_env)?;
+            config
+        }
+        None => Config::default(),
+    };
+    config.apply_env(&env::process_lookup)?;
+    Ok(config)
+}
+
+/// Writes a rendered template to `path`, refusing to replace an existing file unless `force` is set
+pub fn write_template(path: &Path, contents: &str, force: bool) -> Result<()> {
+    if path.exists() && !force {
+        anyhow::bail!(
+            "Config file {} already exists (use --force to overwrite)",
+            path.display()
+        );
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    std::fs::write(path, contents)
+        .map_err(|e| anyhow::anyhow!("Failed to write config file {}: {}", path.display(), e))?;
+    Ok(())
+}
@@ -0,0 +1,440 @@
+//! Deploying container images to boards.
+//!
+//! `deploy-container` gets an image onto a board in one of three ways: the
+//! board's runtime pulls it itself; it pulls through a SOCKS proxy that leaves
+//! through this machine, for boards without internet access; or a saved image
+//! is streamed through the tunnel into `docker load`/`podman load`. It then
+//! (re)creates a detached container from the image.
+
+use crate::config::Config;
+use crate::executor::Stdio;
+use crate::forward::ReverseForward;
+use crate::output::Renderable;
+use crate::process;
+use crate::shell::{self, RemoteCommand};
+use crate::ssh;
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::AsyncRead;
+use tokio::time::timeout;
+use tracing::info;
+
+/// Upper bound for pulling or loading an image
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(1800);
+
+/// Upper bound for detecting the runtime and (re)creating the container
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Container engine on the board
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Runtime {
+    Docker,
+    Podman,
+}
+
+impl Runtime {
+    fn name(self) -> &'static str {
+        match self {
+            Runtime::Docker => "docker",
+            Runtime::Podman => "podman",
+        }
+    }
+
+    /// Defines `rt`, which runs the runtime: podman as the login user (rootless),
+    /// docker directly if the user may talk to the daemon, else as root
+    fn prelude(self) -> String {
+        match self {
+            Runtime::Docker => format!(
+                "{}\nif docker info >/dev/null 2>&1; then rt() {{ docker \"$@\"; }}; else rt() {{ as_root docker \"$@\"; }}; fi",
+                shell::AS_ROOT
+            ),
+            Runtime::Podman => "rt() { podman \"$@\"; }".to_string(),
+        }
+    }
+}
+
+/// How the image gets onto the board
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Transfer {
+    /// The board pulls from the registry itself
+    #[default]
+    Pull,
+    /// The board pulls through a SOCKS proxy on this machine (podman only)
+    Proxy,
+    /// The image is saved here and streamed to the board
+    Stream,
+}
+
+/// What to deploy and how to run it
+#[derive(Debug, Clone, Default)]
+pub struct Deployment {
+    pub image: String,
+    pub transfer: Transfer,
+    /// Saved image (`docker save` output, optionally gzipped) to stream instead of saving one here
+    pub archive: Option<PathBuf>,
+    pub runtime: Option<Runtime>,
+    /// Container name (default: the image's repository name)
+    pub name: Option<String>,
+    /// `KEY=VALUE` environment variables
+    pub env: Vec<String>,
+    /// `-p` port mappings
+    pub publish: Vec<String>,
+    /// `-v` volume mounts
+    pub volumes: Vec<String>,
+    /// Further arguments for `run`, passed as given
+    pub run_args: Vec<String>,
+    /// Only get the image onto the board
+    pub no_run: bool,
+}
+
+impl Deployment {
+    /// The container name: as given, or the last path component of the image without tag or digest
+    fn container_name(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        let repository = self.image.split('@').next().unwrap_or_default();
+        let last = repository.rsplit('/').next().unwrap_or_default();
+        last.split(':').next().unwrap_or_default().to_string()
+    }
+
+    /// Arguments of `run` after the subcommand itself
+    fn run_arguments(&self) -> Vec<String> {
+        let mut args = vec![
+            "-d".to_string(),
+            "--name".to_string(),
+            self.container_name(),
+            "--restart".to_string(),
+            "unless-stopped".to_string(),
+        ];
+        for (flag, values) in [
+            ("-e", &self.env),
+            ("-p", &self.publish),
+            ("-v", &self.volumes),
+        ] {
+            for value in values {
+                args.extend([flag.to_string(), value.clone()]);
+            }
+        }
+        args.extend(self.run_args.iter().cloned());
+        args.push(self.image.clone());
+        args
+    }
+}
+
+/// Result of `deploy-container`
+#[derive(Debug, Serialize)]
+pub struct DeployReport {
+    pub host: String,
+    pub runtime: Runtime,
+    pub image: String,
+    pub transfer: Transfer,
+    /// Bytes streamed, for `stream`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container_id: Option<String>,
+}
+
+impl Renderable for DeployReport {
+    fn to_human(&self) -> String {
+        let mut text = format!(
+            "{} is on {} ({})",
+            self.image,
+            self.host,
+            self.runtime.name()
+        );
+        if let (Some(name), Some(id)) = (&self.container, &self.container_id) {
+            text.push_str(&format!(
+                "\nContainer {} running ({})",
+                name,
+                &id[..id.len().min(12)]
+            ));
+        }
+        text
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Gets `deployment.image` onto `target` and starts a container from it
+pub async fn deploy(
+    config: &Config,
+    target: &Target,
+    deployment: &Deployment,
+) -> Result<DeployReport> {
+    if deployment.image.is_empty()
+        || deployment.image.starts_with('-')
+        || deployment.image.contains(char::is_whitespace)
+    {
+        anyhow::bail!("Invalid image reference {:?}", deployment.image);
+    }
+    let transfer = if deployment.archive.is_some() {
+        Transfer::Stream
+    } else {
+        deployment.transfer
+    };
+
+    SSHTunnelManager::new(config.clone())
+        .connect(target)
+        .await?;
+    let runtime = match deployment.runtime {
+        Some(runtime) => runtime,
+        None => detect_runtime(target).await?,
+    };
+
+    let bytes = match transfer {
+        Transfer::Pull => {
+            pull(target, runtime, &deployment.image, None).await?;
+            None
+        }
+        Transfer::Proxy => {
+            if runtime == Runtime::Docker {
+                return Err(TunnelError::Container(
+                    "docker pulls through its daemon, which can't be given a proxy per pull; use --transfer stream".to_string(),
+                )
+                .into());
+            }
+            pull_through_proxy(target, runtime, &deployment.image).await?;
+            None
+        }
+        Transfer::Stream => Some(stream(target, runtime, deployment).await?),
+    };
+
+    let (container, container_id) = if deployment.no_run {
+        (None, None)
+    } else {
+        let name = deployment.container_name();
+        let id = run(target, runtime, &name, &deployment.run_arguments()).await?;
+        (Some(name), Some(id))
+    };
+
+    Ok(DeployReport {
+        host: target.host.clone(),
+        runtime,
+        image: deployment.image.clone(),
+        transfer,
+        bytes,
+        container,
+        container_id,
+    })
+}
+
+/// Finds docker or podman on the board, preferring docker
+async fn detect_runtime(target: &Target) -> Result<Runtime, TunnelError> {
+    let probe = RemoteCommand::new("sh").arg("-c").arg(
+        "if command -v docker >/dev/null 2>&1; then echo docker; elif command -v podman >/dev/null 2>&1; then echo podman; fi",
+    );
+    let runtime = ssh::run_through_tunnel(target, &probe, COMMAND_TIMEOUT)
+        .await
+        .map_err(|e| e.into_error(TunnelError::Container))?;
+    match runtime.trim() {
+        "docker" => Ok(Runtime::Docker),
+        "podman" => Ok(Runtime::Podman),
+        _ => Err(TunnelError::Container(format!(
+            "neither docker nor podman found on {}",
+            target.host
+        ))),
+    }
+}
+
+/// Has the board pull `image`, optionally through a proxy URL
+async fn pull(
+    target: &Target,
+    runtime: Runtime,
+    image: &str,
+    proxy: Option<&str>,
+) -> Result<(), TunnelError> {
+    info!("Pulling {} on {}...", image, target.host);
+    let script = match proxy {
+        Some(_) => "export HTTPS_PROXY=\"$2\" HTTP_PROXY=\"$2\" https_proxy=\"$2\" http_proxy=\"$2\"\nrt pull \"$1\"",
+        None => "rt pull \"$1\"",
+    };
+    let command = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(format!("{}\n{}", runtime.prelude(), script))
+        .arg("sh")
+        .arg(image)
+        .arg(proxy.unwrap_or_default());
+    ssh::run_through_tunnel(target, &command, TRANSFER_TIMEOUT)
+        .await
+        .map(drop)
+        .map_err(|e| e.into_error(TunnelError::Container))
+}
+
+/// Has podman pull `image` through a SOCKS proxy served from this machine over the tunnel
+async fn pull_through_proxy(
+    target: &Target,
+    runtime: Runtime,
+    image: &str,
+) -> Result<(), TunnelError> {
+    // The tunnel's local port is as good a choice as any, and is free on most boards
+    let port = target.port;
+    let forward = ReverseForward::start(target, &[format!("127.0.0.1:{}", port)]).await?;
+    info!(
+        "Serving a SOCKS proxy on {}'s 127.0.0.1:{}",
+        target.host, port
+    );
+
+    let result = pull(
+        target,
+        runtime,
+        image,
+        Some(&format!("socks5h://127.0.0.1:{}", port)),
+    )
+    .await;
+    forward.stop().await;
+    result
+}
+
+/// Streams a saved image into the board's runtime, returning the bytes sent
+async fn stream(
+    target: &Target,
+    runtime: Runtime,
+    deployment: &Deployment,
+) -> Result<u64, TunnelError> {
+    let failed = |message: String| TunnelError::Container(format!("streaming failed: {}", message));
+
+    // Either the given archive, or `save` from the same runtime here
+    let mut saver = None;
+    let mut source: Box<dyn AsyncRead + Unpin + Send> = match &deployment.archive {
+        Some(path) => {
+            info!("Streaming {} to {}...", path.display(), target.host);
+            Box::new(
+                tokio::fs::File::open(path)
+                    .await
+                    .map_err(|e| failed(format!("cannot read {}: {}", path.display(), e)))?,
+            )
+        }
+        None => {
+            info!(
+                "Saving {} locally and streaming it to {}...",
+                deployment.image, target.host
+            );
+            let mut child = process::command(runtime.name())?
+                .arg("save")
+                .arg(&deployment.image)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .map_err(|e| failed(e.to_string()))?;
+            let stdout = child.stdout.take().expect("stdout is piped");
+            saver = Some(child);
+            Box::new(stdout)
+        }
+    };
+
+    let load = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(format!("{}\nrt load", runtime.prelude()));
+    let mut loader = ssh::through_tunnel(target, &load)?
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| failed(e.to_string()))?;
+    let mut stdin = loader.stdin.take().expect("stdin is piped");
+
+    let copied = timeout(TRANSFER_TIMEOUT, tokio::io::copy(&mut source, &mut stdin))
+        .await
+        .map_err(|_| failed(format!("not done within {}s", TRANSFER_TIMEOUT.as_secs())))?;
+    drop(stdin);
+
+    if let Some(saver) = saver {
+        let output = saver
+            .wait_with_output()
+            .await
+            .map_err(|e| failed(e.to_string()))?;
+        if !output.status.success() {
+            return Err(failed(format!(
+                "{} save {}: {}",
+                runtime.name(),
+                deployment.image,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+    }
+    // Unpacking layers on a slow board continues well after the last byte arrives
+    let output = timeout(TRANSFER_TIMEOUT, loader.wait_with_output())
+        .await
+        .map_err(|_| failed("the board did not finish loading the image".to_string()))?
+        .map_err(|e| failed(e.to_string()))?;
+    if !output.status.success() {
+        return Err(failed(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    info!("{}", String::from_utf8_lossy(&output.stdout).trim());
+    copied.map_err(|e| failed(e.to_string()))
+}
+
+/// Replaces any container called `name` with a new one, returning its ID
+async fn run(
+    target: &Target,
+    runtime: Runtime,
+    name: &str,
+    arguments: &[String],
+) -> Result<String, TunnelError> {
+    info!("Starting container {} on {}...", name, target.host);
+    let command = arguments.iter().fold(
+        RemoteCommand::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "{}\nname=$1\nshift\nrt rm -f \"$name\" >/dev/null 2>&1 || true\nrt run \"$@\"",
+                runtime.prelude()
+            ))
+            .arg("sh")
+            .arg(name),
+        |command, arg| command.arg(arg),
+    );
+    let stdout = ssh::run_through_tunnel(target, &command, COMMAND_TIMEOUT)
+        .await
+        .map_err(|e| e.into_error(TunnelError::Container))?;
+    Ok(stdout.trim().lines().last().unwrap_or_default().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_container_name_defaults_to_the_repository() {
+        let mut deployment = Deployment {
+            image: "ghcr.io/acme/sensor-hub:1.4".to_string(),
+            ..Deployment::default()
+        };
+        assert_eq!(deployment.container_name(), "sensor-hub");
+        deployment.image = "localhost:5000/app@sha256:abcd".to_string();
+        assert_eq!(deployment.container_name(), "app");
+        deployment.image = "nginx".to_string();
+        assert_eq!(deployment.container_name(), "nginx");
+        deployment.name = Some("web".to_string());
+        assert_eq!(deployment.container_name(), "web");
+    }
+
+    #[test]
+    fn test_run_arguments() {
+        let deployment = Deployment {
+            image: "ghcr.io/acme/sensor-hub:1.4".to_string(),
+            env: vec!["LOG=debug".to_string()],
+            publish: vec!["8080:80".to_string()],
+            volumes: vec!["/data:/data".to_string()],
+            run_args: vec!["--privileged".to_string()],
+            ..Deployment::default()
+        };
+        assert_eq!(
+            deployment.run_arguments().join(" "),
+            "-d --name sensor-hub --restart unless-stopped -e LOG=debug -p 8080:80 -v /data:/data --privileged ghcr.io/acme/sensor-hub:1.4"
+        );
+    }
+}
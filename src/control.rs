@@ -0,0 +1,241 @@
+//! The control socket of `up --watch`, through which other invocations reach
+//! the running watch.
+//!
+//! While it runs, the watch listens on `watch-<port>.sock` in the runtime
+//! directory, and removes it when it ends. A connection sends one request as
+//! a line of JSON, e.g. `{"command":"status"}`, and gets one line back: the
+//! watch's statistics, as `{"stats":{...}}`, or `{"error":"..."}`.
+//!
+//! - `status` asks for the statistics as of the last check; `status` uses it
+//!   to tell running watches from ones that were killed
+//! - `down` closes the tunnel and ends the watch; `down` sends it, since a
+//!   tunnel it closed itself would be opened again by the watch
+//! - `reconnect` closes the tunnel and opens it again
+//!
+//! The runtime directory must belong to this user alone (see
+//! [`paths::private_runtime_dir`]), and both ends check that the other runs as
+//! the same user, so other users of the machine can neither order a watch
+//! about nor pose as one.
+//!
+//! Control sockets are Unix sockets; elsewhere `down` closes the tunnel itself
+//! and `status` goes by the saved statistics.
+
+use crate::paths;
+use crate::watch::{Live, Order, Stats};
+use crate::TunnelError;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How long a watch may take to answer, e.g. to close its tunnel
+#[cfg(unix)]
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Longest request accepted, in bytes
+#[cfg(unix)]
+const MAX_REQUEST: u64 = 4096;
+
+/// What a client asks the watch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Down,
+    Reconnect,
+}
+
+/// The watch's answer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Stats(Stats),
+    Error(String),
+}
+
+impl From<Result<Stats, TunnelError>> for Response {
+    fn from(result: Result<Stats, TunnelError>) -> Self {
+        match result {
+            Ok(stats) => Response::Stats(stats),
+            Err(e) => Response::Error(e.to_string()),
+        }
+    }
+}
+
+/// Carries out `request` for the watch in this process
+pub async fn handle(request: Request, live: &Live) -> Response {
+    match request {
+        Request::Status => live
+            .get()
+            .ok_or_else(|| TunnelError::Watch("the watch hasn't started".to_string()))
+            .into(),
+        Request::Down => live.order(Order::Down).await.into(),
+        Request::Reconnect => live.order(Order::Reconnect).await.into(),
+    }
+}
+
+/// The control socket of the watch on local port `port`
+fn socket_path(port: u16) -> PathBuf {
+    paths::runtime_file(&format!("watch-{}.sock", port))
+}
+
+/// Sends `request` to the watch of the tunnel on local port `port`; `None`
+/// when no watch listens there
+pub async fn request(port: u16, request: Request) -> Option<Result<Stats, TunnelError>> {
+    #[cfg(unix)]
+    {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let mut stream = tokio::net::UnixStream::connect(socket_path(port))
+            .await
+            .ok()?;
+        if !unix::same_user(&stream) {
+            return None;
+        }
+        let exchange = async {
+            let mut line = serde_json::to_string(&request)?;
+            line.push('\n');
+            stream.write_all(line.as_bytes()).await?;
+            let mut answer = String::new();
+            BufReader::new(stream).read_line(&mut answer).await?;
+            Ok::<_, std::io::Error>(serde_json::from_str::<Response>(&answer)?)
+        };
+        let answer = tokio::time::timeout(ANSWER_TIMEOUT, exchange)
+            .await
+            .map_err(|_| TunnelError::Watch(format!("the watch on port {} didn't answer", port)))
+            .and_then(|answer| {
+                answer.map_err(|e| TunnelError::Watch(format!("asking the watch: {}", e)))
+            });
+        Some(match answer {
+            Ok(Response::Stats(stats)) => Ok(stats),
+            Ok(Response::Error(e)) => Err(TunnelError::Watch(e)),
+            Err(e) => Err(e),
+        })
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (port, request);
+        None
+    }
+}
+
+#[cfg(unix)]
+pub use self::unix::ControlSocket;
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{UnixListener, UnixStream};
+    use tokio::task::JoinHandle;
+    use tracing::debug;
+
+    /// A listening control socket; dropping it stops listening and removes it
+    pub struct ControlSocket {
+        path: PathBuf,
+        task: JoinHandle<()>,
+    }
+
+    impl ControlSocket {
+        /// Listens for requests to the watch of `live`, on local port `port`
+        pub fn start(port: u16, live: Live) -> Result<Self, TunnelError> {
+            paths::private_runtime_dir()?;
+            let path = socket_path(port);
+            // Left by a watch that was killed; a running one would hold the port
+            let _ = std::fs::remove_file(&path);
+            let listener = UnixListener::bind(&path).map_err(|e| {
+                TunnelError::Watch(format!("control socket {}: {}", path.display(), e))
+            })?;
+            let task = tokio::spawn(async move {
+                while let Ok((client, _)) = listener.accept().await {
+                    let live = live.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(client, &live).await {
+                            debug!("Control socket: {}", e);
+                        }
+                    });
+                }
+            });
+            Ok(Self { path, task })
+        }
+    }
+
+    impl Drop for ControlSocket {
+        fn drop(&mut self) {
+            self.task.abort();
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    /// Whether the process at the other end of `stream` runs as this user
+    pub(super) fn same_user(stream: &UnixStream) -> bool {
+        match stream.peer_cred() {
+            Ok(peer) => peer.uid() == paths::user_id(),
+            Err(e) => {
+                debug!("Control socket: no peer credentials: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Answers the one request of a client connection
+    async fn serve(client: UnixStream, live: &Live) -> std::io::Result<()> {
+        if !same_user(&client) {
+            debug!("Control socket: refused a client of another user");
+            return Ok(());
+        }
+        let (read, mut write) = client.into_split();
+        let mut line = String::new();
+        BufReader::new(read.take(MAX_REQUEST))
+            .read_line(&mut line)
+            .await?;
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => handle(request, live).await,
+            Err(e) => Response::Error(format!("bad request: {}", e)),
+        };
+        let mut answer = serde_json::to_string(&response)?;
+        answer.push('\n');
+        write.write_all(answer.as_bytes()).await
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::Target;
+    use chrono::Utc;
+
+    #[tokio::test]
+    async fn test_requests_reach_the_watch() {
+        let port = 40000 + (std::process::id() % 20000) as u16;
+        assert!(request(port, Request::Status).await.is_none());
+
+        let live = Live::default();
+        let socket = ControlSocket::start(port, live.clone()).unwrap();
+        let answer = request(port, Request::Status).await.unwrap();
+        assert!(answer.unwrap_err().to_string().contains("hasn't started"));
+
+        let target = Target::builder("pi.local", "pi", &Config::default())
+            .build()
+            .unwrap();
+        live.set(&Stats::new(&target, Duration::from_secs(30), Utc::now()));
+        let stats = request(port, Request::Status).await.unwrap().unwrap();
+        assert_eq!((stats.host.as_str(), stats.port), ("pi.local", 2222));
+        // Nothing takes orders without a running watch
+        assert!(request(port, Request::Down).await.unwrap().is_err());
+
+        drop(socket);
+        assert!(!socket_path(port).exists());
+        assert!(request(port, Request::Status).await.is_none());
+    }
+
+    #[test]
+    fn test_requests_are_json_lines() {
+        assert_eq!(
+            serde_json::to_string(&Request::Reconnect).unwrap(),
+            r#"{"command":"reconnect"}"#
+        );
+        let error: Response = serde_json::from_str(r#"{"error":"no"}"#).unwrap();
+        assert!(matches!(error, Response::Error(e) if e == "no"));
+    }
+}
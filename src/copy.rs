@@ -0,0 +1,381 @@
+//! `copy`: files and directories to or from a device, with scp through the tunnel.
+//!
+//! Paths are written as for `scp`: `remote:PATH` (or `<profile>:PATH`) is on
+//! the device, anything else is local, and either all sources or the
+//! destination are remote. A tunnel already open on the target's local port
+//! is reused. On a terminal scp shows its own progress meter; otherwise it is
+//! kept quiet and the result says what was copied.
+
+use crate::config::Config;
+use crate::executor::Stdio;
+use crate::output::{self, OutputFormat, Renderable};
+use crate::prompt;
+use crate::ssh;
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use serde::Serialize;
+use std::ffi::OsString;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Device name in a path that means the target given by the options
+pub const REMOTE: &str = "remote";
+
+/// One side of a copy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    Local(PathBuf),
+    /// `path` on the device named `device` (`remote` or a host profile)
+    Remote {
+        device: String,
+        path: String,
+    },
+}
+
+impl Location {
+    /// Reads `DEVICE:PATH` as remote and anything else as local. As with scp,
+    /// a colon after a slash is part of a local path, so `./a:b` is local.
+    pub fn parse(spec: &str) -> Self {
+        match spec.split_once(':') {
+            Some((device, path)) if !device.is_empty() && !device.contains('/') => {
+                Location::Remote {
+                    device: device.to_string(),
+                    path: path.to_string(),
+                }
+            }
+            _ => Location::Local(PathBuf::from(spec)),
+        }
+    }
+}
+
+/// Which way files go
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// From here to the device
+    Push,
+    /// From the device to here
+    Pull,
+}
+
+/// A copy read from the command line: all sources on one side, dest on the other
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopyPlan {
+    pub direction: Direction,
+    /// The device named in the remote paths, `remote` for the options' target
+    pub device: String,
+    pub sources: Vec<String>,
+    pub dest: String,
+}
+
+impl CopyPlan {
+    /// Sorts `paths` (sources, then the destination) into a plan
+    pub fn parse(paths: &[String]) -> Result<Self, TunnelError> {
+        let invalid = |message: &str| TunnelError::Transfer(message.to_string());
+        let Some((dest, sources)) = paths.split_last().filter(|(_, s)| !s.is_empty()) else {
+            return Err(invalid(
+                "a source and a destination are needed, e.g. ./firmware.bin remote:/tmp/",
+            ));
+        };
+        let locations: Vec<Location> = sources.iter().map(|s| Location::parse(s)).collect();
+        let (direction, remote_paths, devices) = match Location::parse(dest) {
+            Location::Remote { device, path } => {
+                if locations
+                    .iter()
+                    .any(|l| matches!(l, Location::Remote { .. }))
+                {
+                    return Err(invalid(
+                        "either the sources or the destination must be local, not both remote",
+                    ));
+                }
+                (Direction::Push, vec![path], vec![device])
+            }
+            Location::Local(_) => {
+                let mut paths = Vec::new();
+                let mut devices = Vec::new();
+                for location in locations {
+                    match location {
+                        Location::Remote { device, path } => {
+                            devices.push(device);
+                            paths.push(path);
+                        }
+                        Location::Local(path) => {
+                            return Err(TunnelError::Transfer(format!(
+                                "{} and the destination are both local; write device paths as remote:PATH",
+                                path.display()
+                            )))
+                        }
+                    }
+                }
+                (Direction::Pull, paths, devices)
+            }
+        };
+        if devices.iter().any(|d| *d != devices[0]) {
+            return Err(invalid("all remote paths must be on the same device"));
+        }
+        if remote_paths.iter().any(String::is_empty) {
+            return Err(invalid(
+                "a remote path is empty; write remote:. for the login user's home",
+            ));
+        }
+        let device = devices[0].clone();
+        Ok(match direction {
+            Direction::Push => CopyPlan {
+                direction,
+                device,
+                sources: sources.to_vec(),
+                dest: remote_paths[0].clone(),
+            },
+            Direction::Pull => CopyPlan {
+                direction,
+                device,
+                sources: remote_paths,
+                dest: dest.clone(),
+            },
+        })
+    }
+}
+
+/// Result of `copy`
+#[derive(Debug, Serialize)]
+pub struct CopyReport {
+    pub host: String,
+    pub direction: Direction,
+    pub sources: Vec<String>,
+    pub dest: String,
+    pub recursive: bool,
+    /// Size of what was copied, as it is on this machine
+    pub bytes: u64,
+    #[serde(rename = "duration_ms", serialize_with = "as_millis")]
+    pub duration: Duration,
+}
+
+pub(crate) fn as_millis<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_millis() as u64)
+}
+
+impl Renderable for CopyReport {
+    fn to_human(&self) -> String {
+        let (from, to) = match self.direction {
+            Direction::Push => (
+                self.sources.join(", "),
+                format!("{}:{}", self.host, self.dest),
+            ),
+            Direction::Pull => {
+                let sources: Vec<String> = self
+                    .sources
+                    .iter()
+                    .map(|source| format!("{}:{}", self.host, source))
+                    .collect();
+                (sources.join(", "), self.dest.clone())
+            }
+        };
+        format!(
+            "Copied {} to {} ({} bytes in {})",
+            from,
+            to,
+            self.bytes,
+            output::format_duration(self.duration)
+        )
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Total size of the files at `path`, following directories
+fn local_size(path: &Path) -> u64 {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    std::fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| local_size(&entry.path()))
+        .sum()
+}
+
+/// Where a pulled `source` lands locally under `dest`
+fn pulled_path(source: &str, dest: &Path, single: bool) -> PathBuf {
+    if single && !dest.is_dir() {
+        return dest.to_path_buf();
+    }
+    let name = source
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or(source);
+    dest.join(name)
+}
+
+/// Copies as `plan` says between here and `target`, whole directories with `recursive`
+pub async fn copy(
+    config: &Config,
+    target: &Target,
+    plan: &CopyPlan,
+    recursive: bool,
+) -> Result<CopyReport> {
+    if plan.direction == Direction::Push {
+        for source in &plan.sources {
+            let path = Path::new(source);
+            if path.is_dir() && !recursive {
+                anyhow::bail!(TunnelError::Transfer(format!(
+                    "{} is a directory; pass --recursive to copy it",
+                    source
+                )));
+            }
+            if !path.exists() {
+                anyhow::bail!(TunnelError::Transfer(format!("{} does not exist", source)));
+            }
+        }
+    }
+
+    SSHTunnelManager::new(config.clone())
+        .reuse_or_connect(target)
+        .await?;
+
+    let remote = |path: &str| OsString::from(format!("localhost:{}", path));
+    let (sources, dest): (Vec<OsString>, OsString) = match plan.direction {
+        Direction::Push => (
+            plan.sources.iter().map(OsString::from).collect(),
+            remote(&plan.dest),
+        ),
+        Direction::Pull => (
+            plan.sources.iter().map(|source| remote(source)).collect(),
+            OsString::from(&plan.dest),
+        ),
+    };
+    // scp draws its progress meter when stdout is a terminal
+    let meter = output::format() == OutputFormat::Human
+        && !prompt::is_batch()
+        && std::io::stdout().is_terminal();
+    let mut options = Vec::new();
+    if !meter {
+        options.push("-q");
+    }
+    if recursive {
+        options.push("-r");
+    }
+
+    info!(
+        "Copying {} {} {}...",
+        plan.sources.join(", "),
+        match plan.direction {
+            Direction::Push => "to",
+            Direction::Pull => "from",
+        },
+        target.host
+    );
+    let started = Instant::now();
+    let output = ssh::scp_through_tunnel(target, &options, &sources, &dest)?
+        .stdout(if meter {
+            Stdio::inherit()
+        } else {
+            Stdio::null()
+        })
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| TunnelError::Transfer(e.to_string()))?;
+    if !output.status.success() {
+        return Err(TunnelError::Transfer(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        )
+        .into());
+    }
+    let duration = started.elapsed();
+
+    let bytes = match plan.direction {
+        Direction::Push => plan.sources.iter().map(|s| local_size(Path::new(s))).sum(),
+        Direction::Pull => {
+            let single = plan.sources.len() == 1;
+            plan.sources
+                .iter()
+                .map(|source| local_size(&pulled_path(source, Path::new(&plan.dest), single)))
+                .sum()
+        }
+    };
+    Ok(CopyReport {
+        host: target.host.clone(),
+        direction: plan.direction,
+        sources: plan.sources.clone(),
+        dest: plan.dest.clone(),
+        recursive,
+        bytes,
+        duration,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(paths: &[&str]) -> Result<CopyPlan, TunnelError> {
+        CopyPlan::parse(&paths.iter().map(|p| p.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_locations_follow_scp() {
+        assert_eq!(
+            Location::parse("remote:/tmp/"),
+            Location::Remote {
+                device: "remote".to_string(),
+                path: "/tmp/".to_string()
+            }
+        );
+        assert_eq!(
+            Location::parse("./a:b"),
+            Location::Local(PathBuf::from("./a:b"))
+        );
+        assert_eq!(Location::parse(":x"), Location::Local(PathBuf::from(":x")));
+    }
+
+    #[test]
+    fn test_plan_direction_and_errors() {
+        let push = plan(&["./firmware.bin", "./boot.img", "pi:/tmp/"]).unwrap();
+        assert_eq!(push.direction, Direction::Push);
+        assert_eq!(push.device, "pi");
+        assert_eq!(push.sources, ["./firmware.bin", "./boot.img"]);
+        assert_eq!(push.dest, "/tmp/");
+
+        let pull = plan(&["remote:/var/log/syslog", "./"]).unwrap();
+        assert_eq!(pull.direction, Direction::Pull);
+        assert_eq!(pull.sources, ["/var/log/syslog"]);
+        assert_eq!(pull.dest, "./");
+
+        for paths in [
+            &["remote:/tmp/"][..],
+            &["a", "b"],
+            &["remote:/a", "remote:/b"],
+            &["pi:/a", "jetson:/b", "./"],
+            &["remote:/a", "b", "./"],
+            &["a", "remote:"],
+        ] {
+            assert!(plan(paths).is_err(), "{:?}", paths);
+        }
+    }
+
+    #[test]
+    fn test_pulled_path() {
+        let dir = std::env::temp_dir();
+        assert_eq!(
+            pulled_path("/var/log/syslog", &dir, true),
+            dir.join("syslog")
+        );
+        assert_eq!(pulled_path("/var/log/", &dir, false), dir.join("log"));
+        assert_eq!(
+            pulled_path("/var/log/syslog", Path::new("./saved.log"), true),
+            PathBuf::from("./saved.log")
+        );
+    }
+}
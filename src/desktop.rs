@@ -0,0 +1,127 @@
+//! `up --watch --notify`: desktop notifications, so a flaky board needs no
+//! terminal kept in sight.
+//!
+//! A notification is shown when the key has been deployed, when the watched
+//! tunnel stops answering and when it is back. They are sent with
+//! `notify-send`, or `osascript` on macOS, in the background. One that can't
+//! be shown is a warning, given once.
+
+use crate::output::Event;
+use crate::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::warn;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a failure has been warned about
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Shows notifications from then on
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Shows `event`, if notifications are on and it is one they are for
+pub fn show(event: &Event) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let Some((title, body)) = describe(event) else {
+        return;
+    };
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    runtime.spawn(async move {
+        if let Err(e) = send(&title, &body).await {
+            if !WARNED.swap(true, Ordering::Relaxed) {
+                warn!("Can't show desktop notifications: {}", e);
+            }
+        }
+    });
+}
+
+/// The title and text of the notification for `event`
+fn describe(event: &Event) -> Option<(String, String)> {
+    match event {
+        Event::KeyTransferred { port, key_path } => Some((
+            "Key deployed".to_string(),
+            format!(
+                "{} is on the device on localhost:{}",
+                key_path.display(),
+                port
+            ),
+        )),
+        Event::TunnelLost { host, error, .. } => {
+            Some((format!("Tunnel to {} lost", host), error.clone()))
+        }
+        Event::TunnelRecovered {
+            host,
+            port,
+            reconnects,
+        } => Some((
+            format!("Tunnel to {} back", host),
+            format!("On localhost:{}, opened again {} time(s)", port, reconnects),
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "macos")]
+async fn send(title: &str, body: &str) -> Result<(), String> {
+    // AppleScript strings take backslash escapes
+    let quote = |text: &str| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+    let script = format!(
+        "display notification {} with title {}",
+        quote(body),
+        quote(title)
+    );
+    run(process::command("osascript")
+        .map_err(|e| e.to_string())?
+        .args(["-e", &script]))
+    .await
+}
+
+#[cfg(not(target_os = "macos"))]
+async fn send(title: &str, body: &str) -> Result<(), String> {
+    run(process::command("notify-send")
+        .map_err(|e| e.to_string())?
+        .args(["--app-name", env!("CARGO_PKG_NAME"), "--", title, body]))
+    .await
+}
+
+async fn run(command: &mut tokio::process::Command) -> Result<(), String> {
+    let output = command.output().await.map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_transitions_are_shown() {
+        let lost = Event::TunnelLost {
+            host: "pi.local".to_string(),
+            port: 2222,
+            error: "timed out".to_string(),
+        };
+        assert_eq!(
+            describe(&lost),
+            Some((
+                "Tunnel to pi.local lost".to_string(),
+                "timed out".to_string()
+            ))
+        );
+        let back = Event::TunnelRecovered {
+            host: "pi.local".to_string(),
+            port: 2222,
+            reconnects: 2,
+        };
+        assert_eq!(describe(&back).unwrap().0, "Tunnel to pi.local back");
+        assert_eq!(describe(&Event::TunnelValidated { port: 2222 }), None);
+    }
+}
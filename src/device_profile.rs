@@ -0,0 +1,543 @@
+//! Declarative device profiles, applied by `apply-profile`.
+//!
+//! A `[device_profiles.<name>]` section describes how a kind of board should
+//! end up: hostname, user accounts, authorized keys, sshd settings, packages,
+//! interfaces and overlays, and Wi-Fi. Applying one first asks the device what
+//! would change and shows that plan, then makes only those changes. Every step
+//! checks before it acts, so applying a profile again changes nothing.
+//!
+//! Each step runs a shell script with `plan` or `apply` as its first argument;
+//! both modes print one line per change, `+` for something added, `-` for
+//! something removed and `~` for something changed.
+//!
+//! Values may be templates over the device's facts (see [`crate::template`]
+//! and [`crate::facts`]), so one profile can pick packages or overlays per
+//! board, e.g. `"{% if mem_mb < 1024 %}zram-tools{% endif %}"`.
+
+use crate::authorized_keys;
+use crate::config::Config;
+use crate::executor::Stdio;
+use crate::facts::{self, Facts};
+use crate::hardware;
+use crate::keys::{self, PublicKey};
+use crate::output::Renderable;
+use crate::paths;
+use crate::prompt;
+use crate::pure::profile::{
+    self, change_lines, context, Change, DeviceProfile, ProfileReport, Step,
+};
+use crate::shell::{self, RemoteCommand};
+use crate::ssh;
+use crate::sshd::{self, Login};
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time::timeout;
+use tracing::info;
+
+/// Upper bound for most steps
+const STEP_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Installing packages downloads them first
+const PACKAGES_TIMEOUT: Duration = Duration::from_secs(1800);
+
+/// The sshd_config drop-in the `sshd` settings are written to
+const SSHD_DROP_IN: &str = "/etc/ssh/sshd_config.d/50-ssh-ip-tunnel.conf";
+
+/// Sets the hostname to `$2`, in /etc/hosts too
+pub const HOSTNAME_SCRIPT: &str = r#"set -e
+current=$(hostname)
+[ "$current" != "$2" ] || exit 0
+echo "~ hostname $current -> $2"
+[ "$1" = apply ] || exit 0
+if command -v hostnamectl >/dev/null 2>&1 && [ -d /run/systemd/system ]; then
+  as_root hostnamectl set-hostname "$2"
+else
+  echo "$2" | as_root tee /etc/hostname >/dev/null
+  as_root hostname "$2"
+fi
+if grep -q '^127\.0\.1\.1[[:space:]]' /etc/hosts; then
+  as_root sed -i "s/^127\.0\.1\.1[[:space:]].*/127.0.1.1\t$2/" /etc/hosts
+else
+  printf '127.0.1.1\t%s\n' "$2" | as_root tee -a /etc/hosts >/dev/null
+fi"#;
+
+/// Creates user `$2`, adds it to the groups in `$3` (space-separated) and
+/// authorizes the key lines in `$4` (newline-separated)
+pub const USER_SCRIPT: &str = r#"set -e
+mode=$1 name=$2 groups=$3 keys=$4
+if id "$name" >/dev/null 2>&1; then
+  current=" $(id -nG "$name") "
+else
+  current=
+  echo "+ user $name"
+  if [ "$mode" = apply ]; then
+    if command -v useradd >/dev/null 2>&1; then as_root useradd -m -s /bin/sh "$name"; else as_root adduser -D "$name"; fi
+  fi
+fi
+for group in $groups; do
+  case "$current" in *" $group "*) continue ;; esac
+  echo "+ $name in group $group"
+  [ "$mode" = apply ] || continue
+  if ! grep -q "^$group:" /etc/group; then as_root groupadd "$group" 2>/dev/null || as_root addgroup "$group"; fi
+  if command -v usermod >/dev/null 2>&1; then as_root usermod -aG "$group" "$name"; else as_root addgroup "$name" "$group"; fi
+done
+[ -n "$keys" ] || exit 0
+home=$(awk -F: -v user="$name" '$1 == user { print $6 }' /etc/passwd)
+file="$home/.ssh/authorized_keys"
+printf '%s\n' "$keys" | while IFS= read -r key; do
+  data=$(echo "$key" | awk '{ print $2 }')
+  if [ -n "$home" ] && as_root cat "$file" 2>/dev/null | awk -v data="$data" '{ for (i = 1; i <= NF; i++) if ($i == data) found = 1 } END { exit !found }'; then
+    continue
+  fi
+  echo "+ key $(echo "$key" | awk '{ print $1, $3 }') for $name"
+  [ "$mode" = apply ] || continue
+  as_root mkdir -p "$home/.ssh"
+  if as_root test -s "$file" && [ -n "$(as_root tail -c1 "$file")" ]; then echo | as_root tee -a "$file" >/dev/null; fi
+  printf '%s\n' "$key" | as_root tee -a "$file" >/dev/null
+  as_root chmod 700 "$home/.ssh"
+  as_root chmod 600 "$file"
+  as_root chown -R "$name:" "$home/.ssh"
+done"#;
+
+/// Writes the sshd settings in `$2` (one `Keyword value` per line) to the
+/// drop-in `$3`, checks the result with `sshd -t` and reloads sshd; a
+/// configuration sshd rejects is rolled back
+pub const SSHD_SCRIPT: &str = r#"set -e
+mode=$1 content=$2 file=$3
+current=$(as_root cat "$file" 2>/dev/null || true)
+included=yes
+grep -qiE '^[[:space:]]*Include[[:space:]]+/etc/ssh/sshd_config\.d/' /etc/ssh/sshd_config 2>/dev/null || included=
+[ "$current" != "$content" ] || [ -z "$included" ] || exit 0
+printf '%s\n' "$content" | while IFS= read -r line; do
+  printf '%s\n' "$current" | grep -qxF "$line" || echo "+ $line"
+done
+printf '%s\n' "$current" | while IFS= read -r line; do
+  [ -z "$line" ] || printf '%s\n' "$content" | grep -qxF "$line" || echo "- $line"
+done
+[ -n "$included" ] || echo "+ Include /etc/ssh/sshd_config.d/*.conf in /etc/ssh/sshd_config"
+[ "$mode" = apply ] || exit 0
+sshd=$(command -v sshd || echo /usr/sbin/sshd)
+[ -x "$sshd" ] || { echo "sshd not found; sshd settings need OpenSSH's sshd" >&2; exit 1; }
+as_root mkdir -p "$(dirname "$file")"
+if [ -e "$file" ]; then as_root cp -p "$file" "$file.bak"; fi
+printf '%s\n' "$content" | as_root tee "$file" >/dev/null
+if [ -z "$included" ]; then
+  as_root cp -p /etc/ssh/sshd_config /etc/ssh/sshd_config.ssh-ip-tunnel.bak
+  as_root sed -i '1i Include /etc/ssh/sshd_config.d/*.conf' /etc/ssh/sshd_config
+fi
+if ! error=$(as_root "$sshd" -t 2>&1); then
+  if [ -e "$file.bak" ]; then as_root mv -f "$file.bak" "$file"; else as_root rm -f "$file"; fi
+  [ -n "$included" ] || as_root mv -f /etc/ssh/sshd_config.ssh-ip-tunnel.bak /etc/ssh/sshd_config
+  echo "sshd rejected the settings, which were rolled back: $error" >&2
+  exit 1
+fi
+as_root systemctl reload ssh 2>/dev/null || as_root systemctl reload sshd 2>/dev/null || as_root kill -HUP "$(cat /var/run/sshd.pid)""#;
+
+/// Installs the packages in `$2...` that are missing, with whichever of apt,
+/// apk, dnf and opkg the device has
+pub const PACKAGES_SCRIPT: &str = r#"set -e
+mode=$1
+shift
+if command -v apt-get >/dev/null 2>&1; then manager=apt
+elif command -v apk >/dev/null 2>&1; then manager=apk
+elif command -v dnf >/dev/null 2>&1; then manager=dnf
+elif command -v opkg >/dev/null 2>&1; then manager=opkg
+else echo "no supported package manager (apt, apk, dnf or opkg)" >&2; exit 1
+fi
+missing=
+for package in "$@"; do
+  case $manager in
+    apt) dpkg-query -W -f='${Status}' "$package" 2>/dev/null | grep -q 'ok installed' ;;
+    apk) apk info -e "$package" >/dev/null 2>&1 ;;
+    dnf) rpm -q "$package" >/dev/null 2>&1 ;;
+    opkg) opkg list-installed 2>/dev/null | grep -q "^$package " ;;
+  esac && continue
+  echo "+ package $package"
+  missing="$missing $package"
+done
+[ -n "$missing" ] && [ "$mode" = apply ] || exit 0
+case $manager in
+  apt) as_root env DEBIAN_FRONTEND=noninteractive apt-get update -q >/dev/null
+       as_root env DEBIAN_FRONTEND=noninteractive apt-get install -y -q $missing >/dev/null ;;
+  apk) as_root apk add -q $missing ;;
+  dnf) as_root dnf install -y -q $missing ;;
+  opkg) as_root opkg update >/dev/null
+        as_root opkg install $missing >/dev/null ;;
+esac"#;
+
+/// Adds Wi-Fi network `$2` with country `$3`, reading the passphrase from
+/// stdin so it stays out of the ssh command line
+pub const WIFI_SCRIPT: &str = r#"set -e
+mode=$1 ssid=$2 country=$3
+IFS= read -r psk || true
+if command -v nmcli >/dev/null 2>&1; then
+  ! nmcli -t -f NAME connection show 2>/dev/null | grep -qxF "$ssid" || exit 0
+  echo "+ wifi network $ssid (NetworkManager)"
+  [ "$mode" = apply ] || exit 0
+  as_root nmcli connection add type wifi con-name "$ssid" ssid "$ssid" wifi-sec.key-mgmt wpa-psk wifi-sec.psk "$psk" connection.autoconnect yes >/dev/null
+  if [ -n "$country" ] && command -v iw >/dev/null 2>&1; then as_root iw reg set "$country" || true; fi
+else
+  conf=/etc/wpa_supplicant/wpa_supplicant.conf
+  ! as_root grep -qF "ssid=\"$ssid\"" "$conf" 2>/dev/null || exit 0
+  echo "+ wifi network $ssid (wpa_supplicant)"
+  [ "$mode" = apply ] || exit 0
+  if [ -e "$conf" ]; then
+    as_root cp -p "$conf" "$conf.ssh-ip-tunnel.bak"
+  else
+    as_root mkdir -p /etc/wpa_supplicant
+    printf 'ctrl_interface=DIR=/var/run/wpa_supplicant GROUP=netdev\nupdate_config=1\n' | as_root tee "$conf" >/dev/null
+  fi
+  if [ -n "$country" ] && ! as_root grep -q '^country=' "$conf"; then
+    printf 'country=%s\n' "$country" | as_root tee -a "$conf" >/dev/null
+  fi
+  printf 'network={\n\tssid="%s"\n\tpsk="%s"\n}\n' "$ssid" "$psk" | as_root tee -a "$conf" >/dev/null
+  as_root chmod 600 "$conf"
+  as_root wpa_cli reconfigure >/dev/null 2>&1 || true
+fi"#;
+
+impl Renderable for ProfileReport {
+    fn to_human(&self) -> String {
+        self.text()
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Applies device profile `name` to `target`, or only plans it if `plan_only`.
+///
+/// The profile's templates are rendered with the device's facts (gathered
+/// anew if `refresh_facts`) and `host_name` as `name`. The plan is shown and
+/// must be confirmed unless `assume_yes`.
+pub async fn apply(
+    config: &Config,
+    target: &Target,
+    name: &str,
+    host_name: &str,
+    options: ApplyOptions,
+) -> Result<ProfileReport> {
+    let ApplyOptions {
+        plan_only,
+        assume_yes,
+        refresh_facts,
+    } = options;
+    let profile = config.device_profiles.get(name).ok_or_else(|| {
+        let known: Vec<&str> = config.device_profiles.keys().map(String::as_str).collect();
+        anyhow::anyhow!(
+            "Unknown device profile '{}' (known profiles: {})",
+            name,
+            if known.is_empty() {
+                "none".to_string()
+            } else {
+                known.join(", ")
+            }
+        )
+    })?;
+    // Catch template and value errors before connecting
+    profile
+        .render(&context(&Facts::example(), host_name))?
+        .validate()?;
+    if !plan_only && !assume_yes && !prompt::is_interactive() {
+        anyhow::bail!("apply-profile changes the device; pass --yes to apply without a terminal, or --plan to only show the plan");
+    }
+
+    SSHTunnelManager::new(config.clone())
+        .connect(target)
+        .await?;
+    let facts = facts::gather(target, refresh_facts).await?;
+    let profile = &profile.render(&context(&facts, host_name))?;
+    profile.validate()?;
+    let keys = KeySet::load(profile)?;
+
+    info!(
+        "Comparing {} with device profile '{}'...",
+        target.host, name
+    );
+    let step = StepRunner {
+        target,
+        profile,
+        keys: &keys,
+    };
+    let mut planned = Vec::new();
+    for each in Step::ALL {
+        planned.extend(step.run(each, false).await?);
+    }
+    let mut report = ProfileReport {
+        host: target.host.clone(),
+        device_profile: name.to_string(),
+        facts,
+        reboot_required: false,
+        changes: planned,
+        applied: false,
+    };
+    if plan_only || report.changes.is_empty() {
+        return Ok(report);
+    }
+
+    if !assume_yes {
+        prompt::notice(&report.to_human());
+        let answer = prompt::ask("Apply these changes? (yes/no)", "no")?;
+        if !matches!(answer.to_ascii_lowercase().as_str(), "y" | "yes") {
+            anyhow::bail!("Not confirmed; {} was left unchanged", target.host);
+        }
+    }
+
+    let mut applied = Vec::new();
+    for each in Step::ALL {
+        if report.changes.iter().any(|change| change.step == each) {
+            info!("Applying {}...", each.as_str());
+            applied.extend(step.run(each, true).await?);
+        }
+    }
+    report.reboot_required = profile::reboot_required(&applied);
+    report.changes = applied;
+    report.applied = true;
+    Ok(report)
+}
+
+/// How [`apply`] runs
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ApplyOptions {
+    /// Only show what would change
+    pub plan_only: bool,
+    /// Don't ask for confirmation
+    pub assume_yes: bool,
+    /// Gather the device's facts even if they are cached
+    pub refresh_facts: bool,
+}
+
+/// The profile's public keys
+struct KeySet {
+    login: Vec<PublicKey>,
+    users: Vec<Vec<PublicKey>>,
+}
+
+impl KeySet {
+    fn load(profile: &DeviceProfile) -> Result<Self, TunnelError> {
+        let read = |paths: &[String]| -> Result<Vec<PublicKey>, TunnelError> {
+            paths
+                .iter()
+                .map(|path| keys::read_public_key(&paths::expand_tilde(path)?))
+                .collect()
+        };
+        Ok(Self {
+            login: read(&profile.keys)?,
+            users: profile
+                .users
+                .iter()
+                .map(|user| read(&user.keys))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+struct StepRunner<'a> {
+    target: &'a Target,
+    profile: &'a DeviceProfile,
+    keys: &'a KeySet,
+}
+
+impl StepRunner<'_> {
+    /// Plans `step`, or applies it if `apply`, returning its changes
+    async fn run(&self, step: Step, apply: bool) -> Result<Vec<Change>, TunnelError> {
+        let mode = if apply { "apply" } else { "plan" };
+        let profile = self.profile;
+        let lines = match step {
+            Step::Hostname => match &profile.hostname {
+                Some(hostname) => {
+                    self.script(HOSTNAME_SCRIPT, &[mode, hostname], None, STEP_TIMEOUT)
+                        .await?
+                }
+                None => Vec::new(),
+            },
+            Step::Users => {
+                let mut lines = Vec::new();
+                for (user, keys) in profile.users.iter().zip(&self.keys.users) {
+                    let key_lines: Vec<String> = keys.iter().map(PublicKey::to_line).collect();
+                    let args = [
+                        mode,
+                        &user.name,
+                        &user.groups.join(" "),
+                        &key_lines.join("\n"),
+                    ];
+                    lines.extend(self.script(USER_SCRIPT, &args, None, STEP_TIMEOUT).await?);
+                }
+                lines
+            }
+            Step::Keys => {
+                let mut lines = Vec::new();
+                if !self.keys.login.is_empty() {
+                    // Login keys are the login user's even with --target-user
+                    let login = Target {
+                        target_user: None,
+                        ..self.target.clone()
+                    };
+                    let authorized = authorized_keys::read(&login).await?;
+                    for key in &self.keys.login {
+                        if keys::is_authorized(&authorized, key) {
+                            continue;
+                        }
+                        lines.push(format!("+ key {} {}", key.key_type, key.comment));
+                        if apply {
+                            authorized_keys::install(&login, key, &[]).await?;
+                        }
+                    }
+                }
+                lines
+            }
+            Step::Sshd if !profile.sshd.is_empty() => {
+                let args = [mode, &profile.sshd_drop_in(), SSHD_DROP_IN];
+                let step = self.script(SSHD_SCRIPT, &args, None, STEP_TIMEOUT);
+                if apply {
+                    sshd::change(self.target, Login::Target, step).await?
+                } else {
+                    step.await?
+                }
+            }
+            Step::Packages if !profile.packages.is_empty() => {
+                let mut args = vec![mode];
+                args.extend(profile.packages.iter().map(String::as_str));
+                self.script(PACKAGES_SCRIPT, &args, None, PACKAGES_TIMEOUT)
+                    .await?
+            }
+            Step::Hardware if !profile.hardware.is_empty() => {
+                let report = hardware::run(self.target, &profile.hardware, !apply)
+                    .await
+                    .map_err(|e| TunnelError::Profile(e.to_string()))?;
+                let config = report.boot_config.as_deref().unwrap_or("config.txt");
+                let config = config.rsplit('/').next().unwrap_or(config);
+                report
+                    .config_added
+                    .iter()
+                    .map(|line| format!("+ {}: {}", config, line))
+                    .chain(
+                        report
+                            .modules_added
+                            .iter()
+                            .map(|module| format!("+ module {}", module)),
+                    )
+                    .collect()
+            }
+            Step::Wifi => match &profile.wifi {
+                Some(wifi) => {
+                    let args = [mode, &wifi.ssid, wifi.country.as_deref().unwrap_or("")];
+                    self.script(WIFI_SCRIPT, &args, Some(&wifi.psk), STEP_TIMEOUT)
+                        .await?
+                }
+                None => Vec::new(),
+            },
+            Step::Sshd | Step::Packages | Step::Hardware => Vec::new(),
+        };
+        Ok(lines
+            .into_iter()
+            .map(|description| Change { step, description })
+            .collect())
+    }
+
+    /// Runs one step script, returning the change lines it printed
+    async fn script(
+        &self,
+        script: &str,
+        args: &[&str],
+        stdin: Option<&str>,
+        limit: Duration,
+    ) -> Result<Vec<String>, TunnelError> {
+        let mut command = RemoteCommand::new("sh")
+            .arg("-c")
+            .arg(format!("{}\n{}", shell::AS_ROOT, script))
+            .arg("sh");
+        for arg in args {
+            command = command.arg(arg);
+        }
+        let mut child = ssh::through_tunnel(self.target, &command)?
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| TunnelError::Profile(e.to_string()))?;
+        if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            pipe.write_all(format!("{}\n", input).as_bytes())
+                .await
+                .map_err(|e| TunnelError::Profile(e.to_string()))?;
+        }
+        let output = timeout(limit, child.wait_with_output())
+            .await
+            .map_err(|_| TunnelError::Profile("timeout".to_string()))?
+            .map_err(|e| TunnelError::Profile(e.to_string()))?;
+        if !output.status.success() {
+            return Err(TunnelError::Profile(format!(
+                "{} (root or passwordless sudo is needed)",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(change_lines(&String::from_utf8_lossy(&output.stdout)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `script` here in plan mode, as the device would
+    #[cfg(unix)]
+    fn plan(script: &str, args: &[&str]) -> Vec<String> {
+        let output = std::process::Command::new("sh")
+            .args([
+                "-c",
+                &format!("{}\n{}", shell::AS_ROOT, script),
+                "sh",
+                "plan",
+            ])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        change_lines(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_plans_list_changes_without_making_them() {
+        let current = std::process::Command::new("hostname").output().unwrap();
+        let current = String::from_utf8_lossy(&current.stdout).trim().to_string();
+        assert!(plan(HOSTNAME_SCRIPT, &[&current]).is_empty());
+        assert_eq!(
+            plan(HOSTNAME_SCRIPT, &["lab-pi-7"]),
+            [format!("~ hostname {} -> lab-pi-7", current)]
+        );
+
+        let user = "ssh-ip-tunnel-no-such-user";
+        assert_eq!(
+            plan(USER_SCRIPT, &[user, "gpio i2c", ""]),
+            [
+                format!("+ user {}", user),
+                format!("+ {} in group gpio", user),
+                format!("+ {} in group i2c", user)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_profile_is_refused_before_connecting() {
+        let config = Config::default();
+        let target = Target::builder("pi.local", "pi", &config).build().unwrap();
+        let e = apply(&config, &target, "sensor", "pi", ApplyOptions::default())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Unknown device profile 'sensor' (known profiles: none)"
+        );
+    }
+}
@@ -0,0 +1,188 @@
+//! A DNS forwarder for devices without a working resolver.
+//!
+//! SSH only forwards TCP, so the forwarder takes DNS over TCP (RFC 1035
+//! length-prefixed messages) from the device and passes each query on to this
+//! machine's own resolver over UDP, retrying over TCP when the answer is
+//! truncated. The device is pointed at it with `options use-vc` in
+//! resolv.conf, which glibc honours; musl's resolver can't be made to use TCP.
+
+use crate::TunnelError;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tracing::debug;
+
+/// How long the upstream resolver gets to answer one query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The TC (truncated) bit in the third byte of a DNS header
+const TRUNCATED: u8 = 0x02;
+
+/// A running forwarder; dropping it stops accepting connections
+pub struct DnsForwarder {
+    port: u16,
+    task: JoinHandle<()>,
+}
+
+impl DnsForwarder {
+    /// Starts serving on `127.0.0.1:port`, forwarding to `upstream`
+    pub async fn start(port: u16, upstream: SocketAddr) -> Result<Self, TunnelError> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.map_err(|e| {
+            TunnelError::Forward(format!("DNS forwarder on 127.0.0.1:{}: {}", port, e))
+        })?;
+        let port = listener
+            .local_addr()
+            .map(|addr| addr.port())
+            .unwrap_or(port);
+        let task = tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    if let Err(e) = serve(client, upstream).await {
+                        debug!("DNS forwarder: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(Self { port, task })
+    }
+
+    /// The port the forwarder listens on
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for DnsForwarder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// The first `nameserver` in this machine's resolv.conf, on port 53
+pub fn system_resolver() -> Result<SocketAddr, TunnelError> {
+    let path = Path::new("/etc/resolv.conf");
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| TunnelError::Forward(format!("{}: {}", path.display(), e)))?;
+    first_nameserver(&contents)
+        .ok_or_else(|| TunnelError::Forward(format!("no nameserver in {}", path.display())))
+}
+
+fn first_nameserver(resolv_conf: &str) -> Option<SocketAddr> {
+    resolv_conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("nameserver") {
+            return None;
+        }
+        // Link-local IPv6 servers carry a zone (`fe80::1%eth0`) that IpAddr can't parse
+        let address: IpAddr = words.next()?.parse().ok()?;
+        Some(SocketAddr::new(address, 53))
+    })
+}
+
+/// Answers the queries on one connection until the device closes it
+async fn serve(mut client: TcpStream, upstream: SocketAddr) -> std::io::Result<()> {
+    loop {
+        let length = match client.read_u16().await {
+            Ok(length) => length,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut query = vec![0; usize::from(length)];
+        client.read_exact(&mut query).await?;
+        let answer = resolve(&query, upstream).await?;
+        client.write_u16(answer.len() as u16).await?;
+        client.write_all(&answer).await?;
+    }
+}
+
+/// Sends `query` to `upstream`, over TCP too if the UDP answer didn't fit
+async fn resolve(query: &[u8], upstream: SocketAddr) -> std::io::Result<Vec<u8>> {
+    let answer = resolve_udp(query, upstream).await?;
+    if answer.get(2).is_some_and(|flags| flags & TRUNCATED != 0) {
+        return resolve_tcp(query, upstream).await;
+    }
+    Ok(answer)
+}
+
+async fn resolve_udp(query: &[u8], upstream: SocketAddr) -> std::io::Result<Vec<u8>> {
+    let local: SocketAddr = if upstream.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+    let mut answer = vec![0; 65535];
+    loop {
+        let received = timeout(QUERY_TIMEOUT, socket.recv(&mut answer))
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+        // Ignore anything that isn't the answer to this query's ID
+        if received >= 2 && answer[..2] == query[..2.min(query.len())] {
+            answer.truncate(received);
+            return Ok(answer);
+        }
+    }
+}
+
+async fn resolve_tcp(query: &[u8], upstream: SocketAddr) -> std::io::Result<Vec<u8>> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(upstream).await?;
+        stream.write_u16(query.len() as u16).await?;
+        stream.write_all(query).await?;
+        let length = stream.read_u16().await?;
+        let mut answer = vec![0; usize::from(length)];
+        stream.read_exact(&mut answer).await?;
+        Ok(answer)
+    };
+    timeout(QUERY_TIMEOUT, exchange)
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_nameserver() {
+        let resolv_conf = "# generated\nsearch lan\nnameserver fe80::1%eth0\nnameserver 192.168.1.1\nnameserver 1.1.1.1\n";
+        assert_eq!(
+            first_nameserver(resolv_conf),
+            Some("192.168.1.1:53".parse().unwrap())
+        );
+        assert_eq!(first_nameserver("options use-vc\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_forwarder_relays_tcp_queries_over_udp() {
+        // An upstream that answers by setting the QR bit on whatever it is asked
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_address = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = vec![0; 512];
+            let (n, peer) = upstream.recv_from(&mut buffer).await.unwrap();
+            buffer[2] |= 0x80;
+            upstream.send_to(&buffer[..n], peer).await.unwrap();
+        });
+
+        let forwarder = DnsForwarder::start(0, upstream_address).await.unwrap();
+        let mut client = TcpStream::connect(("127.0.0.1", forwarder.port()))
+            .await
+            .unwrap();
+        let query = [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        client.write_u16(query.len() as u16).await.unwrap();
+        client.write_all(&query).await.unwrap();
+
+        let length = client.read_u16().await.unwrap();
+        let mut answer = vec![0; usize::from(length)];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer[..2], [0x12, 0x34]);
+        assert_eq!(answer[2], 0x81);
+    }
+}
@@ -0,0 +1,102 @@
+//! `SSH_IP_TUNNEL_*` environment variable overrides.
+//!
+//! Environment values sit between the config file and command-line flags, so
+//! containers and CI jobs can configure the tool without writing files. Callers
+//! take a lookup function rather than reading the process environment directly,
+//! which keeps the layering testable.
+
+use anyhow::Result;
+use std::str::FromStr;
+
+/// Prefix shared by every variable the tool reads
+pub const PREFIX: &str = "SSH_IP_TUNNEL_";
+
+/// Looks up `SSH_IP_TUNNEL_<suffix>` in a variable source
+pub type Lookup<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Reads `SSH_IP_TUNNEL_<suffix>` from the process environment, treating empty values as unset
+#[cfg(feature = "runtime")]
+pub fn process_lookup(suffix: &str) -> Option<String> {
+    std::env::var(format!("{}{}", PREFIX, suffix))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+/// Parses `SSH_IP_TUNNEL_<suffix>`, naming the variable in any error
+pub fn parse<T>(lookup: Lookup, suffix: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    lookup(suffix)
+        .map(|raw| {
+            raw.trim().parse().map_err(|e| {
+                anyhow::anyhow!("Invalid value {:?} for {}{}: {}", raw, PREFIX, suffix, e)
+            })
+        })
+        .transpose()
+}
+
+/// Parses a comma-separated variable, naming the variable in any error
+pub fn list<T>(lookup: Lookup, suffix: &str) -> Result<Option<Vec<T>>>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    lookup(suffix)
+        .map(|raw| {
+            raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| {
+                    item.parse().map_err(|e| {
+                        anyhow::anyhow!("Invalid value {:?} in {}{}: {}", item, PREFIX, suffix, e)
+                    })
+                })
+                .collect()
+        })
+        .transpose()
+}
+
+/// Parses a boolean variable, accepting `1/true/yes/on` and `0/false/no/off`
+pub fn flag(lookup: Lookup, suffix: &str) -> Result<Option<bool>> {
+    lookup(suffix)
+        .map(|raw| match raw.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" => Ok(false),
+            _ => Err(anyhow::anyhow!(
+                "Invalid value {:?} for {}{}: expected true or false",
+                raw,
+                PREFIX,
+                suffix
+            )),
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_flag() {
+        let lookup = |suffix: &str| match suffix {
+            "PORT" => Some("2300".to_string()),
+            "BAD_PORT" => Some("70000".to_string()),
+            "FLAG" => Some("Yes".to_string()),
+            _ => None,
+        };
+
+        assert_eq!(parse::<u16>(&lookup, "PORT").unwrap(), Some(2300));
+        assert_eq!(parse::<u16>(&lookup, "MISSING").unwrap(), None);
+        let err = parse::<u16>(&lookup, "BAD_PORT").unwrap_err().to_string();
+        assert!(err.contains("SSH_IP_TUNNEL_BAD_PORT"), "{}", err);
+
+        assert_eq!(flag(&lookup, "FLAG").unwrap(), Some(true));
+        assert!(flag(&lookup, "PORT").is_err());
+
+        let lookup = |suffix: &str| (suffix == "PORTS").then(|| "22, 2222,".to_string());
+        assert_eq!(list::<u16>(&lookup, "PORTS").unwrap(), Some(vec![22, 2222]));
+        assert_eq!(list::<u16>(&lookup, "MISSING").unwrap(), None);
+    }
+}
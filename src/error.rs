@@ -0,0 +1,133 @@
+//! The error type shared by every module.
+
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// What went wrong, by kind. New kinds are added in minor releases, so match
+/// with a wildcard arm.
+#[non_exhaustive]
+#[derive(Error, Debug)]
+pub enum TunnelError {
+    #[error("SSH tunnel creation failed: {0}")]
+    TunnelCreation(String),
+    #[error("SSH key transfer failed: {0}")]
+    KeyTransfer(String),
+    #[error("Connection validation failed: {0}")]
+    ConnectionValidation(String),
+    #[error("Timeout waiting for tunnel to be ready")]
+    TunnelTimeout,
+    #[error("Invalid SSH key path: {0}")]
+    InvalidKeyPath(PathBuf),
+    #[error("Refusing to transfer private key {0}; pass its public key instead, e.g. {1}")]
+    PrivateKeyRefused(PathBuf, PathBuf),
+    #[error("Architecture detection failed: {0}")]
+    ArchitectureDetection(String),
+    #[error("Non-ARM CPU detected: {0}. This tool is designed for ARM CPUs only")]
+    NonArmCpu(String),
+    #[error("Wrong architecture: {0}")]
+    WrongArchitecture(String),
+    #[error("Unexpected operating system: {0}")]
+    UnexpectedOs(String),
+    #[error("Invalid OS requirement: {0}")]
+    InvalidOsRequirement(String),
+    #[error("Required program '{0}' was not found in PATH")]
+    MissingProgram(String),
+    #[error("Could not determine home directory; set HOME or use absolute paths")]
+    NoHomeDirectory,
+    #[error("Invalid username: {0}")]
+    InvalidUsername(String),
+    #[error("Invalid host: {0}")]
+    InvalidHost(String),
+    #[error("Invalid namespace: {0}")]
+    InvalidNamespace(String),
+    #[error("Invalid public key comment: {0}")]
+    InvalidKeyComment(String),
+    #[error("Invalid public key: {0}")]
+    InvalidPublicKey(String),
+    #[error("Invalid authorized_keys option {0}")]
+    InvalidKeyOption(String),
+    #[error("Invalid phase selection: {0}")]
+    InvalidPhases(String),
+    #[error("Agent installation failed: {0}")]
+    AgentInstall(String),
+    #[error("Download failed: {0}")]
+    Download(String),
+    #[error("No matching artifact: {0}")]
+    NoMatchingArtifact(String),
+    #[error("File transfer failed: {0}")]
+    Transfer(String),
+    #[error("Checksum mismatch: {0}")]
+    ChecksumMismatch(String),
+    #[error("No usable SSH identity: {0}")]
+    NoIdentity(String),
+    #[error("Connection lost: {0}")]
+    ConnectionLost(String),
+    #[error("Security key: {0}")]
+    SecurityKey(String),
+    #[error("Invalid certificate: {0}")]
+    InvalidCertificate(String),
+    #[error("Flashing failed: {0}")]
+    Flash(String),
+    #[error("Update failed: {0}")]
+    Update(String),
+    #[error("Host key: {0}")]
+    HostKey(String),
+    #[error("Container deployment failed: {0}")]
+    Container(String),
+    #[error("Reverse forward failed: {0}")]
+    Forward(String),
+    #[error("Mirror failed: {0}")]
+    Mirror(String),
+    #[error("Key generation failed: {0}")]
+    KeyGeneration(String),
+    #[error("Clock sync failed: {0}")]
+    Clock(String),
+    #[error("Swap setup failed: {0}")]
+    Swap(String),
+    #[error("Hardware configuration failed: {0}")]
+    Hardware(String),
+    #[error("Applying the device profile failed: {0}")]
+    Profile(String),
+    #[error("Hardening sshd failed: {0}")]
+    Harden(String),
+    #[error("Changing sshd failed: {0}")]
+    Sshd(String),
+    #[error("Running the command failed: {0}")]
+    Exec(String),
+    #[error("Opening a shell failed: {0}")]
+    Shell(String),
+    #[error("Run history: {0}")]
+    History(String),
+    #[error("Dashboard failed: {0}")]
+    Tui(String),
+    #[error("Installing the service failed: {0}")]
+    Service(String),
+    #[error("Snapshot failed: {0}")]
+    Snapshot(String),
+    #[error("Hook failed: {0}")]
+    Hook(String),
+    #[error("Webhook failed: {0}")]
+    Webhook(String),
+    #[error("Provisioning script failed: {0}")]
+    Provision(String),
+    #[error("Missing tools: {0}")]
+    MissingTools(String),
+    #[error("Gathering device facts failed: {0}")]
+    Facts(String),
+    #[error("Reading device information failed: {0}")]
+    Info(String),
+    #[error("Measuring latency failed: {0}")]
+    Ping(String),
+    #[error("Throughput test failed: {0}")]
+    Bench(String),
+    #[error("sshd will refuse keys for this user: {0}")]
+    SshPermissions(String),
+    #[error("Injected fault: {0}")]
+    FaultInjected(String),
+    #[error("Logging in with the key alone failed: {0}")]
+    KeyVerification(String),
+    #[error("Watching the tunnel failed: {0}")]
+    Watch(String),
+    #[error("Unsafe runtime directory {0}")]
+    RuntimeDir(String),
+}
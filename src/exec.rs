@@ -0,0 +1,120 @@
+//! `exec` and `shell`: running commands on a device through its tunnel.
+//!
+//! A tunnel already listening on the target's local port is reused, so a
+//! device brought up once with `up` takes commands without connecting again;
+//! otherwise the tunnel is opened first. `exec` runs its command under
+//! `sh -c`, its output reaches this process's stdout and stderr as it is
+//! produced, and stdin is passed along. `shell` gives `ssh` this terminal and
+//! asks for one on the device, so `ssh` puts the terminal in raw mode and
+//! passes window size changes on. Either way the remote exit code becomes the
+//! tool's own.
+
+use crate::config::Config;
+use crate::executor::Stdio;
+use crate::prompt;
+use crate::shell::RemoteCommand;
+use crate::ssh;
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use std::io::IsTerminal;
+use tracing::info;
+
+/// Exit code when the command ended without one, e.g. killed by a signal, as ssh reports it
+const NO_EXIT_CODE: i32 = 255;
+
+/// Runs `command` (its words joined with spaces, as `ssh` does) on `target`,
+/// returning its exit code
+pub async fn exec(config: &Config, target: &Target, command: &[String]) -> Result<i32> {
+    let command = command.join(" ");
+    if command.trim().is_empty() {
+        anyhow::bail!("No command given: pass it after --, e.g. exec -- uptime");
+    }
+    SSHTunnelManager::new(config.clone())
+        .reuse_or_connect(target)
+        .await?;
+
+    info!("Running `{}` on {}...", command, target.host);
+    let status = ssh::through_tunnel(target, &remote(&command))?
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .await
+        .map_err(|e| TunnelError::Exec(e.to_string()))?;
+    Ok(status.code().unwrap_or(NO_EXIT_CODE))
+}
+
+/// The command line that runs `command` under the device's `sh`, quoted so
+/// it reaches `sh -c` as one word whatever it holds
+fn remote(command: &str) -> RemoteCommand {
+    RemoteCommand::new("sh").arg("-c").arg(command)
+}
+
+/// Opens an interactive login shell on `target` on this terminal,
+/// returning the shell's exit code once it ends
+pub async fn shell(config: &Config, target: &Target) -> Result<i32> {
+    if prompt::is_batch() {
+        return Err(
+            TunnelError::Shell("an interactive shell can't run with --batch".to_string()).into(),
+        );
+    }
+    if !(std::io::stdin().is_terminal() && std::io::stdout().is_terminal()) {
+        return Err(TunnelError::Shell(
+            "stdin and stdout must be a terminal; use exec to run commands from scripts"
+                .to_string(),
+        )
+        .into());
+    }
+    SSHTunnelManager::new(config.clone())
+        .reuse_or_connect(target)
+        .await?;
+
+    info!("Opening a shell on {} as {}...", target.host, target.user);
+    let status = ssh::shell_through_tunnel(target)?
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .await
+        .map_err(|e| TunnelError::Shell(e.to_string()))?;
+    Ok(status.code().unwrap_or(NO_EXIT_CODE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulate;
+
+    fn words(command: &str) -> Vec<String> {
+        command.split(' ').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_exec_quotes_the_command_for_the_remote_shell() {
+        let target = Target {
+            host: "pi.local".to_string(),
+            user: "pi".to_string(),
+            ..Target::default()
+        };
+        let command = words(r#"echo "it's $HOME" > 'a b'"#).join(" ");
+        let args = ssh::through_tunnel(&target, &remote(&command))
+            .unwrap()
+            .get_args();
+        assert_eq!(
+            args.last().unwrap(),
+            r#"sh -c 'echo "it'\''s $HOME" > '\''a b'\'''"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exec_returns_the_remote_exit_code() {
+        let dir = std::env::temp_dir().join(format!("ssh_ip_tunnel-exec-{}", std::process::id()));
+        let target = simulate::test_target(&dir);
+        let config = Config::default();
+
+        assert_eq!(exec(&config, &target, &words("true")).await.unwrap(), 0);
+        assert_eq!(exec(&config, &target, &words("exit 3")).await.unwrap(), 3);
+        assert!(exec(&config, &target, &words(" ")).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
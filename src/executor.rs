@@ -0,0 +1,535 @@
+//! Running the `ssh`, `scp` and `rsync` commands [`crate::ssh`] builds.
+//!
+//! A [`Command`] is the real program, or with `--simulate` the fake device of
+//! [`crate::simulate`], which answers in this process on a blocking thread.
+//! Callers drive both the same way, as they would a
+//! [`tokio::process::Command`]: arguments, where the streams go ([`Stdio`]),
+//! then `output`, `status` or `spawn`. A spawned fake device talks through
+//! in-memory pipes, so scripts that converse over stdin and stdout, such as
+//! sshd's guard, work as they do over `ssh`.
+
+use crate::simulate;
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::{ExitStatus, Output};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::runtime::Handle;
+use tokio::sync::oneshot::{self, error::TryRecvError};
+
+/// Bytes buffered between the caller and the fake device, each way
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Exit code of a fake `ssh` that was killed, as `ssh` exits when it loses
+/// its connection
+const KILLED: i32 = 255;
+
+/// Where a stream of a command goes, as [`std::process::Stdio`] says, which
+/// the fake device can't look into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stdio(Kind);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Piped,
+    Null,
+    Inherit,
+}
+
+impl Stdio {
+    pub fn piped() -> Self {
+        Self(Kind::Piped)
+    }
+
+    pub fn null() -> Self {
+        Self(Kind::Null)
+    }
+
+    pub fn inherit() -> Self {
+        Self(Kind::Inherit)
+    }
+}
+
+impl From<Stdio> for std::process::Stdio {
+    fn from(stdio: Stdio) -> Self {
+        match stdio.0 {
+            Kind::Piped => std::process::Stdio::piped(),
+            Kind::Null => std::process::Stdio::null(),
+            Kind::Inherit => std::process::Stdio::inherit(),
+        }
+    }
+}
+
+/// A command for the device, not yet run
+#[derive(Debug)]
+pub struct Command(Inner);
+
+#[derive(Debug)]
+enum Inner {
+    Process(tokio::process::Command),
+    Simulated(Simulated),
+}
+
+/// A command for the fake device
+#[derive(Debug)]
+struct Simulated {
+    /// The state file of the device
+    state: PathBuf,
+    args: Vec<String>,
+    stdin: Kind,
+    /// Unset, output goes where `output` and `spawn` send it by default
+    stdout: Option<Kind>,
+    stderr: Option<Kind>,
+}
+
+impl From<tokio::process::Command> for Command {
+    fn from(command: tokio::process::Command) -> Self {
+        Self(Inner::Process(command))
+    }
+}
+
+impl Command {
+    /// The fake device kept in `state`; stdin is null as for
+    /// [`crate::process::command`]
+    pub fn simulated(state: PathBuf) -> Self {
+        Self(Inner::Simulated(Simulated {
+            state,
+            args: Vec::new(),
+            stdin: Kind::Null,
+            stdout: None,
+            stderr: None,
+        }))
+    }
+
+    pub fn arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Self {
+        match &mut self.0 {
+            Inner::Process(command) => {
+                command.arg(arg);
+            }
+            Inner::Simulated(simulated) => simulated
+                .args
+                .push(arg.as_ref().to_string_lossy().into_owned()),
+        }
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    pub fn stdin(&mut self, stdio: Stdio) -> &mut Self {
+        match &mut self.0 {
+            Inner::Process(command) => {
+                command.stdin(stdio);
+            }
+            Inner::Simulated(simulated) => simulated.stdin = stdio.0,
+        }
+        self
+    }
+
+    pub fn stdout(&mut self, stdio: Stdio) -> &mut Self {
+        match &mut self.0 {
+            Inner::Process(command) => {
+                command.stdout(stdio);
+            }
+            Inner::Simulated(simulated) => simulated.stdout = Some(stdio.0),
+        }
+        self
+    }
+
+    pub fn stderr(&mut self, stdio: Stdio) -> &mut Self {
+        match &mut self.0 {
+            Inner::Process(command) => {
+                command.stderr(stdio);
+            }
+            Inner::Simulated(simulated) => simulated.stderr = Some(stdio.0),
+        }
+        self
+    }
+
+    /// Whether dropping the [`Child`] stops it; a fake device always stops
+    /// once its pipes close
+    pub fn kill_on_drop(&mut self, kill: bool) -> &mut Self {
+        if let Inner::Process(command) = &mut self.0 {
+            command.kill_on_drop(kill);
+        }
+        self
+    }
+
+    /// The arguments given so far
+    #[cfg(test)]
+    pub fn get_args(&self) -> Vec<String> {
+        match &self.0 {
+            Inner::Process(command) => command
+                .as_std()
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            Inner::Simulated(simulated) => simulated.args.clone(),
+        }
+    }
+
+    /// Runs the command to the end, collecting its output
+    pub async fn output(&mut self) -> io::Result<Output> {
+        match &mut self.0 {
+            Inner::Process(command) => command.output().await,
+            Inner::Simulated(simulated) => simulated.spawn(Kind::Piped)?.wait_with_output().await,
+        }
+    }
+
+    /// Runs the command to the end, its output going where it was sent
+    pub async fn status(&mut self) -> io::Result<ExitStatus> {
+        match &mut self.0 {
+            Inner::Process(command) => command.status().await,
+            Inner::Simulated(simulated) => simulated.spawn(Kind::Inherit)?.wait().await,
+        }
+    }
+
+    pub fn spawn(&mut self) -> io::Result<Child> {
+        match &mut self.0 {
+            Inner::Process(command) => {
+                let mut child = command.spawn()?;
+                Ok(Child {
+                    stdin: child.stdin.take().map(Pipe::Process),
+                    stdout: child.stdout.take().map(Pipe::Process),
+                    stderr: child.stderr.take().map(Pipe::Process),
+                    inner: ChildInner::Process(child),
+                })
+            }
+            Inner::Simulated(simulated) => simulated.spawn(Kind::Inherit),
+        }
+    }
+}
+
+impl Simulated {
+    /// Starts the fake device, with output unset going to `output`
+    fn spawn(&self, output: Kind) -> io::Result<Child> {
+        let runtime = Handle::current();
+        let (stdin, device_stdin): (_, Box<dyn Read + Send>) = match self.stdin {
+            Kind::Piped => {
+                let (ours, device) = tokio::io::duplex(PIPE_CAPACITY);
+                (Some(ours), Box::new(Blocking::new(&runtime, device)))
+            }
+            Kind::Null => (None, Box::new(io::empty())),
+            Kind::Inherit => (None, Box::new(io::stdin())),
+        };
+        let (stdout, mut device_stdout) = writer(
+            &runtime,
+            self.stdout.unwrap_or(output),
+            Box::new(io::stdout()),
+        );
+        let (stderr, mut device_stderr) = writer(
+            &runtime,
+            self.stderr.unwrap_or(output),
+            Box::new(io::stderr()),
+        );
+
+        let (stop, stopped) = oneshot::channel();
+        let (exit, exited) = oneshot::channel();
+        if simulate::holds_open(&self.args) {
+            // Forwards are held open until the tool stops them
+            tokio::spawn(async move {
+                let _ = stopped.await;
+                let _ = exit.send(KILLED);
+            });
+        } else {
+            let (state, args) = (self.state.clone(), self.args.clone());
+            let mut device_stdin = device_stdin;
+            tokio::task::spawn_blocking(move || {
+                let code = simulate::respond(
+                    &state,
+                    &args,
+                    &mut *device_stdin,
+                    &mut *device_stdout,
+                    &mut *device_stderr,
+                );
+                let _ = device_stdout.flush();
+                let _ = device_stderr.flush();
+                let _ = exit.send(code);
+            });
+        }
+        Ok(Child {
+            stdin: stdin.map(Pipe::Simulated),
+            stdout: stdout.map(Pipe::Simulated),
+            stderr: stderr.map(Pipe::Simulated),
+            inner: ChildInner::Simulated {
+                exited: Some(exited),
+                stop: Some(stop),
+                status: None,
+            },
+        })
+    }
+}
+
+/// The caller's end, if piped, and the fake device's end of an output stream
+/// going `kind`, where inheriting writes to `inherited`
+fn writer(
+    runtime: &Handle,
+    kind: Kind,
+    inherited: Box<dyn Write + Send>,
+) -> (Option<DuplexStream>, Box<dyn Write + Send>) {
+    match kind {
+        Kind::Piped => {
+            let (ours, device) = tokio::io::duplex(PIPE_CAPACITY);
+            (Some(ours), Box::new(Blocking::new(runtime, device)))
+        }
+        Kind::Null => (None, Box::new(io::sink())),
+        Kind::Inherit => (None, inherited),
+    }
+}
+
+/// The fake device's end of a pipe, used from its blocking thread
+struct Blocking {
+    runtime: Handle,
+    stream: DuplexStream,
+}
+
+impl Blocking {
+    fn new(runtime: &Handle, stream: DuplexStream) -> Self {
+        Self {
+            runtime: runtime.clone(),
+            stream,
+        }
+    }
+}
+
+impl Read for Blocking {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.runtime.block_on(self.stream.read(buf))
+    }
+}
+
+impl Write for Blocking {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.runtime.block_on(self.stream.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.runtime.block_on(self.stream.flush())
+    }
+}
+
+/// A running command
+#[derive(Debug)]
+pub struct Child {
+    pub stdin: Option<ChildStdin>,
+    pub stdout: Option<ChildStdout>,
+    pub stderr: Option<ChildStderr>,
+    inner: ChildInner,
+}
+
+#[derive(Debug)]
+enum ChildInner {
+    Process(tokio::process::Child),
+    Simulated {
+        /// The fake device's exit code, until it has been waited for
+        exited: Option<oneshot::Receiver<i32>>,
+        /// Ends a held-open forward
+        stop: Option<oneshot::Sender<()>>,
+        status: Option<ExitStatus>,
+    },
+}
+
+impl Child {
+    /// Waits for the command to end, closing its stdin first
+    pub async fn wait(&mut self) -> io::Result<ExitStatus> {
+        self.stdin.take();
+        match &mut self.inner {
+            ChildInner::Process(child) => child.wait().await,
+            ChildInner::Simulated { exited, status, .. } => {
+                if let Some(exited) = exited.take() {
+                    *status = Some(exit_status(exited.await.unwrap_or(KILLED)));
+                }
+                Ok(status.unwrap_or_else(|| exit_status(KILLED)))
+            }
+        }
+    }
+
+    /// Whether the command has ended, and how, without waiting
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        match &mut self.inner {
+            ChildInner::Process(child) => child.try_wait(),
+            ChildInner::Simulated { exited, status, .. } => {
+                let code = match exited.as_mut().map(|exited| exited.try_recv()) {
+                    Some(Ok(code)) => code,
+                    Some(Err(TryRecvError::Closed)) => KILLED,
+                    Some(Err(TryRecvError::Empty)) | None => return Ok(*status),
+                };
+                exited.take();
+                *status = Some(exit_status(code));
+                Ok(*status)
+            }
+        }
+    }
+
+    /// Stops the command without waiting for it to end
+    pub fn start_kill(&mut self) -> io::Result<()> {
+        match &mut self.inner {
+            ChildInner::Process(child) => child.start_kill(),
+            ChildInner::Simulated { stop, .. } => {
+                // The fake device ends once its pipes close
+                self.stdin.take();
+                self.stdout.take();
+                self.stderr.take();
+                if let Some(stop) = stop.take() {
+                    let _ = stop.send(());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Stops the command and waits for it to end
+    pub async fn kill(&mut self) -> io::Result<()> {
+        self.start_kill()?;
+        self.wait().await.map(|_| ())
+    }
+
+    /// Waits for the command to end, collecting the output that was piped
+    pub async fn wait_with_output(mut self) -> io::Result<Output> {
+        async fn read_to_end(pipe: Option<Pipe<impl AsyncRead + Unpin>>) -> io::Result<Vec<u8>> {
+            let mut buffer = Vec::new();
+            if let Some(mut pipe) = pipe {
+                pipe.read_to_end(&mut buffer).await?;
+            }
+            Ok(buffer)
+        }
+        self.stdin.take();
+        let (stdout, stderr) = (self.stdout.take(), self.stderr.take());
+        let (stdout, stderr) = tokio::try_join!(read_to_end(stdout), read_to_end(stderr))?;
+        let status = self.wait().await?;
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+/// One of the streams of a [`Child`]: a pipe to the process, or to the fake
+/// device
+#[derive(Debug)]
+pub enum Pipe<T> {
+    Process(T),
+    Simulated(DuplexStream),
+}
+
+pub type ChildStdin = Pipe<tokio::process::ChildStdin>;
+pub type ChildStdout = Pipe<tokio::process::ChildStdout>;
+pub type ChildStderr = Pipe<tokio::process::ChildStderr>;
+
+impl<T: AsyncRead + Unpin> AsyncRead for Pipe<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Pipe::Process(pipe) => Pin::new(pipe).poll_read(cx, buf),
+            Pipe::Simulated(pipe) => Pin::new(pipe).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Pipe<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Pipe::Process(pipe) => Pin::new(pipe).poll_write(cx, buf),
+            Pipe::Simulated(pipe) => Pin::new(pipe).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Pipe::Process(pipe) => Pin::new(pipe).poll_flush(cx),
+            Pipe::Simulated(pipe) => Pin::new(pipe).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Pipe::Process(pipe) => Pin::new(pipe).poll_shutdown(cx),
+            Pipe::Simulated(pipe) => Pin::new(pipe).poll_shutdown(cx),
+        }
+    }
+}
+
+/// The status of a program that exited with `code`
+fn exit_status(code: i32) -> ExitStatus {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        ExitStatus::from_raw((code & 0xff) << 8)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::ExitStatusExt;
+        ExitStatus::from_raw(code as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ping::ECHO_SCRIPT;
+    use crate::shell::RemoteCommand;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    fn device(args: &[&str]) -> Command {
+        let state = std::env::temp_dir().join(format!(
+            "ssh_ip_tunnel-executor-{}.json",
+            std::process::id()
+        ));
+        let mut command = Command::simulated(state);
+        command.args(args);
+        command
+    }
+
+    #[tokio::test]
+    async fn test_fake_device_converses_over_pipes() {
+        let echo = RemoteCommand::new("sh")
+            .arg("-c")
+            .arg(ECHO_SCRIPT)
+            .to_shell_string();
+        let mut child = device(&["-l", "pi", "--", "localhost", &echo])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "ready");
+        stdin.write_all(b"ping 1\n").await.unwrap();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "ping 1");
+        drop(stdin);
+        assert!(child.wait().await.unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn test_fake_device_exits_like_ssh() {
+        let tunnel = device(&["-fN", "-L", "2222:localhost:22"]).output().await;
+        assert!(tunnel.unwrap().status.success());
+
+        let shell = device(&["-t", "-l", "pi"]).output().await.unwrap();
+        assert_eq!(shell.status.code(), Some(255));
+        assert!(String::from_utf8_lossy(&shell.stderr).contains("not simulated"));
+
+        let mut forward = device(&["-N", "-R", "8080:localhost:80"]).spawn().unwrap();
+        assert!(forward.try_wait().unwrap().is_none());
+        forward.kill().await.unwrap();
+        assert_eq!(forward.try_wait().unwrap().unwrap().code(), Some(KILLED));
+    }
+}
@@ -0,0 +1,121 @@
+//! Exit codes of the command line, one per kind of failure, so scripts can
+//! branch on what went wrong. They are stable: a code, once documented, keeps
+//! its meaning.
+
+use crate::phase::{Phase, PhaseError};
+use crate::TunnelError;
+
+/// Any failure without a code of its own
+pub const FAILURE: u8 = 1;
+/// The tunnel could not be opened, or the device's host key was refused
+pub const TUNNEL: u8 = 10;
+/// The device didn't answer through the tunnel, or runs an unexpected OS
+pub const VALIDATION: u8 = 11;
+/// The key could not be read, installed or logged in with
+pub const KEY_TRANSFER: u8 = 12;
+/// The device's architecture is not accepted, or could not be told
+pub const ARCHITECTURE: u8 = 13;
+/// The tunnel was not ready in time
+pub const TIMEOUT: u8 = 14;
+
+/// Every code the command line exits with, and what it means, for the manual
+pub const DESCRIBED: &[(u8, &str)] = &[
+    (0, "Success"),
+    (FAILURE, "Any other failure, including group runs where some hosts failed"),
+    (2, "Invalid command-line arguments"),
+    (TUNNEL, "The tunnel could not be opened: ssh failed, the host key was refused or no identity could log in"),
+    (VALIDATION, "Validation failed: the device didn't answer through the tunnel, or runs an unexpected OS"),
+    (KEY_TRANSFER, "The key could not be read, transferred, or logged in with afterwards"),
+    (ARCHITECTURE, "Wrong architecture, or it could not be detected"),
+    (TIMEOUT, "Timeout waiting for the tunnel to answer"),
+];
+
+/// The exit code for `error`: by the phase it interrupted, or else by the
+/// kind of [`TunnelError`] behind it. A timeout is one whatever the phase.
+pub fn code(error: &anyhow::Error) -> u8 {
+    let phase_error = error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<PhaseError>());
+    let tunnel_error = phase_error.map(|e| &e.source).or_else(|| {
+        error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<TunnelError>())
+    });
+    let by_phase = phase_error.map_or(FAILURE, |e| of_phase(e.phase));
+    match tunnel_error.map(of_error) {
+        Some(TIMEOUT) => TIMEOUT,
+        _ if by_phase != FAILURE => by_phase,
+        Some(code) => code,
+        None => FAILURE,
+    }
+}
+
+fn of_error(error: &TunnelError) -> u8 {
+    match error {
+        TunnelError::TunnelTimeout => TIMEOUT,
+        TunnelError::TunnelCreation(_) | TunnelError::HostKey(_) => TUNNEL,
+        TunnelError::ConnectionValidation(_) | TunnelError::UnexpectedOs(_) => VALIDATION,
+        TunnelError::KeyTransfer(_)
+        | TunnelError::InvalidKeyPath(_)
+        | TunnelError::PrivateKeyRefused(..)
+        | TunnelError::InvalidPublicKey(_)
+        | TunnelError::InvalidKeyOption(_)
+        | TunnelError::SshPermissions(_)
+        | TunnelError::KeyVerification(_)
+        | TunnelError::NoIdentity(_)
+        | TunnelError::SecurityKey(_) => KEY_TRANSFER,
+        TunnelError::NonArmCpu(_)
+        | TunnelError::WrongArchitecture(_)
+        | TunnelError::ArchitectureDetection(_) => ARCHITECTURE,
+        _ => FAILURE,
+    }
+}
+
+fn of_phase(phase: Phase) -> u8 {
+    match phase {
+        Phase::Tunnel => TUNNEL,
+        Phase::Validate => VALIDATION,
+        Phase::Arch => ARCHITECTURE,
+        Phase::Key => KEY_TRANSFER,
+        _ => FAILURE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes_by_phase_then_error() {
+        let at = |phase, error| anyhow::Error::new(PhaseError::at(phase)(error));
+        let injected = || TunnelError::FaultInjected("fail_key".to_string());
+        assert_eq!(
+            code(&at(
+                Phase::Arch,
+                TunnelError::WrongArchitecture("x86_64".to_string())
+            )),
+            ARCHITECTURE
+        );
+        assert_eq!(code(&at(Phase::Key, injected())), KEY_TRANSFER);
+        assert_eq!(code(&at(Phase::Harden, injected())), FAILURE);
+        // Logging in is part of opening the tunnel, whatever the cause
+        assert_eq!(
+            code(&at(
+                Phase::Tunnel,
+                TunnelError::NoIdentity("none".to_string())
+            )),
+            TUNNEL
+        );
+        assert_eq!(
+            code(&anyhow::Error::new(TunnelError::InvalidPublicKey(
+                "x".to_string()
+            ))),
+            KEY_TRANSFER
+        );
+        assert_eq!(
+            code(&anyhow::Error::new(TunnelError::TunnelTimeout).context("waiting")),
+            TIMEOUT
+        );
+        assert_eq!(code(&anyhow::anyhow!("2 hosts failed")), FAILURE);
+    }
+}
@@ -0,0 +1,94 @@
+//! Facts about a device, for templates in device profiles.
+//!
+//! One round trip collects the architecture, board model, memory and OS. Facts
+//! are cached per device under the cache directory for [`FACTS_TTL`], since
+//! they rarely change and a fleet run would otherwise ask every board again;
+//! `--refresh-facts` gathers them anew.
+
+use crate::paths;
+use crate::shell::RemoteCommand;
+use crate::ssh;
+use crate::{Target, TunnelError};
+use chrono::Utc;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{debug, info};
+
+pub use crate::pure::facts::Facts;
+
+/// How long cached facts are used before they are gathered again
+const FACTS_TTL: chrono::Duration = chrono::Duration::hours(1);
+
+/// Upper bound for gathering
+const GATHER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Prints one `name=value` line per fact
+pub const FACTS_SCRIPT: &str = r#"echo "arch=$(uname -m)"
+echo "kernel=$(uname -r)"
+echo "hostname=$(hostname)"
+model=
+if [ -r /proc/device-tree/model ]; then
+  model=$(tr -d '\000' < /proc/device-tree/model)
+elif [ -r /sys/class/dmi/id/product_name ]; then
+  model=$(cat /sys/class/dmi/id/product_name)
+fi
+echo "model=$model"
+echo "mem_mb=$(( $(awk '/^MemTotal:/ { print $2 }' /proc/meminfo) / 1024 ))"
+echo "cpus=$(grep -c '^processor' /proc/cpuinfo)"
+if [ -r /etc/os-release ]; then
+  (. /etc/os-release; echo "os=$ID"; echo "os_version=$VERSION_ID")
+fi"#;
+
+/// Where `target`'s facts are cached
+fn cache_path(target: &Target) -> PathBuf {
+    paths::cache_dir().join("facts").join(format!(
+        "{}_{}.json",
+        target.host.replace([':', '/', '\\'], "_"),
+        target.remote_port.unwrap_or(22)
+    ))
+}
+
+/// `target`'s facts, from the cache when they are recent enough unless `refresh`
+pub async fn gather(target: &Target, refresh: bool) -> Result<Facts, TunnelError> {
+    let path = cache_path(target);
+    // A simulated device's facts must not stand in for the real one's
+    let refresh = refresh || target.simulated.is_some();
+    if !refresh {
+        let cached = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Facts>(&contents).ok())
+            .filter(|facts| Utc::now() - facts.gathered_at < FACTS_TTL);
+        if let Some(facts) = cached {
+            debug!(
+                "Using facts about {} cached at {}",
+                target.host, facts.gathered_at
+            );
+            return Ok(facts);
+        }
+    }
+
+    info!("Gathering facts about {}...", target.host);
+    let command = RemoteCommand::new("sh").arg("-c").arg(FACTS_SCRIPT);
+    let stdout = ssh::run_through_tunnel(target, &command, GATHER_TIMEOUT)
+        .await
+        .map_err(|e| e.into_error(TunnelError::Facts))?;
+    let facts = Facts::parse(&stdout, Utc::now());
+
+    // A cache that can't be written only costs the next run a round trip
+    if target.simulated.is_some() {
+        return Ok(facts);
+    }
+    let saved = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| {
+            std::fs::write(
+                &path,
+                serde_json::to_string_pretty(&facts).unwrap_or_default(),
+            )
+        });
+    if let Err(e) = saved {
+        debug!("Could not cache facts in {}: {}", path.display(), e);
+    }
+    Ok(facts)
+}
@@ -0,0 +1,132 @@
+//! Fault injection for testing how scripts around the tool handle failures.
+//!
+//! `SSH_IP_TUNNEL_FAULT` (or the hidden `--inject-fault`) lists points at
+//! which a run fails on purpose, separated by commas:
+//! - `fail_<phase>` fails the phase before it does anything
+//! - `drop_after_<phase>` loses the connection once the phase has finished,
+//!   so whatever reaches the device next fails as if the network went away
+//!
+//! Faults apply to every target of a run; nothing is injected unless asked.
+
+use crate::phase::Phase;
+use crate::{Target, TunnelError};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use tracing::warn;
+
+/// A point at which a run fails on purpose
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Fail(Phase),
+    DropAfter(Phase),
+}
+
+impl FromStr for Fault {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(phase) = s.strip_prefix("drop_after_") {
+            return Ok(Fault::DropAfter(phase.parse()?));
+        }
+        if let Some(phase) = s.strip_prefix("fail_") {
+            return Ok(Fault::Fail(phase.parse()?));
+        }
+        Err(format!(
+            "unknown fault '{}', expected fail_<phase> or drop_after_<phase>",
+            s
+        ))
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Fail(phase) => write!(f, "fail_{}", phase),
+            Fault::DropAfter(phase) => write!(f, "drop_after_{}", phase),
+        }
+    }
+}
+
+/// Parses a comma-separated list of faults
+pub fn parse(spec: &str) -> Result<Vec<Fault>, String> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|point| !point.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+static FAULTS: OnceLock<Vec<Fault>> = OnceLock::new();
+
+/// Targets whose connection an injected fault dropped, by host and local port
+static DROPPED: Mutex<BTreeSet<(String, u16)>> = Mutex::new(BTreeSet::new());
+
+/// Injects `faults` for the rest of the process
+pub fn install(faults: Vec<Fault>) {
+    let _ = FAULTS.set(faults);
+}
+
+fn injected() -> &'static [Fault] {
+    FAULTS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Fails if a `fail_<phase>` fault was asked for; call before the phase starts
+pub fn before(phase: Phase) -> Result<(), TunnelError> {
+    if injected().contains(&Fault::Fail(phase)) {
+        return Err(TunnelError::FaultInjected(Fault::Fail(phase).to_string()));
+    }
+    Ok(())
+}
+
+/// Drops `target`'s connection if a `drop_after_<phase>` fault was asked for;
+/// call once the phase has finished
+pub fn after(phase: Phase, target: &Target) {
+    if injected().contains(&Fault::DropAfter(phase)) {
+        warn!("Injected fault: dropping the connection to {}", target.host);
+        if let Ok(mut dropped) = DROPPED.lock() {
+            dropped.insert((target.host.clone(), target.port));
+        }
+    }
+}
+
+/// Forgets that a fault dropped `target`'s connection, as its tunnel is being
+/// closed and the next one is a new connection
+pub fn closed(target: &Target) {
+    if let Ok(mut dropped) = DROPPED.lock() {
+        dropped.remove(&(target.host.clone(), target.port));
+    }
+}
+
+/// Fails if an injected fault dropped `target`'s connection; every `ssh` the
+/// tool starts for the target checks this first
+pub fn check_connection(target: &Target) -> Result<(), TunnelError> {
+    let dropped = DROPPED
+        .lock()
+        .map(|dropped| dropped.contains(&(target.host.clone(), target.port)))
+        .unwrap_or(false);
+    if dropped {
+        return Err(TunnelError::FaultInjected(format!(
+            "connection to {} dropped",
+            target.host
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_faults() {
+        assert_eq!(
+            parse("drop_after_validate, fail_key,").unwrap(),
+            vec![Fault::DropAfter(Phase::Validate), Fault::Fail(Phase::Key)]
+        );
+        assert_eq!(Fault::DropAfter(Phase::Swap).to_string(), "drop_after_swap");
+        assert!(parse("fail_keys").unwrap_err().contains("unknown phase"));
+        assert!(parse("explode").unwrap_err().contains("unknown fault"));
+    }
+}
@@ -0,0 +1,198 @@
+//! Downloading files over HTTP(S).
+
+use crate::TunnelError;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::info;
+
+/// Give up on a server that stops sending data for this long
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest text document (e.g. a checksum file) accepted by [`fetch_text`]
+const MAX_TEXT_LEN: u64 = 64 * 1024;
+
+/// Downloads `url` to `dest`, returning `false` if the server has no such file.
+///
+/// The body is written to `<dest>.part` and renamed into place once complete,
+/// so an interrupted download never leaves a truncated file at `dest`. A
+/// `.part` file left by an earlier attempt is resumed with a range request.
+pub async fn download(url: &str, dest: &Path) -> Result<bool, TunnelError> {
+    let url = url.to_string();
+    let dest = dest.to_path_buf();
+    tokio::task::spawn_blocking(move || download_blocking(&url, &dest))
+        .await
+        .map_err(|e| TunnelError::Download(format!("download task failed: {}", e)))?
+}
+
+/// Fetches a small text document, returning `None` if the server has no such file
+pub async fn fetch_text(url: &str) -> Result<Option<String>, TunnelError> {
+    let url = url.to_string();
+    tokio::task::spawn_blocking(move || {
+        let failed = |message: String| TunnelError::Download(format!("{}: {}", url, message));
+        let response = match agent().get(&url).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(e) => return Err(failed(e.to_string())),
+        };
+        let mut text = String::new();
+        response
+            .into_reader()
+            .take(MAX_TEXT_LEN)
+            .read_to_string(&mut text)
+            .map_err(|e| failed(e.to_string()))?;
+        Ok(Some(text))
+    })
+    .await
+    .map_err(|e| TunnelError::Download(format!("download task failed: {}", e)))?
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(10))
+        .timeout_read(READ_TIMEOUT)
+        .build()
+}
+
+fn partial_path(dest: &Path) -> PathBuf {
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(".part");
+    PathBuf::from(partial)
+}
+
+fn download_blocking(url: &str, dest: &Path) -> Result<bool, TunnelError> {
+    let failed = |message: String| TunnelError::Download(format!("{}: {}", url, message));
+
+    if let Some(dir) = dest.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| failed(e.to_string()))?;
+    }
+    let partial = partial_path(dest);
+    let offset = std::fs::metadata(&partial).map(|m| m.len()).unwrap_or(0);
+
+    let mut request = agent().get(url);
+    if offset > 0 {
+        info!("Resuming download of {} at {} bytes...", url, offset);
+        request = request.set("Range", &format!("bytes={}-", offset));
+    } else {
+        info!("Downloading {}...", url);
+    }
+
+    let response = match request.call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(404, _)) => return Ok(false),
+        // The partial file is no prefix of the current file; start over
+        Err(ureq::Error::Status(416, _)) => {
+            std::fs::remove_file(&partial).map_err(|e| failed(e.to_string()))?;
+            return download_blocking(url, dest);
+        }
+        Err(ureq::Error::Status(code, response)) => {
+            return Err(failed(format!("HTTP {} {}", code, response.status_text())))
+        }
+        Err(e) => return Err(failed(e.to_string())),
+    };
+
+    // A server that ignores the range sends the whole file again
+    let resumed = response.status() == 206;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(&partial)
+        .map_err(|e| failed(e.to_string()))?;
+    std::io::copy(&mut response.into_reader(), &mut file).map_err(|e| failed(e.to_string()))?;
+    std::fs::rename(&partial, dest).map_err(|e| failed(e.to_string()))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Answers one request per response in turn, returning the URL to ask and
+    /// the `Range` header of each request
+    fn serve(
+        responses: Vec<(&'static str, &'static str)>,
+    ) -> (String, std::thread::JoinHandle<Vec<Option<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/image.img", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut ranges = Vec::new();
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("range:") {
+                        range = Some(value.trim().to_string());
+                    }
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                }
+                ranges.push(range);
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                reader.into_inner().write_all(response.as_bytes()).unwrap();
+            }
+            ranges
+        });
+        (url, server)
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "ssh_ip_tunnel-fetch-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_download_renames_the_file_once_complete() {
+        let dir = scratch("complete");
+        let dest = dir.join("image.img");
+        let (url, server) = serve(vec![("200 OK", "image"), ("404 Not Found", "")]);
+
+        assert!(download(&url, &dest).await.unwrap());
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "image");
+        assert!(!partial_path(&dest).exists());
+
+        let missing = dir.join("missing.img");
+        assert!(!download(&url, &missing).await.unwrap());
+        assert!(!missing.exists());
+        assert_eq!(server.join().unwrap(), vec![None, None]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_download_resumes_a_partial_file() {
+        let dir = scratch("resume");
+        let dest = dir.join("image.img");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(partial_path(&dest), "ima").unwrap();
+        // The second server ignores the range and sends the whole file
+        let (url, server) = serve(vec![("206 Partial Content", "ge"), ("200 OK", "image")]);
+
+        assert!(download(&url, &dest).await.unwrap());
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "image");
+
+        std::fs::write(partial_path(&dest), "ima").unwrap();
+        assert!(download(&url, &dest).await.unwrap());
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "image");
+        let range = Some("bytes=3-".to_string());
+        assert_eq!(server.join().unwrap(), vec![range.clone(), range]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+//! C bindings for opening tunnels and transferring keys, with the `ffi` feature.
+//!
+//! A [`SshIpTunnel`] handle holds one target and a runtime to drive it; every
+//! call blocks until its step is done. Functions return an [`ErrorCode`], and
+//! [`ssh_ip_tunnel_last_error`] describes the last failure on the calling
+//! thread. `include/ssh_ip_tunnel.h` declares the same interface for C and
+//! C++; build the shared library with
+//! `cargo rustc --release --lib --features ffi --crate-type cdylib`.
+
+use crate::phase::PhaseError;
+use crate::{load_config, Config, SSHTunnelManager, Target, TunnelError};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+
+/// What a call returned, by kind of failure
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Ok = 0,
+    /// A null handle or argument, text that isn't UTF-8, or an invalid host or user
+    InvalidArgument = 1,
+    /// Loading the configuration file failed
+    Config = 2,
+    /// The tunnel could not be opened
+    Tunnel = 3,
+    /// The device did not answer through the tunnel
+    Connection = 4,
+    /// The device's host key is unknown or changed
+    HostKey = 5,
+    /// The device is not an ARM board, or its architecture could not be told
+    Architecture = 6,
+    /// The key could not be read or installed, or there is none to log in with
+    Key = 7,
+    Other = 99,
+}
+
+impl ErrorCode {
+    fn of(error: &anyhow::Error) -> Self {
+        let tunnel_error = error
+            .downcast_ref::<PhaseError>()
+            .map(|e| &e.source)
+            .or_else(|| error.downcast_ref::<TunnelError>());
+        match tunnel_error {
+            Some(TunnelError::InvalidUsername(_) | TunnelError::InvalidHost(_)) => {
+                ErrorCode::InvalidArgument
+            }
+            Some(TunnelError::TunnelCreation(_) | TunnelError::TunnelTimeout) => ErrorCode::Tunnel,
+            Some(TunnelError::ConnectionValidation(_) | TunnelError::ConnectionLost(_)) => {
+                ErrorCode::Connection
+            }
+            Some(TunnelError::HostKey(_)) => ErrorCode::HostKey,
+            Some(
+                TunnelError::NonArmCpu(_)
+                | TunnelError::WrongArchitecture(_)
+                | TunnelError::ArchitectureDetection(_),
+            ) => ErrorCode::Architecture,
+            Some(
+                TunnelError::KeyTransfer(_)
+                | TunnelError::InvalidKeyPath(_)
+                | TunnelError::PrivateKeyRefused(..)
+                | TunnelError::InvalidPublicKey(_)
+                | TunnelError::InvalidKeyOption(_)
+                | TunnelError::SshPermissions(_)
+                | TunnelError::KeyVerification(_)
+                | TunnelError::NoIdentity(_)
+                | TunnelError::SecurityKey(_),
+            ) => ErrorCode::Key,
+            _ => ErrorCode::Other,
+        }
+    }
+}
+
+/// One device, and the runtime its calls run on
+pub struct SshIpTunnel {
+    runtime: tokio::runtime::Runtime,
+    manager: SSHTunnelManager,
+    target: Target,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Runs `call`, turning errors and panics into an [`ErrorCode`] and the last error
+fn guard(call: impl FnOnce() -> Result<(), (ErrorCode, String)>) -> c_int {
+    let code = match catch_unwind(AssertUnwindSafe(call)) {
+        Ok(Ok(())) => ErrorCode::Ok,
+        Ok(Err((code, message))) => {
+            set_last_error(message);
+            code
+        }
+        Err(_) => {
+            set_last_error("internal error (panic)".to_string());
+            ErrorCode::Other
+        }
+    };
+    code as c_int
+}
+
+fn failed(error: anyhow::Error) -> (ErrorCode, String) {
+    (ErrorCode::of(&error), error.to_string())
+}
+
+/// Reads a C string argument, which must not be null
+unsafe fn text<'a>(value: *const c_char, name: &str) -> Result<&'a str, (ErrorCode, String)> {
+    if value.is_null() {
+        return Err((ErrorCode::InvalidArgument, format!("{} is null", name)));
+    }
+    CStr::from_ptr(value)
+        .to_str()
+        .map_err(|_| (ErrorCode::InvalidArgument, format!("{} is not UTF-8", name)))
+}
+
+/// Resolves a handle argument, which must not be null
+unsafe fn handle<'a>(handle: *mut SshIpTunnel) -> Result<&'a mut SshIpTunnel, (ErrorCode, String)> {
+    handle
+        .as_mut()
+        .ok_or_else(|| (ErrorCode::InvalidArgument, "handle is null".to_string()))
+}
+
+/// Creates a handle for `user@host` with the defaults from the configuration
+/// file at `config_path`, or the usual one when it is null. Stores the handle
+/// in `*out`, to be released with [`ssh_ip_tunnel_free`].
+///
+/// # Safety
+///
+/// `host`, `user` and a non-null `config_path` must be NUL-terminated
+/// strings, and `out` must point to writable memory for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn ssh_ip_tunnel_new(
+    host: *const c_char,
+    user: *const c_char,
+    config_path: *const c_char,
+    out: *mut *mut SshIpTunnel,
+) -> c_int {
+    guard(|| {
+        if out.is_null() {
+            return Err((ErrorCode::InvalidArgument, "out is null".to_string()));
+        }
+        *out = ptr::null_mut();
+        let host = text(host, "host")?;
+        let user = text(user, "user")?;
+        let config_path = if config_path.is_null() {
+            None
+        } else {
+            Some(PathBuf::from(text(config_path, "config_path")?))
+        };
+        let config: Config =
+            load_config(config_path).map_err(|e| (ErrorCode::Config, format!("{:#}", e)))?;
+        let target = Target::builder(host, user, &config)
+            .build()
+            .map_err(|e| failed(e.into()))?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| (ErrorCode::Other, format!("cannot start a runtime: {}", e)))?;
+        *out = Box::into_raw(Box::new(SshIpTunnel {
+            runtime,
+            manager: SSHTunnelManager::new(config),
+            target,
+        }));
+        Ok(())
+    })
+}
+
+/// Sets the public key [`ssh_ip_tunnel_transfer_key`] installs.
+///
+/// # Safety
+///
+/// `tunnel` must come from [`ssh_ip_tunnel_new`] and `key_path` must be a
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ssh_ip_tunnel_set_key(
+    tunnel: *mut SshIpTunnel,
+    key_path: *const c_char,
+) -> c_int {
+    guard(|| {
+        let tunnel = handle(tunnel)?;
+        tunnel.target.key_path = text(key_path, "key_path")?.to_string();
+        Ok(())
+    })
+}
+
+/// Sets the local port of the tunnel.
+///
+/// # Safety
+///
+/// `tunnel` must come from [`ssh_ip_tunnel_new`].
+#[no_mangle]
+pub unsafe extern "C" fn ssh_ip_tunnel_set_port(tunnel: *mut SshIpTunnel, port: u16) -> c_int {
+    guard(|| {
+        handle(tunnel)?.target.port = port;
+        Ok(())
+    })
+}
+
+/// Opens the tunnel and checks that the device answers through it.
+///
+/// # Safety
+///
+/// `tunnel` must come from [`ssh_ip_tunnel_new`].
+#[no_mangle]
+pub unsafe extern "C" fn ssh_ip_tunnel_create(tunnel: *mut SshIpTunnel) -> c_int {
+    guard(|| {
+        let tunnel = handle(tunnel)?;
+        tunnel
+            .runtime
+            .block_on(tunnel.manager.connect(&tunnel.target))
+            .map(|_| ())
+            .map_err(|e| failed(e.into()))
+    })
+}
+
+/// Installs the key in the device's authorized_keys through an open tunnel,
+/// after checking its architecture unless the configuration skips that.
+///
+/// # Safety
+///
+/// `tunnel` must come from [`ssh_ip_tunnel_new`].
+#[no_mangle]
+pub unsafe extern "C" fn ssh_ip_tunnel_transfer_key(tunnel: *mut SshIpTunnel) -> c_int {
+    guard(|| {
+        let tunnel = handle(tunnel)?;
+        let (manager, target) = (&tunnel.manager, &tunnel.target);
+        tunnel
+            .runtime
+            .block_on(async {
+                manager.validate_architecture(target).await?;
+                manager.transfer_key(target).await
+            })
+            .map(|_| ())
+            .map_err(|e| failed(e.into()))
+    })
+}
+
+/// Closes the tunnel; closing one that isn't open is not an error.
+///
+/// # Safety
+///
+/// `tunnel` must come from [`ssh_ip_tunnel_new`].
+#[no_mangle]
+pub unsafe extern "C" fn ssh_ip_tunnel_close(tunnel: *mut SshIpTunnel) -> c_int {
+    guard(|| {
+        let tunnel = handle(tunnel)?;
+        tunnel
+            .runtime
+            .block_on(tunnel.manager.close_tunnel(&tunnel.target))
+            .map(|_| ())
+            .map_err(|e| failed(e.into()))
+    })
+}
+
+/// Releases a handle; the tunnel stays open unless closed first.
+///
+/// # Safety
+///
+/// `tunnel` must come from [`ssh_ip_tunnel_new`] and not be used afterwards;
+/// null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn ssh_ip_tunnel_free(tunnel: *mut SshIpTunnel) {
+    if !tunnel.is_null() {
+        drop(Box::from_raw(tunnel));
+    }
+}
+
+/// Describes the last failure on this thread, or returns null if there was
+/// none. The text stays valid until the next failing call on the thread.
+#[no_mangle]
+pub extern "C" fn ssh_ip_tunnel_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_arguments_set_last_error() {
+        let mut tunnel = ptr::null_mut();
+        let code = unsafe {
+            ssh_ip_tunnel_new(
+                c"10.0.0.5".as_ptr(),
+                c"bad user".as_ptr(),
+                ptr::null(),
+                &mut tunnel,
+            )
+        };
+        assert_eq!(code, ErrorCode::InvalidArgument as c_int);
+        assert!(tunnel.is_null());
+        let message = unsafe { CStr::from_ptr(ssh_ip_tunnel_last_error()) };
+        assert!(message.to_str().unwrap().contains("bad user"));
+
+        let code = unsafe { ssh_ip_tunnel_create(ptr::null_mut()) };
+        assert_eq!(code, ErrorCode::InvalidArgument as c_int);
+    }
+}
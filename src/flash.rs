@@ -0,0 +1,358 @@
+//! Writing OS images to a board's secondary storage.
+//!
+//! `flash` streams an image through the tunnel straight into `dd` on the
+//! board, so nothing is staged on the board's own storage, then reads the
+//! device back and compares checksums. Overwriting a disk is not undoable:
+//! the target must be a whole, unmounted block device that is not the one the
+//! board booted from, and the user has to type its name to confirm.
+
+use crate::checksum::{self, SHA256_FN};
+use crate::config::Config;
+use crate::executor::Stdio;
+use crate::output::{self, Renderable};
+use crate::prompt;
+use crate::shell::{self, RemoteCommand};
+use crate::ssh;
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::info;
+
+/// Upper bound for inspecting the device before writing
+const INSPECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Upper bound for reading the image back; SD cards and eMMC read at 20-100 MB/s
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// How often progress is logged while writing
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What the board reports about the device to be overwritten
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlockDevice {
+    pub path: String,
+    pub size: u64,
+    pub model: String,
+}
+
+impl BlockDevice {
+    /// Parses the inspection script's output: `<size> <model...>`
+    fn parse(path: &str, output: &str) -> Option<Self> {
+        let (size, model) = output.trim().split_once(' ').unwrap_or((output.trim(), ""));
+        Some(Self {
+            path: path.to_string(),
+            size: size.parse().ok()?,
+            model: model.trim().to_string(),
+        })
+    }
+}
+
+/// Result of `flash`
+#[derive(Debug, Serialize)]
+pub struct FlashReport {
+    pub host: String,
+    pub image: PathBuf,
+    pub device: BlockDevice,
+    pub bytes: u64,
+    pub sha256: String,
+    /// Whether the device was read back and matched `sha256`
+    pub verified: bool,
+    pub elapsed_secs: f64,
+}
+
+impl Renderable for FlashReport {
+    fn to_human(&self) -> String {
+        format!(
+            "Flashed {} ({} bytes) to {}:{} in {}\nSHA-256 {}{}",
+            self.image.display(),
+            self.bytes,
+            self.host,
+            self.device.path,
+            output::format_duration(Duration::from_secs_f64(self.elapsed_secs)),
+            self.sha256,
+            if self.verified {
+                " (verified on the device)"
+            } else {
+                ""
+            }
+        )
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Writes `image` to the block device `device` on `target`.
+///
+/// Asks for confirmation unless `assume_yes`; reads the device back unless `skip_verify`.
+pub async fn flash(
+    config: &Config,
+    target: &Target,
+    image: &Path,
+    device: &str,
+    assume_yes: bool,
+    skip_verify: bool,
+) -> Result<FlashReport> {
+    let failed = |message: String| TunnelError::Flash(format!("{}: {}", device, message));
+
+    if !device.starts_with("/dev/") {
+        return Err(failed("expected a /dev/... path".to_string()).into());
+    }
+    let size = std::fs::metadata(image)
+        .map_err(|e| anyhow::anyhow!("Cannot read {}: {}", image.display(), e))?
+        .len();
+    if !assume_yes && !prompt::is_interactive() {
+        anyhow::bail!(
+            "flash overwrites {}; pass --yes to confirm without a terminal",
+            device
+        );
+    }
+
+    info!("Computing SHA-256 of {}...", image.display());
+    let sha256 = checksum::sha256_file(image)
+        .map_err(|e| anyhow::anyhow!("Cannot read {}: {}", image.display(), e))?;
+
+    SSHTunnelManager::new(config.clone())
+        .connect(target)
+        .await?;
+
+    let block = inspect(target, device).await?;
+    if block.size < size {
+        return Err(failed(format!(
+            "the image ({} bytes) is larger than the device ({} bytes)",
+            size, block.size
+        ))
+        .into());
+    }
+
+    if !assume_yes {
+        let name = device.trim_start_matches("/dev/");
+        let question = format!(
+            "This erases {} on {} ({}, {} bytes). Type {} to continue",
+            device, target.host, block.model, block.size, name
+        );
+        let answer = prompt::ask(&question, "")?;
+        if answer != name {
+            anyhow::bail!("Not confirmed; {} was left untouched", device);
+        }
+    }
+
+    let started = Instant::now();
+    write_image(target, image, size, device).await?;
+
+    let verified = if skip_verify {
+        false
+    } else {
+        info!("Reading {} back to verify...", device);
+        let digest = device_sha256(target, device, size).await?;
+        if digest != sha256 {
+            return Err(TunnelError::ChecksumMismatch(format!(
+                "{} on {} has SHA-256 {} after flashing, expected {}",
+                device, target.host, digest, sha256
+            ))
+            .into());
+        }
+        true
+    };
+
+    Ok(FlashReport {
+        host: target.host.clone(),
+        image: image.to_path_buf(),
+        device: block,
+        bytes: size,
+        sha256,
+        verified,
+        elapsed_secs: started.elapsed().as_secs_f64(),
+    })
+}
+
+/// Checks that `device` may be overwritten and returns what the board knows about it
+async fn inspect(target: &Target, device: &str) -> Result<BlockDevice, TunnelError> {
+    let script = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(INSPECT_SCRIPT)
+        .arg("sh")
+        .arg(device);
+    let stdout = ssh::run_through_tunnel(target, &script, INSPECT_TIMEOUT)
+        .await
+        .map_err(|e| {
+            e.into_error(|message| TunnelError::Flash(format!("{}: {}", device, message)))
+        })?;
+    BlockDevice::parse(device, &stdout).ok_or_else(|| {
+        TunnelError::Flash(format!(
+            "{}: unexpected inspection output {:?}",
+            device, stdout
+        ))
+    })
+}
+
+/// Shell script that refuses anything but a whole disk with nothing mounted
+/// from it (which rules out the boot disk), then prints `<size in bytes> <model>`.
+///
+/// Mounts are matched by device number, since the root filesystem often shows
+/// up as `/dev/root` rather than under its real name.
+const INSPECT_SCRIPT: &str = r#"set -e
+dev=$1
+[ -b "$dev" ] || { echo "not a block device" >&2; exit 1; }
+name=${dev##*/}
+[ -e "/sys/block/$name" ] || { echo "not a whole disk (a partition?)" >&2; exit 1; }
+for number in "/sys/block/$name/dev" "/sys/block/$name/$name"*/dev; do
+  [ -e "$number" ] || continue
+  mount=$(awk -v dev="$(cat "$number")" '$3 == dev { print $5; exit }' /proc/self/mountinfo)
+  [ "$mount" != / ] || { echo "holds the root filesystem" >&2; exit 1; }
+  [ -z "$mount" ] || { echo "is mounted at $mount; unmount it first" >&2; exit 1; }
+done
+sectors=$(cat "/sys/block/$name/size")
+model=$(cat "/sys/block/$name/device/model" 2>/dev/null || cat "/sys/block/$name/device/name" 2>/dev/null || echo unknown)
+echo "$((sectors * 512)) $model"
+"#;
+
+/// Streams the image into `dd` on the board, logging progress
+async fn write_image(
+    target: &Target,
+    image: &Path,
+    size: u64,
+    device: &str,
+) -> Result<(), TunnelError> {
+    let failed = |message: String| TunnelError::Flash(format!("{}: {}", device, message));
+    let script = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(format!(
+            r#"{}
+as_root dd of="$1" bs=4M conv=fsync 2>/dev/null"#,
+            shell::AS_ROOT
+        ))
+        .arg("sh")
+        .arg(device);
+    let mut child = ssh::through_tunnel(target, &script)?
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| failed(e.to_string()))?;
+
+    info!("Writing {} to {}...", image.display(), device);
+    let mut file = tokio::fs::File::open(image)
+        .await
+        .map_err(|e| failed(e.to_string()))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut written = 0u64;
+    let started = Instant::now();
+    let mut last_report = Instant::now();
+    let copied: std::io::Result<()> = async {
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                return Ok(());
+            }
+            stdin.write_all(&buffer[..read]).await?;
+            written += read as u64;
+            if last_report.elapsed() >= PROGRESS_INTERVAL {
+                last_report = Instant::now();
+                info!("{}", progress(written, size, started.elapsed()));
+            }
+        }
+    }
+    .await;
+    drop(stdin);
+
+    info!("Waiting for {} to finish writing...", device);
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| failed(e.to_string()))?;
+    if !output.status.success() {
+        return Err(failed(format!(
+            "writing failed after {} bytes: {}",
+            written,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    copied.map_err(|e| failed(format!("writing failed after {} bytes: {}", written, e)))?;
+    if written != size {
+        return Err(failed(format!(
+            "{} changed size while flashing ({} of {} bytes written)",
+            image.display(),
+            written,
+            size
+        )));
+    }
+    Ok(())
+}
+
+/// Formats `written` of `total` bytes with percentage and throughput
+fn progress(written: u64, total: u64, elapsed: Duration) -> String {
+    const MB: f64 = 1024.0 * 1024.0;
+    let percent = if total == 0 {
+        100.0
+    } else {
+        written as f64 * 100.0 / total as f64
+    };
+    let rate = written as f64 / MB / elapsed.as_secs_f64().max(0.001);
+    format!(
+        "{:.0}/{:.0} MB ({:.0}%), {:.1} MB/s",
+        written as f64 / MB,
+        total as f64 / MB,
+        percent,
+        rate
+    )
+}
+
+/// SHA-256 of the first `len` bytes of `device`
+async fn device_sha256(target: &Target, device: &str, len: u64) -> Result<String, TunnelError> {
+    let failed = |message: String| TunnelError::Flash(format!("{}: {}", device, message));
+    let script = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(format!(
+            r#"set -e
+{}
+{}
+as_root head -c "$2" "$1" | sha256"#,
+            shell::AS_ROOT,
+            SHA256_FN
+        ))
+        .arg("sh")
+        .arg(device)
+        .arg(len.to_string());
+    let stdout = ssh::run_through_tunnel(target, &script, VERIFY_TIMEOUT)
+        .await
+        .map_err(|e| e.into_error(|message| failed(format!("reading it back: {}", message))))?;
+    checksum::parse_sha256(&stdout)
+        .ok_or_else(|| failed(format!("unexpected checksum output {:?}", stdout)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_device_parse() {
+        assert_eq!(
+            BlockDevice::parse("/dev/mmcblk1", "31914983424 SD32G\n"),
+            Some(BlockDevice {
+                path: "/dev/mmcblk1".to_string(),
+                size: 31914983424,
+                model: "SD32G".to_string(),
+            })
+        );
+        assert_eq!(BlockDevice::parse("/dev/sda", "1024").unwrap().model, "");
+        assert_eq!(BlockDevice::parse("/dev/sda", "oops"), None);
+    }
+
+    #[test]
+    fn test_progress() {
+        assert_eq!(
+            progress(
+                512 * 1024 * 1024,
+                2048 * 1024 * 1024,
+                Duration::from_secs(64)
+            ),
+            "512/2048 MB (25%), 8.0 MB/s"
+        );
+    }
+}
@@ -0,0 +1,367 @@
+//! Running the tunnel workflow against one or many hosts.
+
+use crate::config::Config;
+use crate::output::{self, Event, Renderable};
+use crate::phase::{self, Phase};
+use crate::swap::SwapMode;
+use crate::timing::{self, HostState, Timings};
+use crate::{RunReport, SSHTunnelManager, Target};
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{info, info_span, Instrument};
+
+/// The result of running the workflow against one member of a batch
+#[derive(Debug)]
+pub struct HostOutcome {
+    pub name: String,
+    pub target: Target,
+    pub result: Result<RunReport>,
+    pub duration: Duration,
+}
+
+impl HostOutcome {
+    fn failed_phase(&self) -> Option<Phase> {
+        self.result.as_ref().err().and_then(phase::failed_phase)
+    }
+
+    fn architecture(&self) -> Option<&str> {
+        self.result.as_ref().ok()?.architecture.as_deref()
+    }
+
+    fn board(&self) -> Option<&str> {
+        self.result
+            .as_ref()
+            .ok()?
+            .target_info
+            .as_ref()?
+            .board
+            .as_deref()
+    }
+
+    fn swap(&self) -> Option<String> {
+        Some(self.result.as_ref().ok()?.swap.as_ref()?.summary())
+    }
+}
+
+/// Outcomes of a batch run, in inventory order
+#[derive(Debug)]
+pub struct GroupReport {
+    pub outcomes: Vec<HostOutcome>,
+    pub elapsed: Duration,
+    /// Directory holding one log file per host, when `--log-dir` was given
+    pub log_dir: Option<PathBuf>,
+}
+
+impl GroupReport {
+    /// Number of hosts whose run failed
+    pub fn failed(&self) -> usize {
+        self.outcomes.iter().filter(|o| o.result.is_err()).count()
+    }
+}
+
+impl Renderable for GroupReport {
+    /// A summary table with failures first, then totals and the failure details
+    fn to_human(&self) -> String {
+        let mut sorted: Vec<&HostOutcome> = self.outcomes.iter().collect();
+        sorted.sort_by_key(|o| o.result.is_ok());
+
+        // Swap is only set up when configured, so its column only appears then
+        let with_swap = self.outcomes.iter().any(|o| o.target.swap != SwapMode::Off);
+        let with_board = self.outcomes.iter().any(|o| o.board().is_some());
+        let rows: Vec<Vec<String>> = sorted
+            .iter()
+            .map(|o| {
+                let mut row = vec![
+                    o.name.clone(),
+                    if o.result.is_ok() { "ok" } else { "FAILED" }.to_string(),
+                    o.failed_phase().map_or("-", Phase::as_str).to_string(),
+                    output::format_duration(o.duration),
+                    o.architecture().unwrap_or("-").to_string(),
+                ];
+                if with_board {
+                    row.push(o.board().unwrap_or("-").to_string());
+                }
+                if with_swap {
+                    row.push(o.swap().unwrap_or_else(|| "-".to_string()));
+                }
+                row
+            })
+            .collect();
+
+        let mut headers = vec!["HOST", "RESULT", "PHASE", "DURATION", "ARCH"];
+        if with_board {
+            headers.push("BOARD");
+        }
+        if with_swap {
+            headers.push("SWAP");
+        }
+        let mut text = output::table(&headers, &rows);
+        let failed = self.failed();
+        text.push_str(&format!(
+            "\n{} hosts: {} ok, {} failed in {}",
+            self.outcomes.len(),
+            self.outcomes.len() - failed,
+            failed,
+            output::format_duration(self.elapsed)
+        ));
+        for outcome in sorted.iter().take_while(|o| o.result.is_err()) {
+            if let Err(e) = &outcome.result {
+                text.push_str(&format!("\n{}: {}", outcome.name, e));
+            }
+        }
+        let reboot: Vec<&str> = sorted
+            .iter()
+            .filter(|o| {
+                o.result
+                    .as_ref()
+                    .is_ok_and(|r| r.hardware.as_ref().is_some_and(|h| h.reboot_required))
+            })
+            .map(|o| o.name.as_str())
+            .collect();
+        if !reboot.is_empty() {
+            text.push_str(&format!("\nReboot required: {}", reboot.join(", ")));
+        }
+        if let Some(dir) = &self.log_dir {
+            text.push_str(&format!("\nPer-host logs: {}", dir.display()));
+        }
+        text
+    }
+
+    fn to_json(&self) -> Value {
+        let hosts: Vec<Value> = self
+            .outcomes
+            .iter()
+            .map(|o| {
+                let mut host = json!({
+                    "name": o.name,
+                    "port": o.target.port,
+                    "duration_ms": o.duration.as_millis() as u64,
+                });
+                match &o.result {
+                    Ok(report) => {
+                        host["status"] = json!("ok");
+                        host["report"] = report.to_json();
+                    }
+                    Err(e) => {
+                        host["status"] = json!("error");
+                        host["phase"] = json!(o.failed_phase());
+                        host["error"] = json!(format!("{:#}", e));
+                    }
+                }
+                host
+            })
+            .collect();
+        json!({
+            "hosts": hosts,
+            "total": self.outcomes.len(),
+            "failed": self.failed(),
+            "duration_ms": self.elapsed.as_millis() as u64,
+            "log_dir": self.log_dir,
+        })
+    }
+}
+
+/// Runs the full workflow (tunnel, validation, key transfer) against a single target
+pub async fn run_target(config: &Config, target: &Target) -> Result<RunReport> {
+    let mut config = config.clone();
+    config.skip_arch_validation = target.skip_arch_validation;
+
+    SSHTunnelManager::new(config).run(target).await
+}
+
+/// Runs the workflow against every target, at most `jobs` at a time.
+///
+/// Each host's log lines are tagged with its name. Outcomes are reported in
+/// the same order as `targets`, regardless of completion order.
+pub async fn run_fleet(
+    config: &Config,
+    targets: Vec<(String, Target)>,
+    jobs: usize,
+) -> GroupReport {
+    let started = Instant::now();
+    let permits = Arc::new(Semaphore::new(jobs.max(1)));
+    let mut tasks = JoinSet::new();
+
+    let mut timings = Timings::load();
+    let simulated: Vec<bool> = targets
+        .iter()
+        .map(|(_, target)| target.simulated.is_some())
+        .collect();
+    let classes: Vec<String> = targets
+        .iter()
+        .map(|(name, _)| timing::class_of(config, Some(name)))
+        .collect();
+    let expected: Vec<Option<Duration>> = targets
+        .iter()
+        .zip(&classes)
+        .map(|((_, target), class)| timings.estimate(class, target))
+        .collect();
+    let total = targets.len();
+    let pending: Vec<HostState> = expected.iter().map(|e| HostState::Pending(*e)).collect();
+    if let Some(eta) = timing::eta(&pending, jobs) {
+        info!(
+            "{} hosts, {} at a time: about {} going by past runs",
+            total,
+            jobs.max(1),
+            output::format_duration(eta)
+        );
+    }
+    // When each host got its slot, for the estimate of what's left
+    let running: Arc<Mutex<Vec<Option<Instant>>>> = Arc::new(Mutex::new(vec![None; total]));
+
+    for (index, (name, target)) in targets.into_iter().enumerate() {
+        let permits = Arc::clone(&permits);
+        let running = Arc::clone(&running);
+        let config = config.clone();
+        let span = info_span!("host", name = %name);
+
+        tasks.spawn(
+            async move {
+                let _permit = permits
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore is never closed");
+                let started = Instant::now();
+                running.lock().unwrap_or_else(|e| e.into_inner())[index] = Some(started);
+                let result = run_target(&config, &target).await;
+                (
+                    index,
+                    HostOutcome {
+                        name,
+                        target,
+                        result,
+                        duration: started.elapsed(),
+                    },
+                )
+            }
+            .instrument(span),
+        );
+    }
+
+    let mut outcomes = Vec::with_capacity(tasks.len());
+    let mut finished: Vec<Option<Duration>> = vec![None; total];
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((index, outcome)) => {
+                finished[index] = Some(outcome.duration);
+                outcomes.push((index, outcome));
+            }
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+        let states: Vec<HostState> = {
+            let running = running.lock().unwrap_or_else(|e| e.into_inner());
+            (0..total)
+                .map(|i| match (finished[i], running[i]) {
+                    (Some(duration), _) => HostState::Done(duration),
+                    (None, Some(started)) => HostState::Running {
+                        expected: expected[i],
+                        elapsed: started.elapsed(),
+                    },
+                    (None, None) => HostState::Pending(expected[i]),
+                })
+                .collect()
+        };
+        report_progress(outcomes.len(), total, timing::eta(&states, jobs));
+    }
+    outcomes.sort_by_key(|(index, _)| *index);
+
+    for (((_, outcome), class), simulated) in outcomes.iter().zip(&classes).zip(&simulated) {
+        // A simulated device's speed says nothing about the real one's
+        if let (Ok(report), false) = (&outcome.result, simulated) {
+            timings.record(class, &report.phase_ms);
+        }
+    }
+    timings.save();
+    GroupReport {
+        outcomes: outcomes.into_iter().map(|(_, outcome)| outcome).collect(),
+        elapsed: started.elapsed(),
+        log_dir: None,
+    }
+}
+
+/// Logs and emits how far a group run is, with the estimated time left
+fn report_progress(done: usize, total: usize, eta: Option<Duration>) {
+    let percent = (done * 100 / total.max(1)) as u8;
+    info!(
+        "{} of {} hosts done ({}%){}",
+        done,
+        total,
+        percent,
+        match eta {
+            Some(eta) if done < total => format!(", about {} left", output::format_duration(eta)),
+            _ => String::new(),
+        }
+    );
+    output::emit(Event::Progress {
+        done,
+        total,
+        percent,
+        eta_secs: eta.filter(|_| done < total).map(|eta| eta.as_secs()),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phase::PhaseError;
+    use crate::TunnelError;
+    use std::collections::BTreeMap;
+
+    fn outcome(name: &str, result: Result<RunReport>) -> HostOutcome {
+        HostOutcome {
+            name: name.to_string(),
+            target: Target {
+                host: format!("{}.local", name),
+                user: "pi".to_string(),
+                key_path: "~/.ssh/id_rsa.pub".to_string(),
+                port: 2222,
+                ..Target::default()
+            },
+            result,
+            duration: Duration::from_millis(1500),
+        }
+    }
+
+    #[test]
+    fn test_summary_lists_failures_first_with_phase() {
+        let ok = RunReport {
+            host: "pi1.local".to_string(),
+            user: "pi".to_string(),
+            port: 2222,
+            architecture: Some("aarch64".to_string()),
+            target_info: None,
+            key_transferred: true,
+            key_already_deployed: false,
+            key_fingerprint: None,
+            clock: None,
+            hardware: None,
+            swap: None,
+            provision: None,
+            harden: None,
+            phase_ms: BTreeMap::new(),
+        };
+        let failure = PhaseError::at(Phase::Arch)(TunnelError::NonArmCpu("x86_64".to_string()));
+        let report = GroupReport {
+            outcomes: vec![outcome("pi1", Ok(ok)), outcome("nuc", Err(failure.into()))],
+            elapsed: Duration::from_secs(2),
+            log_dir: None,
+        };
+
+        let text = report.to_human();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].starts_with("HOST"));
+        assert!(
+            lines[1].starts_with("nuc") && lines[1].contains("FAILED") && lines[1].contains("arch")
+        );
+        assert!(lines[2].starts_with("pi1") && lines[2].contains("aarch64"));
+        assert_eq!(lines[3], "2 hosts: 1 ok, 1 failed in 2.0s");
+        assert!(lines[4].starts_with("nuc: Non-ARM CPU detected"));
+
+        assert_eq!(report.to_json()["hosts"][1]["phase"], "arch");
+    }
+}
@@ -0,0 +1,65 @@
+//! Reverse forwards that let a device reach services on this machine.
+//!
+//! A [`ReverseForward`] is a background `ssh -N -R ...` through the tunnel.
+//! It lives as long as the value: dropping it closes the forwards.
+
+use crate::executor::Child;
+use crate::executor::Stdio;
+use crate::ssh;
+use crate::{Target, TunnelError};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// How long a new forward gets to fail before it is considered up
+const STARTUP_GRACE: Duration = Duration::from_secs(2);
+
+/// Reverse forwards held open through the tunnel
+pub struct ReverseForward {
+    child: Child,
+}
+
+impl ReverseForward {
+    /// Opens `forwards` (`-R` specs, see [`ssh::reverse_forwards_through_tunnel`])
+    pub async fn start(target: &Target, forwards: &[String]) -> Result<Self, TunnelError> {
+        let failed = |message: String| {
+            TunnelError::Forward(format!(
+                "{} on {}: {}",
+                forwards.join(", "),
+                target.host,
+                message
+            ))
+        };
+        let mut child = ssh::reverse_forwards_through_tunnel(target, forwards)?
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| failed(e.to_string()))?;
+
+        // ExitOnForwardFailure makes ssh quit right away if a port can't be bound
+        sleep(STARTUP_GRACE).await;
+        if child
+            .try_wait()
+            .map_err(|e| failed(e.to_string()))?
+            .is_some()
+        {
+            let output = child
+                .wait_with_output()
+                .await
+                .map_err(|e| failed(e.to_string()))?;
+            return Err(failed(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        Ok(Self { child })
+    }
+
+    /// Resolves when the forwards go down on their own, e.g. with the connection
+    pub async fn closed(&mut self) {
+        let _ = self.child.wait().await;
+    }
+
+    /// Closes the forwards
+    pub async fn stop(mut self) {
+        let _ = self.child.kill().await;
+    }
+}
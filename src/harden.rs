@@ -0,0 +1,189 @@
+//! Turning off password logins once a key works, with `harden` or `up --harden`.
+//!
+//! Hardening puts `PasswordAuthentication no`, `KbdInteractiveAuthentication
+//! no` and `PermitRootLogin no` at the top of the device's sshd_config, where
+//! they win over anything later in the file or in its includes. It refuses to
+//! start until a fresh login with nothing but the target's key has worked, and
+//! makes the change through [`sshd::change`], which puts the old configuration
+//! back if such a login fails afterwards.
+
+use crate::config::Config;
+use crate::output::Renderable;
+use crate::shell::{self, RemoteCommand};
+use crate::ssh::{self, RunError};
+use crate::sshd::{self, Login};
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use serde::Serialize;
+use std::time::Duration;
+use tracing::info;
+
+/// Upper bound for editing sshd_config and reloading sshd
+const HARDEN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Turns off password and root logins unless sshd already has them off,
+/// backing sshd_config up first and restarting sshd; a configuration
+/// `sshd -t` rejects is rolled back. Prints `unchanged`, or `backup <path>`
+/// and `hardened`.
+pub const HARDEN_SCRIPT: &str = r#"set -e
+PATH="$PATH:/usr/sbin:/sbin"
+config=/etc/ssh/sshd_config
+backup=$config.ssh-ip-tunnel-harden.bak
+sshd=$(command -v sshd || true)
+[ -n "$sshd" ] || { echo "sshd not found; hardening needs OpenSSH's sshd" >&2; exit 1; }
+effective=$(as_root "$sshd" -T 2>/dev/null || true)
+off() { printf '%s\n' "$effective" | grep -qx "$1 no"; }
+if off passwordauthentication && off kbdinteractiveauthentication && off permitrootlogin; then
+  echo unchanged
+  exit 0
+fi
+as_root cp -p "$config" "$backup"
+echo "backup $backup"
+# sshd keeps the first value it reads, so these go above Include lines and Match blocks
+{ printf '# Added by ssh-ip-tunnel harden: key logins only\nPasswordAuthentication no\nKbdInteractiveAuthentication no\nPermitRootLogin no\n'; as_root cat "$backup"; } | as_root tee "$config" >/dev/null
+if ! error=$(as_root "$sshd" -t 2>&1); then
+  as_root cp -p "$backup" "$config"
+  echo "sshd rejected the settings, which were rolled back: $error" >&2
+  exit 1
+fi
+as_root systemctl reload ssh 2>/dev/null || as_root systemctl reload sshd 2>/dev/null || as_root kill -HUP "$(cat /var/run/sshd.pid)"
+echo hardened"#;
+
+/// What hardening did
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct HardenReport {
+    pub host: String,
+    /// False when sshd already refused passwords and root logins
+    pub changed: bool,
+    /// Copy of sshd_config from before the change
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup: Option<String>,
+}
+
+impl HardenReport {
+    /// One-line summary, e.g. `password and root logins turned off`
+    pub fn summary(&self) -> String {
+        match &self.backup {
+            Some(backup) if self.changed => format!(
+                "password and root logins turned off (previous sshd_config in {})",
+                backup
+            ),
+            _ => "password and root logins were already off".to_string(),
+        }
+    }
+
+    /// Parses the output of [`HARDEN_SCRIPT`]
+    fn parse(host: &str, output: &str) -> Option<Self> {
+        let mut report = HardenReport {
+            host: host.to_string(),
+            changed: false,
+            backup: None,
+        };
+        for line in output.lines() {
+            match line.split_once(' ') {
+                Some(("backup", path)) => report.backup = Some(path.to_string()),
+                None if line == "hardened" => report.changed = true,
+                None if line == "unchanged" => return Some(report),
+                _ => {}
+            }
+        }
+        report.changed.then_some(report)
+    }
+}
+
+impl Renderable for HardenReport {
+    fn to_human(&self) -> String {
+        format!("{}: {}", self.host, self.summary())
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Runs [`HARDEN_SCRIPT`], returning what it printed
+async fn run_script(target: &Target) -> Result<String, TunnelError> {
+    let command =
+        RemoteCommand::new("sh")
+            .arg("-c")
+            .arg(format!("{}\n{}", shell::AS_ROOT, HARDEN_SCRIPT));
+    let stdout = ssh::run_through_tunnel(target, &command, HARDEN_TIMEOUT)
+        .await
+        .map_err(|e| match e {
+            RunError::Failed { stderr, .. } => {
+                TunnelError::Harden(format!("{} (root or passwordless sudo is needed)", stderr))
+            }
+            e => e.into_error(TunnelError::Harden),
+        })?;
+    Ok(stdout)
+}
+
+/// Refuses to harden for root, whom `PermitRootLogin no` would lock out
+fn check_user(target: &Target) -> Result<(), TunnelError> {
+    if target.user == "root" {
+        return Err(TunnelError::Harden(
+            "the login user is root, whom PermitRootLogin no would lock out; log in as a user with sudo".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Turns off password and root logins on `target` through an open tunnel,
+/// once a login with the target's key alone has worked
+pub async fn harden(target: &Target) -> Result<HardenReport, TunnelError> {
+    check_user(target)?;
+    info!("Checking that {} takes the key alone...", target.host);
+    sshd::fresh_login(target, Login::KeyAlone)
+        .await
+        .map_err(|e| {
+            TunnelError::Harden(format!(
+                "refusing to turn off password logins: {} could not log in as {} with {} alone ({})",
+                target.host, target.user, target.key_path, e
+            ))
+        })?;
+
+    info!("Turning off password logins on {}...", target.host);
+    let report = sshd::change(target, Login::KeyAlone, async {
+        let output = run_script(target).await?;
+        HardenReport::parse(&target.host, &output)
+            .ok_or_else(|| TunnelError::Harden(format!("unexpected output {:?}", output.trim())))
+    })
+    .await?;
+    info!("{}: {}", target.host, report.summary());
+    Ok(report)
+}
+
+/// Opens the tunnel to `target` and hardens its sshd, for `harden`
+pub async fn run(config: &Config, target: &Target) -> Result<HardenReport> {
+    check_user(target)?;
+    SSHTunnelManager::new(config.clone())
+        .connect(target)
+        .await?;
+    Ok(harden(target).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_report() {
+        let report = HardenReport::parse(
+            "pi",
+            "backup /etc/ssh/sshd_config.ssh-ip-tunnel-harden.bak\nhardened\n",
+        )
+        .unwrap();
+        assert!(report.changed);
+        assert_eq!(
+            report.backup.as_deref(),
+            Some("/etc/ssh/sshd_config.ssh-ip-tunnel-harden.bak")
+        );
+        assert!(!HardenReport::parse("pi", "unchanged\n").unwrap().changed);
+        assert_eq!(
+            HardenReport::parse("pi", "backup /etc/ssh/x\n"),
+            None,
+            "a run that stopped after the backup did not harden anything"
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//! Enabling interfaces, device tree overlays and kernel modules.
+//!
+//! The `[hardware]` section of the configuration (or of a host profile)
+//! declares what a board needs, and `up` makes it so after the key transfer:
+//! `dtparam`/`dtoverlay`/`enable_uart` lines are added to the Raspberry Pi
+//! boot configuration (`/boot/firmware/config.txt`, or `/boot/config.txt` on
+//! older images) and modules to `/etc/modules`. Lines that are already there
+//! are left alone, so repeated runs change nothing, and each file is copied
+//! aside before its first change. Boot configuration changes take effect at
+//! the next reboot, which is left to the user; modules are loaded right away.
+
+use crate::shell::{self, RemoteCommand};
+use crate::ssh::{self, RunError};
+use crate::{Target, TunnelError};
+use chrono::Local;
+use serde::Serialize;
+use std::time::Duration;
+use tracing::{info, warn};
+
+pub use crate::pure::hardware::HardwareConfig;
+
+/// Upper bound for editing the files and loading the modules
+const HARDWARE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Adds the config.txt lines in `$1` and the modules in `$2` (both one per
+/// line) where missing, copying each file to `<file>.$3` before changing it;
+/// with `$4` set, only reports what it would add. Prints `config <path>`,
+/// `backup <path>`, `added config|module <line>` and `loaded <module>` lines.
+pub const HARDWARE_SCRIPT: &str = r#"set -e
+stamp=$3
+dry=$4
+touched=
+append() {
+  [ -z "$dry" ] || return 0
+  # Back up each file once, before this run's first change, unless this run created it
+  case " $touched " in
+    *" $1 "*) ;;
+    *)
+      touched="$touched $1"
+      if [ -e "$1" ]; then
+        as_root cp -p "$1" "$1.$stamp"
+        echo "backup $1.$stamp"
+      fi
+      ;;
+  esac
+  if [ -s "$1" ] && [ -n "$(tail -c1 "$1")" ]; then echo | as_root tee -a "$1" >/dev/null; fi
+  printf '%s\n' "$2" | as_root tee -a "$1" >/dev/null
+}
+if [ -n "$1" ]; then
+  config=
+  for candidate in /boot/firmware/config.txt /boot/config.txt; do
+    if [ -f "$candidate" ]; then config=$candidate; break; fi
+  done
+  if [ -z "$config" ]; then
+    echo "neither /boot/firmware/config.txt nor /boot/config.txt exists; interfaces and dtoverlays need a Raspberry Pi boot partition" >&2
+    exit 1
+  fi
+  echo "config $config"
+  section=$(grep '^\[' "$config" | tail -n1)
+  printf '%s\n' "$1" | while IFS= read -r line; do
+    grep -qxF "$line" "$config" && continue
+    # Lines under a conditional section like [pi4] would only apply to that model
+    if [ -n "$section" ] && [ "$section" != "[all]" ]; then append "$config" "[all]"; section="[all]"; fi
+    append "$config" "$line"
+    echo "added config $line"
+  done
+fi
+if [ -n "$2" ]; then
+  modules=/etc/modules
+  if [ ! -f "$modules" ]; then
+    modules=/etc/modules-load.d/ssh-ip-tunnel.conf
+    [ -n "$dry" ] || as_root mkdir -p /etc/modules-load.d
+  fi
+  printf '%s\n' "$2" | while IFS= read -r module; do
+    if ! grep -qxF "$module" "$modules" 2>/dev/null; then
+      append "$modules" "$module"
+      echo "added module $module"
+    fi
+    if [ -z "$dry" ] && as_root modprobe "$module" 2>/dev/null; then echo "loaded $module"; fi
+  done
+fi"#;
+
+/// What the hardware step changed
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct HardwareReport {
+    /// The boot configuration file, when there were lines for it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_config: Option<String>,
+    /// Lines added to the boot configuration
+    pub config_added: Vec<String>,
+    /// Modules added to the modules file
+    pub modules_added: Vec<String>,
+    /// Modules that are loaded now
+    pub modules_loaded: Vec<String>,
+    /// Copies of the files as they were before this run changed them
+    pub backups: Vec<String>,
+    /// Whether the boot configuration changed, which takes a reboot to apply
+    pub reboot_required: bool,
+}
+
+impl HardwareReport {
+    /// One-line summary, e.g. `dtparam=spi=on added to /boot/firmware/config.txt (reboot required)`
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.config_added.is_empty() {
+            parts.push(format!(
+                "{} added to {}",
+                self.config_added.join(", "),
+                self.boot_config.as_deref().unwrap_or("config.txt")
+            ));
+        }
+        if !self.modules_added.is_empty() {
+            parts.push(format!("modules {} added", self.modules_added.join(", ")));
+        }
+        if parts.is_empty() {
+            return "already configured".to_string();
+        }
+        let mut summary = parts.join("; ");
+        if self.reboot_required {
+            summary.push_str(" (reboot required)");
+        }
+        summary
+    }
+
+    /// Parses the output of [`HARDWARE_SCRIPT`]
+    fn parse(output: &str) -> Self {
+        let mut report = HardwareReport::default();
+        for line in output.lines() {
+            if let Some(path) = line.strip_prefix("config ") {
+                report.boot_config = Some(path.to_string());
+            } else if let Some(path) = line.strip_prefix("backup ") {
+                report.backups.push(path.to_string());
+            } else if let Some(added) = line.strip_prefix("added config ") {
+                report.config_added.push(added.to_string());
+            } else if let Some(module) = line.strip_prefix("added module ") {
+                report.modules_added.push(module.to_string());
+            } else if let Some(module) = line.strip_prefix("loaded ") {
+                report.modules_loaded.push(module.to_string());
+            }
+        }
+        report.reboot_required = !report.config_added.is_empty();
+        report
+    }
+}
+
+/// Applies `target`'s hardware configuration
+pub async fn configure(target: &Target) -> Result<HardwareReport, TunnelError> {
+    info!("Configuring interfaces and modules on {}...", target.host);
+    let report = run(target, &target.hardware, false).await?;
+    info!("Hardware on {}: {}", target.host, report.summary());
+    if report.reboot_required {
+        warn!(
+            "{} must be rebooted for the boot configuration changes to take effect",
+            target.host
+        );
+    }
+    Ok(report)
+}
+
+/// Runs [`HARDWARE_SCRIPT`] for `hardware`, only reporting if `dry_run`
+pub async fn run(
+    target: &Target,
+    hardware: &HardwareConfig,
+    dry_run: bool,
+) -> Result<HardwareReport, TunnelError> {
+    let stamp = format!("ssh-ip-tunnel-{}", Local::now().format("%Y%m%d-%H%M%S"));
+    let command = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(format!("{}\n{}", shell::AS_ROOT, HARDWARE_SCRIPT))
+        .arg("sh")
+        .arg(hardware.config_lines().join("\n"))
+        .arg(hardware.module_lines().join("\n"))
+        .arg(stamp)
+        .arg(if dry_run { "1" } else { "" });
+    let stdout = ssh::run_through_tunnel(target, &command, HARDWARE_TIMEOUT)
+        .await
+        .map_err(|e| match e {
+            RunError::Failed { stderr, .. } => {
+                TunnelError::Hardware(format!("{} (root or passwordless sudo is needed)", stderr))
+            }
+            e => e.into_error(TunnelError::Hardware),
+        })?;
+
+    Ok(HardwareReport::parse(&stdout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_report() {
+        let report = HardwareReport::parse(
+            "config /boot/firmware/config.txt\nbackup /boot/firmware/config.txt.ssh-ip-tunnel-20260101-120000\nadded config dtparam=spi=on\nloaded i2c-dev\n",
+        );
+        assert!(report.reboot_required);
+        assert_eq!(report.modules_loaded, ["i2c-dev"]);
+        assert_eq!(
+            report.summary(),
+            "dtparam=spi=on added to /boot/firmware/config.txt (reboot required)"
+        );
+        assert_eq!(
+            HardwareReport::parse("config /boot/config.txt\n").summary(),
+            "already configured"
+        );
+    }
+}
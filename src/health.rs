@@ -0,0 +1,162 @@
+//! `up --watch --health-listen`: `GET /healthz` for supervisors.
+//!
+//! Docker healthchecks, Kubernetes probes and the like only need a status
+//! code: 200 while the watched tunnel passed its latest check, 503 while it
+//! doesn't and before the watch has started. Any other request gets 404.
+//! Each connection carries one request and is closed after the answer.
+
+use crate::watch::Live;
+use crate::TunnelError;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
+
+/// Longest request head accepted, in bytes
+const MAX_HEAD: usize = 8 * 1024;
+
+/// A running health endpoint; dropping it stops accepting connections
+pub struct HealthServer {
+    task: JoinHandle<()>,
+}
+
+impl HealthServer {
+    /// Starts serving the health of `live` on `address`
+    pub async fn start(address: SocketAddr, live: Live) -> Result<Self, TunnelError> {
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(|e| TunnelError::Watch(format!("health endpoint on {}: {}", address, e)))?;
+        let address = listener.local_addr().unwrap_or(address);
+        info!("Serving the tunnel's health on http://{}/healthz", address);
+        let task = tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let live = live.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(client, &live).await {
+                        debug!("Health endpoint: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(Self { task })
+    }
+}
+
+impl Drop for HealthServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Reads a request head (request line and headers); `None` when the client
+/// hung up or sent too much. Also used by [`crate::api`].
+pub(crate) async fn read_head(
+    reader: &mut BufReader<TcpStream>,
+) -> std::io::Result<Option<String>> {
+    let mut head = String::new();
+    loop {
+        // Cut off at what's left of MAX_HEAD, for clients that never send a newline
+        let left = (MAX_HEAD + 1).saturating_sub(head.len()) as u64;
+        let read = (&mut *reader).take(left).read_line(&mut head).await?;
+        if read == 0 || head.len() > MAX_HEAD {
+            return Ok(None);
+        }
+        if head.ends_with("\r\n\r\n") || head.ends_with("\n\n") || head.trim().is_empty() {
+            return Ok(Some(head));
+        }
+    }
+}
+
+/// A complete response with `body`, after which the connection is closed
+pub(crate) fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+/// Answers one client connection
+async fn serve(client: TcpStream, live: &Live) -> std::io::Result<()> {
+    let mut reader = BufReader::new(client);
+    let Some(head) = read_head(&mut reader).await? else {
+        return Ok(());
+    };
+    let mut words = head.split_whitespace();
+    let path = match words.next() {
+        Some("GET") => words
+            .next()
+            .map(|target| target.split('?').next().unwrap_or(target)),
+        _ => None,
+    };
+    let (status, body) = match path {
+        Some("/healthz") if live.is_healthy() => ("200 OK", "ok\n"),
+        Some("/healthz") => ("503 Service Unavailable", "down\n"),
+        _ => ("404 Not Found", "not found\n"),
+    };
+    reader
+        .into_inner()
+        .write_all(response(status, "text/plain", body).as_bytes())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::watch::Stats;
+    use crate::Target;
+    use chrono::Utc;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    async fn get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_healthz_follows_the_latest_check() {
+        let live = Live::default();
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let _server = HealthServer::start(address, live.clone()).await.unwrap();
+        assert_eq!(
+            get(address, "/healthz").await,
+            "HTTP/1.1 503 Service Unavailable"
+        );
+
+        let target = Target::builder("pi.local", "pi", &Config::default())
+            .build()
+            .unwrap();
+        let mut stats = Stats::new(&target, Duration::from_secs(30), Utc::now());
+        live.set(&stats);
+        assert_eq!(get(address, "/healthz").await, "HTTP/1.1 200 OK");
+        assert_eq!(get(address, "/metrics").await, "HTTP/1.1 404 Not Found");
+
+        stats.up_since = None;
+        live.set(&stats);
+        assert_eq!(
+            get(address, "/healthz?verbose").await,
+            "HTTP/1.1 503 Service Unavailable"
+        );
+
+        // A request line with no end is dropped once it passes MAX_HEAD
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let _ = stream.write_all(&vec![b'a'; MAX_HEAD * 2]).await;
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        assert_eq!(response, "");
+    }
+}
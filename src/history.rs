@@ -0,0 +1,531 @@
+//! Run history: what each invocation did, for `history show <run-id>`.
+//!
+//! Every invocation has a [`RunId`], which the CLI puts on its log lines (as
+//! the `run` span), in JSON and NDJSON output and in the state it keeps. Its
+//! full debug log goes to `runs/<run-id>.log` under the state directory, and
+//! when it ends `runs/<run-id>.json` records the command line, times, outcome,
+//! result, any per-host log directory and the devices `up` connected to, for
+//! `history list` and `up --last`. The newest [`KEEP_RUNS`] runs are kept.
+
+use crate::output::{self, Renderable};
+use crate::paths;
+use crate::run::{self, RunId};
+use crate::{RunReport, TunnelError};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::warn;
+
+/// Runs whose records are kept; older ones are deleted when a new run starts
+pub const KEEP_RUNS: usize = 100;
+
+/// Replaces the values of options that would leak secrets into the history
+const SECRET_OPTIONS: &[&str] = &["--password"];
+
+/// What one invocation did
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct RunRecord {
+    pub run_id: String,
+    /// Command line, with secrets replaced by `***`
+    pub command: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub exit_code: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Structured form of the result the run showed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    /// Directory of a group run's per-host logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_logs: Option<PathBuf>,
+    /// Devices the run set up, in the order they finished
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub connections: Vec<Connection>,
+}
+
+/// A device `up` set up successfully
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Connection {
+    pub host: String,
+    pub user: String,
+    /// Local port of the tunnel
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub architecture: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    /// The key was installed, rather than found already there
+    pub key_transferred: bool,
+}
+
+/// The run in progress, until [`finish`] records it
+struct Started {
+    command: Vec<String>,
+    started: DateTime<Utc>,
+    host_logs: Option<PathBuf>,
+}
+
+static STARTED: Mutex<Option<Started>> = Mutex::new(None);
+
+/// Devices set up so far by the run in progress
+static CONNECTIONS: Mutex<Vec<Connection>> = Mutex::new(Vec::new());
+
+/// Directory holding the run records
+fn runs_dir() -> PathBuf {
+    paths::state_dir().join("runs")
+}
+
+fn record_path(id: &RunId) -> PathBuf {
+    runs_dir().join(format!("{}.json", id))
+}
+
+fn log_path(id: &RunId) -> PathBuf {
+    runs_dir().join(format!("{}.log", id))
+}
+
+/// `args` with the values of [`SECRET_OPTIONS`] replaced
+fn redact(args: &[String]) -> Vec<String> {
+    let mut redacted = Vec::with_capacity(args.len());
+    let mut secret_next = false;
+    for arg in args {
+        if std::mem::take(&mut secret_next) {
+            redacted.push("***".to_string());
+            continue;
+        }
+        match arg.split_once('=') {
+            Some((option, _)) if SECRET_OPTIONS.contains(&option) => {
+                redacted.push(format!("{}=***", option));
+            }
+            _ => {
+                secret_next = SECRET_OPTIONS.contains(&arg.as_str());
+                redacted.push(arg.clone());
+            }
+        }
+    }
+    redacted
+}
+
+/// Starts recording this run, deleting the oldest records beyond [`KEEP_RUNS`],
+/// and returns the file for its log
+pub fn begin(args: &[String], host_logs: Option<&Path>) -> Result<File, TunnelError> {
+    *STARTED.lock().unwrap_or_else(|e| e.into_inner()) = Some(Started {
+        command: redact(args),
+        started: Utc::now(),
+        host_logs: host_logs.map(Path::to_path_buf),
+    });
+    let dir = runs_dir();
+    prune(&dir, KEEP_RUNS.saturating_sub(1));
+    std::fs::create_dir_all(&dir)
+        .and_then(|_| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log_path(run::current()))
+        })
+        .map_err(|e| TunnelError::History(format!("{}: {}", dir.display(), e)))
+}
+
+/// Deletes all but the newest `keep` runs' files in `dir`
+fn prune(dir: &Path, keep: usize) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut ids: Vec<String> = entries
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            let id = name.strip_suffix(".json")?;
+            RunId::parse(id).map(|id| id.to_string())
+        })
+        .collect();
+    // Run IDs sort chronologically
+    ids.sort_unstable();
+    let old = ids.len().saturating_sub(keep);
+    for id in &ids[..old] {
+        let _ = std::fs::remove_file(dir.join(format!("{}.json", id)));
+        let _ = std::fs::remove_file(dir.join(format!("{}.log", id)));
+    }
+}
+
+/// Notes that `up` set up the device in `report`, for the record of a run
+/// started with [`begin`]
+pub fn connected(report: &RunReport) {
+    if STARTED.lock().unwrap_or_else(|e| e.into_inner()).is_none() {
+        return;
+    }
+    CONNECTIONS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(Connection {
+            host: report.host.clone(),
+            user: report.user.clone(),
+            port: report.port,
+            architecture: report.architecture.clone(),
+            timestamp: Utc::now(),
+            key_transferred: report.key_transferred,
+        });
+}
+
+/// Records how the run started with [`begin`] ended; does nothing without one
+pub fn finish(error: Option<&anyhow::Error>, exit_code: i32) {
+    let Some(started) = STARTED.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    let id = run::current();
+    let record = RunRecord {
+        run_id: id.to_string(),
+        command: started.command,
+        namespace: paths::namespace().map(str::to_string),
+        started: started.started,
+        finished: Utc::now(),
+        exit_code,
+        error: error.map(|e| format!("{:#}", e)),
+        result: output::last_result(),
+        host_logs: started.host_logs,
+        connections: std::mem::take(&mut *CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner())),
+    };
+    let path = record_path(id);
+    let text = serde_json::to_string_pretty(&record).unwrap_or_default();
+    if let Err(e) = std::fs::write(&path, text) {
+        warn!("Cannot record this run in {}: {}", path.display(), e);
+    }
+}
+
+/// A past run and everything logged during it, for `history show`
+#[derive(Debug, Serialize)]
+pub struct RunDetails {
+    #[serde(flatten)]
+    pub record: RunRecord,
+    /// The run's debug log
+    pub log: Vec<String>,
+    /// Each host's log from a group run, by host
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub host_log_files: Vec<HostLog>,
+}
+
+/// One host's log from a group run
+#[derive(Debug, Serialize)]
+pub struct HostLog {
+    pub path: PathBuf,
+    pub lines: Vec<String>,
+}
+
+impl Renderable for RunDetails {
+    fn to_human(&self) -> String {
+        let record = &self.record;
+        let duration = (record.finished - record.started)
+            .to_std()
+            .unwrap_or_default();
+        let outcome = match (&record.error, record.exit_code) {
+            (Some(error), _) => format!("failed: {}", error),
+            (None, 0) => "ok".to_string(),
+            (None, code) => format!("exit code {}", code),
+        };
+        let mut fields = vec![
+            ("Run", record.run_id.clone()),
+            ("Command", record.command.join(" ")),
+            (
+                "Started",
+                record.started.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+            ),
+            ("Duration", output::format_duration(duration)),
+            ("Outcome", outcome),
+        ];
+        if let Some(namespace) = &record.namespace {
+            fields.push(("Namespace", namespace.clone()));
+        }
+        let mut text = fields
+            .iter()
+            .map(|(label, value)| format!("{:<10} {}", format!("{}:", label), value))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if let Some(result) = &record.result {
+            let result = serde_json::to_string_pretty(result).unwrap_or_default();
+            text.push_str(&format!("\n\nReport:\n{}", result));
+        }
+        text.push_str("\n\nLog:");
+        for line in &self.log {
+            text.push_str(&format!("\n{}", line));
+        }
+        for host_log in &self.host_log_files {
+            text.push_str(&format!("\n\nLog {}:", host_log.path.display()));
+            for line in &host_log.lines {
+                text.push_str(&format!("\n{}", line));
+            }
+        }
+        text
+    }
+
+    fn to_json(&self) -> Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Lines of the file at `path`, or none when it can't be read
+fn read_lines(path: &Path) -> Vec<String> {
+    std::fs::read_to_string(path)
+        .map(|text| text.lines().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Reassembles run `id` from its record, its log and its hosts' logs
+pub fn show(id: &str) -> Result<RunDetails, TunnelError> {
+    let id = RunId::parse(id).ok_or_else(|| {
+        TunnelError::History(format!(
+            "{} is not a run ID, e.g. 20261016T082653Z-3f2a",
+            id
+        ))
+    })?;
+    let path = record_path(&id);
+    let text = std::fs::read_to_string(&path).map_err(|_| {
+        TunnelError::History(format!(
+            "no run {} in {} (the newest {} runs are kept)",
+            id,
+            runs_dir().display(),
+            KEEP_RUNS
+        ))
+    })?;
+    let record: RunRecord = serde_json::from_str(&text)
+        .map_err(|e| TunnelError::History(format!("{}: {}", path.display(), e)))?;
+
+    let mut host_log_files: Vec<HostLog> = record
+        .host_logs
+        .as_deref()
+        .and_then(|dir| std::fs::read_dir(dir).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            (path.extension()? == "log").then(|| HostLog {
+                lines: read_lines(&path),
+                path,
+            })
+        })
+        .collect();
+    host_log_files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(RunDetails {
+        log: read_lines(&log_path(&id)),
+        record,
+        host_log_files,
+    })
+}
+
+/// Records of the kept runs, newest first; unreadable ones are skipped
+fn records() -> Vec<RunRecord> {
+    let Ok(entries) = std::fs::read_dir(runs_dir()) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            RunId::parse(path.file_stem()?.to_str()?)?;
+            (path.extension()? == "json").then_some(path)
+        })
+        .collect();
+    // Run IDs sort chronologically
+    paths.sort_unstable();
+    paths
+        .iter()
+        .rev()
+        .filter_map(|path| serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok())
+        .collect()
+}
+
+/// A connection and the run that made it
+#[derive(Debug, Clone, Serialize)]
+pub struct PastConnection {
+    pub run_id: String,
+    #[serde(flatten)]
+    pub connection: Connection,
+}
+
+/// Every connection of the kept runs, newest first
+pub fn connections() -> Vec<PastConnection> {
+    records()
+        .into_iter()
+        .flat_map(|record| {
+            let run_id = record.run_id;
+            record
+                .connections
+                .into_iter()
+                .rev()
+                .map(move |connection| PastConnection {
+                    run_id: run_id.clone(),
+                    connection,
+                })
+        })
+        .collect()
+}
+
+/// The newest connection, for `up --last`
+pub fn last_connection() -> Option<Connection> {
+    connections().into_iter().next().map(|past| past.connection)
+}
+
+/// Host and user of the newest connection, e.g. as defaults for a prompt
+pub fn last_login() -> Option<(String, String)> {
+    recent_logins().into_iter().next()
+}
+
+/// Each host and user connected to, newest first
+pub fn recent_logins() -> Vec<(String, String)> {
+    let mut logins: Vec<(String, String)> = Vec::new();
+    for past in connections() {
+        let login = (past.connection.host, past.connection.user);
+        if !logins.contains(&login) {
+            logins.push(login);
+        }
+    }
+    logins
+}
+
+/// Result of `history list`
+#[derive(Debug, Serialize)]
+#[serde(transparent)]
+pub struct ConnectionList(pub Vec<PastConnection>);
+
+impl Renderable for ConnectionList {
+    fn to_human(&self) -> String {
+        if self.0.is_empty() {
+            return "No connections recorded yet".to_string();
+        }
+        let rows: Vec<Vec<String>> = self
+            .0
+            .iter()
+            .map(|past| {
+                let connection = &past.connection;
+                vec![
+                    connection.timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    connection.host.clone(),
+                    connection.user.clone(),
+                    connection.port.to_string(),
+                    connection.architecture.clone().unwrap_or_default(),
+                    past.run_id.clone(),
+                ]
+            })
+            .collect();
+        output::table(
+            &["TIME (UTC)", "HOST", "USER", "PORT", "ARCH", "RUN"],
+            &rows,
+        )
+    }
+
+    fn to_json(&self) -> Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Result of `history clear`
+#[derive(Debug, Serialize)]
+pub struct ClearReport {
+    pub removed: usize,
+}
+
+impl Renderable for ClearReport {
+    fn to_human(&self) -> String {
+        format!("Removed {} run(s) from the history", self.removed)
+    }
+
+    fn to_json(&self) -> Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Deletes every kept run but this one, with its log and connections
+pub fn clear() -> Result<ClearReport, TunnelError> {
+    let dir = runs_dir();
+    let Ok(entries) = std::fs::read_dir(&dir) else {
+        return Ok(ClearReport { removed: 0 });
+    };
+    let current = run::current().to_string();
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        if id == current || RunId::parse(id).is_none() {
+            continue;
+        }
+        if matches!(extension, Some("json" | "log")) {
+            std::fs::remove_file(&path)
+                .map_err(|e| TunnelError::History(format!("{}: {}", path.display(), e)))?;
+            removed += usize::from(extension == Some("json"));
+        }
+    }
+    Ok(ClearReport { removed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_hides_passwords() {
+        let args: Vec<String> = [
+            "ssh-ip-tunnel",
+            "up",
+            "--password",
+            "hunter2",
+            "--password=hunter2",
+            "-H",
+            "pi.local",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        assert_eq!(
+            redact(&args),
+            [
+                "ssh-ip-tunnel",
+                "up",
+                "--password",
+                "***",
+                "--password=***",
+                "-H",
+                "pi.local"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prune_keeps_the_newest_runs() {
+        let dir = std::env::temp_dir().join(format!("ssh_ip_tunnel-prune-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ids = [
+            "20261016T082653Z-3f2a",
+            "20261016T082700Z-0001",
+            "20261017T000000Z-ffff",
+        ];
+        for id in ids {
+            std::fs::write(dir.join(format!("{}.json", id)), "{}").unwrap();
+            std::fs::write(dir.join(format!("{}.log", id)), "").unwrap();
+        }
+        std::fs::write(dir.join("notes.json"), "{}").unwrap();
+
+        prune(&dir, 2);
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            left,
+            [
+                "20261016T082700Z-0001.json",
+                "20261016T082700Z-0001.log",
+                "20261017T000000Z-ffff.json",
+                "20261017T000000Z-ffff.log",
+                "notes.json"
+            ]
+        );
+    }
+}
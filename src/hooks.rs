@@ -0,0 +1,183 @@
+//! Hooks: the `[hooks]` commands, run on this machine around the phases of `up`.
+//!
+//! Each hook is a shell command (`sh -c`, or `cmd /C` on Windows) with the
+//! run described in its environment: `SSH_IP_TUNNEL_HOOK`, `_HOST`, `_USER`,
+//! `_PORT` (the tunnel's local port), `_RUN_ID` and `_OUTCOME`, plus `_PHASE`
+//! and `_ERROR` for `on_failure`. Its output goes to the log. A failing `pre_`
+//! hook stops the run before its phase; other hooks failing is only a warning.
+
+use crate::config::Hooks;
+use crate::phase::Phase;
+use crate::process;
+use crate::run;
+use crate::{Target, TunnelError};
+use std::time::Duration;
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+/// Upper bound for one hook, e.g. bringing up a VPN
+const HOOK_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[cfg(windows)]
+const SHELL: [&str; 2] = ["cmd", "/C"];
+#[cfg(not(windows))]
+const SHELL: [&str; 2] = ["sh", "-c"];
+
+/// A point in the run where a hook can run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    PreTunnel,
+    PostTunnel,
+    PreKeyTransfer,
+    PostKeyTransfer,
+    OnFailure,
+}
+
+impl Hook {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Hook::PreTunnel => "pre_tunnel",
+            Hook::PostTunnel => "post_tunnel",
+            Hook::PreKeyTransfer => "pre_key_transfer",
+            Hook::PostKeyTransfer => "post_key_transfer",
+            Hook::OnFailure => "on_failure",
+        }
+    }
+
+    /// The command configured for this hook, if any
+    fn command(self, hooks: &Hooks) -> Option<&str> {
+        match self {
+            Hook::PreTunnel => hooks.pre_tunnel.as_deref(),
+            Hook::PostTunnel => hooks.post_tunnel.as_deref(),
+            Hook::PreKeyTransfer => hooks.pre_key_transfer.as_deref(),
+            Hook::PostKeyTransfer => hooks.post_key_transfer.as_deref(),
+            Hook::OnFailure => hooks.on_failure.as_deref(),
+        }
+    }
+}
+
+/// How the run stands when a hook runs
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome<'a> {
+    /// Everything so far succeeded
+    Ok,
+    /// The run failed in `phase` with `error`
+    Failed {
+        phase: Option<Phase>,
+        error: &'a str,
+    },
+}
+
+/// The variables a hook gets for `target` and `outcome`
+fn environment(hook: Hook, target: &Target, outcome: Outcome) -> Vec<(&'static str, String)> {
+    let mut vars = vec![
+        ("SSH_IP_TUNNEL_HOOK", hook.as_str().to_string()),
+        ("SSH_IP_TUNNEL_HOST", target.host.clone()),
+        ("SSH_IP_TUNNEL_USER", target.user.clone()),
+        ("SSH_IP_TUNNEL_PORT", target.port.to_string()),
+        ("SSH_IP_TUNNEL_RUN_ID", run::current().to_string()),
+    ];
+    match outcome {
+        Outcome::Ok => vars.push(("SSH_IP_TUNNEL_OUTCOME", "ok".to_string())),
+        Outcome::Failed { phase, error } => vars.extend([
+            ("SSH_IP_TUNNEL_OUTCOME", "failed".to_string()),
+            (
+                "SSH_IP_TUNNEL_PHASE",
+                phase.map(Phase::as_str).unwrap_or_default().to_string(),
+            ),
+            ("SSH_IP_TUNNEL_ERROR", error.to_string()),
+        ]),
+    }
+    vars
+}
+
+/// Runs `hook` for `target` if it is configured, failing if the command does
+pub async fn run(
+    hooks: &Hooks,
+    hook: Hook,
+    target: &Target,
+    outcome: Outcome<'_>,
+) -> Result<(), TunnelError> {
+    let Some(command) = hook.command(hooks) else {
+        return Ok(());
+    };
+    info!("Running the {} hook...", hook.as_str());
+    let failed = |message: String| TunnelError::Hook(format!("{}: {}", hook.as_str(), message));
+    let output = timeout(
+        HOOK_TIMEOUT,
+        process::command(SHELL[0])?
+            .arg(SHELL[1])
+            .arg(command)
+            .envs(environment(hook, target, outcome))
+            .output(),
+    )
+    .await
+    .map_err(|_| failed(format!("still running after {}s", HOOK_TIMEOUT.as_secs())))?
+    .map_err(|e| failed(e.to_string()))?;
+
+    let text = [output.stdout, output.stderr]
+        .iter()
+        .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+        .collect::<Vec<_>>()
+        .join("");
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        info!("{}: {}", hook.as_str(), line);
+    }
+    if !output.status.success() {
+        let last = text.lines().rev().find(|line| !line.trim().is_empty());
+        return Err(failed(match (output.status.code(), last) {
+            (Some(code), Some(line)) => format!("exit code {}: {}", code, line.trim()),
+            (Some(code), None) => format!("exit code {}", code),
+            (None, _) => "killed by a signal".to_string(),
+        }));
+    }
+    Ok(())
+}
+
+/// Runs `hook` like [`run`], only warning if it fails
+pub async fn notify(hooks: &Hooks, hook: Hook, target: &Target, outcome: Outcome<'_>) {
+    if let Err(e) = run(hooks, hook, target, outcome).await {
+        warn!("{}", e);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hooks_see_the_run_and_fail_with_their_command() {
+        let target = Target {
+            host: "pi.local".to_string(),
+            user: "pi".to_string(),
+            port: 2222,
+            ..Target::default()
+        };
+        let hooks = Hooks {
+            pre_tunnel: Some(
+                r#"test "$SSH_IP_TUNNEL_HOST:$SSH_IP_TUNNEL_PORT:$SSH_IP_TUNNEL_OUTCOME" = pi.local:2222:ok"#
+                    .to_string(),
+            ),
+            on_failure: Some(
+                r#"echo "$SSH_IP_TUNNEL_PHASE: $SSH_IP_TUNNEL_ERROR" >&2; exit 3"#.to_string(),
+            ),
+            ..Hooks::default()
+        };
+
+        assert!(run(&hooks, Hook::PreTunnel, &target, Outcome::Ok)
+            .await
+            .is_ok());
+        assert!(run(&hooks, Hook::PostTunnel, &target, Outcome::Ok)
+            .await
+            .is_ok());
+        let failure = Outcome::Failed {
+            phase: Some(Phase::Key),
+            error: "denied",
+        };
+        let error = run(&hooks, Hook::OnFailure, &target, failure)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, "Hook failed: on_failure: exit code 3: key: denied");
+    }
+}
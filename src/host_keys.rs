@@ -0,0 +1,502 @@
+//! Host key pinning.
+//!
+//! Every connection checks the device's host key against a known_hosts file
+//! the tool keeps for itself ([`known_hosts_path`]), recorded under the
+//! device's address ([`alias`]) rather than the `localhost:<port>` of the
+//! tunnel it is reached through. The first connection to a device records its
+//! key (trust on first use); from then on a different key fails the
+//! connection. With a fingerprint given up front, the key is checked against
+//! it before anything is recorded, so not even the first connection can be
+//! intercepted.
+//!
+//! The `known-hosts` subcommand lists and edits the file (or the user's own
+//! `~/.ssh/known_hosts`) through the functions at the end of this module.
+
+use crate::capabilities::{self, Tool};
+use crate::keys;
+use crate::output::Renderable;
+use crate::paths;
+use crate::process;
+use crate::ssh;
+use crate::ssh_agent::Identity;
+use crate::{Target, TunnelError};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::timeout;
+use tracing::{debug, info};
+
+/// Host key algorithms tried, in order, when looking for the key a pinned fingerprint belongs to
+const ALGORITHMS: &[&str] = &["ssh-ed25519", "ecdsa-sha2-nistp256", "rsa-sha2-512"];
+
+/// Upper bound for fetching one host key
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The known_hosts file the tool records device keys in
+pub fn known_hosts_path() -> PathBuf {
+    paths::state_dir().join("known_hosts")
+}
+
+/// Name `target`'s key is recorded under: its host, with the port when sshd is not on 22
+pub fn alias(target: &Target) -> String {
+    match target.remote_port {
+        Some(port) if port != 22 => format!("[{}]:{}", target.host, port),
+        _ => target.host.clone(),
+    }
+}
+
+/// Readies host key checking for `target`, pinning its fingerprint first if it has one
+pub async fn prepare(target: &Target) -> Result<(), TunnelError> {
+    let known_hosts = known_hosts_path();
+    create_parent(&known_hosts)?;
+    match &target.host_key_fingerprint {
+        Some(fingerprint) => pin(target, &known_hosts, Some(fingerprint)).await.map(drop),
+        None => Ok(()),
+    }
+}
+
+/// Records `target`'s host key in `known_hosts`, replacing what was there, and
+/// returns its fingerprint. With `fingerprint`, only a key that has it is recorded.
+async fn pin(
+    target: &Target,
+    known_hosts: &Path,
+    fingerprint: Option<&str>,
+) -> Result<String, TunnelError> {
+    let alias = alias(target);
+    if let Some(fingerprint) = fingerprint {
+        if recorded(known_hosts, &alias)
+            .await?
+            .iter()
+            .any(|recorded| recorded == fingerprint)
+        {
+            debug!("Host key {} of {} is already recorded", fingerprint, alias);
+            return Ok(fingerprint.to_string());
+        }
+    }
+
+    // Fetched keys land in a scratch file first, so a wrong key is never trusted
+    paths::private_runtime_dir()?;
+    let scratch = paths::runtime_file(&format!("hostkey-{}.tmp", target.port));
+    let mut presented = Vec::new();
+    let mut last_error = String::new();
+    for algorithm in ALGORITHMS {
+        let _ = std::fs::remove_file(&scratch);
+        match timeout(
+            PROBE_TIMEOUT,
+            ssh::host_key_probe(target, &scratch, algorithm)?.output(),
+        )
+        .await
+        {
+            Ok(Ok(output)) => {
+                last_error = String::from_utf8_lossy(&output.stderr).trim().to_string()
+            }
+            Ok(Err(e)) => last_error = e.to_string(),
+            Err(_) => last_error = format!("timeout after {}s", PROBE_TIMEOUT.as_secs()),
+        }
+        let found = recorded(&scratch, &alias).await?;
+        if let Some(key) = found
+            .iter()
+            .find(|key| fingerprint.is_none_or(|fingerprint| *key == fingerprint))
+        {
+            let result = match std::fs::read_to_string(&scratch) {
+                Ok(entries) => replace(known_hosts, &alias, &entries).await,
+                Err(e) => Err(TunnelError::HostKey(e.to_string())),
+            };
+            let _ = std::fs::remove_file(&scratch);
+            result?;
+            info!("Pinned host key {} for {}", key, alias);
+            return Ok(key.clone());
+        }
+        debug!("{} offered no matching {} key", alias, algorithm);
+        presented.extend(found);
+    }
+    let _ = std::fs::remove_file(&scratch);
+
+    Err(TunnelError::HostKey(if presented.is_empty() {
+        format!("could not fetch the host key of {}: {}", alias, last_error)
+    } else {
+        format!(
+            "{} presented {}, not the pinned {}; refusing to connect",
+            alias,
+            presented.join(", "),
+            fingerprint.unwrap_or_default()
+        )
+    }))
+}
+
+/// Fingerprints of the keys recorded for `alias` in `known_hosts`
+async fn recorded(known_hosts: &Path, alias: &str) -> Result<Vec<String>, TunnelError> {
+    if !known_hosts.exists() {
+        return Ok(Vec::new());
+    }
+    if !capabilities::has(Tool::SshKeygen) {
+        return Ok(read_entries(known_hosts)?
+            .into_iter()
+            .filter(|entry| names(&entry.host, alias))
+            .map(|entry| entry.fingerprint)
+            .collect());
+    }
+    let output = process::command("ssh-keygen")?
+        .args(["-l", "-F", alias, "-f"])
+        .arg(known_hosts)
+        .output()
+        .await
+        .map_err(|e| TunnelError::HostKey(format!("failed to run ssh-keygen: {}", e)))?;
+    Ok(parse_fingerprints(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Extracts fingerprints from `ssh-keygen -l -F` output: `<host> <TYPE> <fingerprint>` lines
+fn parse_fingerprints(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            line.split_whitespace()
+                .find(|word| word.starts_with("SHA256:"))
+                .map(str::to_string)
+        })
+        .collect()
+}
+
+/// Replaces whatever is recorded for `alias` in `known_hosts` with `entries`
+async fn replace(known_hosts: &Path, alias: &str, entries: &str) -> Result<(), TunnelError> {
+    let failed =
+        |e: String| TunnelError::HostKey(format!("cannot update {}: {}", known_hosts.display(), e));
+    if known_hosts.exists() && !capabilities::has(Tool::SshKeygen) {
+        let kept: String = std::fs::read_to_string(known_hosts)
+            .map_err(|e| failed(e.to_string()))?
+            .lines()
+            .filter(|line| {
+                !line
+                    .split_whitespace()
+                    .next()
+                    .is_some_and(|hosts| names(hosts, alias))
+            })
+            .map(|line| format!("{}\n", line))
+            .collect();
+        std::fs::write(known_hosts, kept).map_err(|e| failed(e.to_string()))?;
+    } else if known_hosts.exists() {
+        let output = process::command("ssh-keygen")?
+            .args(["-R", alias, "-f"])
+            .arg(known_hosts)
+            .output()
+            .await
+            .map_err(|e| failed(e.to_string()))?;
+        if !output.status.success() {
+            return Err(failed(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(known_hosts)
+        .map_err(|e| failed(e.to_string()))?;
+    std::io::Write::write_all(&mut file, entries.as_bytes()).map_err(|e| failed(e.to_string()))
+}
+
+/// Turns `ssh`'s complaint about a changed or unacceptable host key into an explanation
+pub fn verification_failure(target: &Target, stderr: &str) -> Option<TunnelError> {
+    if !stderr.contains("Host key verification failed")
+        && !stderr.contains("REMOTE HOST IDENTIFICATION HAS CHANGED")
+    {
+        return None;
+    }
+    let alias = alias(target);
+    if stderr.contains("you have requested strict checking") {
+        return Some(TunnelError::HostKey(format!(
+            "no host key for {} is recorded in {}, and --secure doesn't trust keys on first use. Pass --fingerprint with the device's key, or record it with `known-hosts add`",
+            alias,
+            known_hosts_path().display()
+        )));
+    }
+    Some(TunnelError::HostKey(format!(
+        "{} presented a different host key than the one recorded in {}. If the device was re-imaged, pass --fingerprint with its new key, or forget the old one with `known-hosts remove '{}'`",
+        alias,
+        known_hosts_path().display(),
+        alias
+    )))
+}
+
+/// One key in a known_hosts file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Entry {
+    /// Host names, or the hash ssh stores instead when `HashKnownHosts` is on
+    pub host: String,
+    pub key_type: String,
+    pub fingerprint: String,
+}
+
+/// The tool's known_hosts file, or with `user_file` the user's `~/.ssh/known_hosts`
+pub fn store(user_file: bool) -> Result<PathBuf, TunnelError> {
+    if user_file {
+        Ok(paths::home_dir()?.join(".ssh").join("known_hosts"))
+    } else {
+        Ok(known_hosts_path())
+    }
+}
+
+/// Lists the keys in `known_hosts`
+pub async fn list(known_hosts: &Path) -> Result<Vec<Entry>, TunnelError> {
+    if !known_hosts.exists() {
+        return Ok(Vec::new());
+    }
+    if !capabilities::has(Tool::SshKeygen) {
+        return read_entries(known_hosts);
+    }
+    let output = process::command("ssh-keygen")?
+        .arg("-lf")
+        .arg(known_hosts)
+        .output()
+        .await
+        .map_err(|e| TunnelError::HostKey(format!("failed to run ssh-keygen: {}", e)))?;
+    if !output.status.success() {
+        return Err(TunnelError::HostKey(format!(
+            "cannot read {}: {}",
+            known_hosts.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(Identity::parse)
+        .map(|identity| Entry {
+            host: identity.comment,
+            key_type: identity.key_type,
+            fingerprint: identity.fingerprint,
+        })
+        .collect())
+}
+
+/// Records the public key in `key_file` (a board's `ssh_host_*_key.pub`) for `target`
+pub async fn add_key_file(
+    known_hosts: &Path,
+    target: &Target,
+    key_file: &Path,
+) -> Result<String, TunnelError> {
+    let key = keys::read_public_key(key_file)?;
+    let alias = alias(target);
+    let line = format!("{} {} {}\n", alias, key.key_type, key.data);
+    create_parent(known_hosts)?;
+    replace(known_hosts, &alias, &line).await?;
+    recorded(known_hosts, &alias)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| TunnelError::HostKey(format!("{} is not a host key", key_file.display())))
+}
+
+/// Fetches `target`'s host key and records it, replacing what was there.
+/// With `fingerprint`, only a key that has it is accepted.
+pub async fn add_from_device(
+    known_hosts: &Path,
+    target: &Target,
+    fingerprint: Option<&str>,
+) -> Result<String, TunnelError> {
+    create_parent(known_hosts)?;
+    pin(target, known_hosts, fingerprint).await
+}
+
+/// Fetches `target`'s host key without trusting it, returning its fingerprint.
+/// With `fingerprint`, only a key that has it is accepted.
+pub async fn fetch(target: &Target, fingerprint: Option<&str>) -> Result<String, TunnelError> {
+    let file = paths::runtime_file(&format!("hostkey-{}.fetched", target.port));
+    let _ = std::fs::remove_file(&file);
+    let fingerprint = pin(target, &file, fingerprint).await;
+    let _ = std::fs::remove_file(&file);
+    fingerprint
+}
+
+/// Removes every key recorded for `host` (as listed, e.g. `[10.0.0.5]:2200`),
+/// returning how many there were
+pub async fn remove(known_hosts: &Path, host: &str) -> Result<usize, TunnelError> {
+    let found = recorded(known_hosts, host).await?.len();
+    if found > 0 {
+        replace(known_hosts, host, "").await?;
+    }
+    Ok(found)
+}
+
+/// Result of `known-hosts list` and `known-hosts export`
+#[derive(Debug, Serialize)]
+pub struct ListReport {
+    pub file: PathBuf,
+    pub entries: Vec<Entry>,
+    /// The file itself, for `export`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contents: Option<String>,
+}
+
+impl Renderable for ListReport {
+    fn to_human(&self) -> String {
+        if let Some(contents) = &self.contents {
+            return contents.trim_end().to_string();
+        }
+        if self.entries.is_empty() {
+            return format!("No host keys in {}", self.file.display());
+        }
+        self.entries
+            .iter()
+            .map(|entry| format!("{}  {}  {}", entry.host, entry.key_type, entry.fingerprint))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Result of `known-hosts add` and `known-hosts remove`
+#[derive(Debug, Serialize)]
+pub struct ChangeReport {
+    pub file: PathBuf,
+    pub host: String,
+    /// Fingerprint of the key now recorded, for `add`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Keys dropped, for `remove`
+    pub removed: usize,
+}
+
+impl Renderable for ChangeReport {
+    fn to_human(&self) -> String {
+        match &self.fingerprint {
+            Some(fingerprint) => format!(
+                "Recorded {} for {} in {}",
+                fingerprint,
+                self.host,
+                self.file.display()
+            ),
+            None if self.removed == 0 => {
+                format!("No keys for {} in {}", self.host, self.file.display())
+            }
+            None => format!(
+                "Removed {} key(s) for {} from {}",
+                self.removed,
+                self.host,
+                self.file.display()
+            ),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Whether the host names of a known_hosts line include `alias`. Hashed names
+/// never match: checking them is left to ssh-keygen.
+fn names(hosts: &str, alias: &str) -> bool {
+    hosts.split(',').any(|host| host == alias)
+}
+
+/// Key type as `ssh-keygen -l` prints it
+fn type_label(key_type: &str) -> String {
+    let sk = key_type.starts_with("sk-");
+    let name = key_type.trim_start_matches("sk-");
+    let label = match name.split('@').next().unwrap_or(name) {
+        "ssh-ed25519" => "ED25519",
+        "ssh-rsa" => "RSA",
+        "ssh-dss" => "DSA",
+        name if name.starts_with("ecdsa-") => "ECDSA",
+        name => return name.to_uppercase(),
+    };
+    if sk {
+        format!("{}-SK", label)
+    } else {
+        label.to_string()
+    }
+}
+
+/// Reads the keys in `known_hosts` without ssh-keygen, skipping markers
+/// (`@revoked`, `@cert-authority`) and anything unreadable
+fn read_entries(known_hosts: &Path) -> Result<Vec<Entry>, TunnelError> {
+    let text = std::fs::read_to_string(known_hosts).map_err(|e| {
+        TunnelError::HostKey(format!("cannot read {}: {}", known_hosts.display(), e))
+    })?;
+    Ok(text
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let host = fields.next().filter(|host| !host.starts_with(['#', '@']))?;
+            let key =
+                keys::PublicKey::parse(&format!("{} {}", fields.next()?, fields.next()?)).ok()?;
+            Some(Entry {
+                host: host.to_string(),
+                key_type: type_label(&key.key_type),
+                fingerprint: key.fingerprint(),
+            })
+        })
+        .collect())
+}
+
+fn create_parent(known_hosts: &Path) -> Result<(), TunnelError> {
+    match known_hosts.parent() {
+        Some(dir) => std::fs::create_dir_all(dir)
+            .map_err(|e| TunnelError::HostKey(format!("cannot create {}: {}", dir.display(), e))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alias_includes_non_default_ports() {
+        let mut target = Target {
+            host: "10.0.0.5".to_string(),
+            ..Target::default()
+        };
+        assert_eq!(alias(&target), "10.0.0.5");
+        target.remote_port = Some(22);
+        assert_eq!(alias(&target), "10.0.0.5");
+        target.remote_port = Some(2200);
+        assert_eq!(alias(&target), "[10.0.0.5]:2200");
+    }
+
+    #[test]
+    fn test_parse_fingerprints() {
+        let output = "# Host [10.0.0.5]:2200 found: line 1 \n\
+            [10.0.0.5]:2200 ED25519 SHA256:3F26rDROxqcR+yemtKr0e6wMtzZEode3kzQ9WaEOdTs\n\
+            # Host [10.0.0.5]:2200 found: line 2 \n\
+            [10.0.0.5]:2200 RSA SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s\n";
+        assert_eq!(
+            parse_fingerprints(output),
+            vec![
+                "SHA256:3F26rDROxqcR+yemtKr0e6wMtzZEode3kzQ9WaEOdTs",
+                "SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s"
+            ]
+        );
+        assert!(parse_fingerprints("").is_empty());
+    }
+
+    #[test]
+    fn test_read_entries_without_ssh_keygen() {
+        let key =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGEzQlRajRYThuX5pTiDmDWdC828KQA2dbkn0CUKBSWy";
+        let file = std::env::temp_dir().join(format!("known_hosts_test_{}", std::process::id()));
+        std::fs::write(
+            &file,
+            format!(
+                "# comment\npi.local,10.0.0.5 {}\n|1|abc=|def= {}\n@revoked * {}\n",
+                key, key, key
+            ),
+        )
+        .unwrap();
+        let entries = read_entries(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].key_type, "ED25519");
+        assert_eq!(
+            entries[0].fingerprint,
+            "SHA256:Aus6cWX/Pi5lncKcIUlkd22kHXb7dg8UsJBk+UbluZ0"
+        );
+        assert!(names(&entries[0].host, "10.0.0.5"));
+        assert!(!names(&entries[1].host, "pi.local"));
+        assert_eq!(type_label("sk-ecdsa-sha2-nistp256@openssh.com"), "ECDSA-SK");
+    }
+}
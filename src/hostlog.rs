@@ -0,0 +1,160 @@
+//! Per-host log files for group runs.
+//!
+//! Every event recorded inside a host's span (see [`crate::fleet::run_fleet`])
+//! is appended to `<dir>/<host>.log` at debug level, independent of the console
+//! filter, so a terse console run still leaves a full trail for each device.
+
+use chrono::Utc;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Name of the span that [`crate::fleet::run_fleet`] opens around each host
+const HOST_SPAN: &str = "host";
+
+/// Writes each host's events to its own file
+pub struct HostLogLayer {
+    dir: PathBuf,
+    files: Mutex<HashMap<String, File>>,
+}
+
+/// Host name stored in the extensions of a host span
+struct HostName(String);
+
+impl HostLogLayer {
+    /// Creates the layer, creating `dir` if needed
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            files: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn append(&self, host: &str, line: &str) {
+        let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        if !files.contains_key(host) {
+            let path = self.dir.join(format!("{}.log", file_stem(host)));
+            // Nowhere to report a failure from inside the logger; the console log still has it
+            let Ok(file) = OpenOptions::new().create(true).append(true).open(path) else {
+                return;
+            };
+            files.insert(host.to_string(), file);
+        }
+        if let Some(file) = files.get_mut(host) {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
+
+impl<S> Layer<S> for HostLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if attrs.metadata().name() != HOST_SPAN {
+            return;
+        }
+        let mut fields = FieldText::default();
+        attrs.record(&mut fields);
+        if let (Some(name), Some(span)) = (fields.name, ctx.span(id)) {
+            span.extensions_mut().insert(HostName(name));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(host) = ctx.event_scope(event).and_then(|scope| {
+            scope
+                .from_root()
+                .find_map(|span| span.extensions().get::<HostName>().map(|h| h.0.clone()))
+        }) else {
+            return;
+        };
+
+        let mut fields = FieldText::default();
+        event.record(&mut fields);
+        let line = format!(
+            "{} {:>5} {}\n",
+            Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            event.metadata().level(),
+            fields.text
+        );
+        self.append(&host, &line);
+    }
+}
+
+/// Collects an event's message and fields as text, and a span's `name` field
+#[derive(Default)]
+struct FieldText {
+    text: String,
+    name: Option<String>,
+}
+
+impl Visit for FieldText {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "name" {
+            self.name = Some(value.to_string());
+        }
+        self.record_debug(field, &format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.text, "{:?}", value);
+        } else {
+            if field.name() == "name" && self.name.is_none() {
+                self.name = Some(format!("{:?}", value));
+            }
+            let _ = write!(self.text, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Turns a host name into a safe file name
+fn file_stem(host: &str) -> String {
+    host.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::{info, info_span};
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn test_events_are_routed_to_their_host_file() {
+        let dir = std::env::temp_dir().join(format!("hostlog-test-{}", std::process::id()));
+        let layer = HostLogLayer::new(&dir).unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            info!("outside any host");
+            info_span!("host", name = "pi1").in_scope(|| info!("hello from pi1"));
+            info_span!("host", name = "../pi2").in_scope(|| info!(port = 2223, "hello"));
+        });
+
+        let pi1 = std::fs::read_to_string(dir.join("pi1.log")).unwrap();
+        assert!(pi1.contains("INFO hello from pi1"), "{}", pi1);
+        assert!(!pi1.contains("outside"));
+        let pi2 = std::fs::read_to_string(dir.join(".._pi2.log")).unwrap();
+        assert!(pi2.contains("hello port=2223"), "{}", pi2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,347 @@
+//! A small HTTP forward proxy for devices without a route to the internet.
+//!
+//! It serves `CONNECT` tunnels (HTTPS and anything else TLS) and plain
+//! `http://` requests in absolute form, which is all `curl`, `wget`, `pip` and
+//! apt send to a proxy. Plain requests are passed on with `Connection: close`,
+//! so each connection carries one request and nothing has to parse responses.
+//! The proxy only listens on this machine's loopback interface; devices reach
+//! it through a reverse forward (see [`crate::forward`]).
+//!
+//! Destinations are resolved here and refused (403) when every address of
+//! theirs is loopback, link-local or unspecified, so a device can't use the
+//! proxy to reach services that only listen on this machine, or cloud metadata
+//! endpoints such as `169.254.169.254`.
+
+use crate::TunnelError;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{lookup_host, TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+/// Longest request head accepted, in bytes
+const MAX_HEAD: usize = 64 * 1024;
+
+/// Headers meant for the proxy, or replaced by it, which are not passed on
+const HOP_BY_HOP: [&str; 4] = [
+    "connection",
+    "proxy-connection",
+    "proxy-authorization",
+    "keep-alive",
+];
+
+/// A running proxy; dropping it stops accepting connections
+pub struct HttpProxy {
+    port: u16,
+    task: JoinHandle<()>,
+}
+
+impl HttpProxy {
+    /// Starts serving on `127.0.0.1:port`
+    pub async fn start(port: u16) -> Result<Self, TunnelError> {
+        Self::listen(port, is_reachable).await
+    }
+
+    /// Starts serving on `127.0.0.1:port`, connecting only to addresses
+    /// `reachable` accepts
+    async fn listen(port: u16, reachable: fn(IpAddr) -> bool) -> Result<Self, TunnelError> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.map_err(|e| {
+            TunnelError::Forward(format!("HTTP proxy on 127.0.0.1:{}: {}", port, e))
+        })?;
+        let port = listener
+            .local_addr()
+            .map(|addr| addr.port())
+            .unwrap_or(port);
+        let task = tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    if let Err(e) = serve(client, reachable).await {
+                        debug!("HTTP proxy: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(Self { port, task })
+    }
+
+    /// The port the proxy listens on
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for HttpProxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Where a request goes and what to send there first
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+    /// `CONNECT host:port`: answer 200, then relay bytes both ways
+    Connect { authority: String },
+    /// A plain HTTP request, rewritten to origin form
+    Forward { authority: String, head: String },
+}
+
+/// Parses a request head (request line and headers, without the blank line)
+fn parse(head: &str) -> Result<Request, String> {
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(uri), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(format!("malformed request line {:?}", request_line));
+    };
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        if !uri.contains(':') {
+            return Err(format!("CONNECT needs host:port, got {:?}", uri));
+        }
+        return Ok(Request::Connect {
+            authority: uri.to_string(),
+        });
+    }
+
+    let Some(rest) = uri.strip_prefix("http://") else {
+        return Err(format!(
+            "only http:// URLs and CONNECT are proxied, got {:?}",
+            uri
+        ));
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(format!("no host in {:?}", uri));
+    }
+    let authority = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+
+    let mut rewritten = format!("{} {} {}\r\n", method, path, version);
+    for line in lines {
+        let name = line.split(':').next().unwrap_or_default().trim();
+        if !HOP_BY_HOP.iter().any(|hop| name.eq_ignore_ascii_case(hop)) {
+            rewritten.push_str(line);
+            rewritten.push_str("\r\n");
+        }
+    }
+    rewritten.push_str("Connection: close\r\n\r\n");
+    Ok(Request::Forward {
+        authority,
+        head: rewritten,
+    })
+}
+
+/// Whether the proxy may connect to `ip`: anything but this machine and
+/// link-local networks
+fn is_reachable(ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    let link_local = match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    };
+    !(ip.is_loopback() || ip.is_unspecified() || link_local)
+}
+
+/// The addresses of `authority` the proxy may connect to; an error names
+/// why there are none
+async fn resolve(
+    authority: &str,
+    reachable: fn(IpAddr) -> bool,
+) -> Result<Vec<SocketAddr>, &'static str> {
+    let addresses: Vec<SocketAddr> = lookup_host(authority)
+        .await
+        .map_err(|_| "502 Bad Gateway")?
+        .collect();
+    let allowed: Vec<SocketAddr> = addresses
+        .iter()
+        .copied()
+        .filter(|address| reachable(address.ip()))
+        .collect();
+    match (addresses.is_empty(), allowed.is_empty()) {
+        (true, _) => Err("502 Bad Gateway"),
+        (false, true) => Err("403 Forbidden"),
+        (false, false) => Ok(allowed),
+    }
+}
+
+/// Handles one client connection
+async fn serve(client: TcpStream, reachable: fn(IpAddr) -> bool) -> std::io::Result<()> {
+    let mut reader = BufReader::new(client);
+    let mut head = String::new();
+    loop {
+        // A line longer than what's left of MAX_HEAD is cut off there, so
+        // the check below stops a client that never sends a newline
+        let left = (MAX_HEAD + 1).saturating_sub(head.len()) as u64;
+        let read = (&mut reader).take(left).read_line(&mut head).await?;
+        if read == 0 || head.len() > MAX_HEAD {
+            return Ok(());
+        }
+        if head.ends_with("\r\n\r\n") || head == "\r\n" {
+            break;
+        }
+    }
+    // Whatever the client sent after the head, e.g. the start of a request body
+    let early = reader.buffer().to_vec();
+    let mut client = reader.into_inner();
+
+    let request = match parse(head.trim_end_matches("\r\n")) {
+        Ok(request) => request,
+        Err(e) => {
+            debug!("HTTP proxy: {}", e);
+            return client
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")
+                .await;
+        }
+    };
+    let authority = match &request {
+        Request::Connect { authority } | Request::Forward { authority, .. } => authority,
+    };
+    debug!("HTTP proxy: {}", head.lines().next().unwrap_or_default());
+    let addresses = match resolve(authority, reachable).await {
+        Ok(addresses) => addresses,
+        Err(status) => {
+            debug!("HTTP proxy: {} for {}", status, authority);
+            return client
+                .write_all(format!("HTTP/1.1 {}\r\nConnection: close\r\n\r\n", status).as_bytes())
+                .await;
+        }
+    };
+    let mut upstream = match TcpStream::connect(addresses.as_slice()).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            debug!("HTTP proxy: connecting to {}: {}", authority, e);
+            return client
+                .write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n")
+                .await;
+        }
+    };
+    match &request {
+        Request::Connect { .. } => {
+            client
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await?
+        }
+        Request::Forward { head, .. } => upstream.write_all(head.as_bytes()).await?,
+    }
+    upstream.write_all(&early).await?;
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_parse_rewrites_absolute_requests() {
+        let request = parse(
+            "GET http://deb.debian.org/debian/dists/bookworm/InRelease HTTP/1.1\r\nHost: deb.debian.org\r\nProxy-Connection: keep-alive\r\nConnection: keep-alive",
+        )
+        .unwrap();
+        assert_eq!(
+            request,
+            Request::Forward {
+                authority: "deb.debian.org:80".to_string(),
+                head: "GET /debian/dists/bookworm/InRelease HTTP/1.1\r\nHost: deb.debian.org\r\nConnection: close\r\n\r\n".to_string(),
+            }
+        );
+
+        assert_eq!(
+            parse("CONNECT pypi.org:443 HTTP/1.1\r\nHost: pypi.org:443").unwrap(),
+            Request::Connect {
+                authority: "pypi.org:443".to_string()
+            }
+        );
+        assert!(parse("GET /index.html HTTP/1.1").is_err());
+        assert!(parse("CONNECT pypi.org HTTP/1.1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_relays_plain_requests() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = origin.accept().await.unwrap();
+            let mut received = vec![0; 1024];
+            let n = stream.read(&mut received).await.unwrap();
+            let request = String::from_utf8_lossy(&received[..n]).into_owned();
+            let body = request.lines().next().unwrap().to_string();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let proxy = HttpProxy::listen(0, |_| true).await.unwrap();
+        let mut client = TcpStream::connect(("127.0.0.1", proxy.port()))
+            .await
+            .unwrap();
+        client
+            .write_all(
+                format!(
+                    "GET http://127.0.0.1:{}/pool/a.deb HTTP/1.1\r\nHost: x\r\n\r\n",
+                    origin_port
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(
+            response.ends_with("GET /pool/a.deb HTTP/1.1"),
+            "{}",
+            response
+        );
+    }
+
+    async fn answer(proxy: &HttpProxy, request: &[u8]) -> String {
+        let mut client = TcpStream::connect(("127.0.0.1", proxy.port()))
+            .await
+            .unwrap();
+        let _ = client.write_all(request).await;
+        let mut response = String::new();
+        let _ = client.read_to_string(&mut response).await;
+        response
+    }
+
+    #[tokio::test]
+    async fn test_proxy_refuses_this_machine_and_long_heads() {
+        assert!(!is_reachable("127.0.0.1".parse().unwrap()));
+        assert!(!is_reachable("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!is_reachable("169.254.169.254".parse().unwrap()));
+        assert!(!is_reachable("fe80::1".parse().unwrap()));
+        assert!(!is_reachable("0.0.0.0".parse().unwrap()));
+        assert!(is_reachable("192.168.1.20".parse().unwrap()));
+        assert!(is_reachable("2a00:1450::1".parse().unwrap()));
+
+        let proxy = HttpProxy::start(0).await.unwrap();
+        let local = answer(&proxy, b"CONNECT localhost:22 HTTP/1.1\r\n\r\n").await;
+        assert!(local.starts_with("HTTP/1.1 403 Forbidden"), "{}", local);
+        let metadata = answer(
+            &proxy,
+            b"GET http://169.254.169.254/latest/meta-data/ HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(
+            metadata.starts_with("HTTP/1.1 403 Forbidden"),
+            "{}",
+            metadata
+        );
+
+        let endless = vec![b'a'; MAX_HEAD * 2];
+        assert_eq!(answer(&proxy, &endless).await, "");
+    }
+}
@@ -0,0 +1,165 @@
+//! `info`: a report of what a device is, for inventories.
+//!
+//! The architecture probe of `up` says which board and operating system it
+//! is; one more round trip reads memory, the root filesystem, uptime and the
+//! SSH server's version. The report prints as text, or with `--format` as a
+//! bare JSON or TOML document, e.g. to collect a lab's devices in one file.
+
+use crate::config::Config;
+use crate::output::{self, Renderable};
+use crate::pure::info::{format_mb, DeviceInfo};
+use crate::shell::RemoteCommand;
+use crate::ssh;
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use chrono::Utc;
+use clap::ValueEnum;
+use std::time::Duration;
+use tracing::info;
+
+/// Upper bound for reading the device's resources
+const INFO_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Prints the lines [`DeviceInfo::parse`] reads. sshd is often outside a
+/// user's PATH, and only recent versions know `-V`; older ones print their
+/// version in the usage message instead.
+pub const INFO_SCRIPT: &str = r#"awk '/^MemTotal:/ { print "mem_total_kb=" $2 } /^MemAvailable:/ { print "mem_available_kb=" $2 }' /proc/meminfo 2>/dev/null
+df -Pk / 2>/dev/null | awk 'NR == 2 { print "disk_total_kb=" $2; print "disk_used_kb=" $3; print "disk_available_kb=" $4 }'
+if [ -r /proc/uptime ]; then echo "uptime=$(cut -d' ' -f1 /proc/uptime)"; fi
+for sshd in "$(command -v sshd)" /usr/sbin/sshd /usr/local/sbin/sshd; do
+  if [ -x "$sshd" ]; then
+    echo "sshd=$("$sshd" -V 2>&1 | sed -n 's/.*\(OpenSSH_[^ ,]*\).*/\1/p' | head -n 1)"
+    break
+  fi
+done
+if command -v dropbear >/dev/null 2>&1; then
+  echo "dropbear=$(dropbear -V 2>&1 | sed -n 's/.*Dropbear v\([^ ]*\).*/\1/p' | head -n 1)"
+fi
+true"#;
+
+/// How `info` prints its report
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum InfoFormat {
+    /// One line per fact
+    #[default]
+    Text,
+    /// The report alone as a JSON document
+    Json,
+    /// The report alone as a TOML document
+    Toml,
+}
+
+/// Result of `info`
+#[derive(Debug)]
+pub struct InfoReport {
+    pub info: DeviceInfo,
+    pub format: InfoFormat,
+}
+
+impl InfoReport {
+    fn to_text(&self) -> String {
+        let info = &self.info;
+        let mut rows = vec![
+            ("Host", info.host.clone()),
+            ("Device", info.device.describe()),
+        ];
+        if let Some(os) = &info.device.os {
+            rows.push(("OS", os.describe()));
+        }
+        if let Some(memory) = &info.memory {
+            rows.push((
+                "Memory",
+                match memory.available_mb {
+                    Some(available) => format!(
+                        "{} available of {}",
+                        format_mb(available),
+                        format_mb(memory.total_mb)
+                    ),
+                    None => format_mb(memory.total_mb),
+                },
+            ));
+        }
+        if let Some(disk) = &info.disk {
+            rows.push((
+                "Disk",
+                format!(
+                    "{} free of {} on /",
+                    format_mb(disk.available_mb),
+                    format_mb(disk.total_mb)
+                ),
+            ));
+        }
+        if let Some(secs) = info.uptime_secs {
+            let uptime = if secs < 86_400 {
+                output::format_duration(Duration::from_secs(secs))
+            } else {
+                format!("{}d{:02}h", secs / 86_400, secs % 86_400 / 3_600)
+            };
+            rows.push(("Uptime", uptime));
+        }
+        if let Some(sshd) = &info.sshd {
+            rows.push(("sshd", sshd.clone()));
+        }
+        let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0) + 1;
+        rows.iter()
+            .map(|(name, value)| format!("{:<width$} {}", format!("{}:", name), value))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl Renderable for InfoReport {
+    fn to_human(&self) -> String {
+        match self.format {
+            InfoFormat::Text => self.to_text(),
+            InfoFormat::Json => serde_json::to_string_pretty(&self.info).unwrap_or_default(),
+            InfoFormat::Toml => toml::to_string_pretty(&self.info)
+                .unwrap_or_default()
+                .trim_end()
+                .to_string(),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(&self.info).unwrap_or_default()
+    }
+}
+
+/// Gathers the report of `target`, reusing its tunnel if one is open
+pub async fn gather(config: &Config, target: &Target) -> Result<DeviceInfo> {
+    let manager = SSHTunnelManager::new(config.clone());
+    manager.reuse_or_connect(target).await?;
+    let device = manager.probe(target).await?;
+
+    info!("Reading the resources of {}...", target.host);
+    let command = RemoteCommand::new("sh").arg("-c").arg(INFO_SCRIPT);
+    let stdout = ssh::run_through_tunnel(target, &command, INFO_TIMEOUT)
+        .await
+        .map_err(|e| e.into_error(TunnelError::Info))?;
+    Ok(DeviceInfo::parse(&target.host, device, &stdout, Utc::now()))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::pure::board::TargetInfo;
+
+    #[tokio::test]
+    async fn test_info_script_reads_this_machine() {
+        let output = tokio::process::Command::new("sh")
+            .args(["-c", INFO_SCRIPT])
+            .output()
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        let device = TargetInfo::parse("arch=x86_64\n").unwrap();
+        let info = DeviceInfo::parse(
+            "localhost",
+            device,
+            &String::from_utf8_lossy(&output.stdout),
+            Utc::now(),
+        );
+        assert!(info.memory.is_some_and(|memory| memory.total_mb > 0));
+        assert!(info.uptime_secs.is_some());
+    }
+}
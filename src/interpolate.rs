@@ -0,0 +1,88 @@
+//! `${VAR}` references in configuration values.
+//!
+//! Names resolve against the config file's `[vars]` table first and the process
+//! environment second, so one file can be shared between machines and users.
+//! `$$` stands for a literal `$`; a `$` not followed by `{` is kept as is.
+
+use std::collections::BTreeMap;
+
+/// Resolves a variable name from the environment
+pub type Env<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Reads a variable from the process environment.
+///
+/// `HOME` and `USER` fall back to the platform's idea of the current user, as
+/// they are often unset in containers and service managers.
+#[cfg(feature = "runtime")]
+pub fn process_env(name: &str) -> Option<String> {
+    let value = std::env::var(name).ok().filter(|v| !v.is_empty());
+    match name {
+        "HOME" => value.or_else(|| dirs::home_dir().map(|p| p.display().to_string())),
+        "USER" => value.or_else(|| std::env::var("LOGNAME").ok().filter(|v| !v.is_empty())),
+        _ => value,
+    }
+}
+
+/// Expands every reference in `value`, naming the first undefined or malformed one on error
+pub fn expand(value: &str, vars: &BTreeMap<String, String>, env: Env) -> Result<String, String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(index) = rest.find('$') {
+        expanded.push_str(&rest[..index]);
+        rest = &rest[index..];
+
+        if let Some(after) = rest.strip_prefix("$$") {
+            expanded.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unterminated variable reference in {:?}", value))?;
+            let name = &after[..end];
+            if !is_valid_name(name) {
+                return Err(format!("invalid variable name {:?}", name));
+            }
+            let resolved = vars
+                .get(name)
+                .cloned()
+                .or_else(|| env(name))
+                .ok_or_else(|| format!("undefined variable ${{{}}}", name))?;
+            expanded.push_str(&resolved);
+            rest = &after[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let vars = BTreeMap::from([("LAB".to_string(), "lab-a".to_string())]);
+        let env = |name: &str| (name == "HOME").then(|| "/home/pi".to_string());
+
+        assert_eq!(
+            expand("${HOME}/.ssh/${LAB}.pub", &vars, &env).unwrap(),
+            "/home/pi/.ssh/lab-a.pub"
+        );
+        assert_eq!(expand("$$HOME $x", &vars, &env).unwrap(), "$HOME $x");
+
+        let err = expand("${NOPE}/key", &vars, &env).unwrap_err();
+        assert_eq!(err, "undefined variable ${NOPE}");
+        assert!(expand("${HOME", &vars, &env).is_err());
+        assert!(expand("${1X}", &vars, &env).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+//! Tunnels to ARM devices over SSH, and provisioning them through it.
+//!
+//! The stable API is what this root re-exports: [`SSHTunnelManager`] to open
+//! the tunnel and provision a device, [`Target`] to say which device and how,
+//! [`TunnelError`] and [`PhaseError`] for what went wrong, [`Event`] with
+//! [`on_event`] to follow a run as it happens, and [`pure`], the logic that
+//! needs no device, for checking configuration files and rendering plans
+//! elsewhere, such as in a browser. It follows semver: error and event enums
+//! and report structs are `#[non_exhaustive]`, so new variants and fields
+//! arrive in minor releases. Everything else is internal to the
+//! `ssh-ip-tunnel` binary and may change in any release.
+
+// Helpers shared with the runtime modules go unused in a build of the pure module alone
+#![cfg_attr(not(feature = "runtime"), allow(dead_code))]
+
+#[cfg(feature = "runtime")]
+mod agent;
+#[cfg(feature = "runtime")]
+mod api;
+#[cfg(feature = "runtime")]
+mod artifact;
+#[cfg(feature = "runtime")]
+mod askpass;
+#[cfg(feature = "runtime")]
+mod authorized_keys;
+#[cfg(feature = "runtime")]
+mod bench;
+#[cfg(feature = "runtime")]
+mod capabilities;
+#[cfg(feature = "runtime")]
+mod certs;
+#[cfg(feature = "runtime")]
+mod checksum;
+#[cfg(feature = "runtime")]
+#[doc(hidden)]
+pub mod cli;
+#[cfg(feature = "runtime")]
+mod clock;
+#[cfg(feature = "runtime")]
+mod completions;
+#[cfg(feature = "runtime")]
+mod config;
+#[cfg(feature = "runtime")]
+mod container;
+#[cfg(feature = "runtime")]
+mod control;
+#[cfg(feature = "runtime")]
+mod copy;
+#[cfg(feature = "runtime")]
+mod desktop;
+#[cfg(feature = "runtime")]
+mod device_profile;
+#[cfg(feature = "runtime")]
+mod dns;
+mod env;
+mod error;
+#[cfg(feature = "runtime")]
+mod exec;
+#[cfg(feature = "runtime")]
+mod executor;
+#[cfg(feature = "runtime")]
+mod exit;
+#[cfg(feature = "runtime")]
+mod facts;
+#[cfg(feature = "runtime")]
+mod fault;
+#[cfg(feature = "runtime")]
+mod fetch;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "runtime")]
+mod flash;
+#[cfg(feature = "runtime")]
+mod fleet;
+#[cfg(feature = "runtime")]
+mod forward;
+#[cfg(feature = "runtime")]
+mod harden;
+#[cfg(feature = "runtime")]
+mod hardware;
+#[cfg(feature = "runtime")]
+mod health;
+#[cfg(feature = "runtime")]
+mod history;
+#[cfg(feature = "runtime")]
+mod hooks;
+#[cfg(feature = "runtime")]
+mod host_keys;
+#[cfg(feature = "runtime")]
+mod hostlog;
+#[cfg(feature = "runtime")]
+mod http_proxy;
+#[cfg(feature = "runtime")]
+mod info;
+mod interpolate;
+#[cfg(feature = "runtime")]
+mod keys;
+#[cfg(feature = "runtime")]
+mod man;
+#[cfg(feature = "runtime")]
+mod mirror;
+#[cfg(feature = "runtime")]
+mod onboard;
+#[cfg(feature = "runtime")]
+mod output;
+#[cfg(feature = "runtime")]
+mod paths;
+#[cfg(feature = "runtime")]
+mod phase;
+#[cfg(feature = "runtime")]
+mod picker;
+#[cfg(feature = "runtime")]
+mod ping;
+#[cfg(feature = "runtime")]
+mod process;
+#[cfg(feature = "runtime")]
+mod prompt;
+#[cfg(feature = "runtime")]
+mod provision;
+pub mod pure;
+#[cfg(feature = "runtime")]
+mod push;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "runtime")]
+mod rotate;
+#[cfg(feature = "runtime")]
+mod rsync;
+#[cfg(feature = "runtime")]
+mod run;
+#[cfg(feature = "runtime")]
+mod service;
+#[cfg(feature = "runtime")]
+mod shell;
+#[cfg(feature = "runtime")]
+mod simulate;
+#[cfg(feature = "runtime")]
+mod snapshot;
+#[cfg(feature = "runtime")]
+mod spinner;
+#[cfg(feature = "runtime")]
+mod ssh;
+#[cfg(feature = "runtime")]
+mod ssh_agent;
+#[cfg(feature = "runtime")]
+mod ssh_config;
+#[cfg(feature = "runtime")]
+mod sshd;
+#[cfg(feature = "runtime")]
+mod swap;
+#[cfg(feature = "runtime")]
+mod systemd;
+mod template;
+#[cfg(feature = "runtime")]
+mod timing;
+#[cfg(feature = "runtime")]
+mod tui;
+#[cfg(feature = "runtime")]
+mod tunnel;
+#[cfg(feature = "runtime")]
+mod update;
+mod validate;
+#[cfg(feature = "runtime")]
+mod watch;
+#[cfg(feature = "runtime")]
+mod webhooks;
+
+#[cfg(feature = "runtime")]
+pub use askpass::Password;
+#[cfg(feature = "runtime")]
+pub use clock::Adjustment as ClockAdjustment;
+#[cfg(feature = "runtime")]
+pub use config::load_config;
+pub use error::TunnelError;
+#[cfg(feature = "runtime")]
+pub use harden::HardenReport;
+#[cfg(feature = "runtime")]
+pub use hardware::HardwareReport;
+#[cfg(feature = "runtime")]
+pub use output::{on_event, Event};
+#[cfg(feature = "runtime")]
+pub use phase::{Phase, PhaseError, Phases};
+pub use pure::board::TargetInfo;
+pub use pure::config::{Config, Hooks, Webhooks};
+pub use pure::hardware::{HardwareConfig, Interface};
+pub use pure::os::{OsInfo, OsRequirements};
+pub use pure::swap::SwapMode;
+#[cfg(feature = "runtime")]
+pub use swap::SwapOutcome;
+#[cfg(feature = "runtime")]
+pub use tunnel::{RunReport, SSHTunnelManager, Target, TargetBuilder};
@@ -0,0 +1,282 @@
+//! `docs man`: the manual page, e.g. for distribution packages.
+//!
+//! Options and commands come from the command line definition, so they
+//! can't drift from `--help`. Options that the top level takes too, such as
+//! the target options, are described once under OPTIONS. Configuration keys,
+//! the other environment variables and exit codes are written out here; a
+//! test checks that every key of the configuration file is. The variables of
+//! target options are the ones their parsing actually reads, and the events
+//! come from [`output::EVENTS`].
+
+use crate::config;
+use crate::env::PREFIX;
+use crate::exit;
+use crate::output;
+use clap::{Arg, Command};
+use clap_mangen::roff::{bold, italic, roman, Inline, Roff};
+use clap_mangen::Man;
+use std::io::{self, Write};
+
+/// Top-level keys of the configuration file
+const CONFIG_KEYS: &[(&str, &str)] = &[
+    ("default_key_path", "Public key to install; unset, the standard keys are searched as for --key"),
+    ("default_port", "Local tunnel port (default 2222)"),
+    ("tunnel_timeout_secs", "Seconds to wait for the tunnel to answer (default 30)"),
+    ("max_retries", "Times push resumes an upload the connection dropped (default 3)"),
+    ("skip_arch_validation", "Accept devices of any architecture"),
+    ("allowed_architectures", "Architectures devices may have, as uname -m names them; ARM by default, any when empty"),
+    ("require_os", "Operating system devices must run, as uname -s names it"),
+    ("require_distro", "Distributions (ID in /etc/os-release) devices may run"),
+    ("min_kernel", "Oldest kernel devices may run, e.g. \"5.10\""),
+    ("secure", "Secure mode (--secure) for every device"),
+    ("swap", "Swap for boards with little RAM: off, auto, zram or file"),
+    ("hardware", "Interfaces, overlays and modules up enables"),
+    ("device_profiles.<name>", "Board configuration applied by apply-profile"),
+    ("groups.<name>", "Host profile names targeted by up --group <name>"),
+    ("hosts.<name>", "Host profile, selected with up <name>: host, user, port, key_path and most target options"),
+    ("vars.<NAME>", "Custom variable for ${NAME} references"),
+    ("hooks", "Local commands run around the phases of up: pre_tunnel, post_tunnel, pre_key_transfer, post_key_transfer, on_failure"),
+    ("webhooks", "URLs sent JSON about up: on_up, on_down, on_key_deployed"),
+    ("artifacts", "Directory, or path or URL pattern with {arch}, of per-architecture agent builds"),
+];
+
+/// Variables read besides those of the target options and settings, by
+/// their suffix
+const ENVIRONMENT: &[(&str, &str)] = &[
+    ("CONFIG", "Configuration file, as --config"),
+    ("NAMESPACE", "Namespace, as --namespace"),
+    ("BATCH", "Batch mode, as --batch (1, true, yes or on)"),
+    ("PROFILE", "Host profile, as up <PROFILE>"),
+    (
+        "API_TOKEN",
+        "Token clients of --api-listen send as Authorization: Bearer",
+    ),
+    (
+        "FAULT",
+        "Failure points up stops at on purpose, comma-separated, for testing scripts",
+    ),
+];
+
+/// Writes the manual page of `cmd`, the whole command line, whose target
+/// options are also read from the variables with suffixes `target_variables`
+pub fn render(cmd: Command, target_variables: &[String], out: &mut dyn Write) -> io::Result<()> {
+    let mut cmd = cmd
+        .name(env!("CARGO_PKG_NAME"))
+        .bin_name(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"));
+    cmd.build();
+    let man = Man::new(cmd.clone());
+    man.render_title(out)?;
+    man.render_name_section(out)?;
+    man.render_synopsis_section(out)?;
+    man.render_description_section(out)?;
+    man.render_options_section(out)?;
+
+    let mut roff = Roff::new();
+    roff.control("SH", ["COMMANDS"]);
+    let shared: Vec<&Arg> = cmd.get_arguments().collect();
+    for sub in cmd.get_subcommands() {
+        command(&mut roff, env!("CARGO_PKG_NAME"), sub, &shared);
+    }
+
+    roff.control("SH", ["CONFIGURATION"]);
+    roff.text([roman(
+        "Read from --config, else $SSH_IP_TUNNEL_CONFIG, else config.toml in the user's \
+         configuration directory (e.g. ~/.config/ssh_ip_tunnel). Command-line options \
+         override the environment, which overrides the file.",
+    )]);
+    for (key, description) in CONFIG_KEYS {
+        roff.control("TP", [])
+            .text([bold(*key)])
+            .text([roman(*description)]);
+    }
+
+    roff.control("SH", ["ENVIRONMENT"]);
+    roff.text([roman(
+        "Command-line options override these. Switches take 1, true, yes or on, and \
+         lists are comma-separated.",
+    )]);
+    for (suffix, description) in ENVIRONMENT {
+        roff.control("TP", [])
+            .text([bold(format!("{}{}", PREFIX, suffix))])
+            .text([roman(*description)]);
+    }
+    for suffix in target_variables {
+        if ENVIRONMENT.iter().any(|(described, _)| described == suffix) {
+            continue;
+        }
+        let long = suffix.to_lowercase().replace('_', "-");
+        if cmd.get_arguments().any(|arg| arg.get_long() == Some(&long)) {
+            let description = match config::ENV_SETTINGS.iter().find(|(_, s)| s == suffix) {
+                Some((key, _)) => format!("As --{}, and overrides {}", long, key),
+                None => format!("As --{}", long),
+            };
+            roff.control("TP", [])
+                .text([bold(format!("{}{}", PREFIX, suffix))])
+                .text([roman(description)]);
+        }
+    }
+    for (key, suffix) in config::ENV_SETTINGS {
+        if target_variables.iter().any(|variable| variable == suffix) {
+            continue;
+        }
+        roff.control("TP", [])
+            .text([bold(format!("{}{}", PREFIX, suffix))])
+            .text([roman(format!("Overrides {}", key))]);
+    }
+    roff.control("TP", [])
+        .text([bold("RUST_LOG")])
+        .text([roman("Log filter, e.g. debug")]);
+
+    roff.control("SH", ["EVENTS"]);
+    roff.text([roman(
+        "With --events ndjson, or --output ndjson, stdout has a JSON object per line for \
+         each event: its event field names it, run_id the run, and the fields below \
+         (bracketed ones only when known) tell the rest. A result or error object comes \
+         last.",
+    )]);
+    for (name, fields, description) in output::EVENTS {
+        roff.control("TP", [])
+            .text([bold(*name), roman(" "), italic(fields.join(", "))])
+            .text([roman(*description)]);
+    }
+
+    roff.control("SH", ["EXIT STATUS"]);
+    for (code, description) in exit::DESCRIBED {
+        roff.control("TP", [])
+            .text([bold(code.to_string())])
+            .text([roman(*description)]);
+    }
+    roff.control("PP", []).text([roman(
+        "up failures are coded by the phase they stopped in. exec and shell exit with \
+         the remote command's own code instead.",
+    )]);
+    roff.to_writer(out)?;
+
+    man.render_version_section(out)
+}
+
+/// Describes `cmd` and its own arguments, then its subcommands
+fn command(roff: &mut Roff, parent: &str, cmd: &Command, shared: &[&Arg]) {
+    if cmd.is_hide_set() || cmd.get_name() == "help" {
+        return;
+    }
+    let path = format!("{} {}", parent, cmd.get_name());
+    roff.control("SS", [path.as_str()]);
+    if let Some(about) = cmd.get_long_about().or_else(|| cmd.get_about()) {
+        roff.text([roman(about.to_string())]);
+    }
+    let (own, inherited): (Vec<&Arg>, Vec<&Arg>) = cmd
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_set() && !matches!(arg.get_id().as_str(), "help" | "version"))
+        .partition(|arg| arg.is_positional() || !is_shared(arg, shared));
+    for arg in own {
+        roff.control("TP", []).text(header(arg));
+        let help = arg
+            .get_long_help()
+            .or_else(|| arg.get_help())
+            .map(ToString::to_string);
+        let values: Vec<String> = arg
+            .get_possible_values()
+            .iter()
+            .filter(|value| !value.is_hide_set())
+            .map(|value| value.get_name().to_string())
+            .collect();
+        let values =
+            (!values.is_empty()).then(|| format!("[possible values: {}]", values.join(", ")));
+        let text: Vec<String> = help.into_iter().chain(values).collect();
+        if !text.is_empty() {
+            roff.text([roman(text.join(" "))]);
+        }
+    }
+    if inherited.iter().any(|arg| !arg.is_global_set()) {
+        roff.control("PP", []).text([roman(
+            "Also takes the target options described under OPTIONS.",
+        )]);
+    }
+    for sub in cmd.get_subcommands() {
+        command(roff, &path, sub, shared);
+    }
+}
+
+/// Whether the top level takes `arg` too, so OPTIONS describes it
+fn is_shared(arg: &Arg, shared: &[&Arg]) -> bool {
+    let help = |arg: &Arg| arg.get_help().map(ToString::to_string);
+    shared
+        .iter()
+        .any(|top| top.get_id() == arg.get_id() && help(top) == help(arg))
+}
+
+/// `-s, --long <VALUE>` for an option, `<NAME>` for a positional argument
+fn header(arg: &Arg) -> Vec<Inline> {
+    let value = || {
+        let names = arg
+            .get_value_names()
+            .map(|names| names.iter().map(|name| format!("<{}>", name)).collect())
+            .unwrap_or_else(|| vec![format!("<{}>", arg.get_id().as_str().to_uppercase())]);
+        italic(names.join(" "))
+    };
+    if arg.is_positional() {
+        return vec![value()];
+    }
+    let mut header = Vec::new();
+    if let Some(short) = arg.get_short() {
+        header.push(bold(format!("-{}", short)));
+    }
+    if let Some(long) = arg.get_long() {
+        if !header.is_empty() {
+            header.push(roman(", "));
+        }
+        header.push(bold(format!("--{}", long)));
+    }
+    if arg.get_num_args().is_some_and(|range| range.takes_values()) {
+        header.push(roman(" "));
+        header.push(value());
+    }
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ArgAction;
+
+    #[test]
+    fn test_shared_options_are_described_once() {
+        let host = || Arg::new("host").long("host").help("Device to connect to");
+        let cmd = Command::new("tool").arg(host()).subcommand(
+            Command::new("exec")
+                .about("Run a command")
+                .arg(host())
+                .arg(Arg::new("force").long("force").action(ArgAction::SetTrue))
+                .arg(Arg::new("command").help("Command to run")),
+        );
+        let mut page = Vec::new();
+        render(cmd, &[], &mut page).unwrap();
+        let page = String::from_utf8(page).unwrap();
+
+        let exec = &page[page.find(".SS").unwrap()..page.find(".SH CONFIGURATION").unwrap()];
+        assert!(exec.contains("Run a command"));
+        assert!(exec.contains("\\fI<COMMAND>\\fR\nCommand to run"));
+        assert!(exec.contains("\\-\\-force"));
+        assert!(!exec.contains("\\-\\-host"));
+        assert!(exec.contains("Also takes the target options"));
+        for section in ["OPTIONS", "ENVIRONMENT", "\"EXIT STATUS\""] {
+            assert!(page.contains(&format!(".SH {}", section)), "{}", section);
+        }
+        assert!(page.contains("\\fBSSH_IP_TUNNEL_CONFIG\\fR"));
+    }
+
+    #[test]
+    fn test_every_config_key_is_described() {
+        let config = serde_json::to_value(crate::Config::default()).unwrap();
+        let described: Vec<&str> = CONFIG_KEYS
+            .iter()
+            .map(|(key, _)| key.split('.').next().unwrap_or(key))
+            .collect();
+        for key in config.as_object().unwrap().keys() {
+            assert!(described.contains(&key.as_str()), "{}", key);
+        }
+        assert_eq!(described.len(), config.as_object().unwrap().len());
+    }
+}
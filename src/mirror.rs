@@ -0,0 +1,449 @@
+//! Lending this machine's package caches and network access to air-gapped devices.
+//!
+//! `mirror` forwards a local container registry and/or apt proxy to the
+//! device's loopback interface through the tunnel, and points the device's
+//! package tools at them with drop-in files. With `--reverse-proxy` it also
+//! serves an HTTP/HTTPS forward proxy here (see [`crate::http_proxy`]) and
+//! points apt and login shells at it, and with `--dns` a DNS forwarder (see
+//! [`crate::dns`]) that temporarily replaces the device's resolv.conf. The
+//! drop-ins are removed and resolv.conf put back when the mirror stops,
+//! whether after `--run` finishes or on Ctrl-C or SIGTERM; a backup left
+//! behind by a mirror that was killed outright is restored by the next one.
+//!
+//! Podman, CRI-O and Buildah read the registry drop-in on every pull. Docker's
+//! daemon only takes mirrors on restart, so Docker users pull from
+//! `127.0.0.1:<port>/<image>` explicitly instead, which Docker allows without
+//! TLS for loopback addresses.
+
+use crate::config::Config;
+use crate::dns::{self, DnsForwarder};
+use crate::executor::Stdio;
+use crate::forward::ReverseForward;
+use crate::http_proxy::HttpProxy;
+use crate::output::Renderable;
+use crate::prompt;
+use crate::pure::forward::Forwarded;
+use crate::shell::{self, RemoteCommand};
+use crate::ssh;
+use crate::watch;
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tracing::{info, warn};
+
+/// Upper bound for writing or removing the drop-ins
+const CONFIGURE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// apt drop-in pointing at the proxy
+const APT_DROP_IN: &str = "/etc/apt/apt.conf.d/99ssh-ip-tunnel-proxy";
+
+/// containers-registries.conf drop-in making the registry a Docker Hub mirror
+const REGISTRY_DROP_IN: &str = "/etc/containers/registries.conf.d/99-ssh-ip-tunnel-mirror.conf";
+
+/// Login shell drop-in exporting the forward proxy
+const SHELL_DROP_IN: &str = "/etc/profile.d/99-ssh-ip-tunnel-proxy.sh";
+
+/// Where the device's own resolv.conf is kept while `--dns` replaces it
+const RESOLV_CONF_BACKUP: &str = "/etc/resolv.conf.ssh-ip-tunnel";
+
+/// Puts back the resolv.conf saved at `$1`, if any
+const RESTORE_RESOLV_CONF: &str = r#"if [ -e "$1" ] || [ -L "$1" ]; then
+  as_root mv -f "$1" /etc/resolv.conf
+fi"#;
+
+/// Writes the drop-ins for the services given as `$1` (apt's HTTP proxy URL),
+/// `$2` (registry address) and `$3` (forward proxy URL), to the paths in
+/// `$4` to `$6`, and with `$7` non-empty replaces resolv.conf, keeping it at
+/// `$8`. Prints the name of each tool configured
+const CONFIGURE_SCRIPT: &str = r#"set -e
+if { [ -n "$1" ] || [ -n "$3" ]; } && [ -d /etc/apt/apt.conf.d ]; then
+  {
+    if [ -n "$1" ]; then printf 'Acquire::http::Proxy "%s";\n' "$1"; fi
+    if [ -n "$3" ]; then printf 'Acquire::https::Proxy "%s";\n' "$3"; fi
+  } | as_root tee "$4" >/dev/null
+  echo apt
+fi
+if [ -n "$2" ] && [ -d /etc/containers ]; then
+  as_root mkdir -p "${5%/*}"
+  printf '[[registry]]\nlocation = "docker.io"\n\n[[registry.mirror]]\nlocation = "%s"\ninsecure = true\n' "$2" | as_root tee "$5" >/dev/null
+  echo containers
+fi
+if [ -n "$3" ] && [ -d /etc/profile.d ]; then
+  printf 'export http_proxy=%s https_proxy=%s HTTP_PROXY=%s HTTPS_PROXY=%s\nexport no_proxy=localhost,127.0.0.1 NO_PROXY=localhost,127.0.0.1\n' "$3" "$3" "$3" "$3" | as_root tee "$6" >/dev/null
+  echo shell
+fi
+if [ -n "$7" ]; then
+  if [ -e "$8" ] || [ -L "$8" ]; then as_root mv -f "$8" /etc/resolv.conf; fi
+  if [ -e /etc/resolv.conf ] || [ -L /etc/resolv.conf ]; then
+    as_root mv -f /etc/resolv.conf "$8"
+  else
+    as_root touch "$8"
+  fi
+  printf 'nameserver 127.0.0.1\noptions use-vc\n' | as_root tee /etc/resolv.conf >/dev/null
+  echo dns
+fi
+"#;
+
+/// What to lend the device: `host:port` addresses on this machine
+#[derive(Debug, Clone, Default)]
+pub struct MirrorOptions {
+    pub registry: Option<String>,
+    pub apt_proxy: Option<String>,
+    /// Local port to serve an HTTP/HTTPS forward proxy on for the device
+    pub reverse_proxy: Option<u16>,
+    /// Local port to serve DNS on for the device, which becomes its resolver
+    pub dns: Option<u16>,
+    /// Shell command to run on the device while the mirror is up; without it
+    /// the mirror stays up until Ctrl-C
+    pub run: Option<String>,
+}
+
+/// Result of `mirror`
+#[derive(Debug, Serialize)]
+pub struct MirrorReport {
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registry: Option<Forwarded>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub apt_proxy: Option<Forwarded>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reverse_proxy: Option<Forwarded>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns: Option<Forwarded>,
+    /// Tools whose configuration was changed for the duration (`apt`, `containers`, `shell`, `dns`)
+    pub configured: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+}
+
+impl Renderable for MirrorReport {
+    fn to_human(&self) -> String {
+        let mut text = format!("Mirror on {} closed", self.host);
+        if let Some(command) = &self.command {
+            text.push_str(&format!(" after `{}` succeeded", command));
+        }
+        if !self.configured.is_empty() {
+            text.push_str(&format!(
+                "; {} configuration restored",
+                self.configured.join(", ")
+            ));
+        }
+        text
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Lends the services in `options` to `target` until `options.run` finishes, or until Ctrl-C
+pub async fn mirror(
+    config: &Config,
+    target: &Target,
+    options: &MirrorOptions,
+) -> Result<MirrorReport> {
+    let registry = options
+        .registry
+        .as_deref()
+        .map(Forwarded::parse)
+        .transpose()?;
+    let apt_proxy = options
+        .apt_proxy
+        .as_deref()
+        .map(Forwarded::parse)
+        .transpose()?;
+    let proxy = match options.reverse_proxy {
+        Some(port) => Some(HttpProxy::start(port).await?),
+        None => None,
+    };
+    let reverse_proxy = proxy.as_ref().map(|proxy| Forwarded {
+        local: format!("127.0.0.1:{}", proxy.port()),
+        device_port: proxy.port(),
+    });
+    let forwarder = match options.dns {
+        Some(port) => {
+            // sshd lets only root listen on ports below 1024, such as the device's port 53
+            if target.user != "root" {
+                anyhow::bail!(
+                    "--dns needs to log in as root: only root may bind port 53 on {}",
+                    target.host
+                );
+            }
+            Some(DnsForwarder::start(port, dns::system_resolver()?).await?)
+        }
+        None => None,
+    };
+    let dns = forwarder.as_ref().map(|forwarder| Forwarded {
+        local: format!("127.0.0.1:{}", forwarder.port()),
+        device_port: 53,
+    });
+    let specs: Vec<String> = registry
+        .iter()
+        .chain(&apt_proxy)
+        .chain(&reverse_proxy)
+        .chain(&dns)
+        .map(Forwarded::spec)
+        .collect();
+    if specs.is_empty() {
+        anyhow::bail!(
+            "Nothing to mirror: pass --registry, --apt-proxy, --reverse-proxy and/or --dns"
+        );
+    }
+
+    SSHTunnelManager::new(config.clone())
+        .connect(target)
+        .await?;
+    let mut forward = ReverseForward::start(target, &specs).await?;
+    let proxy_url = reverse_proxy
+        .as_ref()
+        .map(|proxy| format!("http://127.0.0.1:{}", proxy.device_port));
+    // A caching apt proxy beats the plain forward proxy for http:// sources
+    let apt_url = apt_proxy
+        .as_ref()
+        .map(|proxy| format!("http://127.0.0.1:{}", proxy.device_port))
+        .or_else(|| proxy_url.clone());
+    let registry_address = registry
+        .as_ref()
+        .map(|registry| format!("127.0.0.1:{}", registry.device_port));
+    let configured = match configure(
+        target,
+        apt_url.as_deref(),
+        registry_address.as_deref(),
+        proxy_url.as_deref(),
+        dns.is_some(),
+    )
+    .await
+    {
+        Ok(configured) => configured,
+        Err(e) => {
+            // Part of the configuration may have been written
+            restore(target).await;
+            forward.stop().await;
+            return Err(e.into());
+        }
+    };
+    if let Some(address) = &registry_address {
+        info!(
+            "Registry available on {} as {} (e.g. docker pull {}/<image>)",
+            target.host, address, address
+        );
+    }
+    if let Some(url) = &apt_url {
+        info!("apt on {} uses the proxy at {}", target.host, url);
+    }
+    if dns.is_some() {
+        info!("{} resolves names through this machine", target.host);
+    }
+    if let Some(url) = &proxy_url {
+        info!(
+            "{} can reach the network through {} (http_proxy/https_proxy in login shells)",
+            target.host, url
+        );
+    }
+
+    // Whatever ends the mirror, the device's configuration is restored below
+    let outcome = match &options.run {
+        Some(command) => tokio::select! {
+            outcome = run(target, command, proxy_url.as_deref()) => outcome,
+            _ = watch::stop_requested() => Err(TunnelError::Mirror(format!(
+                "`{}` was stopped before it finished",
+                command
+            ))),
+        },
+        None => {
+            prompt::notice(
+                "Mirror is up; press Ctrl-C to stop it and restore the device's configuration",
+            );
+            tokio::select! {
+                _ = watch::stop_requested() => Ok(()),
+                _ = forward.closed() => Err(TunnelError::ConnectionLost(format!("the mirror on {} went down", target.host))),
+            }
+        }
+    };
+
+    info!("Restoring configuration on {}...", target.host);
+    restore(target).await;
+    forward.stop().await;
+    drop(proxy);
+    drop(forwarder);
+    outcome?;
+
+    Ok(MirrorReport {
+        host: target.host.clone(),
+        registry,
+        apt_proxy,
+        reverse_proxy,
+        dns,
+        configured,
+        command: options.run.clone(),
+    })
+}
+
+/// Writes the drop-ins, returning the tools configured
+async fn configure(
+    target: &Target,
+    apt_url: Option<&str>,
+    registry: Option<&str>,
+    proxy_url: Option<&str>,
+    dns: bool,
+) -> Result<Vec<String>, TunnelError> {
+    let command = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(format!("{}\n{}", shell::AS_ROOT, CONFIGURE_SCRIPT))
+        .arg("sh")
+        .arg(apt_url.unwrap_or_default())
+        .arg(registry.unwrap_or_default())
+        .arg(proxy_url.unwrap_or_default())
+        .arg(APT_DROP_IN)
+        .arg(REGISTRY_DROP_IN)
+        .arg(SHELL_DROP_IN)
+        .arg(if dns { "1" } else { "" })
+        .arg(RESOLV_CONF_BACKUP);
+    let stdout = ssh::run_through_tunnel(target, &command, CONFIGURE_TIMEOUT)
+        .await
+        .map_err(|e| {
+            e.into_error(|message| {
+                TunnelError::Mirror(format!("configuring the device failed: {}", message))
+            })
+        })?;
+    let configured: Vec<String> = stdout.split_whitespace().map(str::to_string).collect();
+    if apt_url.is_some() && !configured.iter().any(|tool| tool == "apt") {
+        warn!(
+            "{} has no /etc/apt/apt.conf.d; apt was not configured",
+            target.host
+        );
+    }
+    Ok(configured)
+}
+
+/// Removes the drop-ins and puts resolv.conf back; failures are logged, since
+/// the mirror is going away regardless
+async fn restore(target: &Target) {
+    let command = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "{}\n{}\nshift\nas_root rm -f \"$@\"",
+            shell::AS_ROOT,
+            RESTORE_RESOLV_CONF
+        ))
+        .arg("sh")
+        .arg(RESOLV_CONF_BACKUP)
+        .arg(APT_DROP_IN)
+        .arg(REGISTRY_DROP_IN)
+        .arg(SHELL_DROP_IN);
+    if let Err(e) = ssh::run_through_tunnel(target, &command, CONFIGURE_TIMEOUT).await {
+        warn!(
+            "Could not restore the configuration on {}: {}; delete {}, {} and {}, and move {} back to /etc/resolv.conf by hand",
+            target.host, e, APT_DROP_IN, REGISTRY_DROP_IN, SHELL_DROP_IN, RESOLV_CONF_BACKUP
+        );
+    }
+}
+
+/// Runs the user's provisioning command on the device, logging its output.
+///
+/// It isn't a login shell, so the forward proxy is passed in the environment.
+async fn run(target: &Target, command: &str, proxy_url: Option<&str>) -> Result<(), TunnelError> {
+    info!("Running `{}` on {}...", command, target.host);
+    let mut remote = RemoteCommand::new("env");
+    if let Some(url) = proxy_url {
+        for name in ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"] {
+            remote = remote.arg(format!("{}={}", name, url));
+        }
+        remote = remote.arg("no_proxy=localhost,127.0.0.1");
+    }
+    let remote = remote
+        .arg("sh")
+        .arg("-c")
+        .arg(format!("exec 2>&1\n{}", command));
+    let mut child = ssh::through_tunnel(target, &remote)?
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Stopping the mirror mid-command drops this, ending the command
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| TunnelError::Mirror(e.to_string()))?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let mut lines = BufReader::new(stdout).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        info!("{}: {}", target.host, line);
+    }
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| TunnelError::Mirror(e.to_string()))?;
+    if !output.status.success() {
+        return Err(TunnelError::Mirror(format!(
+            "`{}` failed on {} ({}) {}",
+            command,
+            target.host,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_options_are_checked_before_connecting() {
+        let config = Config::default();
+        let target = Target::builder("pi.local", "pi", &config).build().unwrap();
+        let refused = |options: MirrorOptions| {
+            let (config, target) = (config.clone(), target.clone());
+            async move {
+                mirror(&config, &target, &options)
+                    .await
+                    .unwrap_err()
+                    .to_string()
+            }
+        };
+        assert!(refused(MirrorOptions::default())
+            .await
+            .starts_with("Nothing to mirror"));
+        assert_eq!(
+            refused(MirrorOptions {
+                registry: Some("5000".to_string()),
+                ..Default::default()
+            })
+            .await,
+            "Expected host:port, got \"5000\""
+        );
+        assert_eq!(
+            refused(MirrorOptions {
+                dns: Some(0),
+                ..Default::default()
+            })
+            .await,
+            "--dns needs to log in as root: only root may bind port 53 on pi.local"
+        );
+    }
+
+    #[test]
+    fn test_report_says_what_was_restored() {
+        let mut report = MirrorReport {
+            host: "pi.local".to_string(),
+            registry: Some(Forwarded::parse("localhost:5000").unwrap()),
+            apt_proxy: None,
+            reverse_proxy: None,
+            dns: None,
+            configured: vec!["containers".to_string()],
+            command: Some("podman pull alpine".to_string()),
+        };
+        assert_eq!(
+            report.to_human(),
+            "Mirror on pi.local closed after `podman pull alpine` succeeded; containers configuration restored"
+        );
+        let json = report.to_json();
+        assert_eq!(json["registry"]["device_port"], 5000);
+        assert!(json.get("apt_proxy").is_none());
+
+        report.command = None;
+        report.configured.clear();
+        assert_eq!(report.to_human(), "Mirror on pi.local closed");
+    }
+}
@@ -0,0 +1,442 @@
+//! `onboard`: taking a new device from first boot to a managed host in one run.
+//!
+//! Onboarding shows the host key the device presents and asks before trusting
+//! it, asks for the first login's password, generates the key pair if there is
+//! none, deploys it and turns off password logins (the `up` workflow), sets the
+//! hostname, records the device as a host profile in the configuration file
+//! and installs the agent. Every step checks before it acts, so onboarding a
+//! device again after a failed step picks up where the last run stopped.
+
+use crate::agent::{self, AgentBuild};
+use crate::askpass;
+use crate::config::{self, Config, HostProfile};
+use crate::device_profile::HOSTNAME_SCRIPT;
+use crate::facts::{self, Facts};
+use crate::fleet;
+use crate::host_keys;
+use crate::keys;
+use crate::output::{self, Renderable};
+use crate::paths;
+use crate::prompt;
+use crate::pure::profile::is_valid_hostname;
+use crate::shell::{self, RemoteCommand};
+use crate::ssh::{self, RunError};
+use crate::{Target, TunnelError};
+use anyhow::Result;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::info;
+
+/// Upper bound for setting the hostname
+const HOSTNAME_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How [`onboard`] runs
+#[derive(Debug, Clone, Default)]
+pub struct OnboardOptions {
+    /// Host profile to record the device as (default: its hostname)
+    pub name: Option<String>,
+    /// Hostname to give the device
+    pub hostname: Option<String>,
+    /// Leave password logins on
+    pub no_harden: bool,
+    /// Agent build to install
+    pub agent: Option<AgentBuild>,
+    /// Configuration file the host profile is written to
+    pub config_file: PathBuf,
+    /// Don't ask; trust the host key the device presents unless `--fingerprint` pins one
+    pub assume_yes: bool,
+}
+
+/// How a step of onboarding went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Done,
+    /// The device was already that way
+    Unchanged,
+    Skipped,
+}
+
+impl StepStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            StepStatus::Done => "done",
+            StepStatus::Unchanged => "unchanged",
+            StepStatus::Skipped => "skipped",
+        }
+    }
+}
+
+/// One line of the onboarding report
+#[derive(Debug, Clone, Serialize)]
+pub struct OnboardStep {
+    pub step: &'static str,
+    pub status: StepStatus,
+    pub detail: String,
+}
+
+/// Result of `onboard`
+#[derive(Debug, Serialize)]
+#[non_exhaustive]
+pub struct OnboardReport {
+    pub host: String,
+    /// The host profile the device was recorded as
+    pub name: String,
+    pub config_file: PathBuf,
+    pub facts: Facts,
+    pub steps: Vec<OnboardStep>,
+}
+
+impl Renderable for OnboardReport {
+    fn to_human(&self) -> String {
+        let rows: Vec<Vec<String>> = self
+            .steps
+            .iter()
+            .map(|step| {
+                vec![
+                    step.step.to_string(),
+                    step.status.as_str().to_string(),
+                    step.detail.clone(),
+                ]
+            })
+            .collect();
+        format!(
+            "Onboarded {} as '{}': {} ({}, {} {})\n{}\nFrom now on: ssh-ip-tunnel up {}",
+            self.host,
+            self.name,
+            self.facts.model,
+            self.facts.arch,
+            self.facts.os,
+            self.facts.os_version,
+            output::table(&["STEP", "STATUS", "DETAIL"], &rows),
+            self.name
+        )
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Collects the report's steps, logging each as it finishes
+#[derive(Default)]
+struct Steps(Vec<OnboardStep>);
+
+impl Steps {
+    fn push(&mut self, step: &'static str, status: StepStatus, detail: impl Into<String>) {
+        let detail = detail.into();
+        info!("Onboarding {}: {} ({})", step, status.as_str(), detail);
+        self.0.push(OnboardStep {
+            step,
+            status,
+            detail,
+        });
+    }
+}
+
+/// Names the step an error stopped onboarding at
+fn stopped(step: &str, e: impl std::fmt::Display) -> anyhow::Error {
+    anyhow::anyhow!("Onboarding stopped at the {} step: {:#}", step, e)
+}
+
+/// Asks a yes/no question, taking anything but yes as no
+fn confirm(question: &str) -> Result<bool> {
+    let answer = prompt::ask(&format!("{} (yes/no)", question), "no")?;
+    Ok(matches!(answer.to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Onboards the device `target` points at, as described in the module docs
+pub async fn onboard(
+    config: &Config,
+    target: &Target,
+    options: &OnboardOptions,
+) -> Result<OnboardReport> {
+    let interactive = prompt::is_interactive();
+    if !options.assume_yes && !interactive {
+        anyhow::bail!("onboard asks before trusting a device; pass --yes, ideally with --fingerprint, to onboard without a terminal");
+    }
+    // Catch bad options before the device is touched
+    if let Some(hostname) = &options.hostname {
+        if !is_valid_hostname(hostname) {
+            anyhow::bail!(
+                "Invalid hostname {:?}: expected letters, digits and '-', at most 63",
+                hostname
+            );
+        }
+    }
+    if let Some(build) = &options.agent {
+        build.check()?;
+    }
+    let mut target = target.clone();
+    target.skip_key_transfer = false;
+    target.harden = !options.no_harden;
+    let mut steps = Steps::default();
+
+    // Discovery: the host key proves something answers with sshd
+    if target.simulated.is_some() {
+        steps.push("discover", StepStatus::Done, "simulated device");
+        steps.push(
+            "host key",
+            StepStatus::Skipped,
+            "host keys are not simulated",
+        );
+    } else {
+        let presented = host_keys::fetch(&target, target.host_key_fingerprint.as_deref())
+            .await
+            .map_err(|e| stopped("discover", e))?;
+        steps.push(
+            "discover",
+            StepStatus::Done,
+            format!("sshd answers with host key {}", presented),
+        );
+        let detail = match &target.host_key_fingerprint {
+            Some(_) => "matches --fingerprint".to_string(),
+            None if options.assume_yes => "trusted as presented (--yes)".to_string(),
+            None => {
+                prompt::notice(&format!(
+                    "{} presents host key {}.\nCompare it with `ssh-keygen -lf /etc/ssh/ssh_host_ed25519_key.pub` on the device's console.",
+                    target.host, presented
+                ));
+                if !confirm(&format!("Trust this key and onboard {}?", target.host))? {
+                    anyhow::bail!("Not confirmed; {} was left alone", target.host);
+                }
+                "confirmed".to_string()
+            }
+        };
+        steps.push("host key", StepStatus::Done, detail);
+        target.host_key_fingerprint = Some(presented);
+    }
+
+    // First-login credentials
+    if target.password.is_none() && !target.interactive_auth && !options.assume_yes {
+        let password = askpass::ask(&format!(
+            "Password for {}@{} (empty if a key already logs in): ",
+            target.user, target.host
+        ))
+        .map_err(|e| anyhow::anyhow!("Cannot read password: {}", e))?;
+        if !password.is_empty() {
+            target.password = Some(askpass::Password::new(password));
+        }
+    }
+    match (&target.password, target.interactive_auth) {
+        (Some(_), _) => steps.push("credentials", StepStatus::Done, "password"),
+        (None, true) => steps.push("credentials", StepStatus::Done, "asked by ssh"),
+        (None, false) => steps.push(
+            "credentials",
+            StepStatus::Skipped,
+            "no password; an existing key or ssh-agent logs in",
+        ),
+    }
+
+    // Key pair
+    let key_path = paths::expand_tilde(&target.key_path)?;
+    if key_path.exists() {
+        steps.push("key", StepStatus::Unchanged, key_path.display().to_string());
+    } else {
+        let generated = keys::generate(&key_path, &keys::default_comment().await)
+            .await
+            .map_err(|e| stopped("key", e))?;
+        steps.push(
+            "key",
+            StepStatus::Done,
+            format!(
+                "generated {} ({})",
+                generated.private_key.display(),
+                generated.fingerprint
+            ),
+        );
+    }
+
+    // Deployment and hardening: the `up` workflow
+    let run = fleet::run_target(config, &target)
+        .await
+        .map_err(|e| stopped("deploy", e))?;
+    if run.key_transferred {
+        steps.push(
+            "deploy",
+            StepStatus::Done,
+            format!("key authorized for {}", target.key_user()),
+        );
+    } else if run.key_already_deployed {
+        steps.push(
+            "deploy",
+            StepStatus::Unchanged,
+            "key was already authorized",
+        );
+    } else {
+        steps.push("deploy", StepStatus::Skipped, "--skip key");
+    }
+    match &run.harden {
+        Some(report) if report.changed => steps.push("harden", StepStatus::Done, report.summary()),
+        Some(report) => steps.push("harden", StepStatus::Unchanged, report.summary()),
+        None if options.no_harden => steps.push("harden", StepStatus::Skipped, "--no-harden"),
+        None => steps.push("harden", StepStatus::Skipped, "--skip harden"),
+    }
+
+    // Hostname
+    match &options.hostname {
+        Some(hostname) => {
+            let changed = set_hostname(&target, hostname)
+                .await
+                .map_err(|e| stopped("hostname", e))?;
+            match changed {
+                Some(change) => steps.push("hostname", StepStatus::Done, change),
+                None => steps.push("hostname", StepStatus::Unchanged, hostname.clone()),
+            }
+        }
+        None => steps.push("hostname", StepStatus::Skipped, "no --hostname given"),
+    }
+    let facts = facts::gather(&target, true)
+        .await
+        .map_err(|e| stopped("hostname", e))?;
+
+    // Registry entry
+    let name = options
+        .name
+        .clone()
+        .unwrap_or_else(|| facts.hostname.clone());
+    let profile = HostProfile {
+        host: Some(target.host.clone()),
+        user: Some(target.user.clone()),
+        port: (target.port != config.default_port).then_some(target.port),
+        key_path: Some(target.key_path.clone()),
+        fingerprint: target.host_key_fingerprint.clone(),
+        harden: target.harden.then_some(true),
+        ..Default::default()
+    };
+    let (status, detail) = record(config, &options.config_file, &name, &profile)
+        .map_err(|e| stopped("registry", e))?;
+    steps.push("registry", status, detail);
+
+    // Agent
+    match &options.agent {
+        Some(build) => {
+            let installed = agent::deploy(config, &target, run, build, false)
+                .await
+                .map_err(|e| stopped("agent", e))?;
+            steps.push(
+                "agent",
+                StepStatus::Done,
+                format!(
+                    "{}.service running {}",
+                    installed.unit,
+                    installed.binary.display()
+                ),
+            );
+        }
+        None => steps.push("agent", StepStatus::Skipped, "no agent build given"),
+    }
+
+    Ok(OnboardReport {
+        host: target.host.clone(),
+        name,
+        config_file: options.config_file.clone(),
+        facts,
+        steps: steps.0,
+    })
+}
+
+/// Sets the hostname with [`HOSTNAME_SCRIPT`], returning the change if there was one
+async fn set_hostname(target: &Target, hostname: &str) -> Result<Option<String>, TunnelError> {
+    let command = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(format!("{}\n{}", shell::AS_ROOT, HOSTNAME_SCRIPT))
+        .arg("sh")
+        .arg("apply")
+        .arg(hostname);
+    let stdout = ssh::run_through_tunnel(target, &command, HOSTNAME_TIMEOUT)
+        .await
+        .map_err(|e| match e {
+            RunError::Failed { stderr, .. } => TunnelError::Profile(format!(
+                "setting the hostname failed: {} (root or passwordless sudo is needed)",
+                stderr
+            )),
+            e => e.into_error(|message| {
+                TunnelError::Profile(format!("setting the hostname: {}", message))
+            }),
+        })?;
+    Ok(stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("~ "))
+        .map(str::to_string))
+}
+
+/// Writes `profile` to `[hosts.<name>]` in `file`, creating the file from the
+/// commented template if there is none
+fn record(
+    config: &Config,
+    file: &std::path::Path,
+    name: &str,
+    profile: &HostProfile,
+) -> Result<(StepStatus, String)> {
+    let (contents, existed) = match std::fs::read_to_string(file) {
+        Ok(contents) => (contents, true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (
+            config::render_template(&config.default_key(), config.default_port),
+            false,
+        ),
+        Err(e) => anyhow::bail!("Failed to read config file {}: {}", file.display(), e),
+    };
+    let updated = config::set_host_profile(&contents, name, profile)?;
+    if existed && updated == contents {
+        return Ok((
+            StepStatus::Unchanged,
+            format!("[hosts.{}] in {}", name, file.display()),
+        ));
+    }
+    let verb = if config.hosts.contains_key(name) {
+        "updated"
+    } else {
+        "added"
+    };
+    config::write_template(file, &updated, true)?;
+    Ok((
+        StepStatus::Done,
+        format!("{} [hosts.{}] in {}", verb, name, file.display()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_adds_the_profile_once() {
+        let dir =
+            std::env::temp_dir().join(format!("ssh_ip_tunnel-onboard-{}", std::process::id()));
+        let file = dir.join("config.toml");
+        let config = Config::default();
+        let profile = HostProfile {
+            host: Some("10.0.0.5".to_string()),
+            user: Some("pi".to_string()),
+            harden: Some(true),
+            ..Default::default()
+        };
+
+        let (status, detail) = record(&config, &file, "rpi4-lab", &profile).unwrap();
+        assert_eq!(status, StepStatus::Done);
+        assert!(detail.starts_with("added [hosts.rpi4-lab] in "));
+        let written = std::fs::read_to_string(&file).unwrap();
+        assert!(written.contains("[hosts.rpi4-lab]"));
+        assert!(written.contains("host = \"10.0.0.5\""));
+
+        // Onboarding the device again finds it recorded
+        let (status, _) = record(&config, &file, "rpi4-lab", &profile).unwrap();
+        assert_eq!(status, StepStatus::Unchanged);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_bad_hostname_is_refused_before_the_device_is_touched() {
+        let config = Config::default();
+        let target = Target::builder("pi.local", "pi", &config).build().unwrap();
+        let options = OnboardOptions {
+            hostname: Some("lab_pi".to_string()),
+            assume_yes: true,
+            ..Default::default()
+        };
+        let e = onboard(&config, &target, &options).await.unwrap_err();
+        assert!(e.to_string().starts_with("Invalid hostname \"lab_pi\""));
+    }
+}
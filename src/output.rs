@@ -0,0 +1,676 @@
+//! User-facing output.
+//!
+//! Commands never print directly. They hand lifecycle [`Event`]s and final
+//! results to the [`Renderer`] chosen once at startup with `--output`, so every
+//! command supports every output mode. Diagnostic logging stays with `tracing`.
+//! Machine-readable output carries the run ID (see [`crate::run`]).
+//! On a terminal, human output shows events as ✓/✗ status lines instead of
+//! log lines (see [`Style`]).
+
+use crate::desktop;
+use crate::phase::Phase;
+use crate::run;
+use crate::spinner;
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+pub use crate::pure::text::table;
+
+/// Output mode selected with `--output`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Log lines and plain-text results
+    #[default]
+    Human,
+    /// A single JSON document with the final result
+    Json,
+    /// One JSON object per line for each event, then the result
+    Ndjson,
+    /// Nothing but errors
+    Quiet,
+}
+
+impl OutputFormat {
+    /// Whether stdout is reserved for machine-readable output
+    pub fn is_machine_readable(self) -> bool {
+        matches!(self, Self::Json | Self::Ndjson)
+    }
+}
+
+/// Event stream selected with `--events`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EventFormat {
+    /// One JSON object per line for each event, then the result, as
+    /// `--output ndjson` writes them
+    Ndjson,
+}
+
+/// When to use colors, selected with `--color`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// When writing to a terminal, unless `NO_COLOR` is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether output to `stream` gets colors
+    pub fn enabled(self, stream: &impl IsTerminal) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => stream.is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+        }
+    }
+}
+
+/// How human output looks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Style {
+    /// Events as ✓/✗ status lines, for someone watching a terminal; the log
+    /// then only shows warnings and errors
+    pub status_lines: bool,
+    /// ANSI colors on status lines
+    pub color: bool,
+}
+
+/// Something that happened during a run
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Event {
+    /// An SSH connection for the tunnel is tried, counting from 1
+    TunnelAttempt {
+        host: String,
+        port: u16,
+        attempt: u32,
+    },
+    TunnelUp {
+        host: String,
+        port: u16,
+    },
+    TunnelValidated {
+        port: u16,
+    },
+    ClockSet {
+        port: u16,
+        offset_secs: i64,
+    },
+    ArchDetected {
+        port: u16,
+        arch: String,
+        /// The board, when the device names it
+        #[serde(skip_serializing_if = "Option::is_none")]
+        board: Option<String>,
+        /// The distribution and kernel, when `uname -s` answered
+        #[serde(skip_serializing_if = "Option::is_none")]
+        os: Option<String>,
+    },
+    KeyTransferred {
+        port: u16,
+        key_path: PathBuf,
+    },
+    KeyVerified {
+        port: u16,
+        user: String,
+    },
+    FilePushed {
+        port: u16,
+        path: String,
+        bytes: u64,
+    },
+    /// A host of a group run finished; `eta_secs` is the estimated time left
+    Progress {
+        done: usize,
+        total: usize,
+        percent: u8,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        eta_secs: Option<u64>,
+    },
+    /// A watched tunnel stopped answering (`up --watch`)
+    TunnelLost {
+        host: String,
+        port: u16,
+        error: String,
+    },
+    /// A watched tunnel answers again after it was opened anew
+    TunnelRecovered {
+        host: String,
+        port: u16,
+        /// Times it has been opened again
+        reconnects: u32,
+    },
+    /// A run for a host failed, in `phase` when it is known
+    Failure {
+        host: String,
+        port: u16,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        phase: Option<Phase>,
+        error: String,
+    },
+}
+
+/// Each event's name and the fields its `ndjson` line has besides `event`
+/// and `run_id` (bracketed ones only when known), with when it comes
+pub const EVENTS: &[(&str, &[&str], &str)] = &[
+    (
+        "tunnel_attempt",
+        &["host", "port", "attempt"],
+        "An SSH connection for the tunnel is tried, counting from 1",
+    ),
+    ("tunnel_up", &["host", "port"], "The tunnel is open"),
+    (
+        "tunnel_validated",
+        &["port"],
+        "A command ran through the tunnel",
+    ),
+    (
+        "clock_set",
+        &["port", "offset_secs"],
+        "The device's clock was set; it was offset_secs off",
+    ),
+    (
+        "arch_detected",
+        &["port", "arch", "[board]", "[os]"],
+        "The device's architecture, and its board and OS when it names them",
+    ),
+    (
+        "key_transferred",
+        &["port", "key_path"],
+        "The key or certificate is on the device",
+    ),
+    (
+        "key_verified",
+        &["port", "user"],
+        "user logged in with the key",
+    ),
+    (
+        "file_pushed",
+        &["port", "path", "bytes"],
+        "push uploaded a file",
+    ),
+    (
+        "progress",
+        &["done", "total", "percent", "[eta_secs]"],
+        "A host of a group run finished; eta_secs estimates the time left",
+    ),
+    (
+        "tunnel_lost",
+        &["host", "port", "error"],
+        "A watched tunnel stopped answering (up --watch)",
+    ),
+    (
+        "tunnel_recovered",
+        &["host", "port", "reconnects"],
+        "A watched tunnel answers again, opened anew reconnects times so far",
+    ),
+    (
+        "failure",
+        &["host", "port", "[phase]", "error"],
+        "The run for a host failed, in phase when it is known",
+    ),
+];
+
+/// A command result that can be shown in any output mode
+pub trait Renderable {
+    /// Plain-text form, one or more lines
+    fn to_human(&self) -> String;
+    /// Structured form used by `json` and `ndjson`
+    fn to_json(&self) -> Value;
+}
+
+/// Presents events, results and errors in one output mode
+pub trait Renderer: Send + Sync {
+    fn event(&self, event: &Event);
+    fn result(&self, result: &dyn Renderable);
+    fn error(&self, error: &anyhow::Error);
+}
+
+struct HumanRenderer(Style);
+
+impl Renderer for HumanRenderer {
+    // Without status lines, progress is already narrated by the log lines
+    fn event(&self, event: &Event) {
+        if !self.0.status_lines {
+            return;
+        }
+        if let Some((ok, text)) = status_line(event) {
+            let mark = match (ok, self.0.color) {
+                (true, true) => "\x1b[32m✓\x1b[0m",
+                (false, true) => "\x1b[31m✗\x1b[0m",
+                (true, false) => "✓",
+                (false, false) => "✗",
+            };
+            let _ = spinner::write_above(format!("{} {}\n", mark, text).as_bytes());
+        }
+    }
+
+    fn result(&self, result: &dyn Renderable) {
+        println!("{}", result.to_human());
+    }
+
+    fn error(&self, error: &anyhow::Error) {
+        tracing::error!("Operation failed: {}", error);
+    }
+}
+
+/// Prints exactly one document per process: whichever of result or error comes first
+#[derive(Default)]
+struct JsonRenderer {
+    printed: AtomicBool,
+}
+
+impl JsonRenderer {
+    fn print_once(&self, document: Value) {
+        if !self.printed.swap(true, Ordering::SeqCst) {
+            println!("{:#}", document);
+        }
+    }
+}
+
+impl Renderer for JsonRenderer {
+    fn event(&self, _event: &Event) {}
+
+    fn result(&self, result: &dyn Renderable) {
+        self.print_once(json!({
+            "status": "ok",
+            "run_id": run::current().as_str(),
+            "result": result.to_json(),
+        }));
+    }
+
+    fn error(&self, error: &anyhow::Error) {
+        self.print_once(json!({
+            "status": "error",
+            "run_id": run::current().as_str(),
+            "error": format!("{:#}", error),
+        }));
+    }
+}
+
+struct NdjsonRenderer;
+
+impl Renderer for NdjsonRenderer {
+    fn event(&self, event: &Event) {
+        if let Ok(Value::Object(mut line)) = serde_json::to_value(event) {
+            line.insert("run_id".to_string(), run::current().as_str().into());
+            println!("{}", Value::Object(line));
+        }
+    }
+
+    fn result(&self, result: &dyn Renderable) {
+        println!(
+            "{}",
+            json!({
+                "event": "result",
+                "run_id": run::current().as_str(),
+                "result": result.to_json(),
+            })
+        );
+    }
+
+    fn error(&self, error: &anyhow::Error) {
+        println!(
+            "{}",
+            json!({
+                "event": "error",
+                "run_id": run::current().as_str(),
+                "error": format!("{:#}", error),
+            })
+        );
+    }
+}
+
+struct QuietRenderer;
+
+impl Renderer for QuietRenderer {
+    fn event(&self, _event: &Event) {}
+    fn result(&self, _result: &dyn Renderable) {}
+    fn error(&self, _error: &anyhow::Error) {}
+}
+
+/// The status line for `event`, and whether it reports success; None for
+/// events not worth a line
+fn status_line(event: &Event) -> Option<(bool, String)> {
+    let line = match event {
+        Event::TunnelAttempt { attempt: 1, .. } => return None,
+        Event::TunnelAttempt { host, attempt, .. } => (
+            false,
+            format!("Tunnel to {} failed; attempt {}", host, attempt),
+        ),
+        Event::TunnelUp { host, port } => {
+            (true, format!("Tunnel to {} on localhost:{}", host, port))
+        }
+        Event::TunnelValidated { .. } => (true, "Tunnel answers".to_string()),
+        Event::ClockSet { offset_secs, .. } => (
+            true,
+            format!(
+                "Clock corrected; it was {}",
+                crate::clock::describe_offset(*offset_secs)
+            ),
+        ),
+        Event::ArchDetected { arch, board, .. } => (
+            true,
+            match board {
+                Some(board) => format!("Architecture {} ({})", arch, board),
+                None => format!("Architecture {}", arch),
+            },
+        ),
+        Event::KeyTransferred { key_path, .. } => {
+            (true, format!("Key {} deployed", key_path.display()))
+        }
+        Event::KeyVerified { user, .. } => (true, format!("Key logs in as {}", user)),
+        Event::FilePushed { path, bytes, .. } => {
+            (true, format!("Pushed {} ({} bytes)", path, bytes))
+        }
+        Event::Progress {
+            done,
+            total,
+            percent,
+            ..
+        } => (
+            true,
+            format!("{} of {} hosts done ({}%)", done, total, percent),
+        ),
+        Event::TunnelLost { host, error, .. } => {
+            (false, format!("Tunnel to {} lost: {}", host, error))
+        }
+        Event::TunnelRecovered { host, port, .. } => (
+            true,
+            format!("Tunnel to {} back on localhost:{}", host, port),
+        ),
+        Event::Failure { host, phase, .. } => (
+            false,
+            match phase {
+                Some(phase) => format!("{} failed in the {} phase", host, phase),
+                None => format!("{} failed", host),
+            },
+        ),
+    };
+    Some(line)
+}
+
+/// Formats a duration compactly: `850ms`, `12.3s`, `4m05s`
+pub fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    if millis < 1_000 {
+        format!("{}ms", millis)
+    } else if millis < 60_000 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else if millis < 3_600_000 {
+        let secs = duration.as_secs();
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        let mins = duration.as_secs() / 60;
+        format!("{}h{:02}m", mins / 60, mins % 60)
+    }
+}
+
+/// Passes everything on, keeping the last result for the run's history
+struct Recording(Box<dyn Renderer>);
+
+static LAST_RESULT: Mutex<Option<Value>> = Mutex::new(None);
+
+impl Renderer for Recording {
+    fn event(&self, event: &Event) {
+        self.0.event(event);
+    }
+
+    fn result(&self, result: &dyn Renderable) {
+        *LAST_RESULT.lock().unwrap_or_else(|e| e.into_inner()) = Some(result.to_json());
+        self.0.result(result);
+    }
+
+    fn error(&self, error: &anyhow::Error) {
+        self.0.error(error);
+    }
+}
+
+/// Structured form of the last result shown, if any
+pub fn last_result() -> Option<Value> {
+    LAST_RESULT
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+static RENDERER: OnceLock<Box<dyn Renderer>> = OnceLock::new();
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+static STYLE: OnceLock<Style> = OnceLock::new();
+
+/// Selects the renderer for the rest of the process, with `style` for human
+/// output. Only the first call has any effect.
+pub fn init(format: OutputFormat, style: Style) {
+    let _ = FORMAT.set(format);
+    let _ = STYLE.set(style);
+    let renderer: Box<dyn Renderer> = match format {
+        OutputFormat::Human => Box::new(HumanRenderer(style)),
+        OutputFormat::Json => Box::new(JsonRenderer::default()),
+        OutputFormat::Ndjson => Box::new(NdjsonRenderer),
+        OutputFormat::Quiet => Box::new(QuietRenderer),
+    };
+    let _ = RENDERER.set(Box::new(Recording(renderer)));
+}
+
+/// The active renderer (human output if [`init`] was never called)
+pub fn renderer() -> &'static dyn Renderer {
+    RENDERER
+        .get_or_init(|| Box::new(Recording(Box::new(HumanRenderer(Style::default())))))
+        .as_ref()
+}
+
+/// The output mode selected with [`init`] (human output if it was never called)
+pub fn format() -> OutputFormat {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+/// The style selected with [`init`]
+pub fn style() -> Style {
+    STYLE.get().copied().unwrap_or_default()
+}
+
+type EventHandler = Box<dyn Fn(&Event) + Send + Sync>;
+
+static EVENT_HANDLER: OnceLock<EventHandler> = OnceLock::new();
+
+/// Calls `handler` with every event from then on, for programs using the
+/// library. Only the first call has any effect.
+pub fn on_event(handler: impl Fn(&Event) + Send + Sync + 'static) {
+    let _ = EVENT_HANDLER.set(Box::new(handler));
+}
+
+/// Reports a lifecycle event to the active renderer and any handler
+pub fn emit(event: Event) {
+    renderer().event(&event);
+    desktop::show(&event);
+    if let Some(handler) = EVENT_HANDLER.get() {
+        handler(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_serialize_with_tag() {
+        let event = Event::ArchDetected {
+            port: 2222,
+            arch: "aarch64".to_string(),
+            board: None,
+            os: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            json!({ "event": "arch_detected", "port": 2222, "arch": "aarch64" })
+        );
+        let failure = Event::Failure {
+            host: "pi.local".to_string(),
+            port: 2222,
+            phase: Some(Phase::Key),
+            error: "denied".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_value(&failure).unwrap(),
+            json!({ "event": "failure", "host": "pi.local", "port": 2222, "phase": "key", "error": "denied" })
+        );
+    }
+
+    #[test]
+    fn test_events_match_the_documented_schema() {
+        let host = || "pi.local".to_string();
+        let events = [
+            Event::TunnelAttempt {
+                host: host(),
+                port: 2222,
+                attempt: 1,
+            },
+            Event::TunnelUp {
+                host: host(),
+                port: 2222,
+            },
+            Event::TunnelValidated { port: 2222 },
+            Event::ClockSet {
+                port: 2222,
+                offset_secs: -3,
+            },
+            Event::ArchDetected {
+                port: 2222,
+                arch: "aarch64".to_string(),
+                board: Some("Raspberry Pi 4".to_string()),
+                os: Some("debian 12".to_string()),
+            },
+            Event::KeyTransferred {
+                port: 2222,
+                key_path: PathBuf::from("id.pub"),
+            },
+            Event::KeyVerified {
+                port: 2222,
+                user: "pi".to_string(),
+            },
+            Event::FilePushed {
+                port: 2222,
+                path: "fw.bin".to_string(),
+                bytes: 1,
+            },
+            Event::Progress {
+                done: 1,
+                total: 2,
+                percent: 50,
+                eta_secs: Some(60),
+            },
+            Event::TunnelLost {
+                host: host(),
+                port: 2222,
+                error: "timeout".to_string(),
+            },
+            Event::TunnelRecovered {
+                host: host(),
+                port: 2222,
+                reconnects: 1,
+            },
+            Event::Failure {
+                host: host(),
+                port: 2222,
+                phase: Some(Phase::Key),
+                error: "denied".to_string(),
+            },
+        ];
+        // A new event fails to compile here until it is sampled above and documented
+        for event in &events {
+            match event {
+                Event::TunnelAttempt { .. }
+                | Event::TunnelUp { .. }
+                | Event::TunnelValidated { .. }
+                | Event::ClockSet { .. }
+                | Event::ArchDetected { .. }
+                | Event::KeyTransferred { .. }
+                | Event::KeyVerified { .. }
+                | Event::FilePushed { .. }
+                | Event::Progress { .. }
+                | Event::TunnelLost { .. }
+                | Event::TunnelRecovered { .. }
+                | Event::Failure { .. } => {}
+            }
+        }
+        assert_eq!(events.len(), EVENTS.len());
+
+        let readme = include_str!("../README.md");
+        for (event, (name, fields, _)) in events.iter().zip(EVENTS) {
+            let Value::Object(mut line) = serde_json::to_value(event).unwrap() else {
+                panic!("{:?} is not an object", event);
+            };
+            assert_eq!(line.remove("event"), Some(Value::from(*name)));
+            let documented: Vec<&str> = fields
+                .iter()
+                .map(|field| field.trim_matches(['[', ']']))
+                .collect();
+            let mut serialized: Vec<&str> = line.keys().map(String::as_str).collect();
+            serialized.sort_by_key(|key| documented.iter().position(|field| field == key));
+            assert_eq!(serialized, documented, "fields of {}", name);
+            assert!(
+                readme.contains(&format!("`{}`", name)),
+                "README leaves out {}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_status_lines() {
+        let up = Event::TunnelUp {
+            host: "pi.local".to_string(),
+            port: 2222,
+        };
+        assert_eq!(
+            status_line(&up),
+            Some((true, "Tunnel to pi.local on localhost:2222".to_string()))
+        );
+        let first = Event::TunnelAttempt {
+            host: "pi.local".to_string(),
+            port: 2222,
+            attempt: 1,
+        };
+        assert_eq!(status_line(&first), None);
+        let failure = Event::Failure {
+            host: "pi.local".to_string(),
+            port: 2222,
+            phase: Some(Phase::Arch),
+            error: "x86_64".to_string(),
+        };
+        assert_eq!(
+            status_line(&failure),
+            Some((false, "pi.local failed in the arch phase".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_table_aligns_columns() {
+        let rows = vec![
+            vec!["pi1".to_string(), "FAILED".to_string()],
+            vec!["jetson-nano".to_string(), "ok".to_string()],
+        ];
+        assert_eq!(
+            table(&["HOST", "RESULT"], &rows),
+            "HOST         RESULT\npi1          FAILED\njetson-nano  ok"
+        );
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_millis(850)), "850ms");
+        assert_eq!(format_duration(Duration::from_millis(12_340)), "12.3s");
+        assert_eq!(format_duration(Duration::from_secs(245)), "4m05s");
+        assert_eq!(format_duration(Duration::from_secs(7_530)), "2h05m");
+    }
+}
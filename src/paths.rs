@@ -0,0 +1,214 @@
+//! Home, configuration and cache directory resolution.
+//!
+//! Static musl builds are often run inside provisioning containers where `HOME`
+//! is unset and the running UID has no passwd entry, so every lookup here has an
+//! explicit fallback or a clear error instead of a silent `None`.
+//!
+//! With a namespace (`--namespace`), the configuration, state and cache
+//! directories and the tunnels' sockets are the namespace's own, so teams
+//! sharing a machine don't see each other's profiles, known hosts or tunnels.
+
+use crate::TunnelError;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tracing::debug;
+
+/// System-wide configuration directory, used when no per-user config directory exists
+pub const SYSTEM_CONFIG_DIR: &str = "/etc/ssh_ip_tunnel";
+
+static NAMESPACE: OnceLock<String> = OnceLock::new();
+
+/// Keeps this process's files apart under namespace `name`, which must have
+/// passed [`crate::validate::validate_namespace`]; only the first call counts
+pub fn set_namespace(name: String) {
+    let _ = NAMESPACE.set(name);
+}
+
+/// The namespace set with [`set_namespace`], if any
+pub fn namespace() -> Option<&'static str> {
+    NAMESPACE.get().map(String::as_str)
+}
+
+/// `dir`, or the namespace's directory within it
+fn namespaced(dir: PathBuf, namespace: Option<&str>) -> PathBuf {
+    match namespace {
+        Some(name) => dir.join("namespaces").join(name),
+        None => dir,
+    }
+}
+
+/// Returns the current user's home directory
+pub fn home_dir() -> Result<PathBuf, TunnelError> {
+    dirs::home_dir()
+        .filter(|home| !home.as_os_str().is_empty())
+        .ok_or(TunnelError::NoHomeDirectory)
+}
+
+/// Expands a leading `~` or `~/` to the home directory
+pub fn expand_tilde(path: &str) -> Result<PathBuf, TunnelError> {
+    if path == "~" {
+        return home_dir();
+    }
+    match path.strip_prefix("~/") {
+        Some(rest) => Ok(home_dir()?.join(rest)),
+        None => Ok(PathBuf::from(path)),
+    }
+}
+
+/// Returns the per-user configuration directory for this tool, if one can be determined
+pub fn user_config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| namespaced(dir.join("ssh_ip_tunnel"), namespace()))
+}
+
+/// Returns the directory for sockets: `$XDG_RUNTIME_DIR`, or else this
+/// user's own directory in the system temp directory. Anything that creates
+/// files in it goes through [`private_runtime_dir`] first
+pub fn runtime_dir() -> PathBuf {
+    dirs::runtime_dir().unwrap_or_else(|| {
+        #[cfg(unix)]
+        let name = format!("ssh_ip_tunnel-{}", user_id());
+        #[cfg(not(unix))]
+        let name = "ssh_ip_tunnel".to_string();
+        std::env::temp_dir().join(name)
+    })
+}
+
+/// Returns [`runtime_dir`], created for this user alone if it is missing.
+///
+/// One that another user owns, or that others may write to, is refused: they
+/// could swap the tunnels' sockets for their own.
+pub fn private_runtime_dir() -> Result<PathBuf, TunnelError> {
+    private(runtime_dir())
+}
+
+fn private(dir: PathBuf) -> Result<PathBuf, TunnelError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+        let refuse =
+            |reason: &str| TunnelError::RuntimeDir(format!("{}: {}", dir.display(), reason));
+        match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => debug!("Created {}", dir.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(refuse(&e.to_string())),
+        }
+        let metadata = std::fs::symlink_metadata(&dir).map_err(|e| refuse(&e.to_string()))?;
+        if !metadata.is_dir() {
+            return Err(refuse("not a directory"));
+        }
+        if metadata.uid() != user_id() {
+            return Err(refuse(&format!(
+                "it belongs to uid {}, not this user",
+                metadata.uid()
+            )));
+        }
+        if metadata.mode() & 0o022 != 0 {
+            return Err(refuse("other users can write to it; chmod 700 it"));
+        }
+    }
+    Ok(dir)
+}
+
+/// The user this process runs as
+#[cfg(unix)]
+pub fn user_id() -> u32 {
+    // SAFETY: geteuid has no preconditions and can't fail
+    unsafe { libc::geteuid() }
+}
+
+/// Returns `name` in [`runtime_dir`], prefixed with the tool's name and namespace
+pub fn runtime_file(name: &str) -> PathBuf {
+    let prefix = match namespace() {
+        Some(namespace) => format!("ssh_ip_tunnel-{}-", namespace),
+        None => "ssh_ip_tunnel-".to_string(),
+    };
+    runtime_dir().join(prefix + name)
+}
+
+/// Returns the directory for downloaded files, falling back to the system temp directory
+pub fn cache_dir() -> PathBuf {
+    let dir = dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("ssh_ip_tunnel");
+    namespaced(dir, namespace())
+}
+
+/// Returns the directory for state kept between runs, falling back to the cache directory
+pub fn state_dir() -> PathBuf {
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .map(|dir| namespaced(dir.join("ssh_ip_tunnel"), namespace()))
+        .unwrap_or_else(cache_dir)
+}
+
+/// Returns the configuration file that should be loaded when `--config` is not given.
+///
+/// The per-user file wins; the system-wide file is the fallback for containers
+/// that have no usable home directory.
+pub fn default_config_path() -> Option<PathBuf> {
+    if let Some(path) = user_config_dir().map(|dir| dir.join("config.toml")) {
+        if path.exists() {
+            return Some(path);
+        }
+    } else {
+        debug!("No user configuration directory available (HOME unset?)");
+    }
+
+    let system = namespaced(PathBuf::from(SYSTEM_CONFIG_DIR), namespace()).join("config.toml");
+    system.exists().then_some(system)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_tilde_leaves_plain_paths_alone() {
+        assert_eq!(
+            expand_tilde("/etc/ssh/key.pub").unwrap(),
+            PathBuf::from("/etc/ssh/key.pub")
+        );
+        assert_eq!(expand_tilde("~user/x").unwrap(), PathBuf::from("~user/x"));
+    }
+
+    #[test]
+    fn test_namespaced_dirs() {
+        let dir = PathBuf::from("/home/pi/.local/state/ssh_ip_tunnel");
+        assert_eq!(namespaced(dir.clone(), None), dir);
+        assert_eq!(
+            namespaced(dir, Some("team-a")),
+            PathBuf::from("/home/pi/.local/state/ssh_ip_tunnel/namespaces/team-a")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_runtime_dir_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let base = std::env::temp_dir().join(format!("runtime_test_{}", std::process::id()));
+        std::fs::create_dir_all(&base).unwrap();
+
+        let dir = private(base.join("created")).unwrap();
+        let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        assert!(private(dir).is_ok());
+
+        let shared = base.join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o777)).unwrap();
+        let error = private(shared).unwrap_err().to_string();
+        assert!(
+            error.ends_with("other users can write to it; chmod 700 it"),
+            "{}",
+            error
+        );
+
+        let file = base.join("file");
+        std::fs::write(&file, "").unwrap();
+        assert!(private(file)
+            .unwrap_err()
+            .to_string()
+            .ends_with("not a directory"));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
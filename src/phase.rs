@@ -0,0 +1,170 @@
+//! The phases of a provisioning run.
+
+use crate::TunnelError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// One step of [`crate::SSHTunnelManager::run`], in execution order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Phase {
+    Tunnel,
+    Validate,
+    Clock,
+    Arch,
+    Key,
+    Hardware,
+    Swap,
+    Provision,
+    Harden,
+}
+
+impl Phase {
+    /// Every phase, in execution order
+    pub const ALL: [Phase; 9] = [
+        Phase::Tunnel,
+        Phase::Validate,
+        Phase::Clock,
+        Phase::Arch,
+        Phase::Key,
+        Phase::Hardware,
+        Phase::Swap,
+        Phase::Provision,
+        Phase::Harden,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::Tunnel => "tunnel",
+            Phase::Validate => "validate",
+            Phase::Clock => "clock",
+            Phase::Arch => "arch",
+            Phase::Key => "key",
+            Phase::Hardware => "hardware",
+            Phase::Swap => "swap",
+            Phase::Provision => "provision",
+            Phase::Harden => "harden",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Phase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Phase::ALL
+            .into_iter()
+            .find(|phase| phase.as_str() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Phase::ALL.iter().map(|phase| phase.as_str()).collect();
+                format!(
+                    "unknown phase '{}', expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Which phases a run performs, always in the order of [`Phase::ALL`]; every
+/// phase unless narrowed with `--only` or `--skip`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Phases(BTreeSet<Phase>);
+
+impl Default for Phases {
+    fn default() -> Self {
+        Self(Phase::ALL.into_iter().collect())
+    }
+}
+
+impl Phases {
+    /// Just `phases`
+    pub fn only(phases: &[Phase]) -> Self {
+        Self(phases.iter().copied().collect())
+    }
+
+    /// Every phase but `phases`
+    pub fn skipping(phases: &[Phase]) -> Self {
+        Self(
+            Phase::ALL
+                .into_iter()
+                .filter(|phase| !phases.contains(phase))
+                .collect(),
+        )
+    }
+
+    pub fn contains(&self, phase: Phase) -> bool {
+        self.0.contains(&phase)
+    }
+
+    /// Checks that something runs, and that the tunnel every other phase
+    /// runs through is among it
+    pub fn check(&self) -> Result<(), TunnelError> {
+        match self.0.first() {
+            None => Err(TunnelError::InvalidPhases(
+                "every phase is skipped, so nothing would run".to_string(),
+            )),
+            Some(Phase::Tunnel) => Ok(()),
+            Some(phase) => Err(TunnelError::InvalidPhases(format!(
+                "{} needs the tunnel, which every other phase runs through",
+                phase
+            ))),
+        }
+    }
+}
+
+/// A [`TunnelError`] tagged with the phase it interrupted. Displays as the
+/// underlying error so single-host messages read exactly as before.
+#[derive(Debug, Error)]
+#[error("{source}")]
+#[non_exhaustive]
+pub struct PhaseError {
+    pub phase: Phase,
+    #[source]
+    pub source: TunnelError,
+}
+
+impl PhaseError {
+    /// Returns a closure tagging an error with `phase`, for use with `map_err`
+    pub fn at(phase: Phase) -> impl FnOnce(TunnelError) -> PhaseError {
+        move |source| PhaseError { phase, source }
+    }
+}
+
+/// Finds the phase a run failed in, if the error came from a phase
+pub fn failed_phase(error: &anyhow::Error) -> Option<Phase> {
+    error.downcast_ref::<PhaseError>().map(|e| e.phase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_selection_needs_the_tunnel() {
+        assert!(Phases::default().check().is_ok());
+        assert!(Phases::only(&[Phase::Key, Phase::Tunnel]).check().is_ok());
+
+        let phases = Phases::skipping(&[Phase::Validate, Phase::Arch]);
+        assert!(phases.check().is_ok());
+        assert!(!phases.contains(Phase::Arch));
+        assert!(phases.contains(Phase::Key));
+
+        let error = Phases::skipping(&[Phase::Tunnel])
+            .check()
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("validate needs the tunnel"), "{}", error);
+        assert!(Phases::only(&[]).check().is_err());
+    }
+}
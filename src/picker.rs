@@ -0,0 +1,180 @@
+//! Picking the device on a terminal, when the command line names none.
+//!
+//! Offers the hosts of recent runs, newest first, then the remaining host
+//! profiles. Typing a number picks that entry, Enter the first; anything else
+//! narrows the list with a fuzzy match, and text matching nothing is taken as
+//! a new host.
+
+use crate::config::Config;
+use crate::history;
+use crate::prompt;
+use crate::pure::fuzzy;
+use std::io;
+
+/// Entries listed at a time
+const SHOWN: usize = 9;
+
+/// The device picked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Choice {
+    /// A host profile, by name
+    Profile(String),
+    /// A host, and its user if it is known
+    Login { host: String, user: Option<String> },
+}
+
+struct Entry {
+    label: String,
+    choice: Choice,
+}
+
+/// `recent` logins, newest first, shown as their profile where one has the
+/// same host and user, then the other profiles
+fn entries(config: &Config, recent: Vec<(String, String)>) -> Vec<Entry> {
+    let profile_entry = |name: &String| {
+        let profile = &config.hosts[name];
+        let label = match (&profile.user, &profile.host) {
+            (Some(user), Some(host)) => format!("{}  ({}@{})", name, user, host),
+            (None, Some(host)) => format!("{}  ({})", name, host),
+            _ => name.clone(),
+        };
+        Entry {
+            label,
+            choice: Choice::Profile(name.clone()),
+        }
+    };
+    let mut entries: Vec<Entry> = Vec::new();
+    for (host, user) in recent {
+        let profile = config.hosts.iter().find(|(_, profile)| {
+            profile.host.as_ref() == Some(&host) && profile.user.as_ref() == Some(&user)
+        });
+        let entry = match profile {
+            Some((name, _)) => profile_entry(name),
+            None => Entry {
+                label: format!("{}@{}", user, host),
+                choice: Choice::Login {
+                    host,
+                    user: Some(user),
+                },
+            },
+        };
+        if !entries.iter().any(|e| e.choice == entry.choice) {
+            entries.push(entry);
+        }
+    }
+    for name in config.hosts.keys() {
+        let profile = Choice::Profile(name.clone());
+        if !entries.iter().any(|e| e.choice == profile) {
+            entries.push(profile_entry(name));
+        }
+    }
+    entries
+}
+
+/// Asks which device to use; None when there is nothing to offer
+pub fn pick(config: &Config) -> io::Result<Option<Choice>> {
+    let entries = entries(config, history::recent_logins());
+    if entries.is_empty() {
+        return Ok(None);
+    }
+    let labels: Vec<&str> = entries.iter().map(|e| e.label.as_str()).collect();
+    let mut shown = fuzzy::rank("", &labels);
+    loop {
+        shown.truncate(SHOWN);
+        for (number, &index) in shown.iter().enumerate() {
+            prompt::notice(&format!("  {}) {}", number + 1, labels[index]));
+        }
+        let answer = prompt::ask("Host (number, or text to search)", "1")?;
+        match choose(&answer, &shown, &entries) {
+            Ok(choice) => return Ok(Some(choice)),
+            Err(matches) => shown = matches,
+        }
+    }
+}
+
+/// The entry `answer` picks from those `shown`, or the entries it narrows the
+/// list to
+fn choose(answer: &str, shown: &[usize], entries: &[Entry]) -> Result<Choice, Vec<usize>> {
+    if let Some(&index) = answer
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| shown.get(number.checked_sub(1)?))
+    {
+        return Ok(entries[index].choice.clone());
+    }
+    let labels: Vec<&str> = entries.iter().map(|e| e.label.as_str()).collect();
+    let matches = fuzzy::rank(answer, &labels);
+    match matches.as_slice() {
+        [] => Ok(Choice::Login {
+            host: answer.to_string(),
+            user: None,
+        }),
+        [index] => Ok(entries[*index].choice.clone()),
+        _ => Err(matches),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HostProfile;
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        for (name, host) in [("rpi4-lab", "10.0.0.5"), ("zero-bench", "10.0.0.9")] {
+            config.hosts.insert(
+                name.to_string(),
+                HostProfile {
+                    host: Some(host.to_string()),
+                    user: Some("pi".to_string()),
+                    ..Default::default()
+                },
+            );
+        }
+        config
+    }
+
+    #[test]
+    fn test_recent_logins_come_first_as_their_profile() {
+        let recent = vec![
+            ("10.0.0.9".to_string(), "pi".to_string()),
+            ("pi.local".to_string(), "admin".to_string()),
+            ("10.0.0.9".to_string(), "pi".to_string()),
+        ];
+        let labels: Vec<String> = entries(&config(), recent)
+            .into_iter()
+            .map(|e| e.label)
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                "zero-bench  (pi@10.0.0.9)",
+                "admin@pi.local",
+                "rpi4-lab  (pi@10.0.0.5)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_answers_pick_by_number_or_search() {
+        let entries = entries(&config(), Vec::new());
+        let shown = [1, 0];
+        assert_eq!(
+            choose("1", &shown, &entries),
+            Ok(Choice::Profile("zero-bench".to_string()))
+        );
+        assert_eq!(
+            choose("rpi4", &shown, &entries),
+            Ok(Choice::Profile("rpi4-lab".to_string()))
+        );
+        // Numbers past the list are searched for, and a new host is taken as is
+        assert_eq!(
+            choose("7", &shown, &entries),
+            Ok(Choice::Login {
+                host: "7".to_string(),
+                user: None
+            })
+        );
+        assert_eq!(choose("pi", &shown, &entries).unwrap_err().len(), 2);
+    }
+}
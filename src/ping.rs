@@ -0,0 +1,244 @@
+//! `ping`: where the time goes on the way to a device.
+//!
+//! Three probes, each repeated `--count` times: TCP connections to the
+//! device's sshd straight from here, which is the network alone; lines echoed
+//! back by one shell session through the tunnel, which adds SSH; and new
+//! commands through the tunnel, which add starting a session and a process on
+//! the board. Comparing them tells a slow WiFi from a slow tunnel or board.
+
+use crate::config::Config;
+use crate::executor::Stdio;
+use crate::output::{self, Renderable};
+use crate::pure::ping::{bottleneck, Bottleneck, Latency};
+use crate::shell::RemoteCommand;
+use crate::ssh::{self, RunError};
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::{sleep, timeout};
+use tracing::{debug, info};
+
+/// How long one round trip may take before it counts as lost
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upper bound for logging in for the echo session
+const SESSION_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Says it's ready, then echoes each line back until stdin closes
+pub const ECHO_SCRIPT: &str = r#"echo ready
+while IFS= read -r line; do echo "$line"; done"#;
+
+/// Result of `ping`
+#[derive(Debug, Serialize)]
+pub struct PingReport {
+    pub host: String,
+    /// TCP connections to the device's sshd, bypassing the tunnel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<Latency>,
+    /// Why the network wasn't measured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network_skipped: Option<String>,
+    /// Lines echoed by one shell session through the tunnel
+    pub tunnel: Latency,
+    /// New commands through the tunnel
+    pub command: Latency,
+    pub bottleneck: Bottleneck,
+}
+
+impl Renderable for PingReport {
+    fn to_human(&self) -> String {
+        let ms = |ms: f64| format!("{:.1}ms", ms);
+        let row = |probe: &str, latency: &Latency| {
+            vec![
+                probe.to_string(),
+                ms(latency.min_ms),
+                ms(latency.avg_ms),
+                ms(latency.max_ms),
+                ms(latency.jitter_ms),
+                format!("{}/{}", latency.lost, latency.sent),
+            ]
+        };
+        let mut rows: Vec<Vec<String>> = self
+            .network
+            .iter()
+            .map(|network| row("network", network))
+            .collect();
+        rows.push(row("tunnel", &self.tunnel));
+        rows.push(row("command", &self.command));
+
+        let mut text = format!(
+            "Round trips to {}:\n{}\n",
+            self.host,
+            output::table(&["PROBE", "MIN", "AVG", "MAX", "JITTER", "LOST"], &rows)
+        );
+        if let Some(reason) = &self.network_skipped {
+            text.push_str(&format!("Network not measured: {}\n", reason));
+        }
+        let culprit = match self.bottleneck {
+            Bottleneck::Network => "the network between here and the device, e.g. WiFi",
+            Bottleneck::Tunnel if self.network.is_none() => {
+                "getting through to the device, over the network and SSH"
+            }
+            Bottleneck::Tunnel => "SSH through the tunnel",
+            Bottleneck::Board => "the board, starting sessions and commands",
+        };
+        text.push_str(&format!("Most of the time goes to {}", culprit));
+        text
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Measures `count` round trips of each kind to `target`, `interval` apart,
+/// reusing its tunnel if one is open
+pub async fn run(
+    config: &Config,
+    target: &Target,
+    count: u32,
+    interval: Duration,
+) -> Result<PingReport> {
+    let manager = SSHTunnelManager::new(config.clone());
+    manager.reuse_or_connect(target).await?;
+
+    let (network, network_skipped) = match network(target, count, interval).await {
+        Ok(latency) => (Some(latency), None),
+        Err(reason) => (None, Some(reason)),
+    };
+    let tunnel = echo(target, count, interval).await?;
+    let command = commands(target, count, interval).await?;
+    Ok(PingReport {
+        host: target.host.clone(),
+        bottleneck: bottleneck(network.as_ref(), &tunnel, &command),
+        network,
+        network_skipped,
+        tunnel,
+        command,
+    })
+}
+
+/// Times TCP connections to the device's sshd, or says why it can't
+async fn network(target: &Target, count: u32, interval: Duration) -> Result<Latency, String> {
+    if target.proxy_jump.is_some() {
+        return Err("the device is only reached through a jump host".to_string());
+    }
+    if target.simulated.is_some() {
+        return Err("simulated devices have no network".to_string());
+    }
+    let port = target.remote_port.unwrap_or(ssh::DEFAULT_SSH_PORT);
+    // Resolved once, so that the name lookup isn't timed
+    let address = lookup_host((target.host.as_str(), port))
+        .await
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+        .ok_or_else(|| format!("cannot resolve {}", target.host))?;
+
+    info!("Connecting to {} directly...", address);
+    let mut samples = Vec::new();
+    for i in 0..count {
+        if i > 0 {
+            sleep(interval).await;
+        }
+        let started = Instant::now();
+        match timeout(PROBE_TIMEOUT, TcpStream::connect(address)).await {
+            Ok(Ok(_)) => samples.push(started.elapsed()),
+            Ok(Err(e)) => debug!("Connecting to {} failed: {}", address, e),
+            Err(_) => debug!("Connecting to {} timed out", address),
+        }
+    }
+    Latency::from_samples(&samples, count)
+        .ok_or_else(|| format!("no connection to {} succeeded", address))
+}
+
+/// Times lines echoed back by a shell session through the tunnel
+async fn echo(target: &Target, count: u32, interval: Duration) -> Result<Latency, TunnelError> {
+    info!("Echoing through the tunnel to {}...", target.host);
+    let command = RemoteCommand::new("sh").arg("-c").arg(ECHO_SCRIPT);
+    let mut child = ssh::through_tunnel(target, &command)?
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| TunnelError::Ping(e.to_string()))?;
+    let (Some(mut stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        return Err(TunnelError::Ping(
+            "no pipes to the echo session".to_string(),
+        ));
+    };
+    let mut lines = BufReader::new(stdout).lines();
+
+    // The login isn't part of any round trip
+    let ready = timeout(SESSION_TIMEOUT, lines.next_line()).await;
+    if !matches!(&ready, Ok(Ok(Some(line))) if line == "ready") {
+        let _ = child.start_kill();
+        let mut stderr = String::new();
+        if let Some(mut pipe) = child.stderr.take() {
+            let _ = timeout(Duration::from_secs(1), pipe.read_to_string(&mut stderr)).await;
+        }
+        return Err(TunnelError::Ping(match ready {
+            Err(_) => "timeout starting the echo session".to_string(),
+            Ok(_) if stderr.trim().is_empty() => "the echo session ended".to_string(),
+            Ok(_) => stderr.trim().to_string(),
+        }));
+    }
+
+    let mut samples = Vec::new();
+    for seq in 0..count {
+        if seq > 0 {
+            sleep(interval).await;
+        }
+        let expected = seq.to_string();
+        let started = Instant::now();
+        if stdin
+            .write_all(format!("{}\n", expected).as_bytes())
+            .await
+            .is_err()
+        {
+            break;
+        }
+        // Late answers to lost probes are skipped by their number
+        let answer = timeout(PROBE_TIMEOUT, async {
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line == expected {
+                    return true;
+                }
+            }
+            false
+        })
+        .await;
+        match answer {
+            Ok(true) => samples.push(started.elapsed()),
+            Ok(false) => break,
+            Err(_) => debug!("Echo {} through the tunnel timed out", seq),
+        }
+    }
+    drop(stdin);
+    let _ = timeout(Duration::from_secs(1), child.wait()).await;
+
+    Latency::from_samples(&samples, count)
+        .ok_or_else(|| TunnelError::Ping("no line came back through the tunnel".to_string()))
+}
+
+/// Times new commands through the tunnel, each its own session
+async fn commands(target: &Target, count: u32, interval: Duration) -> Result<Latency, TunnelError> {
+    info!("Running commands through the tunnel to {}...", target.host);
+    let command = RemoteCommand::new("true");
+    let mut samples = Vec::new();
+    for i in 0..count {
+        if i > 0 {
+            sleep(interval).await;
+        }
+        let started = Instant::now();
+        match ssh::run_through_tunnel(target, &command, PROBE_TIMEOUT).await {
+            Ok(_) => samples.push(started.elapsed()),
+            Err(RunError::Ssh(e)) => return Err(e),
+            Err(e) => debug!("Command through the tunnel failed: {}", e),
+        }
+    }
+    Latency::from_samples(&samples, count)
+        .ok_or_else(|| TunnelError::Ping("no command through the tunnel succeeded".to_string()))
+}
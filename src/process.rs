@@ -0,0 +1,86 @@
+//! Spawning of external programs (`ssh`, `ssh-keygen`, ...).
+//!
+//! Programs are resolved against `PATH` up front so a missing binary is reported
+//! by name rather than as a bare "No such file or directory". Minimal containers
+//! frequently run without `PATH` at all, in which case the conventional system
+//! directories are searched instead.
+
+use crate::TunnelError;
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::Command;
+
+/// Search path used when `PATH` is unset or empty
+const FALLBACK_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Locates an executable by name, searching `PATH` (or the fallback path)
+pub fn find_program(name: &str) -> Result<PathBuf, TunnelError> {
+    if name.contains(std::path::MAIN_SEPARATOR) {
+        let path = PathBuf::from(name);
+        return if is_executable(&path) {
+            Ok(path)
+        } else {
+            Err(TunnelError::MissingProgram(name.to_string()))
+        };
+    }
+
+    let search_path = env::var_os("PATH")
+        .filter(|p| !p.is_empty())
+        .unwrap_or_else(|| OsString::from(FALLBACK_PATH));
+
+    env::split_paths(&search_path)
+        .flat_map(|dir| candidates(&dir, name))
+        .find(|candidate| is_executable(candidate))
+        .ok_or_else(|| TunnelError::MissingProgram(name.to_string()))
+}
+
+/// Builds a command for an external program with non-interactive defaults.
+///
+/// Stdin is detached so child processes never compete for the terminal, and
+/// children are killed if the future driving them is dropped (e.g. on timeout).
+pub fn command(name: &str) -> Result<Command, TunnelError> {
+    let program = find_program(name)?;
+    let mut cmd = Command::new(program);
+    cmd.stdin(Stdio::null()).kill_on_drop(true);
+    Ok(cmd)
+}
+
+#[cfg(windows)]
+fn candidates(dir: &Path, name: &str) -> Vec<PathBuf> {
+    vec![dir.join(name), dir.join(format!("{}.exe", name))]
+}
+
+#[cfg(not(windows))]
+fn candidates(dir: &Path, name: &str) -> Vec<PathBuf> {
+    vec![dir.join(name)]
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_program_is_reported_by_name() {
+        match find_program("definitely-not-a-real-program-xyz") {
+            Err(TunnelError::MissingProgram(name)) => {
+                assert_eq!(name, "definitely-not-a-real-program-xyz")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
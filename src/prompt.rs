@@ -0,0 +1,57 @@
+//! Interactive prompts on the controlling terminal.
+
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::{Mutex, MutexGuard};
+
+/// Set by `--batch`: nothing may wait for an answer
+static BATCH: AtomicBool = AtomicBool::new(false);
+
+/// Held while a child process may prompt, so parallel group runs ask one at a time
+static TERMINAL: Mutex<()> = Mutex::const_new(());
+
+/// Turns prompting off for the rest of the process, so whatever would ask fails instead
+pub fn disable() {
+    BATCH.store(true, Ordering::Relaxed);
+}
+
+/// Whether `--batch` turned prompting off
+pub fn is_batch() -> bool {
+    BATCH.load(Ordering::Relaxed)
+}
+
+/// Whether prompting is possible (stdin and stderr are both terminals, and
+/// not in batch mode)
+pub fn is_interactive() -> bool {
+    !is_batch() && io::stdin().is_terminal() && io::stderr().is_terminal()
+}
+
+/// Waits until no other task is prompting on the terminal
+pub async fn lock_terminal() -> MutexGuard<'static, ()> {
+    TERMINAL.lock().await
+}
+
+/// Tells the user to do something, on stderr even when logging is quiet
+pub fn notice(message: &str) {
+    let _ = writeln!(io::stderr(), "{}", message);
+}
+
+/// Asks for a value on stderr, returning `default` when the answer is empty
+pub fn ask(question: &str, default: &str) -> io::Result<String> {
+    let mut stderr = io::stderr();
+    if default.is_empty() {
+        write!(stderr, "{}: ", question)?;
+    } else {
+        write!(stderr, "{} [{}]: ", question, default)?;
+    }
+    stderr.flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(if answer.is_empty() {
+        default.to_string()
+    } else {
+        answer.to_string()
+    })
+}
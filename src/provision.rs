@@ -0,0 +1,198 @@
+//! The provision phase: a script of the user's, run on the device once the key works.
+//!
+//! The script is sent over the tunnel into a private temporary file and run
+//! there in the same session, through `sudo -n` when asked (directly when the
+//! login user is root). A script starting with `#!` runs with its interpreter,
+//! anything else with `sh`. Its output is logged line by line as it arrives,
+//! and the file is removed whatever the outcome.
+
+use crate::executor::Stdio;
+use crate::output;
+use crate::shell::{self, RemoteCommand};
+use crate::ssh;
+use crate::{Target, TunnelError};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::time::timeout;
+use tracing::info;
+
+/// Upper bound for a provisioning script, e.g. installing packages on a slow board
+const PROVISION_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Stores stdin in a temporary file and runs it, as root when `$1` is `sudo`
+pub const PROVISION_SCRIPT: &str = r#"set -e
+umask 077
+script=$(mktemp "${TMPDIR:-/tmp}/ssh_ip_tunnel-provision.XXXXXX")
+trap 'rm -f "$script"' EXIT
+cat > "$script"
+chmod 700 "$script"
+if [ "$(head -c 2 "$script")" = '#!' ]; then set -- "$1" "$script"; else set -- "$1" sh "$script"; fi
+if [ "$1" = sudo ]; then
+  shift
+  as_root "$@" < /dev/null
+else
+  shift
+  "$@" < /dev/null
+fi"#;
+
+/// A script to run on the device after the key transfer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Provision {
+    pub script: PathBuf,
+    /// Run it as root, through `sudo -n` unless the login user is root
+    pub sudo: bool,
+}
+
+/// What the provisioning script did
+#[derive(Debug, Clone, Serialize)]
+pub struct ProvisionReport {
+    pub script: PathBuf,
+    pub sudo: bool,
+    /// Lines it printed, on stdout and stderr together
+    pub lines: usize,
+    pub duration_ms: u64,
+}
+
+impl ProvisionReport {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} ran{} in {}, printing {} lines",
+            script_name(&self.script),
+            if self.sudo { " as root" } else { "" },
+            output::format_duration(Duration::from_millis(self.duration_ms)),
+            self.lines
+        )
+    }
+}
+
+fn script_name(script: &Path) -> String {
+    script
+        .file_name()
+        .unwrap_or(script.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Logs each line `reader` yields under `name`, returning the count and the last one
+async fn relay<R: AsyncRead + Unpin>(reader: Option<R>, name: &str) -> (usize, Option<String>) {
+    let Some(reader) = reader else {
+        return (0, None);
+    };
+    let mut lines = BufReader::new(reader).lines();
+    let (mut count, mut last) = (0, None);
+    while let Ok(Some(line)) = lines.next_line().await {
+        info!("{}: {}", name, line);
+        count += 1;
+        if !line.trim().is_empty() {
+            last = Some(line);
+        }
+    }
+    (count, last)
+}
+
+/// Uploads and runs `provision` on `target` through its tunnel
+pub async fn run(target: &Target, provision: &Provision) -> Result<ProvisionReport, TunnelError> {
+    let name = script_name(&provision.script);
+    let contents = tokio::fs::read(&provision.script).await.map_err(|e| {
+        TunnelError::Provision(format!("cannot read {}: {}", provision.script.display(), e))
+    })?;
+
+    info!(
+        "Running {} on {}{}...",
+        name,
+        target.host,
+        if provision.sudo { " as root" } else { "" }
+    );
+    let started = Instant::now();
+    let command = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(format!("{}\n{}", shell::AS_ROOT, PROVISION_SCRIPT))
+        .arg("sh")
+        .arg(if provision.sudo { "sudo" } else { "user" });
+    let mut child = ssh::through_tunnel(target, &command)?
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| TunnelError::Provision(e.to_string()))?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let upload = async move {
+        let written = stdin.write_all(&contents).await;
+        drop(stdin);
+        written
+    };
+    let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+    let finished = timeout(PROVISION_TIMEOUT, async {
+        let (uploaded, (out_lines, out_last), (err_lines, err_last)) =
+            tokio::join!(upload, relay(stdout, &name), relay(stderr, &name));
+        let status = child.wait().await;
+        (
+            uploaded,
+            out_lines + err_lines,
+            err_last.or(out_last),
+            status,
+        )
+    })
+    .await
+    .map_err(|_| {
+        TunnelError::Provision(format!(
+            "{} still running after {}s",
+            name,
+            PROVISION_TIMEOUT.as_secs()
+        ))
+    })?;
+    let (uploaded, lines, last, status) = finished;
+    let status = status.map_err(|e| TunnelError::Provision(e.to_string()))?;
+
+    if !status.success() {
+        let reason = match (status.code(), last) {
+            (Some(code), Some(line)) => format!("exit code {}: {}", code, line.trim()),
+            (Some(code), None) => format!("exit code {}", code),
+            (None, _) => "killed by a signal".to_string(),
+        };
+        return Err(TunnelError::Provision(format!("{}: {}", name, reason)));
+    }
+    // The script ran, so a failed write can only be one it didn't read to the end
+    uploaded.map_err(|e| TunnelError::Provision(format!("uploading {}: {}", name, e)))?;
+    Ok(ProvisionReport {
+        script: provision.script.clone(),
+        sudo: provision.sudo,
+        lines,
+        duration_ms: started.elapsed().as_millis() as u64,
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// Runs the remote side here, with `script` as what gets uploaded
+    async fn run_locally(script: &str) -> std::process::Output {
+        let mut child = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("{}\n{}", shell::AS_ROOT, PROVISION_SCRIPT))
+            .args(["sh", "user"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(script.as_bytes()).await.unwrap();
+        drop(stdin);
+        child.wait_with_output().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_provision_script_runs_the_upload() {
+        let output = run_locally("echo \"running as $0\"\n").await;
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("ssh_ip_tunnel-provision."), "{}", stdout);
+
+        let output = run_locally("#!/bin/sh\nexit 3\n").await;
+        assert_eq!(output.status.code(), Some(3));
+    }
+}
@@ -0,0 +1,155 @@
+//! CPU architectures, and which of them count as ARM boards.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// A CPU architecture, as `uname -m` names it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Arch {
+    /// 64-bit ARM (`aarch64`, `arm64`)
+    Aarch64,
+    /// 32-bit userland on an ARMv8 core (`armv8l`)
+    Armv8,
+    /// `armv7l`, `armhf`
+    Armv7,
+    /// `armv6l`, e.g. the first Raspberry Pis and the Zero
+    Armv6,
+    /// Any older or unnamed 32-bit ARM (`armv5tel`, `armel`, `arm`)
+    Arm,
+    X86_64,
+    /// 32-bit x86 (`i686`, `i386`)
+    X86,
+    Riscv64,
+    /// Anything else, by its `uname -m` name
+    Other(String),
+}
+
+impl Arch {
+    /// What the ARM check accepts unless `allowed_architectures` says otherwise
+    pub const ARM_FAMILY: &'static [Arch] = &[
+        Arch::Aarch64,
+        Arch::Armv8,
+        Arch::Armv7,
+        Arch::Armv6,
+        Arch::Arm,
+    ];
+
+    /// Reads `uname -m` output; never fails, as unknown names become [`Arch::Other`]
+    pub fn parse(machine: &str) -> Self {
+        let machine = machine.trim().to_ascii_lowercase();
+        match machine.as_str() {
+            "aarch64" | "aarch64_be" | "arm64" => Arch::Aarch64,
+            "armhf" => Arch::Armv7,
+            "x86_64" | "amd64" => Arch::X86_64,
+            "x86" | "i386" | "i486" | "i586" | "i686" => Arch::X86,
+            "riscv64" => Arch::Riscv64,
+            m if m.starts_with("armv8") => Arch::Armv8,
+            m if m.starts_with("armv7") => Arch::Armv7,
+            m if m.starts_with("armv6") => Arch::Armv6,
+            m if m.starts_with("arm") => Arch::Arm,
+            _ => Arch::Other(machine),
+        }
+    }
+
+    /// The name `uname -m` usually prints for it
+    pub fn as_str(&self) -> &str {
+        match self {
+            Arch::Aarch64 => "aarch64",
+            Arch::Armv8 => "armv8l",
+            Arch::Armv7 => "armv7l",
+            Arch::Armv6 => "armv6l",
+            Arch::Arm => "arm",
+            Arch::X86_64 => "x86_64",
+            Arch::X86 => "i686",
+            Arch::Riscv64 => "riscv64",
+            Arch::Other(name) => name,
+        }
+    }
+
+    pub fn is_arm(&self) -> bool {
+        Arch::ARM_FAMILY.contains(self)
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Parses an architecture given by the user, e.g. to `--require-arch`
+impl FromStr for Arch {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(format!(
+                "invalid architecture {:?}: expected a name such as aarch64, armv7l, x86_64 or riscv64",
+                name
+            ));
+        }
+        Ok(Arch::parse(name))
+    }
+}
+
+impl From<Arch> for String {
+    fn from(arch: Arch) -> Self {
+        arch.to_string()
+    }
+}
+
+impl TryFrom<String> for Arch {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        name.parse()
+    }
+}
+
+/// Whether `arch`, as printed by `uname -m`, is an ARM architecture
+pub fn is_arm(arch: &str) -> bool {
+    Arch::parse(arch).is_arm()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arm_architecture_patterns() {
+        for arch in ["armv7l", "armv6l", "aarch64", "arm64", "armv8l", "armhf"] {
+            assert!(
+                is_arm(arch),
+                "Architecture '{}' should be detected as ARM",
+                arch
+            );
+        }
+    }
+
+    #[test]
+    fn test_non_arm_architecture_patterns() {
+        for arch in ["x86_64", "i686", "i386", "s390x", "ppc64le", "mips64"] {
+            assert!(
+                !is_arm(arch),
+                "Architecture '{}' should NOT be detected as ARM",
+                arch
+            );
+        }
+    }
+
+    #[test]
+    fn test_names_parse_to_one_architecture() {
+        assert_eq!("arm64".parse(), Ok(Arch::Aarch64));
+        assert_eq!(Arch::parse("armv7hl"), Arch::Armv7);
+        // A 32-bit userland is not what an aarch64 build needs
+        assert_ne!(Arch::parse("armv8l"), Arch::Aarch64);
+        assert_eq!(Arch::parse("ppc64le").to_string(), "ppc64le");
+        assert!("aarch64 armv7l".parse::<Arch>().is_err());
+    }
+}
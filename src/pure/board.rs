@@ -0,0 +1,170 @@
+//! Which board a device is, from its device tree model and `/proc/cpuinfo`.
+
+use crate::pure::os::OsInfo;
+use serde::{Deserialize, Serialize};
+
+/// Board families, by a phrase their models contain
+const FAMILIES: &[(&str, &str)] = &[
+    ("Raspberry Pi", "raspberry_pi"),
+    ("Jetson", "jetson"),
+    ("BeagleBone", "beaglebone"),
+    ("BeagleBoard", "beagleboard"),
+    ("BeaglePlay", "beagleboard"),
+    ("Orange Pi", "orange_pi"),
+    ("Banana Pi", "banana_pi"),
+    ("NanoPi", "nanopi"),
+    ("ODROID", "odroid"),
+    ("Radxa", "radxa"),
+    ("ROCK", "radxa"),
+    ("Pine64", "pine64"),
+    ("PINE64", "pine64"),
+    ("Khadas", "khadas"),
+    ("Toradex", "toradex"),
+];
+
+/// What the architecture probe found out about a device
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TargetInfo {
+    /// `uname -m`, e.g. `aarch64`
+    pub arch: String,
+    /// The model as the device names itself, e.g. `Raspberry Pi 4 Model B Rev 1.4`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// The model without its revision, e.g. `Raspberry Pi 4 Model B`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub board: Option<String>,
+    /// The family of known boards it belongs to, e.g. `raspberry_pi`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub family: Option<String>,
+    /// The operating system and kernel it runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<OsInfo>,
+}
+
+impl TargetInfo {
+    /// Parses `arch=`, `model=` (device tree), `cpuinfo_model=`, `dmi=` and
+    /// `hardware=` (cpuinfo) lines, taking the model from the first of those
+    /// in that order, and the operating system's lines (see [`OsInfo::parse`]);
+    /// None without an architecture
+    pub fn parse(output: &str) -> Option<Self> {
+        let value = |name: &str| {
+            output
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .find(|(key, value)| *key == name && !value.trim().is_empty())
+                .map(|(_, value)| value.trim().trim_end_matches('\0').to_string())
+        };
+        let arch = value("arch")?;
+        let model = ["model", "cpuinfo_model", "dmi", "hardware"]
+            .into_iter()
+            .find_map(value);
+        let board = model
+            .as_deref()
+            .map(|model| without_revision(model).to_string());
+        let family = model.as_deref().and_then(family);
+        Some(Self {
+            arch,
+            model,
+            board,
+            family: family.map(str::to_string),
+            os: OsInfo::parse(output),
+        })
+    }
+
+    /// The board and architecture, e.g. `Raspberry Pi 4 Model B (aarch64)`,
+    /// or just the architecture when the board is unknown
+    pub fn describe(&self) -> String {
+        match &self.board {
+            Some(board) => format!("{} ({})", board, self.arch),
+            None => self.arch.clone(),
+        }
+    }
+}
+
+/// `model` without a trailing ` Rev 1.4` or ` Rev. C`
+fn without_revision(model: &str) -> &str {
+    model
+        .rfind(" Rev")
+        .filter(|at| {
+            let rest = model[at + 4..].trim_start_matches('.').trim();
+            !rest.is_empty() && !rest.contains(' ')
+        })
+        .map_or(model, |at| model[..at].trim_end())
+}
+
+/// The family of `model`, if it is a known board
+pub fn family(model: &str) -> Option<&'static str> {
+    FAMILIES
+        .iter()
+        .find(|(phrase, _)| {
+            model
+                .match_indices(phrase)
+                .any(|(at, _)| is_word_start(model, at) && is_word_end(model, at + phrase.len()))
+        })
+        .map(|(_, family)| *family)
+}
+
+fn is_word_start(text: &str, at: usize) -> bool {
+    text[..at]
+        .chars()
+        .next_back()
+        .is_none_or(|c| !c.is_alphanumeric())
+}
+
+fn is_word_end(text: &str, at: usize) -> bool {
+    text[at..].chars().next().is_none_or(|c| !c.is_alphabetic())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_prefers_the_device_tree() {
+        let info = TargetInfo::parse(
+            "arch=aarch64\nmodel=Raspberry Pi 4 Model B Rev 1.4\0\n\
+             cpuinfo_model=Raspberry Pi 4 Model B Rev 1.4\nhardware=BCM2835\n",
+        )
+        .unwrap();
+        assert_eq!(
+            info.model.as_deref(),
+            Some("Raspberry Pi 4 Model B Rev 1.4")
+        );
+        assert_eq!(info.family.as_deref(), Some("raspberry_pi"));
+        assert_eq!(info.describe(), "Raspberry Pi 4 Model B (aarch64)");
+
+        let info = TargetInfo::parse("arch=armv7l\nmodel=\nhardware=BCM2835\n").unwrap();
+        assert_eq!(info.describe(), "BCM2835 (armv7l)");
+        assert_eq!(info.family, None);
+
+        assert_eq!(
+            TargetInfo::parse("arch=x86_64\n").unwrap().describe(),
+            "x86_64"
+        );
+        assert_eq!(TargetInfo::parse("model=Raspberry Pi\n"), None);
+    }
+
+    #[test]
+    fn test_families_of_known_boards() {
+        for (model, expected) in [
+            ("NVIDIA Jetson Nano Developer Kit", Some("jetson")),
+            ("TI AM335x BeagleBone Black", Some("beaglebone")),
+            ("Radxa ROCK 5B", Some("radxa")),
+            ("ROCK Pi 4B", Some("radxa")),
+            ("Rockchip RK3588", None),
+            ("Xunlong Orange Pi 5", Some("orange_pi")),
+            (
+                "Raspberry Pi Compute Module 4 Rev 1.0",
+                Some("raspberry_pi"),
+            ),
+            ("QEMU Virtual Machine", None),
+        ] {
+            assert_eq!(family(model), expected, "{}", model);
+        }
+        assert_eq!(
+            without_revision("TI AM335x BeagleBone Black Rev. C"),
+            "TI AM335x BeagleBone Black"
+        );
+        assert_eq!(without_revision("Revolution Pi"), "Revolution Pi");
+    }
+}
@@ -0,0 +1,839 @@
+//! Configuration files: their contents, checks and `${NAME}` expansion.
+
+use crate::env::{self, Lookup};
+use crate::interpolate::{self, Env};
+use crate::pure::arch::Arch;
+use crate::pure::facts::Facts;
+use crate::pure::hardware::HardwareConfig;
+use crate::pure::key_options;
+use crate::pure::os::OsRequirements;
+use crate::pure::profile::{self, DeviceProfile};
+use crate::pure::swap::SwapMode;
+use crate::validate;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use toml_edit::{DocumentMut, ImDocument, Item, TableLike};
+
+/// Commented template written by `config init`; the shipped example doubles as the template
+const TEMPLATE: &str = include_str!("../../config.toml.example");
+
+/// Settings that are absent from a serialized default config because they are unset
+const OPTIONAL_SETTINGS: &[&str] = &["default_key_path", "artifacts", "require_os", "min_kernel"];
+
+/// Settings [`Config::apply_env`] overrides, with the `SSH_IP_TUNNEL_` suffix of
+/// their variable; lists are comma-separated
+pub const ENV_SETTINGS: &[(&str, &str)] = &[
+    ("default_key_path", "DEFAULT_KEY_PATH"),
+    ("default_port", "DEFAULT_PORT"),
+    ("tunnel_timeout_secs", "TUNNEL_TIMEOUT_SECS"),
+    ("max_retries", "MAX_RETRIES"),
+    ("skip_arch_validation", "SKIP_ARCH_VALIDATION"),
+    ("allowed_architectures", "ALLOWED_ARCHITECTURES"),
+    ("require_os", "REQUIRE_OS"),
+    ("require_distro", "REQUIRE_DISTRO"),
+    ("min_kernel", "MIN_KERNEL"),
+    ("secure", "SECURE"),
+    ("swap", "SWAP"),
+    ("artifacts", "ARTIFACTS"),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct Config {
+    /// Key to transfer when none is given; unset, the standard keys are searched (see [`Config::default_key`])
+    pub default_key_path: Option<String>,
+    pub default_port: u16,
+    pub tunnel_timeout_secs: u64,
+    /// Times `push` resumes an upload the connection dropped
+    pub max_retries: u32,
+    pub skip_arch_validation: bool,
+    /// Architectures devices may have, as `uname -m` names them; any when empty
+    pub allowed_architectures: Vec<Arch>,
+    /// Operating system every device must run, as `uname -s` prints it, e.g. `linux`
+    pub require_os: Option<String>,
+    /// Distributions (os-release `ID`s) devices may run; any when empty
+    pub require_distro: Vec<String>,
+    /// Oldest kernel devices may run, e.g. `5.10`
+    pub min_kernel: Option<String>,
+    /// Require known host keys and modern algorithms for every device (see `--secure`)
+    pub secure: bool,
+    /// Swap to set up on boards with little RAM during `up`
+    pub swap: SwapMode,
+    /// Interfaces, overlays and modules to enable on every device during `up`
+    pub hardware: HardwareConfig,
+    /// Named board configurations, applied with `apply-profile`
+    pub device_profiles: BTreeMap<String, DeviceProfile>,
+    /// Named host profiles, selected with `ssh-ip-tunnel up <name>`
+    pub hosts: BTreeMap<String, HostProfile>,
+    /// Named sets of host profiles, selected with `up --group <name>`
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// Custom `${NAME}` variables for use in other values
+    pub vars: BTreeMap<String, String>,
+    /// Where per-architecture builds live: a directory, or a path or URL with `{arch}`
+    pub artifacts: Option<String>,
+    /// Local commands run around the phases of `up`
+    pub hooks: Hooks,
+    /// URLs told about the lifecycle of `up` with a JSON POST
+    pub webhooks: Webhooks,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            default_key_path: None,
+            default_port: 2222,
+            tunnel_timeout_secs: 30,
+            max_retries: 3,
+            skip_arch_validation: false,
+            allowed_architectures: Arch::ARM_FAMILY.to_vec(),
+            require_os: None,
+            require_distro: Vec::new(),
+            min_kernel: None,
+            secure: false,
+            swap: SwapMode::Off,
+            hardware: HardwareConfig::default(),
+            device_profiles: BTreeMap::new(),
+            hosts: BTreeMap::new(),
+            groups: BTreeMap::new(),
+            vars: BTreeMap::new(),
+            artifacts: None,
+            hooks: Hooks::default(),
+            webhooks: Webhooks::default(),
+        }
+    }
+}
+
+/// A `[hosts.<name>]` section. Every field is optional; unset fields fall back
+/// to the global defaults, and CLI flags override whatever is set here.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostProfile {
+    pub host: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub key_path: Option<String>,
+    /// authorized_keys options for the transferred key (see `--key-option`)
+    pub key_options: Option<Vec<String>>,
+    pub key_comment: Option<String>,
+    /// Account to install the key for instead of `user` (see `--target-user`)
+    pub target_user: Option<String>,
+    pub no_key_transfer: Option<bool>,
+    pub skip_arch_validation: Option<bool>,
+    /// Replace the global `require_os`, `require_distro` and `min_kernel` for this host
+    pub require_os: Option<String>,
+    pub require_distro: Option<Vec<String>>,
+    pub min_kernel: Option<String>,
+    /// Expected SHA256 fingerprint of the device's host key
+    pub fingerprint: Option<String>,
+    pub secure: Option<bool>,
+    pub sync_time: Option<bool>,
+    pub swap: Option<SwapMode>,
+    /// Replaces the global `[hardware]` section for this host
+    pub hardware: Option<HardwareConfig>,
+    /// Device profile `apply-profile` applies to this host
+    pub device_profile: Option<String>,
+    /// Script `up` runs on the device once the key is deployed (see `--provision`)
+    pub provision: Option<String>,
+    /// Run the provisioning script as root (see `--provision-sudo`)
+    pub provision_sudo: Option<bool>,
+    /// Turn off password and root logins after `up` (see `--harden`)
+    pub harden: Option<bool>,
+}
+
+/// The `[hooks]` section: shell commands run on this machine around the
+/// phases of `up`, with the host, port and outcome in `SSH_IP_TUNNEL_*` variables
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    /// Before the tunnel is opened; failing stops the run
+    pub pre_tunnel: Option<String>,
+    /// Once the tunnel is up and validated
+    pub post_tunnel: Option<String>,
+    /// Before the key is transferred; failing stops the run
+    pub pre_key_transfer: Option<String>,
+    /// Once the key is transferred, or found already deployed
+    pub post_key_transfer: Option<String>,
+    /// When the run fails, in any phase
+    pub on_failure: Option<String>,
+}
+
+/// The `[webhooks]` section: URLs that get a JSON POST with the host, user,
+/// port, time and run, plus the phase and error when a run failed
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Webhooks {
+    /// Once the tunnel is up and validated
+    pub on_up: Option<String>,
+    /// When a run fails, or a tunnel is closed
+    pub on_down: Option<String>,
+    /// Once the key is deployed, or found already there
+    pub on_key_deployed: Option<String>,
+}
+
+impl Config {
+    /// The images devices may run, with `profile`'s settings replacing the global ones
+    pub fn os_requirements(&self, profile: Option<&HostProfile>) -> OsRequirements {
+        let profile = profile.cloned().unwrap_or_default();
+        OsRequirements {
+            os: profile.require_os.or_else(|| self.require_os.clone()),
+            distros: profile
+                .require_distro
+                .unwrap_or_else(|| self.require_distro.clone()),
+            min_kernel: profile.min_kernel.or_else(|| self.min_kernel.clone()),
+        }
+    }
+
+    /// Looks up a host profile by name
+    pub fn profile(&self, name: &str) -> Result<&HostProfile> {
+        self.hosts.get(name).ok_or_else(|| {
+            let known: Vec<&str> = self.hosts.keys().map(String::as_str).collect();
+            if known.is_empty() {
+                anyhow::anyhow!("Unknown host profile '{}': no [hosts] are configured", name)
+            } else {
+                anyhow::anyhow!(
+                    "Unknown host profile '{}' (known profiles: {})",
+                    name,
+                    known.join(", ")
+                )
+            }
+        })
+    }
+
+    /// Applies `SSH_IP_TUNNEL_*` overrides to the global settings (see [`ENV_SETTINGS`])
+    pub fn apply_env(&mut self, lookup: Lookup) -> Result<()> {
+        if let Some(key_path) = lookup("DEFAULT_KEY_PATH") {
+            self.default_key_path = Some(key_path);
+        }
+        if let Some(port) = env::parse(lookup, "DEFAULT_PORT")? {
+            self.default_port = port;
+        }
+        if let Some(secs) = env::parse(lookup, "TUNNEL_TIMEOUT_SECS")? {
+            self.tunnel_timeout_secs = secs;
+        }
+        if let Some(retries) = env::parse(lookup, "MAX_RETRIES")? {
+            self.max_retries = retries;
+        }
+        if let Some(skip) = env::flag(lookup, "SKIP_ARCH_VALIDATION")? {
+            self.skip_arch_validation = skip;
+        }
+        if let Some(archs) = env::list(lookup, "ALLOWED_ARCHITECTURES")? {
+            self.allowed_architectures = archs;
+        }
+        if let Some(os) = lookup("REQUIRE_OS") {
+            self.require_os = Some(os);
+        }
+        if let Some(distros) = env::list(lookup, "REQUIRE_DISTRO")? {
+            self.require_distro = distros;
+        }
+        if let Some(kernel) = lookup("MIN_KERNEL") {
+            self.min_kernel = Some(kernel);
+        }
+        if let Some(secure) = env::flag(lookup, "SECURE")? {
+            self.secure = secure;
+        }
+        if let Some(swap) = env::parse(lookup, "SWAP")? {
+            self.swap = swap;
+        }
+        if let Some(artifacts) = lookup("ARTIFACTS") {
+            self.artifacts = Some(artifacts);
+        }
+        Ok(())
+    }
+
+    /// Expands `${NAME}` references in paths, hosts and users
+    pub fn interpolate(&mut self, env: Env) -> Result<()> {
+        match self.expand_vars(env).into_iter().next() {
+            Some((path, message)) => {
+                anyhow::bail!("In config value {}: {}", path.join("."), message)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Expands what it can, returning the path and problem of each field that failed
+    fn expand_vars(&mut self, env: Env) -> Vec<(Vec<String>, String)> {
+        let mut problems = Vec::new();
+        let vars = &self.vars;
+        let mut expand =
+            |path: Vec<String>, value: &mut String| match interpolate::expand(value, vars, env) {
+                Ok(expanded) => *value = expanded,
+                Err(message) => problems.push((path, message)),
+            };
+
+        if let Some(key_path) = &mut self.default_key_path {
+            expand(vec!["default_key_path".to_string()], key_path);
+        }
+        if let Some(artifacts) = &mut self.artifacts {
+            expand(vec!["artifacts".to_string()], artifacts);
+        }
+        for (name, profile) in &mut self.device_profiles {
+            let path = |fields: &[&str]| {
+                let mut path = vec!["device_profiles".to_string(), name.clone()];
+                path.extend(fields.iter().map(|field| field.to_string()));
+                path
+            };
+            if let Some(hostname) = &mut profile.hostname {
+                expand(path(&["hostname"]), hostname);
+            }
+            // The passphrase in particular is better kept out of the file, e.g. as ${WIFI_PSK}
+            if let Some(wifi) = &mut profile.wifi {
+                expand(path(&["wifi", "ssid"]), &mut wifi.ssid);
+                expand(path(&["wifi", "psk"]), &mut wifi.psk);
+            }
+        }
+        let webhooks = [
+            ("on_up", &mut self.webhooks.on_up),
+            ("on_down", &mut self.webhooks.on_down),
+            ("on_key_deployed", &mut self.webhooks.on_key_deployed),
+        ];
+        // Webhook URLs often hold a token, better kept in the environment
+        for (field, url) in webhooks {
+            if let Some(url) = url {
+                expand(vec!["webhooks".to_string(), field.to_string()], url);
+            }
+        }
+        for (name, profile) in &mut self.hosts {
+            let fields = [
+                ("host", &mut profile.host),
+                ("user", &mut profile.user),
+                ("key_path", &mut profile.key_path),
+                ("provision", &mut profile.provision),
+            ];
+            for (field, value) in fields {
+                if let Some(value) = value {
+                    let path = vec!["hosts".to_string(), name.clone(), field.to_string()];
+                    expand(path, value);
+                }
+            }
+        }
+        problems
+    }
+
+    /// Looks up a host group by name, checking that every member is a known profile
+    pub fn group(&self, name: &str) -> Result<&[String]> {
+        let members = self
+            .groups
+            .get(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown host group '{}'", name))?;
+        if members.is_empty() {
+            anyhow::bail!("Host group '{}' has no members", name);
+        }
+        for member in members {
+            self.profile(member)
+                .map_err(|e| anyhow::anyhow!("In host group '{}': {}", name, e))?;
+        }
+        Ok(members)
+    }
+}
+
+/// A problem found by `config validate`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    /// 1-based line of the offending key, when it can be located
+    pub line: Option<usize>,
+    /// Dotted path of the offending field, e.g. `hosts.pi1.user`
+    pub field: String,
+    pub message: String,
+}
+
+/// Checks a configuration file's contents, returning every problem found.
+///
+/// Syntax and type errors stop the check at the first error; otherwise unknown
+/// top-level keys, undefined variables and invalid values are all reported
+/// together. Variables not defined in `[vars]` are resolved through `env`.
+pub fn validate_str(contents: &str, env: Env) -> Vec<Diagnostic> {
+    let mut config: Config = match toml::from_str(contents) {
+        Ok(config) => config,
+        Err(e) => {
+            return vec![Diagnostic {
+                line: e.span().map(|span| line_at(contents, span.start)),
+                field: String::new(),
+                message: e.message().to_string(),
+            }]
+        }
+    };
+    // toml already parsed this successfully, so only spans are needed from here
+    let document = match ImDocument::parse(contents) {
+        Ok(document) => document,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut problems = Vec::new();
+    let mut report = |path: &[&str], message: String| {
+        problems.push(Diagnostic {
+            line: locate(&document, contents, path),
+            field: path.join("."),
+            message,
+        });
+    };
+
+    let unexpanded = config.expand_vars(env);
+    for (path, message) in &unexpanded {
+        let path: Vec<&str> = path.iter().map(String::as_str).collect();
+        report(&path, message.clone());
+    }
+    // Fields that failed to expand were reported above; don't also flag their raw text
+    let expanded = |path: &[&str]| !unexpanded.iter().any(|(p, _)| p.as_slice() == path);
+
+    let known = toml::Table::try_from(Config::default()).unwrap_or_default();
+    for (key, _) in document.iter() {
+        if !known.contains_key(key) && !OPTIONAL_SETTINGS.contains(&key) {
+            report(&[key], "unknown setting".to_string());
+        }
+    }
+
+    if config.default_port == 0 {
+        report(&["default_port"], "must be between 1 and 65535".to_string());
+    }
+    if config.tunnel_timeout_secs == 0 {
+        report(
+            &["tunnel_timeout_secs"],
+            "must be greater than 0".to_string(),
+        );
+    }
+
+    if let Err(e) = config.hardware.validate() {
+        report(&["hardware"], e.to_string());
+    }
+    for (field, message) in os_requirement_problems(&config.require_os, &config.min_kernel) {
+        report(&[field], message);
+    }
+    let webhooks = [
+        ("on_up", &config.webhooks.on_up),
+        ("on_down", &config.webhooks.on_down),
+        ("on_key_deployed", &config.webhooks.on_key_deployed),
+    ];
+    for (field, url) in webhooks {
+        if let Some(url) = url.as_ref().filter(|_| expanded(&["webhooks", field])) {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                report(
+                    &["webhooks", field],
+                    format!("{:?} is not an http:// or https:// URL", url),
+                );
+            }
+        }
+    }
+
+    for (name, profile) in &config.hosts {
+        if let Some(host) = profile
+            .host
+            .as_ref()
+            .filter(|_| expanded(&["hosts", name, "host"]))
+        {
+            if let Err(e) = validate::validate_host(host) {
+                report(&["hosts", name, "host"], e.to_string());
+            }
+        }
+        if let Some(user) = profile
+            .user
+            .as_ref()
+            .filter(|_| expanded(&["hosts", name, "user"]))
+        {
+            if let Err(e) = validate::validate_username(user) {
+                report(&["hosts", name, "user"], e.to_string());
+            }
+        }
+        if let Some(fingerprint) = profile
+            .fingerprint
+            .as_ref()
+            .filter(|_| expanded(&["hosts", name, "fingerprint"]))
+        {
+            if let Err(e) = validate::validate_fingerprint(fingerprint) {
+                report(&["hosts", name, "fingerprint"], e.to_string());
+            }
+        }
+        if let Some(Err(e)) = profile.hardware.as_ref().map(HardwareConfig::validate) {
+            report(&["hosts", name, "hardware"], e.to_string());
+        }
+        for (field, message) in os_requirement_problems(&profile.require_os, &profile.min_kernel) {
+            report(&["hosts", name, field], message);
+        }
+        for option in profile.key_options.iter().flatten() {
+            if let Err(e) = key_options::key_option(option) {
+                report(&["hosts", name, "key_options"], e.to_string());
+            }
+        }
+        if let Some(device_profile) = &profile.device_profile {
+            if !config.device_profiles.contains_key(device_profile) {
+                report(
+                    &["hosts", name, "device_profile"],
+                    format!("unknown device profile '{}'", device_profile),
+                );
+            }
+        }
+        if profile.port == Some(0) {
+            report(
+                &["hosts", name, "port"],
+                "must be between 1 and 65535".to_string(),
+            );
+        }
+    }
+
+    // Templates are checked by rendering them with made-up facts
+    let context = profile::context(&Facts::example(), "example");
+    for (name, profile) in &config.device_profiles {
+        if let Err(e) = profile.render(&context).and_then(|p| p.validate()) {
+            report(&["device_profiles", name], e.to_string());
+        }
+    }
+
+    for (name, members) in &config.groups {
+        if members.is_empty() {
+            report(&["groups", name], "group has no members".to_string());
+        }
+        for member in members {
+            if !config.hosts.contains_key(member) {
+                report(
+                    &["groups", name],
+                    format!("unknown host profile '{}'", member),
+                );
+            }
+        }
+    }
+
+    problems
+}
+
+/// The invalid ones of a `require_os` and a `min_kernel`, by field
+fn os_requirement_problems(
+    os: &Option<String>,
+    min_kernel: &Option<String>,
+) -> Vec<(&'static str, String)> {
+    let only = |requirements: OsRequirements| requirements.validate().err().map(|e| e.to_string());
+    let os = only(OsRequirements {
+        os: os.clone(),
+        ..OsRequirements::default()
+    });
+    let min_kernel = only(OsRequirements {
+        min_kernel: min_kernel.clone(),
+        ..OsRequirements::default()
+    });
+    [("require_os", os), ("min_kernel", min_kernel)]
+        .into_iter()
+        .filter_map(|(field, problem)| problem.map(|message| (field, message)))
+        .collect()
+}
+
+/// Finds the line of the key at `path`, falling back to the nearest enclosing key
+fn locate(document: &ImDocument<&str>, contents: &str, path: &[&str]) -> Option<usize> {
+    let mut table: &dyn TableLike = document.as_table();
+    let mut line = None;
+    for segment in path {
+        let (key, item) = table.get_key_value(segment)?;
+        line = key
+            .span()
+            .map(|span| line_at(contents, span.start))
+            .or(line);
+        match item.as_table_like() {
+            Some(inner) => table = inner,
+            None => break,
+        }
+    }
+    line
+}
+
+fn line_at(contents: &str, offset: usize) -> usize {
+    contents[..offset.min(contents.len())].matches('\n').count() + 1
+}
+
+/// Renders the commented default configuration with the given key path and port
+pub fn render_template(key_path: &str, port: u16) -> String {
+    let mut rendered = String::with_capacity(TEMPLATE.len());
+    for line in TEMPLATE.lines() {
+        if line.starts_with("default_key_path =") || line.starts_with("# default_key_path =") {
+            rendered.push_str(&format!(
+                "default_key_path = {}",
+                toml::Value::from(key_path)
+            ));
+        } else if line.starts_with("default_port =") {
+            rendered.push_str(&format!("default_port = {}", port));
+        } else {
+            rendered.push_str(line);
+        }
+        rendered.push('\n');
+    }
+    rendered
+}
+
+/// Sets the fields of `profile` in a configuration file's `[hosts.<name>]`,
+/// adding the section if needed and keeping everything else, comments included
+pub fn set_host_profile(contents: &str, name: &str, profile: &HostProfile) -> Result<String> {
+    let mut document: DocumentMut = contents
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Failed to parse config file: {}", e))?;
+    let fields: DocumentMut = toml::to_string(profile)?.parse()?;
+
+    let hosts = document.entry("hosts").or_insert_with(|| {
+        let mut hosts = toml_edit::Table::new();
+        hosts.set_implicit(true);
+        Item::Table(hosts)
+    });
+    let section = hosts
+        .as_table_mut()
+        .ok_or_else(|| anyhow::anyhow!("`hosts` in the config file is not a table"))?
+        .entry(name)
+        .or_insert_with(toml_edit::table)
+        .as_table_mut()
+        .ok_or_else(|| anyhow::anyhow!("`hosts.{}` in the config file is not a table", name))?;
+    for (key, value) in fields.iter() {
+        section.insert(key, value.clone());
+    }
+    Ok(document.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_default() {
+        let config = Config::default();
+        assert_eq!(config.default_port, 2222);
+        assert_eq!(config.default_key_path, None);
+        assert!(!config.skip_arch_validation);
+        assert!(config.allowed_architectures.iter().all(Arch::is_arm));
+        assert!(config.hosts.is_empty());
+    }
+
+    #[test]
+    fn test_host_profiles_parse() {
+        let config: Config = toml::from_str(
+            r#"
+            default_port = 2300
+            allowed_architectures = ["arm64", "riscv64"]
+
+            [hosts.mydevboard]
+            host = "192.168.1.42"
+            user = "pi"
+            port = 2223
+            key_path = "~/.ssh/pi_key.pub"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.default_port, 2300);
+        assert_eq!(config.tunnel_timeout_secs, 30);
+        assert_eq!(config.allowed_architectures, [Arch::Aarch64, Arch::Riscv64]);
+        let profile = config.profile("mydevboard").unwrap();
+        assert_eq!(profile.host.as_deref(), Some("192.168.1.42"));
+        assert_eq!(profile.port, Some(2223));
+        assert_eq!(profile.skip_arch_validation, None);
+        assert!(config.profile("missing").is_err());
+    }
+
+    #[test]
+    fn test_groups_require_known_members() {
+        let config: Config = toml::from_str(
+            r#"
+            [hosts.pi1]
+            host = "10.0.0.1"
+            [hosts.pi2]
+            host = "10.0.0.2"
+
+            [groups]
+            lab-a = ["pi1", "pi2"]
+            broken = ["pi1", "jetson1"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.group("lab-a").unwrap(), ["pi1", "pi2"]);
+        assert!(config.group("broken").is_err());
+        assert!(config.group("lab-b").is_err());
+    }
+
+    #[test]
+    fn test_rendered_template_parses_with_chosen_values() {
+        let rendered = render_template("~/.ssh/id_ed25519.pub", 2300);
+        let config: Config = toml::from_str(&rendered).unwrap();
+        assert_eq!(
+            config.default_key_path.as_deref(),
+            Some("~/.ssh/id_ed25519.pub")
+        );
+        assert_eq!(config.default_port, 2300);
+        assert!(rendered.contains("# [hosts.raspberry-pi]"));
+
+        let quoted: Config = toml::from_str(&render_template("C:\\keys\\a \"b\".pub", 1)).unwrap();
+        assert_eq!(
+            quoted.default_key_path.as_deref(),
+            Some("C:\\keys\\a \"b\".pub")
+        );
+    }
+
+    #[test]
+    fn test_validate_reports_syntax_errors_with_line() {
+        let problems = validate_str("default_port = 2222\ndefault_port = \"x\n", &|_| None);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].line, Some(2));
+    }
+
+    #[test]
+    fn test_validate_reports_every_semantic_problem() {
+        let problems = validate_str(
+            r#"default_port = 0
+deafult_key_path = "~/.ssh/id_ed25519.pub"
+
+[hosts.pi1]
+host = "10.0.0.1"
+user = "pi;reboot"
+
+[hosts.pi1.hardware]
+modules = ["w1 therm"]
+
+[groups]
+lab-a = ["pi1", "jetson1"]
+"#,
+            &|_| None,
+        );
+        let found: Vec<(Option<usize>, &str)> = problems
+            .iter()
+            .map(|p| (p.line, p.field.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (Some(2), "deafult_key_path"),
+                (Some(1), "default_port"),
+                (Some(6), "hosts.pi1.user"),
+                (Some(8), "hosts.pi1.hardware"),
+                (Some(12), "groups.lab-a"),
+            ]
+        );
+        assert!(validate_str(TEMPLATE, &|_| None).is_empty());
+    }
+
+    #[test]
+    fn test_interpolation_uses_vars_then_env() {
+        let contents = r#"default_key_path = "${HOME}/.ssh/${KEY}.pub"
+
+[vars]
+KEY = "lab"
+
+[hosts.pi1]
+host = "${SUBNET}.7"
+user = "${USER}"
+"#;
+        let env = |name: &str| match name {
+            "HOME" => Some("/home/ci".to_string()),
+            "USER" => Some("ci".to_string()),
+            "KEY" => Some("ignored".to_string()),
+            _ => None,
+        };
+
+        let problems = validate_str(contents, &env);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].field, "hosts.pi1.host");
+        assert_eq!(problems[0].line, Some(7));
+        assert!(problems[0].message.contains("${SUBNET}"));
+
+        let mut config: Config = toml::from_str(contents).unwrap();
+        let err = config.interpolate(&env).unwrap_err().to_string();
+        assert!(err.contains("hosts.pi1.host"), "{}", err);
+
+        config.hosts.get_mut("pi1").unwrap().host = Some("10.0.0.7".to_string());
+        config.interpolate(&env).unwrap();
+        assert_eq!(
+            config.default_key_path.as_deref(),
+            Some("/home/ci/.ssh/lab.pub")
+        );
+        assert_eq!(config.profile("pi1").unwrap().user.as_deref(), Some("ci"));
+    }
+
+    #[test]
+    fn test_env_overrides_file_values() {
+        let mut config: Config = toml::from_str("default_port = 2300\nmax_retries = 5").unwrap();
+        let lookup = |suffix: &str| match suffix {
+            "DEFAULT_PORT" => Some("2400".to_string()),
+            "TUNNEL_TIMEOUT_SECS" => Some("90".to_string()),
+            _ => None,
+        };
+        config.apply_env(&lookup).unwrap();
+
+        assert_eq!(config.default_port, 2400);
+        assert_eq!(config.tunnel_timeout_secs, 90);
+        assert_eq!(config.max_retries, 5);
+    }
+
+    #[test]
+    fn test_every_setting_has_a_variable() {
+        // Sections too structured for a variable, set in the file only
+        const FILE_ONLY_SETTINGS: &[&str] = &[
+            "hardware",
+            "device_profiles",
+            "hosts",
+            "groups",
+            "vars",
+            "hooks",
+            "webhooks",
+        ];
+        let sample = |suffix: &str| {
+            Some(
+                match suffix {
+                    "DEFAULT_KEY_PATH" => "/keys/lab.pub",
+                    "DEFAULT_PORT" => "2400",
+                    "TUNNEL_TIMEOUT_SECS" => "90",
+                    "MAX_RETRIES" => "7",
+                    "SKIP_ARCH_VALIDATION" => "yes",
+                    "ALLOWED_ARCHITECTURES" => "aarch64, riscv64",
+                    "REQUIRE_OS" => "linux",
+                    "REQUIRE_DISTRO" => "debian,raspbian",
+                    "MIN_KERNEL" => "6.1",
+                    "SECURE" => "1",
+                    "SWAP" => "zram",
+                    "ARTIFACTS" => "dist/{arch}/agent",
+                    _ => return None,
+                }
+                .to_string(),
+            )
+        };
+        let default = serde_json::to_value(Config::default()).unwrap();
+        let mut config = Config::default();
+        config.apply_env(&sample).unwrap();
+        let overridden = serde_json::to_value(&config).unwrap();
+
+        for field in default.as_object().unwrap().keys() {
+            if FILE_ONLY_SETTINGS.contains(&field.as_str()) {
+                continue;
+            }
+            let Some((_, suffix)) = ENV_SETTINGS.iter().find(|(name, _)| name == field) else {
+                panic!("{} has no SSH_IP_TUNNEL_ variable in ENV_SETTINGS", field);
+            };
+            assert!(sample(suffix).is_some(), "no sample value for {}", suffix);
+            assert_ne!(
+                default[field], overridden[field],
+                "SSH_IP_TUNNEL_{} doesn't change {}",
+                suffix, field
+            );
+        }
+        assert_eq!(config.require_distro, ["debian", "raspbian"]);
+        assert_eq!(config.allowed_architectures.len(), 2);
+    }
+
+    #[test]
+    fn test_set_host_profile_keeps_the_rest() {
+        let contents = "# Lab boards\ndefault_port = 2300\n\n[hosts.pi1]\nhost = \"10.0.0.1\" # desk\nuser = \"pi\"\ndevice_profile = \"kiosk\"\n";
+        let profile = HostProfile {
+            host: Some("10.0.0.9".to_string()),
+            user: Some("pi".to_string()),
+            harden: Some(true),
+            ..Default::default()
+        };
+
+        let updated = set_host_profile(contents, "pi1", &profile).unwrap();
+        assert!(updated.starts_with("# Lab boards\ndefault_port = 2300\n"));
+        let config: Config = toml::from_str(&updated).unwrap();
+        let pi1 = &config.hosts["pi1"];
+        assert_eq!(pi1.host.as_deref(), Some("10.0.0.9"));
+        assert_eq!(pi1.device_profile.as_deref(), Some("kiosk"));
+        assert_eq!(pi1.harden, Some(true));
+
+        let added = set_host_profile(&updated, "pi2", &profile).unwrap();
+        let config: Config = toml::from_str(&added).unwrap();
+        assert_eq!(config.hosts.len(), 2);
+        assert!(added.contains("[hosts.pi2]\n"));
+        assert!(!added.contains("[hosts]\n"));
+        assert!(set_host_profile("hosts = 1\n", "pi1", &profile).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//! Facts about a device, as template values for device profiles.
+
+use crate::template;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// What a device is
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Facts {
+    /// `uname -m`, e.g. `aarch64`
+    pub arch: String,
+    /// Device tree or DMI model, e.g. `Raspberry Pi 4 Model B Rev 1.4`
+    pub model: String,
+    pub mem_mb: u64,
+    pub cpus: u32,
+    /// `uname -r`
+    pub kernel: String,
+    pub hostname: String,
+    /// `ID` from /etc/os-release, e.g. `debian`
+    pub os: String,
+    /// `VERSION_ID` from /etc/os-release, e.g. `12`
+    pub os_version: String,
+    pub gathered_at: DateTime<Utc>,
+}
+
+impl Facts {
+    /// Plausible facts for checking templates without a device
+    pub fn example() -> Self {
+        Self {
+            arch: "aarch64".to_string(),
+            model: "Raspberry Pi 4 Model B Rev 1.4".to_string(),
+            mem_mb: 3792,
+            cpus: 4,
+            kernel: "6.6.31+rpt-rpi-v8".to_string(),
+            hostname: "raspberrypi".to_string(),
+            os: "debian".to_string(),
+            os_version: "12".to_string(),
+            gathered_at: DateTime::default(),
+        }
+    }
+
+    /// The facts as template values
+    pub fn context(&self) -> template::Context {
+        [
+            ("arch", self.arch.clone()),
+            ("model", self.model.clone()),
+            ("mem_mb", self.mem_mb.to_string()),
+            ("cpus", self.cpus.to_string()),
+            ("kernel", self.kernel.clone()),
+            ("hostname", self.hostname.clone()),
+            ("os", self.os.clone()),
+            ("os_version", self.os_version.clone()),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+    }
+
+    /// Parses the output of `FACTS_SCRIPT`, `name=value` lines
+    pub fn parse(output: &str, gathered_at: DateTime<Utc>) -> Self {
+        let mut facts = Facts {
+            gathered_at,
+            ..Default::default()
+        };
+        for line in output.lines() {
+            let Some((name, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().to_string();
+            match name {
+                "arch" => facts.arch = value,
+                "model" => facts.model = value,
+                "mem_mb" => facts.mem_mb = value.parse().unwrap_or_default(),
+                "cpus" => facts.cpus = value.parse().unwrap_or_default(),
+                "kernel" => facts.kernel = value,
+                "hostname" => facts.hostname = value,
+                "os" => facts.os = value,
+                "os_version" => facts.os_version = value,
+                _ => {}
+            }
+        }
+        facts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_facts() {
+        let facts = Facts::parse(
+            "arch=armv7l\nkernel=6.1.21-v7+\nhostname=pi\nmodel=Raspberry Pi 3 Model B Rev 1.2\nmem_mb=922\ncpus=4\nos=raspbian\nos_version=11\n",
+            DateTime::default(),
+        );
+        assert_eq!(facts.arch, "armv7l");
+        assert_eq!(facts.mem_mb, 922);
+        assert_eq!(facts.context()["model"], "Raspberry Pi 3 Model B Rev 1.2");
+        assert_eq!(facts.context()["cpus"], "4");
+    }
+}
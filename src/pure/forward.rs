@@ -0,0 +1,48 @@
+//! Services forwarded from this machine to a device, as `-R` specs.
+
+use anyhow::Result;
+use serde::Serialize;
+
+/// A local service and the device port it is forwarded to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Forwarded {
+    pub local: String,
+    pub device_port: u16,
+}
+
+impl Forwarded {
+    /// Parses `host:port`; the device gets the same port on its loopback interface
+    pub fn parse(address: &str) -> Result<Self> {
+        let port = address
+            .rsplit_once(':')
+            .and_then(|(host, port)| (!host.is_empty()).then_some(port))
+            .and_then(|port| port.parse::<u16>().ok())
+            .filter(|port| *port != 0)
+            .ok_or_else(|| anyhow::anyhow!("Expected host:port, got {:?}", address))?;
+        Ok(Self {
+            local: address.to_string(),
+            device_port: port,
+        })
+    }
+
+    /// The `-R` spec for this service
+    pub fn spec(&self) -> String {
+        format!("127.0.0.1:{}:{}", self.device_port, self.local)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forwarded_parse() {
+        let registry = Forwarded::parse("localhost:5000").unwrap();
+        assert_eq!(registry.device_port, 5000);
+        assert_eq!(registry.spec(), "127.0.0.1:5000:localhost:5000");
+
+        assert!(Forwarded::parse("localhost").is_err());
+        assert!(Forwarded::parse(":3142").is_err());
+        assert!(Forwarded::parse("localhost:0").is_err());
+    }
+}
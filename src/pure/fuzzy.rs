@@ -0,0 +1,53 @@
+//! Fuzzy matching for pickers: `rp4` finds `rpi4-lab`, best matches first.
+
+/// How well `query` matches `text`, ignoring case: its characters must all
+/// appear in order. Matches that run on, or start words, score higher; None
+/// when it doesn't match.
+pub fn score(query: &str, text: &str) -> Option<u32> {
+    let text: Vec<char> = text.chars().flat_map(char::to_lowercase).collect();
+    let mut score = 0;
+    let mut next = 0;
+    let mut previous: Option<usize> = None;
+    for wanted in query.chars().flat_map(char::to_lowercase) {
+        let found = next + text[next..].iter().position(|&c| c == wanted)?;
+        score += 1;
+        if previous.is_some_and(|previous| previous + 1 == found) {
+            score += 4;
+        }
+        if found == 0 || !text[found - 1].is_alphanumeric() {
+            score += 2;
+        }
+        previous = Some(found);
+        next = found + 1;
+    }
+    Some(score)
+}
+
+/// Indices of the `items` matching `query`, best first; ties keep their order
+pub fn rank<S: AsRef<str>>(query: &str, items: &[S]) -> Vec<usize> {
+    let mut matches: Vec<(usize, u32)> = items
+        .iter()
+        .enumerate()
+        .filter_map(|(index, item)| Some((index, score(query, item.as_ref())?)))
+        .collect();
+    matches.sort_by_key(|&(_, score)| std::cmp::Reverse(score));
+    matches.into_iter().map(|(index, _)| index).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rank_prefers_runs_and_word_starts() {
+        assert_eq!(score("", "pi"), Some(0));
+        assert_eq!(score("x", "pi"), None);
+        assert_eq!(score("ip", "pi"), None);
+        assert!(score("LAB", "rpi4-lab").is_some());
+
+        let items = ["kiosk pi@10.0.0.7", "rpi4-lab pi@rpi4.lan", "pi@lab-gw"];
+        assert_eq!(rank("lab", &items), vec![1, 2]);
+        assert_eq!(rank("rp4", &items), vec![1]);
+        assert_eq!(rank("", &items), vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,138 @@
+//! The `[hardware]` section: interfaces, device tree overlays and kernel modules.
+
+use crate::TunnelError;
+use serde::{Deserialize, Serialize};
+
+/// A peripheral interface that has a switch in the boot configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum Interface {
+    I2c,
+    Spi,
+    Uart,
+}
+
+impl Interface {
+    /// The config.txt line that switches the interface on
+    pub fn config_line(self) -> &'static str {
+        match self {
+            Interface::I2c => "dtparam=i2c_arm=on",
+            Interface::Spi => "dtparam=spi=on",
+            Interface::Uart => "enable_uart=1",
+        }
+    }
+
+    /// The module that gives the interface its device nodes, if it needs one
+    pub fn module(self) -> Option<&'static str> {
+        match self {
+            Interface::I2c => Some("i2c-dev"),
+            Interface::Spi | Interface::Uart => None,
+        }
+    }
+}
+
+/// A `[hardware]` section
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct HardwareConfig {
+    /// Interfaces to switch on: `i2c`, `spi` and/or `uart`
+    pub interfaces: Vec<Interface>,
+    /// Overlays with their parameters, e.g. `w1-gpio,gpiopin=4`
+    pub dtoverlays: Vec<String>,
+    /// Kernel modules to load at boot
+    pub modules: Vec<String>,
+}
+
+impl HardwareConfig {
+    pub fn is_empty(&self) -> bool {
+        self.interfaces.is_empty() && self.dtoverlays.is_empty() && self.modules.is_empty()
+    }
+
+    /// Checks that every entry fits on one line of the files it goes into
+    pub fn validate(&self) -> Result<(), TunnelError> {
+        for overlay in &self.dtoverlays {
+            if overlay.is_empty() || overlay.contains(char::is_whitespace) {
+                return Err(TunnelError::Hardware(format!(
+                    "invalid dtoverlay {:?}: expected a name with optional ,parameters",
+                    overlay
+                )));
+            }
+        }
+        for module in &self.modules {
+            if module.is_empty()
+                || !module
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                return Err(TunnelError::Hardware(format!(
+                    "invalid module name {:?}",
+                    module
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Lines for config.txt, in order and without duplicates
+    pub fn config_lines(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .interfaces
+            .iter()
+            .map(|interface| interface.config_line().to_string())
+            .chain(self.dtoverlays.iter().map(|o| format!("dtoverlay={}", o)))
+            .collect();
+        dedup(&mut lines);
+        lines
+    }
+
+    /// Modules for /etc/modules, including those the interfaces need
+    pub fn module_lines(&self) -> Vec<String> {
+        let mut modules: Vec<String> = self
+            .interfaces
+            .iter()
+            .filter_map(|interface| interface.module())
+            .map(str::to_string)
+            .chain(self.modules.iter().cloned())
+            .collect();
+        dedup(&mut modules);
+        modules
+    }
+}
+
+/// Removes repeated entries, keeping the first of each
+fn dedup(items: &mut Vec<String>) {
+    let mut seen = std::collections::HashSet::new();
+    items.retain(|item| seen.insert(item.clone()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lines_include_interface_modules_once() {
+        let hardware = HardwareConfig {
+            interfaces: vec![Interface::I2c, Interface::Uart, Interface::I2c],
+            dtoverlays: vec!["w1-gpio,gpiopin=4".to_string()],
+            modules: vec!["i2c-dev".to_string(), "w1-therm".to_string()],
+        };
+        assert_eq!(
+            hardware.config_lines(),
+            [
+                "dtparam=i2c_arm=on",
+                "enable_uart=1",
+                "dtoverlay=w1-gpio,gpiopin=4"
+            ]
+        );
+        assert_eq!(hardware.module_lines(), ["i2c-dev", "w1-therm"]);
+        assert!(hardware.validate().is_ok());
+        assert!(HardwareConfig {
+            modules: vec!["i2c-dev\nrm".to_string()],
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,126 @@
+//! The device report of `info`: what a device is, runs and has left.
+
+use crate::pure::board::TargetInfo;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// RAM, from `/proc/meminfo`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Memory {
+    pub total_mb: u64,
+    /// What programs can still get without swapping
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_mb: Option<u64>,
+}
+
+/// Space on the root filesystem, from `df -P /`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Disk {
+    pub total_mb: u64,
+    pub used_mb: u64,
+    pub available_mb: u64,
+}
+
+/// Everything `info` found out about a device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub host: String,
+    pub gathered_at: DateTime<Utc>,
+    /// Architecture, board and operating system
+    pub device: TargetInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<Memory>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk: Option<Disk>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uptime_secs: Option<u64>,
+    /// The SSH server, e.g. `OpenSSH_9.2p1` or `Dropbear 2022.83`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sshd: Option<String>,
+}
+
+impl DeviceInfo {
+    /// Adds what `INFO_SCRIPT` printed, `name=value` lines, to `device`
+    pub fn parse(host: &str, device: TargetInfo, output: &str, gathered_at: DateTime<Utc>) -> Self {
+        let value = |name: &str| {
+            output
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .find(|(key, value)| *key == name && !value.trim().is_empty())
+                .map(|(_, value)| value.trim().to_string())
+        };
+        let mb = |name: &str| value(name)?.parse::<u64>().ok().map(|kb| kb / 1024);
+
+        let memory = mb("mem_total_kb").map(|total_mb| Memory {
+            total_mb,
+            available_mb: mb("mem_available_kb"),
+        });
+        let disk = match (
+            mb("disk_total_kb"),
+            mb("disk_used_kb"),
+            mb("disk_available_kb"),
+        ) {
+            (Some(total_mb), Some(used_mb), Some(available_mb)) => Some(Disk {
+                total_mb,
+                used_mb,
+                available_mb,
+            }),
+            _ => None,
+        };
+        // /proc/uptime has fractions of a second
+        let uptime_secs = value("uptime")
+            .and_then(|uptime| uptime.split('.').next().and_then(|secs| secs.parse().ok()));
+        let sshd = value("sshd")
+            .or_else(|| value("dropbear").map(|version| format!("Dropbear {}", version)));
+        Self {
+            host: host.to_string(),
+            gathered_at,
+            device,
+            memory,
+            disk,
+            uptime_secs,
+            sshd,
+        }
+    }
+}
+
+/// `mb` megabytes for people, e.g. `3.7 GB` or `512 MB`
+pub fn format_mb(mb: u64) -> String {
+    if mb < 1024 {
+        format!("{} MB", mb)
+    } else {
+        format!("{:.1} GB", mb as f64 / 1024.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_resources() {
+        let device = TargetInfo::parse("arch=aarch64\n").unwrap();
+        let info = DeviceInfo::parse(
+            "pi.local",
+            device,
+            "mem_total_kb=3884332\nmem_available_kb=3266100\n\
+             disk_total_kb=30450832\ndisk_used_kb=4214116\ndisk_available_kb=24965388\n\
+             uptime=93731.52\nsshd=\ndropbear=2022.83\n",
+            DateTime::default(),
+        );
+        assert_eq!(
+            info.memory,
+            Some(Memory {
+                total_mb: 3793,
+                available_mb: Some(3189)
+            })
+        );
+        assert_eq!(info.disk.map(|disk| disk.used_mb), Some(4115));
+        assert_eq!(info.uptime_secs, Some(93731));
+        assert_eq!(info.sshd.as_deref(), Some("Dropbear 2022.83"));
+        assert_eq!(format_mb(3793), "3.7 GB");
+
+        let bare = DeviceInfo::parse("pi.local", info.device, "", DateTime::default());
+        assert_eq!((bare.memory, bare.disk, bare.sshd), (None, None, None));
+    }
+}
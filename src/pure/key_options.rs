@@ -0,0 +1,70 @@
+//! Options for authorized_keys lines, checked before they reach a device.
+
+use crate::TunnelError;
+
+/// Options sshd understands in authorized_keys. sshd skips a line with an
+/// option it doesn't know, so a typo would silently lock the key out.
+pub const KEY_OPTIONS: [&str; 23] = [
+    "agent-forwarding",
+    "cert-authority",
+    "command",
+    "environment",
+    "expiry-time",
+    "from",
+    "no-agent-forwarding",
+    "no-port-forwarding",
+    "no-pty",
+    "no-touch-required",
+    "no-user-rc",
+    "no-X11-forwarding",
+    "permitlisten",
+    "permitopen",
+    "port-forwarding",
+    "principals",
+    "pty",
+    "restrict",
+    "tunnel",
+    "user-rc",
+    "verify-required",
+    "X11-forwarding",
+    "zero-touch-required",
+];
+
+/// Turns `name` or `name=value` into an authorized_keys option, quoting the value
+pub fn key_option(raw: &str) -> Result<String, TunnelError> {
+    let invalid = |reason: String| {
+        Err(TunnelError::InvalidKeyOption(format!(
+            "{:?}: {}",
+            raw, reason
+        )))
+    };
+    let (name, value) = match raw.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (raw, None),
+    };
+    if !KEY_OPTIONS
+        .iter()
+        .any(|known| known.eq_ignore_ascii_case(name))
+    {
+        return invalid(format!(
+            "unknown option (known: {})",
+            KEY_OPTIONS.join(", ")
+        ));
+    }
+    let Some(value) = value else {
+        return Ok(name.to_string());
+    };
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    if value.is_empty() {
+        return invalid("empty value".to_string());
+    }
+    if value.contains(|c: char| c == '"' || c == '\\' || c.is_control()) {
+        return invalid(
+            "values can't contain quotes, backslashes or control characters".to_string(),
+        );
+    }
+    Ok(format!("{}=\"{}\"", name, value))
+}
@@ -0,0 +1,28 @@
+//! The logic that needs no device, network or filesystem.
+//!
+//! Parsing and checking configuration files, rendering device profiles and
+//! their plans, and the architecture policy live here, so anything that has a
+//! configuration file's contents can use the same code as the CLI. Nothing in
+//! this module spawns processes, opens files or sockets, or reads the clock.
+//! It is all that is built without the default `runtime` feature, which
+//! compiles for targets without an OS such as `wasm32-unknown-unknown`:
+//!
+//! ```text
+//! cargo build --lib --no-default-features --target wasm32-unknown-unknown
+//! ```
+
+pub mod arch;
+pub mod board;
+pub mod config;
+pub mod facts;
+pub mod forward;
+pub mod fuzzy;
+pub mod hardware;
+pub mod info;
+pub mod key_options;
+pub mod os;
+pub mod ping;
+pub mod profile;
+pub mod snapshot;
+pub mod swap;
+pub mod text;
@@ -0,0 +1,242 @@
+//! Which operating system a device runs, and the images a configuration accepts.
+
+use crate::TunnelError;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+/// The operating system the architecture probe found
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OsInfo {
+    /// `uname -s`, e.g. `Linux`
+    pub name: String,
+    /// `uname -r`, e.g. `6.6.31+rpt-rpi-v8`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<String>,
+    /// `ID` from /etc/os-release, e.g. `debian`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distro: Option<String>,
+    /// `VERSION_ID` from /etc/os-release, e.g. `12`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distro_version: Option<String>,
+    /// `PRETTY_NAME` from /etc/os-release, e.g. `Debian GNU/Linux 12 (bookworm)`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pretty_name: Option<String>,
+}
+
+impl OsInfo {
+    /// Parses `os=`, `kernel=`, `distro=`, `distro_version=` and
+    /// `distro_name=` lines; None without an `os=`
+    pub fn parse(output: &str) -> Option<Self> {
+        let value = |name: &str| {
+            output
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .find(|(key, value)| *key == name && !value.trim().is_empty())
+                .map(|(_, value)| value.trim().to_string())
+        };
+        Some(Self {
+            name: value("os")?,
+            kernel: value("kernel"),
+            distro: value("distro"),
+            distro_version: value("distro_version"),
+            pretty_name: value("distro_name"),
+        })
+    }
+
+    /// The distribution and kernel, e.g. `Debian GNU/Linux 12 (bookworm), Linux 6.1.21-v8+`
+    pub fn describe(&self) -> String {
+        let kernel = match &self.kernel {
+            Some(kernel) => format!("{} {}", self.name, kernel),
+            None => self.name.clone(),
+        };
+        let distro = self.pretty_name.clone().or_else(|| {
+            self.distro
+                .as_ref()
+                .map(|distro| match &self.distro_version {
+                    Some(version) => format!("{} {}", distro, version),
+                    None => distro.clone(),
+                })
+        });
+        match distro {
+            Some(distro) => format!("{}, {}", distro, kernel),
+            None => kernel,
+        }
+    }
+}
+
+/// The images a device may run: `require_os`, `require_distro` and `min_kernel`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct OsRequirements {
+    /// `uname -s`, compared without regard to case
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    /// Accepted os-release `ID`s; any when empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub distros: Vec<String>,
+    /// Oldest accepted kernel, e.g. `5.10`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_kernel: Option<String>,
+}
+
+impl OsRequirements {
+    /// Whether there is nothing to check
+    pub fn is_empty(&self) -> bool {
+        self.os.is_none() && self.distros.is_empty() && self.min_kernel.is_none()
+    }
+
+    pub fn validate(&self) -> Result<(), TunnelError> {
+        if let Some(min_kernel) = &self.min_kernel {
+            if version(min_kernel).is_empty() {
+                return Err(TunnelError::InvalidOsRequirement(format!(
+                    "min_kernel {:?} is not a version like 5.10",
+                    min_kernel
+                )));
+            }
+        }
+        if self.os.as_deref().is_some_and(|os| os.trim().is_empty()) {
+            return Err(TunnelError::InvalidOsRequirement(
+                "require_os is empty".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fails with what `os` doesn't meet, naming the first requirement it misses
+    pub fn check(&self, os: &OsInfo) -> Result<(), TunnelError> {
+        let unexpected = |message: String| Err(TunnelError::UnexpectedOs(message));
+        if let Some(required) = &self.os {
+            if !os.name.eq_ignore_ascii_case(required) {
+                return unexpected(format!("{} is not {}", os.name, required));
+            }
+        }
+        if !self.distros.is_empty() {
+            let Some(distro) = &os.distro else {
+                return unexpected(format!(
+                    "no /etc/os-release to tell the distribution, expected {}",
+                    self.distros.join(" or ")
+                ));
+            };
+            if !self
+                .distros
+                .iter()
+                .any(|accepted| accepted.eq_ignore_ascii_case(distro))
+            {
+                return unexpected(format!(
+                    "distribution {} is not {}",
+                    distro,
+                    self.distros.join(" or ")
+                ));
+            }
+        }
+        if let Some(min_kernel) = &self.min_kernel {
+            let kernel = os.kernel.as_deref().unwrap_or_default();
+            if compare_versions(kernel, min_kernel) == Ordering::Less {
+                return unexpected(format!(
+                    "kernel {} is older than {}",
+                    if kernel.is_empty() { "unknown" } else { kernel },
+                    min_kernel
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The leading numbers of a version, e.g. `[6, 1, 21]` for `6.1.21-v8+`
+fn version(text: &str) -> Vec<u64> {
+    let mut numbers = Vec::new();
+    for part in text.trim().split('.') {
+        let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+        let Ok(number) = digits.parse() else {
+            break;
+        };
+        numbers.push(number);
+        // Whatever follows a part like `21-v8+` is no longer the version
+        if digits.len() < part.len() {
+            break;
+        }
+    }
+    numbers
+}
+
+/// Compares two kernel versions by their numbers, missing ones counting as 0
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a, b) = (version(a), version(b));
+    (0..a.len().max(b.len()))
+        .map(|i| {
+            let part = |v: &[u64]| v.get(i).copied().unwrap_or(0);
+            part(&a).cmp(&part(&b))
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bookworm() -> OsInfo {
+        OsInfo::parse(
+            "arch=aarch64\nos=Linux\nkernel=6.1.21-v8+\ndistro=debian\ndistro_version=12\n\
+             distro_name=Debian GNU/Linux 12 (bookworm)\n",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_describe() {
+        assert_eq!(
+            bookworm().describe(),
+            "Debian GNU/Linux 12 (bookworm), Linux 6.1.21-v8+"
+        );
+        let bare = OsInfo::parse("os=Linux\nkernel=5.10.0\ndistro=alpine\n").unwrap();
+        assert_eq!(bare.describe(), "alpine, Linux 5.10.0");
+        assert_eq!(OsInfo::parse("arch=aarch64\n"), None);
+    }
+
+    #[test]
+    fn test_kernel_versions_compare_by_number() {
+        assert_eq!(compare_versions("6.1.21-v8+", "5.10"), Ordering::Greater);
+        assert_eq!(compare_versions("5.10.0", "5.10"), Ordering::Equal);
+        assert_eq!(compare_versions("5.4.83-v7l+", "5.10"), Ordering::Less);
+        assert_eq!(
+            compare_versions("6.6.31+rpt-rpi-v8", "6.6.31"),
+            Ordering::Equal
+        );
+        assert_eq!(compare_versions("", "5.10"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_check_names_the_missed_requirement() {
+        let requirements = OsRequirements {
+            os: Some("linux".to_string()),
+            distros: vec!["debian".to_string(), "raspbian".to_string()],
+            min_kernel: Some("5.10".to_string()),
+        };
+        assert!(requirements.validate().is_ok());
+        assert!(requirements.check(&bookworm()).is_ok());
+
+        let old = OsInfo {
+            kernel: Some("5.4.83-v7l+".to_string()),
+            ..bookworm()
+        };
+        assert_eq!(
+            requirements.check(&old).unwrap_err().to_string(),
+            "Unexpected operating system: kernel 5.4.83-v7l+ is older than 5.10"
+        );
+        let ubuntu = OsInfo {
+            distro: Some("ubuntu".to_string()),
+            ..bookworm()
+        };
+        assert_eq!(
+            requirements.check(&ubuntu).unwrap_err().to_string(),
+            "Unexpected operating system: distribution ubuntu is not debian or raspbian"
+        );
+
+        let invalid = OsRequirements {
+            min_kernel: Some("latest".to_string()),
+            ..OsRequirements::default()
+        };
+        assert!(invalid.validate().is_err());
+    }
+}
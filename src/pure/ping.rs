@@ -0,0 +1,118 @@
+//! Round-trip statistics of `ping`, and which part of the way to a device they blame.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Round trips of one kind of probe, in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Latency {
+    pub sent: u32,
+    /// Probes that got no answer in time
+    pub lost: u32,
+    pub min_ms: f64,
+    pub avg_ms: f64,
+    pub max_ms: f64,
+    /// Mean difference between consecutive round trips
+    pub jitter_ms: f64,
+}
+
+impl Latency {
+    /// Summarizes the round trips that were answered out of `sent` probes;
+    /// None when none were
+    pub fn from_samples(samples: &[Duration], sent: u32) -> Option<Self> {
+        let ms: Vec<f64> = samples.iter().map(|d| d.as_secs_f64() * 1000.0).collect();
+        let (first, rest) = ms.split_first()?;
+        let (min_ms, max_ms) = rest.iter().fold((*first, *first), |(min, max), &ms| {
+            (min.min(ms), max.max(ms))
+        });
+        let changes: Vec<f64> = ms
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .collect();
+        Some(Self {
+            sent,
+            lost: sent.saturating_sub(ms.len() as u32),
+            min_ms,
+            avg_ms: ms.iter().sum::<f64>() / ms.len() as f64,
+            max_ms,
+            jitter_ms: match changes.len() {
+                0 => 0.0,
+                n => changes.iter().sum::<f64>() / n as f64,
+            },
+        })
+    }
+}
+
+/// The part of a round trip that takes the longest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Bottleneck {
+    /// Getting packets to the device and back, e.g. over WiFi
+    Network,
+    /// SSH and the tunnel on top of the network
+    Tunnel,
+    /// Starting a session and a process on the device
+    Board,
+}
+
+/// Splits the average command round trip into the network's share, what
+/// the tunnel adds to it and what starting a command adds on top, and names
+/// the largest. Without a network measurement, the tunnel's share includes
+/// the network's.
+pub fn bottleneck(network: Option<&Latency>, tunnel: &Latency, command: &Latency) -> Bottleneck {
+    let network_ms = network.map_or(0.0, |network| network.avg_ms);
+    let shares = [
+        (Bottleneck::Network, network_ms),
+        (Bottleneck::Tunnel, tunnel.avg_ms - network_ms),
+        (Bottleneck::Board, command.avg_ms - tunnel.avg_ms),
+    ];
+    shares
+        .into_iter()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(Bottleneck::Tunnel, |(part, _)| part)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latency(samples_ms: &[u64], sent: u32) -> Latency {
+        let samples: Vec<Duration> = samples_ms
+            .iter()
+            .map(|&ms| Duration::from_millis(ms))
+            .collect();
+        Latency::from_samples(&samples, sent).unwrap()
+    }
+
+    #[test]
+    fn test_statistics_and_bottleneck() {
+        let network = latency(&[4, 8, 6], 4);
+        assert_eq!(network.lost, 1);
+        assert_eq!(
+            (network.min_ms, network.avg_ms, network.max_ms),
+            (4.0, 6.0, 8.0)
+        );
+        assert_eq!(network.jitter_ms, 3.0);
+        assert_eq!(latency(&[5], 1).jitter_ms, 0.0);
+        assert_eq!(Latency::from_samples(&[], 3), None);
+
+        let tunnel = latency(&[9, 11], 2);
+        assert_eq!(
+            bottleneck(Some(&network), &tunnel, &latency(&[90, 110], 2)),
+            Bottleneck::Board
+        );
+        assert_eq!(
+            bottleneck(Some(&network), &latency(&[60], 1), &latency(&[70], 1)),
+            Bottleneck::Tunnel
+        );
+        let wifi = latency(&[80, 120], 2);
+        assert_eq!(
+            bottleneck(Some(&wifi), &latency(&[105], 1), &latency(&[140], 1)),
+            Bottleneck::Network
+        );
+        assert_eq!(
+            bottleneck(None, &latency(&[105], 1), &latency(&[140], 1)),
+            Bottleneck::Tunnel
+        );
+    }
+}
@@ -0,0 +1,444 @@
+//! Device profiles and their plans, short of running anything on a device.
+//!
+//! A profile is parsed and checked here, its templates rendered from a
+//! device's facts, and a step script's output turned into [`Change`]s. What
+//! the steps run lives in `device_profile`, which needs a device.
+
+use crate::pure::facts::Facts;
+use crate::pure::hardware::HardwareConfig;
+use crate::pure::text;
+use crate::template;
+use crate::validate;
+use crate::TunnelError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A `[device_profiles.<name>]` section. Every part is optional; parts that
+/// are left out are not touched.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceProfile {
+    /// Hostname to set; `{name}` is replaced with the host profile's name
+    /// (like `{{ name }}`)
+    pub hostname: Option<String>,
+    /// User accounts to create
+    pub users: Vec<UserSpec>,
+    /// Public keys to authorize for the login user
+    pub keys: Vec<String>,
+    /// sshd_config keywords and values, e.g. `PasswordAuthentication = "no"`
+    pub sshd: BTreeMap<String, String>,
+    /// Packages to install
+    pub packages: Vec<String>,
+    /// Interfaces, overlays and modules, as in `[hardware]`
+    pub hardware: HardwareConfig,
+    pub wifi: Option<WifiConfig>,
+}
+
+/// A user account a device profile creates
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserSpec {
+    pub name: String,
+    /// Supplementary groups, created if missing
+    pub groups: Vec<String>,
+    /// Public keys to authorize for the user
+    pub keys: Vec<String>,
+}
+
+/// A WPA2-PSK network
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WifiConfig {
+    pub ssid: String,
+    pub psk: String,
+    /// Two-letter regulatory domain, e.g. `GB`
+    pub country: Option<String>,
+}
+
+/// Whether `hostname` is a single label: letters, digits and '-', at most 63
+pub fn is_valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && hostname.len() <= 63
+        && !hostname.starts_with('-')
+        && hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+impl DeviceProfile {
+    /// Checks every value that ends up in a file or command on the device
+    pub fn validate(&self) -> Result<(), TunnelError> {
+        let invalid = |message: String| Err(TunnelError::Profile(message));
+        if let Some(hostname) = &self.hostname {
+            if !is_valid_hostname(hostname) {
+                return invalid(format!(
+                    "invalid hostname {:?}: expected letters, digits and '-', at most 63",
+                    hostname
+                ));
+            }
+        }
+        for user in &self.users {
+            validate::validate_username(&user.name)?;
+            for group in &user.groups {
+                validate::validate_username(group)
+                    .map_err(|_| TunnelError::Profile(format!("invalid group name {:?}", group)))?;
+            }
+        }
+        for (keyword, value) in &self.sshd {
+            if !keyword.chars().all(|c| c.is_ascii_alphanumeric())
+                || value.is_empty()
+                || value.contains(|c: char| c.is_control())
+            {
+                return invalid(format!("invalid sshd setting {} = {:?}", keyword, value));
+            }
+        }
+        for package in &self.packages {
+            if package.is_empty()
+                || package.starts_with('-')
+                || !package
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '+' | '-' | '_' | ':'))
+            {
+                return invalid(format!("invalid package name {:?}", package));
+            }
+        }
+        self.hardware
+            .validate()
+            .map_err(|e| TunnelError::Profile(e.to_string()))?;
+        if let Some(wifi) = &self.wifi {
+            if wifi.ssid.is_empty()
+                || wifi.ssid.len() > 32
+                || wifi.ssid.contains(|c: char| c == '"' || c.is_control())
+            {
+                return invalid(format!("invalid Wi-Fi SSID {:?}", wifi.ssid));
+            }
+            if !(8..=63).contains(&wifi.psk.len())
+                || wifi.psk.contains(|c: char| c == '"' || c.is_control())
+            {
+                return invalid(
+                    "the Wi-Fi passphrase must be 8 to 63 characters without quotes".to_string(),
+                );
+            }
+            if let Some(country) = &wifi.country {
+                if country.len() != 2 || !country.chars().all(|c| c.is_ascii_uppercase()) {
+                    return invalid(format!(
+                        "invalid Wi-Fi country {:?}: expected two capital letters",
+                        country
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The profile with its templates filled in from `context`. List entries
+    /// that render empty are dropped, so `{% if %}` can leave one out.
+    pub fn render(&self, context: &template::Context) -> Result<DeviceProfile, TunnelError> {
+        let one = |field: &str, value: &str| -> Result<String, TunnelError> {
+            if !template::is_template(value) {
+                return Ok(value.to_string());
+            }
+            template::render(value, context)
+                .map(|rendered| rendered.trim().to_string())
+                .map_err(|e| TunnelError::Profile(format!("{}: {}", field, e)))
+        };
+        let list = |field: &str, values: &[String]| -> Result<Vec<String>, TunnelError> {
+            let mut rendered = Vec::new();
+            for value in values {
+                let value = one(field, value)?;
+                if !value.is_empty() {
+                    rendered.push(value);
+                }
+            }
+            Ok(rendered)
+        };
+
+        let mut users = Vec::new();
+        for user in &self.users {
+            let name = one("users.name", &user.name)?;
+            if !name.is_empty() {
+                users.push(UserSpec {
+                    name,
+                    groups: list("users.groups", &user.groups)?,
+                    keys: list("users.keys", &user.keys)?,
+                });
+            }
+        }
+        Ok(DeviceProfile {
+            hostname: match &self.hostname {
+                Some(hostname) => {
+                    let name = context.get("name").map(String::as_str).unwrap_or_default();
+                    Some(one("hostname", &hostname.replace("{name}", name))?)
+                }
+                None => None,
+            },
+            users,
+            keys: list("keys", &self.keys)?,
+            sshd: self
+                .sshd
+                .iter()
+                .map(|(keyword, value)| {
+                    Ok((keyword.clone(), one(&format!("sshd.{}", keyword), value)?))
+                })
+                .collect::<Result<_, TunnelError>>()?,
+            packages: list("packages", &self.packages)?,
+            hardware: HardwareConfig {
+                interfaces: self.hardware.interfaces.clone(),
+                dtoverlays: list("hardware.dtoverlays", &self.hardware.dtoverlays)?,
+                modules: list("hardware.modules", &self.hardware.modules)?,
+            },
+            wifi: match &self.wifi {
+                Some(wifi) => Some(WifiConfig {
+                    ssid: one("wifi.ssid", &wifi.ssid)?,
+                    psk: one("wifi.psk", &wifi.psk)?,
+                    country: wifi
+                        .country
+                        .as_deref()
+                        .map(|country| one("wifi.country", country))
+                        .transpose()?,
+                }),
+                None => None,
+            },
+        })
+    }
+
+    /// The sshd drop-in's contents
+    pub fn sshd_drop_in(&self) -> String {
+        self.sshd
+            .iter()
+            .map(|(keyword, value)| format!("{} {}", keyword, value))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// The parts of a device profile, in the order they are applied. Keys come
+/// before sshd, so tightening authentication can't lock the login user out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Step {
+    Hostname,
+    Users,
+    Keys,
+    Sshd,
+    Packages,
+    Hardware,
+    Wifi,
+}
+
+impl Step {
+    pub const ALL: [Step; 7] = [
+        Step::Hostname,
+        Step::Users,
+        Step::Keys,
+        Step::Sshd,
+        Step::Packages,
+        Step::Hardware,
+        Step::Wifi,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Step::Hostname => "hostname",
+            Step::Users => "users",
+            Step::Keys => "keys",
+            Step::Sshd => "sshd",
+            Step::Packages => "packages",
+            Step::Hardware => "hardware",
+            Step::Wifi => "wifi",
+        }
+    }
+}
+
+/// One difference between the device and its profile
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    pub step: Step,
+    /// `+ package i2c-tools`, `~ hostname raspberrypi -> sensor-01`, ...
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileReport {
+    pub host: String,
+    pub device_profile: String,
+    /// The facts the profile's templates were rendered with
+    pub facts: Facts,
+    pub changes: Vec<Change>,
+    /// False when only the plan was made
+    pub applied: bool,
+    /// Whether boot configuration changes take a reboot to apply
+    pub reboot_required: bool,
+}
+
+impl ProfileReport {
+    /// The plan, or what was applied, as shown to people
+    pub fn text(&self) -> String {
+        let mut text = if self.changes.is_empty() {
+            format!(
+                "{} already matches device profile '{}'",
+                self.host, self.device_profile
+            )
+        } else {
+            format!(
+                "{} {} change{} for device profile '{}' on {}:",
+                if self.applied { "Applied" } else { "Planned" },
+                self.changes.len(),
+                if self.changes.len() == 1 { "" } else { "s" },
+                self.device_profile,
+                self.host
+            )
+        };
+        let rows: Vec<Vec<String>> = self
+            .changes
+            .iter()
+            .map(|change| vec![change.step.as_str().to_string(), change.description.clone()])
+            .collect();
+        if !rows.is_empty() {
+            text.push('\n');
+            text.push_str(&text::table(&["STEP", "CHANGE"], &rows));
+        }
+        text.push_str(&format!(
+            "\nBoard: {} ({}, {} MB)",
+            if self.facts.model.is_empty() {
+                "unknown model"
+            } else {
+                &self.facts.model
+            },
+            self.facts.arch,
+            self.facts.mem_mb
+        ));
+        if self.reboot_required {
+            text.push_str(&format!(
+                "\n{} must be rebooted for the boot configuration changes to take effect",
+                self.host
+            ));
+        }
+        text
+    }
+}
+
+/// Template values: the device's facts and the host profile's `name`
+pub fn context(facts: &Facts, host_name: &str) -> template::Context {
+    let mut context = facts.context();
+    context.insert("name".to_string(), host_name.to_string());
+    context
+}
+
+/// Whether `changes` include boot configuration lines, which take a reboot
+/// to apply
+pub fn reboot_required(changes: &[Change]) -> bool {
+    changes.iter().any(|change| {
+        change.step == Step::Hardware && change.description.starts_with("+ config.txt")
+    })
+}
+
+/// The `+`/`-`/`~` lines of a step script's output
+pub fn change_lines(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| ["+ ", "- ", "~ "].iter().any(|mark| line.starts_with(mark)))
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_parses_and_validates() {
+        let profile: DeviceProfile = toml::from_str::<DeviceProfile>(
+            r#"
+hostname = "sensor-{name}"
+packages = ["i2c-tools", "python3-smbus"]
+sshd = { PasswordAuthentication = "no", PermitRootLogin = "prohibit-password" }
+
+[[users]]
+name = "telemetry"
+groups = ["i2c", "gpio"]
+
+[hardware]
+interfaces = ["i2c"]
+
+[wifi]
+ssid = "lab"
+psk = "correct horse"
+country = "GB"
+"#,
+        )
+        .unwrap()
+        .render(&context(&Facts::example(), "01"))
+        .unwrap();
+        assert_eq!(profile.hostname.as_deref(), Some("sensor-01"));
+        assert!(profile.validate().is_ok());
+        assert_eq!(
+            profile.sshd_drop_in(),
+            "PasswordAuthentication no\nPermitRootLogin prohibit-password"
+        );
+
+        let bad = |edit: fn(&mut DeviceProfile)| {
+            let mut profile = profile.clone();
+            edit(&mut profile);
+            profile.validate().is_err()
+        };
+        assert!(bad(|p| p.hostname = Some("sensor_01".to_string())));
+        assert!(bad(|p| p
+            .packages
+            .push("--allow-unauthenticated".to_string())));
+        assert!(bad(|p| {
+            p.sshd.insert(
+                "AllowUsers".to_string(),
+                "pi\nPermitRootLogin yes".to_string(),
+            );
+        }));
+        assert!(bad(|p| p.wifi.as_mut().unwrap().psk = "short".to_string()));
+    }
+
+    #[test]
+    fn test_render_picks_values_per_board() {
+        let profile: DeviceProfile = toml::from_str(
+            r#"
+hostname = "{{ name }}-{{ arch }}"
+packages = ["i2c-tools", "{% if mem_mb < 1024 %}zram-tools{% endif %}"]
+
+[hardware]
+dtoverlays = ['{% if model contains "Pi 4" %}dwc2{% else %}w1-gpio{% endif %}']
+"#,
+        )
+        .unwrap();
+        let small = Facts {
+            arch: "armv7l".to_string(),
+            model: "Raspberry Pi Zero 2 W Rev 1.0".to_string(),
+            mem_mb: 427,
+            ..Facts::example()
+        };
+
+        let rendered = profile.render(&context(&Facts::example(), "lab")).unwrap();
+        assert_eq!(rendered.hostname.as_deref(), Some("lab-aarch64"));
+        assert_eq!(rendered.packages, ["i2c-tools"]);
+        assert_eq!(rendered.hardware.dtoverlays, ["dwc2"]);
+        let rendered = profile.render(&context(&small, "lab")).unwrap();
+        assert_eq!(rendered.packages, ["i2c-tools", "zram-tools"]);
+        assert_eq!(rendered.hardware.dtoverlays, ["w1-gpio"]);
+
+        let typo = DeviceProfile {
+            packages: vec!["{{ mem }}".to_string()],
+            ..Default::default()
+        };
+        assert!(typo
+            .render(&context(&small, "lab"))
+            .unwrap_err()
+            .to_string()
+            .contains("packages: unknown name 'mem'"));
+    }
+
+    #[test]
+    fn test_change_lines() {
+        assert_eq!(
+            change_lines("Reading package lists...\n+ package i2c-tools\n~ hostname a -> b\n"),
+            ["+ package i2c-tools", "~ hostname a -> b"]
+        );
+    }
+}
@@ -0,0 +1,253 @@
+//! Snapshots of a device's state, and what changed between two of them.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The parts of a device's state that provisioning changes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub host: String,
+    pub taken_at: DateTime<Utc>,
+    /// Installed packages and their versions
+    pub packages: BTreeMap<String, String>,
+    /// SHA-256 of sshd_config and each file in sshd_config.d, by path
+    pub sshd_config: BTreeMap<String, String>,
+    /// The login user's authorized_keys lines, without blanks and comments
+    pub authorized_keys: BTreeSet<String>,
+    /// Services enabled at boot
+    pub services: BTreeSet<String>,
+}
+
+impl Snapshot {
+    /// Parses lines of `package <name> <version>`, `sshd <sha256> <path>`,
+    /// `key <line>` and `service <name>`, ignoring anything else
+    pub fn parse(host: &str, output: &str, taken_at: DateTime<Utc>) -> Self {
+        let mut snapshot = Snapshot {
+            host: host.to_string(),
+            taken_at,
+            ..Snapshot::default()
+        };
+        for line in output.lines() {
+            let Some((kind, rest)) = line.split_once(' ') else {
+                continue;
+            };
+            match kind {
+                "package" => {
+                    let (name, version) = rest.split_once(' ').unwrap_or((rest, ""));
+                    snapshot
+                        .packages
+                        .insert(name.to_string(), version.trim().to_string());
+                }
+                "sshd" => {
+                    if let Some((hash, path)) = rest.split_once(' ') {
+                        snapshot
+                            .sshd_config
+                            .insert(path.to_string(), hash.to_string());
+                    }
+                }
+                "key" if !rest.trim().is_empty() => {
+                    snapshot.authorized_keys.insert(rest.trim().to_string());
+                }
+                "service" if !rest.trim().is_empty() => {
+                    snapshot.services.insert(rest.trim().to_string());
+                }
+                _ => {}
+            }
+        }
+        snapshot
+    }
+}
+
+/// How an item differs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// One difference between two snapshots
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Change {
+    /// `package`, `sshd_config`, `authorized_keys` or `service`
+    pub area: &'static str,
+    pub item: String,
+    pub kind: ChangeKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+}
+
+/// What changed from one snapshot to another
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SnapshotDiff {
+    pub host: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub changes: Vec<Change>,
+}
+
+/// Differences between two maps of item to value
+fn diff_maps(
+    area: &'static str,
+    from: &BTreeMap<String, String>,
+    to: &BTreeMap<String, String>,
+    changes: &mut Vec<Change>,
+) {
+    for (item, old) in from {
+        match to.get(item) {
+            None => changes.push(Change {
+                area,
+                item: item.clone(),
+                kind: ChangeKind::Removed,
+                from: Some(old.clone()),
+                to: None,
+            }),
+            Some(new) if new != old => changes.push(Change {
+                area,
+                item: item.clone(),
+                kind: ChangeKind::Changed,
+                from: Some(old.clone()),
+                to: Some(new.clone()),
+            }),
+            Some(_) => {}
+        }
+    }
+    for (item, new) in to {
+        if !from.contains_key(item) {
+            changes.push(Change {
+                area,
+                item: item.clone(),
+                kind: ChangeKind::Added,
+                from: None,
+                to: Some(new.clone()),
+            });
+        }
+    }
+}
+
+/// Differences between two sets of items
+fn diff_sets(
+    area: &'static str,
+    from: &BTreeSet<String>,
+    to: &BTreeSet<String>,
+    changes: &mut Vec<Change>,
+) {
+    for (items, others, kind) in [
+        (from, to, ChangeKind::Removed),
+        (to, from, ChangeKind::Added),
+    ] {
+        changes.extend(items.difference(others).map(|item| Change {
+            area,
+            item: item.clone(),
+            kind,
+            from: None,
+            to: None,
+        }));
+    }
+}
+
+/// Everything that differs from `from` to `to`, by area
+pub fn diff(from: &Snapshot, to: &Snapshot) -> SnapshotDiff {
+    let mut changes = Vec::new();
+    diff_maps("package", &from.packages, &to.packages, &mut changes);
+    diff_maps(
+        "sshd_config",
+        &from.sshd_config,
+        &to.sshd_config,
+        &mut changes,
+    );
+    diff_sets(
+        "authorized_keys",
+        &from.authorized_keys,
+        &to.authorized_keys,
+        &mut changes,
+    );
+    diff_sets("service", &from.services, &to.services, &mut changes);
+    SnapshotDiff {
+        host: to.host.clone(),
+        from: from.taken_at,
+        to: to.taken_at,
+        changes,
+    }
+}
+
+/// A short form of an authorized_keys line: its options, key type, the end
+/// of the key and the comment
+pub fn key_label(line: &str) -> String {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let Some(position) = fields.iter().position(|field| {
+        field.starts_with("ssh-") || field.starts_with("ecdsa-") || field.starts_with("sk-")
+    }) else {
+        return line.to_string();
+    };
+    let mut label: Vec<String> = fields[..=position].iter().map(|f| f.to_string()).collect();
+    if let Some(data) = fields.get(position + 1) {
+        let tail = data.len().saturating_sub(8);
+        label.push(format!("...{}", &data[tail..]));
+    }
+    label.extend(fields.iter().skip(position + 2).map(|f| f.to_string()));
+    label.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: &str = "package openssh-server 1:9.2p1-2\npackage curl 7.88.1\n\
+        sshd 3f2a /etc/ssh/sshd_config\nkey ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIabcdefgh pi@laptop\n\
+        service ssh.service\nservice cron.service\nnoise\n";
+
+    #[test]
+    fn test_parse_snapshot() {
+        let snapshot = Snapshot::parse("pi", OUTPUT, DateTime::default());
+        assert_eq!(snapshot.packages["openssh-server"], "1:9.2p1-2");
+        assert_eq!(snapshot.sshd_config["/etc/ssh/sshd_config"], "3f2a");
+        assert_eq!(snapshot.authorized_keys.len(), 1);
+        assert_eq!(
+            snapshot.services.iter().collect::<Vec<_>>(),
+            ["cron.service", "ssh.service"]
+        );
+    }
+
+    #[test]
+    fn test_diff_reports_each_area() {
+        let from = Snapshot::parse("pi", OUTPUT, DateTime::default());
+        let mut to = from.clone();
+        to.packages.insert("curl".to_string(), "8.0".to_string());
+        to.packages.insert("htop".to_string(), "3.2".to_string());
+        to.sshd_config.clear();
+        to.services.remove("cron.service");
+        to.authorized_keys.insert("ssh-rsa AAAAB3 ci".to_string());
+
+        let changes = diff(&from, &to).changes;
+        let summary: Vec<(&str, &str, ChangeKind)> = changes
+            .iter()
+            .map(|c| (c.area, c.item.as_str(), c.kind))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("package", "curl", ChangeKind::Changed),
+                ("package", "htop", ChangeKind::Added),
+                ("sshd_config", "/etc/ssh/sshd_config", ChangeKind::Removed),
+                ("authorized_keys", "ssh-rsa AAAAB3 ci", ChangeKind::Added),
+                ("service", "cron.service", ChangeKind::Removed),
+            ]
+        );
+        assert_eq!(changes[0].from.as_deref(), Some("7.88.1"));
+        assert!(diff(&from, &from).changes.is_empty());
+    }
+
+    #[test]
+    fn test_key_label() {
+        assert_eq!(
+            key_label("restrict ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIabcdefgh pi@laptop"),
+            "restrict ssh-ed25519 ...abcdefgh pi@laptop"
+        );
+        assert_eq!(key_label("garbage"), "garbage");
+    }
+}
@@ -0,0 +1,50 @@
+//! The kinds of swap `up` can set up.
+
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// What kind of swap to set up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "runtime", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum SwapMode {
+    /// Leave swap alone
+    #[default]
+    Off,
+    /// zram if the kernel has it, otherwise a swapfile
+    Auto,
+    /// Compressed swap in RAM
+    Zram,
+    /// A swapfile on the root filesystem
+    File,
+}
+
+impl SwapMode {
+    const ALL: [SwapMode; 4] = [
+        SwapMode::Off,
+        SwapMode::Auto,
+        SwapMode::Zram,
+        SwapMode::File,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SwapMode::Off => "off",
+            SwapMode::Auto => "auto",
+            SwapMode::Zram => "zram",
+            SwapMode::File => "file",
+        }
+    }
+}
+
+impl FromStr for SwapMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("invalid variant: {}", s))
+    }
+}
@@ -0,0 +1,637 @@
+//! Copying files to a device through the tunnel.
+//!
+//! `push` can fetch the file from an HTTP(S) server first, for artifacts that
+//! live somewhere the device itself cannot reach. Both legs resume: a partial
+//! download continues with a range request, and a partial upload (kept as
+//! `<dest>.part` on the device) continues from the size already there. With
+//! `--verify` the upload is hashed on the device before it replaces `dest`.
+//!
+//! Each upload in progress is recorded under the state directory, so a later
+//! run only appends to a `.part` file that holds the start of the same file.
+//! A connection dropped mid-upload is reopened and the upload resumed, up to
+//! `max_retries` times.
+
+use crate::checksum;
+use crate::config::Config;
+use crate::executor::Stdio;
+use crate::fetch;
+use crate::output::{self, Renderable};
+use crate::paths;
+use crate::run;
+use crate::shell::RemoteCommand;
+use crate::ssh;
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+/// Upper bound for the small remote commands around the upload
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Upper bound for joining the chunks of a parallel upload on the device
+const JOIN_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Prints the size of the file `$1`, 0 if there is none
+pub(crate) const SIZE_SCRIPT: &str = r#"if [ -f "$1" ]; then wc -c < "$1"; else echo 0; fi"#;
+
+/// Writes stdin to `$1`
+pub(crate) const WRITE_SCRIPT: &str = r#"cat > "$1""#;
+
+/// Appends stdin to `$1`
+pub(crate) const APPEND_SCRIPT: &str = r#"cat >> "$1""#;
+
+/// Joins the files after `$1` into it and removes them
+pub(crate) const JOIN_SCRIPT: &str = r#"out=$1; shift; cat "$@" > "$out" && rm -f "$@""#;
+
+/// Moves `$1` to `$2`
+pub(crate) const MOVE_SCRIPT: &str = r#"mv -f "$1" "$2""#;
+
+/// Where the file to push comes from
+#[derive(Debug, Clone)]
+pub enum PushSource {
+    File(PathBuf),
+    Url(String),
+}
+
+/// Result of `push`
+#[derive(Debug, Serialize)]
+pub struct PushReport {
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    pub local_path: PathBuf,
+    pub remote_path: String,
+    pub bytes: u64,
+    /// Bytes already on the device from an earlier, interrupted upload
+    pub resumed_from: u64,
+    /// Times the connection dropped and the upload was resumed
+    pub retries: u32,
+    /// Parallel connections the upload was split over
+    pub streams: usize,
+    pub sha256: String,
+    /// Whether `sha256` was checked against a published or given checksum
+    pub verified: bool,
+    /// SHA-256 of the uploaded copy, computed on the device with `--verify`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_sha256: Option<String>,
+}
+
+impl Renderable for PushReport {
+    fn to_human(&self) -> String {
+        let mut text = format!(
+            "Pushed {} ({} bytes) to {}:{}",
+            self.local_path.display(),
+            self.bytes,
+            self.host,
+            self.remote_path
+        );
+        if self.resumed_from > 0 {
+            text.push_str(&format!(", resumed at {} bytes", self.resumed_from));
+        }
+        if self.streams > 1 {
+            text.push_str(&format!(" over {} connections", self.streams));
+        }
+        if self.retries > 0 {
+            text.push_str(&format!(" after {} dropped connection(s)", self.retries));
+        }
+        text.push_str(&format!(
+            "\nSHA-256 {}{}",
+            self.sha256,
+            if self.verified { " (verified)" } else { "" }
+        ));
+        if self.remote_sha256.is_some() {
+            text.push_str("\nDevice copy matches");
+        }
+        text
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Fetches `source` if needed and copies it to `dest` on `target`.
+///
+/// `dest` defaults to the source's file name in the login user's home directory.
+/// With `verify`, the uploaded copy is hashed on the device and must match.
+/// With `streams` > 1 the upload is split over that many connections, and
+/// always verified since the chunks are joined on the device.
+pub async fn push(
+    config: &Config,
+    target: &Target,
+    source: &PushSource,
+    dest: Option<&str>,
+    expected_sha256: Option<&str>,
+    verify: bool,
+    streams: usize,
+) -> Result<PushReport> {
+    let verify = verify || streams > 1;
+    let expected = expected_sha256
+        .map(|hex| {
+            checksum::parse_sha256(hex)
+                .ok_or_else(|| anyhow::anyhow!("Invalid SHA-256 checksum {:?}", hex))
+        })
+        .transpose()?;
+
+    // Get the file in hand before touching the device
+    let (local_path, url, expected) = match source {
+        PushSource::File(path) => (path.clone(), None, expected),
+        PushSource::Url(url) => {
+            let expected = match expected {
+                Some(digest) => Some(digest),
+                None => published_checksum(url).await?,
+            };
+            (
+                download(url, expected.as_deref()).await?,
+                Some(url.clone()),
+                expected,
+            )
+        }
+    };
+    let sha256 = checksum::sha256_file(&local_path)
+        .map_err(|e| anyhow::anyhow!("Cannot read {}: {}", local_path.display(), e))?;
+    if let Some(expected) = &expected {
+        if *expected != sha256 {
+            return Err(TunnelError::ChecksumMismatch(format!(
+                "{} has SHA-256 {}, expected {}",
+                local_path.display(),
+                sha256,
+                expected
+            ))
+            .into());
+        }
+    } else {
+        warn!("No checksum to verify {} against", local_path.display());
+    }
+
+    let remote_path = match dest {
+        Some(dest) => dest.to_string(),
+        None => file_name(&local_path)?,
+    };
+
+    let manager = SSHTunnelManager::new(config.clone());
+    manager.connect(target).await?;
+
+    let mut retries = 0;
+    let (bytes, resumed_from) = loop {
+        match upload(target, &local_path, &remote_path, &sha256, verify, streams).await {
+            Ok(done) => break done,
+            Err(TunnelError::ConnectionLost(message)) if retries < config.max_retries => {
+                retries += 1;
+                warn!(
+                    "Upload interrupted ({}); resuming, attempt {} of {}",
+                    message, retries, config.max_retries
+                );
+                if manager.validate_tunnel(target).await.is_err() {
+                    manager.connect(target).await?;
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    // upload() only returns once the device copy matched
+    let remote_sha256 = verify.then(|| sha256.clone());
+    Ok(PushReport {
+        host: target.host.clone(),
+        url,
+        local_path,
+        remote_path,
+        bytes,
+        resumed_from,
+        retries,
+        streams,
+        sha256,
+        verified: expected.is_some(),
+        remote_sha256,
+    })
+}
+
+fn file_name(path: &Path) -> Result<String> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow::anyhow!("Cannot derive a file name from {}", path.display()))
+}
+
+/// Looks for a `<url>.sha256` file next to the artifact
+async fn published_checksum(url: &str) -> Result<Option<String>, TunnelError> {
+    let sidecar = format!("{}.sha256", url);
+    match fetch::fetch_text(&sidecar).await? {
+        Some(text) => checksum::parse_sha256(&text).map(Some).ok_or_else(|| {
+            TunnelError::Download(format!("{} does not contain a SHA-256 checksum", sidecar))
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Downloads `url` into the cache, reusing an earlier download that matches `expected`
+async fn download(url: &str, expected: Option<&str>) -> Result<PathBuf> {
+    let name = url
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("URL {} does not name a file", url))?;
+    let dest = paths::cache_dir().join("downloads").join(name);
+
+    if let Some(expected) = expected {
+        if checksum::sha256_file(&dest).is_ok_and(|digest| digest == expected) {
+            info!("Using cached {}", dest.display());
+            return Ok(dest);
+        }
+    }
+    if !fetch::download(url, &dest).await? {
+        anyhow::bail!(TunnelError::Download(format!("{}: not found", url)));
+    }
+    Ok(dest)
+}
+
+/// An unfinished upload, persisted so that only `.part` files of the same source are resumed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct TransferState {
+    host: String,
+    remote_path: String,
+    sha256: String,
+    size: u64,
+    streams: usize,
+    /// The run that started the upload
+    #[serde(default)]
+    run_id: String,
+}
+
+impl TransferState {
+    fn path(&self) -> PathBuf {
+        let key = checksum::sha256_bytes(format!("{}\0{}", self.host, self.remote_path).as_bytes());
+        paths::state_dir()
+            .join("transfers")
+            .join(format!("{}.json", &key[..16]))
+    }
+
+    /// The run that started this same upload, if an earlier one recorded it
+    fn pending_run(&self) -> Option<String> {
+        std::fs::read_to_string(self.path())
+            .ok()
+            .and_then(|text| serde_json::from_str::<Self>(&text).ok())
+            .filter(|saved| {
+                Self {
+                    run_id: self.run_id.clone(),
+                    ..saved.clone()
+                } == *self
+            })
+            .map(|saved| saved.run_id)
+    }
+
+    fn save(&self) {
+        let path = self.path();
+        let written = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, serde_json::to_string(self).unwrap_or_default()));
+        if let Err(e) = written {
+            warn!("Cannot record upload state in {}: {}", path.display(), e);
+        }
+    }
+
+    fn clear(&self) {
+        let _ = std::fs::remove_file(self.path());
+    }
+}
+
+/// One slice of the file, uploaded to its own `<dest>.part.<n>` in a parallel upload
+#[derive(Debug, Clone, PartialEq)]
+struct Chunk {
+    start: u64,
+    len: u64,
+    remote: String,
+}
+
+/// Splits `size` bytes into at most `streams` contiguous chunks
+fn chunks(partial: &str, size: u64, streams: usize) -> Vec<Chunk> {
+    let chunk_len = size.div_ceil(streams as u64).max(1);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    loop {
+        let len = chunk_len.min(size - start);
+        chunks.push(Chunk {
+            start,
+            len,
+            remote: format!("{}.{}", partial, chunks.len()),
+        });
+        start += len;
+        if start >= size {
+            return chunks;
+        }
+    }
+}
+
+/// Copies `local` to `remote` on the device, returning its size and the bytes resumed from.
+///
+/// With `streams` > 1 the file goes up in chunks over that many connections and is
+/// joined on the device. With `verify`, the finished `.part` file must have SHA-256
+/// `sha256` before it is moved into place; a corrupt one is deleted so the next
+/// attempt starts over.
+async fn upload(
+    target: &Target,
+    local: &Path,
+    remote: &str,
+    sha256: &str,
+    verify: bool,
+    streams: usize,
+) -> Result<(u64, u64), TunnelError> {
+    let failed = |message: String| TunnelError::Transfer(format!("{}: {}", remote, message));
+    let partial = format!("{}.part", remote);
+
+    let size = std::fs::metadata(local)
+        .map_err(|e| failed(e.to_string()))?
+        .len();
+    let mut state = TransferState {
+        host: target.host.clone(),
+        remote_path: remote.to_string(),
+        sha256: sha256.to_string(),
+        size,
+        streams,
+        run_id: run::current().to_string(),
+    };
+    let pending_run = state.pending_run();
+    let pending = pending_run.is_some();
+    if let Some(run_id) = pending_run {
+        debug!("Continuing the upload started by run {}", run_id);
+        state.run_id = run_id;
+    }
+    state.save();
+
+    let existing = remote_size(target, &partial).await?;
+    // Anything longer than the file can't be a prefix of it
+    let offset = if pending && existing <= size {
+        existing
+    } else {
+        if existing > 0 {
+            info!(
+                "Discarding {} bytes of {} left by a different upload",
+                existing, partial
+            );
+        }
+        0
+    };
+
+    let resumed = if offset == size && size > 0 {
+        info!("{} is already complete on the device", partial);
+        offset
+    } else if streams > 1 {
+        info!(
+            "Uploading {} to {} over {} connections...",
+            local.display(),
+            remote,
+            streams
+        );
+        send_chunks(target, local, &partial, size, streams, pending).await?
+    } else {
+        if offset > 0 {
+            info!("Resuming upload of {} at {} bytes...", remote, offset);
+        } else {
+            info!("Uploading {} to {}...", local.display(), remote);
+        }
+        send_range(target, local, offset, size - offset, &partial, offset > 0).await?;
+        offset
+    };
+
+    if verify {
+        if let Err(e) = checksum::verify_remote(target, &partial, sha256).await {
+            if matches!(e, TunnelError::ChecksumMismatch(_)) {
+                state.clear();
+                let discard = RemoteCommand::new("rm").arg("-f").arg(&partial);
+                if let Err(rm) = ssh::run_through_tunnel(target, &discard, COMMAND_TIMEOUT).await {
+                    warn!("Could not remove corrupt {}: {}", partial, rm);
+                }
+            }
+            return Err(e);
+        }
+    }
+
+    let finish = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(MOVE_SCRIPT)
+        .arg("sh")
+        .arg(&partial)
+        .arg(remote);
+    ssh::run_through_tunnel(target, &finish, COMMAND_TIMEOUT)
+        .await
+        .map_err(|e| e.into_retryable(TunnelError::Transfer))?;
+    state.clear();
+
+    output::emit(output::Event::FilePushed {
+        port: target.port,
+        path: remote.to_string(),
+        bytes: size,
+    });
+    Ok((size, resumed))
+}
+
+/// Uploads the chunks of `local` in parallel, then joins them into `partial`.
+///
+/// Returns the bytes that earlier, interrupted runs had already uploaded.
+async fn send_chunks(
+    target: &Target,
+    local: &Path,
+    partial: &str,
+    size: u64,
+    streams: usize,
+    pending: bool,
+) -> Result<u64, TunnelError> {
+    let chunks = chunks(partial, size, streams);
+    let mut uploads = JoinSet::new();
+    for chunk in chunks.clone() {
+        let target = target.clone();
+        let local = local.to_path_buf();
+        uploads.spawn(async move {
+            let existing = remote_size(&target, &chunk.remote).await?;
+            let done = if pending && existing <= chunk.len {
+                existing
+            } else {
+                0
+            };
+            if done == 0 || done < chunk.len {
+                send_range(
+                    &target,
+                    &local,
+                    chunk.start + done,
+                    chunk.len - done,
+                    &chunk.remote,
+                    done > 0,
+                )
+                .await?;
+            }
+            Ok::<_, TunnelError>(done)
+        });
+    }
+
+    // Dropping the set on the first error kills the other uploads
+    let mut resumed = 0;
+    while let Some(result) = uploads.join_next().await {
+        resumed +=
+            result.map_err(|e| TunnelError::Transfer(format!("upload task failed: {}", e)))??;
+    }
+
+    let join = chunks.iter().fold(
+        RemoteCommand::new("sh")
+            .arg("-c")
+            .arg(JOIN_SCRIPT)
+            .arg("sh")
+            .arg(partial),
+        |command, chunk| command.arg(&chunk.remote),
+    );
+    ssh::run_through_tunnel(target, &join, JOIN_TIMEOUT)
+        .await
+        .map_err(|e| e.into_retryable(TunnelError::Transfer))?;
+    Ok(resumed)
+}
+
+/// Streams `len` bytes of `local` from `start` into `remote`, appending if `append`
+async fn send_range(
+    target: &Target,
+    local: &Path,
+    start: u64,
+    len: u64,
+    remote: &str,
+    append: bool,
+) -> Result<(), TunnelError> {
+    let failed = |message: String| TunnelError::Transfer(format!("{}: {}", remote, message));
+
+    let mut file = tokio::fs::File::open(local)
+        .await
+        .map_err(|e| failed(e.to_string()))?;
+    file.seek(SeekFrom::Start(start))
+        .await
+        .map_err(|e| failed(e.to_string()))?;
+
+    let script = if append { APPEND_SCRIPT } else { WRITE_SCRIPT };
+    let stream = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(script)
+        .arg("sh")
+        .arg(remote);
+    let mut child = ssh::through_tunnel(target, &stream)?
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| failed(e.to_string()))?;
+
+    // A failed copy means ssh went away; its exit status says why
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let copied = tokio::io::copy(&mut file.take(len), &mut stdin).await;
+    drop(stdin);
+
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| failed(e.to_string()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        return Err(if output.status.code() == Some(ssh::SSH_FAILURE) {
+            TunnelError::ConnectionLost(stderr)
+        } else {
+            failed(stderr)
+        });
+    }
+    match copied {
+        Ok(sent) if sent == len => Ok(()),
+        Ok(sent) => Err(failed(format!("{} changed size during the upload", sent))),
+        Err(e) => Err(TunnelError::ConnectionLost(e.to_string())),
+    }
+}
+
+/// Size of `path` on the device, 0 if it doesn't exist
+async fn remote_size(target: &Target, path: &str) -> Result<u64, TunnelError> {
+    let probe = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(SIZE_SCRIPT)
+        .arg("sh")
+        .arg(path);
+    let stdout = ssh::run_through_tunnel(target, &probe, COMMAND_TIMEOUT)
+        .await
+        .map_err(|e| e.into_retryable(TunnelError::Transfer))?;
+    stdout
+        .trim()
+        .parse()
+        .map_err(|_| TunnelError::Transfer(format!("unexpected size of {}: {:?}", path, stdout)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulate::{self, Device};
+
+    #[test]
+    fn test_chunks_cover_the_file_once() {
+        let parts = chunks("fw.bin.part", 10, 3);
+        assert_eq!(
+            parts
+                .iter()
+                .map(|c| (c.start, c.len, c.remote.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, 4, "fw.bin.part.0"),
+                (4, 4, "fw.bin.part.1"),
+                (8, 2, "fw.bin.part.2"),
+            ]
+        );
+
+        // Never more chunks than bytes, and an empty file still gets one
+        assert_eq!(chunks("p", 2, 8).len(), 2);
+        assert_eq!(
+            chunks("p", 0, 4),
+            vec![Chunk {
+                start: 0,
+                len: 0,
+                remote: "p.0".to_string()
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_push_uploads_to_a_simulated_device() {
+        let dir = std::env::temp_dir().join(format!("ssh_ip_tunnel-push-{}", std::process::id()));
+        let target = simulate::test_target(&dir);
+        let config = Config::default();
+        let contents = "firmware\n".repeat(100);
+        let local = dir.join("fw.bin");
+        std::fs::write(&local, &contents).unwrap();
+        let source = PushSource::File(local);
+
+        let report = push(&config, &target, &source, Some("fw.bin"), None, true, 1)
+            .await
+            .unwrap();
+        assert_eq!(report.bytes, contents.len() as u64);
+        assert_eq!(report.remote_sha256.as_ref(), Some(&report.sha256));
+        // In chunks, joined on the device
+        let report = push(
+            &config,
+            &target,
+            &source,
+            Some("/tmp/fw.bin"),
+            None,
+            false,
+            3,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.streams, 3);
+
+        let device: Device =
+            serde_json::from_str(&std::fs::read_to_string(dir.join("device.json")).unwrap())
+                .unwrap();
+        assert_eq!(
+            device.files,
+            [("/tmp/fw.bin", &contents), ("fw.bin", &contents)]
+                .map(|(path, contents)| (path.to_string(), contents.clone()))
+                .into()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
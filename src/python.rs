@@ -0,0 +1,290 @@
+//! Python bindings, with the `python` feature: the `ssh_ip_tunnel` module.
+//!
+//! `TunnelManager` loads the configuration and hands out `TargetBuilder`s,
+//! which mirror [`crate::TargetBuilder`]. Its tunnel methods come in pairs:
+//! `run_sync()` blocks with the GIL released, and `run()` is an awaitable that
+//! does the same in `asyncio.to_thread`, for asyncio code such as
+//! pytest-asyncio tests. The Rust side runs on a tokio runtime of its own
+//! whose threads never call into Python, so nothing is left holding the
+//! interpreter when it exits. Failures raise `TunnelError`, whose `args` are
+//! the message and the phase it happened in, or `None`.
+
+use crate::phase;
+use crate::{load_config, Config, SSHTunnelManager};
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+create_exception!(
+    ssh_ip_tunnel,
+    TunnelError,
+    PyException,
+    "A tunnel or provisioning step failed; args are (message, phase or None)"
+);
+
+/// Runs the futures of every `TunnelManager`; its threads never touch Python
+static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+
+fn to_py(error: anyhow::Error) -> PyErr {
+    let phase = phase::failed_phase(&error).map(|phase| phase.to_string());
+    TunnelError::new_err((error.to_string(), phase))
+}
+
+/// A device and how to reach it, from `TargetBuilder.build()`
+#[pyclass(module = "ssh_ip_tunnel", frozen)]
+#[derive(Clone)]
+struct Target {
+    inner: crate::Target,
+}
+
+#[pymethods]
+impl Target {
+    #[getter]
+    fn host(&self) -> &str {
+        &self.inner.host
+    }
+
+    #[getter]
+    fn user(&self) -> &str {
+        &self.inner.user
+    }
+
+    #[getter]
+    fn port(&self) -> u16 {
+        self.inner.port
+    }
+
+    #[getter]
+    fn key_path(&self) -> &str {
+        &self.inner.key_path
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Target({}@{}, port={})",
+            self.inner.user, self.inner.host, self.inner.port
+        )
+    }
+}
+
+/// Builds a `Target`; each setter returns the builder, so calls chain
+#[pyclass(module = "ssh_ip_tunnel")]
+struct TargetBuilder {
+    inner: crate::TargetBuilder,
+}
+
+impl TargetBuilder {
+    fn with(
+        mut slf: PyRefMut<'_, Self>,
+        set: impl FnOnce(crate::TargetBuilder) -> crate::TargetBuilder,
+    ) -> PyRefMut<'_, Self> {
+        slf.inner = set(slf.inner.clone());
+        slf
+    }
+}
+
+#[pymethods]
+impl TargetBuilder {
+    fn key_path(slf: PyRefMut<'_, Self>, path: String) -> PyRefMut<'_, Self> {
+        Self::with(slf, |builder| builder.key_path(path))
+    }
+
+    fn port(slf: PyRefMut<'_, Self>, port: u16) -> PyRefMut<'_, Self> {
+        Self::with(slf, |builder| builder.port(port))
+    }
+
+    fn remote_port(slf: PyRefMut<'_, Self>, port: u16) -> PyRefMut<'_, Self> {
+        Self::with(slf, |builder| builder.remote_port(port))
+    }
+
+    fn identity_file(slf: PyRefMut<'_, Self>, path: PathBuf) -> PyRefMut<'_, Self> {
+        Self::with(slf, |builder| builder.identity_file(path))
+    }
+
+    fn proxy_jump(slf: PyRefMut<'_, Self>, jump: String) -> PyRefMut<'_, Self> {
+        Self::with(slf, |builder| builder.proxy_jump(jump))
+    }
+
+    fn host_key_fingerprint(slf: PyRefMut<'_, Self>, fingerprint: String) -> PyRefMut<'_, Self> {
+        Self::with(slf, |builder| builder.host_key_fingerprint(fingerprint))
+    }
+
+    fn target_user(slf: PyRefMut<'_, Self>, user: String) -> PyRefMut<'_, Self> {
+        Self::with(slf, |builder| builder.target_user(user))
+    }
+
+    #[pyo3(signature = (skip = true))]
+    fn skip_key_transfer(slf: PyRefMut<'_, Self>, skip: bool) -> PyRefMut<'_, Self> {
+        Self::with(slf, |builder| builder.skip_key_transfer(skip))
+    }
+
+    #[pyo3(signature = (skip = true))]
+    fn skip_arch_validation(slf: PyRefMut<'_, Self>, skip: bool) -> PyRefMut<'_, Self> {
+        Self::with(slf, |builder| builder.skip_arch_validation(skip))
+    }
+
+    #[pyo3(signature = (secure = true))]
+    fn secure(slf: PyRefMut<'_, Self>, secure: bool) -> PyRefMut<'_, Self> {
+        Self::with(slf, |builder| builder.secure(secure))
+    }
+
+    #[pyo3(signature = (sync = true))]
+    fn sync_time(slf: PyRefMut<'_, Self>, sync: bool) -> PyRefMut<'_, Self> {
+        Self::with(slf, |builder| builder.sync_time(sync))
+    }
+
+    #[pyo3(signature = (harden = true))]
+    fn harden(slf: PyRefMut<'_, Self>, harden: bool) -> PyRefMut<'_, Self> {
+        Self::with(slf, |builder| builder.harden(harden))
+    }
+
+    /// Checks the settings; raises ValueError for an invalid host, user or fingerprint
+    fn build(&self) -> PyResult<Target> {
+        self.inner
+            .clone()
+            .build()
+            .map(|inner| Target { inner })
+            .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))
+    }
+}
+
+/// Opens tunnels to devices and provisions them, with the defaults from a
+/// configuration file
+#[pyclass(module = "ssh_ip_tunnel", frozen)]
+struct TunnelManager {
+    config: Config,
+}
+
+impl TunnelManager {
+    /// Runs `call` on the module's runtime with the GIL released
+    fn block_on<T, F>(
+        &self,
+        py: Python<'_>,
+        call: impl FnOnce(SSHTunnelManager) -> F + Send,
+    ) -> PyResult<T>
+    where
+        T: Send,
+        F: Future<Output = anyhow::Result<T>> + Send,
+    {
+        let runtime = RUNTIME.get_or_init(|| {
+            tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("cannot start a tokio runtime")
+        });
+        let manager = SSHTunnelManager::new(self.config.clone());
+        py.allow_threads(|| runtime.block_on(call(manager)))
+            .map_err(to_py)
+    }
+
+    /// An awaitable running the blocking `method` in a worker thread, so the
+    /// event loop stays free
+    fn in_thread<'py>(
+        slf: &Bound<'py, Self>,
+        method: &str,
+        target: &Bound<'py, Target>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        slf.py()
+            .import("asyncio")?
+            .call_method1("to_thread", (slf.getattr(method)?, target))
+    }
+}
+
+#[pymethods]
+impl TunnelManager {
+    /// Loads `config_path`, or the usual configuration file when it is None
+    #[new]
+    #[pyo3(signature = (config_path = None))]
+    fn new(config_path: Option<PathBuf>) -> PyResult<Self> {
+        let config = load_config(config_path).map_err(to_py)?;
+        Ok(Self { config })
+    }
+
+    /// Starts a target for `user@host` with the configuration's defaults
+    fn target(&self, host: String, user: String) -> TargetBuilder {
+        TargetBuilder {
+            inner: crate::Target::builder(host, user, &self.config),
+        }
+    }
+
+    /// Provisions the device like `up` and returns the run report as a dict
+    fn run_sync<'py>(&self, py: Python<'py>, target: &Target) -> PyResult<Bound<'py, PyAny>> {
+        let report = self.block_on(
+            py,
+            |manager| async move { manager.run(&target.inner).await },
+        )?;
+        let json = serde_json::to_string(&report).unwrap_or_default();
+        py.import("json")?.call_method1("loads", (json,))
+    }
+
+    /// Opens the tunnel and checks that the device answers through it
+    fn connect_sync(&self, py: Python<'_>, target: &Target) -> PyResult<()> {
+        self.block_on(py, |manager| async move {
+            manager.connect(&target.inner).await?;
+            Ok(())
+        })
+    }
+
+    /// Installs the target's key through an open tunnel, after checking the
+    /// architecture unless that is skipped; returns False when the key was
+    /// already there
+    fn transfer_key_sync(&self, py: Python<'_>, target: &Target) -> PyResult<bool> {
+        self.block_on(py, |manager| async move {
+            if !target.inner.skip_arch_validation {
+                manager.validate_architecture(&target.inner).await?;
+            }
+            Ok(manager.transfer_key(&target.inner).await?)
+        })
+    }
+
+    /// Closes the target's tunnel; returns False when none was open
+    fn close_sync(&self, py: Python<'_>, target: &Target) -> PyResult<bool> {
+        self.block_on(py, |manager| async move {
+            Ok(manager.close_tunnel(&target.inner).await?)
+        })
+    }
+
+    /// `run_sync` as an awaitable
+    fn run<'py>(
+        slf: &Bound<'py, Self>,
+        target: &Bound<'py, Target>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        Self::in_thread(slf, "run_sync", target)
+    }
+
+    /// `connect_sync` as an awaitable
+    fn connect<'py>(
+        slf: &Bound<'py, Self>,
+        target: &Bound<'py, Target>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        Self::in_thread(slf, "connect_sync", target)
+    }
+
+    /// `transfer_key_sync` as an awaitable
+    fn transfer_key<'py>(
+        slf: &Bound<'py, Self>,
+        target: &Bound<'py, Target>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        Self::in_thread(slf, "transfer_key_sync", target)
+    }
+
+    /// `close_sync` as an awaitable
+    fn close<'py>(
+        slf: &Bound<'py, Self>,
+        target: &Bound<'py, Target>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        Self::in_thread(slf, "close_sync", target)
+    }
+}
+
+#[pymodule]
+fn ssh_ip_tunnel(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<TunnelManager>()?;
+    m.add_class::<TargetBuilder>()?;
+    m.add_class::<Target>()?;
+    m.add("TunnelError", m.py().get_type::<TunnelError>())?;
+    Ok(())
+}
@@ -0,0 +1,163 @@
+//! `keys rotate`: replacing the key a device is logged in to with a new one.
+//!
+//! The steps run so that a key that works is authorized at every point: the
+//! new key is generated here, added next to the old one and tried in a fresh
+//! login, and only a login with the new key removes the old one. A new key
+//! the device doesn't accept is removed again, leaving the old one in place.
+
+use crate::authorized_keys;
+use crate::config::Config;
+use crate::keys;
+use crate::output::Renderable;
+use crate::paths;
+use crate::{SSHTunnelManager, Target};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Result of `keys rotate`
+#[derive(Debug, Clone, Serialize)]
+pub struct RotateReport {
+    pub host: String,
+    pub user: String,
+    pub old_fingerprint: String,
+    /// Public half of the new key pair
+    pub new_key: PathBuf,
+    pub new_fingerprint: String,
+    /// The old key was left authorized, with `--keep-old`
+    pub old_kept: bool,
+    /// Number of authorized_keys lines of the old key deleted
+    pub old_removed: usize,
+    /// Where authorized_keys was backed up before the old key was removed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup: Option<String>,
+}
+
+impl Renderable for RotateReport {
+    fn to_human(&self) -> String {
+        let mut text = format!(
+            "Rotated the key of {}@{}: {} -> {}\nThe new key is {}; use it from now on, e.g. as key_path in the host profile",
+            self.user,
+            self.host,
+            self.old_fingerprint,
+            self.new_fingerprint,
+            self.new_key.display()
+        );
+        if self.old_kept {
+            text.push_str(&format!(
+                "\nThe old key is still authorized; remove it with keys revoke --key-fingerprint {}",
+                self.old_fingerprint
+            ));
+        } else if self.old_removed == 0 {
+            text.push_str("\nThe old key was not in authorized_keys");
+        }
+        text
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Where the new key goes when none is given: next to `old`, named after it
+/// and `date`, e.g. `~/.ssh/id_ed25519-20240101.pub`
+pub fn default_new_key_path(old: &Path, date: chrono::NaiveDate) -> PathBuf {
+    let name = old.file_name().unwrap_or_default().to_string_lossy();
+    let stem = name.strip_suffix(".pub").unwrap_or(&name);
+    old.with_file_name(format!("{}-{}.pub", stem, date.format("%Y%m%d")))
+}
+
+/// Generates `new_key`, authorizes it on the device, checks that it logs in,
+/// then removes the target's key unless `keep_old`
+pub async fn rotate(
+    config: &Config,
+    target: &Target,
+    new_key: &Path,
+    comment: &str,
+    keep_old: bool,
+) -> Result<RotateReport> {
+    let old = keys::read_public_key(&paths::expand_tilde(&target.key_path)?)?;
+    if old.is_certificate() {
+        anyhow::bail!(
+            "{} is a certificate; rotate the key it certifies and have it signed again",
+            target.key_path
+        );
+    }
+
+    SSHTunnelManager::new(config.clone())
+        .connect(target)
+        .await?;
+
+    info!("Step 1/4: generating {}...", new_key.display());
+    let generated = keys::generate(new_key, comment)
+        .await
+        .context("Nothing was changed on the device")?;
+    let new = keys::read_public_key(new_key)?;
+
+    info!("Step 2/4: authorizing the new key on {}...", target.host);
+    let installed = match authorized_keys::install(target, &new, &target.key_options).await {
+        Ok(()) => authorized_keys::fix_permissions(target).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    installed.with_context(|| {
+        format!(
+            "The old key still works; the new key pair {} is unused",
+            generated.private_key.display()
+        )
+    })?;
+
+    info!("Step 3/4: logging in with the new key...");
+    let (login, options) = authorized_keys::key_login(target, new_key);
+    if let Err(e) = authorized_keys::verify_login(&login, &options).await {
+        warn!(
+            "{} does not accept the new key; removing it again",
+            target.host
+        );
+        let cleanup = authorized_keys::remove(target, &[], &[new.data.as_str()]).await;
+        let outcome = match cleanup {
+            Ok(_) => "it was removed again".to_string(),
+            Err(cleanup) => format!("removing it again failed too: {}", cleanup),
+        };
+        return Err(anyhow::Error::new(e).context(format!(
+            "Logging in with the new key failed; {} and the old key still works",
+            outcome
+        )));
+    }
+
+    let mut report = RotateReport {
+        host: target.host.clone(),
+        user: target.key_user().to_string(),
+        old_fingerprint: old.fingerprint(),
+        new_key: new_key.to_path_buf(),
+        new_fingerprint: new.fingerprint(),
+        old_kept: keep_old,
+        old_removed: 0,
+        backup: None,
+    };
+    if keep_old {
+        info!("Step 4/4: keeping the old key, as asked");
+        return Ok(report);
+    }
+
+    info!("Step 4/4: removing the old key, logged in with the new one...");
+    (report.old_removed, report.backup) =
+        authorized_keys::remove(&login, &options, &[old.data.as_str()])
+            .await
+            .context("The new key works, but the old key is still authorized")?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_new_key_path() {
+        let date = chrono::NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        assert_eq!(
+            default_new_key_path(Path::new("/home/pi/.ssh/id_ed25519.pub"), date),
+            PathBuf::from("/home/pi/.ssh/id_ed25519-20240101.pub")
+        );
+    }
+}
@@ -0,0 +1,292 @@
+//! `sync`: keeping a tree on a device in step with one here, with rsync through the tunnel.
+//!
+//! Paths are written as for `copy` (`remote:PATH` is on the device), and as
+//! with rsync a source ending in `/` stands for the directory's contents.
+//! rsync runs `ssh` on the tunnel's local port with the same options as every
+//! other connection, so only what changed is sent. Its `--stats` become the
+//! structured result.
+
+use crate::config::Config;
+use crate::copy::{self, CopyPlan, Direction};
+use crate::executor::Stdio;
+use crate::output::{self, Renderable};
+use crate::ssh;
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use serde::Serialize;
+use std::ffi::OsString;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// What rsync reported doing, from `--stats`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SyncStats {
+    /// Files and directories considered
+    pub files: u64,
+    pub files_transferred: u64,
+    pub files_created: u64,
+    pub files_deleted: u64,
+    /// Size of everything considered
+    pub total_size: u64,
+    /// Size of the files that were transferred
+    pub transferred_size: u64,
+    /// Bytes sent as is, and bytes the other side already had
+    pub literal_data: u64,
+    pub matched_data: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl SyncStats {
+    /// Reads the `Name: value` lines of rsync's `--stats`, ignoring the rest.
+    /// rsync before 3.1 says `Number of files transferred`.
+    pub fn parse(output: &str) -> Self {
+        let mut stats = SyncStats::default();
+        for line in output.lines() {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            // Thousands separators depend on the locale
+            let number = value
+                .split_whitespace()
+                .next()
+                .map(|n| n.chars().filter(char::is_ascii_digit).collect::<String>())
+                .and_then(|n| n.parse().ok())
+                .unwrap_or_default();
+            let field = match name.trim() {
+                "Number of files" => &mut stats.files,
+                "Number of regular files transferred" | "Number of files transferred" => {
+                    &mut stats.files_transferred
+                }
+                "Number of created files" => &mut stats.files_created,
+                "Number of deleted files" => &mut stats.files_deleted,
+                "Total file size" => &mut stats.total_size,
+                "Total transferred file size" => &mut stats.transferred_size,
+                "Literal data" => &mut stats.literal_data,
+                "Matched data" => &mut stats.matched_data,
+                "Total bytes sent" => &mut stats.bytes_sent,
+                "Total bytes received" => &mut stats.bytes_received,
+                _ => continue,
+            };
+            *field = number;
+        }
+        stats
+    }
+}
+
+/// Options for `sync` beyond the paths
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// Delete files on the receiving side that the sources don't have
+    pub delete: bool,
+    /// rsync patterns of files to leave out
+    pub excludes: Vec<String>,
+}
+
+/// Result of `sync`
+#[derive(Debug, Serialize)]
+pub struct SyncReport {
+    pub host: String,
+    pub direction: Direction,
+    pub sources: Vec<String>,
+    pub dest: String,
+    pub delete: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub excludes: Vec<String>,
+    pub stats: SyncStats,
+    #[serde(rename = "duration_ms", serialize_with = "copy::as_millis")]
+    pub duration: Duration,
+}
+
+impl Renderable for SyncReport {
+    fn to_human(&self) -> String {
+        let (from, to) = match self.direction {
+            Direction::Push => (
+                self.sources.join(", "),
+                format!("{}:{}", self.host, self.dest),
+            ),
+            Direction::Pull => {
+                let sources: Vec<String> = self
+                    .sources
+                    .iter()
+                    .map(|source| format!("{}:{}", self.host, source))
+                    .collect();
+                (sources.join(", "), self.dest.clone())
+            }
+        };
+        let stats = &self.stats;
+        let mut text = format!(
+            "Synced {} to {} in {}\n{} of {} files transferred ({} of {} bytes)",
+            from,
+            to,
+            output::format_duration(self.duration),
+            stats.files_transferred,
+            stats.files,
+            stats.transferred_size,
+            stats.total_size
+        );
+        if self.delete {
+            text.push_str(&format!(", {} deleted", stats.files_deleted));
+        }
+        text.push_str(&format!(
+            "\nSent {} bytes, received {} bytes ({} bytes matched)",
+            stats.bytes_sent, stats.bytes_received, stats.matched_data
+        ));
+        text
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// The arguments for rsync itself
+fn rsync_options(options: &SyncOptions) -> Vec<String> {
+    let mut args = vec!["--archive".to_string(), "--stats".to_string()];
+    if options.delete {
+        args.push("--delete".to_string());
+    }
+    args.extend(
+        options
+            .excludes
+            .iter()
+            .map(|pattern| format!("--exclude={}", pattern)),
+    );
+    args
+}
+
+/// Syncs as `plan` says between here and `target`
+pub async fn sync(
+    config: &Config,
+    target: &Target,
+    plan: &CopyPlan,
+    options: &SyncOptions,
+) -> Result<SyncReport> {
+    if plan.direction == Direction::Push {
+        if let Some(missing) = plan.sources.iter().find(|s| !Path::new(s).exists()) {
+            anyhow::bail!(TunnelError::Transfer(format!("{} does not exist", missing)));
+        }
+    }
+
+    SSHTunnelManager::new(config.clone())
+        .reuse_or_connect(target)
+        .await?;
+
+    let remote = |path: &str| OsString::from(format!("localhost:{}", path));
+    let (sources, dest): (Vec<OsString>, OsString) = match plan.direction {
+        Direction::Push => (
+            plan.sources.iter().map(OsString::from).collect(),
+            remote(&plan.dest),
+        ),
+        Direction::Pull => (
+            plan.sources.iter().map(|source| remote(source)).collect(),
+            OsString::from(&plan.dest),
+        ),
+    };
+
+    info!(
+        "Syncing {} {} {}...",
+        plan.sources.join(", "),
+        match plan.direction {
+            Direction::Push => "to",
+            Direction::Pull => "from",
+        },
+        target.host
+    );
+    let started = Instant::now();
+    let output = ssh::rsync_through_tunnel(target, &rsync_options(options), &sources, &dest)?
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| TunnelError::Transfer(e.to_string()))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    debug!("rsync: {}", stdout.trim());
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        let message =
+            if stderr.contains("rsync: command not found") || stderr.contains("rsync: not found") {
+                format!(
+                    "rsync is not installed on {}; install it there or use copy",
+                    target.host
+                )
+            } else {
+                stderr
+            };
+        return Err(TunnelError::Transfer(message).into());
+    }
+
+    Ok(SyncReport {
+        host: target.host.clone(),
+        direction: plan.direction,
+        sources: plan.sources.clone(),
+        dest: plan.dest.clone(),
+        delete: options.delete,
+        excludes: options.excludes.clone(),
+        stats: SyncStats::parse(&stdout),
+        duration: started.elapsed(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stats() {
+        let output = "\
+Number of files: 1,204 (reg: 1,100, dir: 104)
+Number of created files: 3 (reg: 3)
+Number of deleted files: 2 (reg: 2)
+Number of regular files transferred: 17
+Total file size: 48,211,337 bytes
+Total transferred file size: 1,032,448 bytes
+Literal data: 20,480 bytes
+Matched data: 1,011,968 bytes
+File list generation time: 0.001 seconds
+Total bytes sent: 31,522
+Total bytes received: 1,130
+
+sent 31,522 bytes  received 1,130 bytes  65,304.00 bytes/sec
+total size is 48,211,337  speedup is 1,476.58
+";
+        assert_eq!(
+            SyncStats::parse(output),
+            SyncStats {
+                files: 1204,
+                files_transferred: 17,
+                files_created: 3,
+                files_deleted: 2,
+                total_size: 48_211_337,
+                transferred_size: 1_032_448,
+                literal_data: 20_480,
+                matched_data: 1_011_968,
+                bytes_sent: 31_522,
+                bytes_received: 1_130,
+            }
+        );
+        assert_eq!(
+            SyncStats::parse("Number of files transferred: 4\n").files_transferred,
+            4
+        );
+    }
+
+    #[test]
+    fn test_rsync_options() {
+        let options = SyncOptions {
+            delete: true,
+            excludes: vec!["*.o".to_string(), "/tmp/".to_string()],
+        };
+        assert_eq!(
+            rsync_options(&options),
+            [
+                "--archive",
+                "--stats",
+                "--delete",
+                "--exclude=*.o",
+                "--exclude=/tmp/"
+            ]
+        );
+    }
+}
@@ -0,0 +1,68 @@
+//! Identification of a single invocation of the tool.
+
+use chrono::Utc;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Identifies one invocation, e.g. `20261016T082653Z-3f2a`.
+///
+/// Sorts chronologically, and the suffix keeps concurrent invocations apart.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RunId(String);
+
+impl RunId {
+    pub fn generate() -> Self {
+        let now = Utc::now();
+        let suffix = (std::process::id() ^ now.timestamp_subsec_nanos()) & 0xffff;
+        Self(format!("{}-{:04x}", now.format("%Y%m%dT%H%M%SZ"), suffix))
+    }
+
+    /// Reads a run ID given by the user, refusing anything that isn't one
+    pub fn parse(text: &str) -> Option<Self> {
+        let (time, suffix) = text.split_once('-')?;
+        let valid = time.len() == 16
+            && time.ends_with('Z')
+            && time
+                .chars()
+                .all(|c| c.is_ascii_digit() || c == 'T' || c == 'Z')
+            && suffix.len() == 4
+            && suffix.chars().all(|c| c.is_ascii_hexdigit());
+        valid.then(|| Self(text.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+static CURRENT: OnceLock<RunId> = OnceLock::new();
+
+/// This process's run ID, generated on first use
+pub fn current() -> &'static RunId {
+    CURRENT.get_or_init(RunId::generate)
+}
+
+impl fmt::Display for RunId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_accepts_generated_ids_only() {
+        let id = RunId::generate();
+        assert_eq!(RunId::parse(id.as_str()), Some(id));
+        for text in [
+            "",
+            "latest",
+            "../20261016T082653Z-3f2a",
+            "20261016T082653Z-3f2g",
+        ] {
+            assert_eq!(RunId::parse(text), None, "{}", text);
+        }
+    }
+}
@@ -0,0 +1,342 @@
+//! `service install`: a systemd unit that keeps a host profile's tunnel up.
+//!
+//! The unit runs `up --watch` for the profile in batch mode, as a
+//! `Type=notify` service: it is started once the tunnel answers, which it
+//! must within `TimeoutStartSec`. `up --watch` then keeps checking it,
+//! feeding the watchdog, and opens it again when a check fails. When no check
+//! passes within the watchdog period, systemd stops what is left of the
+//! tunnel and `Restart=on-failure` runs `up` again until the device is back.
+//! `ExecStop` closes the tunnel with `down`.
+
+use crate::interpolate;
+use crate::output::Renderable;
+use crate::paths;
+use crate::process;
+use crate::TunnelError;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Where system units go
+const SYSTEM_UNIT_DIR: &str = "/etc/systemd/system";
+
+/// Where systemd keeps the users whose units start at boot, without a login
+const LINGER_DIR: &str = "/var/lib/systemd/linger";
+
+/// Seconds between attempts while the device is unreachable
+const RESTART_SECS: u32 = 10;
+
+/// Seconds the tunnel may go unchecked; `up --watch` checks twice as often
+const WATCHDOG_SECS: u32 = 90;
+
+/// Seconds `up` may take beyond the tunnel timeout to report the tunnel
+/// ready: the hooks before it, and its check
+const START_SLACK_SECS: u64 = 60;
+
+/// Which systemd instance runs the unit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// The user's own, `systemctl --user`
+    User,
+    /// The system's, running the unit as the installing user
+    System,
+}
+
+impl Scope {
+    fn systemctl_args(self) -> &'static [&'static str] {
+        match self {
+            Scope::User => &["--user"],
+            Scope::System => &[],
+        }
+    }
+}
+
+/// What the unit runs
+#[derive(Debug, Clone)]
+pub struct Unit {
+    /// This program
+    pub program: PathBuf,
+    pub profile: String,
+    /// The configuration file the profile is in
+    pub config: Option<PathBuf>,
+    pub namespace: Option<String>,
+    /// Account a system unit runs as; user units run as their user
+    pub run_as: Option<String>,
+    pub scope: Scope,
+    /// The profile's `tunnel_timeout_secs`, which bounds how long starting takes
+    pub tunnel_timeout_secs: u64,
+}
+
+impl Unit {
+    /// `ssh-ip-tunnel-<profile>.service`, with the namespace before the profile
+    /// if there is one. Fails for names systemd doesn't take.
+    pub fn name(&self) -> Result<String, TunnelError> {
+        if let Some(c) = self
+            .profile
+            .chars()
+            .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')))
+        {
+            return Err(TunnelError::Service(format!(
+                "host profile '{}' contains {:?}, which a unit name can't; rename the profile",
+                self.profile, c
+            )));
+        }
+        Ok(match &self.namespace {
+            Some(namespace) => format!("ssh-ip-tunnel-{}-{}.service", namespace, self.profile),
+            None => format!("ssh-ip-tunnel-{}.service", self.profile),
+        })
+    }
+
+    /// The command line for `command`, e.g. `down`, quoted for systemd
+    fn command_line(&self, command: &[&str]) -> String {
+        let mut args = vec![self.program.display().to_string(), "--batch".to_string()];
+        if let Some(config) = &self.config {
+            args.extend(["--config".to_string(), config.display().to_string()]);
+        }
+        if let Some(namespace) = &self.namespace {
+            args.extend(["--namespace".to_string(), namespace.clone()]);
+        }
+        args.extend(command.iter().map(|arg| arg.to_string()));
+        args.push(self.profile.clone());
+        args.iter()
+            .map(|arg| quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The unit file's contents
+    pub fn render(&self) -> String {
+        let mut service = vec![
+            "Type=notify".to_string(),
+            format!("ExecStart={}", self.command_line(&["up", "--watch"])),
+            format!("ExecStop={}", self.command_line(&["down"])),
+            format!(
+                "TimeoutStartSec={}",
+                self.tunnel_timeout_secs + START_SLACK_SECS
+            ),
+            format!("WatchdogSec={}", WATCHDOG_SECS),
+            "Restart=on-failure".to_string(),
+            format!("RestartSec={}", RESTART_SECS),
+        ];
+        if let Some(user) = self.run_as.as_ref().filter(|_| self.scope == Scope::System) {
+            service.push(format!("User={}", user));
+        }
+        let wanted_by = match self.scope {
+            Scope::User => "default.target",
+            Scope::System => "multi-user.target",
+        };
+        format!(
+            "# Written by `ssh_ip_tunnel service install`\n\
+             [Unit]\n\
+             Description=SSH tunnel to host profile {}\n\
+             Wants=network-online.target\n\
+             After=network-online.target\n\
+             # Keep retrying however long the device is away\n\
+             StartLimitIntervalSec=0\n\
+             \n\
+             [Service]\n\
+             {}\n\
+             \n\
+             [Install]\n\
+             WantedBy={}\n",
+            self.profile,
+            service.join("\n"),
+            wanted_by
+        )
+    }
+}
+
+/// `arg` as one word of a systemd command line, where `%` starts a specifier
+/// and `$` a variable
+fn quote(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty()
+        && !escaped
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';'))
+    {
+        return escaped;
+    }
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The account a system unit should run as: the one that ran `sudo`, or the current one
+pub fn invoking_user() -> Option<String> {
+    std::env::var("SUDO_USER")
+        .ok()
+        .filter(|user| !user.is_empty())
+        .or_else(|| interpolate::process_env("USER"))
+}
+
+/// Directory for units of `scope`
+fn unit_dir(scope: Scope) -> Result<PathBuf, TunnelError> {
+    match scope {
+        Scope::System => Ok(PathBuf::from(SYSTEM_UNIT_DIR)),
+        Scope::User => dirs::config_dir()
+            .map(|dir| dir.join("systemd").join("user"))
+            .or_else(|| {
+                paths::home_dir()
+                    .ok()
+                    .map(|home| home.join(".config/systemd/user"))
+            })
+            .ok_or(TunnelError::NoHomeDirectory),
+    }
+}
+
+/// Result of `service install`
+#[derive(Debug, Clone, Serialize)]
+pub struct InstallReport {
+    pub unit: String,
+    pub path: PathBuf,
+    pub scope: Scope,
+    /// Whether a user unit will start at boot; it needs lingering for that
+    pub starts_at_boot: bool,
+}
+
+impl Renderable for InstallReport {
+    fn to_human(&self) -> String {
+        let user = match self.scope {
+            Scope::User => "--user ",
+            Scope::System => "",
+        };
+        let mut text = format!(
+            "Installed {} at {} and started it\nFollow it with: journalctl {}-u {}",
+            self.unit,
+            self.path.display(),
+            user,
+            self.unit
+        );
+        if !self.starts_at_boot {
+            text.push_str(
+                "\nUser units start at login; run `loginctl enable-linger` to start it at boot",
+            );
+        }
+        text
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Writes `unit`, then enables and starts it. An existing unit of the same
+/// name is only replaced with `force`.
+pub async fn install(unit: &Unit, force: bool) -> Result<InstallReport, TunnelError> {
+    let name = unit.name()?;
+    let dir = unit_dir(unit.scope)?;
+    let path = dir.join(&name);
+    if path.exists() && !force {
+        return Err(TunnelError::Service(format!(
+            "{} already exists (use --force to replace it)",
+            path.display()
+        )));
+    }
+    let write = || -> std::io::Result<()> {
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(&path, unit.render())
+    };
+    write().map_err(|e| TunnelError::Service(format!("writing {}: {}", path.display(), e)))?;
+    info!("Wrote {}", path.display());
+
+    systemctl(unit.scope, &["daemon-reload"]).await?;
+    systemctl(unit.scope, &["enable", "--now", &name]).await?;
+    Ok(InstallReport {
+        unit: name,
+        path,
+        scope: unit.scope,
+        starts_at_boot: unit.scope == Scope::System || lingers(),
+    })
+}
+
+/// Whether the current user's units start at boot
+fn lingers() -> bool {
+    interpolate::process_env("USER").is_some_and(|user| Path::new(LINGER_DIR).join(user).exists())
+}
+
+async fn systemctl(scope: Scope, args: &[&str]) -> Result<(), TunnelError> {
+    let output = process::command("systemctl")?
+        .args(scope.systemctl_args())
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| TunnelError::Service(format!("running systemctl: {}", e)))?;
+    if !output.status.success() {
+        return Err(TunnelError::Service(format!(
+            "systemctl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_runs_up_and_down_for_the_profile() {
+        let mut unit = Unit {
+            program: PathBuf::from("/opt/my tools/ssh_ip_tunnel"),
+            profile: "rpi4-lab".to_string(),
+            config: Some(PathBuf::from("/home/pi/100%.toml")),
+            namespace: None,
+            run_as: Some("pi".to_string()),
+            scope: Scope::User,
+            tunnel_timeout_secs: 30,
+        };
+        assert_eq!(unit.name().unwrap(), "ssh-ip-tunnel-rpi4-lab.service");
+        let rendered = unit.render();
+        assert!(rendered.contains(
+            "\nExecStart=\"/opt/my tools/ssh_ip_tunnel\" --batch --config /home/pi/100%%.toml up --watch rpi4-lab\n"
+        ));
+        assert!(rendered.contains("\nExecStop=\"/opt/my tools/ssh_ip_tunnel\" --batch"));
+        assert!(rendered.contains("\nType=notify\n"));
+        assert!(rendered.contains("\nTimeoutStartSec=90\n"));
+        assert!(rendered.contains("\nRestart=on-failure\n"));
+        assert!(rendered.contains("\nWantedBy=default.target\n"));
+        assert!(!rendered.contains("User="));
+
+        unit.scope = Scope::System;
+        unit.namespace = Some("team-a".to_string());
+        assert_eq!(
+            unit.name().unwrap(),
+            "ssh-ip-tunnel-team-a-rpi4-lab.service"
+        );
+        let rendered = unit.render();
+        assert!(rendered.contains("--namespace team-a down rpi4-lab\n"));
+        assert!(rendered.contains("\nUser=pi\n"));
+        assert!(rendered.contains("\nWantedBy=multi-user.target\n"));
+
+        unit.profile = "lab/pi".to_string();
+        assert!(unit.name().is_err());
+    }
+
+    #[test]
+    fn test_start_and_stop_quote_spaces_for_the_same_tunnel() {
+        let unit = Unit {
+            program: PathBuf::from("/usr/bin/ssh_ip_tunnel"),
+            profile: "rpi4-lab".to_string(),
+            config: Some(PathBuf::from("/home/pi/lab configs/$HOME \"a\".toml")),
+            namespace: Some("team a".to_string()),
+            run_as: None,
+            scope: Scope::User,
+            tunnel_timeout_secs: 30,
+        };
+        let rendered = unit.render();
+        let line = |key: &str| {
+            rendered
+                .lines()
+                .find_map(|line| line.strip_prefix(key))
+                .unwrap()
+                .to_string()
+        };
+        let options = r#"/usr/bin/ssh_ip_tunnel --batch --config "/home/pi/lab configs/$$HOME \"a\".toml" --namespace "team a""#;
+        assert_eq!(
+            line("ExecStart="),
+            format!("{} up --watch rpi4-lab", options)
+        );
+        assert_eq!(line("ExecStop="), format!("{} down rpi4-lab", options));
+    }
+}
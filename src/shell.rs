@@ -0,0 +1,114 @@
+//! POSIX shell quoting and remote command construction.
+//!
+//! `ssh` joins its command arguments into a single string that the remote login
+//! shell re-parses, so anything that is not a trusted literal must be quoted
+//! before it leaves this process. Build remote commands with [`RemoteCommand`]
+//! rather than `format!`.
+
+use std::borrow::Cow;
+use std::fmt;
+
+/// Quotes a single word for a POSIX shell.
+///
+/// Words made only of unambiguous characters are returned unchanged; anything
+/// else is wrapped in single quotes, with embedded single quotes spliced in as
+/// `'\''`.
+pub fn quote(word: &str) -> Cow<'_, str> {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c);
+
+    if !word.is_empty() && word.chars().all(is_safe) {
+        Cow::Borrowed(word)
+    } else {
+        Cow::Owned(format!("'{}'", word.replace('\'', r"'\''")))
+    }
+}
+
+/// Defines `as_root`, which runs its arguments as root: directly when already
+/// root, otherwise through `sudo -n`, so a missing sudo rule fails instead of
+/// waiting for a password nobody can type
+pub const AS_ROOT: &str =
+    r#"if [ "$(id -u)" -eq 0 ]; then as_root() { "$@"; }; else as_root() { sudo -n "$@"; }; fi"#;
+
+/// A remote command line assembled from individually quoted arguments
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RemoteCommand {
+    words: Vec<String>,
+}
+
+impl RemoteCommand {
+    /// Starts a command running `program`
+    pub fn new(program: &str) -> Self {
+        Self::default().arg(program)
+    }
+
+    /// Appends a quoted argument
+    pub fn arg(mut self, arg: impl AsRef<str>) -> Self {
+        self.words.push(quote(arg.as_ref()).into_owned());
+        self
+    }
+
+    /// Renders the command as a single string for the remote shell
+    pub fn to_shell_string(&self) -> String {
+        self.words.join(" ")
+    }
+}
+
+impl fmt::Display for RemoteCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_shell_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOSTILE: &[&str] = &[
+        "plain",
+        "",
+        "with space",
+        "it's",
+        "'",
+        "$(touch /tmp/pwned)",
+        "`id`",
+        "; reboot",
+        "a && b || c",
+        "$HOME",
+        "line\nbreak",
+        "-oProxyCommand=sh",
+        "back\\slash",
+        "*",
+        "~root",
+        "unicodé ✓",
+    ];
+
+    #[test]
+    fn test_quote_leaves_safe_words_unquoted() {
+        assert_eq!(quote("uname"), "uname");
+        assert_eq!(
+            quote("/home/pi/.ssh/authorized_keys"),
+            "/home/pi/.ssh/authorized_keys"
+        );
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hostile_arguments_survive_a_real_shell() {
+        for word in HOSTILE {
+            let cmd = RemoteCommand::new("printf").arg("%s").arg(word);
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(cmd.to_shell_string())
+                .output()
+                .expect("sh should be available");
+            assert!(output.status.success(), "command failed for {:?}", word);
+            assert_eq!(
+                String::from_utf8(output.stdout).unwrap(),
+                *word,
+                "argument was altered by the shell"
+            );
+        }
+    }
+}
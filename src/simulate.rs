@@ -0,0 +1,1023 @@
+//! `--simulate`: fake devices in place of `ssh`, for trying out profiles and
+//! group runs without hardware.
+//!
+//! A [`crate::Target`] with a `simulated` state file has every `ssh` the tool
+//! would start for it answered by the fake device instead, in this process
+//! (see [`crate::executor`]). It recognises the tool's own remote scripts and
+//! answers them from that file ([`state_path`] keeps one per host in the state
+//! directory), so a key transferred in one run is already there in the next. Editing or deleting that file changes or
+//! resets the device. Remote commands it doesn't know fail with a message
+//! saying so; nothing goes over the network.
+
+use crate::authorized_keys;
+use crate::bench;
+use crate::checksum;
+use crate::clock;
+use crate::device_profile;
+use crate::facts::{self, Facts};
+use crate::harden;
+use crate::hardware;
+use crate::info;
+use crate::keys::{self, PublicKey};
+use crate::paths;
+use crate::ping;
+use crate::provision;
+use crate::push;
+use crate::shell;
+use crate::snapshot;
+use crate::sshd;
+use crate::swap;
+use crate::tunnel;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Held from loading a fake device to saving it again
+static TURN: Mutex<()> = Mutex::new(());
+
+/// The board config.txt lines go to on the fake device
+const BOOT_CONFIG: &str = "/boot/firmware/config.txt";
+
+/// Where the fake device for `host` keeps its state
+pub fn state_path(host: &str) -> PathBuf {
+    paths::state_dir()
+        .join("simulated")
+        .join(format!("{}.json", host.replace([':', '/', '\\'], "_")))
+}
+
+/// Whether the `ssh` command line `args` holds forwards open until it is
+/// stopped, which [`respond`] doesn't do
+pub fn holds_open(args: &[String]) -> bool {
+    args.iter().any(|arg| arg == "-N")
+}
+
+/// Answers the `ssh` command line `args` as the fake device kept in `path`,
+/// returning the exit code `ssh` would
+pub fn respond(
+    path: &Path,
+    args: &[String],
+    stdin: &mut dyn Read,
+    stdout: &mut dyn Write,
+    stderr: &mut dyn Write,
+) -> i32 {
+    // The tunnel itself: `ssh -fN` returns once it is up
+    if args.iter().any(|arg| arg == "-fN") {
+        return 0;
+    }
+    if args.first().is_some_and(|arg| arg == "-t") {
+        let _ = writeln!(
+            stderr,
+            "interactive shells are not simulated; use exec with --simulate"
+        );
+        return 255;
+    }
+    if args.first().is_some_and(|arg| arg == "--archive") {
+        let _ = writeln!(
+            stderr,
+            "rsync transfers are not simulated; leave out --simulate to sync"
+        );
+        return 12;
+    }
+    if args.iter().any(|arg| arg == "PubkeyAuthentication=no") {
+        let _ = writeln!(
+            stderr,
+            "host keys are not simulated; leave out --fingerprint with --simulate"
+        );
+        return 255;
+    }
+
+    let words = split_words(args.last().map(String::as_str).unwrap_or_default());
+    if is_script(&words, sshd::GUARD_SCRIPT) {
+        return guard(path, stdin, stdout, stderr);
+    }
+    if is_script(&words, ping::ECHO_SCRIPT) {
+        return echo(stdin, stdout);
+    }
+    if is_script(&words, bench::UPLOAD_SCRIPT) {
+        return sink(stdin, stdout);
+    }
+    if is_script(&words, bench::DOWNLOAD_SCRIPT) {
+        let blocks = words.get(4).and_then(|blocks| blocks.parse().ok());
+        return zeros(blocks.unwrap_or(0), stdout);
+    }
+    // Parallel commands, like the chunks of a push, would lose each other's changes
+    let _turn = TURN.lock().unwrap_or_else(|e| e.into_inner());
+    let mut device = load(path);
+    let stdin = RefCell::new(stdin);
+    let read_stdin = || {
+        let mut input = String::new();
+        let _ = stdin.borrow_mut().read_to_string(&mut input);
+        input
+    };
+    // Logging in as one of the accounts the tool created uses its keys
+    let login = args
+        .windows(2)
+        .find(|pair| pair[0] == "-l")
+        .map(|pair| pair[1].clone())
+        .unwrap_or_default();
+    if device.refuses(&login) {
+        let _ = writeln!(
+            stderr,
+            "{}@localhost: Permission denied (publickey).",
+            login
+        );
+        return 255;
+    }
+    // A login restricted to one key needs that key to be authorized
+    if let Some(key) = single_key(args) {
+        let authorized = device
+            .as_user(&login, |device| device.authorized_keys.clone())
+            .unwrap_or_else(|| device.authorized_keys.clone());
+        if !authorized.lines().any(|line| has_field(line, &key.data)) {
+            let _ = writeln!(
+                stderr,
+                "{}@localhost: Permission denied (publickey).",
+                login
+            );
+            return 255;
+        }
+    }
+    let response = device
+        .as_user(&login, |device| device.respond(&words, &read_stdin))
+        .unwrap_or_else(|| device.respond(&words, &read_stdin));
+
+    if let Err(code) = save(path, &device, stderr) {
+        return code;
+    }
+    let _ = stdout.write_all(response.stdout.as_bytes());
+    let _ = stderr.write_all(response.stderr.as_bytes());
+    response.code
+}
+
+/// The device kept in `path`, or a new one
+fn load(path: &Path) -> Device {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// Keeps `device` in `path`, or returns the exit code for failing to
+fn save(path: &Path, device: &Device, stderr: &mut dyn Write) -> Result<(), i32> {
+    let saved = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| {
+            std::fs::write(
+                path,
+                serde_json::to_string_pretty(device).unwrap_or_default(),
+            )
+        });
+    saved.map_err(|e| {
+        let _ = writeln!(
+            stderr,
+            "cannot save the simulated device in {}: {}",
+            path.display(),
+            e
+        );
+        255
+    })
+}
+
+/// Whether `words` run `script` with `sh -c`, as [`Device::respond`] would
+fn is_script(words: &[String], script: &str) -> bool {
+    match words {
+        [sh, flag, body, ..] if sh == "sh" && flag == "-c" => script_body(body) == script,
+        _ => false,
+    }
+}
+
+/// `script` without the shell functions defined ahead of it
+fn script_body(script: &str) -> &str {
+    [
+        shell::AS_ROOT,
+        authorized_keys::BACK_UP,
+        checksum::SHA256_FN,
+    ]
+    .iter()
+    .fold(script, |script, functions| {
+        script
+            .strip_prefix(functions)
+            .map_or(script, |rest| rest.trim_start_matches('\n'))
+    })
+}
+
+/// Plays [`sshd::GUARD_SCRIPT`]: the sshd settings are remembered, and put
+/// back unless `keep` arrives. The device is read again before that, since
+/// the change was made by other commands in the meantime.
+fn guard(path: &Path, stdin: &mut dyn Read, stdout: &mut dyn Write, stderr: &mut dyn Write) -> i32 {
+    let before = load(path);
+    let _ = writeln!(stdout, "ready");
+    let _ = stdout.flush();
+    let mut decision = String::new();
+    let _ = BufReader::new(stdin).read_line(&mut decision);
+    if decision.trim() == "keep" {
+        let _ = writeln!(stdout, "kept");
+        return 0;
+    }
+    let mut device = load(path);
+    device.sshd_drop_in = before.sshd_drop_in;
+    device.hardened = before.hardened;
+    if let Err(code) = save(path, &device, stderr) {
+        return code;
+    }
+    let _ = writeln!(stdout, "restored");
+    0
+}
+
+/// Plays [`ping::ECHO_SCRIPT`], echoing lines as they arrive
+fn echo(stdin: &mut dyn Read, stdout: &mut dyn Write) -> i32 {
+    let _ = writeln!(stdout, "ready");
+    let _ = stdout.flush();
+    let mut stdin = BufReader::new(stdin);
+    let mut line = String::new();
+    while stdin.read_line(&mut line).is_ok_and(|read| read > 0) {
+        if stdout
+            .write_all(line.as_bytes())
+            .and_then(|_| stdout.flush())
+            .is_err()
+        {
+            return 1;
+        }
+        line.clear();
+    }
+    0
+}
+
+/// Plays [`bench::UPLOAD_SCRIPT`], counting what arrives
+fn sink(stdin: &mut dyn Read, stdout: &mut dyn Write) -> i32 {
+    let _ = writeln!(stdout, "ready");
+    let _ = stdout.flush();
+    let received = std::io::copy(stdin, &mut std::io::sink()).unwrap_or(0);
+    let _ = writeln!(stdout, "{}", received);
+    0
+}
+
+/// Plays [`bench::DOWNLOAD_SCRIPT`], sending `blocks` blocks of 64 KiB of zeros
+fn zeros(blocks: u64, stdout: &mut dyn Write) -> i32 {
+    let _ = writeln!(stdout, "ready");
+    let block = [0u8; 65536];
+    for _ in 0..blocks {
+        if stdout.write_all(&block).is_err() {
+            return 1;
+        }
+    }
+    let _ = stdout.flush();
+    0
+}
+
+/// The key an `ssh` command line offers when it is limited to one
+/// (`IdentitiesOnly=yes`), read from the `.pub` of its `IdentityFile`
+fn single_key(args: &[String]) -> Option<PublicKey> {
+    if !args.iter().any(|arg| arg == "IdentitiesOnly=yes") {
+        return None;
+    }
+    let file = args
+        .iter()
+        .find_map(|arg| arg.strip_prefix("IdentityFile="))?
+        .trim_matches('"');
+    let public = if file.ends_with(".pub") {
+        PathBuf::from(file)
+    } else {
+        PathBuf::from(format!("{}.pub", file))
+    };
+    keys::read_public_key(&public).ok()
+}
+
+/// What a fake device has, as far as the tool's scripts can tell
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Device {
+    /// Architecture, model, memory, OS and hostname
+    pub facts: Facts,
+    pub swap_mb: u64,
+    /// The login user's authorized_keys
+    pub authorized_keys: String,
+    /// Its backups by file name, e.g. `authorized_keys.bak-20240101T120000Z`
+    pub authorized_keys_backups: BTreeMap<String, String>,
+    /// Lines added to config.txt
+    pub boot_config: Vec<String>,
+    /// Modules loaded at boot
+    pub modules: Vec<String>,
+    /// Accounts beyond the login user
+    pub users: BTreeMap<String, User>,
+    /// Contents of the tool's sshd drop-in
+    pub sshd_drop_in: String,
+    pub packages: BTreeSet<String>,
+    /// Wi-Fi networks by SSID
+    pub wifi: BTreeSet<String>,
+    /// sshd refuses password and root logins
+    pub hardened: bool,
+    /// Files written by `push`, by the path it gave
+    pub files: BTreeMap<String, String>,
+}
+
+impl Default for Device {
+    fn default() -> Self {
+        Self {
+            facts: Facts::example(),
+            swap_mb: 0,
+            authorized_keys: String::new(),
+            authorized_keys_backups: BTreeMap::new(),
+            boot_config: Vec::new(),
+            modules: Vec::new(),
+            users: BTreeMap::new(),
+            sshd_drop_in: String::new(),
+            packages: BTreeSet::new(),
+            wifi: BTreeSet::new(),
+            hardened: false,
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct User {
+    pub groups: Vec<String>,
+    pub authorized_keys: Vec<String>,
+    pub authorized_keys_backups: BTreeMap<String, String>,
+}
+
+/// Exit code and output of a simulated remote command
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Response {
+    fn ok(lines: Vec<String>) -> Self {
+        let mut stdout = lines.join("\n");
+        if !stdout.is_empty() {
+            stdout.push('\n');
+        }
+        Self {
+            code: 0,
+            stdout,
+            stderr: String::new(),
+        }
+    }
+
+    fn unsupported(command: &str) -> Self {
+        let first = command.lines().next().unwrap_or_default();
+        let mut summary: String = first.chars().take(60).collect();
+        if summary.len() < first.len() {
+            summary.push_str("...");
+        }
+        Self {
+            code: 127,
+            stdout: String::new(),
+            stderr: format!("the simulated device can't run {:?}\n", summary),
+        }
+    }
+}
+
+impl Device {
+    /// Answers the remote command `words` (as the remote shell would split
+    /// it); `stdin` is only read by commands that take input
+    pub fn respond(&mut self, words: &[String], stdin: &dyn Fn() -> String) -> Response {
+        let Some((program, args)) = words.split_first() else {
+            return Response::ok(Vec::new());
+        };
+        match (program.as_str(), args) {
+            ("true", []) => Response::ok(Vec::new()),
+            ("false", []) => Response {
+                code: 1,
+                ..Response::ok(Vec::new())
+            },
+            ("exit", [code]) => Response {
+                code: code.parse().unwrap_or(2),
+                ..Response::ok(Vec::new())
+            },
+            ("echo", _) => Response::ok(vec![args.join(" ")]),
+            ("uname", [flag]) if flag == "-m" => Response::ok(vec![self.facts.arch.clone()]),
+            ("uname", [flag]) if flag == "-r" => Response::ok(vec![self.facts.kernel.clone()]),
+            ("hostname", []) => Response::ok(vec![self.facts.hostname.clone()]),
+            ("rm", [flag, paths @ ..]) if flag == "-f" => {
+                for path in paths {
+                    self.files.remove(path);
+                }
+                Response::ok(Vec::new())
+            }
+            // `sh -c <script> <$0> <$1>...`
+            ("sh", [flag, script, rest @ ..]) if flag == "-c" => self.run_script(
+                script_body(script),
+                rest.get(1..).unwrap_or_default(),
+                stdin,
+            ),
+            _ => Response::unsupported(&words.join(" ")),
+        }
+    }
+
+    /// Whether the sshd drop-in's `AllowUsers` or `DenyUsers` keep `login` out
+    fn refuses(&self, login: &str) -> bool {
+        self.sshd_drop_in.lines().any(|line| {
+            let mut words = line.split_whitespace();
+            let keyword = words.next().unwrap_or_default().to_ascii_lowercase();
+            let mut users = words;
+            match keyword.as_str() {
+                "allowusers" => !users.any(|user| user == login),
+                "denyusers" => users.any(|user| user == login),
+                _ => false,
+            }
+        })
+    }
+
+    /// Runs `f` with the keys of the account `name` in place of the login
+    /// user's, or returns None when the device has no such account
+    fn as_user<T>(&mut self, name: &str, f: impl FnOnce(&mut Self) -> T) -> Option<T> {
+        let user = self.users.get_mut(name)?;
+        let mut keys: String = user
+            .authorized_keys
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let mut backups = std::mem::take(&mut user.authorized_keys_backups);
+        std::mem::swap(&mut keys, &mut self.authorized_keys);
+        std::mem::swap(&mut backups, &mut self.authorized_keys_backups);
+        let result = f(self);
+        std::mem::swap(&mut keys, &mut self.authorized_keys);
+        std::mem::swap(&mut backups, &mut self.authorized_keys_backups);
+        let user = self.users.get_mut(name)?;
+        user.authorized_keys = keys.lines().map(str::to_string).collect();
+        user.authorized_keys_backups = backups;
+        Some(result)
+    }
+
+    /// Backs up authorized_keys like the scripts do, keeping the newest ten,
+    /// and returns the line they print about it
+    fn back_up_authorized_keys(&mut self) -> Option<String> {
+        if self.authorized_keys.is_empty() {
+            return None;
+        }
+        let stamp = format!(
+            "authorized_keys.bak-{}",
+            chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+        );
+        let name = std::iter::once(stamp.clone())
+            .chain((1..).map(|n| format!("{}.{}", stamp, n)))
+            .find(|name| !self.authorized_keys_backups.contains_key(name))
+            .unwrap_or(stamp);
+        self.authorized_keys_backups
+            .insert(name.clone(), self.authorized_keys.clone());
+        while self.authorized_keys_backups.len() > 10 {
+            self.authorized_keys_backups.pop_first();
+        }
+        Some(format!("backup ~/.ssh/{}", name))
+    }
+
+    /// Answers one of the tool's own scripts, run with `args` as `$1...`
+    fn run_script(
+        &mut self,
+        script: &str,
+        args: &[String],
+        stdin: &dyn Fn() -> String,
+    ) -> Response {
+        let arg = |i: usize| args.get(i).map(String::as_str).unwrap_or_default();
+        let apply = arg(0) == "apply";
+        let lines = match script {
+            authorized_keys::READ_SCRIPT => {
+                return Response {
+                    stdout: self.authorized_keys.clone(),
+                    ..Default::default()
+                }
+            }
+            // The fake device's files always have the modes sshd wants
+            authorized_keys::PERMISSIONS_SCRIPT => Vec::new(),
+            authorized_keys::CREATE_USER_SCRIPT => {
+                if self.users.contains_key(arg(0)) {
+                    return Response::ok(Vec::new());
+                }
+                self.users.insert(arg(0).to_string(), User::default());
+                vec!["created".to_string()]
+            }
+            // Runs the script on the user's files in place of the login user's
+            authorized_keys::AS_USER_SCRIPT => {
+                let mut words: Vec<String> = ["sh", "-c", arg(1), "sh"].map(String::from).into();
+                words.extend(args.iter().skip(2).cloned());
+                return self
+                    .as_user(arg(0), |device| device.respond(&words, stdin))
+                    .unwrap_or_else(|| Response {
+                        code: 1,
+                        stderr: format!("sudo: unknown user {}\n", arg(0)),
+                        ..Default::default()
+                    });
+            }
+            authorized_keys::INSTALL_SCRIPT => {
+                let mut lines: Vec<String> = self
+                    .authorized_keys
+                    .lines()
+                    .filter(|line| !has_field(line, arg(1)))
+                    .map(str::to_string)
+                    .collect();
+                lines.push(arg(0).to_string());
+                let backup = self.back_up_authorized_keys();
+                self.authorized_keys = format!("{}\n", lines.join("\n"));
+                backup.into_iter().collect()
+            }
+            authorized_keys::REVOKE_SCRIPT => {
+                let data: Vec<&str> = arg(0).lines().collect();
+                let before = self.authorized_keys.lines().count();
+                let kept: Vec<&str> = self
+                    .authorized_keys
+                    .lines()
+                    .filter(|line| !data.iter().any(|data| has_field(line, data)))
+                    .collect();
+                let removed = before - kept.len();
+                if removed == 0 {
+                    return Response::ok(vec!["0".to_string()]);
+                }
+                let kept: String = kept.iter().map(|line| format!("{}\n", line)).collect();
+                let mut lines: Vec<String> = self.back_up_authorized_keys().into_iter().collect();
+                self.authorized_keys = kept;
+                lines.push(removed.to_string());
+                lines
+            }
+            authorized_keys::RESTORE_SCRIPT => {
+                let name = match arg(0) {
+                    "" => self.authorized_keys_backups.keys().next_back(),
+                    name => self.authorized_keys_backups.keys().find(|key| *key == name),
+                };
+                let Some(name) = name.cloned() else {
+                    return Response {
+                        code: 1,
+                        stderr: "no backup of authorized_keys in ~/.ssh\n".to_string(),
+                        ..Default::default()
+                    };
+                };
+                let restored = self.authorized_keys_backups[&name].clone();
+                let mut lines: Vec<String> = self.back_up_authorized_keys().into_iter().collect();
+                self.authorized_keys = restored;
+                lines.push(format!("restored ~/.ssh/{}", name));
+                lines
+            }
+            facts::FACTS_SCRIPT => {
+                let facts = &self.facts;
+                vec![
+                    format!("arch={}", facts.arch),
+                    format!("kernel={}", facts.kernel),
+                    format!("hostname={}", facts.hostname),
+                    format!("model={}", facts.model),
+                    format!("mem_mb={}", facts.mem_mb),
+                    format!("cpus={}", facts.cpus),
+                    format!("os={}", facts.os),
+                    format!("os_version={}", facts.os_version),
+                ]
+            }
+            snapshot::SNAPSHOT_SCRIPT => {
+                // sshd_config itself only differs by whether it was hardened
+                let sshd_config = format!("hardened={}", self.hardened);
+                let mut lines: Vec<String> = self
+                    .packages
+                    .iter()
+                    .map(|name| format!("package {} simulated", name))
+                    .collect();
+                lines.push(format!(
+                    "sshd {} /etc/ssh/sshd_config",
+                    checksum::sha256_bytes(sshd_config.as_bytes())
+                ));
+                if !self.sshd_drop_in.is_empty() {
+                    lines.push(format!(
+                        "sshd {} /etc/ssh/sshd_config.d/50-ssh-ip-tunnel.conf",
+                        checksum::sha256_bytes(self.sshd_drop_in.as_bytes())
+                    ));
+                }
+                lines.extend(
+                    self.authorized_keys
+                        .lines()
+                        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+                        .map(|line| format!("key {}", line)),
+                );
+                lines.push("service ssh.service".to_string());
+                lines
+            }
+            tunnel::PROBE_SCRIPT => vec![
+                format!("arch={}", self.facts.arch),
+                "os=Linux".to_string(),
+                format!("kernel={}", self.facts.kernel),
+                format!("distro={}", self.facts.os),
+                format!("distro_version={}", self.facts.os_version),
+                format!("model={}", self.facts.model),
+            ],
+            info::INFO_SCRIPT => vec![
+                format!("mem_total_kb={}", self.facts.mem_mb * 1024),
+                format!("mem_available_kb={}", self.facts.mem_mb * 1024 * 4 / 5),
+                "disk_total_kb=30450832".to_string(),
+                "disk_used_kb=4214116".to_string(),
+                "disk_available_kb=24965388".to_string(),
+                "uptime=93731.52".to_string(),
+                "sshd=OpenSSH_9.2p1".to_string(),
+            ],
+            clock::SYNC_SCRIPT => vec![chrono::Utc::now().timestamp().to_string()],
+            hardware::HARDWARE_SCRIPT => {
+                let dry_run = !arg(3).is_empty();
+                let mut lines = Vec::new();
+                if !arg(0).is_empty() {
+                    lines.push(format!("config {}", BOOT_CONFIG));
+                }
+                for line in arg(0).lines() {
+                    if !self.boot_config.iter().any(|present| present == line) {
+                        lines.push(format!("added config {}", line));
+                        if !dry_run {
+                            self.boot_config.push(line.to_string());
+                        }
+                    }
+                }
+                for module in arg(1).lines() {
+                    if !self.modules.iter().any(|present| present == module) {
+                        lines.push(format!("added module {}", module));
+                        if !dry_run {
+                            self.modules.push(module.to_string());
+                        }
+                    }
+                    if !dry_run {
+                        lines.push(format!("loaded {}", module));
+                    }
+                }
+                lines
+            }
+            swap::SWAP_SCRIPT => {
+                let ram = self.facts.mem_mb;
+                let threshold: u64 = arg(1).parse().unwrap_or_default();
+                let size = ram.min(arg(2).parse().unwrap_or_default());
+                let mut lines = vec![format!("ram {}", ram)];
+                if ram > threshold {
+                    lines.push(format!("skip not_needed {}", self.swap_mb));
+                } else if self.swap_mb >= size {
+                    lines.push(format!("skip present {}", self.swap_mb));
+                } else {
+                    let kind = if arg(0) == "file" { "file" } else { "zram" };
+                    self.swap_mb = size;
+                    lines.push(format!("set {} {} persistent", kind, size));
+                }
+                lines
+            }
+            device_profile::HOSTNAME_SCRIPT => {
+                if self.facts.hostname == arg(1) {
+                    return Response::ok(Vec::new());
+                }
+                let line = format!("~ hostname {} -> {}", self.facts.hostname, arg(1));
+                if apply {
+                    self.facts.hostname = arg(1).to_string();
+                }
+                vec![line]
+            }
+            device_profile::USER_SCRIPT => {
+                let name = arg(1);
+                let mut lines = Vec::new();
+                if !self.users.contains_key(name) {
+                    lines.push(format!("+ user {}", name));
+                }
+                let mut user = self.users.get(name).cloned().unwrap_or_default();
+                for group in arg(2).split_whitespace() {
+                    if !user.groups.iter().any(|present| present == group) {
+                        lines.push(format!("+ {} in group {}", name, group));
+                        user.groups.push(group.to_string());
+                    }
+                }
+                for key in arg(3).lines() {
+                    let fields: Vec<&str> = key.split_whitespace().collect();
+                    let data = fields.get(1).copied().unwrap_or_default();
+                    if !user
+                        .authorized_keys
+                        .iter()
+                        .any(|line| has_field(line, data))
+                    {
+                        lines.push(format!(
+                            "+ key {} {} for {}",
+                            fields.first().copied().unwrap_or_default(),
+                            fields.get(2).copied().unwrap_or_default(),
+                            name
+                        ));
+                        user.authorized_keys.push(key.to_string());
+                    }
+                }
+                if apply {
+                    self.users.insert(name.to_string(), user);
+                }
+                lines
+            }
+            device_profile::SSHD_SCRIPT => {
+                let (content, current) = (arg(1), self.sshd_drop_in.as_str());
+                let mut lines: Vec<String> = content
+                    .lines()
+                    .filter(|line| !current.lines().any(|present| present == *line))
+                    .map(|line| format!("+ {}", line))
+                    .collect();
+                lines.extend(
+                    current
+                        .lines()
+                        .filter(|line| !content.lines().any(|wanted| wanted == *line))
+                        .map(|line| format!("- {}", line)),
+                );
+                if apply {
+                    self.sshd_drop_in = content.to_string();
+                }
+                lines
+            }
+            device_profile::PACKAGES_SCRIPT => {
+                let missing: Vec<&String> = args[1.min(args.len())..]
+                    .iter()
+                    .filter(|package| !self.packages.contains(*package))
+                    .collect();
+                let lines = missing
+                    .iter()
+                    .map(|package| format!("+ package {}", package))
+                    .collect();
+                if apply {
+                    self.packages.extend(missing.into_iter().cloned());
+                }
+                lines
+            }
+            device_profile::WIFI_SCRIPT => {
+                let ssid = arg(1);
+                let psk = stdin();
+                if self.wifi.contains(ssid) {
+                    return Response::ok(Vec::new());
+                }
+                if psk.trim().is_empty() {
+                    return Response {
+                        code: 1,
+                        stderr: "no passphrase on stdin\n".to_string(),
+                        ..Default::default()
+                    };
+                }
+                if apply {
+                    self.wifi.insert(ssid.to_string());
+                }
+                vec![format!("+ wifi network {} (NetworkManager)", ssid)]
+            }
+            harden::HARDEN_SCRIPT if self.hardened => vec!["unchanged".to_string()],
+            harden::HARDEN_SCRIPT => {
+                self.hardened = true;
+                vec![
+                    "backup /etc/ssh/sshd_config.ssh-ip-tunnel-harden.bak".to_string(),
+                    "hardened".to_string(),
+                ]
+            }
+            // Simple scripts run line by line, like `exec` commands
+            provision::PROVISION_SCRIPT => {
+                let mut response = Response::ok(Vec::new());
+                for line in stdin()
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                {
+                    let step = self.respond(&split_words(line), &String::new);
+                    response.stdout.push_str(&step.stdout);
+                    response.stderr.push_str(&step.stderr);
+                    if step.code != 0 {
+                        response.code = step.code;
+                        break;
+                    }
+                }
+                return response;
+            }
+            push::SIZE_SCRIPT => vec![self.files.get(arg(0)).map_or(0, String::len).to_string()],
+            push::WRITE_SCRIPT => {
+                self.files.insert(arg(0).to_string(), stdin());
+                Vec::new()
+            }
+            push::APPEND_SCRIPT => {
+                let input = stdin();
+                self.files
+                    .entry(arg(0).to_string())
+                    .or_default()
+                    .push_str(&input);
+                Vec::new()
+            }
+            push::JOIN_SCRIPT => {
+                let mut joined = String::new();
+                for part in args.get(1..).unwrap_or_default() {
+                    match self.files.remove(part) {
+                        Some(contents) => joined.push_str(&contents),
+                        None => return missing("cat", part),
+                    }
+                }
+                self.files.insert(arg(0).to_string(), joined);
+                Vec::new()
+            }
+            push::MOVE_SCRIPT => match self.files.remove(arg(0)) {
+                Some(contents) => {
+                    self.files.insert(arg(1).to_string(), contents);
+                    Vec::new()
+                }
+                None => return missing("mv", arg(0)),
+            },
+            checksum::FILE_SCRIPT => match self.files.get(arg(0)) {
+                Some(contents) => vec![format!(
+                    "{}  -",
+                    checksum::sha256_bytes(contents.as_bytes())
+                )],
+                None => return missing("sha256", arg(0)),
+            },
+            // A one-line command, as `exec` runs them
+            _ if !script.contains('\n') => return self.respond(&split_words(script), stdin),
+            _ => return Response::unsupported(script),
+        };
+        Response::ok(lines)
+    }
+}
+
+/// `program` failing on a file the device doesn't have
+fn missing(program: &str, path: &str) -> Response {
+    Response {
+        code: 1,
+        stderr: format!("{}: {}: No such file or directory\n", program, path),
+        ..Default::default()
+    }
+}
+
+/// A target answered by a new fake device kept in `dir`, with a key there to deploy
+#[cfg(test)]
+pub(crate) fn test_target(dir: &Path) -> crate::Target {
+    std::fs::create_dir_all(dir).unwrap();
+    let state = dir.join("device.json");
+    let _ = std::fs::remove_file(&state);
+    let key = dir.join("id_ed25519.pub");
+    std::fs::write(
+        &key,
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE2r6Mh3R6ghh0xOP+VDXN9PKNIINEYTIeU5oGPPS51a test@host\n",
+    )
+    .unwrap();
+    crate::Target::builder("pi.local", "pi", &crate::config::Config::default())
+        .key_path(key.to_string_lossy())
+        .simulated(state)
+        .build()
+        .unwrap()
+}
+
+/// Whether `line` has `word` as one of its whitespace-separated fields
+fn has_field(line: &str, word: &str) -> bool {
+    !word.is_empty() && line.split_whitespace().any(|field| field == word)
+}
+
+/// Splits a command line built by [`shell::RemoteCommand`] back into words
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let (mut in_word, mut quoted) = (false, false);
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            '\\' if !quoted => {
+                word.extend(chars.next());
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::PublicKey;
+    use crate::shell::RemoteCommand;
+
+    fn run(device: &mut Device, command: RemoteCommand) -> Response {
+        device.respond(&split_words(&command.to_shell_string()), &String::new)
+    }
+
+    fn script(script: &str, args: &[&str]) -> RemoteCommand {
+        let mut command = RemoteCommand::new("sh")
+            .arg("-c")
+            .arg(format!("{}\n{}", shell::AS_ROOT, script))
+            .arg("sh");
+        for arg in args {
+            command = command.arg(arg);
+        }
+        command
+    }
+
+    #[test]
+    fn test_split_words_undoes_quoting() {
+        let command = RemoteCommand::new("sh")
+            .arg("-c")
+            .arg("echo 'it'\\''s' \"$1\"")
+            .arg("")
+            .arg("a b");
+        assert_eq!(
+            split_words(&command.to_shell_string()),
+            ["sh", "-c", "echo 'it'\\''s' \"$1\"", "", "a b"]
+        );
+    }
+
+    #[test]
+    fn test_device_keeps_keys_and_packages_between_commands() {
+        let mut device = Device::default();
+        let echo = RemoteCommand::new("echo").arg("tunnel_test");
+        assert_eq!(run(&mut device, echo).stdout, "tunnel_test\n");
+        assert_eq!(
+            run(&mut device, RemoteCommand::new("uname").arg("-m")).stdout,
+            "aarch64\n"
+        );
+
+        let key = PublicKey::parse("ssh-ed25519 AAAAkey laptop").unwrap();
+        let install = RemoteCommand::new("sh")
+            .arg("-c")
+            .arg(authorized_keys::with_back_up(
+                authorized_keys::INSTALL_SCRIPT,
+            ))
+            .arg("sh")
+            .arg(key.to_line())
+            .arg(&key.data);
+        assert_eq!(run(&mut device, install.clone()).code, 0);
+        assert_eq!(run(&mut device, install).code, 0);
+        assert_eq!(device.authorized_keys, "ssh-ed25519 AAAAkey laptop\n");
+        let revoke = RemoteCommand::new("sh")
+            .arg("-c")
+            .arg(authorized_keys::with_back_up(
+                authorized_keys::REVOKE_SCRIPT,
+            ))
+            .arg("sh")
+            .arg("AAAAkey");
+        assert!(run(&mut device, revoke).stdout.ends_with("\n1\n"));
+        assert_eq!(device.authorized_keys, "");
+        let restore = RemoteCommand::new("sh")
+            .arg("-c")
+            .arg(authorized_keys::with_back_up(
+                authorized_keys::RESTORE_SCRIPT,
+            ))
+            .arg("sh")
+            .arg("");
+        assert_eq!(run(&mut device, restore).code, 0);
+        assert_eq!(device.authorized_keys, "ssh-ed25519 AAAAkey laptop\n");
+
+        let packages = ["plan", "i2c-tools", "git"];
+        let plan = run(
+            &mut device,
+            script(device_profile::PACKAGES_SCRIPT, &packages),
+        );
+        assert_eq!(plan.stdout, "+ package i2c-tools\n+ package git\n");
+        assert!(device.packages.is_empty());
+        let apply = ["apply", "i2c-tools", "git"];
+        run(&mut device, script(device_profile::PACKAGES_SCRIPT, &apply));
+        let again = run(
+            &mut device,
+            script(device_profile::PACKAGES_SCRIPT, &packages),
+        );
+        assert_eq!(again.stdout, "");
+
+        let unknown = run(&mut device, RemoteCommand::new("reboot"));
+        assert_eq!(unknown.code, 127);
+        assert!(unknown.stderr.contains("can't run"));
+    }
+
+    #[test]
+    fn test_target_user_has_its_own_keys() {
+        let mut device = Device::default();
+        let key = PublicKey::parse("ssh-ed25519 AAAAkey laptop").unwrap();
+        let as_deploy = |script: &str, args: &[&str]| {
+            let mut command = RemoteCommand::new("sh")
+                .arg("-c")
+                .arg(authorized_keys::AS_USER_SCRIPT)
+                .arg("sh")
+                .arg("deploy")
+                .arg(script);
+            for arg in args {
+                command = command.arg(arg);
+            }
+            command
+        };
+        let install = as_deploy(
+            &authorized_keys::with_back_up(authorized_keys::INSTALL_SCRIPT),
+            &[&key.to_line(), &key.data],
+        );
+        assert_eq!(run(&mut device, install.clone()).code, 1);
+
+        let create = script(authorized_keys::CREATE_USER_SCRIPT, &["deploy"]);
+        assert_eq!(run(&mut device, create.clone()).stdout, "created\n");
+        assert_eq!(run(&mut device, create).stdout, "");
+        assert_eq!(run(&mut device, install).code, 0);
+        assert_eq!(device.authorized_keys, "");
+        assert_eq!(
+            device.users["deploy"].authorized_keys,
+            ["ssh-ed25519 AAAAkey laptop"]
+        );
+        let read = run(&mut device, as_deploy(authorized_keys::READ_SCRIPT, &[]));
+        assert_eq!(read.stdout, "ssh-ed25519 AAAAkey laptop\n");
+    }
+}
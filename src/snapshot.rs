@@ -0,0 +1,218 @@
+//! `snapshot` and `snapshot diff`: recording a device's state and what changed since.
+//!
+//! A snapshot holds the installed packages, hashes of the sshd configuration,
+//! the login user's authorized_keys and the services enabled at boot, all read
+//! in one round trip. Snapshots are kept as JSON under the state directory,
+//! `snapshots/<host>/<id>.json`, where the ID is the time it was taken.
+//! `snapshot diff` compares two of them, or one against the device as it is now.
+
+use crate::config::Config;
+use crate::output::{self, Renderable};
+use crate::paths;
+use crate::pure::snapshot::{self as pure, ChangeKind};
+use crate::shell::RemoteCommand;
+use crate::ssh;
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use chrono::Utc;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::info;
+
+pub use crate::pure::snapshot::{Snapshot, SnapshotDiff};
+
+/// Upper bound for reading the device's state
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Format of snapshot IDs, the UTC time the snapshot was taken
+const ID_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Prints the lines [`Snapshot::parse`] reads. Packages come from dpkg, rpm,
+/// apk or opkg, services from systemd or OpenRC, whichever the device has.
+pub const SNAPSHOT_SCRIPT: &str = r#"if command -v dpkg-query >/dev/null 2>&1; then
+  dpkg-query -W -f='package ${Package} ${Version}\n' 2>/dev/null
+elif command -v rpm >/dev/null 2>&1; then
+  rpm -qa --qf 'package %{NAME} %{VERSION}-%{RELEASE}\n' 2>/dev/null
+elif command -v apk >/dev/null 2>&1; then
+  apk list -I 2>/dev/null | awk '{ n = $1; sub(/-[^-]+-r[0-9]+$/, "", n); print "package", n, substr($1, length(n) + 2) }'
+elif command -v opkg >/dev/null 2>&1; then
+  opkg list-installed 2>/dev/null | awk '{ print "package", $1, $3 }'
+fi
+for f in /etc/ssh/sshd_config /etc/ssh/sshd_config.d/*.conf; do
+  if [ -r "$f" ]; then echo "sshd $(sha256sum < "$f" | cut -d' ' -f1) $f"; fi
+done
+if [ -r "$HOME/.ssh/authorized_keys" ]; then
+  grep -v -e '^[[:space:]]*#' -e '^[[:space:]]*$' "$HOME/.ssh/authorized_keys" | sed 's/^/key /'
+fi
+if command -v systemctl >/dev/null 2>&1; then
+  systemctl list-unit-files --type=service --state=enabled --no-legend --no-pager 2>/dev/null | awk '{ print "service", $1 }'
+elif [ -d /etc/runlevels/default ]; then
+  ls /etc/runlevels/default | sed 's/^/service /'
+fi"#;
+
+/// Directory of `host`'s snapshots
+fn host_dir(host: &str) -> PathBuf {
+    paths::state_dir()
+        .join("snapshots")
+        .join(host.replace([':', '/', '\\'], "_"))
+}
+
+/// Reads the device's state now, through an open tunnel
+pub async fn capture(target: &Target) -> Result<Snapshot, TunnelError> {
+    info!("Reading the state of {}...", target.host);
+    let command = RemoteCommand::new("sh").arg("-c").arg(SNAPSHOT_SCRIPT);
+    let stdout = ssh::run_through_tunnel(target, &command, SNAPSHOT_TIMEOUT)
+        .await
+        .map_err(|e| e.into_error(TunnelError::Snapshot))?;
+    Ok(Snapshot::parse(&target.host, &stdout, Utc::now()))
+}
+
+/// Result of `snapshot`
+#[derive(Debug, Serialize)]
+pub struct SnapshotReport {
+    pub id: String,
+    pub path: PathBuf,
+    pub host: String,
+    pub packages: usize,
+    pub sshd_config_files: usize,
+    pub authorized_keys: usize,
+    pub services: usize,
+}
+
+impl Renderable for SnapshotReport {
+    fn to_human(&self) -> String {
+        format!(
+            "Snapshot {} of {}: {} packages, {} sshd config files, {} authorized keys, {} enabled services\nSaved to {}",
+            self.id,
+            self.host,
+            self.packages,
+            self.sshd_config_files,
+            self.authorized_keys,
+            self.services,
+            self.path.display()
+        )
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Takes a snapshot of `target` and saves it
+pub async fn take(config: &Config, target: &Target) -> Result<SnapshotReport> {
+    SSHTunnelManager::new(config.clone())
+        .reuse_or_connect(target)
+        .await?;
+    let snapshot = capture(target).await?;
+
+    let id = snapshot.taken_at.format(ID_FORMAT).to_string();
+    let path = host_dir(&target.host).join(format!("{}.json", id));
+    let saved = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| {
+            std::fs::write(
+                &path,
+                serde_json::to_string_pretty(&snapshot).unwrap_or_default(),
+            )
+        });
+    saved.map_err(|e| TunnelError::Snapshot(format!("{}: {}", path.display(), e)))?;
+    Ok(SnapshotReport {
+        id,
+        path,
+        host: snapshot.host,
+        packages: snapshot.packages.len(),
+        sshd_config_files: snapshot.sshd_config.len(),
+        authorized_keys: snapshot.authorized_keys.len(),
+        services: snapshot.services.len(),
+    })
+}
+
+/// Loads the snapshot `reference`: a file, or the ID of one of `host`'s snapshots
+pub fn load(reference: &str, host: Option<&str>) -> Result<Snapshot, TunnelError> {
+    let path = if Path::new(reference).is_file() {
+        PathBuf::from(reference)
+    } else if let Some(host) = host.filter(|_| !reference.contains(['/', '\\', '.'])) {
+        host_dir(host).join(format!("{}.json", reference))
+    } else {
+        return Err(TunnelError::Snapshot(format!(
+            "{} is not a snapshot file; a snapshot ID needs the device, e.g. -H or a profile",
+            reference
+        )));
+    };
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| TunnelError::Snapshot(format!("cannot read {}: {}", path.display(), e)))?;
+    serde_json::from_str(&text)
+        .map_err(|e| TunnelError::Snapshot(format!("{}: {}", path.display(), e)))
+}
+
+impl Renderable for SnapshotDiff {
+    fn to_human(&self) -> String {
+        let header = format!(
+            "{}: changes from {} to {}",
+            self.host,
+            self.from.format("%Y-%m-%d %H:%M:%S UTC"),
+            self.to.format("%Y-%m-%d %H:%M:%S UTC")
+        );
+        if self.changes.is_empty() {
+            return format!("{}\nNothing changed", header);
+        }
+        let rows: Vec<Vec<String>> = self
+            .changes
+            .iter()
+            .map(|change| {
+                let item = match change.area {
+                    "authorized_keys" => pure::key_label(&change.item),
+                    _ => change.item.clone(),
+                };
+                let detail = match (change.kind, &change.from, &change.to) {
+                    (ChangeKind::Changed, Some(from), Some(to)) => format!("{} -> {}", from, to),
+                    (_, Some(value), None) | (_, None, Some(value)) => value.clone(),
+                    _ => String::new(),
+                };
+                let kind = match change.kind {
+                    ChangeKind::Added => "added",
+                    ChangeKind::Removed => "removed",
+                    ChangeKind::Changed => "changed",
+                };
+                vec![change.area.to_string(), kind.to_string(), item, detail]
+            })
+            .collect();
+        format!(
+            "{}\n{}",
+            header,
+            output::table(&["AREA", "CHANGE", "ITEM", "DETAIL"], &rows)
+        )
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Compares snapshot `from` with snapshot `to`, or with the device as it is
+/// now when there is no `to`
+pub async fn diff(
+    config: &Config,
+    target: Option<&Target>,
+    from: &str,
+    to: Option<&str>,
+) -> Result<SnapshotDiff> {
+    let host = target.map(|target| target.host.as_str());
+    let before = load(from, host)?;
+    let after = match (to, target) {
+        (Some(to), _) => load(to, host)?,
+        (None, Some(target)) => {
+            SSHTunnelManager::new(config.clone())
+                .reuse_or_connect(target)
+                .await?;
+            capture(target).await?
+        }
+        (None, None) => anyhow::bail!(TunnelError::Snapshot(
+            "comparing with the device as it is now needs the device, e.g. -H or a profile"
+                .to_string()
+        )),
+    };
+    Ok(pure::diff(&before, &after))
+}
@@ -0,0 +1,137 @@
+//! A spinner on the terminal while a slow step runs, e.g. a tunnel going
+//! through its retries.
+//!
+//! Only drawn along with status lines (see [`output::Style`]). Status and log
+//! lines printed meanwhile go through [`write_above`], which clears the
+//! spinner first; the next tick draws it again below them.
+
+use crate::output;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant};
+
+const FRAMES: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+
+/// How often the spinner turns
+const TICK: Duration = Duration::from_millis(100);
+
+/// Clears the terminal line the cursor is on
+const CLEAR_LINE: &str = "\r\x1b[2K";
+
+struct Current {
+    id: u64,
+    text: String,
+    started: Instant,
+    deadline: Option<Instant>,
+}
+
+static CURRENT: Mutex<Option<Current>> = Mutex::new(None);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+static TICKER: Once = Once::new();
+
+/// Shows a spinner until dropped; does nothing without status lines
+pub struct Spinner {
+    id: Option<u64>,
+}
+
+/// Starts a spinner saying `text`, with the time left until `deadline`, or
+/// else the time taken so far
+pub fn start(text: impl Into<String>, deadline: Option<Instant>) -> Spinner {
+    if !output::style().status_lines {
+        return Spinner { id: None };
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    *lock() = Some(Current {
+        id,
+        text: text.into(),
+        started: Instant::now(),
+        deadline,
+    });
+    TICKER.call_once(|| {
+        std::thread::spawn(|| {
+            for frame in FRAMES.iter().cycle() {
+                std::thread::sleep(TICK);
+                if let Some(current) = lock().as_ref() {
+                    draw(frame, current);
+                }
+            }
+        });
+    });
+    Spinner { id: Some(id) }
+}
+
+impl Spinner {
+    /// Changes what the spinner says, e.g. to count attempts
+    pub fn set_text(&self, text: impl Into<String>) {
+        let mut current = lock();
+        if let Some(current) = current.as_mut().filter(|c| Some(c.id) == self.id) {
+            current.text = text.into();
+        }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        let mut current = lock();
+        if self.id.is_some() && current.as_ref().map(|c| c.id) == self.id {
+            *current = None;
+            let mut stdout = io::stdout();
+            let _ = write!(stdout, "{}", CLEAR_LINE);
+            let _ = stdout.flush();
+        }
+    }
+}
+
+/// Writes `bytes` to stdout, first clearing a spinner if one is drawn
+pub fn write_above(bytes: &[u8]) -> io::Result<()> {
+    let current = lock();
+    let mut stdout = io::stdout();
+    if current.is_some() {
+        write!(stdout, "{}", CLEAR_LINE)?;
+    }
+    stdout.write_all(bytes)?;
+    stdout.flush()
+}
+
+/// Stdout for log lines, which clears the spinner before each write
+pub struct Stdout;
+
+impl Write for Stdout {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        write_above(bytes)?;
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+fn lock() -> std::sync::MutexGuard<'static, Option<Current>> {
+    CURRENT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn draw(frame: &str, current: &Current) {
+    let time = match current.deadline {
+        Some(deadline) => format!(
+            "{}s left",
+            deadline.saturating_duration_since(Instant::now()).as_secs()
+        ),
+        None => format!("{}s", current.started.elapsed().as_secs()),
+    };
+    let frame = if output::style().color {
+        format!("\x1b[36m{}\x1b[0m", frame)
+    } else {
+        frame.to_string()
+    };
+    let mut stdout = io::stdout();
+    let _ = write!(
+        stdout,
+        "{}{} {} ({})",
+        CLEAR_LINE, frame, current.text, time
+    );
+    let _ = stdout.flush();
+}
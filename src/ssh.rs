@@ -0,0 +1,651 @@
+//! Construction of `ssh` command lines.
+//!
+//! User names and hosts are always passed as separate arguments (`-l`, `-p`,
+//! `--`) and never spliced into `user@host`, so a value such as
+//! `-oProxyCommand=...` can't be mistaken for an option by the local `ssh`.
+//!
+//! With `--interactive-auth`, or a security key as the login key, the tunnel's
+//! `ssh` is also a connection master (`ControlMaster`), and every later command
+//! is multiplexed over it, so the user answers passphrase or 2FA prompts (or
+//! touches the key) once rather than once per command.
+//! With `--password`, every command is started through [`command`] so `ssh` can
+//! answer password prompts (see [`askpass`]). With `--batch` and no password,
+//! `ssh` runs in its own batch mode, failing where it would have asked.
+//!
+//! Host keys are checked against the tool's own known_hosts file (see
+//! [`host_keys`]). In secure mode (`--secure`), keys that aren't recorded yet
+//! are refused rather than trusted on first use, only the algorithms in
+//! [`SECURE_ALGORITHMS`] are negotiated, and ssh's warnings are not silenced.
+//!
+//! The commands built here are [`crate::executor::Command`]s: with
+//! `--simulate`, a fake device answers them in this process instead (see
+//! [`crate::simulate`]). [`run`] runs one to completion within a time limit,
+//! telling a timeout, a lost connection and a failed remote command apart.
+
+use crate::askpass;
+use crate::executor::Command;
+use crate::fault;
+use crate::host_keys;
+use crate::paths;
+use crate::process;
+use crate::prompt;
+use crate::shell::RemoteCommand;
+use crate::{Target, TunnelError};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// Algorithms allowed in secure mode: AEAD ciphers, encrypt-then-MAC, and none
+/// of the SHA-1 based fallbacks such as `ssh-rsa` or `diffie-hellman-group14-sha1`
+pub const SECURE_ALGORITHMS: [&str; 5] = [
+    "KexAlgorithms=sntrup761x25519-sha512@openssh.com,curve25519-sha256,curve25519-sha256@libssh.org",
+    "Ciphers=chacha20-poly1305@openssh.com,aes256-gcm@openssh.com,aes128-gcm@openssh.com",
+    "MACs=hmac-sha2-512-etm@openssh.com,hmac-sha2-256-etm@openssh.com",
+    "HostKeyAlgorithms=ssh-ed25519,ecdsa-sha2-nistp256,rsa-sha2-512,rsa-sha2-256",
+    "PubkeyAcceptedAlgorithms=ssh-ed25519,sk-ssh-ed25519@openssh.com,ecdsa-sha2-nistp256,sk-ecdsa-sha2-nistp256@openssh.com,rsa-sha2-512,rsa-sha2-256",
+];
+
+/// Options shared by every connection the tool makes to `target`
+pub fn common_options(target: &Target) -> Vec<String> {
+    let mut options = Vec::new();
+    if target.secure {
+        options.extend(SECURE_ALGORITHMS);
+        options.extend(["ForwardAgent=no", "ForwardX11=no"]);
+    } else {
+        options.push("LogLevel=ERROR");
+    }
+    // Notice a dead link within a minute instead of hanging on it
+    options.extend(["ServerAliveInterval=15", "ServerAliveCountMax=4"]);
+    // A password is answered by askpass, which batch mode would turn off
+    if prompt::is_batch() && target.password.is_none() {
+        options.push("BatchMode=yes");
+    }
+
+    let mut args = host_key_options(target, &host_keys::known_hosts_path(), target.secure);
+    args.extend(
+        options
+            .iter()
+            .flat_map(|opt| ["-o".to_string(), opt.to_string()]),
+    );
+    args
+}
+
+/// Checks the host key against `known_hosts` under the device's own name,
+/// recording it on first contact unless `strict`
+fn host_key_options(target: &Target, known_hosts: &Path, strict: bool) -> Vec<String> {
+    [
+        format!(
+            "StrictHostKeyChecking={}",
+            if strict { "yes" } else { "accept-new" }
+        ),
+        path_option("UserKnownHostsFile", known_hosts),
+        format!("HostKeyAlias={}", host_keys::alias(target)),
+        // Plain names can be looked up without ssh-keygen
+        "HashKnownHosts=no".to_string(),
+    ]
+    .into_iter()
+    .flat_map(|opt| ["-o".to_string(), opt])
+    .collect()
+}
+
+/// Builds a command for `ssh`, or a program that runs it, logging in to `target`
+pub fn command(program: &str, target: &Target) -> Result<Command, TunnelError> {
+    fault::check_connection(target)?;
+    if let Some(state) = &target.simulated {
+        return Ok(Command::simulated(state.clone()));
+    }
+    let mut cmd = process::command(program)?;
+    askpass::configure(&mut cmd, target)?;
+    Ok(cmd.into())
+}
+
+/// The port sshd listens on when the target doesn't say otherwise
+pub const DEFAULT_SSH_PORT: u16 = 22;
+
+/// `name=path` for `-o`, with the path quoted: ssh would read one with spaces
+/// as several words
+pub fn path_option(name: &str, path: &Path) -> String {
+    format!("{}=\"{}\"", name, path.display())
+}
+
+/// `-o IdentityFile=` for the target's identity file, if it has one
+pub fn identity_options(target: &Target) -> Vec<String> {
+    match &target.identity_file {
+        Some(path) => vec!["-o".to_string(), path_option("IdentityFile", path)],
+        None => Vec::new(),
+    }
+}
+
+/// Socket of the connection master for the tunnel on the target's local port
+pub fn control_path(target: &Target) -> PathBuf {
+    paths::runtime_file(&format!("{}.sock", target.port))
+}
+
+/// Options that make `ssh` the connection master (`master`) or a client of it,
+/// empty unless logging in to the target needs the user
+pub fn multiplex_options(target: &Target, master: bool) -> Vec<String> {
+    if !target.multiplexed() {
+        return Vec::new();
+    }
+    vec![
+        "-o".to_string(),
+        format!("ControlMaster={}", if master { "yes" } else { "no" }),
+        "-o".to_string(),
+        path_option("ControlPath", &control_path(target)),
+    ]
+}
+
+/// Builds the arguments that open a local forward from the target's local port to its sshd
+pub fn tunnel_args(target: &Target) -> Vec<String> {
+    let remote_port = target.remote_port.unwrap_or(DEFAULT_SSH_PORT);
+    let mut args = vec![
+        "-fN".to_string(),
+        "-L".to_string(),
+        format!("{}:localhost:{}", target.port, remote_port),
+    ];
+    args.extend(login_args(target));
+    args.extend(multiplex_options(target, true));
+    args.extend(common_options(target));
+    args.push("--".to_string());
+    args.push(target.host.clone());
+    args
+}
+
+/// User, port, jump host and identity for logging in to the device itself
+fn login_args(target: &Target) -> Vec<String> {
+    let mut args = vec!["-l".to_string(), target.user.clone()];
+    if let Some(port) = target.remote_port {
+        args.extend(["-p".to_string(), port.to_string()]);
+    }
+    if let Some(jump) = &target.proxy_jump {
+        args.extend(["-J".to_string(), jump.clone()]);
+    }
+    args.extend(identity_options(target));
+    args
+}
+
+/// Builds an `ssh` command that runs `remote` on the device directly, bypassing the tunnel.
+///
+/// For checking on a device while it reboots, when the tunnel and any
+/// connection master have gone down with it.
+pub fn direct(target: &Target, remote: &RemoteCommand) -> Result<Command, TunnelError> {
+    let mut cmd = command("ssh", target)?;
+    cmd.args(login_args(target))
+        .args(["-o", "ConnectTimeout=5"])
+        .args(common_options(target))
+        .arg("--")
+        .arg(&target.host)
+        .arg(remote.to_shell_string());
+    Ok(cmd)
+}
+
+/// Builds an `ssh` command that only fetches the device's `algorithm` host key into `known_hosts`.
+///
+/// The key is recorded during key exchange; logging in is then made to fail,
+/// since batch mode leaves no way to authenticate but keys, and none are offered.
+pub fn host_key_probe(
+    target: &Target,
+    known_hosts: &Path,
+    algorithm: &str,
+) -> Result<Command, TunnelError> {
+    let mut cmd = if let Some(state) = &target.simulated {
+        Command::simulated(state.clone())
+    } else {
+        process::command("ssh")?.into()
+    };
+    cmd.args(login_args(target))
+        // The scratch file starts empty; the key is only kept once its fingerprint is checked
+        .args(host_key_options(target, known_hosts, false))
+        .args([
+            "-o",
+            "ConnectTimeout=10",
+            "-o",
+            "BatchMode=yes",
+            "-o",
+            "PubkeyAuthentication=no",
+            "-o",
+            "ControlPath=none",
+            "-o",
+            &format!("HostKeyAlgorithms={}", algorithm),
+            "-o",
+            "LogLevel=ERROR",
+        ])
+        .arg("--")
+        .arg(&target.host)
+        .arg("true");
+    Ok(cmd)
+}
+
+/// Builds an `ssh` command that runs `remote` on the target through the local tunnel port
+pub fn through_tunnel(target: &Target, remote: &RemoteCommand) -> Result<Command, TunnelError> {
+    through_tunnel_with(target, &[], remote)
+}
+
+/// Like [`through_tunnel`], with extra `ssh` arguments that take precedence over the defaults
+pub fn through_tunnel_with(
+    target: &Target,
+    options: &[String],
+    remote: &RemoteCommand,
+) -> Result<Command, TunnelError> {
+    let mut cmd = command("ssh", target)?;
+    cmd.args(["-p", &target.port.to_string(), "-l", &target.user])
+        .args(options)
+        .args(["-o", "ConnectTimeout=5"])
+        .args(identity_options(target))
+        .args(multiplex_options(target, false))
+        .args(common_options(target))
+        .arg("--")
+        .arg("localhost")
+        .arg(remote.to_shell_string());
+    Ok(cmd)
+}
+
+/// Exit status `ssh` itself uses when the connection fails, as opposed to the
+/// remote command
+pub const SSH_FAILURE: i32 = 255;
+
+/// How a remote command started with [`run`] failed
+#[derive(Debug)]
+pub enum RunError {
+    /// The `ssh` command line couldn't be built
+    Ssh(TunnelError),
+    /// It didn't finish in time, and was killed
+    Timeout,
+    /// `ssh` couldn't be started
+    Start(std::io::Error),
+    /// It exited unsuccessfully; `code` is None when it was killed by a signal
+    Failed { code: Option<i32>, stderr: String },
+}
+
+impl RunError {
+    /// Whether `ssh` lost or never had the connection, rather than the
+    /// remote command failing
+    pub fn is_connection_lost(&self) -> bool {
+        matches!(
+            self,
+            Self::Failed {
+                code: Some(SSH_FAILURE),
+                ..
+            }
+        )
+    }
+
+    /// The caller's error for this failure, made by `wrap` from its message;
+    /// an `ssh` command line that couldn't be built keeps its own error
+    pub fn into_error(self, wrap: impl FnOnce(String) -> TunnelError) -> TunnelError {
+        match self {
+            Self::Ssh(e) => e,
+            e => wrap(e.to_string()),
+        }
+    }
+
+    /// Like [`RunError::into_error`], except that a lost connection or a
+    /// timeout becomes [`TunnelError::ConnectionLost`], which is worth retrying
+    pub fn into_retryable(self, wrap: impl FnOnce(String) -> TunnelError) -> TunnelError {
+        if self.is_connection_lost() || matches!(self, Self::Timeout) {
+            TunnelError::ConnectionLost(self.to_string())
+        } else {
+            self.into_error(wrap)
+        }
+    }
+}
+
+impl From<TunnelError> for RunError {
+    fn from(e: TunnelError) -> Self {
+        Self::Ssh(e)
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ssh(e) => write!(f, "{}", e),
+            Self::Timeout => write!(f, "timed out"),
+            Self::Start(e) => write!(f, "ssh could not be started: {}", e),
+            Self::Failed { stderr, .. } if !stderr.is_empty() => write!(f, "{}", stderr),
+            Self::Failed {
+                code: Some(code), ..
+            } => write!(f, "exited with status {}", code),
+            Self::Failed { code: None, .. } => write!(f, "was killed"),
+        }
+    }
+}
+
+/// Runs `command`, an `ssh` built here, for at most `limit`, returning its
+/// stdout once it succeeds
+pub async fn run(mut command: Command, limit: Duration) -> Result<String, RunError> {
+    let output = timeout(limit, command.kill_on_drop(true).output())
+        .await
+        .map_err(|_| RunError::Timeout)?
+        .map_err(RunError::Start)?;
+    if !output.status.success() {
+        return Err(RunError::Failed {
+            code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Runs `remote` on the target through the local tunnel port (see [`run`])
+pub async fn run_through_tunnel(
+    target: &Target,
+    remote: &RemoteCommand,
+    limit: Duration,
+) -> Result<String, RunError> {
+    run(through_tunnel(target, remote)?, limit).await
+}
+
+/// Builds an `ssh` command that opens an interactive login shell on the
+/// target through the local tunnel port, on a remote terminal (`-t`)
+pub fn shell_through_tunnel(target: &Target) -> Result<Command, TunnelError> {
+    let mut cmd = command("ssh", target)?;
+    cmd.args(["-t", "-p", &target.port.to_string(), "-l", &target.user])
+        .args(["-o", "ConnectTimeout=5"])
+        .args(identity_options(target))
+        .args(multiplex_options(target, false))
+        .args(common_options(target))
+        .arg("--")
+        .arg("localhost");
+    Ok(cmd)
+}
+
+/// Builds an `ssh` command that holds reverse forwards open: each of `forwards`
+/// is an `-R` spec, `127.0.0.1:<port>:<host>:<port>` to reach a service here
+/// from the target, or `127.0.0.1:<port>` for a SOCKS proxy
+pub fn reverse_forwards_through_tunnel(
+    target: &Target,
+    forwards: &[String],
+) -> Result<Command, TunnelError> {
+    let mut cmd = command("ssh", target)?;
+    cmd.args(["-N", "-p", &target.port.to_string(), "-l", &target.user]);
+    for forward in forwards {
+        cmd.args(["-R", forward]);
+    }
+    cmd.args(["-o", "ExitOnForwardFailure=yes", "-o", "ConnectTimeout=5"])
+        .args(identity_options(target))
+        .args(multiplex_options(target, false))
+        .args(common_options(target))
+        .arg("--")
+        .arg("localhost");
+    Ok(cmd)
+}
+
+/// Builds an `scp` command that copies `local` to `remote` on the target through the local tunnel port.
+///
+/// A relative `remote` path is resolved against the login user's home directory.
+pub fn copy_through_tunnel(
+    target: &Target,
+    local: &Path,
+    remote: &str,
+) -> Result<Command, TunnelError> {
+    let remote = OsString::from(format!("localhost:{}", remote));
+    scp_through_tunnel(target, &["-q"], &[local.into()], &remote)
+}
+
+/// Builds an `scp` command with `options` that copies `sources` to `dest`
+/// through the local tunnel port, where `localhost:<path>` is a path on the target
+pub fn scp_through_tunnel(
+    target: &Target,
+    options: &[&str],
+    sources: &[OsString],
+    dest: &OsStr,
+) -> Result<Command, TunnelError> {
+    let mut cmd = command("scp", target)?;
+    cmd.args(options)
+        .args(["-P", &target.port.to_string()])
+        .args([
+            "-o",
+            &format!("User={}", target.user),
+            "-o",
+            "ConnectTimeout=5",
+        ])
+        .args(identity_options(target))
+        .args(multiplex_options(target, false))
+        .args(common_options(target))
+        .arg("--")
+        .args(sources)
+        .arg(dest);
+    Ok(cmd)
+}
+
+/// Quotes `word` for the command line rsync's `-e` takes, which rsync splits
+/// itself: inside single quotes everything is literal and `''` is a quote
+fn rsync_quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "''"))
+}
+
+/// The `ssh` command line rsync's `-e` takes to reach the target through the
+/// local tunnel port
+fn rsync_shell(target: &Target) -> String {
+    let mut ssh = vec![
+        "ssh".to_string(),
+        "-p".to_string(),
+        target.port.to_string(),
+        "-l".to_string(),
+        target.user.clone(),
+        "-o".to_string(),
+        "ConnectTimeout=5".to_string(),
+    ];
+    ssh.extend(identity_options(target));
+    ssh.extend(multiplex_options(target, false));
+    ssh.extend(common_options(target));
+    ssh.iter()
+        .map(|word| rsync_quote(word))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Builds an `rsync` command that copies `sources` to `dest` over `ssh` through
+/// the local tunnel port, with `options` for rsync itself. Remote paths are
+/// written `localhost:PATH`.
+pub fn rsync_through_tunnel(
+    target: &Target,
+    options: &[String],
+    sources: &[OsString],
+    dest: &OsStr,
+) -> Result<Command, TunnelError> {
+    let mut cmd = command("rsync", target)?;
+    cmd.args(options)
+        .arg("-e")
+        .arg(rsync_shell(target))
+        .arg("--")
+        .args(sources)
+        .arg(dest);
+    Ok(cmd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tunnel_args_keep_user_and_host_out_of_option_position() {
+        let target = Target {
+            host: "-oProxyCommand=evil".to_string(),
+            user: "-oLocalCommand=evil".to_string(),
+            ..Target::default()
+        };
+        let args = tunnel_args(&target);
+        let separator = args.iter().position(|a| a == "--").unwrap();
+        assert_eq!(args[separator + 1], "-oProxyCommand=evil");
+        assert_eq!(args.len(), separator + 2);
+
+        let user = args.iter().position(|a| a == "-l").unwrap();
+        assert_eq!(args[user + 1], "-oLocalCommand=evil");
+    }
+
+    #[test]
+    fn test_tunnel_args_forward_to_the_remote_sshd_port() {
+        let target = Target {
+            host: "10.0.0.5".to_string(),
+            user: "pi".to_string(),
+            port: 2222,
+            remote_port: Some(2200),
+            proxy_jump: Some("bastion".to_string()),
+            ..Target::default()
+        };
+        let args = tunnel_args(&target).join(" ");
+        assert!(
+            args.starts_with("-fN -L 2222:localhost:2200 -l pi -p 2200 -J bastion "),
+            "{}",
+            args
+        );
+    }
+
+    #[test]
+    fn test_interactive_auth_multiplexes_over_the_tunnel() {
+        let mut target = Target {
+            host: "10.0.0.5".to_string(),
+            user: "pi".to_string(),
+            port: 2222,
+            ..Target::default()
+        };
+        assert!(multiplex_options(&target, true).is_empty());
+
+        target.interactive_auth = true;
+        let socket = path_option("ControlPath", &control_path(&target));
+        assert!(socket.ends_with("ssh_ip_tunnel-2222.sock\""));
+
+        let tunnel = tunnel_args(&target);
+        assert!(tunnel.contains(&"ControlMaster=yes".to_string()));
+        assert!(tunnel.contains(&socket));
+
+        let probe = through_tunnel(&target, &RemoteCommand::new("true")).unwrap();
+        let args = probe.get_args();
+        assert!(args.contains(&"ControlMaster=no".to_string()));
+        assert!(args.contains(&socket));
+
+        target.interactive_auth = false;
+        target.security_key = true;
+        assert_eq!(multiplex_options(&target, true).len(), 4);
+    }
+
+    #[test]
+    fn test_shell_asks_for_a_terminal_and_runs_no_command() {
+        let target = Target {
+            host: "10.0.0.5".to_string(),
+            user: "pi".to_string(),
+            port: 2222,
+            interactive_auth: true,
+            ..Target::default()
+        };
+        let shell = shell_through_tunnel(&target).unwrap();
+        let args = shell.get_args();
+        assert_eq!(args[..5], ["-t", "-p", "2222", "-l", "pi"]);
+        assert_eq!(args[args.len() - 2..], ["--", "localhost"]);
+        assert!(args.contains(&"ControlMaster=no".to_string()));
+    }
+
+    #[test]
+    fn test_paths_with_spaces_stay_one_word() {
+        let target = Target {
+            host: "10.0.0.5".to_string(),
+            user: "pi".to_string(),
+            port: 2222,
+            identity_file: Some("/home/me/My Keys/id_ed25519".into()),
+            interactive_auth: true,
+            ..Target::default()
+        };
+        assert_eq!(
+            identity_options(&target),
+            ["-o", "IdentityFile=\"/home/me/My Keys/id_ed25519\""]
+        );
+        let args = through_tunnel(&target, &RemoteCommand::new("true"))
+            .unwrap()
+            .get_args();
+        assert!(args.contains(&"IdentityFile=\"/home/me/My Keys/id_ed25519\"".to_string()));
+        assert!(args
+            .iter()
+            .any(|arg| arg.starts_with("ControlPath=\"/") && arg.ends_with(".sock\"")));
+    }
+
+    #[test]
+    fn test_rsync_runs_ssh_on_the_tunnel_port() {
+        let target = Target {
+            user: "o'brien".to_string(),
+            port: 2222,
+            ..Target::default()
+        };
+        let shell = rsync_shell(&target);
+        assert!(shell.starts_with("'ssh' '-p' '2222' '-l' 'o''brien' '-o' 'ConnectTimeout=5' "));
+        assert!(shell.contains("'UserKnownHostsFile=\"/"));
+    }
+
+    #[test]
+    fn test_host_keys_are_checked_under_the_device_name() {
+        let target = Target {
+            host: "10.0.0.5".to_string(),
+            user: "pi".to_string(),
+            port: 2222,
+            remote_port: Some(2200),
+            ..Target::default()
+        };
+        let probe = through_tunnel(&target, &RemoteCommand::new("true")).unwrap();
+        let args = probe.get_args();
+        assert!(args.contains(&"StrictHostKeyChecking=accept-new".to_string()));
+        assert!(args.contains(&"HostKeyAlias=[10.0.0.5]:2200".to_string()));
+        assert!(args
+            .iter()
+            .any(|arg| arg.starts_with("UserKnownHostsFile=\"") && arg.ends_with("known_hosts\"")));
+    }
+
+    #[test]
+    fn test_secure_mode_refuses_unknown_host_keys_and_legacy_algorithms() {
+        let mut target = Target {
+            host: "10.0.0.5".to_string(),
+            user: "pi".to_string(),
+            ..Target::default()
+        };
+        let lab = common_options(&target);
+        assert!(lab.contains(&"LogLevel=ERROR".to_string()));
+        assert!(!lab.iter().any(|opt| opt.starts_with("Ciphers=")));
+
+        target.secure = true;
+        let secure = common_options(&target);
+        assert!(secure.contains(&"StrictHostKeyChecking=yes".to_string()));
+        assert!(!secure.iter().any(|opt| opt.starts_with("LogLevel=")));
+        assert!(secure.contains(&SECURE_ALGORITHMS[1].to_string()));
+        assert!(!secure.iter().any(|opt| opt.contains("ssh-rsa,")));
+
+        // Probing for a pinned key records into a scratch file, so it stays permissive
+        let probe = host_key_probe(&target, Path::new("/tmp/scratch"), "ssh-ed25519").unwrap();
+        assert!(probe
+            .get_args()
+            .contains(&"StrictHostKeyChecking=accept-new".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_run_returns_stdout_or_how_the_command_failed() {
+        let dir =
+            std::env::temp_dir().join(format!("ssh_ip_tunnel-run-ssh-{}", std::process::id()));
+        let target = crate::simulate::test_target(&dir);
+        let run = |remote: RemoteCommand| {
+            let target = target.clone();
+            async move { run_through_tunnel(&target, &remote, Duration::from_secs(5)).await }
+        };
+
+        let hello = run(RemoteCommand::new("echo").arg("hello")).await.unwrap();
+        assert_eq!(hello, "hello\n");
+
+        let failed = run(RemoteCommand::new("exit").arg("3")).await.unwrap_err();
+        assert!(matches!(failed, RunError::Failed { code: Some(3), .. }));
+        assert_eq!(failed.to_string(), "exited with status 3");
+        assert!(matches!(
+            failed.into_retryable(TunnelError::Transfer),
+            TunnelError::Transfer(_)
+        ));
+
+        let lost = run(RemoteCommand::new("exit").arg("255"))
+            .await
+            .unwrap_err();
+        assert!(lost.is_connection_lost());
+        assert!(matches!(
+            lost.into_retryable(TunnelError::Transfer),
+            TunnelError::ConnectionLost(_)
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,232 @@
+//! ssh-agent awareness.
+//!
+//! Every connection the tool makes runs `ssh` without a terminal, so it can only
+//! log in with a key it can use unattended: one loaded in the agent, or an
+//! unencrypted key file. Before connecting, [`ensure_identity`] checks that one
+//! exists, optionally loading the login key into the agent with `ssh-add`.
+
+use crate::askpass;
+use crate::capabilities::{self, Tool};
+use crate::keys;
+use crate::paths;
+use crate::process;
+use crate::prompt;
+use crate::{Target, TunnelError};
+use std::path::PathBuf;
+use std::process::Stdio;
+use tracing::{debug, info, warn};
+
+/// Private keys ssh tries when no `IdentityFile` is configured, in its order
+const DEFAULT_IDENTITIES: &[&str] = &[
+    "id_rsa",
+    "id_ecdsa",
+    "id_ecdsa_sk",
+    "id_ed25519",
+    "id_ed25519_sk",
+];
+
+/// A key as listed by `ssh-add -l` or `ssh-keygen -l`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub bits: u32,
+    pub fingerprint: String,
+    pub comment: String,
+    pub key_type: String,
+}
+
+impl Identity {
+    /// Parses `<bits> <fingerprint> <comment> (<TYPE>)`
+    pub fn parse(line: &str) -> Option<Self> {
+        let (rest, key_type) = line.trim().rsplit_once(" (")?;
+        let key_type = key_type.strip_suffix(')')?;
+        let mut words = rest.splitn(3, ' ');
+        Some(Self {
+            bits: words.next()?.parse().ok()?,
+            fingerprint: words.next()?.to_string(),
+            comment: words.next().unwrap_or_default().to_string(),
+            key_type: key_type.to_string(),
+        })
+    }
+}
+
+/// Lists the identities loaded in the agent, or `None` when no agent is reachable
+pub async fn identities() -> Result<Option<Vec<Identity>>, TunnelError> {
+    if std::env::var_os("SSH_AUTH_SOCK").is_none_or(|sock| sock.is_empty()) {
+        debug!("SSH_AUTH_SOCK is not set; no ssh-agent");
+        return Ok(None);
+    }
+    if !capabilities::has(Tool::SshAdd) {
+        debug!("ssh-add was not found; ignoring ssh-agent");
+        return Ok(None);
+    }
+    let output = process::command("ssh-add")?
+        .arg("-l")
+        .output()
+        .await
+        .map_err(|e| TunnelError::NoIdentity(format!("Failed to run ssh-add: {}", e)))?;
+
+    // ssh-add -l exits 1 for an empty agent and 2 when it cannot reach one
+    match output.status.code() {
+        Some(0) => Ok(Some(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter_map(Identity::parse)
+                .collect(),
+        )),
+        Some(1) => Ok(Some(Vec::new())),
+        _ => {
+            debug!(
+                "ssh-agent unreachable: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// The private key ssh will log in to `target` with: its `IdentityFile`, else the first default key
+pub fn login_key(target: &Target) -> Option<PathBuf> {
+    if let Some(path) = &target.identity_file {
+        return Some(path.clone());
+    }
+    let ssh_dir = paths::home_dir().ok()?.join(".ssh");
+    DEFAULT_IDENTITIES
+        .iter()
+        .map(|name| ssh_dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Fingerprint of a key file, as `ssh-keygen -l` reports it, or from its
+/// `.pub` half when there is no ssh-keygen
+async fn fingerprint(path: &PathBuf) -> Result<Option<String>, TunnelError> {
+    if !capabilities::has(Tool::SshKeygen) {
+        let mut public = path.clone().into_os_string();
+        public.push(".pub");
+        return Ok(keys::read_public_key(public.as_ref())
+            .ok()
+            .map(|key| key.fingerprint()));
+    }
+    let output = process::command("ssh-keygen")?
+        .arg("-lf")
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| TunnelError::NoIdentity(format!("Failed to run ssh-keygen: {}", e)))?;
+    Ok(output
+        .status
+        .success()
+        .then(|| Identity::parse(&String::from_utf8_lossy(&output.stdout)))
+        .flatten()
+        .map(|identity| identity.fingerprint))
+}
+
+/// Loads `key` into the agent unless it is already there
+async fn add_key(key: &PathBuf, loaded: &[Identity]) -> Result<(), TunnelError> {
+    // ssh-add may ask for a passphrase
+    let _terminal = prompt::lock_terminal().await;
+    if let Some(fingerprint) = fingerprint(key).await? {
+        if loaded
+            .iter()
+            .any(|identity| identity.fingerprint == fingerprint)
+        {
+            debug!("{} is already loaded in ssh-agent", key.display());
+            return Ok(());
+        }
+    }
+
+    info!("Adding {} to ssh-agent...", key.display());
+    let mut ssh_add = process::command("ssh-add")?;
+    if prompt::is_batch() {
+        askpass::refuse(&mut ssh_add)?;
+    }
+    let status = ssh_add
+        .arg("--")
+        .arg(key)
+        .stdin(Stdio::inherit())
+        .status()
+        .await
+        .map_err(|e| TunnelError::NoIdentity(format!("Failed to run ssh-add: {}", e)))?;
+    if !status.success() {
+        return Err(TunnelError::NoIdentity(format!(
+            "ssh-add could not load {}",
+            key.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that `target` can be logged in to without a prompt, loading its key first if asked
+pub async fn ensure_identity(target: &Target) -> Result<(), TunnelError> {
+    // Simulated devices let anyone in
+    if target.simulated.is_some() {
+        return Ok(());
+    }
+    let mut agent = identities().await?;
+    let key = login_key(target);
+
+    if target.add_to_agent {
+        let (Some(loaded), Some(key)) = (&agent, &key) else {
+            return Err(TunnelError::NoIdentity(match key {
+                Some(_) if !capabilities::has(Tool::SshAdd) => {
+                    "--add-key needs ssh-add, which was not found in PATH".to_string()
+                }
+                Some(_) => "--add-key needs a running ssh-agent (SSH_AUTH_SOCK is not set or the agent is unreachable)".to_string(),
+                None => "--add-key found no private key to add; set IdentityFile in ~/.ssh/config".to_string(),
+            }));
+        };
+        add_key(key, loaded).await?;
+        agent = identities().await?;
+    }
+
+    match &agent {
+        Some(loaded) if !loaded.is_empty() => {
+            for identity in loaded {
+                debug!(
+                    "ssh-agent identity: {} {} {}",
+                    identity.key_type, identity.fingerprint, identity.comment
+                );
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+    if let Some(key) = key {
+        debug!("Logging in with {}", key.display());
+        return Ok(());
+    }
+    if target.interactive_auth || target.password.is_some() {
+        // ssh will ask on the terminal, or be answered with the password
+        return Ok(());
+    }
+
+    let hint = "start ssh-agent and load a key with ssh-add (or pass --add-key), or set IdentityFile in ~/.ssh/config";
+    if prompt::is_interactive() {
+        warn!(
+            "No SSH identity found; ssh will fall back to prompting. To avoid this, {}",
+            hint
+        );
+        Ok(())
+    } else {
+        Err(TunnelError::NoIdentity(format!(
+            "no key in ssh-agent and no private key in ~/.ssh; {}",
+            hint
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_parse() {
+        let identity =
+            Identity::parse("256 SHA256:abc/def+ghi pi@lab (laptop) (ED25519)\n").unwrap();
+        assert_eq!(identity.bits, 256);
+        assert_eq!(identity.fingerprint, "SHA256:abc/def+ghi");
+        assert_eq!(identity.comment, "pi@lab (laptop)");
+        assert_eq!(identity.key_type, "ED25519");
+
+        assert!(Identity::parse("The agent has no identities.").is_none());
+    }
+}
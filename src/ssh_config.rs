@@ -0,0 +1,354 @@
+//! Reading host aliases from the user's OpenSSH client configuration.
+//!
+//! Only what the tool needs to reach a device is extracted: `HostName`, `User`,
+//! `Port`, `IdentityFile` and `ProxyJump`. As in OpenSSH, the first value found
+//! for a keyword wins, `Host` patterns support `*`, `?` and `!` negation, and
+//! `Include` pulls in further files. `Match` blocks are not evaluated and their
+//! contents are ignored.
+
+use crate::paths;
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Guards against `Include` cycles
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Settings for one host as resolved from the configuration
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostOptions {
+    pub host_name: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_file: Option<String>,
+    pub proxy_jump: Option<String>,
+}
+
+/// A run of keyword/value pairs in file order, applying to hosts that match
+/// every pattern list in `conditions` (the `Host` line, plus those of any
+/// blocks it was included from)
+#[derive(Debug, Clone)]
+struct Block {
+    conditions: Vec<Vec<String>>,
+    options: Vec<(String, String)>,
+}
+
+impl Block {
+    fn new(conditions: Vec<Vec<String>>) -> Self {
+        Self {
+            conditions,
+            options: Vec::new(),
+        }
+    }
+
+    fn applies_to(&self, host: &str) -> bool {
+        self.conditions
+            .iter()
+            .all(|patterns| matches_host(patterns, host))
+    }
+}
+
+/// A parsed client configuration
+#[derive(Debug, Clone, Default)]
+pub struct SshConfig {
+    blocks: Vec<Block>,
+}
+
+impl SshConfig {
+    /// Loads `~/.ssh/config`, or an empty configuration if there is none
+    pub fn load() -> Result<Self> {
+        let Ok(ssh_dir) = paths::home_dir().map(|home| home.join(".ssh")) else {
+            return Ok(Self::default());
+        };
+        let path = ssh_dir.join("config");
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        debug!("Reading {}", path.display());
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        Self::parse(&contents, &ssh_dir)
+            .map_err(|e| anyhow::anyhow!("In {}: {}", path.display(), e))
+    }
+
+    /// Parses configuration text; relative `Include` paths resolve against `include_dir`
+    pub fn parse(contents: &str, include_dir: &Path) -> Result<Self> {
+        let mut config = Self::default();
+        config.parse_into(contents, include_dir, 0)?;
+        Ok(config)
+    }
+
+    fn read_file(&mut self, path: &Path, include_dir: &Path, depth: usize) -> Result<()> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        self.parse_into(&contents, include_dir, depth)
+            .map_err(|e| anyhow::anyhow!("In {}: {}", path.display(), e))
+    }
+
+    fn parse_into(&mut self, contents: &str, include_dir: &Path, depth: usize) -> Result<()> {
+        // Options before the first Host line apply to every host
+        self.blocks.push(Block::new(Vec::new()));
+
+        for (index, line) in contents.lines().enumerate() {
+            let Some((keyword, value)) = split_line(line) else {
+                continue;
+            };
+            match keyword.as_str() {
+                "host" => self.blocks.push(Block::new(vec![value
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect()])),
+                // An empty pattern list never matches, so nothing applies until the next Host line
+                "match" => self.blocks.push(Block::new(vec![Vec::new()])),
+                "include" => {
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        anyhow::bail!("line {}: Include nested too deeply", index + 1);
+                    }
+                    let enclosing = self
+                        .blocks
+                        .last()
+                        .map(|b| b.conditions.clone())
+                        .unwrap_or_default();
+                    for file in value
+                        .split_whitespace()
+                        .flat_map(|p| expand_include(p, include_dir))
+                    {
+                        let mut included = Self::default();
+                        included.read_file(&file, include_dir, depth + 1)?;
+                        for mut block in included.blocks {
+                            block.conditions.splice(0..0, enclosing.iter().cloned());
+                            self.blocks.push(block);
+                        }
+                    }
+                    // Options after the Include still belong to the enclosing block
+                    self.blocks.push(Block::new(enclosing));
+                }
+                _ => {
+                    if let Some(block) = self.blocks.last_mut() {
+                        block.options.push((keyword, value));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves the options for `host`, first value winning
+    pub fn lookup(&self, host: &str) -> HostOptions {
+        let mut options = HostOptions::default();
+        let matching = self.blocks.iter().filter(|b| b.applies_to(host));
+        for (keyword, value) in matching.flat_map(|b| b.options.iter()) {
+            match keyword.as_str() {
+                "hostname" => set_once(&mut options.host_name, || expand_tokens(value, host)),
+                "user" => set_once(&mut options.user, || value.clone()),
+                "port" if options.port.is_none() => options.port = value.parse().ok(),
+                "identityfile" => set_once(&mut options.identity_file, || value.clone()),
+                "proxyjump" => set_once(&mut options.proxy_jump, || value.clone()),
+                _ => {}
+            }
+        }
+        options
+    }
+}
+
+fn set_once(slot: &mut Option<String>, value: impl FnOnce() -> String) {
+    if slot.is_none() {
+        *slot = Some(value());
+    }
+}
+
+/// Splits `Keyword value` or `Keyword=value`, lowercasing the keyword and unquoting the value
+fn split_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let split = line.find(|c: char| c.is_whitespace() || c == '=')?;
+    let keyword = line[..split].to_ascii_lowercase();
+    let value = line[split..]
+        .trim_start_matches(|c: char| c.is_whitespace())
+        .strip_prefix('=')
+        .unwrap_or_else(|| line[split..].trim_start())
+        .trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    Some((keyword, value.to_string()))
+}
+
+/// Whether `host` matches a `Host` pattern list: any positive match and no negated one
+fn matches_host(patterns: &[String], host: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        match pattern.strip_prefix('!') {
+            Some(negated) if glob_match(negated, host) => return false,
+            Some(_) => {}
+            None => matched |= glob_match(pattern, host),
+        }
+    }
+    matched
+}
+
+/// Matches `*` and `?` wildcards
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c.eq_ignore_ascii_case(&text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Expands the `%h` and `%%` tokens allowed in `HostName`
+fn expand_tokens(value: &str, host: &str) -> String {
+    value
+        .replace("%%", "\0")
+        .replace("%h", host)
+        .replace('\0', "%")
+}
+
+/// Resolves an `Include` argument to files, expanding `~` and a `*` in the file name.
+/// Like ssh, a file that doesn't exist is skipped rather than an error.
+fn expand_include(pattern: &str, include_dir: &Path) -> Vec<PathBuf> {
+    let path = match paths::expand_tilde(pattern) {
+        Ok(path) if path.is_absolute() => path,
+        _ => include_dir.join(pattern),
+    };
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return Vec::new();
+    };
+    if !name.contains(['*', '?']) {
+        return if path.exists() {
+            vec![path]
+        } else {
+            Vec::new()
+        };
+    }
+    let Some(dir) = path.parent() else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|file| {
+            file.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| glob_match(name, n))
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_first_value_wins_across_blocks() {
+        let config = SshConfig::parse(
+            r#"
+# Lab devices
+Host mypi pi-*
+    HostName %h.lab.example.com
+    User pi
+    IdentityFile ~/.ssh/lab_ed25519
+
+Host pi-7 !pi-8
+    Port 2200
+
+Host *
+    User=nobody
+    ProxyJump "bastion.example.com"
+
+Match host mypi
+    User ignored
+"#,
+            Path::new("/nonexistent"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.lookup("pi-7"),
+            HostOptions {
+                host_name: Some("pi-7.lab.example.com".to_string()),
+                user: Some("pi".to_string()),
+                port: Some(2200),
+                identity_file: Some("~/.ssh/lab_ed25519".to_string()),
+                proxy_jump: Some("bastion.example.com".to_string()),
+            }
+        );
+        assert_eq!(config.lookup("pi-8").port, None);
+        assert_eq!(config.lookup("mypi").user.as_deref(), Some("pi"));
+        assert_eq!(config.lookup("10.0.0.1").user.as_deref(), Some("nobody"));
+        assert_eq!(config.lookup("10.0.0.1").host_name, None);
+    }
+
+    #[test]
+    fn test_include_is_scoped_to_the_including_block() {
+        let dir = std::env::temp_dir().join(format!("ssh-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("config.d")).unwrap();
+        std::fs::write(
+            dir.join("config.d/lab.conf"),
+            "User lab\nHost pi*\n  Port 2022\n",
+        )
+        .unwrap();
+
+        let config = SshConfig::parse(
+            "Host pi1 pi2\n  Include config.d/*.conf\n  HostName 10.0.0.1\nHost jetson\n  Include config.d/lab.conf\n",
+            &dir,
+        )
+        .unwrap();
+        let pi1 = config.lookup("pi1");
+        assert_eq!(pi1.user.as_deref(), Some("lab"));
+        assert_eq!(pi1.host_name.as_deref(), Some("10.0.0.1"));
+        assert_eq!(pi1.port, Some(2022));
+        // Host pi* inside the jetson block requires both to match
+        assert_eq!(config.lookup("jetson").port, None);
+        assert_eq!(config.lookup("jetson").user.as_deref(), Some("lab"));
+        assert_eq!(config.lookup("pi3"), HostOptions::default());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_include_is_skipped() {
+        let dir = std::env::temp_dir().join(format!("ssh-config-missing-{}", std::process::id()));
+        let config = SshConfig::parse(
+            "Include does-not-exist.conf missing.d/lab.conf\nHost pi\n  User pi\n",
+            &dir,
+        )
+        .unwrap();
+        assert_eq!(config.lookup("pi").user.as_deref(), Some("pi"));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("pi-*", "pi-12"));
+        assert!(glob_match("*.lab", "a.b.lab"));
+        assert!(glob_match("pi-?", "PI-1"));
+        assert!(!glob_match("pi-?", "pi-12"));
+        assert!(!glob_match("pi", "pi2"));
+    }
+}
@@ -0,0 +1,312 @@
+//! Changing a device's sshd without locking ourselves out.
+//!
+//! sshd leaves established sessions alone when it restarts. [`change`] relies
+//! on that: before the change it opens a guard session, which snapshots
+//! sshd_config and sshd_config.d and then waits. The change is made and sshd
+//! restarted, then a fresh login is tried through the tunnel. Only once one
+//! gets in within [`VERIFY_TIMEOUT`] is the guard told to keep the change;
+//! otherwise, and if the guard's connection goes away, it puts the snapshot
+//! back itself, without needing a new login.
+
+use crate::authorized_keys;
+use crate::certs;
+use crate::executor::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use crate::keys;
+use crate::paths;
+use crate::shell::{self, RemoteCommand};
+use crate::ssh;
+use crate::{Target, TunnelError};
+use std::future::Future;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::time::{sleep, timeout, Instant};
+use tracing::{debug, info, warn};
+
+/// How long sshd may take to let a fresh login in after a change
+pub const VERIFY_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Pause between fresh logins while sshd restarts
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Upper bound for one fresh login, and for each step of the guard
+const STEP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Snapshots sshd_config and sshd_config.d, prints `ready` and waits for a
+/// line on stdin. `keep` deletes the snapshot and prints `kept`; anything
+/// else, end of input included, puts the snapshot back without files added
+/// since, restarts sshd and prints `restored`.
+pub const GUARD_SCRIPT: &str = r#"set -e
+PATH="$PATH:/usr/sbin:/sbin"
+dir=/etc/ssh
+snapshot=$dir/sshd_config.ssh-ip-tunnel-snapshot.tar
+files=sshd_config
+if [ -d "$dir/sshd_config.d" ]; then files="$files sshd_config.d"; fi
+as_root tar -C "$dir" -cf "$snapshot" $files
+echo ready
+read -r decision || decision=
+if [ "$decision" = keep ]; then
+  as_root rm -f "$snapshot"
+  echo kept
+  exit 0
+fi
+as_root rm -rf "$dir/sshd_config.d"
+as_root tar -C "$dir" -xpf "$snapshot"
+as_root rm -f "$snapshot"
+as_root systemctl reload ssh 2>/dev/null || as_root systemctl reload sshd 2>/dev/null || as_root kill -HUP "$(cat /var/run/sshd.pid)"
+echo restored"#;
+
+/// Who a fresh login logs in as, and with what
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Login {
+    /// The target's own login, with whatever it authenticates with
+    Target,
+    /// The login user with nothing but the target's key or certificate
+    KeyAlone,
+}
+
+/// Logs in afresh through the tunnel, bypassing any connection master, and runs `true`
+pub async fn fresh_login(target: &Target, login: Login) -> Result<(), TunnelError> {
+    match login {
+        Login::Target => {
+            let options = ["-o".to_string(), "ControlPath=none".to_string()];
+            let probe = RemoteCommand::new("true");
+            ssh::run(
+                ssh::through_tunnel_with(target, &options, &probe)?,
+                STEP_TIMEOUT,
+            )
+            .await
+            .map(drop)
+            .map_err(|e| e.into_error(TunnelError::Sshd))
+        }
+        Login::KeyAlone => {
+            // Whoever logs in after the change is the login user, even with --target-user
+            let target = &Target {
+                target_user: None,
+                ..target.clone()
+            };
+            let key_path = paths::expand_tilde(&target.key_path)?;
+            if keys::read_public_key(&key_path)?.is_certificate() {
+                certs::verify_login(target, &key_path).await
+            } else {
+                let (login, options) = authorized_keys::key_login(target, &key_path);
+                authorized_keys::verify_login(&login, &options).await
+            }
+        }
+    }
+}
+
+/// Retries [`fresh_login`] until it works or [`VERIFY_TIMEOUT`] has passed
+async fn verify(target: &Target, login: Login) -> Result<(), TunnelError> {
+    let deadline = Instant::now() + VERIFY_TIMEOUT;
+    loop {
+        match fresh_login(target, login).await {
+            Ok(()) => return Ok(()),
+            Err(e) if Instant::now() + RETRY_INTERVAL >= deadline => return Err(e),
+            Err(e) => {
+                debug!("Fresh login to {} failed, retrying: {}", target.host, e);
+                sleep(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// [`GUARD_SCRIPT`] with what it needs to run
+fn guard_command() -> RemoteCommand {
+    RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(format!("{}\n{}", shell::AS_ROOT, GUARD_SCRIPT))
+}
+
+/// A session running [`GUARD_SCRIPT`]. Dropping it ends the session, which
+/// makes the device restore its snapshot.
+struct Guard {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+impl Guard {
+    /// Opens the session and waits until the snapshot is taken
+    async fn start(target: &Target) -> Result<Self, TunnelError> {
+        Self::open(ssh::through_tunnel(target, &guard_command())?).await
+    }
+
+    /// Runs the session `command`, waiting until the snapshot is taken
+    async fn open(mut command: Command) -> Result<Self, TunnelError> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| TunnelError::Sshd(e.to_string()))?;
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(TunnelError::Sshd(
+                "no pipes to the guard session".to_string(),
+            ));
+        };
+        let mut guard = Guard {
+            child,
+            stdin,
+            stdout: BufReader::new(stdout).lines(),
+        };
+        guard.expect("ready", "snapshotting sshd_config").await?;
+        Ok(guard)
+    }
+
+    /// Tells the device to keep the change (`keep`) or restore the snapshot
+    async fn finish(mut self, decision: &str) -> Result<(), TunnelError> {
+        let sent = self
+            .stdin
+            .write_all(format!("{}\n", decision).as_bytes())
+            .await;
+        if let Err(e) = sent {
+            return Err(TunnelError::Sshd(format!(
+                "the guard session is gone ({})",
+                e
+            )));
+        }
+        let (expected, doing) = match decision {
+            "keep" => ("kept", "keeping the change"),
+            _ => ("restored", "restoring the previous sshd configuration"),
+        };
+        self.expect(expected, doing).await
+    }
+
+    /// Waits for the line `expected`, failing with the session's stderr
+    async fn expect(&mut self, expected: &str, doing: &str) -> Result<(), TunnelError> {
+        let line = timeout(STEP_TIMEOUT, self.stdout.next_line()).await;
+        if let Ok(Ok(Some(line))) = &line {
+            if line == expected {
+                return Ok(());
+            }
+        }
+        let _ = self.child.start_kill();
+        let mut stderr = String::new();
+        if let Some(mut pipe) = self.child.stderr.take() {
+            let _ = timeout(RETRY_INTERVAL, pipe.read_to_string(&mut stderr)).await;
+        }
+        let reason = match line {
+            Err(_) => "timeout".to_string(),
+            Ok(_) if stderr.trim().is_empty() => "the guard session ended".to_string(),
+            Ok(_) => stderr.trim().to_string(),
+        };
+        Err(TunnelError::Sshd(format!(
+            "{} failed: {} (root or passwordless sudo is needed)",
+            doing, reason
+        )))
+    }
+}
+
+/// Makes the sshd change `apply`, which restarts sshd itself, then checks
+/// that `login` still gets in with a fresh connection. The previous sshd
+/// configuration is restored when it doesn't, or when `apply` fails.
+pub async fn change<T>(
+    target: &Target,
+    login: Login,
+    apply: impl Future<Output = Result<T, TunnelError>>,
+) -> Result<T, TunnelError> {
+    let guard = Guard::start(target).await?;
+    let value = match apply.await {
+        Ok(value) => value,
+        Err(e) => {
+            // The change's own error says what went wrong, once the device is as it was
+            return match guard.finish("restore").await {
+                Ok(()) => {
+                    info!(
+                        "Restored the previous sshd configuration of {}",
+                        target.host
+                    );
+                    Err(e)
+                }
+                Err(restore) => Err(TunnelError::Sshd(format!("{}; {}", e, restore))),
+            };
+        }
+    };
+
+    info!("Checking that {} lets a fresh login in...", target.host);
+    match verify(target, login).await {
+        Ok(()) => guard.finish("keep").await.map(|()| value),
+        Err(e) => {
+            warn!(
+                "{} refuses fresh logins after the change; restoring its sshd configuration",
+                target.host
+            );
+            let reason = match e {
+                TunnelError::Sshd(reason) => reason,
+                e => e.to_string(),
+            };
+            let outcome = match guard.finish("restore").await {
+                Ok(()) => "the previous sshd configuration was restored".to_string(),
+                Err(restore) => format!("{}; fix it over the open tunnel", restore),
+            };
+            Err(TunnelError::Sshd(format!(
+                "no fresh login got in within {}s ({}); {}",
+                VERIFY_TIMEOUT.as_secs(),
+                reason,
+                outcome
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulate::Device;
+    use std::path::Path;
+
+    /// The guard session on the fake device kept in `state`
+    fn session(state: &Path) -> Command {
+        let mut command = Command::simulated(state.to_path_buf());
+        command
+            .args(["-l", "pi", "--", "localhost"])
+            .arg(guard_command().to_shell_string());
+        command
+    }
+
+    fn set_drop_in(state: &Path, contents: &str) {
+        let device = Device {
+            sshd_drop_in: contents.to_string(),
+            ..Device::default()
+        };
+        std::fs::write(state, serde_json::to_string(&device).unwrap()).unwrap();
+    }
+
+    fn drop_in(state: &Path) -> String {
+        let contents = std::fs::read_to_string(state).unwrap();
+        serde_json::from_str::<Device>(&contents)
+            .unwrap()
+            .sshd_drop_in
+    }
+
+    #[tokio::test]
+    async fn test_guard_keeps_or_restores_the_change() {
+        let state =
+            std::env::temp_dir().join(format!("ssh_ip_tunnel-guard-{}.json", std::process::id()));
+        set_drop_in(&state, "");
+
+        let guard = Guard::open(session(&state)).await.unwrap();
+        set_drop_in(&state, "PasswordAuthentication no\n");
+        guard.finish("restore").await.unwrap();
+        assert_eq!(drop_in(&state), "");
+
+        let guard = Guard::open(session(&state)).await.unwrap();
+        set_drop_in(&state, "PasswordAuthentication no\n");
+        guard.finish("keep").await.unwrap();
+        assert_eq!(drop_in(&state), "PasswordAuthentication no\n");
+        let _ = std::fs::remove_file(&state);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_guard_that_cannot_snapshot_says_why() {
+        let mut command = tokio::process::Command::new("sh");
+        command.args(["-c", "echo 'tar: Permission denied' >&2; exit 2"]);
+        let e = Guard::open(command.into()).await.err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "Changing sshd failed: snapshotting sshd_config failed: tar: Permission denied \
+             (root or passwordless sudo is needed)"
+        );
+    }
+}
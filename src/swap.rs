@@ -0,0 +1,198 @@
+//! Swap for memory-constrained boards.
+//!
+//! With `swap` set in the configuration (or `--swap`), `up` gives boards with
+//! up to [`MEMORY_CONSTRAINED_MB`] of RAM as much swap as they have RAM, capped
+//! at [`MAX_SWAP_MB`]: compressed swap in RAM (zram) where the kernel has it,
+//! otherwise a swapfile. Boards that already have that much swap are left
+//! alone. The swapfile goes into /etc/fstab; zram is set up again at boot by
+//! a systemd unit when the board runs systemd, and lasts until reboot otherwise.
+
+use crate::shell::{self, RemoteCommand};
+use crate::ssh::{self, RunError};
+use crate::{Target, TunnelError};
+use serde::Serialize;
+use std::time::Duration;
+use tracing::info;
+
+pub use crate::pure::swap::SwapMode;
+
+/// Boards with more RAM than this don't get swap
+pub const MEMORY_CONSTRAINED_MB: u64 = 2048;
+
+/// Most swap set up on any board
+pub const MAX_SWAP_MB: u64 = 2048;
+
+/// Where the swapfile goes
+const SWAPFILE: &str = "/swapfile";
+
+/// Writing a 2 GB swapfile to an SD card takes a while
+const SWAP_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Sets up swap of kind `$1` (auto, zram or file) for boards with at most `$2`
+/// MB of RAM, sized like RAM up to `$3` MB, with the swapfile at `$4`.
+/// Prints `ram <MB>`, then `skip <reason> <MB>` or `set <kind> <MB> <persistence>`.
+pub const SWAP_SCRIPT: &str = r#"set -e
+ram=$(( $(awk '/^MemTotal:/ {print $2}' /proc/meminfo) / 1024 ))
+swap=$(( $(awk '/^SwapTotal:/ {print $2}' /proc/meminfo) / 1024 ))
+size=$ram
+[ "$size" -le "$3" ] || size=$3
+echo "ram $ram"
+if [ "$ram" -gt "$2" ]; then echo "skip not_needed $swap"; exit 0; fi
+if [ "$swap" -ge "$size" ]; then echo "skip present $swap"; exit 0; fi
+kind=$1
+if [ "$kind" = auto ]; then
+  if [ -e /sys/class/zram-control ] || as_root modprobe zram 2>/dev/null; then kind=zram; else kind=file; fi
+fi
+if [ "$kind" = zram ]; then
+  [ -e /sys/class/zram-control ] || as_root modprobe zram
+  if [ "$(cat /sys/block/zram0/disksize 2>/dev/null)" = 0 ]; then
+    dev=zram0
+  else
+    dev=zram$(as_root cat /sys/class/zram-control/hot_add)
+  fi
+  echo "${size}M" | as_root tee /sys/block/$dev/disksize >/dev/null
+  as_root mkswap /dev/$dev >/dev/null
+  as_root swapon -p 100 /dev/$dev
+  persistence=until_reboot
+  if [ -d /run/systemd/system ]; then
+    printf '[Unit]\nDescription=Compressed swap in RAM\n\n[Service]\nType=oneshot\nRemainAfterExit=yes\nExecStart=/bin/sh -c "modprobe zram && echo %sM > /sys/block/zram0/disksize && mkswap /dev/zram0 && swapon -p 100 /dev/zram0"\n\n[Install]\nWantedBy=multi-user.target\n' "$size" \
+      | as_root tee /etc/systemd/system/ssh-ip-tunnel-zram.service >/dev/null
+    as_root systemctl daemon-reload
+    as_root systemctl enable ssh-ip-tunnel-zram.service >/dev/null 2>&1
+    persistence=persistent
+  fi
+  echo "set zram $size $persistence"
+else
+  if [ -e "$4" ]; then echo "$4 already exists but is not in use as swap" >&2; exit 1; fi
+  as_root fallocate -l "${size}M" "$4" 2>/dev/null || as_root dd if=/dev/zero of="$4" bs=1M count="$size" 2>/dev/null
+  as_root chmod 600 "$4"
+  as_root mkswap "$4" >/dev/null
+  as_root swapon "$4"
+  grep -q "^$4 " /etc/fstab 2>/dev/null || echo "$4 none swap sw 0 0" | as_root tee -a /etc/fstab >/dev/null
+  echo "set file $size persistent"
+fi"#;
+
+/// What the swap step did
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "status")]
+#[non_exhaustive]
+pub enum SwapOutcome {
+    /// The board has more RAM than `MEMORY_CONSTRAINED_MB`
+    NotNeeded { ram_mb: u64, swap_mb: u64 },
+    /// The board already had enough swap
+    Present { ram_mb: u64, swap_mb: u64 },
+    Configured {
+        ram_mb: u64,
+        kind: String,
+        size_mb: u64,
+        /// Whether the swap comes back after a reboot
+        persistent: bool,
+    },
+}
+
+impl SwapOutcome {
+    /// One-line summary, e.g. `512 MB zram`
+    pub fn summary(&self) -> String {
+        match self {
+            SwapOutcome::NotNeeded { .. } => "not needed".to_string(),
+            SwapOutcome::Present { swap_mb, .. } => format!("{} MB present", swap_mb),
+            SwapOutcome::Configured {
+                kind,
+                size_mb,
+                persistent,
+                ..
+            } => format!(
+                "{} MB {}{}",
+                size_mb,
+                kind,
+                if *persistent { "" } else { " until reboot" }
+            ),
+        }
+    }
+
+    /// Parses the output of [`SWAP_SCRIPT`]
+    fn parse(output: &str) -> Option<Self> {
+        let mut ram_mb = None;
+        for line in output.lines() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["ram", ram] => ram_mb = ram.parse().ok(),
+                ["skip", reason, swap] => {
+                    let (ram_mb, swap_mb) = (ram_mb?, swap.parse().ok()?);
+                    return match *reason {
+                        "not_needed" => Some(SwapOutcome::NotNeeded { ram_mb, swap_mb }),
+                        "present" => Some(SwapOutcome::Present { ram_mb, swap_mb }),
+                        _ => None,
+                    };
+                }
+                ["set", kind, size, persistence] => {
+                    return Some(SwapOutcome::Configured {
+                        ram_mb: ram_mb?,
+                        kind: kind.to_string(),
+                        size_mb: size.parse().ok()?,
+                        persistent: *persistence == "persistent",
+                    })
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+/// Sets up swap on `target` as its `swap` mode asks
+pub async fn configure(target: &Target) -> Result<SwapOutcome, TunnelError> {
+    info!("Checking memory and swap on {}...", target.host);
+    let command = RemoteCommand::new("sh")
+        .arg("-c")
+        .arg(format!("{}\n{}", shell::AS_ROOT, SWAP_SCRIPT))
+        .arg("sh")
+        .arg(target.swap.name())
+        .arg(MEMORY_CONSTRAINED_MB.to_string())
+        .arg(MAX_SWAP_MB.to_string())
+        .arg(SWAPFILE);
+    let stdout = ssh::run_through_tunnel(target, &command, SWAP_TIMEOUT)
+        .await
+        .map_err(|e| match e {
+            RunError::Failed { stderr, .. } => {
+                TunnelError::Swap(format!("{} (root or passwordless sudo is needed)", stderr))
+            }
+            e => e.into_error(TunnelError::Swap),
+        })?;
+    let outcome = SwapOutcome::parse(&stdout)
+        .ok_or_else(|| TunnelError::Swap(format!("unexpected output {:?}", stdout.trim())))?;
+    info!("Swap on {}: {}", target.host, outcome.summary());
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_swap_outcome() {
+        assert_eq!(
+            SwapOutcome::parse("ram 483\nset zram 483 persistent\n"),
+            Some(SwapOutcome::Configured {
+                ram_mb: 483,
+                kind: "zram".to_string(),
+                size_mb: 483,
+                persistent: true,
+            })
+        );
+        assert_eq!(
+            SwapOutcome::parse("ram 7820\nskip not_needed 0\n"),
+            Some(SwapOutcome::NotNeeded {
+                ram_mb: 7820,
+                swap_mb: 0
+            })
+        );
+        assert_eq!(SwapOutcome::parse("skip present 100\n"), None);
+        assert_eq!(
+            SwapOutcome::parse("ram 483\nset file 483 until_reboot\n")
+                .unwrap()
+                .summary(),
+            "483 MB file until reboot"
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! Telling systemd how a `Type=notify` service is doing.
+//!
+//! `up --watch` turns this on. systemd passes the socket in `NOTIFY_SOCKET`;
+//! without it, or on other systems, every call here does nothing. The phases
+//! of `up` are reported as `STATUS=`, `READY=1` is sent as soon as the tunnel
+//! has passed its check, before the key is deployed, and `WATCHDOG=1` after
+//! each later check that passes, so a tunnel that stops answering without ssh
+//! exiting is restarted too.
+
+use crate::phase::Phase;
+use crate::Target;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::debug;
+
+/// How often `--watch` checks the tunnel when systemd asks for no watchdog
+pub const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Sends notifications from then on, if systemd is listening
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Reports the tunnel ready, with `status`
+pub fn ready(status: &str) {
+    notify(&format!("READY=1\nSTATUS={}", status));
+}
+
+/// Reports what the service is doing now
+pub fn status(status: &str) {
+    notify(&format!("STATUS={}", status));
+}
+
+/// Reports that `phase` of `up` has started for `target`
+pub fn phase(phase: Phase, target: &Target) {
+    if ENABLED.load(Ordering::Relaxed) {
+        status(&format!("{} ({})", describe(phase), target.host));
+    }
+}
+
+/// Tells the watchdog the tunnel still answers
+pub fn watchdog() {
+    notify("WATCHDOG=1");
+}
+
+/// How often to check the tunnel: twice per watchdog period if systemd set one
+pub fn check_interval() -> Duration {
+    let pid_matches = std::env::var("WATCHDOG_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_none_or(|pid| pid == std::process::id());
+    std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| *usec > 0 && pid_matches)
+        .map_or(DEFAULT_CHECK_INTERVAL, |usec| {
+            Duration::from_micros(usec / 2)
+        })
+}
+
+fn describe(phase: Phase) -> &'static str {
+    match phase {
+        Phase::Tunnel => "Opening the tunnel",
+        Phase::Validate => "Checking the tunnel",
+        Phase::Clock => "Setting the clock",
+        Phase::Arch => "Checking the architecture",
+        Phase::Key => "Deploying the key",
+        Phase::Hardware => "Configuring hardware",
+        Phase::Swap => "Setting up swap",
+        Phase::Provision => "Provisioning",
+        Phase::Harden => "Hardening sshd",
+    }
+}
+
+fn notify(state: &str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&socket, state) {
+        debug!("Notifying systemd failed: {}", e);
+    }
+}
+
+#[cfg(unix)]
+fn send(socket: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let datagram = UnixDatagram::unbound()?;
+    // A leading @ names a socket in the abstract namespace, which only Linux has
+    match socket.as_encoded_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(state.as_bytes(), &address)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Ok(()),
+        None => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_socket: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn test_notifications_reach_the_socket() {
+        let path =
+            std::env::temp_dir().join(format!("ssh_ip_tunnel-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str(), "READY=1\nSTATUS=Tunnel up").unwrap();
+        let mut buffer = [0; 64];
+        let received = listener.recv(&mut buffer).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&buffer[..received], b"READY=1\nSTATUS=Tunnel up");
+    }
+}
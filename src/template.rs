@@ -0,0 +1,345 @@
+//! A small template language for device profiles, filled in from device facts.
+//!
+//! `{{ name }}` inserts a value; `{% if <condition> %}...{% else %}...{% endif %}`
+//! keeps one branch. A condition is a name on its own (true when the value is
+//! not empty) or `name <op> literal`, where `op` is `==`, `!=` or `contains`
+//! for strings in double quotes, and `<`, `<=`, `>` or `>=` for numbers.
+//! Unknown names are errors, so a typo can't quietly render as nothing.
+
+use std::collections::BTreeMap;
+
+/// Values a template can refer to
+pub type Context = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Value(String),
+    If {
+        condition: Condition,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    NotEmpty(String),
+    Text {
+        name: String,
+        op: TextOp,
+        literal: String,
+    },
+    Number {
+        name: String,
+        op: NumberOp,
+        literal: f64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TextOp {
+    Equal,
+    NotEqual,
+    Contains,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NumberOp {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// Whether `value` contains any template syntax
+pub fn is_template(value: &str) -> bool {
+    value.contains("{{") || value.contains("{%")
+}
+
+/// Renders `template` with `context`
+pub fn render(template: &str, context: &Context) -> Result<String, String> {
+    if !is_template(template) {
+        return Ok(template.to_string());
+    }
+    let nodes = parse(template)?;
+    let mut rendered = String::new();
+    render_nodes(&nodes, context, &mut rendered)?;
+    Ok(rendered)
+}
+
+fn render_nodes(nodes: &[Node], context: &Context, out: &mut String) -> Result<(), String> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Value(name) => out.push_str(lookup(context, name)?),
+            Node::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let branch = if evaluate(condition, context)? {
+                    then
+                } else {
+                    otherwise
+                };
+                render_nodes(branch, context, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn lookup<'a>(context: &'a Context, name: &str) -> Result<&'a str, String> {
+    context.get(name).map(String::as_str).ok_or_else(|| {
+        let known: Vec<&str> = context.keys().map(String::as_str).collect();
+        format!("unknown name '{}' (known: {})", name, known.join(", "))
+    })
+}
+
+fn evaluate(condition: &Condition, context: &Context) -> Result<bool, String> {
+    Ok(match condition {
+        Condition::NotEmpty(name) => !lookup(context, name)?.is_empty(),
+        Condition::Text { name, op, literal } => {
+            let value = lookup(context, name)?;
+            match op {
+                TextOp::Equal => value == literal,
+                TextOp::NotEqual => value != literal,
+                TextOp::Contains => value.contains(literal.as_str()),
+            }
+        }
+        Condition::Number { name, op, literal } => {
+            let raw = lookup(context, name)?;
+            let value: f64 = raw
+                .parse()
+                .map_err(|_| format!("'{}' is {:?}, not a number", name, raw))?;
+            match op {
+                NumberOp::Less => value < *literal,
+                NumberOp::LessOrEqual => value <= *literal,
+                NumberOp::Greater => value > *literal,
+                NumberOp::GreaterOrEqual => value >= *literal,
+            }
+        }
+    })
+}
+
+/// An open `{% if %}` while parsing
+struct Frame {
+    condition: Condition,
+    then: Vec<Node>,
+    in_else: bool,
+}
+
+fn parse(template: &str) -> Result<Vec<Node>, String> {
+    let mut top = Vec::new();
+    let mut open: Vec<(Frame, Vec<Node>)> = Vec::new();
+    let mut rest = template;
+
+    loop {
+        let next = [rest.find("{{"), rest.find("{%")]
+            .into_iter()
+            .flatten()
+            .min();
+        let current = match open.last_mut() {
+            Some((_, nodes)) => nodes,
+            None => &mut top,
+        };
+        let Some(start) = next else {
+            if !rest.is_empty() {
+                current.push(Node::Text(rest.to_string()));
+            }
+            break;
+        };
+        if start > 0 {
+            current.push(Node::Text(rest[..start].to_string()));
+        }
+        let is_value = rest[start..].starts_with("{{");
+        let close = if is_value { "}}" } else { "%}" };
+        let end = rest[start + 2..]
+            .find(close)
+            .ok_or_else(|| format!("unclosed {:?}", &rest[start..start + 2]))?;
+        let inner = rest[start + 2..start + 2 + end].trim();
+        rest = &rest[start + 2 + end + 2..];
+
+        if is_value {
+            current.push(Node::Value(name(inner)?.to_string()));
+            continue;
+        }
+        let (keyword, argument) = inner.split_once(char::is_whitespace).unwrap_or((inner, ""));
+        match keyword {
+            "if" => open.push((
+                Frame {
+                    condition: condition(argument.trim())?,
+                    then: Vec::new(),
+                    in_else: false,
+                },
+                Vec::new(),
+            )),
+            "else" => match open.last_mut() {
+                Some((frame, nodes)) if !frame.in_else => {
+                    frame.then = std::mem::take(nodes);
+                    frame.in_else = true;
+                }
+                _ => return Err("{% else %} without {% if %}".to_string()),
+            },
+            "endif" => {
+                let (frame, nodes) = open
+                    .pop()
+                    .ok_or_else(|| "{% endif %} without {% if %}".to_string())?;
+                let (then, otherwise) = if frame.in_else {
+                    (frame.then, nodes)
+                } else {
+                    (nodes, Vec::new())
+                };
+                let node = Node::If {
+                    condition: frame.condition,
+                    then,
+                    otherwise,
+                };
+                match open.last_mut() {
+                    Some((_, nodes)) => nodes.push(node),
+                    None => top.push(node),
+                }
+            }
+            _ => return Err(format!("unknown tag {{% {} %}}", inner)),
+        }
+    }
+    if !open.is_empty() {
+        return Err("{% if %} without {% endif %}".to_string());
+    }
+    Ok(top)
+}
+
+fn name(word: &str) -> Result<&str, String> {
+    if word.is_empty() || !word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("expected a name, got {:?}", word));
+    }
+    Ok(word)
+}
+
+fn condition(text: &str) -> Result<Condition, String> {
+    let word = |text: &str| -> (String, String) {
+        match text.trim().split_once(char::is_whitespace) {
+            Some((word, rest)) => (word.to_string(), rest.trim().to_string()),
+            None => (text.trim().to_string(), String::new()),
+        }
+    };
+    let (subject, rest) = word(text);
+    let subject = name(&subject)?.to_string();
+    if rest.is_empty() {
+        return Ok(Condition::NotEmpty(subject));
+    }
+    let (op, literal) = word(&rest);
+    if literal.is_empty() {
+        return Err(format!("nothing to compare '{}' with", subject));
+    }
+    let (op, literal) = (op.as_str(), literal.as_str());
+    let text_op = match op {
+        "==" => Some(TextOp::Equal),
+        "!=" => Some(TextOp::NotEqual),
+        "contains" => Some(TextOp::Contains),
+        _ => None,
+    };
+    if let Some(op) = text_op {
+        let literal = literal
+            .strip_prefix('"')
+            .and_then(|l| l.strip_suffix('"'))
+            .ok_or_else(|| {
+                format!(
+                    "expected a \"quoted\" string after {}, got {}",
+                    op_name(op),
+                    literal
+                )
+            })?;
+        return Ok(Condition::Text {
+            name: subject,
+            op,
+            literal: literal.to_string(),
+        });
+    }
+    let op = match op {
+        "<" => NumberOp::Less,
+        "<=" => NumberOp::LessOrEqual,
+        ">" => NumberOp::Greater,
+        ">=" => NumberOp::GreaterOrEqual,
+        _ => return Err(format!("unknown operator '{}'", op)),
+    };
+    let literal = literal
+        .parse()
+        .map_err(|_| format!("expected a number, got {}", literal))?;
+    Ok(Condition::Number {
+        name: subject,
+        op,
+        literal,
+    })
+}
+
+fn op_name(op: TextOp) -> &'static str {
+    match op {
+        TextOp::Equal => "==",
+        TextOp::NotEqual => "!=",
+        TextOp::Contains => "contains",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> Context {
+        [
+            ("arch", "aarch64"),
+            ("model", "Raspberry Pi 4 Model B Rev 1.4"),
+            ("mem_mb", "3792"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn test_render_values_and_conditions() {
+        let context = context();
+        assert_eq!(
+            render("agent-{{ arch }}", &context).unwrap(),
+            "agent-aarch64"
+        );
+        assert_eq!(
+            render(
+                r#"{% if model contains "Pi 4" %}pi4{% else %}other{% endif %}"#,
+                &context
+            )
+            .unwrap(),
+            "pi4"
+        );
+        assert_eq!(
+            render("{% if mem_mb < 1024 %}zram-tools{% endif %}", &context).unwrap(),
+            ""
+        );
+        assert_eq!(
+            render(
+                r#"{% if arch == "aarch64" %}{% if mem_mb >= 2048 %}big{% endif %}{% endif %}"#,
+                &context
+            )
+            .unwrap(),
+            "big"
+        );
+        assert_eq!(
+            render("no templates here", &context).unwrap(),
+            "no templates here"
+        );
+    }
+
+    #[test]
+    fn test_render_errors() {
+        let context = context();
+        assert!(render("{{ archh }}", &context)
+            .unwrap_err()
+            .contains("unknown name"));
+        assert!(render("{% if arch %}x", &context).is_err());
+        assert!(render("{% endif %}", &context).is_err());
+        assert!(render("{{ arch", &context).is_err());
+        assert!(render("{% if arch == aarch64 %}x{% endif %}", &context).is_err());
+        assert!(render("{% if model > 3 %}x{% endif %}", &context).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+//! How long provisioning phases take, for estimating what's left of a group run.
+//!
+//! Each successful run adds its phase durations to a running mean per host
+//! class, kept in `timings.json` under the state directory. A host's class is
+//! its profile's `device_profile`, so boards provisioned alike are timed
+//! together; hosts without one share the `default` class. Recent runs weigh
+//! more than old ones, so the estimates follow a fleet that gets faster or slower.
+
+use crate::config::Config;
+use crate::paths;
+use crate::phase::Phase;
+use crate::Target;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::debug;
+
+/// Class of hosts without a device profile
+pub const DEFAULT_CLASS: &str = "default";
+
+/// A new run counts for at least 1/`WINDOW` of a phase's mean
+const WINDOW: u64 = 20;
+
+/// Mean duration of one phase across past runs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PhaseStat {
+    pub runs: u64,
+    pub mean_ms: u64,
+}
+
+/// Phase durations from past runs, by host class
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timings {
+    pub classes: BTreeMap<String, BTreeMap<Phase, PhaseStat>>,
+}
+
+fn timings_path() -> PathBuf {
+    paths::state_dir().join("timings.json")
+}
+
+/// The class of the host profile `profile`, or of a host given without one
+pub fn class_of(config: &Config, profile: Option<&str>) -> String {
+    profile
+        .and_then(|name| config.hosts.get(name))
+        .and_then(|profile| profile.device_profile.clone())
+        .unwrap_or_else(|| DEFAULT_CLASS.to_string())
+}
+
+impl Timings {
+    /// The saved timings, or none when there are none yet or they can't be read
+    pub fn load() -> Self {
+        let path = timings_path();
+        let Ok(text) = std::fs::read_to_string(&path) else {
+            return Self::default();
+        };
+        serde_json::from_str(&text).unwrap_or_else(|e| {
+            debug!("Ignoring unreadable timings in {}: {}", path.display(), e);
+            Self::default()
+        })
+    }
+
+    /// Saves the timings
+    pub fn save(&self) {
+        let path = timings_path();
+        let saved = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| {
+                std::fs::write(
+                    &path,
+                    serde_json::to_string_pretty(self).unwrap_or_default(),
+                )
+            });
+        // Without them the next run only estimates less well
+        if let Err(e) = saved {
+            debug!("Could not save timings in {}: {}", path.display(), e);
+        }
+    }
+
+    /// Adds one run's phase durations to `class`
+    pub fn record(&mut self, class: &str, phase_ms: &BTreeMap<Phase, u64>) {
+        let stats = self.classes.entry(class.to_string()).or_default();
+        for (phase, ms) in phase_ms {
+            let stat = stats.entry(*phase).or_default();
+            stat.runs += 1;
+            let weight = stat.runs.min(WINDOW) as i128;
+            let mean = stat.mean_ms as i128;
+            stat.mean_ms = (mean + (*ms as i128 - mean) / weight) as u64;
+        }
+    }
+
+    /// Adds one run's phase durations to the saved timings of `class`
+    pub fn remember(class: &str, phase_ms: &BTreeMap<Phase, u64>) {
+        let mut timings = Self::load();
+        timings.record(class, phase_ms);
+        timings.save();
+    }
+
+    /// Mean duration of `phase` for `class`, or across every class when
+    /// `class` has never run it
+    fn mean(&self, class: &str, phase: Phase) -> Option<u64> {
+        if let Some(stat) = self.classes.get(class).and_then(|stats| stats.get(&phase)) {
+            return Some(stat.mean_ms);
+        }
+        let means: Vec<u64> = self
+            .classes
+            .values()
+            .filter_map(|stats| stats.get(&phase))
+            .map(|stat| stat.mean_ms)
+            .collect();
+        (!means.is_empty()).then(|| means.iter().sum::<u64>() / means.len() as u64)
+    }
+
+    /// How long a run of `target` is expected to take, if every phase it
+    /// performs has run before
+    pub fn estimate(&self, class: &str, target: &Target) -> Option<Duration> {
+        Phase::ALL
+            .into_iter()
+            .filter(|phase| target.runs(*phase))
+            .map(|phase| self.mean(class, phase))
+            .sum::<Option<u64>>()
+            .map(Duration::from_millis)
+    }
+}
+
+/// Where one host of a group run is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostState {
+    /// Waiting for a slot, expected to take the duration if known
+    Pending(Option<Duration>),
+    /// Running for `elapsed` so far, expected to take `expected` if known
+    Running {
+        expected: Option<Duration>,
+        elapsed: Duration,
+    },
+    /// Finished after the duration
+    Done(Duration),
+}
+
+/// Time left until every host is done, running `jobs` at a time.
+///
+/// Hosts without an estimate of their own are expected to take as long as the
+/// ones done so far, so the first hosts to finish give an estimate even
+/// without past runs. None until something is known.
+pub fn eta(hosts: &[HostState], jobs: usize) -> Option<Duration> {
+    let done: Vec<Duration> = hosts
+        .iter()
+        .filter_map(|host| match host {
+            HostState::Done(duration) => Some(*duration),
+            _ => None,
+        })
+        .collect();
+    let observed = (!done.is_empty()).then(|| done.iter().sum::<Duration>() / done.len() as u32);
+
+    let mut left = Duration::ZERO;
+    let mut unfinished = 0;
+    for host in hosts {
+        let remaining = match *host {
+            HostState::Pending(expected) => expected.or(observed)?,
+            HostState::Running { expected, elapsed } => {
+                expected.or(observed)?.saturating_sub(elapsed)
+            }
+            HostState::Done(_) => continue,
+        };
+        left += remaining;
+        unfinished += 1;
+    }
+    let lanes = jobs.clamp(1, unfinished.max(1));
+    Some(left / lanes as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_follows_recent_runs() {
+        let mut timings = Timings::default();
+        for ms in [1_000, 3_000] {
+            timings.record("pi4", &BTreeMap::from([(Phase::Tunnel, ms)]));
+        }
+        let stat = timings.classes["pi4"][&Phase::Tunnel];
+        assert_eq!((stat.runs, stat.mean_ms), (2, 2_000));
+
+        for _ in 0..100 {
+            timings.record("pi4", &BTreeMap::from([(Phase::Tunnel, 500)]));
+        }
+        assert!(timings.classes["pi4"][&Phase::Tunnel].mean_ms < 600);
+    }
+
+    #[test]
+    fn test_estimate_falls_back_to_other_classes() {
+        let mut timings = Timings::default();
+        let phases = BTreeMap::from([(Phase::Tunnel, 2_000), (Phase::Validate, 500)]);
+        timings.record("pi4", &phases);
+        let target = Target {
+            phases: crate::phase::Phases::only(&[Phase::Tunnel, Phase::Validate]),
+            ..Target::default()
+        };
+        assert_eq!(
+            timings.estimate("jetson", &target),
+            Some(Duration::from_millis(2_500))
+        );
+
+        let target = Target {
+            phases: crate::phase::Phases::only(&[Phase::Tunnel, Phase::Arch]),
+            ..Target::default()
+        };
+        assert_eq!(timings.estimate("pi4", &target), None);
+    }
+
+    #[test]
+    fn test_eta_spreads_work_over_jobs() {
+        let secs = Duration::from_secs;
+        let hosts = [
+            HostState::Done(secs(60)),
+            HostState::Running {
+                expected: Some(secs(60)),
+                elapsed: secs(20),
+            },
+            HostState::Pending(None),
+            HostState::Pending(Some(secs(100))),
+        ];
+        // 40 + 60 (as long as the finished one) + 100, over two slots
+        assert_eq!(eta(&hosts, 2), Some(secs(100)));
+        assert_eq!(eta(&hosts, 8), Some(secs(200) / 3));
+        assert_eq!(eta(&[HostState::Pending(None)], 4), None);
+        assert_eq!(eta(&[HostState::Done(secs(1))], 4), Some(Duration::ZERO));
+    }
+}
@@ -0,0 +1,435 @@
+//! `tui`: a dashboard of the host profiles and recently reached devices.
+//!
+//! Every few seconds each tunnel's local port is probed through SSH, which
+//! gives its status and latency. Keys bring the selected tunnel up or down,
+//! open a shell or deploy the key again. Bringing a tunnel up, deploying the
+//! key and the shell leave the dashboard for the plain terminal, where `ssh`
+//! can ask for passwords; closing a tunnel happens in place. Log lines go to
+//! the log pane while the dashboard is shown (see [`Console`]).
+
+use crate::config::Config;
+use crate::exec;
+use crate::fleet;
+use crate::output;
+use crate::shell::RemoteCommand;
+use crate::ssh::{self, RunError};
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// How often every tunnel is probed
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Upper bound for one probe
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for a key before redrawing
+const TICK: Duration = Duration::from_millis(250);
+
+/// Log lines kept for the log pane
+const LOG_LINES: usize = 200;
+
+/// Set while the dashboard is on the screen, so log lines go to [`LOG`]
+static DRAWING: AtomicBool = AtomicBool::new(false);
+
+static LOG: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// A tunnel on the dashboard
+pub struct Tunnel {
+    /// The host profile, or `user@host` for a device from the history
+    pub name: String,
+    pub target: Target,
+    /// When `up` last reached the device on this port
+    pub connected: Option<DateTime<Utc>>,
+}
+
+/// What the last probe of a tunnel found
+#[derive(Debug, Clone, PartialEq)]
+enum Status {
+    Unknown,
+    /// Nothing listens on the local port
+    Down,
+    /// The port is open but the device didn't answer through it
+    Failing(String),
+    Up {
+        latency: Duration,
+    },
+}
+
+struct Entry {
+    tunnel: Tunnel,
+    status: Status,
+    /// Since when the tunnel is up, as far as the dashboard knows
+    up_since: Option<DateTime<Utc>>,
+}
+
+impl Entry {
+    fn cells(&self, now: DateTime<Utc>) -> [String; 7] {
+        let target = &self.tunnel.target;
+        let (status, latency) = match &self.status {
+            Status::Unknown => ("…".to_string(), String::new()),
+            Status::Down => ("down".to_string(), String::new()),
+            Status::Failing(error) => (format!("failing: {}", error), String::new()),
+            Status::Up { latency } => (
+                "up".to_string(),
+                format!("{:.0}ms", latency.as_secs_f64() * 1000.0),
+            ),
+        };
+        let uptime = self
+            .up_since
+            .filter(|_| matches!(self.status, Status::Up { .. }))
+            .and_then(|since| (now - since).to_std().ok())
+            .map(output::format_duration)
+            .unwrap_or_default();
+        [
+            self.tunnel.name.clone(),
+            target.host.clone(),
+            target.user.clone(),
+            target.port.to_string(),
+            status,
+            latency,
+            uptime,
+        ]
+    }
+
+    fn update(&mut self, status: Status) {
+        match (&self.status, &status) {
+            (Status::Up { .. }, Status::Up { .. }) => {}
+            // Opened by the `up` the history knows of, or else just now
+            (_, Status::Up { .. }) => {
+                self.up_since = Some(match self.tunnel.connected {
+                    Some(connected) if self.status == Status::Unknown => connected,
+                    _ => Utc::now(),
+                })
+            }
+            _ => self.up_since = None,
+        }
+        self.status = status;
+    }
+}
+
+/// Probes `target`'s tunnel with the same command `up` validates it with
+async fn check(target: Target) -> Status {
+    // A simulated tunnel has no port to find
+    if target.simulated.is_none()
+        && TcpStream::connect(("127.0.0.1", target.port))
+            .await
+            .is_err()
+    {
+        return Status::Down;
+    }
+    let probe = RemoteCommand::new("echo").arg("tunnel_test");
+    let started = Instant::now();
+    match ssh::run_through_tunnel(&target, &probe, CHECK_TIMEOUT).await {
+        Ok(_) => Status::Up {
+            latency: started.elapsed(),
+        },
+        // ssh's last word is the reason
+        Err(RunError::Failed { stderr, .. }) if !stderr.is_empty() => {
+            Status::Failing(stderr.lines().last().unwrap_or_default().to_string())
+        }
+        Err(e) => Status::Failing(e.to_string()),
+    }
+}
+
+/// What a key asks for
+enum Action {
+    Up,
+    Down,
+    Shell,
+    Key,
+    Refresh,
+    Quit,
+}
+
+impl Action {
+    fn of(code: KeyCode) -> Option<Self> {
+        Some(match code {
+            KeyCode::Char('u') => Action::Up,
+            KeyCode::Char('d') => Action::Down,
+            KeyCode::Char('s') => Action::Shell,
+            KeyCode::Char('k') => Action::Key,
+            KeyCode::Char('r') => Action::Refresh,
+            KeyCode::Char('q') | KeyCode::Esc => Action::Quit,
+            _ => return None,
+        })
+    }
+}
+
+/// Shows the dashboard for `tunnels` until the user quits
+pub async fn run(config: &Config, tunnels: Vec<Tunnel>) -> Result<()> {
+    if !(io::stdin().is_terminal() && io::stdout().is_terminal()) {
+        return Err(TunnelError::Tui("stdin and stdout must be a terminal".to_string()).into());
+    }
+    if tunnels.is_empty() {
+        return Err(TunnelError::Tui(
+            "no tunnels to show; add [hosts] profiles or run `up` first".to_string(),
+        )
+        .into());
+    }
+    let mut entries: Vec<Entry> = tunnels
+        .into_iter()
+        .map(|tunnel| Entry {
+            tunnel,
+            status: Status::Unknown,
+            up_since: None,
+        })
+        .collect();
+    let mut table = TableState::default().with_selected(Some(0));
+    let (sender, mut results) = mpsc::unbounded_channel();
+    let mut next_check = Instant::now();
+
+    let mut terminal = enter();
+    let outcome = loop {
+        if Instant::now() >= next_check {
+            for (index, entry) in entries.iter().enumerate() {
+                let sender = sender.clone();
+                let target = entry.tunnel.target.clone();
+                tokio::spawn(async move {
+                    let _ = sender.send((index, check(target).await));
+                });
+            }
+            next_check = Instant::now() + CHECK_INTERVAL;
+        }
+        while let Ok((index, status)) = results.try_recv() {
+            entries[index].update(status);
+        }
+        if let Err(e) = terminal.draw(|frame| draw(frame, &entries, &mut table)) {
+            break Err(e);
+        }
+
+        let key = tokio::task::spawn_blocking(|| -> io::Result<Option<event::KeyEvent>> {
+            if !event::poll(TICK)? {
+                return Ok(None);
+            }
+            Ok(match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => Some(key),
+                _ => None,
+            })
+        })
+        .await
+        .unwrap_or(Ok(None));
+        let key = match key {
+            Ok(Some(key)) => key,
+            Ok(None) => continue,
+            Err(e) => break Err(e),
+        };
+        let selected = table.selected().unwrap_or(0).min(entries.len() - 1);
+        match key.code {
+            KeyCode::Up => table.select(Some(selected.saturating_sub(1))),
+            KeyCode::Down => table.select(Some((selected + 1).min(entries.len() - 1))),
+            code => {
+                let Some(action) = Action::of(code) else {
+                    continue;
+                };
+                let target = &entries[selected].tunnel.target;
+                match action {
+                    Action::Quit => break Ok(()),
+                    Action::Refresh => {}
+                    Action::Down => {
+                        let manager = SSHTunnelManager::new(config.clone());
+                        match manager.close_tunnel(target).await {
+                            Ok(true) => {}
+                            Ok(false) => log(format!("No tunnel on localhost:{}", target.port)),
+                            Err(e) => log(e.to_string()),
+                        }
+                    }
+                    Action::Up | Action::Shell | Action::Key => {
+                        leave();
+                        perform(config, target, action).await;
+                        terminal = enter();
+                    }
+                }
+                next_check = Instant::now();
+            }
+        }
+    };
+    leave();
+    Ok(outcome.map_err(|e| TunnelError::Tui(e.to_string()))?)
+}
+
+/// Brings the tunnel up, opens a shell or deploys the key on the plain terminal
+async fn perform(config: &Config, target: &Target, action: Action) {
+    let outcome = match action {
+        Action::Up => fleet::run_target(config, target).await.map(|report| {
+            output::renderer().result(&report);
+        }),
+        Action::Key => deploy_key(config, target).await,
+        Action::Shell => {
+            if let Err(e) = exec::shell(config, target).await {
+                println!("Error: {:#}", e);
+                wait_for_enter();
+            }
+            return;
+        }
+        _ => return,
+    };
+    if let Err(e) = outcome {
+        println!("Error: {:#}", e);
+    }
+    wait_for_enter();
+}
+
+/// Installs the key through the tunnel, opening it if needed
+async fn deploy_key(config: &Config, target: &Target) -> Result<()> {
+    let manager = SSHTunnelManager::new(config.clone());
+    manager.reuse_or_connect(target).await?;
+    let message = if manager.transfer_key(target).await? {
+        "SSH key deployment completed successfully!"
+    } else {
+        "The key was already deployed"
+    };
+    println!("{}", message);
+    Ok(())
+}
+
+fn wait_for_enter() {
+    print!("Press Enter to return to the dashboard");
+    let _ = io::stdout().flush();
+    let _ = io::stdin().lock().read_line(&mut String::new());
+}
+
+fn enter() -> DefaultTerminal {
+    let terminal = ratatui::init();
+    DRAWING.store(true, Ordering::Relaxed);
+    terminal
+}
+
+fn leave() {
+    DRAWING.store(false, Ordering::Relaxed);
+    ratatui::restore();
+}
+
+fn draw(frame: &mut Frame, entries: &[Entry], table: &mut TableState) {
+    let [tunnels_area, log_area, help_area] = Layout::vertical([
+        Constraint::Length(entries.len() as u16 + 3),
+        Constraint::Min(3),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let now = Utc::now();
+    let rows = entries.iter().map(|entry| {
+        let color = match entry.status {
+            Status::Up { .. } => Color::Green,
+            Status::Down => Color::DarkGray,
+            Status::Failing(_) => Color::Red,
+            Status::Unknown => Color::Reset,
+        };
+        Row::new(entry.cells(now)).style(Style::new().fg(color))
+    });
+    let widths = [
+        Constraint::Fill(2),
+        Constraint::Fill(2),
+        Constraint::Fill(1),
+        Constraint::Length(5),
+        Constraint::Fill(3),
+        Constraint::Length(8),
+        Constraint::Length(10),
+    ];
+    let header = Row::new([
+        "NAME", "HOST", "USER", "PORT", "STATUS", "LATENCY", "UPTIME",
+    ])
+    .style(Style::new().add_modifier(Modifier::BOLD));
+    let tunnels = Table::new(rows, widths)
+        .header(header)
+        .block(Block::bordered().title(" Tunnels "))
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(tunnels, tunnels_area, table);
+
+    let log = LOG.lock().unwrap_or_else(|e| e.into_inner());
+    let shown = log_area.height.saturating_sub(2) as usize;
+    let lines: Vec<Line> = log
+        .iter()
+        .skip(log.len().saturating_sub(shown))
+        .map(|line| Line::raw(line.as_str()))
+        .collect();
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" Log ")),
+        log_area,
+    );
+
+    frame.render_widget(
+        Paragraph::new("↑/↓ select  u up  d down  s shell  k deploy key  r refresh  q quit")
+            .style(Style::new().add_modifier(Modifier::DIM)),
+        help_area,
+    );
+}
+
+/// Adds a line to the log pane
+fn log(line: String) {
+    let mut log = LOG.lock().unwrap_or_else(|e| e.into_inner());
+    if log.len() == LOG_LINES {
+        log.pop_front();
+    }
+    log.push_back(line);
+}
+
+/// Stdout for log lines, which go to the log pane instead while the
+/// dashboard is shown
+pub struct Console;
+
+impl Write for Console {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if !DRAWING.load(Ordering::Relaxed) {
+            return io::stdout().write(bytes);
+        }
+        for line in String::from_utf8_lossy(bytes).lines() {
+            log(line.to_string());
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uptime_starts_at_the_recorded_connection() {
+        let connected = Utc::now() - chrono::Duration::minutes(5);
+        let mut entry = Entry {
+            tunnel: Tunnel {
+                name: "rpi4-lab".to_string(),
+                target: Target {
+                    host: "rpi4.lan".to_string(),
+                    user: "pi".to_string(),
+                    port: 2222,
+                    ..Target::default()
+                },
+                connected: Some(connected),
+            },
+            status: Status::Unknown,
+            up_since: None,
+        };
+        let up = Status::Up {
+            latency: Duration::from_millis(12),
+        };
+        entry.update(up.clone());
+        let cells = entry.cells(connected + chrono::Duration::seconds(90));
+        assert_eq!(cells[4..], ["up", "12ms", "1m30s"]);
+
+        // Once seen down, a tunnel that comes back is new
+        entry.update(Status::Down);
+        assert_eq!(entry.cells(Utc::now())[4..], ["down", "", ""]);
+        entry.update(up);
+        assert!(entry.up_since.is_some_and(|since| since > connected));
+    }
+}
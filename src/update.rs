@@ -0,0 +1,525 @@
+//! A/B system updates with RAUC or SWUpdate.
+//!
+//! `update install` uploads a bundle through the tunnel, has the board's updater
+//! write it to the inactive slot, reboots, and waits for the board to come back
+//! on the new slot. The board is told to keep the new slot only once it answers
+//! and passes an optional health check; otherwise it is sent back to the old
+//! one. A board that never returns is left to its bootloader, which falls back
+//! on its own once the new slot has used up its boot attempts.
+//!
+//! The tunnel goes down with the reboot, so everything after it logs in to the
+//! board directly (see [`ssh::direct`]).
+
+use crate::config::Config;
+use crate::executor::Stdio;
+use crate::output::{self, Renderable};
+use crate::push::{self, PushSource};
+use crate::shell::{self, RemoteCommand};
+use crate::ssh;
+use crate::{SSHTunnelManager, Target, TunnelError};
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::time::{sleep, timeout};
+use tracing::{debug, info, warn};
+
+/// Upper bound for short queries on the board
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Upper bound for the updater to write a bundle
+const INSTALL_TIMEOUT: Duration = Duration::from_secs(1800);
+
+/// Upper bound for the health check
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How often the board is polled while it reboots
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The update framework on the board
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Updater {
+    Rauc,
+    Swupdate,
+}
+
+impl Updater {
+    fn name(self) -> &'static str {
+        match self {
+            Updater::Rauc => "rauc",
+            Updater::Swupdate => "swupdate",
+        }
+    }
+
+    /// Shell command that installs the bundle at `$1`
+    fn install_command(self) -> &'static str {
+        match self {
+            Updater::Rauc => r#"as_root rauc install "$1""#,
+            Updater::Swupdate => r#"as_root swupdate -v -i "$1""#,
+        }
+    }
+
+    /// Shell command that tells the bootloader to keep (`good`) or drop the booted slot
+    fn mark_command(self, good: bool) -> &'static str {
+        match (self, good) {
+            (Updater::Rauc, true) => "as_root rauc status mark-good booted",
+            (Updater::Rauc, false) => "as_root rauc status mark-bad booted",
+            // U-Boot's bootcount convention, which SWUpdate's docs build on;
+            // without fw_setenv there is nothing to confirm
+            (Updater::Swupdate, true) => {
+                "! command -v fw_setenv >/dev/null 2>&1 || as_root fw_setenv upgrade_available 0"
+            }
+            (Updater::Swupdate, false) => "true",
+        }
+    }
+}
+
+/// What the board reports about the system it is running
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SlotState {
+    /// Update framework found on the board, if any
+    pub updater: Option<Updater>,
+    /// Changes on every boot
+    pub boot_id: String,
+    /// RAUC's name for the booted slot, else the kernel's root device
+    pub slot: String,
+}
+
+impl SlotState {
+    /// Parses the slot script's output: `<updater|none> <boot id> <slot>`
+    fn parse(output: &str) -> Option<Self> {
+        let mut words = output.split_whitespace();
+        let updater = match words.next()? {
+            "rauc" => Some(Updater::Rauc),
+            "swupdate" => Some(Updater::Swupdate),
+            "none" => None,
+            _ => return None,
+        };
+        Some(Self {
+            updater,
+            boot_id: words.next()?.to_string(),
+            slot: words.next()?.to_string(),
+        })
+    }
+}
+
+/// Prints `<updater|none> <boot id> <slot>`
+const SLOT_SCRIPT: &str = r#"updater=none
+if command -v rauc >/dev/null 2>&1; then updater=rauc
+elif command -v swupdate >/dev/null 2>&1; then updater=swupdate
+fi
+slot=
+if [ "$updater" = rauc ]; then
+  slot=$(as_root rauc status --output-format=shell 2>/dev/null | sed -n "s/^RAUC_SYSTEM_BOOTED_BOOTNAME='\{0,1\}\([^']*\)'\{0,1\}$/\1/p")
+fi
+[ -n "$slot" ] || slot=$(tr ' ' '\n' < /proc/cmdline | sed -n 's/^root=//p')
+echo "$updater $(cat /proc/sys/kernel/random/boot_id) ${slot:-unknown}"
+"#;
+
+/// Result of `update install`
+#[derive(Debug, Serialize)]
+pub struct UpdateReport {
+    pub host: String,
+    pub updater: Updater,
+    pub bundle: PathBuf,
+    pub previous_slot: String,
+    pub booted_slot: String,
+    pub reboot_secs: f64,
+    pub health_checked: bool,
+}
+
+impl Renderable for UpdateReport {
+    fn to_human(&self) -> String {
+        format!(
+            "Updated {} with {} ({}): {} -> {}, back after {}{}",
+            self.host,
+            self.bundle.display(),
+            self.updater.name(),
+            self.previous_slot,
+            self.booted_slot,
+            output::format_duration(Duration::from_secs_f64(self.reboot_secs)),
+            if self.health_checked {
+                ", health check passed"
+            } else {
+                ""
+            }
+        )
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Result of `update status`
+#[derive(Debug, Serialize)]
+pub struct StatusReport {
+    pub host: String,
+    #[serde(flatten)]
+    pub state: SlotState,
+}
+
+impl Renderable for StatusReport {
+    fn to_human(&self) -> String {
+        format!(
+            "{}: {}, booted slot {} (boot {})",
+            self.host,
+            self.state.updater.map_or("no updater found", Updater::name),
+            self.state.slot,
+            self.state.boot_id
+        )
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Options for `update install`
+#[derive(Debug, Clone)]
+pub struct InstallOptions {
+    /// Path on the board for the bundle (default: /tmp/<file name>)
+    pub dest: Option<String>,
+    /// Updater to use instead of the one found on the board
+    pub updater: Option<Updater>,
+    /// How long to wait for the board to come back after rebooting
+    pub reboot_timeout: Duration,
+    /// Shell command that must succeed on the new slot before it is kept
+    pub health_command: Option<String>,
+}
+
+/// Reports the updater and booted slot on `target`
+pub async fn status(config: &Config, target: &Target) -> Result<StatusReport> {
+    SSHTunnelManager::new(config.clone())
+        .connect(target)
+        .await?;
+    let state = query(target, false).await?;
+    Ok(StatusReport {
+        host: target.host.clone(),
+        state,
+    })
+}
+
+/// Installs `bundle` on `target`, reboots into it and keeps it only if the board comes back healthy
+pub async fn install(
+    config: &Config,
+    target: &Target,
+    bundle: &Path,
+    options: &InstallOptions,
+) -> Result<UpdateReport> {
+    let dest = match &options.dest {
+        Some(dest) => dest.clone(),
+        None => format!(
+            "/tmp/{}",
+            bundle
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| anyhow::anyhow!(
+                    "Cannot derive a file name from {}",
+                    bundle.display()
+                ))?
+        ),
+    };
+
+    // Opens the tunnel, and only returns once the board's copy matched
+    push::push(
+        config,
+        target,
+        &PushSource::File(bundle.to_path_buf()),
+        Some(&dest),
+        None,
+        true,
+        1,
+    )
+    .await?;
+
+    let before = query(target, false).await?;
+    let Some(updater) = options.updater.or(before.updater) else {
+        remove_bundle(target, &dest).await;
+        return Err(TunnelError::Update(format!(
+            "neither rauc nor swupdate found on {}",
+            target.host
+        ))
+        .into());
+    };
+    info!(
+        "{} is running slot {}; installing {} with {}...",
+        target.host,
+        before.slot,
+        bundle.display(),
+        updater.name()
+    );
+    run_updater(target, updater, &dest).await?;
+
+    let rebooted = Instant::now();
+    reboot(target, false).await?;
+    let Some(after) = wait_for_boot(target, &before.boot_id, options.reboot_timeout).await else {
+        return Err(TunnelError::Update(format!(
+            "{} did not come back within {}s; its bootloader should fall back to slot {} once the new slot runs out of boot attempts. Check with `update status`",
+            target.host,
+            options.reboot_timeout.as_secs(),
+            before.slot
+        ))
+        .into());
+    };
+    let reboot_secs = rebooted.elapsed().as_secs_f64();
+    if after.slot == before.slot {
+        return Err(TunnelError::Update(format!(
+            "{} came back on slot {}; the new slot did not boot and was rolled back",
+            target.host, after.slot
+        ))
+        .into());
+    }
+    info!("{} is back on slot {}", target.host, after.slot);
+
+    if let Some(check) = &options.health_command {
+        if let Err(e) = health_check(target, check).await {
+            warn!("Health check failed on slot {}: {}", after.slot, e);
+            return Err(roll_back(
+                target,
+                updater,
+                &after,
+                &before.slot,
+                options.reboot_timeout,
+            )
+            .await);
+        }
+    }
+    run_direct(
+        target,
+        &shell_command(updater.mark_command(true), &[]),
+        COMMAND_TIMEOUT,
+    )
+    .await
+    .map_err(|e| {
+        TunnelError::Update(format!(
+            "slot {} booted but could not be marked good: {}",
+            after.slot, e
+        ))
+    })?;
+
+    Ok(UpdateReport {
+        host: target.host.clone(),
+        updater,
+        bundle: bundle.to_path_buf(),
+        previous_slot: before.slot,
+        booted_slot: after.slot,
+        reboot_secs,
+        health_checked: options.health_command.is_some(),
+    })
+}
+
+/// Reads the slot state through the tunnel, or directly once the tunnel is gone
+async fn query(target: &Target, direct: bool) -> Result<SlotState, TunnelError> {
+    let command = shell_command(SLOT_SCRIPT, &[]);
+    let stdout = if direct {
+        run_direct(target, &command, COMMAND_TIMEOUT).await?
+    } else {
+        run_tunnel(target, &command, COMMAND_TIMEOUT).await?
+    };
+    SlotState::parse(&stdout)
+        .ok_or_else(|| TunnelError::Update(format!("unexpected slot query output {:?}", stdout)))
+}
+
+/// Runs the updater on the uploaded bundle, logging its output, then removes the bundle
+async fn run_updater(target: &Target, updater: Updater, dest: &str) -> Result<(), TunnelError> {
+    let script = format!(
+        "status=0\n{} 2>&1 || status=$?\nrm -f \"$1\"\nexit $status",
+        updater.install_command()
+    );
+    let command = shell_command(&script, &[dest]);
+    let mut child = ssh::through_tunnel(target, &command)?
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| TunnelError::Update(e.to_string()))?;
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let progress = async {
+        let mut lines = BufReader::new(stdout).lines();
+        let mut last = String::new();
+        while let Ok(Some(line)) = lines.next_line().await {
+            info!("{}: {}", updater.name(), line);
+            last = line;
+        }
+        last
+    };
+    let (last, output) = timeout(INSTALL_TIMEOUT, async {
+        tokio::join!(progress, child.wait_with_output())
+    })
+    .await
+    .map_err(|_| {
+        TunnelError::Update(format!(
+            "{} did not finish within {}s",
+            updater.name(),
+            INSTALL_TIMEOUT.as_secs()
+        ))
+    })?;
+    let output = output.map_err(|e| TunnelError::Update(e.to_string()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        return Err(TunnelError::Update(format!(
+            "{} failed: {}",
+            updater.name(),
+            if stderr.is_empty() { last } else { stderr }
+        )));
+    }
+    Ok(())
+}
+
+/// Best-effort removal of an uploaded bundle that will not be installed
+async fn remove_bundle(target: &Target, dest: &str) {
+    let command = RemoteCommand::new("rm").arg("-f").arg("--").arg(dest);
+    if let Err(e) = run_tunnel(target, &command, COMMAND_TIMEOUT).await {
+        debug!("Could not remove {}: {}", dest, e);
+    }
+}
+
+/// Asks the board to reboot; losing the connection while it does is expected
+async fn reboot(target: &Target, direct: bool) -> Result<(), TunnelError> {
+    info!("Rebooting {}...", target.host);
+    let command = shell_command("as_root systemctl reboot || as_root reboot", &[]);
+    let result = if direct {
+        run_direct(target, &command, COMMAND_TIMEOUT).await
+    } else {
+        run_tunnel(target, &command, COMMAND_TIMEOUT).await
+    };
+    match result {
+        Ok(_) | Err(TunnelError::ConnectionLost(_)) => Ok(()),
+        Err(e) => Err(TunnelError::Update(format!("could not reboot: {}", e))),
+    }
+}
+
+/// Polls the board until it reports a boot other than `previous_boot`, or `limit` passes
+async fn wait_for_boot(target: &Target, previous_boot: &str, limit: Duration) -> Option<SlotState> {
+    let started = Instant::now();
+    while started.elapsed() < limit {
+        sleep(POLL_INTERVAL).await;
+        match query(target, true).await {
+            Ok(state) if state.boot_id != previous_boot => return Some(state),
+            Ok(_) => debug!("{} has not gone down yet", target.host),
+            Err(e) => debug!("{} is not answering yet: {}", target.host, e),
+        }
+        info!(
+            "Waiting for {} to come back ({}s of {}s)...",
+            target.host,
+            started.elapsed().as_secs(),
+            limit.as_secs()
+        );
+    }
+    None
+}
+
+/// Runs the user's health check on the freshly booted slot
+async fn health_check(target: &Target, check: &str) -> Result<(), TunnelError> {
+    info!("Running health check on {}...", target.host);
+    run_direct(
+        target,
+        &RemoteCommand::new("sh").arg("-c").arg(check),
+        HEALTH_TIMEOUT,
+    )
+    .await
+    .map(drop)
+}
+
+/// Sends the board back to `previous_slot` and returns the error describing what happened
+async fn roll_back(
+    target: &Target,
+    updater: Updater,
+    booted: &SlotState,
+    previous_slot: &str,
+    limit: Duration,
+) -> anyhow::Error {
+    let failed = |outcome: String| {
+        TunnelError::Update(format!(
+            "slot {} failed its health check; {}",
+            booted.slot, outcome
+        ))
+        .into()
+    };
+    if let Err(e) = run_direct(
+        target,
+        &shell_command(updater.mark_command(false), &[]),
+        COMMAND_TIMEOUT,
+    )
+    .await
+    {
+        return failed(format!("could not mark it bad: {}", e));
+    }
+    if let Err(e) = reboot(target, true).await {
+        return failed(e.to_string());
+    }
+    match wait_for_boot(target, &booted.boot_id, limit).await {
+        Some(state) if state.slot == previous_slot => {
+            failed(format!("rolled back to slot {}", previous_slot))
+        }
+        Some(state) => failed(format!(
+            "rebooted, but {} is on slot {} rather than {}",
+            target.host, state.slot, previous_slot
+        )),
+        None => failed(format!(
+            "rebooted to roll back, but {} did not come back within {}s",
+            target.host,
+            limit.as_secs()
+        )),
+    }
+}
+
+/// `sh -c` running `script` after the `as_root` prelude, with positional `args`
+fn shell_command(script: &str, args: &[&str]) -> RemoteCommand {
+    args.iter().fold(
+        RemoteCommand::new("sh")
+            .arg("-c")
+            .arg(format!("{}\n{}", shell::AS_ROOT, script))
+            .arg("sh"),
+        |command, arg| command.arg(arg),
+    )
+}
+
+/// Runs a remote command through the tunnel, returning its stdout
+async fn run_tunnel(
+    target: &Target,
+    command: &RemoteCommand,
+    limit: Duration,
+) -> Result<String, TunnelError> {
+    ssh::run(ssh::through_tunnel(target, command)?, limit)
+        .await
+        .map_err(|e| e.into_retryable(TunnelError::Update))
+}
+
+/// Runs a remote command on the board directly, returning its stdout
+async fn run_direct(
+    target: &Target,
+    command: &RemoteCommand,
+    limit: Duration,
+) -> Result<String, TunnelError> {
+    ssh::run(ssh::direct(target, command)?, limit)
+        .await
+        .map_err(|e| e.into_retryable(TunnelError::Update))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slot_state_parse() {
+        assert_eq!(
+            SlotState::parse("rauc 0f6c2a7e-4b1d-4f7e-9d1a-3c9e2b8a7d10 A\n"),
+            Some(SlotState {
+                updater: Some(Updater::Rauc),
+                boot_id: "0f6c2a7e-4b1d-4f7e-9d1a-3c9e2b8a7d10".to_string(),
+                slot: "A".to_string(),
+            })
+        );
+        let state = SlotState::parse("none 1234 /dev/mmcblk0p3").unwrap();
+        assert_eq!(state.updater, None);
+        assert_eq!(state.slot, "/dev/mmcblk0p3");
+
+        assert_eq!(SlotState::parse("mender 1234 A"), None);
+        assert_eq!(SlotState::parse("rauc 1234"), None);
+    }
+}
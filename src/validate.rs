@@ -0,0 +1,224 @@
+//! Input validation for values that end up on an `ssh` command line or in a
+//! remote shell.
+//!
+//! Quoting (see [`crate::shell`]) keeps hostile values inert; these checks reject
+//! them outright, with an error naming the offending value, before any process
+//! is spawned. That matters most for batch input such as host groups, where a
+//! single bad inventory entry should fail fast rather than mid-run.
+
+use crate::TunnelError;
+
+const MAX_USERNAME_LEN: usize = 32;
+const MAX_HOST_LEN: usize = 253;
+/// Namespaces end up in socket paths, which are limited to about 100 bytes
+const MAX_NAMESPACE_LEN: usize = 32;
+
+/// Checks a remote login name against the portable POSIX user name set
+/// (`[A-Za-z0-9._-]`, not starting with `-`)
+pub fn validate_username(user: &str) -> Result<(), TunnelError> {
+    let reject = |reason: &str| {
+        Err(TunnelError::InvalidUsername(format!(
+            "'{}' {}",
+            user, reason
+        )))
+    };
+
+    if user.is_empty() {
+        return reject("is empty");
+    }
+    if user.len() > MAX_USERNAME_LEN {
+        return reject(&format!("is longer than {} characters", MAX_USERNAME_LEN));
+    }
+    if user.starts_with('-') {
+        return reject("starts with '-'");
+    }
+    if let Some(c) = user
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')))
+    {
+        return reject(&format!("contains {:?}", c));
+    }
+    Ok(())
+}
+
+/// Checks a host name, IPv4 address or IPv6 address (optionally bracketed or
+/// with a `%zone`) for characters that have no business in one
+pub fn validate_host(host: &str) -> Result<(), TunnelError> {
+    let reject = |reason: &str| Err(TunnelError::InvalidHost(format!("'{}' {}", host, reason)));
+
+    if host.is_empty() {
+        return reject("is empty");
+    }
+    if host.len() > MAX_HOST_LEN {
+        return reject(&format!("is longer than {} characters", MAX_HOST_LEN));
+    }
+    if host.starts_with('-') {
+        return reject("starts with '-'");
+    }
+    if let Some(c) = host.chars().find(|c| {
+        !(c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | ':' | '[' | ']' | '%'))
+    }) {
+        return reject(&format!("contains {:?}", c));
+    }
+    Ok(())
+}
+
+/// Checks a namespace name, which becomes a directory and part of file names:
+/// `[A-Za-z0-9_-]`, not starting with `-`
+pub fn validate_namespace(namespace: &str) -> Result<(), TunnelError> {
+    let reject = |reason: &str| {
+        Err(TunnelError::InvalidNamespace(format!(
+            "'{}' {}",
+            namespace, reason
+        )))
+    };
+
+    if namespace.is_empty() {
+        return reject("is empty");
+    }
+    if namespace.len() > MAX_NAMESPACE_LEN {
+        return reject(&format!("is longer than {} characters", MAX_NAMESPACE_LEN));
+    }
+    if namespace.starts_with('-') {
+        return reject("starts with '-'");
+    }
+    if let Some(c) = namespace
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-')))
+    {
+        return reject(&format!("contains {:?}", c));
+    }
+    Ok(())
+}
+
+/// Checks the comment field of a public key. `authorized_keys` is line based,
+/// so control characters (newlines in particular) would let a comment smuggle
+/// in an extra, attacker-chosen key entry.
+pub fn validate_key_comment(comment: &str) -> Result<(), TunnelError> {
+    match comment.chars().find(|c| c.is_control()) {
+        Some(c) => Err(TunnelError::InvalidKeyComment(format!(
+            "{:?} contains control character {:?}",
+            comment, c
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Checks a host key fingerprint as `ssh-keygen -l` prints it: `SHA256:`
+/// followed by 43 characters of unpadded base64
+pub fn validate_fingerprint(fingerprint: &str) -> Result<(), TunnelError> {
+    let digest = fingerprint.strip_prefix("SHA256:").ok_or_else(|| {
+        TunnelError::HostKey(format!(
+            "fingerprint '{}' does not start with SHA256:",
+            fingerprint
+        ))
+    })?;
+    if digest.len() != 43
+        || !digest
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '/'))
+    {
+        return Err(TunnelError::HostKey(format!(
+            "fingerprint '{}' is not a SHA-256 fingerprint as printed by ssh-keygen -l",
+            fingerprint
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usernames() {
+        for ok in [
+            "pi",
+            "ubuntu",
+            "deploy-bot",
+            "svc_account",
+            "first.last",
+            "root",
+        ] {
+            assert!(validate_username(ok).is_ok(), "{} should be accepted", ok);
+        }
+        for bad in [
+            "",
+            "-oProxyCommand=sh",
+            "pi;reboot",
+            "pi user",
+            "$(id)",
+            "pi\n",
+            "a@b",
+            "averyveryveryverylongusernamethatexceedsposix",
+        ] {
+            assert!(
+                matches!(validate_username(bad), Err(TunnelError::InvalidUsername(_))),
+                "{:?} should be rejected",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_hosts() {
+        for ok in [
+            "192.168.1.42",
+            "raspberrypi.local",
+            "fe80::1%eth0",
+            "[::1]",
+            "my_board",
+        ] {
+            assert!(validate_host(ok).is_ok(), "{} should be accepted", ok);
+        }
+        for bad in [
+            "",
+            "-oProxyCommand=sh",
+            "host;id",
+            "host name",
+            "a/b",
+            "`id`",
+        ] {
+            assert!(
+                matches!(validate_host(bad), Err(TunnelError::InvalidHost(_))),
+                "{:?} should be rejected",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_namespaces() {
+        for ok in ["team-a", "vision_lab", "42"] {
+            assert!(validate_namespace(ok).is_ok(), "{} should be accepted", ok);
+        }
+        for bad in ["", "..", "a/b", "-x", "team a", &"x".repeat(33)] {
+            assert!(
+                matches!(
+                    validate_namespace(bad),
+                    Err(TunnelError::InvalidNamespace(_))
+                ),
+                "{:?} should be rejected",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_key_comments() {
+        assert!(validate_key_comment("pi@raspberrypi (lab bench 3)").is_ok());
+        assert!(validate_key_comment("").is_ok());
+        assert!(validate_key_comment("me\nssh-ed25519 AAAA attacker").is_err());
+        assert!(validate_key_comment("tab\there").is_err());
+    }
+
+    #[test]
+    fn test_fingerprints() {
+        assert!(validate_fingerprint("SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s").is_ok());
+        assert!(validate_fingerprint("uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s").is_err());
+        assert!(validate_fingerprint("SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2").is_err());
+        assert!(
+            validate_fingerprint("SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2\n").is_err()
+        );
+    }
+}
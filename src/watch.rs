@@ -0,0 +1,557 @@
+//! `up --watch`: keeping the tunnel `up` opened, and counting how it goes.
+//!
+//! The tunnel is checked every [`systemd::check_interval`]. When a check
+//! fails, the tunnel is closed and opened again, each interval until it
+//! answers. Under systemd the watchdog is fed only after checks that pass,
+//! so a tunnel that stays down for longer than `WatchdogSec` gets a fresh
+//! `up`.
+//!
+//! How the tunnel is doing goes to `watch/<port>.json` under the state
+//! directory after every check: since when it is up, how long it has been
+//! up in all, its checks and reconnects and the last failure. `status
+//! --stats` shows it, and the file stays after the watch ends, so flaky
+//! boards show in numbers. Bytes aren't counted, since ssh doesn't report
+//! them for a forward. While the watch runs, [`Live`] holds the same
+//! statistics for what serves them, such as `--health-listen` and the
+//! [control socket](crate::control), and takes [`Order`]s: `down` ends the
+//! watch through it rather than have the tunnel it closes opened again.
+
+use crate::config::Config;
+#[cfg(unix)]
+use crate::control::ControlSocket;
+use crate::control::{self, Request};
+use crate::hooks::Outcome;
+use crate::output::{self, Event, Renderable};
+use crate::paths;
+use crate::phase::Phase;
+use crate::systemd;
+use crate::webhooks;
+use crate::{RunReport, SSHTunnelManager, Target, TunnelError};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+/// Checks a watch may miss before `status` takes it for gone
+const MISSED_CHECKS: u32 = 3;
+
+/// Events kept for a subscriber that is behind
+const EVENT_BACKLOG: usize = 64;
+
+/// A check that failed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Failure {
+    pub at: DateTime<Utc>,
+    pub error: String,
+}
+
+/// How a watched tunnel has been doing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    pub host: String,
+    /// Local port of the tunnel
+    pub port: u16,
+    /// The watching process
+    pub pid: u32,
+    pub started: DateTime<Utc>,
+    /// Seconds between checks
+    pub interval_secs: u64,
+    /// When the tunnel last came up; unset while it is down
+    pub up_since: Option<DateTime<Utc>>,
+    /// Seconds the tunnel answered in all, as of the last check
+    pub up_secs: u64,
+    pub checks: u64,
+    pub failed_checks: u64,
+    /// Times the tunnel was opened again after a check failed
+    pub reconnects: u32,
+    pub last_check: DateTime<Utc>,
+    pub last_failure: Option<Failure>,
+    /// When the watch ended; unset while it runs
+    pub ended: Option<DateTime<Utc>>,
+}
+
+impl Stats {
+    /// A watch starting at `now` on a tunnel that has just passed its check
+    pub(crate) fn new(target: &Target, interval: Duration, now: DateTime<Utc>) -> Self {
+        Self {
+            host: target.host.clone(),
+            port: target.port,
+            pid: std::process::id(),
+            started: now,
+            interval_secs: interval.as_secs(),
+            up_since: Some(now),
+            up_secs: 0,
+            checks: 0,
+            failed_checks: 0,
+            reconnects: 0,
+            last_check: now,
+            last_failure: None,
+            ended: None,
+        }
+    }
+
+    /// Counts a check at `now` that passed
+    fn passed(&mut self, now: DateTime<Utc>) {
+        self.checks += 1;
+        if self.up_since.is_some() {
+            self.up_secs += (now - self.last_check).num_seconds().max(0) as u64;
+        } else {
+            self.up_since = Some(now);
+        }
+        self.last_check = now;
+    }
+
+    /// Counts a check at `now` that failed with `error`
+    fn failed(&mut self, now: DateTime<Utc>, error: String) {
+        self.checks += 1;
+        self.failed_checks += 1;
+        self.up_since = None;
+        self.last_check = now;
+        self.last_failure = Some(Failure { at: now, error });
+    }
+
+    /// Counts the tunnel opened again at `now`
+    fn reconnected(&mut self, now: DateTime<Utc>) {
+        self.reconnects += 1;
+        self.up_since = Some(now);
+        self.last_check = now;
+    }
+
+    /// Whether the watch still runs at `now`: it hasn't ended, and hasn't
+    /// missed [`MISSED_CHECKS`] checks
+    pub fn is_running(&self, now: DateTime<Utc>) -> bool {
+        let allowed = self.interval_secs.saturating_mul(MISSED_CHECKS.into());
+        self.ended.is_none() && (now - self.last_check).num_seconds() <= allowed as i64
+    }
+
+    fn save(&self) {
+        let path = stats_path(self.port);
+        let write = || -> std::io::Result<()> {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&path, serde_json::to_string_pretty(self)?)
+        };
+        if let Err(e) = write() {
+            warn!(
+                "Can't save the tunnel's statistics in {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// What the watch can be asked to do, e.g. through the control socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    /// Close the tunnel and end the watch
+    Down,
+    /// Close the tunnel and open it again
+    Reconnect,
+}
+
+/// An order, and where the watch answers it with its statistics
+type Envelope = (Order, oneshot::Sender<Result<Stats, TunnelError>>);
+
+/// The watch in this process: its statistics as of its last check, what
+/// `up` found, the events of the watch, and where it takes orders
+#[derive(Debug, Clone)]
+pub struct Live {
+    stats: Arc<Mutex<Option<Stats>>>,
+    report: Arc<Mutex<Option<RunReport>>>,
+    events: broadcast::Sender<Event>,
+    orders: Arc<Mutex<Option<mpsc::Sender<Envelope>>>>,
+}
+
+impl Default for Live {
+    fn default() -> Self {
+        Self {
+            stats: Arc::default(),
+            report: Arc::default(),
+            events: broadcast::channel(EVENT_BACKLOG).0,
+            orders: Arc::default(),
+        }
+    }
+}
+
+impl Live {
+    /// The statistics; none before the watch starts
+    pub fn get(&self) -> Option<Stats> {
+        self.stats.lock().ok()?.clone()
+    }
+
+    /// What the `up` before the watch found, such as the device's board
+    pub fn report(&self) -> Option<RunReport> {
+        self.report.lock().ok()?.clone()
+    }
+
+    /// Keeps what `up` found
+    pub fn set_report(&self, report: &RunReport) {
+        if let Ok(mut live) = self.report.lock() {
+            *live = Some(report.clone());
+        }
+    }
+
+    /// The events of the watch from now on, such as the tunnel lost
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Reports `event` to the renderer and to subscribers
+    fn emit(&self, event: Event) {
+        // No subscribers is no error
+        let _ = self.events.send(event.clone());
+        output::emit(event);
+    }
+
+    /// Whether the watch runs and the tunnel passed its latest check
+    pub fn is_healthy(&self) -> bool {
+        self.get()
+            .is_some_and(|stats| stats.ended.is_none() && stats.up_since.is_some())
+    }
+
+    /// Shares `stats`
+    pub(crate) fn set(&self, stats: &Stats) {
+        if let Ok(mut live) = self.stats.lock() {
+            *live = Some(stats.clone());
+        }
+    }
+
+    /// Saves `stats` and shares them
+    fn record(&self, stats: &Stats) {
+        stats.save();
+        self.set(stats);
+    }
+
+    /// Has the watch carry out `order`; returns its statistics once it has
+    pub async fn order(&self, order: Order) -> Result<Stats, TunnelError> {
+        let gone = || TunnelError::Watch("the tunnel isn't watched".to_string());
+        let orders = self
+            .orders
+            .lock()
+            .ok()
+            .and_then(|orders| orders.clone())
+            .ok_or_else(gone)?;
+        let (reply, answer) = oneshot::channel();
+        orders.send((order, reply)).await.map_err(|_| gone())?;
+        answer.await.map_err(|_| gone())?
+    }
+
+    /// Where the watch starting now takes its orders
+    fn take_orders(&self) -> mpsc::Receiver<Envelope> {
+        let (sender, receiver) = mpsc::channel(4);
+        if let Ok(mut orders) = self.orders.lock() {
+            *orders = Some(sender);
+        }
+        receiver
+    }
+}
+
+fn stats_dir() -> PathBuf {
+    paths::state_dir().join("watch")
+}
+
+fn stats_path(port: u16) -> PathBuf {
+    stats_dir().join(format!("{}.json", port))
+}
+
+/// Checks the tunnel to `target`, which `up` has just opened, and opens it
+/// again whenever a check fails, recording how it goes in `live`; returns once
+/// asked to stop
+pub async fn watch(config: &Config, target: &Target, live: &Live) -> Result<(), TunnelError> {
+    let manager = SSHTunnelManager::new(config.clone());
+    let interval = systemd::check_interval();
+    let up = format!("Tunnel to {} up on localhost:{}", target.host, target.port);
+    let mut stats = Stats::new(target, interval, Utc::now());
+    live.record(&stats);
+    let mut orders = live.take_orders();
+    #[cfg(unix)]
+    let _control = ControlSocket::start(target.port, live.clone())
+        .inspect_err(|e| warn!("{}; `down` and `status` won't reach this watch", e));
+    info!(
+        "Watching the tunnel on localhost:{}, every {}s",
+        target.port,
+        interval.as_secs()
+    );
+    // The phases after the tunnel's have finished
+    systemd::status(&up);
+    let stop = stop_requested();
+    tokio::pin!(stop);
+    loop {
+        tokio::select! {
+            _ = sleep(interval) => {}
+            Some((order, reply)) = orders.recv() => {
+                let result = match order {
+                    Order::Down => {
+                        info!("Closing the tunnel on localhost:{}, as asked", target.port);
+                        match manager.close_tunnel(target).await {
+                            Ok(_) => {
+                                stats.ended = Some(Utc::now());
+                                live.record(&stats);
+                                let _ = reply.send(Ok(stats));
+                                return Ok(());
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                    Order::Reconnect => {
+                        info!("Opening the tunnel on localhost:{} again, as asked", target.port);
+                        reopen(&manager, target, &mut stats, &up, live).await
+                            .map(|()| stats.clone())
+                    }
+                };
+                live.record(&stats);
+                let _ = reply.send(result);
+                continue;
+            }
+            _ = &mut stop => {
+                stats.ended = Some(Utc::now());
+                live.record(&stats);
+                return Ok(());
+            }
+        }
+        match manager.validate_tunnel(target).await {
+            Ok(()) => {
+                stats.passed(Utc::now());
+                systemd::watchdog();
+            }
+            Err(e) => {
+                if stats.up_since.is_some() {
+                    warn!(
+                        "The tunnel to {} stopped answering: {}; opening it again",
+                        target.host, e
+                    );
+                    live.emit(Event::TunnelLost {
+                        host: target.host.clone(),
+                        port: target.port,
+                        error: e.to_string(),
+                    });
+                    let error = e.to_string();
+                    let outcome = Outcome::Failed {
+                        phase: Some(Phase::Validate),
+                        error: &error,
+                    };
+                    webhooks::notify(&config.webhooks, webhooks::Event::Down, target, outcome)
+                        .await;
+                }
+                stats.failed(Utc::now(), e.to_string());
+                systemd::status(&format!("Tunnel to {} lost: {}", target.host, e));
+                if let Err(e) = reopen(&manager, target, &mut stats, &up, live).await {
+                    warn!("Opening the tunnel to {} again failed: {}", target.host, e);
+                }
+            }
+        }
+        live.record(&stats);
+    }
+}
+
+/// Closes the tunnel and opens it again, counting it in `stats` and telling
+/// `live`; `up` is the status systemd gets once it is. The `on_up` webhook
+/// hears of it if the tunnel was down, and so `on_down` of its loss.
+async fn reopen(
+    manager: &SSHTunnelManager,
+    target: &Target,
+    stats: &mut Stats,
+    up: &str,
+    live: &Live,
+) -> Result<(), TunnelError> {
+    let was_down = stats.up_since.is_none();
+    if let Err(e) = manager.stop_tunnel(target).await {
+        debug!("Closing the tunnel failed: {}", e);
+    }
+    if let Err(e) = manager.connect(target).await {
+        stats.up_since = None;
+        return Err(e.source);
+    }
+    stats.reconnected(Utc::now());
+    live.emit(Event::TunnelRecovered {
+        host: target.host.clone(),
+        port: target.port,
+        reconnects: stats.reconnects,
+    });
+    if was_down {
+        webhooks::notify(
+            &manager.config().webhooks,
+            webhooks::Event::Up,
+            target,
+            Outcome::Ok,
+        )
+        .await;
+    }
+    systemd::status(up);
+    systemd::watchdog();
+    Ok(())
+}
+
+/// Resolves on Ctrl-C, or when systemd or `kill` asks the process to stop
+pub(crate) async fn stop_requested() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// A tunnel `up --watch` keeps or kept, for `status`
+#[derive(Debug, Clone, Serialize)]
+pub struct Watched {
+    #[serde(flatten)]
+    pub stats: Stats,
+    /// Whether the watch still runs
+    pub running: bool,
+}
+
+/// Result of `status`
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusReport {
+    pub tunnels: Vec<Watched>,
+    /// Whether the counters are shown
+    #[serde(skip)]
+    pub stats: bool,
+}
+
+/// The tunnels of every watch that has saved its statistics, by local port.
+/// Running watches are asked through their control socket, so the statistics
+/// are live and a watch that was killed shows as ended.
+pub async fn status(stats: bool) -> StatusReport {
+    let now = Utc::now();
+    let saved: Vec<Stats> = std::fs::read_dir(stats_dir())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            (path.extension()? == "json").then_some(())?;
+            serde_json::from_str::<Stats>(&std::fs::read_to_string(&path).ok()?).ok()
+        })
+        .collect();
+    let mut tunnels = Vec::new();
+    for saved in saved {
+        tunnels.push(match control::request(saved.port, Request::Status).await {
+            Some(Ok(stats)) => Watched {
+                stats,
+                running: true,
+            },
+            // Without control sockets, the saved statistics are all there is to go by
+            _ => Watched {
+                running: !cfg!(unix) && saved.is_running(now),
+                stats: saved,
+            },
+        });
+    }
+    tunnels.sort_by_key(|watched| watched.stats.port);
+    StatusReport { tunnels, stats }
+}
+
+impl Renderable for StatusReport {
+    fn to_human(&self) -> String {
+        if self.tunnels.is_empty() {
+            return "No tunnel has been watched; start one with `up --watch`".to_string();
+        }
+        let now = Utc::now();
+        let rows: Vec<Vec<String>> = self
+            .tunnels
+            .iter()
+            .map(|watched| {
+                let stats = &watched.stats;
+                let state = match (watched.running, stats.up_since) {
+                    (false, _) => "not watched".to_string(),
+                    (true, Some(since)) => format!(
+                        "up for {}",
+                        output::format_duration((now - since).to_std().unwrap_or_default())
+                    ),
+                    (true, None) => "down".to_string(),
+                };
+                let mut row = vec![
+                    stats.host.clone(),
+                    stats.port.to_string(),
+                    state,
+                    stats.started.format("%Y-%m-%d %H:%M:%S").to_string(),
+                ];
+                if self.stats {
+                    row.extend([
+                        output::format_duration(Duration::from_secs(stats.up_secs)),
+                        stats.checks.to_string(),
+                        stats.failed_checks.to_string(),
+                        stats.reconnects.to_string(),
+                        stats
+                            .last_failure
+                            .as_ref()
+                            .map(|failure| {
+                                format!(
+                                    "{} {}",
+                                    failure.at.format("%Y-%m-%d %H:%M:%S"),
+                                    failure.error
+                                )
+                            })
+                            .unwrap_or_default(),
+                    ]);
+                }
+                row
+            })
+            .collect();
+        let mut headers = vec!["HOST", "PORT", "STATE", "WATCHED SINCE"];
+        if self.stats {
+            headers.extend([
+                "UP IN ALL",
+                "CHECKS",
+                "FAILED",
+                "RECONNECTS",
+                "LAST FAILURE",
+            ]);
+        }
+        output::table(&headers, &rows)
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    #[test]
+    fn test_stats_count_uptime_failures_and_reconnects() {
+        let target = Target::builder("pi.local", "pi", &Config::default())
+            .build()
+            .unwrap();
+        let start = Utc::now();
+        let at = |secs| start + TimeDelta::seconds(secs);
+        let mut stats = Stats::new(&target, Duration::from_secs(30), start);
+        stats.passed(at(30));
+        stats.passed(at(60));
+        assert_eq!(stats.up_secs, 60);
+
+        stats.failed(at(90), "timeout".to_string());
+        assert_eq!(stats.up_since, None);
+        stats.failed(at(120), "timeout".to_string());
+        stats.reconnected(at(125));
+        stats.passed(at(155));
+        assert_eq!(stats.up_secs, 90);
+        assert_eq!(
+            (stats.checks, stats.failed_checks, stats.reconnects),
+            (5, 2, 1)
+        );
+        assert_eq!(stats.up_since, Some(at(125)));
+        assert_eq!(stats.last_failure.as_ref().unwrap().at, at(120));
+
+        assert!(stats.is_running(at(200)));
+        assert!(!stats.is_running(at(300)));
+        stats.ended = Some(at(160));
+        assert!(!stats.is_running(at(160)));
+    }
+}
@@ -0,0 +1,196 @@
+//! Webhooks: the `[webhooks]` URLs, told about the lifecycle of `up`, and
+//! of the tunnel `up --watch` keeps.
+//!
+//! Each gets a JSON POST, e.g. for a chat channel or a device-management
+//! backend: the event, host, user, local port, time and run ID, plus the
+//! failed phase and error for `on_down`. A webhook that fails is only a
+//! warning, and none are posted with `--simulate`.
+
+use crate::config::Webhooks;
+use crate::hooks::Outcome;
+use crate::phase::Phase;
+use crate::run;
+use crate::{Target, TunnelError};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Upper bound for one POST
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a webhook is told about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Up,
+    Down,
+    KeyDeployed,
+}
+
+impl Event {
+    /// The URL configured for this event, if any
+    fn url(self, webhooks: &Webhooks) -> Option<&str> {
+        match self {
+            Event::Up => webhooks.on_up.as_deref(),
+            Event::Down => webhooks.on_down.as_deref(),
+            Event::KeyDeployed => webhooks.on_key_deployed.as_deref(),
+        }
+    }
+
+    fn setting(self) -> &'static str {
+        match self {
+            Event::Up => "on_up",
+            Event::Down => "on_down",
+            Event::KeyDeployed => "on_key_deployed",
+        }
+    }
+}
+
+/// The JSON body of a webhook
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    event: Event,
+    host: &'a str,
+    user: &'a str,
+    port: u16,
+    timestamp: DateTime<Utc>,
+    run_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phase: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+impl<'a> Payload<'a> {
+    fn new(event: Event, target: &'a Target, outcome: Outcome<'a>) -> Self {
+        let (phase, error) = match outcome {
+            Outcome::Ok => (None, None),
+            Outcome::Failed { phase, error } => (phase.map(Phase::as_str), Some(error)),
+        };
+        Self {
+            event,
+            host: &target.host,
+            user: &target.user,
+            port: target.port,
+            timestamp: Utc::now(),
+            run_id: run::current().to_string(),
+            phase,
+            error,
+        }
+    }
+}
+
+/// POSTs `event` for `target` to its URL if one is configured, failing if the server refuses
+pub async fn post(
+    webhooks: &Webhooks,
+    event: Event,
+    target: &Target,
+    outcome: Outcome<'_>,
+) -> Result<(), TunnelError> {
+    let Some(url) = event.url(webhooks) else {
+        return Ok(());
+    };
+    if target.simulated.is_some() {
+        info!(
+            "Not posting the {} webhook while simulating",
+            event.setting()
+        );
+        return Ok(());
+    }
+    info!("Posting the {} webhook...", event.setting());
+    let body = serde_json::to_string(&Payload::new(event, target, outcome))
+        .map_err(|e| TunnelError::Webhook(format!("{}: {}", event.setting(), e)))?;
+    let url = url.to_string();
+    let sent = tokio::task::spawn_blocking(move || {
+        let response = ureq::AgentBuilder::new()
+            .timeout(POST_TIMEOUT)
+            .build()
+            .post(&url)
+            .set("Content-Type", "application/json")
+            .send_string(&body);
+        // The URL may hold a token, so only the server's answer is shown
+        match response {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(code, _)) => Err(format!("HTTP {}", code)),
+            Err(ureq::Error::Transport(transport)) => Err(match transport.message() {
+                Some(message) => format!("{}: {}", transport.kind(), message),
+                None => transport.kind().to_string(),
+            }),
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|sent| sent);
+    sent.map_err(|e| TunnelError::Webhook(format!("{}: {}", event.setting(), e)))
+}
+
+/// Posts `event` like [`post`], only warning if it fails
+pub async fn notify(webhooks: &Webhooks, event: Event, target: &Target, outcome: Outcome<'_>) {
+    if let Err(e) = post(webhooks, event, target, outcome).await {
+        warn!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_webhook_posts_the_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                if line.trim().is_empty() {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let mut stream = reader.into_inner();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        });
+
+        let target = Target {
+            host: "pi.local".to_string(),
+            user: "pi".to_string(),
+            port: 2222,
+            ..Target::default()
+        };
+        let webhooks = Webhooks {
+            on_down: Some(format!("http://{}/hook", address)),
+            ..Webhooks::default()
+        };
+        let failure = Outcome::Failed {
+            phase: Some(Phase::Key),
+            error: "denied",
+        };
+        post(&webhooks, Event::Up, &target, Outcome::Ok)
+            .await
+            .unwrap();
+        post(&webhooks, Event::Down, &target, failure)
+            .await
+            .unwrap();
+
+        let body = server.join().unwrap();
+        assert_eq!(body["event"], "down");
+        assert_eq!(body["host"], "pi.local");
+        assert_eq!(body["port"], 2222);
+        assert_eq!(body["phase"], "key");
+        assert_eq!(body["error"], "denied");
+    }
+}